@@ -0,0 +1,347 @@
+// An audit trail of reads and of every change made through the mount, for
+// hosts where who did what to which key has to be accounted for.
+//
+// Each operation is logged once it's replied to, as a line of JSON naming the
+// uid, gid, and pid of the process behind it, the operation, the path and key
+// it was on, and its outcome: ok, or the errno it failed with, eg.
+// {"at":1700000000000,"gid":1000,"key":"a","op":"unlink","outcome":"ENOENT","path":"/kv/a","pid":4242,"uid":1000}
+// Lines are appended to a file, or sent to syslog as LOG_AUTHPRIV. Passwords
+// in raw commands are never logged.
+use fuser::{
+    Errno, FileAttr, FileHandle, FopenFlags, Generation, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyEmpty, ReplyWrite, Request,
+};
+use serde_json::{Map, Value};
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// What audit_log is set to for entries to go to syslog rather than a file.
+pub const SYSLOG: &str = "syslog";
+
+// What's logged in place of a password.
+const REDACTED: &str = "<redacted>";
+
+// Config parameters whose values are passwords.
+const SECRET_PARAMETERS: &[&str] = &["requirepass", "masterauth", "masteruser"];
+
+enum Sink {
+    File(Mutex<File>),
+    Syslog,
+}
+
+pub struct Audit {
+    // None when not auditing, so nothing is ever logged.
+    sink: Option<Sink>,
+}
+
+impl Audit {
+    // An audit log appending to the file at path, or going to syslog if path
+    // is "syslog", or logging nothing without a path. The file is opened
+    // straight away, so it can be somewhere only root can write before
+    // privileges are dropped.
+    pub fn open(path: Option<&Path>) -> std::io::Result<Arc<Audit>> {
+        let sink = match path {
+            None => None,
+            Some(p) if p == Path::new(SYSLOG) => {
+                // openlog keeps the ident it's given, so it mustn't be freed.
+                unsafe {
+                    libc::openlog(
+                        b"fusekv\0".as_ptr() as *const libc::c_char,
+                        libc::LOG_PID,
+                        libc::LOG_AUTHPRIV,
+                    )
+                };
+                Some(Sink::Syslog)
+            }
+            Some(p) => Some(Sink::File(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(p)?,
+            ))),
+        };
+        Ok(Arc::new(Audit { sink }))
+    }
+
+    // An entry for op by whoever sent req on path, and key if it's on one,
+    // to be logged with its outcome. None when not auditing.
+    pub fn begin(
+        self: &Arc<Audit>,
+        req: &Request,
+        op: &'static str,
+        path: Option<String>,
+        key: Option<String>,
+    ) -> Option<Entry> {
+        self.sink.as_ref()?;
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        let mut fields = Map::new();
+        fields.insert("at".to_string(), at.into());
+        fields.insert("uid".to_string(), req.uid().into());
+        fields.insert("gid".to_string(), req.gid().into());
+        fields.insert("pid".to_string(), req.pid().into());
+        fields.insert("op".to_string(), op.into());
+        fields.insert("path".to_string(), path.into());
+        if let Some(key) = key {
+            fields.insert("key".to_string(), key.into());
+        }
+        Some(Entry {
+            audit: self.clone(),
+            fields,
+        })
+    }
+
+    // Failing to log is logged, but doesn't fail the operation, which has
+    // already happened by now.
+    fn log(&self, line: &str) {
+        match &self.sink {
+            Some(Sink::File(file)) => {
+                if let Err(e) = writeln!(file.lock().unwrap(), "{}", line) {
+                    log::error!("Error writing to the audit log: {}", e);
+                }
+            }
+            Some(Sink::Syslog) => {
+                // Lines are JSON, so hold no NULs.
+                let line = CString::new(line).unwrap_or_default();
+                unsafe {
+                    libc::syslog(
+                        libc::LOG_INFO,
+                        b"%s\0".as_ptr() as *const libc::c_char,
+                        line.as_ptr(),
+                    )
+                };
+            }
+            None => {}
+        }
+    }
+}
+
+// An operation waiting on its outcome to be logged.
+pub struct Entry {
+    audit: Arc<Audit>,
+    fields: Map<String, Value>,
+}
+
+impl Entry {
+    // The entry with another field, eg. the bytes written.
+    pub fn with<V: Into<Value>>(mut self, name: &str, value: V) -> Entry {
+        self.fields.insert(name.to_string(), value.into());
+        self
+    }
+
+    // Log the entry with the errno the operation failed with, if it did.
+    pub fn finish(mut self, outcome: Result<(), Errno>) {
+        let outcome = match outcome {
+            Ok(()) => "ok".to_string(),
+            Err(e) => errno_name(e.code()),
+        };
+        self.fields.insert("outcome".to_string(), outcome.into());
+        self.audit.log(&Value::Object(self.fields).to_string());
+    }
+}
+
+// A FUSE reply logging the outcome of the operation it answers, if audited.
+// Replies dropped without answering fail with EIO, as fuser's own do.
+pub struct Audited<R> {
+    reply: Option<R>,
+    entry: Option<Entry>,
+}
+
+impl<R> Audited<R> {
+    pub fn new(reply: R, entry: Option<Entry>) -> Audited<R> {
+        Audited {
+            reply: Some(reply),
+            entry,
+        }
+    }
+
+    // The reply with another field in its entry, eg. where a key was moved to.
+    pub fn with<V: Into<Value>>(mut self, name: &str, value: V) -> Audited<R> {
+        self.entry = self.entry.take().map(|e| e.with(name, value));
+        self
+    }
+
+    fn finish(&mut self, outcome: Result<(), Errno>, bytes: Option<usize>) {
+        if let Some(entry) = self.entry.take() {
+            match bytes {
+                Some(n) => entry.with("bytes", n).finish(outcome),
+                None => entry.finish(outcome),
+            }
+        }
+    }
+}
+
+impl<R> Drop for Audited<R> {
+    fn drop(&mut self) {
+        self.finish(Err(Errno::EIO), None);
+    }
+}
+
+impl Audited<ReplyData> {
+    pub fn data(mut self, data: &[u8]) {
+        self.finish(Ok(()), Some(data.len()));
+        if let Some(reply) = self.reply.take() {
+            reply.data(data);
+        }
+    }
+
+    pub fn error(mut self, err: Errno) {
+        self.finish(Err(err), None);
+        if let Some(reply) = self.reply.take() {
+            reply.error(err);
+        }
+    }
+}
+
+impl Audited<ReplyWrite> {
+    pub fn written(mut self, size: u32) {
+        self.finish(Ok(()), Some(size as usize));
+        if let Some(reply) = self.reply.take() {
+            reply.written(size);
+        }
+    }
+
+    pub fn error(mut self, err: Errno) {
+        self.finish(Err(err), None);
+        if let Some(reply) = self.reply.take() {
+            reply.error(err);
+        }
+    }
+}
+
+impl Audited<ReplyEmpty> {
+    pub fn ok(mut self) {
+        self.finish(Ok(()), None);
+        if let Some(reply) = self.reply.take() {
+            reply.ok();
+        }
+    }
+
+    pub fn error(mut self, err: Errno) {
+        self.finish(Err(err), None);
+        if let Some(reply) = self.reply.take() {
+            reply.error(err);
+        }
+    }
+}
+
+impl Audited<ReplyAttr> {
+    pub fn attr(mut self, ttl: &Duration, attr: &FileAttr) {
+        self.finish(Ok(()), None);
+        if let Some(reply) = self.reply.take() {
+            reply.attr(ttl, attr);
+        }
+    }
+
+    pub fn error(mut self, err: Errno) {
+        self.finish(Err(err), None);
+        if let Some(reply) = self.reply.take() {
+            reply.error(err);
+        }
+    }
+}
+
+impl Audited<ReplyCreate> {
+    pub fn created(
+        mut self,
+        ttl: &Duration,
+        attr: &FileAttr,
+        generation: Generation,
+        fh: FileHandle,
+        flags: FopenFlags,
+    ) {
+        self.finish(Ok(()), None);
+        if let Some(reply) = self.reply.take() {
+            reply.created(ttl, attr, generation, fh, flags);
+        }
+    }
+
+    pub fn error(mut self, err: Errno) {
+        self.finish(Err(err), None);
+        if let Some(reply) = self.reply.take() {
+            reply.error(err);
+        }
+    }
+}
+
+// The raw command split into args, with any passwords in them redacted, or
+// None if it has none, eg. "AUTH <redacted>" for AUTH hunter2.
+pub fn redact(args: &[String]) -> Option<String> {
+    let upper: Vec<String> = args.iter().map(|a| a.to_uppercase()).collect();
+    let mut secret = vec![false; args.len()];
+    match upper.first().map(String::as_str) {
+        // AUTH [username] password
+        Some("AUTH") => secret[1..].iter_mut().for_each(|s| *s = true),
+        // HELLO [protover [AUTH username password] ...]
+        // MIGRATE ... [AUTH password | AUTH2 username password] ...
+        Some("HELLO") | Some("MIGRATE") => {
+            for (i, arg) in upper.iter().enumerate() {
+                let n = match arg.as_str() {
+                    "AUTH" if upper[0] == "HELLO" => 2,
+                    "AUTH" => 1,
+                    "AUTH2" => 2,
+                    _ => continue,
+                };
+                for s in secret.iter_mut().skip(i + 1).take(n) {
+                    *s = true;
+                }
+            }
+        }
+        // CONFIG SET parameter value [parameter value ...]
+        Some("CONFIG") if upper.get(1).map(String::as_str) == Some("SET") => {
+            for i in (2..args.len()).step_by(2) {
+                if SECRET_PARAMETERS.contains(&args[i].to_lowercase().as_str()) {
+                    if let Some(s) = secret.get_mut(i + 1) {
+                        *s = true;
+                    }
+                }
+            }
+        }
+        // ACL SETUSER username [rule ...], where >password and #hash rules,
+        // and their removal by <password and !hash, give passwords away.
+        Some("ACL") if upper.get(1).map(String::as_str) == Some("SETUSER") => {
+            for (i, arg) in args.iter().enumerate().skip(3) {
+                secret[i] = arg.starts_with(['>', '<', '#', '!']);
+            }
+        }
+        _ => {}
+    }
+    if !secret.contains(&true) {
+        return None;
+    }
+    let redacted: Vec<&str> = args
+        .iter()
+        .zip(secret)
+        .map(|(arg, secret)| if secret { REDACTED } else { arg.as_str() })
+        .collect();
+    Some(redacted.join(" "))
+}
+
+// The name of errno e, eg. ENOENT, for the errors the mount fails with.
+fn errno_name(e: i32) -> String {
+    let name = match e {
+        libc::EPERM => "EPERM",
+        libc::ENOENT => "ENOENT",
+        libc::EIO => "EIO",
+        libc::EBADF => "EBADF",
+        libc::EAGAIN => "EAGAIN",
+        libc::EACCES => "EACCES",
+        libc::EBUSY => "EBUSY",
+        libc::EEXIST => "EEXIST",
+        libc::EXDEV => "EXDEV",
+        libc::ENOTDIR => "ENOTDIR",
+        libc::EISDIR => "EISDIR",
+        libc::EINVAL => "EINVAL",
+        libc::EFBIG => "EFBIG",
+        libc::ENOSPC => "ENOSPC",
+        libc::EROFS => "EROFS",
+        libc::ENOTEMPTY => "ENOTEMPTY",
+        libc::ENOTSUP => "ENOTSUP",
+        libc::ETIMEDOUT => "ETIMEDOUT",
+        libc::EDQUOT => "EDQUOT",
+        _ => return format!("errno {}", e),
+    };
+    name.to_string()
+}
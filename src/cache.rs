@@ -0,0 +1,196 @@
+// In-process cache of the attributes and values of keys, and of directory
+// listings, so bursts of stats, reads and listings, eg. from shell completion,
+// don't each go to the backend.
+//
+// Entries expire after their TTL, and anything changed through the mount is
+// forgotten as it changes, so only changes made by other clients can be seen
+// late. A zero TTL disables that part of the cache.
+use crate::fuse::KeyInfo;
+use crate::metrics::Metrics;
+use fuser::{FileAttr, FileType};
+use lru::LruCache;
+use regex::Regex;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Keys attributes and values are each kept for, least recently used first out.
+const CACHE_SIZE: usize = 10_000;
+
+// Directory listings kept, least recently used first out.
+const LISTINGS_SIZE: usize = 1_000;
+
+pub type Listing = Vec<(u64, FileType, String)>;
+
+pub struct Cache {
+    attr_ttl: Duration,
+    data_ttl: Duration,
+    listing_ttl: Duration,
+    attrs: LruCache<String, (Instant, FileAttr)>,
+    // What the backend reports about keys, for their xattrs. Kept as long as
+    // attributes are.
+    infos: LruCache<String, (Instant, KeyInfo)>,
+    values: LruCache<String, (Instant, Vec<u8>)>,
+    // By the path of the directory listed.
+    listings: LruCache<String, (Instant, Listing)>,
+    // Where hits and misses are counted, for the parts enabled.
+    metrics: Arc<Metrics>,
+}
+
+impl Cache {
+    pub fn new(
+        attr_ttl: Duration,
+        data_ttl: Duration,
+        listing_ttl: Duration,
+        metrics: Arc<Metrics>,
+    ) -> Cache {
+        Cache {
+            attr_ttl,
+            data_ttl,
+            listing_ttl,
+            attrs: LruCache::new(CACHE_SIZE),
+            infos: LruCache::new(CACHE_SIZE),
+            values: LruCache::new(CACHE_SIZE),
+            listings: LruCache::new(LISTINGS_SIZE),
+            metrics,
+        }
+    }
+
+    pub fn attr(&mut self, key: &str) -> Option<FileAttr> {
+        let attr = match self.attrs.get(&key.to_string()) {
+            Some((at, attr)) if at.elapsed() < self.attr_ttl => Some(*attr),
+            _ => None,
+        };
+        self.count("attr", self.attr_ttl, attr.is_some());
+        attr
+    }
+
+    pub fn put_attr(&mut self, key: &str, attr: FileAttr) {
+        if !self.attr_ttl.is_zero() {
+            self.attrs.put(key.to_string(), (Instant::now(), attr));
+        }
+    }
+
+    // Whether attributes, and so key metadata, are cached at all.
+    pub fn caches_attrs(&self) -> bool {
+        !self.attr_ttl.is_zero()
+    }
+
+    pub fn caches_values(&self) -> bool {
+        !self.data_ttl.is_zero()
+    }
+
+    // What the backend last reported about key, with its TTL counted down
+    // since.
+    pub fn key_info(&mut self, key: &str) -> Option<KeyInfo> {
+        let info = match self.infos.get(&key.to_string()) {
+            Some((at, info)) if at.elapsed() < self.attr_ttl => {
+                let mut info = info.clone();
+                info.ttl = info.ttl.map(|t| t.saturating_sub(at.elapsed().as_secs()));
+                Some(info)
+            }
+            _ => None,
+        };
+        self.count("key_info", self.attr_ttl, info.is_some());
+        info
+    }
+
+    // Whether key_info would answer for key without counting a lookup, to
+    // find what's worth fetching.
+    pub fn has_key_info(&self, key: &str) -> bool {
+        match self.infos.peek(&key.to_string()) {
+            Some((at, _)) => at.elapsed() < self.attr_ttl,
+            None => false,
+        }
+    }
+
+    pub fn put_key_info(&mut self, key: &str, info: KeyInfo) {
+        if !self.attr_ttl.is_zero() {
+            self.infos.put(key.to_string(), (Instant::now(), info));
+        }
+    }
+
+    pub fn value(&mut self, key: &str) -> Option<Vec<u8>> {
+        let value = match self.values.get(&key.to_string()) {
+            Some((at, value)) if at.elapsed() < self.data_ttl => Some(value.clone()),
+            _ => None,
+        };
+        self.count("value", self.data_ttl, value.is_some());
+        value
+    }
+
+    pub fn put_value(&mut self, key: &str, value: &[u8]) {
+        if !self.data_ttl.is_zero() {
+            self.values
+                .put(key.to_string(), (Instant::now(), value.to_vec()));
+        }
+    }
+
+    pub fn listing(&mut self, path: &str) -> Option<Listing> {
+        let listing = match self.listings.get(&path.to_string()) {
+            Some((at, listing)) if at.elapsed() < self.listing_ttl => Some(listing.clone()),
+            _ => None,
+        };
+        self.count("listing", self.listing_ttl, listing.is_some());
+        listing
+    }
+
+    // Count a lookup in the part of the cache with ttl, unless it's disabled.
+    fn count(&self, part: &'static str, ttl: Duration, hit: bool) {
+        if !ttl.is_zero() {
+            self.metrics.cache_lookup(part, hit);
+        }
+    }
+
+    pub fn put_listing(&mut self, path: &str, listing: &[(u64, FileType, String)]) {
+        if !self.listing_ttl.is_zero() {
+            self.listings
+                .put(path.to_string(), (Instant::now(), listing.to_vec()));
+        }
+    }
+
+    // Forget key, now that it changed. Which listings it appears in isn't
+    // tracked, so they're all forgotten too.
+    pub fn forget(&mut self, key: &str) {
+        self.attrs.pop(&key.to_string());
+        self.infos.pop(&key.to_string());
+        self.values.pop(&key.to_string());
+        self.listings.clear();
+    }
+
+    // Forget the listings of directories, and the keys of /kv entries, whose
+    // paths match pattern, returning how many were forgotten.
+    pub fn invalidate(&mut self, pattern: &Regex) -> usize {
+        let listings: Vec<String> = self
+            .listings
+            .iter()
+            .map(|(path, _)| path.clone())
+            .filter(|path| pattern.is_match(path))
+            .collect();
+        let mut keys: Vec<String> = self
+            .attrs
+            .iter()
+            .map(|(key, _)| key.clone())
+            .chain(self.infos.iter().map(|(key, _)| key.clone()))
+            .chain(self.values.iter().map(|(key, _)| key.clone()))
+            .filter(|key| pattern.is_match(&format!("/kv/{}", key)))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        for path in &listings {
+            self.listings.pop(path);
+        }
+        for key in &keys {
+            self.attrs.pop(key);
+            self.infos.pop(key);
+            self.values.pop(key);
+        }
+        listings.len() + keys.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.attrs.clear();
+        self.infos.clear();
+        self.values.clear();
+        self.listings.clear();
+    }
+}
@@ -0,0 +1,162 @@
+// How recently keys changed in each namespace, learned from the backend's
+// notifications of changes by any client, so the kernel can cache entries and
+// attributes of keys in namespaces nobody is writing to for longer than
+// entry_ttl and attr_ttl, while those written to often stay fresh.
+//
+// Keys are dropped from the kernel's caches as notifications of their changes
+// arrive, so a key changed in a quiet namespace is only seen late when too
+// many changed at once to drop each: by up to adaptive_ttl, and by no more
+// than a quarter of how long the namespace had been quiet. Until
+// notifications arrive, or while they can't be received, every namespace keeps
+// the configured TTLs.
+use crate::drivers::Driver;
+use crate::fuse::{DriverError, Subscription};
+use crate::tasks::Tasks;
+
+use lru::LruCache;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Namespaces changes are tracked for, least recently changed first out.
+const NAMESPACES_TRACKED: usize = 10_000;
+
+// How often notifications are read.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// How long to wait before subscribing again after failing to.
+const RESUBSCRIBE_INTERVAL: Duration = Duration::from_secs(5);
+
+// TTLs are how long a namespace has been quiet divided by this.
+const QUIET_DIVISOR: u32 = 4;
+
+pub struct Churn {
+    max_ttl: Duration,
+    separator: Option<String>,
+    state: Mutex<State>,
+}
+
+struct State {
+    // When a key last changed in each namespace.
+    changed: LruCache<String, Instant>,
+    // Since when notifications have been received without a break, or None
+    // while not subscribed.
+    watching: Option<Instant>,
+    // When a namespace was last dropped from changed. Namespaces not tracked
+    // are taken to have been quiet since then, or since watching began.
+    evicted: Option<Instant>,
+}
+
+impl Churn {
+    // Churn learned by a background task through driver, or none at all, so
+    // TTLs are never lengthened, if max_ttl is zero.
+    pub fn start(
+        tasks: &Tasks,
+        driver: Arc<dyn Driver>,
+        max_ttl: Duration,
+        separator: Option<String>,
+    ) -> Arc<Churn> {
+        let churn = Arc::new(Churn {
+            max_ttl,
+            separator,
+            state: Mutex::new(State {
+                changed: LruCache::new(NAMESPACES_TRACKED),
+                watching: None,
+                evicted: None,
+            }),
+        });
+        if max_ttl.is_zero() {
+            return churn;
+        }
+        let watcher = churn.clone();
+        let mut changes: Option<Box<dyn Subscription>> = None;
+        let mut retry_at = Instant::now();
+        tasks.every("churn", POLL_INTERVAL, move || {
+            if changes.is_none() {
+                if Instant::now() < retry_at {
+                    return true;
+                }
+                match driver.changes() {
+                    Ok(v) => {
+                        log::info!("Adapting TTLs to how often keys change");
+                        changes = Some(v);
+                        watcher.state.lock().unwrap().watching = Some(Instant::now());
+                    }
+                    Err(DriverError::Unsupported(feature)) => {
+                        log::warn!("Not adapting TTLs, as the driver lacks {}", feature);
+                        return false;
+                    }
+                    Err(e) => {
+                        log::warn!("Not adapting TTLs until changes can be watched: {}", e);
+                        retry_at = Instant::now() + RESUBSCRIBE_INTERVAL;
+                        return true;
+                    }
+                }
+            }
+            let subscription = changes.as_mut().unwrap();
+            loop {
+                match subscription.next_message(Some(Duration::from_millis(0))) {
+                    Ok(Some(key)) => watcher.record(&String::from_utf8_lossy(&key)),
+                    Ok(None) => return true,
+                    Err(e) => {
+                        log::warn!("Lost track of changes, not adapting TTLs: {}", e);
+                        changes = None;
+                        retry_at = Instant::now() + RESUBSCRIBE_INTERVAL;
+                        watcher.state.lock().unwrap().watching = None;
+                        return true;
+                    }
+                }
+            }
+        });
+        churn
+    }
+
+    // Note that key just changed.
+    fn record(&self, key: &str) {
+        let namespace = self.namespace(key);
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if state.changed.len() == state.changed.cap() && !state.changed.contains(&namespace) {
+            state.evicted = Some(now);
+        }
+        state.changed.put(namespace, now);
+    }
+
+    // How long the kernel may cache key for, given it would otherwise be
+    // base. Zero TTLs are kept, as caching was turned off.
+    pub fn ttl(&self, key: &str, base: Duration) -> Duration {
+        if base.is_zero() || self.max_ttl <= base {
+            return base;
+        }
+        let namespace = self.namespace(key);
+        let state = self.state.lock().unwrap();
+        let watching = match state.watching {
+            Some(v) => v,
+            None => return base,
+        };
+        let quiet_since = match state.changed.peek(&namespace) {
+            Some(at) => *at,
+            None => state.evicted.map_or(watching, |e| e.max(watching)),
+        };
+        (quiet_since.elapsed() / QUIET_DIVISOR).clamp(base, self.max_ttl)
+    }
+
+    // Whether changes are being watched, and in how many namespaces keys have
+    // changed since.
+    pub fn stats(&self) -> (bool, usize) {
+        let state = self.state.lock().unwrap();
+        (state.watching.is_some(), state.changed.len())
+    }
+
+    // The namespace key is in: everything up to its last separator, or "" if
+    // it has none.
+    fn namespace(&self, key: &str) -> String {
+        let separator = match &self.separator {
+            Some(v) if !v.is_empty() => v,
+            _ => return String::new(),
+        };
+        match key.rfind(separator.as_str()) {
+            Some(i) => key[..i + separator.len()].to_string(),
+            None => String::new(),
+        }
+    }
+}
@@ -0,0 +1,300 @@
+// Coalescing of full-value writes to the same key, so a process rewriting a
+// status file many times a second doesn't turn into as many SETs.
+//
+// The first write to a key goes straight to the driver. Any further writes
+// within the window are held, each replacing the last, and only the latest is
+// written once the window since the previous SET has passed. A background
+// task flushes held writes as they come due, and fsync flushes a key early.
+//
+// In write-behind mode every write is held, and acknowledged straight away,
+// and the background task writes all of them out in pipelined batches.
+use crate::drivers::Driver;
+use crate::fuse::DriverResult;
+use crate::tasks::Tasks;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+struct PendingWrite {
+    value: Vec<u8>,
+    due: Instant,
+}
+
+// A held write taken out of pending and being written to the driver.
+struct InFlight {
+    value: Vec<u8>,
+    // The key was deleted meanwhile, so the write mustn't be held again if
+    // it fails.
+    discarded: bool,
+}
+
+#[derive(Default)]
+struct State {
+    pending: HashMap<String, PendingWrite>,
+    // Writes being written out, which reads still see and which fsync,
+    // discard and further writes of the key wait for.
+    in_flight: HashMap<String, InFlight>,
+    // When each key was last written to the driver. Entries older than the
+    // window are pruned on every flush.
+    last_set: HashMap<String, Instant>,
+}
+
+// How writes are held back in write-behind mode.
+#[derive(Debug, Clone, Copy)]
+pub struct WriteBehind {
+    // Held writes sent to the driver at once.
+    pub batch: usize,
+    // How often held writes are written out.
+    pub interval: Duration,
+    // Writes held at most, past which a write waits for them all to be
+    // written out.
+    pub depth: usize,
+}
+
+pub struct WriteCoalescer {
+    driver: Arc<dyn Driver>,
+    window: Duration,
+    behind: Option<WriteBehind>,
+    state: Mutex<State>,
+    // Notified whenever writes in flight finish.
+    landed: Condvar,
+}
+
+impl WriteCoalescer {
+    // A coalescer writing to driver, with held writes flushed by a background
+    // task that exits once the coalescer is dropped. A zero window disables
+    // coalescing entirely, unless writes are held in write-behind mode, which
+    // ignores the window.
+    pub fn start(
+        tasks: &Tasks,
+        driver: Arc<dyn Driver>,
+        window: Duration,
+        behind: Option<WriteBehind>,
+    ) -> Arc<WriteCoalescer> {
+        let coalescer = Arc::new(WriteCoalescer {
+            driver,
+            window,
+            behind,
+            state: Mutex::new(State::default()),
+            landed: Condvar::new(),
+        });
+        let interval = match behind {
+            Some(b) => Some(b.interval),
+            // Held writes go out at most a quarter window late.
+            None if !window.is_zero() => Some(window / 4),
+            None => None,
+        };
+        if let Some(interval) = interval {
+            let weak = Arc::downgrade(&coalescer);
+            tasks.every("coalescer", interval, move || match weak.upgrade() {
+                Some(coalescer) => {
+                    // Failures are already logged per key.
+                    let _ = coalescer.flush_all(true);
+                    true
+                }
+                None => false,
+            });
+        }
+        coalescer
+    }
+
+    // Write value to key, now or once the window since the last write of key
+    // has passed.
+    pub fn write(&self, key: &str, value: Vec<u8>) -> DriverResult<()> {
+        let now = Instant::now();
+        if let Some(behind) = self.behind {
+            let full = {
+                let mut state = self.state.lock().unwrap();
+                let pending = PendingWrite { value, due: now };
+                state.pending.insert(key.to_string(), pending);
+                state.pending.len() >= behind.depth
+            };
+            if full {
+                log::debug!("Write-behind queue is full, writing it out");
+                // Other keys' failures are theirs to hear of, on fsync.
+                return self.write_out(false, Some(key));
+            }
+            return Ok(());
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(pending) = state.pending.get_mut(key) {
+                log::debug!("Coalescing write to {}", key);
+                pending.value = value;
+                return Ok(());
+            }
+            // Sent now, it could land before the write in flight does, so
+            // it's held until the next flush, which waits for that.
+            if state.in_flight.contains_key(key) {
+                state
+                    .pending
+                    .insert(key.to_string(), PendingWrite { value, due: now });
+                return Ok(());
+            }
+            if let Some(last) = state.last_set.get(key) {
+                if now.duration_since(*last) < self.window {
+                    let due = *last + self.window;
+                    state
+                        .pending
+                        .insert(key.to_string(), PendingWrite { value, due });
+                    return Ok(());
+                }
+            }
+            state.last_set.insert(key.to_string(), now);
+        }
+        self.driver.set(key, &value)
+    }
+
+    // The value held for key, if a write to it hasn't been flushed yet.
+    pub fn pending(&self, key: &str) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        match state.pending.get(key) {
+            Some(p) => Some(p.value.clone()),
+            None => state.in_flight.get(key).map(|f| f.value.clone()),
+        }
+    }
+
+    // How many keys have writes held.
+    pub fn queued(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+
+    // Drop any held value for key without writing it, eg. because the key was
+    // deleted. Returns whether there was one. A write of it already under way
+    // is waited for, so whatever follows isn't undone by it landing later.
+    pub fn discard(&self, key: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let mut discarded = state.pending.remove(key).is_some();
+        if let Some(flight) = state.in_flight.get_mut(key) {
+            flight.discarded = true;
+            discarded = true;
+        }
+        let _state = self.wait_landed(state, key);
+        discarded
+    }
+
+    // Write any held value for key immediately, once any write of it already
+    // under way has finished.
+    pub fn flush(&self, key: &str) -> DriverResult<()> {
+        let value = {
+            let state = self.state.lock().unwrap();
+            let mut state = self.wait_landed(state, key);
+            match state.pending.remove(key) {
+                Some(p) => {
+                    state.last_set.insert(key.to_string(), Instant::now());
+                    state.in_flight.insert(
+                        key.to_string(),
+                        InFlight {
+                            value: p.value.clone(),
+                            discarded: false,
+                        },
+                    );
+                    p.value
+                }
+                None => return Ok(()),
+            }
+        };
+        let result = self.driver.set(key, &value);
+        let mut state = self.state.lock().unwrap();
+        state.in_flight.remove(key);
+        self.landed.notify_all();
+        result
+    }
+
+    // Write every held value immediately, along with waiting for those
+    // already under way, or only those that are due.
+    pub fn flush_all(&self, only_due: bool) -> DriverResult<()> {
+        self.write_out(only_due, None)
+    }
+
+    // Write held values as flush_all does, returning how the write of own went
+    // if given, or else the last failure.
+    fn write_out(&self, only_due: bool, own: Option<&str>) -> DriverResult<()> {
+        let now = Instant::now();
+        let due: Vec<(String, Vec<u8>)> = {
+            let mut state = self.state.lock().unwrap();
+            // Writing every one out includes those under way, which the rest
+            // mustn't overtake.
+            if !only_due {
+                state = self
+                    .landed
+                    .wait_while(state, |s| !s.in_flight.is_empty())
+                    .unwrap();
+            }
+            let state = &mut *state;
+            let in_flight = &state.in_flight;
+            // Keys already being written are left for the next flush.
+            let keys: Vec<String> = state
+                .pending
+                .iter()
+                .filter(|(k, p)| (!only_due || p.due <= now) && !in_flight.contains_key(*k))
+                .map(|(k, _)| k.clone())
+                .collect();
+            let window = self.window;
+            state
+                .last_set
+                .retain(|_, last| now.duration_since(*last) < window);
+            let due: Vec<(String, Vec<u8>)> = keys
+                .into_iter()
+                .filter_map(|k| state.pending.remove(&k).map(|p| (k, p.value)))
+                .collect();
+            for (key, value) in &due {
+                let flight = InFlight {
+                    value: value.clone(),
+                    discarded: false,
+                };
+                state.in_flight.insert(key.clone(), flight);
+            }
+            due
+        };
+        // Keep going past failures so one bad batch doesn't hold up the rest.
+        // Writes are only batched in write-behind mode.
+        let batch = self.behind.map_or(1, |b| b.batch.max(1));
+        let mut result = Ok(());
+        for values in due.chunks(batch) {
+            let keys: Vec<&str> = values.iter().map(|(k, _)| k.as_str()).collect();
+            let written = self.driver.set_many(values);
+            let mut state = self.state.lock().unwrap();
+            let flights: Vec<InFlight> = keys
+                .iter()
+                .filter_map(|k| state.in_flight.remove(*k))
+                .collect();
+            match written {
+                Ok(()) => {
+                    for key in &keys {
+                        state.last_set.insert(key.to_string(), now);
+                    }
+                }
+                Err(e) => {
+                    log::error!("Error flushing held writes to {}: {}", keys.join(", "), e);
+                    // Writes already acknowledged in write-behind mode are
+                    // held again, unless newer ones took their place or the
+                    // key was deleted.
+                    if self.behind.is_some() {
+                        for ((key, _), flight) in values.iter().zip(flights) {
+                            if !flight.discarded {
+                                state.pending.entry(key.clone()).or_insert(PendingWrite {
+                                    value: flight.value,
+                                    due: now,
+                                });
+                            }
+                        }
+                    }
+                    if own.is_none_or(|k| keys.contains(&k)) {
+                        result = Err(e);
+                    }
+                }
+            }
+            self.landed.notify_all();
+        }
+        result
+    }
+
+    // Wait until no write of key is under way, giving state back.
+    fn wait_landed<'a>(&self, state: MutexGuard<'a, State>, key: &str) -> MutexGuard<'a, State> {
+        self.landed
+            .wait_while(state, |s| s.in_flight.contains_key(key))
+            .unwrap()
+    }
+}
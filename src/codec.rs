@@ -0,0 +1,105 @@
+// Compression formats recognised by the magic bytes values start with, so
+// values other applications stored compressed can be read decompressed from
+// /kv/<key>:decompressed.
+use miniz_oxide::inflate;
+
+// Bytes needed to recognise any codec.
+pub const MAGIC_LEN: u64 = 4;
+
+// Most bytes a value is decompressed to, so a small value can't exhaust memory.
+const MAX_DECOMPRESSED: usize = 256 * 1024 * 1024;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+// Gzip header flags.
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum CodecError {
+        Unsupported(codec: Codec) {
+            display("Decompressing {} is not supported.", codec.name())
+        }
+        Corrupt(codec: Codec, reason: String) {
+            display("Invalid {} data: {}", codec.name(), reason)
+        }
+    }
+}
+
+impl Codec {
+    // The codec value was compressed with, going by its first bytes.
+    pub fn detect(value: &[u8]) -> Option<Codec> {
+        if value.starts_with(ZSTD_MAGIC) {
+            Some(Codec::Zstd)
+        } else if value.starts_with(GZIP_MAGIC) {
+            Some(Codec::Gzip)
+        } else {
+            None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    pub fn decompress(&self, value: &[u8]) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Codec::Gzip => gunzip(value),
+            Codec::Zstd => Err(CodecError::Unsupported(*self)),
+        }
+    }
+}
+
+// The contents of the first member of a gzip stream.
+fn gunzip(value: &[u8]) -> Result<Vec<u8>, CodecError> {
+    let corrupt = |reason: &str| CodecError::Corrupt(Codec::Gzip, reason.to_string());
+    // Magic, method, flags, mtime, extra flags, and OS.
+    if value.len() < 10 || value[2] != 8 {
+        return Err(corrupt("not deflate compressed"));
+    }
+    let flags = value[3];
+    let mut pos = 10;
+    if flags & FEXTRA != 0 {
+        let len = match value.get(pos..pos + 2) {
+            Some(v) => u16::from_le_bytes([v[0], v[1]]) as usize,
+            None => return Err(corrupt("truncated header")),
+        };
+        pos += 2 + len;
+    }
+    for flag in &[FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            // Zero-terminated.
+            match value
+                .get(pos..)
+                .and_then(|v| v.iter().position(|&b| b == 0))
+            {
+                Some(end) => pos += end + 1,
+                None => return Err(corrupt("truncated header")),
+            }
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    let body = match value.get(pos..) {
+        Some(v) => v,
+        None => return Err(corrupt("truncated header")),
+    };
+    match inflate::decompress_to_vec_with_limit(body, MAX_DECOMPRESSED) {
+        Ok(v) => Ok(v),
+        Err(e) => Err(corrupt(&e.to_string())),
+    }
+}
@@ -1,18 +1,36 @@
-use serde::Deserialize;
+use crate::fuse::glob_regex;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
 use std::fmt;
 use std::fs;
+use std::net::SocketAddr;
 use std::num::ParseIntError;
 use std::path::PathBuf;
-use toml;
+use std::process;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use validator::Validate;
 
 #[derive(Debug, Validate, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct ConfigFile {
     pub cluster_mode: Option<bool>,
     pub redis: Option<RedisServer>,
+    pub server: Option<Vec<RedisServer>>,
+    pub external: Option<ExternalDriver>,
+    pub prefix: Option<String>,
+    pub separator: Option<String>,
     pub permission: Option<Vec<PathPermission>>,
     pub disable_raw: Option<bool>,
+    pub append_newline: Option<bool>,
+    pub track_mtime: Option<bool>,
     pub read_only: Option<bool>,
+    pub replica_writes: Option<bool>,
+    pub replica: Option<Vec<RedisServer>>,
+    pub discover_replicas: Option<bool>,
+    pub read_preference: Option<ReadPreference>,
+    pub max_replica_lag: Option<u64>,
+    pub read_your_writes: Option<u64>,
     pub allow_other: Option<bool>,
     pub user: Option<String>,
     pub group: Option<String>,
@@ -23,16 +41,111 @@ pub struct ConfigFile {
     ))]
     pub chmod: Option<u16>,
     pub max_results: Option<i64>,
-    // TODO allow configuring r2d2 connection pooling
+    pub lock_mode: Option<LockMode>,
+    pub lock_ttl: Option<u64>,
+    pub lock_privacy: Option<LockPrivacy>,
+    pub empty_file: Option<EmptyFile>,
+    pub nocache: Option<Vec<String>>,
+    pub blocking_timeout: Option<u64>,
+    pub listing_timeout: Option<u64>,
+    pub timeout: Option<Vec<PathTimeout>>,
+    pub listing_order: Option<ListingOrder>,
+    pub ttl_display: Option<TtlDisplay>,
+    pub rename_redirect: Option<u64>,
+    pub listing: Option<Vec<PathListing>>,
+    pub write_allow: Option<Vec<WriteAllow>>,
+    pub policy: Option<Vec<PathPolicy>>,
+    pub validation: Option<Vec<Validation>>,
+    pub acl_user: Option<Vec<AclUser>>,
+    pub bulk_delete_threshold: Option<u64>,
+    pub hot_keys: Option<usize>,
+    pub max_open_handles: Option<usize>,
+    pub max_open_per_key: Option<usize>,
+    pub raw_history: Option<usize>,
+    pub raw_history_stream: Option<String>,
+    pub trace: Option<usize>,
+    pub metrics_listen: Option<SocketAddr>,
+    pub count_sample: Option<u64>,
+    pub versioning: Option<bool>,
+    pub coalesce_window: Option<u64>,
+    pub write_behind: Option<bool>,
+    pub write_behind_batch: Option<usize>,
+    pub write_behind_interval: Option<u64>,
+    pub write_behind_depth: Option<usize>,
+    pub probe_modules: Option<bool>,
+    pub entry_ttl: Option<u64>,
+    pub attr_ttl: Option<u64>,
+    pub data_ttl: Option<u64>,
+    pub listing_ttl: Option<u64>,
+    pub adaptive_ttl: Option<u64>,
+    pub read_lock_ttl: Option<u64>,
+    pub stream_threshold: Option<u64>,
+    pub read_threads: Option<usize>,
+    pub fuse_threads: Option<usize>,
+    pub offload_dir: Option<PathBuf>,
+    pub offload_threshold: Option<u64>,
+    pub harden: Option<bool>,
+    pub confirm_allow_other: Option<bool>,
+    pub daemon: Option<bool>,
+    pub pid_file: Option<PathBuf>,
+    pub audit_log: Option<PathBuf>,
+    pub remount_attempts: Option<u32>,
+    pub static_file: Option<Vec<StaticFile>>,
+    pub static_dir: Option<Vec<StaticDir>>,
+    pub mirror: Option<Vec<Mirror>>,
+    pub quota: Option<Quota>,
+    pub pool_size: Option<u32>,
+    pub pool_connect_timeout: Option<u64>,
+    pub pool_idle_timeout: Option<u64>,
+    pub retry_attempts: Option<u32>,
+    pub retry_backoff: Option<u64>,
+    pub breaker_cooldown: Option<u64>,
+    pub database: Option<i64>,
+    pub redis_user: Option<String>,
+    pub redis_password: Option<Secret>,
+    pub redis_password_file: Option<PathBuf>,
+    pub redis_password_env: Option<String>,
+    pub redis_password_command: Option<String>,
+    pub tls_ca_cert: Option<PathBuf>,
+    pub tls_client_cert: Option<PathBuf>,
+    pub tls_client_key: Option<PathBuf>,
+    pub tls_insecure: Option<bool>,
+    pub hook: Option<Vec<Hook>>,
+    pub fixture: Option<PathBuf>,
 }
 
 #[derive(Debug, Validate, Default, Clone)]
 pub struct Config {
     pub cluster_mode: bool,
-    pub redis: Option<RedisServer>,
+    // Every server to connect to. Only cluster mode uses more than the first,
+    // as seeds to discover the rest of the cluster from.
+    pub servers: Vec<RedisServer>,
+    pub external: Option<ExternalDriver>,
+    // Prefix of every key exposed, left out of their names. Empty exposes
+    // every key.
+    pub prefix: String,
+    // Splits keys into nested directories under /kv, if set.
+    pub separator: Option<String>,
     pub permission: Vec<PathPermission>,
     pub disable_raw: bool,
+    // Add a \n to values when reading them, and drop one when writing.
+    pub append_newline: bool,
+    // Keep when each key was last written, for the mtime of its file.
+    pub track_mtime: bool,
     pub read_only: bool,
+    // Mount replicas read-write, rather than read-only.
+    pub replica_writes: bool,
+    // Replicas of the server reads may go to, besides any discovered from it
+    // when discover_replicas is set.
+    pub replicas: Vec<RedisServer>,
+    pub discover_replicas: bool,
+    pub read_preference: ReadPreference,
+    // Seconds a replica may go without hearing from its primary before reads
+    // stop going to it. 0 doesn't check.
+    pub max_replica_lag: u64,
+    // Seconds keys this mount wrote are read from the primary afterwards,
+    // however far behind replicas are let fall.
+    pub read_your_writes: u64,
     pub allow_other: bool,
     pub uid: u32,
     pub gid: u32,
@@ -42,7 +155,131 @@ pub struct Config {
         message = "Value must be between 000 and 777 (octal)"
     ))]
     pub chmod: u16,
-    pub max_results: i64,
+    // Entries listed per directory at most, or None (or -1) to list them all.
+    pub max_results: Option<i64>,
+    pub lock_mode: LockMode,
+    pub lock_ttl: u64,
+    pub lock_privacy: LockPrivacy,
+    pub empty_file: EmptyFile,
+    pub nocache: Vec<Regex>,
+    pub blocking_timeout: u64,
+    // Milliseconds listing /kv may take before returning what it has. 0 waits
+    // for the whole listing.
+    pub listing_timeout: u64,
+    pub timeout: Vec<PathTimeout>,
+    pub listing_order: ListingOrder,
+    pub ttl_display: TtlDisplay,
+    // Seconds lookups of keys renamed through the mount find where they went.
+    // 0 leaves no redirects.
+    pub rename_redirect: u64,
+    pub listing: Vec<PathListing>,
+    pub write_allow: Vec<WriteAllow>,
+    pub policy: Vec<PathPolicy>,
+    pub validation: Vec<Validation>,
+    pub acl_user: Vec<AclUser>,
+    pub bulk_delete_threshold: u64,
+    pub hot_keys: usize,
+    // Files open through the mount at once, in all and of any one key, past
+    // which opening more fails with EMFILE. 0 allows any number.
+    pub max_open_handles: usize,
+    pub max_open_per_key: usize,
+    // Commands run through /raw kept for /.fusekv/raw_history.
+    pub raw_history: usize,
+    // Stream every command run through /raw is also appended to, if any.
+    pub raw_history_stream: Option<String>,
+    // Commands sent to the backend kept for /.fusekv/trace.
+    pub trace: usize,
+    // Where Prometheus metrics are served over HTTP, if anywhere.
+    pub metrics_listen: Option<SocketAddr>,
+    pub count_sample: u64,
+    pub versioning: bool,
+    pub coalesce_window: u64,
+    // Acknowledge whole-value writes once queued, and write the queue out in
+    // batches of write_behind_batch every write_behind_interval milliseconds.
+    // Writes wait for the queue to be written out once write_behind_depth
+    // keys are queued.
+    pub write_behind: bool,
+    pub write_behind_batch: usize,
+    pub write_behind_interval: u64,
+    pub write_behind_depth: usize,
+    // Check which modules the backend has loaded on mounting, to expose JSON
+    // documents as directories when RedisJSON is.
+    pub probe_modules: bool,
+    // Milliseconds the kernel may cache names and attributes of entries for.
+    pub entry_ttl: u64,
+    // Milliseconds attributes and values of keys are cached for, both in the
+    // kernel and here. Changes made through the mount are seen immediately.
+    pub attr_ttl: u64,
+    pub data_ttl: u64,
+    // Milliseconds listings of /kv, its namespaces, and /tags are cached for.
+    pub listing_ttl: u64,
+    // Milliseconds entry_ttl and attr_ttl may be lengthened to for keys in
+    // namespaces that haven't changed lately. 0 never lengthens them.
+    pub adaptive_ttl: u64,
+    // Seconds read locks taken via user.fusekv.readlock last unless renewed,
+    // which they are while their files are open.
+    pub read_lock_ttl: u64,
+    // Bytes above which values are read in ranges as they're read, rather than
+    // fetched whole and kept for the open file.
+    pub stream_threshold: u64,
+    // Threads serving reads of values that aren't cached here. 0 serves them
+    // on the FUSE thread.
+    pub read_threads: usize,
+    // Threads taking requests from the kernel, which take turns with KVFS.
+    pub fuse_threads: usize,
+    // Directory values bigger than offload_threshold bytes are kept in instead
+    // of the backend, which only holds a pointer to them. None offloads
+    // nothing.
+    pub offload_dir: Option<PathBuf>,
+    pub offload_threshold: u64,
+    pub harden: bool,
+    pub confirm_allow_other: bool,
+    // Fork into the background once mounted.
+    pub daemon: bool,
+    // Where fusekv's PID is written once mounted, removed again on unmount.
+    pub pid_file: Option<PathBuf>,
+    // Where reads, changes, and raw commands are logged with who made them, or
+    // "syslog".
+    pub audit_log: Option<PathBuf>,
+    pub remount_attempts: u32,
+    pub static_file: Vec<StaticFile>,
+    pub static_dir: Vec<StaticDir>,
+    // Other backends shown read-only under /mirror.
+    pub mirror: Vec<Mirror>,
+    pub quota: Option<Quota>,
+    // Most Redis connections kept open at once.
+    pub pool_size: u32,
+    // Seconds to wait for a connection from the pool.
+    pub pool_connect_timeout: u64,
+    // Seconds an unused connection stays open. 0 keeps them open forever.
+    pub pool_idle_timeout: u64,
+    // Times to retry connecting, or commands the server refused to run for
+    // now, eg. while loading, before failing.
+    pub retry_attempts: u32,
+    // Milliseconds before the first retry, doubling for each after.
+    pub retry_backoff: u64,
+    // Seconds operations fail straight away for once retries are exhausted
+    // connecting. 0 never does.
+    pub breaker_cooldown: u64,
+    // Logical database to select on every server, rather than whichever their
+    // URLs give.
+    pub database: Option<i64>,
+    // Credentials and TLS settings for every server, kept out of their URLs.
+    pub redis_user: Option<String>,
+    pub redis_password: Option<Secret>,
+    // Where redis_password is fetched from again when the server rejects it.
+    pub redis_password_file: Option<PathBuf>,
+    pub redis_password_command: Option<String>,
+    pub tls_ca_cert: Option<PathBuf>,
+    // Certificate and key presented to servers asking for one, both PEM.
+    pub tls_client_cert: Option<PathBuf>,
+    pub tls_client_key: Option<PathBuf>,
+    pub tls_insecure: bool,
+    // Drop every inode mapping at mount rather than migrating them.
+    pub reset_ino_cache: bool,
+    pub hook: Vec<Hook>,
+    // Fixture to load into the backend before mounting.
+    pub fixture: Option<PathBuf>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -53,10 +290,360 @@ pub struct RedisServer {
 
 impl fmt::Display for RedisServer {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str(&self.url.to_string())
+        f.write_str(self.url.as_ref())
+    }
+}
+
+// A password, which is left out of the config when it's logged.
+#[derive(Deserialize, Clone, Default)]
+#[serde(transparent)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"<redacted>\"")
+    }
+}
+
+// How nested lock names under /lock interact with each other.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[derive(Default)]
+pub enum LockMode {
+    // Every lock name is independent of every other.
+    #[default]
+    Independent,
+    // A held lock blocks acquiring any lock nested beneath it, and vice versa.
+    ParentBlocking,
+}
+
+impl FromStr for LockMode {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<LockMode, String> {
+        match src {
+            "independent" => Ok(LockMode::Independent),
+            "parent-blocking" => Ok(LockMode::ParentBlocking),
+            _ => Err(format!(
+                "Unknown lock mode {:?}, expected independent or parent-blocking",
+                src
+            )),
+        }
+    }
+}
+
+// What users see of locks held by other users under /lock.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[derive(Default)]
+pub enum LockPrivacy {
+    // Every lock is visible to everyone, along with its owner.
+    #[default]
+    Off,
+    // Locks held by others aren't listed or found at all.
+    Hide,
+    // Locks held by others are listed, but reading who holds them is denied.
+    Stub,
+}
+
+impl FromStr for LockPrivacy {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<LockPrivacy, String> {
+        match src {
+            "off" => Ok(LockPrivacy::Off),
+            "hide" => Ok(LockPrivacy::Hide),
+            "stub" => Ok(LockPrivacy::Stub),
+            _ => Err(format!(
+                "Unknown lock privacy {:?}, expected off, hide, or stub",
+                src
+            )),
+        }
+    }
+}
+
+// What writing an empty file under /kv does to its key.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[derive(Default)]
+pub enum EmptyFile {
+    // The key is set to the empty string.
+    #[default]
+    Store,
+    // The key is deleted.
+    Delete,
+}
+
+impl FromStr for EmptyFile {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<EmptyFile, String> {
+        match src {
+            "store" => Ok(EmptyFile::Store),
+            "delete" => Ok(EmptyFile::Delete),
+            _ => Err(format!(
+                "Unknown empty file mode {:?}, expected store or delete",
+                src
+            )),
+        }
+    }
+}
+
+// What order directories under /kv list their keys in. Namespaces always come
+// first, by name.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[derive(Default)]
+pub enum ListingOrder {
+    // Whatever order the backend returns them in, eg. SCAN's.
+    #[default]
+    Backend,
+    Lexicographic,
+    // Most recently accessed first.
+    Idle,
+    // Biggest first.
+    Size,
+}
+
+impl FromStr for ListingOrder {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<ListingOrder, String> {
+        match src {
+            "backend" => Ok(ListingOrder::Backend),
+            "lexicographic" => Ok(ListingOrder::Lexicographic),
+            "idle" => Ok(ListingOrder::Idle),
+            "size" => Ok(ListingOrder::Size),
+            _ => Err(format!(
+                "Unknown listing order {:?}, expected backend, lexicographic, idle, or size",
+                src
+            )),
+        }
+    }
+}
+
+// Which servers reads go to, when there are replicas to read from.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[derive(Default)]
+pub enum ReadPreference {
+    // Every read goes to the primary, along with writes.
+    #[default]
+    Primary,
+    // Reads go to replicas keeping up with the primary, or to the primary
+    // when none are.
+    PreferReplica,
+    // Reads only go to replicas, failing when none are keeping up.
+    Replica,
+}
+
+impl FromStr for ReadPreference {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<ReadPreference, String> {
+        match src {
+            "primary" => Ok(ReadPreference::Primary),
+            "prefer-replica" => Ok(ReadPreference::PreferReplica),
+            "replica" => Ok(ReadPreference::Replica),
+            _ => Err(format!(
+                "Unknown read preference {:?}, expected primary, prefer-replica, or replica",
+                src
+            )),
+        }
+    }
+}
+
+// How keys that expire show how long they have left in their attributes.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+#[derive(Default)]
+pub enum TtlDisplay {
+    // Attributes are the same as keys that don't expire.
+    #[default]
+    None,
+    // Sizes shrink toward zero with the TTL, from the highest TTL seen.
+    Size,
+    // Blocks are the seconds left.
+    Blocks,
+}
+
+impl FromStr for TtlDisplay {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<TtlDisplay, String> {
+        match src {
+            "none" => Ok(TtlDisplay::None),
+            "size" => Ok(TtlDisplay::Size),
+            "blocks" => Ok(TtlDisplay::Blocks),
+            _ => Err(format!(
+                "Unknown TTL display {:?}, expected none, size, or blocks",
+                src
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ExternalDriver {
+    // Shell command that starts the driver process. See drivers::external for
+    // the protocol it must speak.
+    pub command: String,
+}
+
+// Override of timeouts for paths matching pattern.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PathTimeout {
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub pattern: Regex,
+    // Seconds a blocking read waits before failing with ETIMEDOUT. 0 waits
+    // forever.
+    pub blocking: Option<u64>,
+    // Milliseconds listing the directory may take before returning the entries
+    // gathered so far. 0 waits for the whole listing.
+    pub listing: Option<u64>,
+}
+
+// Override of the listing order of directories matching pattern.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PathListing {
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub pattern: Regex,
+    pub order: ListingOrder,
+}
+
+// Extra read-only file in the mount. Exactly one of content or source must be
+// set, source being a local file read once at startup.
+#[derive(Debug, Deserialize, Clone)]
+pub struct StaticFile {
+    // Path within the mount, eg. "docs/README". Its parent must be the root or
+    // a static_dir.
+    pub path: String,
+    pub content: Option<String>,
+    pub source: Option<PathBuf>,
+}
+
+// Extra empty directory in the mount, for static files to go in.
+#[derive(Debug, Deserialize, Clone)]
+pub struct StaticDir {
+    pub path: String,
+}
+
+// Another server's keys, shown read-only under /mirror/<name>, eg. to diff
+// staging against production. Only keys under prefix are shown, without it.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Mirror {
+    pub name: String,
+    pub url: url::Url,
+    #[serde(default)]
+    pub prefix: String,
+}
+
+// Soft limits on the backend. Nothing is enforced, usage is reported through
+// statfs and the user.fusekv.quota xattr on the mount root instead.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Quota {
+    // Number of keys.
+    pub keys: Option<u64>,
+    // Bytes of memory the backend uses.
+    pub memory: Option<u64>,
+    // Percentage of a limit past which usage is warned about.
+    #[serde(default = "default_warn_percent")]
+    pub warn_percent: u64,
+}
+
+fn default_warn_percent() -> u64 {
+    80
+}
+
+// Only members of groups may mutate paths matching pattern. Everyone else can
+// still read them.
+#[derive(Debug, Deserialize, Clone)]
+pub struct WriteAllow {
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub pattern: Regex,
+    pub groups: Vec<String>,
+    // groups resolved to gids once the config is loaded.
+    #[serde(skip)]
+    pub gids: Vec<u32>,
+}
+
+// Paths matching the glob pattern are read-only, or can't be looked up,
+// opened, or mutated at all if denied, for everyone. The first matching
+// policy wins.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PathPolicy {
+    #[serde(deserialize_with = "deserialize_glob")]
+    pub pattern: Regex,
+    #[serde(default)]
+    pub read_only: bool,
+    #[serde(default)]
+    pub deny: bool,
+}
+
+// Values written to paths matching pattern must pass every check set, or the
+// write fails with EINVAL. json_schema is a file holding a JSON Schema, and
+// command is run with the value on stdin, passing if it exits 0.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Validation {
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub pattern: Regex,
+    #[serde(default, deserialize_with = "deserialize_optional_regex")]
+    pub regex: Option<Regex>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub json_schema: Option<PathBuf>,
+    pub command: Option<String>,
+    // json_schema read once the config is loaded.
+    #[serde(skip)]
+    pub schema: Option<serde_json::Value>,
+}
+
+// Mutations of keys by user are only allowed if the backend's ACLs would let
+// redis_user run the same command, checked with ACL DRYRUN.
+#[derive(Debug, Deserialize, Clone)]
+pub struct AclUser {
+    pub user: String,
+    pub redis_user: String,
+    // user resolved to a uid once the config is loaded.
+    #[serde(skip)]
+    pub uid: u32,
+}
+
+// What happened to a key through the mount, for hooks.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HookOp {
+    Create,
+    Modify,
+    Delete,
+}
+
+impl fmt::Display for HookOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HookOp::Create => "create",
+            HookOp::Modify => "modify",
+            HookOp::Delete => "delete",
+        })
     }
 }
 
+// Run command, or the Lua script lua on the backend, when ops happen to keys
+// whose paths match pattern. Exactly one of command or lua must be set.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Hook {
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub pattern: Regex,
+    #[serde(default = "default_hook_ops")]
+    pub ops: Vec<HookOp>,
+    pub command: Option<String>,
+    pub lua: Option<String>,
+}
+
+fn default_hook_ops() -> Vec<HookOp> {
+    vec![HookOp::Create, HookOp::Modify, HookOp::Delete]
+}
+
 #[derive(Debug, Validate, Deserialize, Clone)]
 pub struct PathPermission {
     pub pattern: String,
@@ -90,6 +677,10 @@ quick_error! {
         Io(err: std::io::Error) {
             source(err)
         }
+        Parse(err: toml::de::Error) {
+            source(err)
+            display("Error parsing config file: {}", err)
+        }
         UserNotFound {
             display("User not found.")
         }
@@ -99,6 +690,125 @@ quick_error! {
         NoDriver {
             display("No driver provided in config file.")
         }
+        Invalid(problems: usize) {
+            display("Config file has {} problem(s).", problems)
+        }
+        UnknownScheme(scheme: String) {
+            display("No driver for {}:// server URLs.", scheme)
+        }
+        NoMountpoint {
+            display("No mount path provided.")
+        }
+        BadPattern(err: regex::Error) {
+            source(err)
+            display("Invalid path pattern: {}", err)
+        }
+        AllowOtherUnconfirmed {
+            display("allow_other exposes the mount to every user, set confirm_allow_other to use it with harden.")
+        }
+        BadStaticFile(path: String) {
+            display("Static file {} must set exactly one of content or source.", path)
+        }
+        BadSchema(path: PathBuf, err: serde_json::Error) {
+            source(err)
+            display("Error parsing JSON Schema {}: {}", path.display(), err)
+        }
+        BadHook(pattern: String) {
+            display("Hook for {} must set exactly one of command or lua.", pattern)
+        }
+        BadMirror(name: String, reason: &'static str) {
+            display("Mirror {:?} {}.", name, reason)
+        }
+        BadWarnPercent(percent: u64) {
+            display("Quota warn_percent must be between 1 and 100, not {}.", percent)
+        }
+        BadPool(reason: &'static str) {
+            display("Invalid connection pool settings: {}.", reason)
+        }
+        NoFuseThreads {
+            display("fuse_threads must be at least 1.")
+        }
+        BadDatabase(reason: &'static str) {
+            display("Invalid database: {}.", reason)
+        }
+        BadCredentials(reason: String) {
+            display("Invalid Redis credentials: {}.", reason)
+        }
+        BadReplicas(reason: &'static str) {
+            display("Invalid replica settings: {}.", reason)
+        }
+        BadChaos(setting: String) {
+            display("Invalid mem:// setting {}, expected latency, jitter, error_rate (0 to 1), or seed.", setting)
+        }
+        HardenAsRoot {
+            display("harden drops privileges to user and group, which must not be root.")
+        }
+        NotBuiltIn(feature: &'static str) {
+            display("fusekv was built without the {} feature.", feature)
+        }
+        ClusterUnsupported(command: &'static str) {
+            display("{} doesn't support cluster mode.", command)
+        }
+        Problems(path: PathBuf, problems: Vec<crate::schema::Problem>) {
+            display(
+                "Config file {} has {} problem(s):{}",
+                path.display(),
+                problems.len(),
+                problems.iter().map(|p| format!("\n  {}", p)).collect::<String>()
+            )
+        }
+        InvalidConfig(err: validator::ValidationErrors) {
+            source(err)
+            display("Invalid config: {}", err)
+        }
+    }
+}
+
+// The Redis password given directly, read from a file, read from an
+// environment variable, or printed by a command, whichever one is set.
+pub fn resolve_password(
+    password: Option<Secret>,
+    file: Option<PathBuf>,
+    env: Option<String>,
+    command: Option<String>,
+) -> Result<Option<Secret>, ConfigError> {
+    match (password, file, env, command) {
+        (None, None, None, None) => Ok(None),
+        (Some(password), None, None, None) => Ok(Some(password)),
+        (None, Some(file), None, None) => match fs::read_to_string(&file) {
+            // Files written with echo end in a newline that isn't part of it.
+            Ok(v) => Ok(Some(Secret(
+                v.trim_end_matches(&['\r', '\n'][..]).to_string(),
+            ))),
+            Err(e) => Err(ConfigError::BadCredentials(format!(
+                "reading {}: {}",
+                file.display(),
+                e
+            ))),
+        },
+        (None, None, Some(env), None) => match std::env::var(&env) {
+            Ok(v) => Ok(Some(Secret(v))),
+            Err(e) => Err(ConfigError::BadCredentials(format!("{}: {}", env, e))),
+        },
+        (None, None, None, Some(command)) => {
+            match process::Command::new("sh").arg("-c").arg(&command).output() {
+                Ok(out) if out.status.success() => Ok(Some(Secret(
+                    String::from_utf8_lossy(&out.stdout)
+                        .trim_end_matches(&['\r', '\n'][..])
+                        .to_string(),
+                ))),
+                Ok(out) => Err(ConfigError::BadCredentials(format!(
+                    "{} exited with {}",
+                    command, out.status
+                ))),
+                Err(e) => Err(ConfigError::BadCredentials(format!("{}: {}", command, e))),
+            }
+        }
+        _ => Err(ConfigError::BadCredentials(
+            "set only one of redis_password, redis_password_file, redis_password_env, and \
+             redis_password_command"
+                .to_string(),
+        )),
     }
 }
 
@@ -112,11 +822,64 @@ pub fn parse_octal(src: &str) -> Result<u16, PermissionParsingError> {
     }
 }
 
+fn deserialize_regex<'de, D: Deserializer<'de>>(d: D) -> Result<Regex, D::Error> {
+    let src = String::deserialize(d)?;
+    Regex::new(&src).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_optional_regex<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Regex>, D::Error> {
+    deserialize_regex(d).map(Some)
+}
+
+// A Redis glob pattern, as a regex matching the same.
+fn deserialize_glob<'de, D: Deserializer<'de>>(d: D) -> Result<Regex, D::Error> {
+    let src = String::deserialize(d)?;
+    glob_regex(&src).ok_or_else(|| serde::de::Error::custom(format!("bad glob {:?}", src)))
+}
+
+// Where a running mount gets its config from again when SIGHUP or a write to
+// /.fusekv/reload asks for it. Reloaded configs wait here until the mount takes
+// them up before its next operation.
+pub struct Reloader {
+    load: Box<dyn Fn() -> Result<Config, ConfigError> + Send + Sync>,
+    pending: Mutex<Option<Config>>,
+}
+
+impl Reloader {
+    pub fn new<F>(load: F) -> Arc<Reloader>
+    where
+        F: Fn() -> Result<Config, ConfigError> + Send + Sync + 'static,
+    {
+        Arc::new(Reloader {
+            load: Box::new(load),
+            pending: Mutex::new(None),
+        })
+    }
+
+    // Load the config again for the mount to take up, leaving what's pending
+    // as it is if that fails.
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        let config = (self.load)()?;
+        *self.pending.lock().unwrap() = Some(config);
+        Ok(())
+    }
+
+    // The config last reloaded, if the mount hasn't taken it up yet.
+    pub fn take(&self) -> Option<Config> {
+        self.pending.lock().unwrap().take()
+    }
+}
+
+// Read the config file at src, failing with every problem found in it, with
+// their lines, rather than only the first.
 pub fn load_file(src: PathBuf) -> Result<ConfigFile, ConfigError> {
-    let f = match fs::read_to_string(src) {
+    let f = match fs::read_to_string(&src) {
         Ok(f) => f,
         Err(e) => return Err(ConfigError::Io(e)),
     };
-    let config: ConfigFile = toml::from_str(&f).unwrap();
-    Ok(config)
+    let problems = crate::schema::validate(&f);
+    if !problems.is_empty() {
+        return Err(ConfigError::Problems(src, problems));
+    }
+    toml::from_str(&f).map_err(ConfigError::Parse)
 }
@@ -0,0 +1,317 @@
+// Driver that delegates to an external process over a line-based protocol on
+// its stdin/stdout, so backends can be written in any language.
+//
+// Every request is a single line of tab-separated fields, the first being the
+// command. Fields are escaped so that `\`, tab, and newline are written as
+// `\\`, `\t`, and `\n` respectively. The process must answer each request with
+// exactly one response line (plus any lines it announces):
+//
+//   HELLO <version>               -> HELLO <version>
+//   GET <key>                     -> VALUE <value> | NIL
+//   LIST <offset> <limit>         -> KEYS <n>, followed by n lines of one key each
+//   LOCK <name> <owner> <mode> <ttl>
+//                                 -> INT 1 (acquired) | INT 0 (held) | INT -1 (blocked)
+//   UNLOCK <name> <owner>         -> INT 1 (released) | INT 0 (not held by owner)
+//   OWNER <name>                  -> VALUE <owner> | NIL
+//   LOCKS <prefix>                -> KEYS <n>, followed by n lines of one lock name each
+//
+// Lock names are /-separated, mode is either independent or parent-blocking
+// (see config::LockMode), and ttl is how many milliseconds the lock is held
+// before it expires, or 0 to hold it until released.
+//
+// Any request may instead be answered with `ERR <message>`. Anything written to
+// stderr is passed through to fusekv's stderr untouched.
+use crate::config;
+use crate::config::LockMode;
+use crate::fuse;
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const PROTOCOL_VERSION: &str = "1";
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ExternalError {
+        Io(err: std::io::Error) {
+            source(err)
+            from()
+            display("Error talking to external driver: {}", err)
+        }
+        Driver(msg: String) {
+            display("External driver returned an error: {}", msg)
+        }
+        Protocol(line: String) {
+            display("Unexpected response from external driver: {:?}", line)
+        }
+        Closed {
+            display("External driver closed its stdout.")
+        }
+    }
+}
+
+struct Pipes {
+    stdin: BufWriter<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+}
+
+pub struct ExternalDriver {
+    child: Mutex<Child>,
+    pipes: Mutex<Pipes>,
+    // The protocol only deals in key names, so remember which key each inode
+    // was handed out for.
+    names_by_ino: Mutex<HashMap<u64, String>>,
+}
+
+impl fuse::KVReader for ExternalDriver {
+    // The protocol only covers reading, listing, and locks.
+    fn capabilities(&self) -> fuse::DriverCapabilities {
+        fuse::DriverCapabilities {
+            locks: true,
+            ..fuse::DriverCapabilities::default()
+        }
+    }
+
+    fn get_by_name(&self, name: String, ino: u64) -> fuse::DriverResult<Option<fuse::KVEntry>> {
+        let fields = ["GET", name.as_str()];
+        let value = match self.call(&fields)? {
+            Response::Value(v) => v,
+            Response::Nil => return Ok(None),
+            other => return Err(unexpected(&fields, other)),
+        };
+        self.names_by_ino.lock().unwrap().insert(ino, name.clone());
+        // Responses are text, so values can't hold anything but UTF-8.
+        Ok(Some(fuse::KVEntry::new(name, value.into_bytes())))
+    }
+
+    fn get_by_ino(&self, ino: u64) -> fuse::DriverResult<Option<fuse::KVEntry>> {
+        let name = match self.names_by_ino.lock().unwrap().get(&ino) {
+            Some(v) => v.clone(),
+            None => return Ok(None),
+        };
+        self.get_by_name(name, ino)
+    }
+
+    fn list_keys(&self, offset: i64, limit: i64) -> fuse::DriverResult<Vec<fuse::KVRef>> {
+        let (offset, limit) = (offset.to_string(), limit.to_string());
+        let fields = ["LIST", offset.as_str(), limit.as_str()];
+        let keys = match self.call(&fields)? {
+            Response::Keys(v) => v,
+            other => return Err(unexpected(&fields, other)),
+        };
+        let mut names_by_ino = self.names_by_ino.lock().unwrap();
+        Ok(keys
+            .into_iter()
+            .map(|key| {
+                let ino = fuse::kv_ino(&key);
+                names_by_ino.insert(ino, key.clone());
+                fuse::KVRef { ino, key }
+            })
+            .collect())
+    }
+
+    fn server_info(&self) -> fuse::DriverResult<fuse::ServerInfo> {
+        Ok(fuse::ServerInfo {
+            driver: "external".to_string(),
+            ..fuse::ServerInfo::default()
+        })
+    }
+}
+
+impl fuse::KVLocker for ExternalDriver {
+    fn acquire_lock(
+        &self,
+        name: &str,
+        owner: &str,
+        mode: LockMode,
+        ttl: Option<Duration>,
+    ) -> fuse::DriverResult<fuse::LockOutcome> {
+        let mode = match mode {
+            LockMode::Independent => "independent",
+            LockMode::ParentBlocking => "parent-blocking",
+        };
+        let ttl = ttl.map_or(0, |t| t.as_millis()).to_string();
+        let fields = ["LOCK", name, owner, mode, &ttl];
+        match self.call(&fields)? {
+            Response::Int(1) => Ok(fuse::LockOutcome::Acquired),
+            Response::Int(0) => Ok(fuse::LockOutcome::Held),
+            Response::Int(_) => Ok(fuse::LockOutcome::Blocked),
+            other => Err(unexpected(&fields, other)),
+        }
+    }
+
+    fn release_lock(&self, name: &str, owner: &str) -> fuse::DriverResult<bool> {
+        let fields = ["UNLOCK", name, owner];
+        match self.call(&fields)? {
+            Response::Int(n) => Ok(n == 1),
+            other => Err(unexpected(&fields, other)),
+        }
+    }
+
+    fn lock_owner(&self, name: &str) -> fuse::DriverResult<Option<String>> {
+        let fields = ["OWNER", name];
+        match self.call(&fields)? {
+            Response::Value(v) => Ok(Some(v)),
+            Response::Nil => Ok(None),
+            other => Err(unexpected(&fields, other)),
+        }
+    }
+
+    fn list_locks(&self, prefix: &str) -> fuse::DriverResult<Vec<String>> {
+        let fields = ["LOCKS", prefix];
+        match self.call(&fields)? {
+            Response::Keys(v) => Ok(v),
+            other => Err(unexpected(&fields, other)),
+        }
+    }
+}
+
+impl fuse::KVWriter for ExternalDriver {}
+
+impl fuse::KVTagger for ExternalDriver {}
+
+#[derive(Debug)]
+enum Response {
+    Hello(String),
+    Value(String),
+    Int(i64),
+    Nil,
+    Keys(Vec<String>),
+}
+
+impl ExternalDriver {
+    pub fn spawn(cfg: &config::ExternalDriver) -> Result<ExternalDriver, ExternalError> {
+        log::debug!("Spawning external driver `{}`.", cfg.command);
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&cfg.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+        let pipes = Pipes {
+            stdin: BufWriter::new(child.stdin.take().unwrap()),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+        };
+        let driver = ExternalDriver {
+            child: Mutex::new(child),
+            pipes: Mutex::new(pipes),
+            names_by_ino: Mutex::new(HashMap::new()),
+        };
+        match driver.request(&["HELLO", PROTOCOL_VERSION])? {
+            Response::Hello(ref v) if v == PROTOCOL_VERSION => Ok(driver),
+            other => Err(ExternalError::Protocol(format!("{:?}", other))),
+        }
+    }
+
+    fn request(&self, fields: &[&str]) -> Result<Response, ExternalError> {
+        let mut pipes = self.pipes.lock().unwrap();
+        let line = fields
+            .iter()
+            .map(|f| escape(f))
+            .collect::<Vec<String>>()
+            .join("\t");
+        writeln!(pipes.stdin, "{}", line)?;
+        pipes.stdin.flush()?;
+
+        let line = read_line(&mut pipes.stdout)?;
+        let mut parts = line.splitn(2, '\t');
+        let kind = parts.next().unwrap_or("");
+        let rest = unescape(parts.next().unwrap_or(""));
+        match kind {
+            "HELLO" => Ok(Response::Hello(rest)),
+            "VALUE" => Ok(Response::Value(rest)),
+            "NIL" => Ok(Response::Nil),
+            "INT" => match rest.parse() {
+                Ok(n) => Ok(Response::Int(n)),
+                Err(_) => Err(ExternalError::Protocol(line.clone())),
+            },
+            "ERR" => Err(ExternalError::Driver(rest)),
+            "KEYS" => {
+                let n: usize = match rest.parse() {
+                    Ok(n) => n,
+                    Err(_) => return Err(ExternalError::Protocol(line.clone())),
+                };
+                let mut keys = Vec::with_capacity(n);
+                for _ in 0..n {
+                    keys.push(unescape(&read_line(&mut pipes.stdout)?));
+                }
+                Ok(Response::Keys(keys))
+            }
+            _ => Err(ExternalError::Protocol(line.clone())),
+        }
+    }
+
+    // Make a request on behalf of the driver traits, describing any failure
+    // by the request that caused it.
+    fn call(&self, fields: &[&str]) -> fuse::DriverResult<Response> {
+        self.request(fields).map_err(|e| {
+            let (command, key) = request_context(fields);
+            match e {
+                ExternalError::Protocol(line) => fuse::DriverError::Corrupt(command, key, line),
+                other => fuse::DriverError::Unavailable(command, key, other.to_string()),
+            }
+        })
+    }
+}
+
+impl Drop for ExternalDriver {
+    fn drop(&mut self) {
+        let mut child = self.child.lock().unwrap();
+        if let Err(e) = child.kill() {
+            log::debug!("Error stopping external driver: {}", e);
+        }
+        let _ = child.wait();
+    }
+}
+
+fn read_line(stdout: &mut BufReader<ChildStdout>) -> Result<String, ExternalError> {
+    let mut line = String::new();
+    if stdout.read_line(&mut line)? == 0 {
+        return Err(ExternalError::Closed);
+    }
+    Ok(line.trim_end_matches('\n').to_string())
+}
+
+// The command and key of a request, for error context. Not every request has a
+// key, in which case its first argument stands in.
+fn request_context(fields: &[&str]) -> (String, String) {
+    (
+        fields[0].to_string(),
+        fields.get(1).unwrap_or(&"").to_string(),
+    )
+}
+
+// A well-formed response that doesn't answer the request it was made to.
+fn unexpected(fields: &[&str], response: Response) -> fuse::DriverError {
+    let (command, key) = request_context(fields);
+    fuse::DriverError::Corrupt(command, key, format!("{:?}", response))
+}
+
+fn escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
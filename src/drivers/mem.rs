@@ -0,0 +1,935 @@
+// Driver keeping every key in this process's memory, selected with a mem://
+// server URL, so fusekv can be tried out or tested without a Redis server.
+// Nothing outlives the mount.
+//
+// Keys behave as they do in Redis as far as the filesystem can tell, TTLs and
+// tags included, and pubsub channels only reach subscribers of this mount. Raw
+// commands, Lua, and versioning aren't supported.
+//
+// A degraded backend can be simulated through the URL's query, to rehearse how
+// applications reading the mount cope without touching a real server: every
+// call is delayed by latency milliseconds, give or take up to jitter, and
+// error_rate of them, from 0 to 1, fail as if the backend were unavailable.
+// seed makes which ones fail repeatable, eg.
+// mem://?latency=50&jitter=20&error_rate=0.01&seed=42.
+use crate::config::{Config, ConfigError, LockMode};
+use crate::drivers::Driver;
+use crate::fuse;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use url::Url;
+
+#[derive(Debug, Clone)]
+enum Value {
+    String(Vec<u8>),
+    List(Vec<String>),
+    Set(BTreeSet<String>),
+}
+
+impl Value {
+    fn kind(&self) -> fuse::ValueKind {
+        match self {
+            Value::String(_) => fuse::ValueKind::String,
+            Value::List(_) => fuse::ValueKind::List,
+            Value::Set(_) => fuse::ValueKind::Set,
+        }
+    }
+
+    // How the value reads, with lists and sets one element per line.
+    fn bytes(&self) -> Vec<u8> {
+        match self {
+            Value::String(v) => v.clone(),
+            Value::List(items) => items.join("\n").into_bytes(),
+            Value::Set(items) => items
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join("\n")
+                .into_bytes(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Store {
+    // Sorted, so listings are stable between calls.
+    values: BTreeMap<String, Value>,
+    expiries: HashMap<String, Instant>,
+    // When each key was last written.
+    modified: HashMap<String, SystemTime>,
+    // The keys with each tag.
+    tags: BTreeMap<String, BTreeSet<String>>,
+    // The key each renamed key was renamed to, and when that's forgotten.
+    redirects: HashMap<String, (String, Instant)>,
+    // The owner of each held lock, and when it expires.
+    locks: BTreeMap<String, (String, Option<Instant>)>,
+    // The subscribers to each channel.
+    subscribers: BTreeMap<String, Vec<Subscriber>>,
+    // Subscribers to the names of keys as they change.
+    watchers: Vec<Subscriber>,
+    // Consumers of each queue, woken whenever it's pushed onto.
+    consumers: BTreeMap<String, Vec<Subscriber>>,
+}
+
+// Where messages published to a channel are sent, for as long as the
+// subscription it was made for is alive.
+#[derive(Debug)]
+struct Subscriber {
+    messages: Sender<Vec<u8>>,
+    alive: Weak<()>,
+}
+
+struct Subscription {
+    messages: Receiver<Vec<u8>>,
+    // Only there to be dropped along with the subscription.
+    _alive: Arc<()>,
+}
+
+impl fuse::Subscription for Subscription {
+    fn next_message(&mut self, timeout: Option<Duration>) -> fuse::DriverResult<Option<Vec<u8>>> {
+        Ok(match timeout {
+            Some(t) => self.messages.recv_timeout(t).ok(),
+            None => self.messages.recv().ok(),
+        })
+    }
+}
+
+// A queue popped from, waiting to be woken by a push whenever it's empty.
+struct Consumer {
+    store: Arc<Mutex<Store>>,
+    queue: String,
+    wakes: Receiver<Vec<u8>>,
+    // Only there to be dropped along with the consumer.
+    _alive: Arc<()>,
+}
+
+impl fuse::Subscription for Consumer {
+    fn next_message(&mut self, timeout: Option<Duration>) -> fuse::DriverResult<Option<Vec<u8>>> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        loop {
+            if let Some(item) = self.store.lock().unwrap().pop(&self.queue)? {
+                return Ok(Some(item));
+            }
+            // Another consumer may get to what woke this one first.
+            let woken = match deadline {
+                Some(at) => self
+                    .wakes
+                    .recv_timeout(at.saturating_duration_since(Instant::now()))
+                    .is_ok(),
+                None => self.wakes.recv().is_ok(),
+            };
+            if !woken {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+impl Store {
+    // Drop every key and lock that has expired.
+    fn purge(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .expiries
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in expired {
+            self.remove(&key);
+        }
+        self.locks
+            .retain(|_, (_, expires)| expires.is_none_or(|at| at > now));
+        self.redirects.retain(|_, (_, expires)| *expires > now);
+        for subscribers in self.subscribers.values_mut() {
+            subscribers.retain(|s| s.alive.strong_count() > 0);
+        }
+        self.subscribers.retain(|_, s| !s.is_empty());
+        self.watchers.retain(|s| s.alive.strong_count() > 0);
+        for consumers in self.consumers.values_mut() {
+            consumers.retain(|s| s.alive.strong_count() > 0);
+        }
+        self.consumers.retain(|_, s| !s.is_empty());
+    }
+
+    // Remove key along with its expiry and tags, returning whether it existed.
+    fn remove(&mut self, key: &str) -> bool {
+        self.expiries.remove(key);
+        self.modified.remove(key);
+        self.untag_all(key);
+        let existed = self.values.remove(key).is_some();
+        if existed {
+            self.changed(key);
+        }
+        existed
+    }
+
+    fn untag_all(&mut self, key: &str) {
+        for keys in self.tags.values_mut() {
+            keys.remove(key);
+        }
+        self.tags.retain(|_, keys| !keys.is_empty());
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.modified.insert(key.to_string(), SystemTime::now());
+        self.changed(key);
+    }
+
+    // Tell watchers key changed.
+    fn changed(&self, key: &str) {
+        for watcher in &self.watchers {
+            let _ = watcher.messages.send(key.as_bytes().to_vec());
+        }
+    }
+
+    // Pop the last element off the list at key, as RPOP does.
+    fn pop(&mut self, key: &str) -> fuse::DriverResult<Option<Vec<u8>>> {
+        self.purge();
+        let (item, empty) = match self.values.get_mut(key) {
+            Some(Value::List(items)) => (items.pop(), items.is_empty()),
+            Some(_) => {
+                return Err(fuse::DriverError::WrongType(
+                    "BRPOP".to_string(),
+                    key.to_string(),
+                ))
+            }
+            None => return Ok(None),
+        };
+        // As in Redis, a list with no elements doesn't exist.
+        if empty {
+            self.remove(key);
+        } else {
+            self.touch(key);
+        }
+        Ok(item.map(String::into_bytes))
+    }
+
+    fn string_mut(&mut self, command: &str, key: &str) -> fuse::DriverResult<&mut Vec<u8>> {
+        self.touch(key);
+        match self
+            .values
+            .entry(key.to_string())
+            .or_insert_with(|| Value::String(vec![]))
+        {
+            Value::String(v) => Ok(v),
+            _ => Err(fuse::DriverError::WrongType(
+                command.to_string(),
+                key.to_string(),
+            )),
+        }
+    }
+}
+
+// How degraded the backend is made to seem, from the URL's query.
+#[derive(Debug, Default)]
+struct Chaos {
+    latency: u64,
+    jitter: u64,
+    error_rate: f64,
+    // State of the xorshift generator picking delays and failures.
+    rng: Mutex<u64>,
+}
+
+impl Chaos {
+    fn parse(url: &Url) -> Result<Chaos, ConfigError> {
+        let mut chaos = Chaos::default();
+        let mut seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        for (name, value) in url.query_pairs() {
+            let bad = || ConfigError::BadChaos(format!("{}={}", name, value));
+            match name.as_ref() {
+                "latency" => chaos.latency = value.parse().map_err(|_| bad())?,
+                "jitter" => chaos.jitter = value.parse().map_err(|_| bad())?,
+                "error_rate" => match value.parse::<f64>() {
+                    Ok(v) if (0.0..=1.0).contains(&v) => chaos.error_rate = v,
+                    _ => return Err(bad()),
+                },
+                "seed" => seed = value.parse().map_err(|_| bad())?,
+                _ => return Err(bad()),
+            }
+        }
+        // xorshift never leaves 0.
+        *chaos.rng.lock().unwrap() = seed.max(1);
+        Ok(chaos)
+    }
+
+    fn enabled(&self) -> bool {
+        self.latency > 0 || self.jitter > 0 || self.error_rate > 0.0
+    }
+
+    // Delay the calling thread, then fail as often as configured.
+    fn strike(&self) -> fuse::DriverResult<()> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let (delay, roll) = {
+            let mut rng = self.rng.lock().unwrap();
+            (xorshift(&mut rng), xorshift(&mut rng))
+        };
+        let jitter = (delay % (2 * self.jitter + 1)) as i64 - self.jitter as i64;
+        let delay = (self.latency as i64 + jitter).max(0) as u64;
+        if delay > 0 {
+            thread::sleep(Duration::from_millis(delay));
+        }
+        // The top 53 bits, as a fraction of 1.
+        let roll = (roll >> 11) as f64 / (1u64 << 53) as f64;
+        if roll < self.error_rate {
+            return Err(fuse::DriverError::Unavailable(
+                "MEM".to_string(),
+                String::new(),
+                "simulated failure".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+pub struct MemDriver {
+    chaos: Chaos,
+    // Shared with consumers, which wait on it after being handed out.
+    store: Arc<Mutex<Store>>,
+    // Which key each inode was handed out for.
+    names_by_ino: Mutex<HashMap<u64, String>>,
+}
+
+impl fuse::KVReader for MemDriver {
+    fn capabilities(&self) -> fuse::DriverCapabilities {
+        fuse::DriverCapabilities {
+            scan: true,
+            ttl: true,
+            locks: true,
+            copy: true,
+            notifications: true,
+            queues: true,
+            ..fuse::DriverCapabilities::default()
+        }
+    }
+
+    fn get_by_name(&self, name: String, ino: u64) -> fuse::DriverResult<Option<fuse::KVEntry>> {
+        let value = match self.store()?.values.get(&name) {
+            Some(v) => v.clone(),
+            None => return Ok(None),
+        };
+        self.names_by_ino.lock().unwrap().insert(ino, name.clone());
+        let mut entry = fuse::KVEntry::new(name, value.bytes());
+        entry.kind = value.kind();
+        Ok(Some(entry))
+    }
+
+    fn get_by_ino(&self, ino: u64) -> fuse::DriverResult<Option<fuse::KVEntry>> {
+        let name = match self.names_by_ino.lock().unwrap().get(&ino) {
+            Some(v) => v.clone(),
+            None => return Ok(None),
+        };
+        self.get_by_name(name, ino)
+    }
+
+    fn list_keys(&self, offset: i64, limit: i64) -> fuse::DriverResult<Vec<fuse::KVRef>> {
+        let limit = if limit == -1 {
+            usize::MAX
+        } else {
+            limit as usize
+        };
+        let keys: Vec<String> = self
+            .store()?
+            .values
+            .keys()
+            .skip(offset as usize)
+            .take(limit)
+            .cloned()
+            .collect();
+        Ok(self.remember(keys))
+    }
+
+    fn read_range(
+        &self,
+        key: &str,
+        offset: u64,
+        size: u64,
+    ) -> fuse::DriverResult<Option<(Vec<u8>, u64)>> {
+        // Unlike KVEntry, this keeps values that aren't UTF-8 intact.
+        let value = match self.store()?.values.get(key) {
+            Some(v) => v.bytes(),
+            None => return Ok(None),
+        };
+        let start = (offset as usize).min(value.len());
+        let end = start.saturating_add(size as usize).min(value.len());
+        Ok(Some((value[start..end].to_vec(), value.len() as u64)))
+    }
+
+    fn ping(&self) -> fuse::DriverResult<()> {
+        self.chaos.strike()
+    }
+
+    fn server_info(&self) -> fuse::DriverResult<fuse::ServerInfo> {
+        Ok(fuse::ServerInfo {
+            driver: "mem".to_string(),
+            ..Default::default()
+        })
+    }
+
+    // The store is in this process, so its clock is ours.
+    fn server_time(&self) -> fuse::DriverResult<Duration> {
+        Ok(SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default())
+    }
+
+    fn match_keys(&self, pattern: &str, limit: i64) -> fuse::DriverResult<Vec<fuse::KVRef>> {
+        let limit = if limit == -1 {
+            usize::MAX
+        } else {
+            limit as usize
+        };
+        // Patterns that can't be matched simply match nothing, as in Redis.
+        let re = fuse::glob_regex(pattern);
+        let keys: Vec<String> = self
+            .store()?
+            .values
+            .keys()
+            .filter(|k| re.as_ref().is_some_and(|re| re.is_match(k)))
+            .take(limit)
+            .cloned()
+            .collect();
+        Ok(self.remember(keys))
+    }
+
+    fn random_key(&self) -> fuse::DriverResult<Option<String>> {
+        let store = self.store()?;
+        if store.values.is_empty() {
+            return Ok(None);
+        }
+        // Random enough to pick a key without pulling in a crate for it.
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos() as usize;
+        Ok(store.values.keys().nth(nanos % store.values.len()).cloned())
+    }
+
+    fn count_keys(&self, _sample: u64) -> fuse::DriverResult<u64> {
+        // Every key is the user's, so there's nothing to estimate.
+        Ok(self.store()?.values.len() as u64)
+    }
+
+    fn usage(&self) -> fuse::DriverResult<fuse::Usage> {
+        let store = self.store()?;
+        Ok(fuse::Usage {
+            keys: store.values.len() as u64,
+            memory: Some(
+                store
+                    .values
+                    .iter()
+                    .map(|(k, v)| (k.len() + v.bytes().len()) as u64)
+                    .sum(),
+            ),
+            memory_limit: None,
+        })
+    }
+
+    fn key_info(&self, key: &str) -> fuse::DriverResult<Option<fuse::KeyInfo>> {
+        let store = self.store()?;
+        let value = match store.values.get(key) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        Ok(Some(fuse::KeyInfo {
+            ttl: store
+                .expiries
+                .get(key)
+                .map(|at| at.saturating_duration_since(Instant::now()).as_secs()),
+            kind: match value {
+                Value::String(_) => "string",
+                Value::List(_) => "list",
+                Value::Set(_) => "set",
+            }
+            .to_string(),
+            encoding: "mem".to_string(),
+            memory: None,
+        }))
+    }
+
+    fn modified(&self, key: &str) -> fuse::DriverResult<Option<SystemTime>> {
+        Ok(self.store()?.modified.get(key).copied())
+    }
+
+    fn redirect_of(&self, key: &str) -> fuse::DriverResult<Option<String>> {
+        Ok(self.store()?.redirects.get(key).map(|(to, _)| to.clone()))
+    }
+
+    fn subscribe(&self, name: &str) -> fuse::DriverResult<Box<dyn fuse::Subscription>> {
+        let (sender, receiver) = channel();
+        let alive = Arc::new(());
+        self.store()?
+            .subscribers
+            .entry(name.to_string())
+            .or_insert_with(Vec::new)
+            .push(Subscriber {
+                messages: sender,
+                alive: Arc::downgrade(&alive),
+            });
+        Ok(Box::new(Subscription {
+            messages: receiver,
+            _alive: alive,
+        }))
+    }
+
+    fn channels(&self) -> fuse::DriverResult<Vec<String>> {
+        Ok(self.store()?.subscribers.keys().cloned().collect())
+    }
+
+    fn changes(&self) -> fuse::DriverResult<Box<dyn fuse::Subscription>> {
+        let (sender, receiver) = channel();
+        let alive = Arc::new(());
+        self.store()?.watchers.push(Subscriber {
+            messages: sender,
+            alive: Arc::downgrade(&alive),
+        });
+        Ok(Box::new(Subscription {
+            messages: receiver,
+            _alive: alive,
+        }))
+    }
+
+    fn consume(&self, queue: &str) -> fuse::DriverResult<Box<dyn fuse::Subscription>> {
+        let (sender, receiver) = channel();
+        let alive = Arc::new(());
+        self.store()?
+            .consumers
+            .entry(queue.to_string())
+            .or_insert_with(Vec::new)
+            .push(Subscriber {
+                messages: sender,
+                alive: Arc::downgrade(&alive),
+            });
+        Ok(Box::new(Consumer {
+            store: self.store.clone(),
+            queue: queue.to_string(),
+            wakes: receiver,
+            _alive: alive,
+        }))
+    }
+}
+
+impl fuse::KVLocker for MemDriver {
+    fn acquire_lock(
+        &self,
+        name: &str,
+        owner: &str,
+        mode: LockMode,
+        ttl: Option<Duration>,
+    ) -> fuse::DriverResult<fuse::LockOutcome> {
+        let mut store = self.store()?;
+        if store.locks.contains_key(name) {
+            return Ok(fuse::LockOutcome::Held);
+        }
+        if mode == LockMode::ParentBlocking {
+            let nested = format!("{}/", name);
+            let blocked = store
+                .locks
+                .keys()
+                .any(|held| held.starts_with(&nested) || name.starts_with(&format!("{}/", held)));
+            if blocked {
+                return Ok(fuse::LockOutcome::Blocked);
+            }
+        }
+        store.locks.insert(
+            name.to_string(),
+            (owner.to_string(), ttl.map(|t| Instant::now() + t)),
+        );
+        Ok(fuse::LockOutcome::Acquired)
+    }
+
+    fn release_lock(&self, name: &str, owner: &str) -> fuse::DriverResult<bool> {
+        let mut store = self.store()?;
+        match store.locks.get(name) {
+            Some((held_by, _)) if held_by == owner => {
+                store.locks.remove(name);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn lock_owner(&self, name: &str) -> fuse::DriverResult<Option<String>> {
+        Ok(self
+            .store()?
+            .locks
+            .get(name)
+            .map(|(owner, _)| owner.clone()))
+    }
+
+    fn list_locks(&self, prefix: &str) -> fuse::DriverResult<Vec<String>> {
+        Ok(self
+            .store()?
+            .locks
+            .keys()
+            .filter(|name| name.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+}
+
+impl fuse::KVWriter for MemDriver {
+    fn preallocate(&self, key: &str, len: u64) -> fuse::DriverResult<()> {
+        let mut store = self.store()?;
+        let value = store.string_mut("preallocate", key)?;
+        if (value.len() as u64) < len {
+            value.resize(len as usize, 0);
+        }
+        Ok(())
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> fuse::DriverResult<()> {
+        let mut store = self.store()?;
+        // Setting a value clears its TTL, as SET does.
+        store.expiries.remove(key);
+        store.touch(key);
+        store
+            .values
+            .insert(key.to_string(), Value::String(value.to_vec()));
+        Ok(())
+    }
+
+    fn write_range(&self, key: &str, offset: u64, data: &[u8]) -> fuse::DriverResult<()> {
+        let mut store = self.store()?;
+        let value = store.string_mut("write_range", key)?;
+        let (start, end) = (offset as usize, offset as usize + data.len());
+        if value.len() < end {
+            value.resize(end, 0);
+        }
+        value[start..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn append(&self, key: &str, separator: &[u8], data: &[u8]) -> fuse::DriverResult<()> {
+        let mut store = self.store()?;
+        let existed = store.values.contains_key(key);
+        let value = store.string_mut("append", key)?;
+        if existed {
+            value.extend_from_slice(separator);
+        }
+        value.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn trim_newline(&self, key: &str, len: u64) -> fuse::DriverResult<()> {
+        if let Some(Value::String(value)) = self.store()?.values.get_mut(key) {
+            if len > 0 && value.len() as u64 == len && value.last() == Some(&b'\n') {
+                value.pop();
+            }
+        }
+        Ok(())
+    }
+
+    fn rename(
+        &self,
+        from: &str,
+        to: &str,
+        kind: Option<fuse::ValueKind>,
+        replace: bool,
+    ) -> fuse::DriverResult<fuse::RenameOutcome> {
+        let mut store = self.store()?;
+        let value = match store.values.get(from) {
+            Some(v) => v.clone(),
+            None => return Ok(fuse::RenameOutcome::Missing),
+        };
+        if !replace && store.values.contains_key(to) {
+            return Ok(fuse::RenameOutcome::Exists);
+        }
+        let (value, keep_ttl) = match (kind, value) {
+            (None, v) => (v, true),
+            (Some(k), v) if k == v.kind() => (v, true),
+            (Some(fuse::ValueKind::List), Value::String(v)) => (
+                Value::List(vec![String::from_utf8_lossy(&v).to_string()]),
+                false,
+            ),
+            (Some(fuse::ValueKind::String), Value::List(items)) => {
+                (Value::String(items.join("\n").into_bytes()), false)
+            }
+            _ => return Ok(fuse::RenameOutcome::Unconvertible),
+        };
+        let expiry = store.expiries.get(from).copied();
+        let tags: Vec<String> = store
+            .tags
+            .iter()
+            .filter(|(_, keys)| keys.contains(from))
+            .map(|(tag, _)| tag.clone())
+            .collect();
+        store.remove(from);
+        store.remove(to);
+        store.values.insert(to.to_string(), value);
+        store.touch(to);
+        if let (Some(at), true) = (expiry, keep_ttl) {
+            store.expiries.insert(to.to_string(), at);
+        }
+        for tag in tags {
+            store.tags.entry(tag).or_default().insert(to.to_string());
+        }
+        self.names_by_ino
+            .lock()
+            .unwrap()
+            .remove(&fuse::kv_ino(from));
+        Ok(fuse::RenameOutcome::Renamed)
+    }
+
+    fn incr_by(&self, key: &str, delta: i64) -> fuse::DriverResult<i64> {
+        let mut store = self.store()?;
+        let value = store.string_mut("incr_by", key)?;
+        let wrong_type = || fuse::DriverError::WrongType("incr_by".to_string(), key.to_string());
+        let current = match value.is_empty() {
+            true => 0,
+            false => std::str::from_utf8(value)
+                .ok()
+                .and_then(|v| v.parse::<i64>().ok())
+                .ok_or_else(wrong_type)?,
+        };
+        let result = current.checked_add(delta).ok_or_else(wrong_type)?;
+        *value = result.to_string().into_bytes();
+        Ok(result)
+    }
+
+    fn push(&self, queue: &str, items: &[Vec<u8>]) -> fuse::DriverResult<()> {
+        if items.is_empty() {
+            return Ok(());
+        }
+        let mut store = self.store()?;
+        store.touch(queue);
+        match store
+            .values
+            .entry(queue.to_string())
+            .or_insert_with(|| Value::List(vec![]))
+        {
+            Value::List(list) => {
+                for item in items {
+                    list.insert(0, String::from_utf8_lossy(item).into_owned());
+                }
+            }
+            _ => {
+                return Err(fuse::DriverError::WrongType(
+                    "LPUSH".to_string(),
+                    queue.to_string(),
+                ))
+            }
+        }
+        for consumer in store.consumers.get(queue).map_or(&[][..], |c| &c[..]) {
+            let _ = consumer.messages.send(vec![]);
+        }
+        Ok(())
+    }
+
+    fn publish(&self, name: &str, message: &[u8]) -> fuse::DriverResult<u64> {
+        let store = self.store()?;
+        let subscribers = store.subscribers.get(name).map_or(&[][..], |s| &s[..]);
+        Ok(subscribers
+            .iter()
+            .filter(|s| s.messages.send(message.to_vec()).is_ok())
+            .count() as u64)
+    }
+
+    fn leave_redirect(&self, from: &str, to: &str, ttl: Duration) -> fuse::DriverResult<()> {
+        self.store()?
+            .redirects
+            .insert(from.to_string(), (to.to_string(), Instant::now() + ttl));
+        Ok(())
+    }
+
+    fn expire(&self, key: &str, ttl: Option<Duration>) -> fuse::DriverResult<bool> {
+        let mut store = self.store()?;
+        if !store.values.contains_key(key) {
+            return Ok(false);
+        }
+        store.changed(key);
+        Ok(match ttl {
+            Some(t) => {
+                store.expiries.insert(key.to_string(), Instant::now() + t);
+                true
+            }
+            None => store.expiries.remove(key).is_some(),
+        })
+    }
+
+    fn delete(&self, keys: &[String]) -> fuse::DriverResult<u64> {
+        let mut store = self.store()?;
+        let mut names_by_ino = self.names_by_ino.lock().unwrap();
+        let mut deleted = 0;
+        for key in keys {
+            if store.remove(key) {
+                deleted += 1;
+            }
+            names_by_ino.remove(&fuse::kv_ino(key));
+        }
+        Ok(deleted)
+    }
+
+    // The store is locked throughout, so nothing sees the writes half done.
+    fn transact(&self, writes: &[(String, Option<Vec<u8>>)]) -> fuse::DriverResult<()> {
+        let mut store = self.store()?;
+        let mut names_by_ino = self.names_by_ino.lock().unwrap();
+        for (key, value) in writes {
+            match value {
+                Some(v) => {
+                    store.expiries.remove(key);
+                    store.touch(key);
+                    store.values.insert(key.clone(), Value::String(v.clone()));
+                }
+                None => {
+                    store.remove(key);
+                    names_by_ino.remove(&fuse::kv_ino(key));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_items(
+        &self,
+        key: &str,
+        kind: fuse::ValueKind,
+        items: &[String],
+        append: bool,
+    ) -> fuse::DriverResult<()> {
+        let mut store = self.store()?;
+        if !append {
+            store.values.remove(key);
+            store.expiries.remove(key);
+            store.modified.remove(key);
+            store.changed(key);
+        }
+        // As in Redis, a list or set with no elements doesn't exist.
+        if items.is_empty() {
+            return Ok(());
+        }
+        store.touch(key);
+        let wrong_type =
+            || fuse::DriverError::WrongType("write_items".to_string(), key.to_string());
+        match kind {
+            fuse::ValueKind::String => {
+                store.values.insert(
+                    key.to_string(),
+                    Value::String(items.join("\n").into_bytes()),
+                );
+            }
+            fuse::ValueKind::List => match store
+                .values
+                .entry(key.to_string())
+                .or_insert_with(|| Value::List(vec![]))
+            {
+                Value::List(list) => list.extend(items.iter().cloned()),
+                _ => return Err(wrong_type()),
+            },
+            fuse::ValueKind::Set => match store
+                .values
+                .entry(key.to_string())
+                .or_insert_with(|| Value::Set(BTreeSet::new()))
+            {
+                Value::Set(set) => set.extend(items.iter().cloned()),
+                _ => return Err(wrong_type()),
+            },
+            // No value here is ever a stream.
+            fuse::ValueKind::Stream => return Err(wrong_type()),
+        }
+        Ok(())
+    }
+}
+
+impl fuse::KVTagger for MemDriver {
+    fn tag(&self, key: &str, tag: &str) -> fuse::DriverResult<()> {
+        self.store()?
+            .tags
+            .entry(tag.to_string())
+            .or_default()
+            .insert(key.to_string());
+        Ok(())
+    }
+
+    fn untag(&self, key: &str, tag: &str) -> fuse::DriverResult<()> {
+        let mut store = self.store()?;
+        if let Some(keys) = store.tags.get_mut(tag) {
+            keys.remove(key);
+            if keys.is_empty() {
+                store.tags.remove(tag);
+            }
+        }
+        Ok(())
+    }
+
+    fn tags_of(&self, key: &str) -> fuse::DriverResult<Vec<String>> {
+        Ok(self
+            .store()?
+            .tags
+            .iter()
+            .filter(|(_, keys)| keys.contains(key))
+            .map(|(tag, _)| tag.clone())
+            .collect())
+    }
+
+    fn tagged(&self, tag: &str) -> fuse::DriverResult<Vec<String>> {
+        Ok(self
+            .store()?
+            .tags
+            .get(tag)
+            .map(|keys| keys.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn list_tags(&self) -> fuse::DriverResult<Vec<String>> {
+        Ok(self.store()?.tags.keys().cloned().collect())
+    }
+}
+
+impl MemDriver {
+    // The store, with anything that has expired already gone, once any
+    // simulated latency has passed. Fails instead as often as error_rate says.
+    fn store(&self) -> fuse::DriverResult<MutexGuard<'_, Store>> {
+        self.chaos.strike()?;
+        let mut store = self.store.lock().unwrap();
+        store.purge();
+        Ok(store)
+    }
+
+    // Refs for keys, remembering which key each inode was handed out for.
+    fn remember(&self, keys: Vec<String>) -> Vec<fuse::KVRef> {
+        let mut names_by_ino = self.names_by_ino.lock().unwrap();
+        keys.into_iter()
+            .map(|key| {
+                let ino = fuse::kv_ino(&key);
+                names_by_ino.insert(ino, key.clone());
+                fuse::KVRef { ino, key }
+            })
+            .collect()
+    }
+}
+
+pub fn open(config: &Config) -> Result<Arc<dyn Driver>, Box<dyn Error>> {
+    log::info!("Keeping keys in memory, they won't outlive the mount.");
+    let chaos = Chaos::parse(&config.servers[0].url)?;
+    if chaos.enabled() {
+        log::warn!(
+            "Simulating a degraded backend: {}ms latency, {}ms jitter, {} error rate.",
+            chaos.latency,
+            chaos.jitter,
+            chaos.error_rate
+        );
+    }
+    Ok(Arc::new(MemDriver {
+        chaos,
+        store: Arc::new(Mutex::new(Store::default())),
+        names_by_ino: Mutex::new(HashMap::new()),
+    }))
+}
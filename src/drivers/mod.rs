@@ -1 +1,148 @@
+// Backends fusekv can mount. Every backend implements Driver, and open picks
+// one by the scheme of the first server URL, so adding a backend only means
+// adding its module and an entry in SCHEMES. Besides redis, each is behind a
+// Cargo feature of the same name.
+#[cfg(feature = "external")]
+pub mod external;
+#[cfg(feature = "mem")]
+pub mod mem;
+#[cfg(feature = "offload")]
+pub mod offload;
+pub mod prefix;
 pub mod redis;
+
+use crate::config::{Config, ConfigError, ExternalDriver, Mirror, ReadPreference, RedisServer};
+use crate::fuse::{KVLocker, KVReader, KVTagger, KVWriter};
+
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+// Drivers are shared with background threads, eg. to flush coalesced writes.
+pub trait Driver: KVReader + KVWriter + KVLocker + KVTagger + Send + Sync {}
+
+impl<T: KVReader + KVWriter + KVLocker + KVTagger + Send + Sync> Driver for T {}
+
+type Opener = fn(&Config) -> Result<Arc<dyn Driver>, Box<dyn Error>>;
+
+// The driver for each URL scheme.
+const SCHEMES: &[(&str, Opener)] = &[
+    ("redis", redis::open),
+    #[cfg(feature = "tls")]
+    ("rediss", redis::open),
+    ("redis+unix", redis::open),
+    ("unix", redis::open),
+    #[cfg(feature = "mem")]
+    ("mem", mem::open),
+];
+
+// The feature each scheme needs, for schemes that fusekv may be built without.
+const SCHEME_FEATURES: &[(&str, &str)] = &[("rediss", "tls"), ("mem", "mem")];
+
+// Open the driver config asks for, offloading big values if it says where to,
+// and limited to keys under its prefix if it has one.
+pub fn open(config: &Config) -> Result<Arc<dyn Driver>, Box<dyn Error>> {
+    let mut driver = open_backend(config)?;
+    if let Some(dir) = &config.offload_dir {
+        log::info!(
+            "Offloading values over {} bytes to {}.",
+            config.offload_threshold,
+            dir.display()
+        );
+        driver = offload(driver, dir, config)?;
+    }
+    if config.prefix.is_empty() {
+        return Ok(driver);
+    }
+    Ok(Arc::new(prefix::PrefixDriver::new(driver, &config.prefix)))
+}
+
+// Open the driver for mirror: its server, with the mount's other settings but
+// none of its credentials, TLS settings, replicas, offloading, or prefix.
+// Mirrors are only read, so it's opened read-only, without the writes opening
+// a mount can make, eg. migrating inode mappings.
+pub fn open_mirror(config: &Config, mirror: &Mirror) -> Result<Arc<dyn Driver>, Box<dyn Error>> {
+    log::info!("Mirroring {} under /mirror/{}.", mirror.url, mirror.name);
+    open(&Config {
+        servers: vec![RedisServer {
+            url: mirror.url.clone(),
+        }],
+        cluster_mode: false,
+        external: None,
+        prefix: mirror.prefix.clone(),
+        offload_dir: None,
+        database: None,
+        redis_user: None,
+        redis_password: None,
+        redis_password_file: None,
+        redis_password_command: None,
+        tls_ca_cert: None,
+        tls_client_cert: None,
+        tls_client_key: None,
+        tls_insecure: false,
+        reset_ino_cache: false,
+        replicas: vec![],
+        discover_replicas: false,
+        read_preference: ReadPreference::Primary,
+        read_only: true,
+        ..config.clone()
+    })
+}
+
+// The external driver if there is one, or whichever serves the scheme of the
+// first server.
+fn open_backend(config: &Config) -> Result<Arc<dyn Driver>, Box<dyn Error>> {
+    if let Some(external) = &config.external {
+        log::info!("Using external driver `{}`.", external.command);
+        return open_external(external);
+    }
+    let server = match config.servers.first() {
+        Some(v) => v,
+        None => return Err(Box::new(ConfigError::NoDriver)),
+    };
+    match SCHEMES.iter().find(|(s, _)| *s == server.url.scheme()) {
+        Some((_, opener)) => opener(config),
+        None => match SCHEME_FEATURES
+            .iter()
+            .find(|(s, _)| *s == server.url.scheme())
+        {
+            Some((_, feature)) => Err(Box::new(ConfigError::NotBuiltIn(feature))),
+            None => Err(Box::new(ConfigError::UnknownScheme(
+                server.url.scheme().to_string(),
+            ))),
+        },
+    }
+}
+
+#[cfg(feature = "external")]
+fn open_external(external: &ExternalDriver) -> Result<Arc<dyn Driver>, Box<dyn Error>> {
+    Ok(Arc::new(external::ExternalDriver::spawn(external)?))
+}
+
+#[cfg(not(feature = "external"))]
+fn open_external(_external: &ExternalDriver) -> Result<Arc<dyn Driver>, Box<dyn Error>> {
+    Err(Box::new(ConfigError::NotBuiltIn("external")))
+}
+
+// driver, with values over the threshold in config stored as files in dir.
+#[cfg(feature = "offload")]
+fn offload(
+    driver: Arc<dyn Driver>,
+    dir: &Path,
+    config: &Config,
+) -> Result<Arc<dyn Driver>, Box<dyn Error>> {
+    Ok(Arc::new(offload::OffloadDriver::new(
+        driver,
+        dir.to_path_buf(),
+        config.offload_threshold,
+    )?))
+}
+
+#[cfg(not(feature = "offload"))]
+fn offload(
+    _driver: Arc<dyn Driver>,
+    _dir: &Path,
+    _config: &Config,
+) -> Result<Arc<dyn Driver>, Box<dyn Error>> {
+    Err(Box::new(ConfigError::NotBuiltIn("offload")))
+}
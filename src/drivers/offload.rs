@@ -0,0 +1,638 @@
+// Wraps another driver to keep values bigger than a threshold out of it, in
+// files under a local directory, leaving only a pointer to the file as the
+// key's value. Reads through the mount find the value behind the pointer, so
+// huge blobs don't take up Redis memory but live at the same paths.
+//
+// Each value written gets a file of its own, removed once the key is
+// overwritten, renamed over, or deleted through the mount. Files of keys that
+// expire, or are deleted or copied some other way, are left behind.
+use crate::config::LockMode;
+use crate::drivers::Driver;
+use crate::fuse::{
+    CommandCount, DriverCapabilities, DriverError, DriverResult, KVEntry, KVLocker, KVReader,
+    KVRef, KVTagger, KVWriter, KeyInfo, LockOutcome, PoolState, RenameOutcome, ServerInfo,
+    StreamEntry, Subscription, TypedKVRef, Usage, ValueKind,
+};
+
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// What values pointing at a file start with, followed by the file's name. The
+// NUL keeps it from being mistaken for text someone stored.
+const POINTER_PREFIX: &[u8] = b"\0fusekv:offloaded:";
+
+// Length of the names of files values are offloaded to, in hex digits.
+const BLOB_NAME_LEN: usize = 32;
+
+const POINTER_LEN: u64 = (POINTER_PREFIX.len() + BLOB_NAME_LEN) as u64;
+
+pub struct OffloadDriver {
+    inner: Arc<dyn Driver>,
+    dir: PathBuf,
+    // Values bigger than this many bytes are offloaded.
+    threshold: u64,
+    // Tells apart files named in the same nanosecond.
+    written: AtomicU64,
+}
+
+impl OffloadDriver {
+    pub fn new(inner: Arc<dyn Driver>, dir: PathBuf, threshold: u64) -> io::Result<OffloadDriver> {
+        fs::create_dir_all(&dir)?;
+        Ok(OffloadDriver {
+            inner,
+            dir,
+            threshold,
+            written: AtomicU64::new(0),
+        })
+    }
+
+    // The file the value of key was offloaded to, if it was. Only strings can
+    // be pointers, so keys of other types aren't.
+    fn blob_of(&self, key: &str) -> DriverResult<Option<String>> {
+        match self.inner.read_range(key, 0, POINTER_LEN) {
+            Ok(Some((data, len))) if len == POINTER_LEN => Ok(blob_name(&data)),
+            Ok(_) | Err(DriverError::WrongType(..)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // A name for a new file to offload the value of key to.
+    fn new_blob_name(&self, key: &str) -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let n = self.written.fetch_add(1, Ordering::SeqCst);
+        let hash = seahash::hash(format!("{}\0{}", key, n).as_bytes());
+        format!("{:016x}{:016x}", hash, nanos)
+    }
+
+    fn blob_path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    fn read_blob(&self, key: &str, name: &str) -> DriverResult<Vec<u8>> {
+        fs::read(self.blob_path(name)).map_err(|e| blob_error(key, name, e))
+    }
+
+    // Write value to a new file, returning its name. It's written under a
+    // temporary name first, so a pointer never reaches a partial file.
+    fn write_blob(&self, key: &str, value: &[u8]) -> DriverResult<String> {
+        let name = self.new_blob_name(key);
+        let tmp = self.blob_path(&format!("{}.tmp", name));
+        fs::write(&tmp, value)
+            .and_then(|()| fs::rename(&tmp, self.blob_path(&name)))
+            .map_err(|e| blob_error(key, &name, e))?;
+        Ok(name)
+    }
+
+    // Remove the file a value was offloaded to, once nothing points at it. A
+    // failure only leaves the file behind, so is just logged.
+    fn remove_blob(&self, name: &str) {
+        if let Err(e) = fs::remove_file(self.blob_path(name)) {
+            if e.kind() != io::ErrorKind::NotFound {
+                log::warn!("Error removing offloaded value {}: {}", name, e);
+            }
+        }
+    }
+
+    // entry with the value behind it if it's a pointer.
+    fn resolve(&self, entry: Option<KVEntry>) -> DriverResult<Option<KVEntry>> {
+        let mut entry = match entry {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        if entry.kind == ValueKind::String && entry.val.len() as u64 == POINTER_LEN {
+            if let Some(name) = blob_name(&entry.val) {
+                entry.val = self.read_blob(&entry.key, &name)?;
+            }
+        }
+        Ok(Some(entry))
+    }
+
+    // Open the file the value of key was offloaded to, for changing in place.
+    fn open_blob(&self, key: &str, name: &str) -> DriverResult<fs::File> {
+        fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.blob_path(name))
+            .map_err(|e| blob_error(key, name, e))
+    }
+}
+
+// The name of the file value points to, if it's a pointer.
+fn blob_name(value: &[u8]) -> Option<String> {
+    let name = std::str::from_utf8(value.strip_prefix(POINTER_PREFIX)?).ok()?;
+    if name.len() != BLOB_NAME_LEN || !name.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+fn pointer(name: &str) -> Vec<u8> {
+    [POINTER_PREFIX, name.as_bytes()].concat()
+}
+
+fn blob_error(key: &str, name: &str, e: io::Error) -> DriverError {
+    match e.kind() {
+        io::ErrorKind::NotFound => DriverError::Corrupt(
+            "OFFLOAD".to_string(),
+            key.to_string(),
+            format!("offloaded value {} is missing", name),
+        ),
+        _ => DriverError::Unavailable("OFFLOAD".to_string(), key.to_string(), e.to_string()),
+    }
+}
+
+impl KVReader for OffloadDriver {
+    fn capabilities(&self) -> DriverCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn get_by_name(&self, name: String, ino: u64) -> DriverResult<Option<KVEntry>> {
+        self.resolve(self.inner.get_by_name(name, ino)?)
+    }
+
+    fn get_by_ino(&self, ino: u64) -> DriverResult<Option<KVEntry>> {
+        self.resolve(self.inner.get_by_ino(ino)?)
+    }
+
+    fn inos_of(&self, keys: &[String]) -> DriverResult<Vec<u64>> {
+        self.inner.inos_of(keys)
+    }
+
+    fn known_inos(&self, keys: &[String]) -> Vec<Option<u64>> {
+        self.inner.known_inos(keys)
+    }
+
+    fn list_keys(&self, offset: i64, limit: i64) -> DriverResult<Vec<KVRef>> {
+        self.inner.list_keys(offset, limit)
+    }
+
+    fn list_keys_until(
+        &self,
+        offset: i64,
+        limit: i64,
+        deadline: Option<Instant>,
+    ) -> DriverResult<(Vec<KVRef>, bool)> {
+        self.inner.list_keys_until(offset, limit, deadline)
+    }
+
+    fn read_range(
+        &self,
+        key: &str,
+        offset: u64,
+        size: u64,
+    ) -> DriverResult<Option<(Vec<u8>, u64)>> {
+        let name = match self.blob_of(key)? {
+            Some(v) => v,
+            None => return self.inner.read_range(key, offset, size),
+        };
+        let mut file =
+            fs::File::open(self.blob_path(&name)).map_err(|e| blob_error(key, &name, e))?;
+        let mut data = vec![];
+        let len = file
+            .metadata()
+            .and_then(|m| {
+                file.seek(SeekFrom::Start(offset.min(m.len())))
+                    .map(|_| m.len())
+            })
+            .and_then(|len| (&mut file).take(size).read_to_end(&mut data).map(|_| len))
+            .map_err(|e| blob_error(key, &name, e))?;
+        Ok(Some((data, len)))
+    }
+
+    fn ping(&self) -> DriverResult<()> {
+        self.inner.ping()
+    }
+
+    fn server_info(&self) -> DriverResult<ServerInfo> {
+        self.inner.server_info()
+    }
+
+    fn is_replica(&self) -> DriverResult<bool> {
+        self.inner.is_replica()
+    }
+
+    fn modules(&self) -> DriverResult<Vec<String>> {
+        self.inner.modules()
+    }
+
+    fn json_get(&self, key: &str, path: &str) -> DriverResult<Option<String>> {
+        self.inner.json_get(key, path)
+    }
+
+    fn dump(&self, key: &str) -> DriverResult<Option<(Vec<u8>, u64)>> {
+        self.inner.dump(key)
+    }
+
+    fn server_time(&self) -> DriverResult<Duration> {
+        self.inner.server_time()
+    }
+
+    fn get_as_of(&self, key: &str, as_of: u64) -> DriverResult<Option<Vec<u8>>> {
+        self.inner.get_as_of(key, as_of)
+    }
+
+    fn list_versioned_keys(&self, as_of: u64, limit: i64) -> DriverResult<Vec<String>> {
+        self.inner.list_versioned_keys(as_of, limit)
+    }
+
+    fn match_keys(&self, pattern: &str, limit: i64) -> DriverResult<Vec<KVRef>> {
+        self.inner.match_keys(pattern, limit)
+    }
+
+    fn match_keys_until(
+        &self,
+        pattern: &str,
+        offset: i64,
+        limit: i64,
+        deadline: Option<Instant>,
+    ) -> DriverResult<(Vec<KVRef>, bool)> {
+        self.inner
+            .match_keys_until(pattern, offset, limit, deadline)
+    }
+
+    fn scan_keys(
+        &self,
+        pattern: &str,
+        cursor: &str,
+        count: usize,
+    ) -> DriverResult<(Vec<KVRef>, Option<String>)> {
+        self.inner.scan_keys(pattern, cursor, count)
+    }
+
+    // Sized like sizes.
+    fn scan_typed_keys(
+        &self,
+        pattern: &str,
+        cursor: &str,
+        count: usize,
+    ) -> DriverResult<(Vec<TypedKVRef>, Option<String>)> {
+        let (mut refs, next) = self.inner.scan_typed_keys(pattern, cursor, count)?;
+        for r in refs.iter_mut() {
+            if r.kind != "string" || r.size != POINTER_LEN {
+                continue;
+            }
+            if let Some(name) = self.blob_of(&r.key)? {
+                r.size = fs::metadata(self.blob_path(&name))
+                    .map_err(|e| blob_error(&r.key, &name, e))?
+                    .len();
+            }
+        }
+        Ok((refs, next))
+    }
+
+    fn random_key(&self) -> DriverResult<Option<String>> {
+        self.inner.random_key()
+    }
+
+    fn count_keys(&self, sample: u64) -> DriverResult<u64> {
+        self.inner.count_keys(sample)
+    }
+
+    fn usage(&self) -> DriverResult<Usage> {
+        self.inner.usage()
+    }
+
+    fn key_info(&self, key: &str) -> DriverResult<Option<KeyInfo>> {
+        self.inner.key_info(key)
+    }
+
+    fn key_infos(&self, keys: &[String]) -> DriverResult<Vec<Option<KeyInfo>>> {
+        self.inner.key_infos(keys)
+    }
+
+    fn modified(&self, key: &str) -> DriverResult<Option<SystemTime>> {
+        self.inner.modified(key)
+    }
+
+    fn redirect_of(&self, key: &str) -> DriverResult<Option<String>> {
+        self.inner.redirect_of(key)
+    }
+
+    fn subscribe(&self, channel: &str) -> DriverResult<Box<dyn Subscription>> {
+        self.inner.subscribe(channel)
+    }
+
+    fn channels(&self) -> DriverResult<Vec<String>> {
+        self.inner.channels()
+    }
+
+    fn changes(&self) -> DriverResult<Box<dyn Subscription>> {
+        self.inner.changes()
+    }
+
+    // Only strings are offloaded, never the lists queues are.
+    fn consume(&self, queue: &str) -> DriverResult<Box<dyn Subscription>> {
+        self.inner.consume(queue)
+    }
+
+    fn stream_after(
+        &self,
+        key: &str,
+        after: &str,
+        block: Option<Duration>,
+    ) -> DriverResult<Vec<StreamEntry>> {
+        self.inner.stream_after(key, after, block)
+    }
+
+    // Only values exactly as long as a pointer can be one, so only those are
+    // looked at again.
+    fn sizes(&self, keys: &[String]) -> DriverResult<Vec<u64>> {
+        let mut sizes = self.inner.sizes(keys)?;
+        for (key, size) in keys.iter().zip(sizes.iter_mut()) {
+            if *size != POINTER_LEN {
+                continue;
+            }
+            if let Some(name) = self.blob_of(key)? {
+                *size = fs::metadata(self.blob_path(&name))
+                    .map_err(|e| blob_error(key, &name, e))?
+                    .len();
+            }
+        }
+        Ok(sizes)
+    }
+
+    fn idle_times(&self, keys: &[String]) -> DriverResult<Vec<u64>> {
+        self.inner.idle_times(keys)
+    }
+
+    fn trace(&self) -> DriverResult<Vec<String>> {
+        self.inner.trace()
+    }
+
+    fn command_counts(&self) -> DriverResult<Vec<CommandCount>> {
+        self.inner.command_counts()
+    }
+
+    fn pool_state(&self) -> DriverResult<PoolState> {
+        self.inner.pool_state()
+    }
+
+    fn forget_cached(&self) {
+        self.inner.forget_cached()
+    }
+}
+
+impl KVLocker for OffloadDriver {
+    fn acquire_lock(
+        &self,
+        name: &str,
+        owner: &str,
+        mode: LockMode,
+        ttl: Option<Duration>,
+    ) -> DriverResult<LockOutcome> {
+        self.inner.acquire_lock(name, owner, mode, ttl)
+    }
+
+    fn release_lock(&self, name: &str, owner: &str) -> DriverResult<bool> {
+        self.inner.release_lock(name, owner)
+    }
+
+    fn lock_owner(&self, name: &str) -> DriverResult<Option<String>> {
+        self.inner.lock_owner(name)
+    }
+
+    fn list_locks(&self, prefix: &str) -> DriverResult<Vec<String>> {
+        self.inner.list_locks(prefix)
+    }
+
+    fn hold_shared(&self, key: &str, owner: &str, ttl: Duration) -> DriverResult<()> {
+        self.inner.hold_shared(key, owner, ttl)
+    }
+
+    fn release_shared(&self, key: &str, owner: &str) -> DriverResult<()> {
+        self.inner.release_shared(key, owner)
+    }
+}
+
+impl KVTagger for OffloadDriver {
+    fn tag(&self, key: &str, tag: &str) -> DriverResult<()> {
+        self.inner.tag(key, tag)
+    }
+
+    fn untag(&self, key: &str, tag: &str) -> DriverResult<()> {
+        self.inner.untag(key, tag)
+    }
+
+    fn tags_of(&self, key: &str) -> DriverResult<Vec<String>> {
+        self.inner.tags_of(key)
+    }
+
+    fn tagged(&self, tag: &str) -> DriverResult<Vec<String>> {
+        self.inner.tagged(tag)
+    }
+
+    fn list_tags(&self) -> DriverResult<Vec<String>> {
+        self.inner.list_tags()
+    }
+}
+
+impl KVWriter for OffloadDriver {
+    fn preallocate(&self, key: &str, len: u64) -> DriverResult<()> {
+        let name = match self.blob_of(key)? {
+            Some(v) => v,
+            None => return self.inner.preallocate(key, len),
+        };
+        let file = self.open_blob(key, &name)?;
+        let grow = match file.metadata() {
+            Ok(m) => m.len() < len,
+            Err(e) => return Err(blob_error(key, &name, e)),
+        };
+        if grow {
+            file.set_len(len).map_err(|e| blob_error(key, &name, e))?;
+        }
+        Ok(())
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> DriverResult<()> {
+        let old = self.blob_of(key)?;
+        if value.len() as u64 > self.threshold {
+            let name = self.write_blob(key, value)?;
+            if let Err(e) = self.inner.set(key, &pointer(&name)) {
+                self.remove_blob(&name);
+                return Err(e);
+            }
+            log::debug!("Offloaded {} bytes of {} to {}", value.len(), key, name);
+        } else {
+            self.inner.set(key, value)?;
+        }
+        if let Some(old) = old {
+            self.remove_blob(&old);
+        }
+        Ok(())
+    }
+
+    // Big values are offloaded before the transaction, and the values they
+    // replace only removed once it succeeds.
+    fn transact(&self, writes: &[(String, Option<Vec<u8>>)]) -> DriverResult<()> {
+        let mut old = vec![];
+        let mut new = vec![];
+        let mut inner = vec![];
+        let result = writes.iter().try_for_each(|(key, value)| {
+            if let Some(name) = self.blob_of(key)? {
+                old.push(name);
+            }
+            let value = match value {
+                Some(v) if v.len() as u64 > self.threshold => {
+                    let name = self.write_blob(key, v)?;
+                    let pointer = pointer(&name);
+                    new.push(name);
+                    Some(pointer)
+                }
+                other => other.clone(),
+            };
+            inner.push((key.clone(), value));
+            Ok(())
+        });
+        let result = result.and_then(|()| self.inner.transact(&inner));
+        let unused = match result {
+            Ok(()) => old,
+            Err(_) => new,
+        };
+        for name in unused {
+            self.remove_blob(&name);
+        }
+        result
+    }
+
+    fn json_set(&self, key: &str, path: &str, json: &str) -> DriverResult<bool> {
+        self.inner.json_set(key, path, json)
+    }
+
+    fn json_del(&self, key: &str, path: &str) -> DriverResult<u64> {
+        self.inner.json_del(key, path)
+    }
+
+    fn restore(&self, key: &str, ttl: u64, payload: &[u8]) -> DriverResult<()> {
+        self.inner.restore(key, ttl, payload)
+    }
+
+    // Offloaded values are changed in place, so big values written a range at
+    // a time don't have to be rewritten whole.
+    fn write_range(&self, key: &str, offset: u64, data: &[u8]) -> DriverResult<()> {
+        let name = match self.blob_of(key)? {
+            Some(v) => v,
+            None => return self.inner.write_range(key, offset, data),
+        };
+        let mut file = self.open_blob(key, &name)?;
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.write_all(data))
+            .map_err(|e| blob_error(key, &name, e))
+    }
+
+    fn append(&self, key: &str, separator: &[u8], data: &[u8]) -> DriverResult<()> {
+        let name = match self.blob_of(key)? {
+            Some(v) => v,
+            None => return self.inner.append(key, separator, data),
+        };
+        let mut file = self.open_blob(key, &name)?;
+        file.seek(SeekFrom::End(0))
+            .and_then(|_| file.write_all(&[separator, data].concat()))
+            .map_err(|e| blob_error(key, &name, e))
+    }
+
+    fn trim_newline(&self, key: &str, len: u64) -> DriverResult<()> {
+        let name = match self.blob_of(key)? {
+            Some(v) => v,
+            None => return self.inner.trim_newline(key, len),
+        };
+        let value = self.read_blob(key, &name)?;
+        if len > 0 && value.len() as u64 == len && value.last() == Some(&b'\n') {
+            let file = self.open_blob(key, &name)?;
+            file.set_len(len - 1)
+                .map_err(|e| blob_error(key, &name, e))?;
+        }
+        Ok(())
+    }
+
+    // A value renamed over is gone, so its file goes too.
+    fn rename(
+        &self,
+        from: &str,
+        to: &str,
+        kind: Option<ValueKind>,
+        replace: bool,
+    ) -> DriverResult<RenameOutcome> {
+        let old = match replace {
+            true => self.blob_of(to)?,
+            false => None,
+        };
+        let outcome = self.inner.rename(from, to, kind, replace)?;
+        if let (RenameOutcome::Renamed, Some(old)) = (outcome, old) {
+            self.remove_blob(&old);
+        }
+        Ok(outcome)
+    }
+
+    fn expire(&self, key: &str, ttl: Option<Duration>) -> DriverResult<bool> {
+        self.inner.expire(key, ttl)
+    }
+
+    fn leave_redirect(&self, from: &str, to: &str, ttl: Duration) -> DriverResult<()> {
+        self.inner.leave_redirect(from, to, ttl)
+    }
+
+    fn delete(&self, keys: &[String]) -> DriverResult<u64> {
+        let mut blobs = vec![];
+        for key in keys {
+            if let Some(name) = self.blob_of(key)? {
+                blobs.push(name);
+            }
+        }
+        let deleted = self.inner.delete(keys)?;
+        for name in blobs {
+            self.remove_blob(&name);
+        }
+        Ok(deleted)
+    }
+
+    fn write_items(
+        &self,
+        key: &str,
+        kind: ValueKind,
+        items: &[String],
+        append: bool,
+    ) -> DriverResult<()> {
+        self.inner.write_items(key, kind, items, append)
+    }
+
+    // Counters are far too small to have been offloaded.
+    fn incr_by(&self, key: &str, delta: i64) -> DriverResult<i64> {
+        self.inner.incr_by(key, delta)
+    }
+
+    fn publish(&self, channel: &str, message: &[u8]) -> DriverResult<u64> {
+        self.inner.publish(channel, message)
+    }
+
+    fn push(&self, queue: &str, items: &[Vec<u8>]) -> DriverResult<()> {
+        self.inner.push(queue, items)
+    }
+
+    fn raw(&self, args: &[String]) -> DriverResult<String> {
+        self.inner.raw(args)
+    }
+
+    fn eval(&self, script: &str, keys: &[String], args: &[String]) -> DriverResult<()> {
+        self.inner.eval(script, keys, args)
+    }
+
+    fn append_to_stream(
+        &self,
+        key: &str,
+        max_len: usize,
+        fields: &[(&str, String)],
+    ) -> DriverResult<()> {
+        self.inner.append_to_stream(key, max_len, fields)
+    }
+
+    fn acl_allows(&self, user: &str, args: &[String]) -> DriverResult<bool> {
+        self.inner.acl_allows(user, args)
+    }
+}
@@ -0,0 +1,499 @@
+// Wraps another driver to only expose the keys starting with a prefix, without
+// the prefix in their names, so teams sharing a backend can each mount their
+// own keys. Lock names are prefixed too, keeping each mount's locks apart.
+// Tags are shared, though only the keys under the prefix are listed as tagged.
+// Pubsub channels are prefixed like keys.
+use crate::config::LockMode;
+use crate::drivers::Driver;
+use crate::fuse::{
+    escape_glob, CommandCount, DriverCapabilities, DriverResult, KVEntry, KVLocker, KVReader,
+    KVRef, KVTagger, KVWriter, KeyInfo, LockOutcome, PoolState, RenameOutcome, ServerInfo,
+    StreamEntry, Subscription, TypedKVRef, Usage, ValueKind,
+};
+
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+// Times to retry picking a random key when it lands outside the prefix.
+const RANDOM_KEY_ATTEMPTS: usize = 10;
+
+pub struct PrefixDriver {
+    inner: Arc<dyn Driver>,
+    prefix: String,
+    // prefix with glob characters escaped, for matching keys under it.
+    glob: String,
+}
+
+impl PrefixDriver {
+    pub fn new(inner: Arc<dyn Driver>, prefix: &str) -> PrefixDriver {
+        PrefixDriver {
+            inner,
+            prefix: prefix.to_string(),
+            glob: escape_glob(prefix),
+        }
+    }
+
+    fn add(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    fn add_all(&self, keys: &[String]) -> Vec<String> {
+        keys.iter().map(|k| self.add(k)).collect()
+    }
+
+    // key without the prefix, or None if it doesn't start with it.
+    fn strip(&self, key: &str) -> Option<String> {
+        key.strip_prefix(&self.prefix).map(String::from)
+    }
+
+    fn strip_all(&self, keys: Vec<String>) -> Vec<String> {
+        keys.iter().filter_map(|k| self.strip(k)).collect()
+    }
+
+    fn strip_refs(&self, refs: Vec<KVRef>) -> Vec<KVRef> {
+        refs.into_iter()
+            .filter_map(|r| {
+                Some(KVRef {
+                    key: self.strip(&r.key)?,
+                    ino: r.ino,
+                })
+            })
+            .collect()
+    }
+
+    fn strip_entry(&self, entry: Option<KVEntry>) -> Option<KVEntry> {
+        let mut entry = entry?;
+        entry.key = self.strip(&entry.key)?;
+        Some(entry)
+    }
+}
+
+// Changes to keys under the prefix, without it.
+struct Changes {
+    inner: Box<dyn Subscription>,
+    prefix: Vec<u8>,
+}
+
+impl Subscription for Changes {
+    fn next_message(&mut self, timeout: Option<Duration>) -> DriverResult<Option<Vec<u8>>> {
+        // Changes to other keys restart the wait, so it can run over timeout
+        // while they keep coming.
+        loop {
+            match self.inner.next_message(timeout)? {
+                Some(key) if key.starts_with(&self.prefix) => {
+                    return Ok(Some(key[self.prefix.len()..].to_vec()))
+                }
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl KVReader for PrefixDriver {
+    fn capabilities(&self) -> DriverCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn get_by_name(&self, name: String, ino: u64) -> DriverResult<Option<KVEntry>> {
+        Ok(self.strip_entry(self.inner.get_by_name(self.add(&name), ino)?))
+    }
+
+    fn get_by_ino(&self, ino: u64) -> DriverResult<Option<KVEntry>> {
+        Ok(self.strip_entry(self.inner.get_by_ino(ino)?))
+    }
+
+    fn inos_of(&self, keys: &[String]) -> DriverResult<Vec<u64>> {
+        self.inner.inos_of(&self.add_all(keys))
+    }
+
+    fn known_inos(&self, keys: &[String]) -> Vec<Option<u64>> {
+        self.inner.known_inos(&self.add_all(keys))
+    }
+
+    fn list_keys(&self, offset: i64, limit: i64) -> DriverResult<Vec<KVRef>> {
+        Ok(self.list_keys_until(offset, limit, None)?.0)
+    }
+
+    fn list_keys_until(
+        &self,
+        offset: i64,
+        limit: i64,
+        deadline: Option<Instant>,
+    ) -> DriverResult<(Vec<KVRef>, bool)> {
+        self.match_keys_until("*", offset, limit, deadline)
+    }
+
+    fn read_range(
+        &self,
+        key: &str,
+        offset: u64,
+        size: u64,
+    ) -> DriverResult<Option<(Vec<u8>, u64)>> {
+        self.inner.read_range(&self.add(key), offset, size)
+    }
+
+    fn ping(&self) -> DriverResult<()> {
+        self.inner.ping()
+    }
+
+    fn server_info(&self) -> DriverResult<ServerInfo> {
+        self.inner.server_info()
+    }
+
+    fn is_replica(&self) -> DriverResult<bool> {
+        self.inner.is_replica()
+    }
+
+    fn modules(&self) -> DriverResult<Vec<String>> {
+        self.inner.modules()
+    }
+
+    fn json_get(&self, key: &str, path: &str) -> DriverResult<Option<String>> {
+        self.inner.json_get(&self.add(key), path)
+    }
+
+    fn dump(&self, key: &str) -> DriverResult<Option<(Vec<u8>, u64)>> {
+        self.inner.dump(&self.add(key))
+    }
+
+    fn server_time(&self) -> DriverResult<Duration> {
+        self.inner.server_time()
+    }
+
+    fn get_as_of(&self, key: &str, as_of: u64) -> DriverResult<Option<Vec<u8>>> {
+        self.inner.get_as_of(&self.add(key), as_of)
+    }
+
+    fn list_versioned_keys(&self, as_of: u64, limit: i64) -> DriverResult<Vec<String>> {
+        let mut keys = self.strip_all(self.inner.list_versioned_keys(as_of, -1)?);
+        if limit != -1 {
+            keys.truncate(limit as usize);
+        }
+        Ok(keys)
+    }
+
+    fn match_keys(&self, pattern: &str, limit: i64) -> DriverResult<Vec<KVRef>> {
+        let pattern = format!("{}{}", self.glob, pattern);
+        Ok(self.strip_refs(self.inner.match_keys(&pattern, limit)?))
+    }
+
+    fn scan_keys(
+        &self,
+        pattern: &str,
+        cursor: &str,
+        count: usize,
+    ) -> DriverResult<(Vec<KVRef>, Option<String>)> {
+        let pattern = format!("{}{}", self.glob, pattern);
+        let (refs, next) = self.inner.scan_keys(&pattern, cursor, count)?;
+        Ok((self.strip_refs(refs), next))
+    }
+
+    fn scan_typed_keys(
+        &self,
+        pattern: &str,
+        cursor: &str,
+        count: usize,
+    ) -> DriverResult<(Vec<TypedKVRef>, Option<String>)> {
+        let pattern = format!("{}{}", self.glob, pattern);
+        let (refs, next) = self.inner.scan_typed_keys(&pattern, cursor, count)?;
+        let refs = refs
+            .into_iter()
+            .filter_map(|mut r| {
+                r.key = self.strip(&r.key)?;
+                Some(r)
+            })
+            .collect();
+        Ok((refs, next))
+    }
+
+    fn match_keys_until(
+        &self,
+        pattern: &str,
+        offset: i64,
+        limit: i64,
+        deadline: Option<Instant>,
+    ) -> DriverResult<(Vec<KVRef>, bool)> {
+        let pattern = format!("{}{}", self.glob, pattern);
+        let (refs, complete) = self
+            .inner
+            .match_keys_until(&pattern, offset, limit, deadline)?;
+        Ok((self.strip_refs(refs), complete))
+    }
+
+    // Falls back to the first key under the prefix if the inner driver keeps
+    // picking keys outside it.
+    fn random_key(&self) -> DriverResult<Option<String>> {
+        for _ in 0..RANDOM_KEY_ATTEMPTS {
+            match self.inner.random_key()? {
+                Some(key) => {
+                    if let Some(key) = self.strip(&key) {
+                        return Ok(Some(key));
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(self.match_keys("*", 1)?.into_iter().next().map(|r| r.key))
+    }
+
+    // Keys under the prefix can only be counted by listing them, so sample is
+    // ignored.
+    fn count_keys(&self, _sample: u64) -> DriverResult<u64> {
+        Ok(self.match_keys("*", -1)?.len() as u64)
+    }
+
+    fn usage(&self) -> DriverResult<Usage> {
+        self.inner.usage()
+    }
+
+    fn key_info(&self, key: &str) -> DriverResult<Option<KeyInfo>> {
+        self.inner.key_info(&self.add(key))
+    }
+
+    fn key_infos(&self, keys: &[String]) -> DriverResult<Vec<Option<KeyInfo>>> {
+        let keys: Vec<String> = keys.iter().map(|k| self.add(k)).collect();
+        self.inner.key_infos(&keys)
+    }
+
+    fn modified(&self, key: &str) -> DriverResult<Option<SystemTime>> {
+        self.inner.modified(&self.add(key))
+    }
+
+    fn redirect_of(&self, key: &str) -> DriverResult<Option<String>> {
+        Ok(self
+            .inner
+            .redirect_of(&self.add(key))?
+            .and_then(|to| self.strip(&to)))
+    }
+
+    fn subscribe(&self, channel: &str) -> DriverResult<Box<dyn Subscription>> {
+        self.inner.subscribe(&self.add(channel))
+    }
+
+    fn channels(&self) -> DriverResult<Vec<String>> {
+        Ok(self.strip_all(self.inner.channels()?))
+    }
+
+    fn consume(&self, queue: &str) -> DriverResult<Box<dyn Subscription>> {
+        self.inner.consume(&self.add(queue))
+    }
+
+    fn changes(&self) -> DriverResult<Box<dyn Subscription>> {
+        Ok(Box::new(Changes {
+            inner: self.inner.changes()?,
+            prefix: self.prefix.clone().into_bytes(),
+        }))
+    }
+
+    fn stream_after(
+        &self,
+        key: &str,
+        after: &str,
+        block: Option<Duration>,
+    ) -> DriverResult<Vec<StreamEntry>> {
+        self.inner.stream_after(&self.add(key), after, block)
+    }
+
+    fn sizes(&self, keys: &[String]) -> DriverResult<Vec<u64>> {
+        self.inner.sizes(&self.add_all(keys))
+    }
+
+    fn idle_times(&self, keys: &[String]) -> DriverResult<Vec<u64>> {
+        self.inner.idle_times(&self.add_all(keys))
+    }
+
+    fn trace(&self) -> DriverResult<Vec<String>> {
+        self.inner.trace()
+    }
+
+    fn command_counts(&self) -> DriverResult<Vec<CommandCount>> {
+        self.inner.command_counts()
+    }
+
+    fn pool_state(&self) -> DriverResult<PoolState> {
+        self.inner.pool_state()
+    }
+
+    fn forget_cached(&self) {
+        self.inner.forget_cached()
+    }
+}
+
+impl KVLocker for PrefixDriver {
+    fn acquire_lock(
+        &self,
+        name: &str,
+        owner: &str,
+        mode: LockMode,
+        ttl: Option<Duration>,
+    ) -> DriverResult<LockOutcome> {
+        self.inner.acquire_lock(&self.add(name), owner, mode, ttl)
+    }
+
+    fn release_lock(&self, name: &str, owner: &str) -> DriverResult<bool> {
+        self.inner.release_lock(&self.add(name), owner)
+    }
+
+    fn lock_owner(&self, name: &str) -> DriverResult<Option<String>> {
+        self.inner.lock_owner(&self.add(name))
+    }
+
+    fn list_locks(&self, prefix: &str) -> DriverResult<Vec<String>> {
+        Ok(self.strip_all(self.inner.list_locks(&self.add(prefix))?))
+    }
+
+    fn hold_shared(&self, key: &str, owner: &str, ttl: Duration) -> DriverResult<()> {
+        self.inner.hold_shared(&self.add(key), owner, ttl)
+    }
+
+    fn release_shared(&self, key: &str, owner: &str) -> DriverResult<()> {
+        self.inner.release_shared(&self.add(key), owner)
+    }
+}
+
+impl KVTagger for PrefixDriver {
+    fn tag(&self, key: &str, tag: &str) -> DriverResult<()> {
+        self.inner.tag(&self.add(key), tag)
+    }
+
+    fn untag(&self, key: &str, tag: &str) -> DriverResult<()> {
+        self.inner.untag(&self.add(key), tag)
+    }
+
+    fn tags_of(&self, key: &str) -> DriverResult<Vec<String>> {
+        self.inner.tags_of(&self.add(key))
+    }
+
+    fn tagged(&self, tag: &str) -> DriverResult<Vec<String>> {
+        Ok(self.strip_all(self.inner.tagged(tag)?))
+    }
+
+    fn list_tags(&self) -> DriverResult<Vec<String>> {
+        self.inner.list_tags()
+    }
+}
+
+impl KVWriter for PrefixDriver {
+    fn preallocate(&self, key: &str, len: u64) -> DriverResult<()> {
+        self.inner.preallocate(&self.add(key), len)
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> DriverResult<()> {
+        self.inner.set(&self.add(key), value)
+    }
+
+    fn set_many(&self, values: &[(String, Vec<u8>)]) -> DriverResult<()> {
+        let values: Vec<(String, Vec<u8>)> = values
+            .iter()
+            .map(|(k, v)| (self.add(k), v.clone()))
+            .collect();
+        self.inner.set_many(&values)
+    }
+
+    fn transact(&self, writes: &[(String, Option<Vec<u8>>)]) -> DriverResult<()> {
+        let writes: Vec<(String, Option<Vec<u8>>)> = writes
+            .iter()
+            .map(|(k, v)| (self.add(k), v.clone()))
+            .collect();
+        self.inner.transact(&writes)
+    }
+
+    fn json_set(&self, key: &str, path: &str, json: &str) -> DriverResult<bool> {
+        self.inner.json_set(&self.add(key), path, json)
+    }
+
+    fn json_del(&self, key: &str, path: &str) -> DriverResult<u64> {
+        self.inner.json_del(&self.add(key), path)
+    }
+
+    fn restore(&self, key: &str, ttl: u64, payload: &[u8]) -> DriverResult<()> {
+        self.inner.restore(&self.add(key), ttl, payload)
+    }
+
+    fn write_range(&self, key: &str, offset: u64, data: &[u8]) -> DriverResult<()> {
+        self.inner.write_range(&self.add(key), offset, data)
+    }
+
+    fn append(&self, key: &str, separator: &[u8], data: &[u8]) -> DriverResult<()> {
+        self.inner.append(&self.add(key), separator, data)
+    }
+
+    fn trim_newline(&self, key: &str, len: u64) -> DriverResult<()> {
+        self.inner.trim_newline(&self.add(key), len)
+    }
+
+    fn rename(
+        &self,
+        from: &str,
+        to: &str,
+        kind: Option<ValueKind>,
+        replace: bool,
+    ) -> DriverResult<RenameOutcome> {
+        self.inner
+            .rename(&self.add(from), &self.add(to), kind, replace)
+    }
+
+    fn expire(&self, key: &str, ttl: Option<Duration>) -> DriverResult<bool> {
+        self.inner.expire(&self.add(key), ttl)
+    }
+
+    fn incr_by(&self, key: &str, delta: i64) -> DriverResult<i64> {
+        self.inner.incr_by(&self.add(key), delta)
+    }
+
+    fn publish(&self, channel: &str, message: &[u8]) -> DriverResult<u64> {
+        self.inner.publish(&self.add(channel), message)
+    }
+
+    fn push(&self, queue: &str, items: &[Vec<u8>]) -> DriverResult<()> {
+        self.inner.push(&self.add(queue), items)
+    }
+
+    fn leave_redirect(&self, from: &str, to: &str, ttl: Duration) -> DriverResult<()> {
+        self.inner
+            .leave_redirect(&self.add(from), &self.add(to), ttl)
+    }
+
+    fn delete(&self, keys: &[String]) -> DriverResult<u64> {
+        self.inner.delete(&self.add_all(keys))
+    }
+
+    fn write_items(
+        &self,
+        key: &str,
+        kind: ValueKind,
+        items: &[String],
+        append: bool,
+    ) -> DriverResult<()> {
+        self.inner.write_items(&self.add(key), kind, items, append)
+    }
+
+    // Raw commands are passed through untouched, reaching every key.
+    fn raw(&self, args: &[String]) -> DriverResult<String> {
+        self.inner.raw(args)
+    }
+
+    fn eval(&self, script: &str, keys: &[String], args: &[String]) -> DriverResult<()> {
+        self.inner.eval(script, &self.add_all(keys), args)
+    }
+
+    // The stream is fusekv's own, so isn't prefixed.
+    fn append_to_stream(
+        &self,
+        key: &str,
+        max_len: usize,
+        fields: &[(&str, String)],
+    ) -> DriverResult<()> {
+        self.inner.append_to_stream(key, max_len, fields)
+    }
+
+    // The key is the first argument of every command checked.
+    fn acl_allows(&self, user: &str, args: &[String]) -> DriverResult<bool> {
+        let mut args = args.to_vec();
+        if let Some(key) = args.get_mut(1) {
+            *key = self.add(key);
+        }
+        self.inner.acl_allows(user, &args)
+    }
+}
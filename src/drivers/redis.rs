@@ -1,85 +1,3622 @@
+use crate::audit;
+use crate::config::{resolve_password, Config, ConfigError, LockMode, ReadPreference, Secret};
+use crate::drivers::Driver;
 use crate::fuse;
 
+use lru::LruCache;
+use r2d2::ManageConnection;
 use redis;
 use redis::Commands;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::error::Error;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-const INO_CACHE_KEY: &str = "__fusekv_ino_cache__";
+// Every key fusekv keeps for its own bookkeeping starts with this.
+const INTERNAL_PREFIX: &str = "__fusekv_";
+
+// Inodes are handed out once and kept both ways, so any mount can find the key
+// behind an inode after its own cache has lost it: key to inode in INOS_KEY,
+// inode to key in KEYS_BY_INO_KEY.
+const INOS_KEY: &str = "__fusekv_inos__";
+const KEYS_BY_INO_KEY: &str = "__fusekv_keys_by_ino__";
+
+// Which format the inode mappings are kept in, so older ones can be migrated
+// at mount. Version 1 kept key to inode alone in LEGACY_INO_CACHE_KEY.
+const INO_VERSION_KEY: &str = "__fusekv_ino_version__";
+const INO_VERSION: u64 = 2;
+const LEGACY_INO_CACHE_KEY: &str = "__fusekv_ino_cache__";
+
+// When each key was last written through fusekv, in milliseconds since the
+// epoch, if track_mtime is set.
+const MTIMES_KEY: &str = "__fusekv_mtimes__";
+
+// Keys renamed through a mount with rename_redirect set leave the key they were
+// renamed to at REDIRECT_PREFIX + key, expiring after rename_redirect.
+const REDIRECT_PREFIX: &str = "__fusekv_moved__:";
+
+// Inode mappings cached in process, each way.
+const INO_CACHE_SIZE: usize = 100_000;
+
+// Keys remembered as written lately, to read them from the primary.
+const WRITTEN_CACHE_SIZE: usize = 100_000;
+
+// The least time keys written are read from the primary for, as even
+// replicas keeping up are a moment behind it.
+const MIN_READ_YOUR_WRITES: Duration = Duration::from_secs(1);
+
+// An entry of a stream as XREAD replies with it: its ID, then its fields and
+// values in turn.
+type StreamReply = (String, Vec<String>);
+
+// Keys whose inodes are looked up or forgotten per command.
+const INO_BATCH: usize = 1000;
+
+// Inodes after the hash of a key tried before giving up on a free one.
+const INO_PROBES: u64 = 64;
+
+// Times to retry RANDOMKEY when it lands on one of our own keys.
+const RANDOM_KEY_ATTEMPTS: usize = 10;
+
+// Retries past this many don't wait any longer than the last.
+const MAX_BACKOFF_DOUBLINGS: u32 = 10;
+
+// Bytes of each argument shown in /.fusekv/trace.
+const TRACE_ARG_LEN: usize = 64;
+
+// How long a replica is trusted to keep up with its primary, or left alone
+// after falling behind, before INFO replication is asked again.
+const REPLICA_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Largest bulk string assumed to be accepted in one command when the server
+// won't say, which is Redis' default proto-max-bulk-len.
+const DEFAULT_MAX_BULK_LEN: u64 = 512 * 1024 * 1024;
+
+// KEYS[1] is the key to grow to at least ARGV[1] bytes. Writing the last byte
+// is enough for Redis to zero-fill everything before it.
+const PREALLOCATE_SCRIPT: &str = r#"
+local len = tonumber(ARGV[1])
+if len > 0 and redis.call('STRLEN', KEYS[1]) < len then
+    redis.call('SETRANGE', KEYS[1], len - 1, '\0')
+end
+return redis.call('STRLEN', KEYS[1])
+"#;
+
+// KEYS[1] is the string to add ARGV[2] to the end of, after ARGV[1] if it
+// already exists.
+const APPEND_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    redis.call('APPEND', KEYS[1], ARGV[1])
+end
+return redis.call('APPEND', KEYS[1], ARGV[2])
+"#;
+
+// KEYS[1] is the key to drop a trailing \n from, if it's exactly ARGV[1] bytes
+// long. Strings can't be shortened in place, so the value is rewritten, keeping
+// its TTL.
+const TRIM_NEWLINE_SCRIPT: &str = r#"
+local len = tonumber(ARGV[1])
+if len > 0 and redis.call('STRLEN', KEYS[1]) == len
+    and redis.call('GETRANGE', KEYS[1], len - 1, len - 1) == '\n' then
+    local ttl = redis.call('PTTL', KEYS[1])
+    redis.call('SET', KEYS[1], redis.call('GETRANGE', KEYS[1], 0, len - 2))
+    if ttl > 0 then
+        redis.call('PEXPIRE', KEYS[1], ttl)
+    end
+end
+return 0
+"#;
+
+// KEYS[1] is the key to move to KEYS[2], and KEYS[3] and KEYS[4] their tag
+// sets. ARGV[1] is the type to convert the value to, or empty to keep it as is,
+// ARGV[2] is 1 if KEYS[2] may be replaced, and ARGV[3..5] are the tag set
+// prefix, KEYS[1] and KEYS[2] unprefixed.
+// Returns 1 if renamed, 0 if KEYS[1] doesn't exist, -1 if KEYS[2] does and
+// can't be replaced, and -2 if the value can't be converted.
+const RENAME_SCRIPT: &str = r#"
+local from = redis.call('TYPE', KEYS[1]).ok
+if from == 'none' then
+    return 0
+end
+if ARGV[2] ~= '1' and redis.call('EXISTS', KEYS[2]) == 1 then
+    return -1
+end
+if ARGV[1] == '' or ARGV[1] == from then
+    redis.call('RENAME', KEYS[1], KEYS[2])
+elseif from == 'string' and ARGV[1] == 'list' then
+    local value = redis.call('GET', KEYS[1])
+    redis.call('DEL', KEYS[1], KEYS[2])
+    redis.call('RPUSH', KEYS[2], value)
+elseif from == 'list' and ARGV[1] == 'string' then
+    local values = redis.call('LRANGE', KEYS[1], 0, -1)
+    redis.call('DEL', KEYS[1])
+    redis.call('SET', KEYS[2], table.concat(values, '\n'))
+else
+    return -2
+end
+redis.call('DEL', KEYS[4])
+for _, tag in ipairs(redis.call('SMEMBERS', KEYS[3])) do
+    redis.call('SREM', ARGV[3] .. tag, ARGV[4])
+    redis.call('SADD', ARGV[3] .. tag, ARGV[5])
+    redis.call('SADD', KEYS[4], tag)
+end
+redis.call('DEL', KEYS[3])
+return 1
+"#;
+
+// KEYS are the ARGV[1] keys to delete, then the key tags set of each, then the
+// tag sets they're to be taken out of. ARGV[2] onwards are the index in KEYS of
+// the key to take out of each tag set. Returns how many keys existed.
+const DELETE_SCRIPT: &str = r#"
+local n = tonumber(ARGV[1])
+local deleted = 0
+for i = 1, n do
+    redis.call('DEL', KEYS[n + i])
+    deleted = deleted + redis.call('UNLINK', KEYS[i])
+end
+for j = 2, #ARGV do
+    redis.call('SREM', KEYS[2 * n + j - 1], KEYS[tonumber(ARGV[j])])
+end
+return deleted
+"#;
+
+// KEYS are the keys to size. Returns the length of each as read through the
+// mount: that of strings, and of the elements of lists and sets joined by
+// newlines. 0 for keys that don't exist.
+const SIZES_SCRIPT: &str = r#"
+local sizes = {}
+for i, key in ipairs(KEYS) do
+    local kind = redis.call('TYPE', key)['ok']
+    local size = 0
+    if kind == 'string' then
+        size = redis.call('STRLEN', key)
+    elseif kind == 'list' or kind == 'set' then
+        local items
+        if kind == 'list' then
+            items = redis.call('LRANGE', key, 0, -1)
+        else
+            items = redis.call('SMEMBERS', key)
+        end
+        for _, item in ipairs(items) do
+            size = size + #item + 1
+        end
+        size = math.max(size - 1, 0)
+    end
+    sizes[i] = size
+end
+return sizes
+"#;
+
+// ARGV[1] is the cursor to SCAN from, ARGV[2] the pattern, and ARGV[3] the
+// count. Returns the next cursor, the keys scanned, and the type and size of
+// each: their length as SIZES_SCRIPT reckons it, or their number of fields for
+// hashes.
+const SCAN_TYPED_SCRIPT: &str = r#"
+local scanned = redis.call('SCAN', ARGV[1], 'MATCH', ARGV[2], 'COUNT', ARGV[3])
+local kinds = {}
+local sizes = {}
+for i, key in ipairs(scanned[2]) do
+    local kind = redis.call('TYPE', key)['ok']
+    local size = 0
+    if kind == 'string' then
+        size = redis.call('STRLEN', key)
+    elseif kind == 'hash' then
+        size = redis.call('HLEN', key)
+    elseif kind == 'list' or kind == 'set' then
+        local items
+        if kind == 'list' then
+            items = redis.call('LRANGE', key, 0, -1)
+        else
+            items = redis.call('SMEMBERS', key)
+        end
+        for _, item in ipairs(items) do
+            size = size + #item + 1
+        end
+        size = math.max(size - 1, 0)
+    end
+    kinds[i] = kind
+    sizes[i] = size
+end
+return {scanned[1], scanned[2], kinds, sizes}
+"#;
+
+// Past values of each key live in a sorted set at VERSIONS_PREFIX + key, scored
+// by milliseconds since the epoch, with members of the form <ms>:<value> so
+// that repeated values stay distinct.
+const VERSIONS_PREFIX: &str = "__fusekv_versions__:";
+
+// Tags are tracked both ways: the keys with a tag in a set at TAG_PREFIX + tag,
+// and the tags of a key in a set at KEY_TAGS_PREFIX + key.
+const TAG_PREFIX: &str = "__fusekv_tag__:";
+const KEY_TAGS_PREFIX: &str = "__fusekv_tags__:";
+
+// Locks live at LOCK_PREFIX + name, and every lock namespace tracks the locks
+// ever taken beneath it in a set at LOCK_CHILDREN_PREFIX + name.
+const LOCK_PREFIX: &str = "__fusekv_lock__:";
+const LOCK_CHILDREN_PREFIX: &str = "__fusekv_lock_children__:";
+
+// In a cluster, locks and their children sets share the hash tag {lock}
+// instead, so they're all in one slot and the lock scripts can touch a lock's
+// ancestors along with it.
+const CLUSTER_LOCK_PREFIX: &str = "__fusekv_{lock}__:";
+const CLUSTER_LOCK_CHILDREN_PREFIX: &str = "__fusekv_{lock}_children__:";
+
+// Shared locks on a key are fields of a hash at SHARED_LOCK_PREFIX + key, from
+// each owner to when its lock expires in milliseconds since the epoch. Writers
+// should wait while any field is in the future. The hash expires with the lock
+// last taken or renewed.
+const SHARED_LOCK_PREFIX: &str = "__fusekv_shared__:";
+
+// KEYS[1] is the lock, KEYS[2] its children set, followed by pairs of lock and
+// children set for each ancestor. ARGV[1] is the owner, ARGV[2] is 1 if held
+// ancestors or descendants should block acquisition, and ARGV[3] is the lock's
+// TTL in milliseconds, or 0 to hold it until released.
+// Returns 1 if acquired, 0 if the lock is held, and -1 if blocked.
+const ACQUIRE_LOCK_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return 0
+end
+if ARGV[2] == '1' then
+    for i = 3, #KEYS, 2 do
+        if redis.call('EXISTS', KEYS[i]) == 1 then
+            return -1
+        end
+    end
+    for _, child in ipairs(redis.call('SMEMBERS', KEYS[2])) do
+        if redis.call('EXISTS', child) == 1 then
+            return -1
+        end
+        redis.call('SREM', KEYS[2], child)
+    end
+end
+if ARGV[3] == '0' then
+    redis.call('SET', KEYS[1], ARGV[1], 'NX')
+else
+    redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[3])
+end
+for i = 4, #KEYS, 2 do
+    redis.call('SADD', KEYS[i], KEYS[1])
+end
+return 1
+"#;
+
+// KEYS[1] is the lock followed by the children set of each ancestor, ARGV[1]
+// is the owner. Only deletes the lock if owner holds it.
+const RELEASE_LOCK_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+redis.call('DEL', KEYS[1])
+for i = 2, #KEYS do
+    redis.call('SREM', KEYS[i], KEYS[1])
+end
+return 1
+"#;
 
 macro_rules! get_conn {
-    ($client:expr) => {
-        match $client.get_connection() {
-            Ok(c) => c,
-            Err(e) => {
-                log::debug!("Error getting redis connection: {}", e);
-                return Err(Box::new(e));
-            }
-        }
+    ($pool:expr) => {
+        $pool.get()?
     };
 }
 
+// A connection for commands that only read, which may be to a replica unless
+// they're about a key written lately.
+macro_rules! read_conn {
+    ($pool:expr) => {
+        $pool.read::<&str>(&[])?
+    };
+    ($pool:expr, $keys:expr) => {
+        $pool.read($keys)?
+    };
+}
+
+// The first argument, if any, is taken to be the key for error context.
 macro_rules! redis_cmd {
-    ($con:expr, $cmd:expr$(, $arg:expr)*) => {
-        match redis::cmd($cmd)$(.arg($arg))*.query(&mut $con) {
+    ($con:expr, $cmd:expr) => {
+        redis::cmd($cmd).query(&mut $con).context($cmd, "")?
+    };
+    ($con:expr, $cmd:expr, $key:expr$(, $arg:expr)*) => {{
+        let key = $key;
+        redis::cmd($cmd)
+            .arg(&key)$(.arg($arg))*
+            .query(&mut $con)
+            .context($cmd, &key.to_string())?
+    }};
+}
+
+// Attach the command and key being run to a Redis error, classifying it as a
+// DriverError.
+trait Context<T> {
+    fn context(self, command: &str, key: &str) -> fuse::DriverResult<T>;
+}
+
+impl<T> Context<T> for redis::RedisResult<T> {
+    fn context(self, command: &str, key: &str) -> fuse::DriverResult<T> {
+        self.map_err(|e| driver_error(e, command, key))
+    }
+}
+
+// Pooled connections to either a single server or a cluster, which routes each
+// command to the node holding its key's slot. Both keep the URL of a server to
+// connect to directly for subscriptions, and cluster mode also uses that seed
+// to reach masters for commands that must run on all of them.
+#[derive(Clone)]
+enum Servers {
+    Single(r2d2::Pool<Manager<redis::Connection>>, url::Url),
+    #[cfg(feature = "cluster")]
+    Cluster(
+        r2d2::Pool<Manager<redis::cluster::ClusterConnection>>,
+        url::Url,
+    ),
+}
+
+// Where reads go when read_preference isn't primary, and the keys this mount
+// wrote lately, which are still read from the primary so it reads its own
+// writes before replicas have caught up with them.
+struct Reads {
+    servers: ReadServers,
+    preference: ReadPreference,
+    // When each key was last written.
+    written: Mutex<LruCache<String, Instant>>,
+    // How long keys are read from the primary after being written, which is
+    // read_your_writes.
+    hold: Duration,
+}
+
+impl Reads {
+    fn wrote<S: AsRef<str>>(&self, keys: &[S]) {
+        let mut written = self.written.lock().unwrap();
+        let now = Instant::now();
+        for key in keys {
+            written.put(key.as_ref().to_string(), now);
+        }
+    }
+
+    fn wrote_lately(&self, key: &str) -> bool {
+        self.written
+            .lock()
+            .unwrap()
+            .get(&key.to_string())
+            .is_some_and(|at| at.elapsed() < self.hold)
+    }
+}
+
+// Replicas of a single server, or a cluster's replicas, which the cluster
+// client picks between for each slot, falling back to the master of slots
+// without any.
+enum ReadServers {
+    Replicas(Replicas),
+    #[cfg(feature = "cluster")]
+    Cluster(r2d2::Pool<Manager<redis::cluster::ClusterConnection>>),
+}
+
+impl ReadServers {
+    // A connection to a replica fit to read from, if any is.
+    fn link(&self) -> Option<Link> {
+        match self {
+            ReadServers::Replicas(replicas) => replicas.link(),
+            #[cfg(feature = "cluster")]
+            ReadServers::Cluster(pool) => match pool.get() {
+                Ok(conn) => Some(Link::Cluster(Box::new(conn))),
+                Err(e) => {
+                    log::warn!("Error connecting to the cluster's replicas: {}", e);
+                    None
+                }
+            },
+        }
+    }
+}
+
+// Replicas of a single server, taken in turn so reads are spread across them,
+// skipping any that have fallen behind.
+struct Replicas {
+    replicas: Vec<Replica>,
+    // Seconds a replica may go without hearing from its primary, or 0 to not
+    // check.
+    max_lag: u64,
+    // Which replica the next read tries first.
+    next: AtomicUsize,
+}
+
+impl Replicas {
+    fn link(&self) -> Option<Link> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..self.replicas.len())
+            .map(|i| &self.replicas[(start + i) % self.replicas.len()])
+            .find_map(|replica| replica.conn(self.max_lag))
+            .map(Link::Single)
+    }
+}
+
+struct Replica {
+    url: url::Url,
+    pool: r2d2::Pool<Manager<redis::Connection>>,
+    // When the replica was last checked, and whether it was keeping up.
+    checked: Mutex<Option<(Instant, bool)>>,
+}
+
+impl Replica {
+    // A connection to the replica, if it's keeping up with its primary as of
+    // its last check, checking again once REPLICA_CHECK_INTERVAL has passed.
+    fn conn(&self, max_lag: u64) -> Option<r2d2::PooledConnection<Manager<redis::Connection>>> {
+        let due = match *self.checked.lock().unwrap() {
+            Some((at, false)) if at.elapsed() < REPLICA_CHECK_INTERVAL => return None,
+            Some((at, true)) => at.elapsed() >= REPLICA_CHECK_INTERVAL,
+            _ => true,
+        };
+        let mut conn = match self.pool.get() {
             Ok(v) => v,
             Err(e) => {
-                log::debug!("Error querying redis: {}", e);
-                return Err(Box::new(e))
+                self.checked(false, &e.to_string());
+                return None;
+            }
+        };
+        if due {
+            let info: redis::RedisResult<redis::InfoDict> =
+                redis::cmd("INFO").arg("replication").query(&mut *conn);
+            let fresh = match info {
+                Ok(info) => keeping_up(&info, max_lag),
+                Err(e) => {
+                    self.checked(false, &e.to_string());
+                    return None;
+                }
+            };
+            self.checked(fresh, "it isn't keeping up with its primary");
+            if !fresh {
+                return None;
             }
         }
-    };
+        Some(conn)
+    }
+
+    // Record whether the replica is keeping up, logging when that changes.
+    fn checked(&self, fresh: bool, why_not: &str) {
+        let mut checked = self.checked.lock().unwrap();
+        match (*checked, fresh) {
+            (None, false) | (Some((_, true)), false) => {
+                log::warn!("Not reading from replica {}: {}.", self.url, why_not)
+            }
+            (Some((_, false)), true) => log::info!("Reading from replica {} again.", self.url),
+            _ => {}
+        }
+        *checked = Some((Instant::now(), fresh));
+    }
+}
+
+#[derive(Clone)]
+struct Pool {
+    servers: Servers,
+    // Where reads may go instead, if read_preference isn't primary.
+    reads: Option<Arc<Reads>>,
+    // What connections outside the pool authenticate with too.
+    credentials: Arc<Credentials>,
+    // Where commands sent over every connection are recorded, if tracing.
+    trace: Option<Arc<Mutex<Trace>>>,
+    // How many commands of each name were sent and failed, if counting.
+    counts: Option<Arc<Mutex<Counts>>>,
+    retry: Arc<Retry>,
+}
+
+impl Pool {
+    fn get(&self) -> fuse::DriverResult<Conn> {
+        let unavailable = |reason: String| {
+            fuse::DriverError::Unavailable("CONNECT".to_string(), String::new(), reason)
+        };
+        if self.retry.is_open() {
+            return Err(unavailable(
+                "not connecting until the backend has had time to recover".to_string(),
+            ));
+        }
+        // The pool only gives up once connection_timeout has passed without a
+        // connection to hand out.
+        let link = self.retry.run(
+            || match &self.servers {
+                Servers::Single(p, _) => p.get().map(Link::Single),
+                #[cfg(feature = "cluster")]
+                Servers::Cluster(p, _) => p.get().map(|c| Link::Cluster(Box::new(c))),
+            },
+            |_| true,
+        );
+        match link {
+            Ok(link) => {
+                self.retry.close();
+                Ok(self.conn(link))
+            }
+            Err(e) => {
+                self.retry.open();
+                Err(unavailable(e.to_string()))
+            }
+        }
+    }
+
+    // Whether keys are spread across the slots of a cluster, so commands
+    // touching several can't be relied on to run in one place.
+    fn clustered(&self) -> bool {
+        match &self.servers {
+            Servers::Single(..) => false,
+            #[cfg(feature = "cluster")]
+            Servers::Cluster(..) => true,
+        }
+    }
+
+    // A connection for commands that only read, about keys if any: to a
+    // replica if there's one keeping up with the primary, otherwise to the
+    // primary unless read_preference is replica. Keys written lately are
+    // always read from the primary.
+    fn read<S: AsRef<str>>(&self, keys: &[S]) -> fuse::DriverResult<Conn> {
+        let reads = match &self.reads {
+            Some(v) if !keys.iter().any(|k| v.wrote_lately(k.as_ref())) => v,
+            _ => return self.get(),
+        };
+        match (reads.servers.link(), reads.preference) {
+            (Some(link), _) => Ok(self.conn(link)),
+            (None, ReadPreference::Replica) => Err(fuse::DriverError::Unavailable(
+                "CONNECT".to_string(),
+                keys.first().map_or("", |k| k.as_ref()).to_string(),
+                "no replica is keeping up with the primary".to_string(),
+            )),
+            (None, _) => self.get(),
+        }
+    }
+
+    // Note keys were just written, so they're read from the primary for now.
+    fn wrote<S: AsRef<str>>(&self, keys: &[S]) {
+        if let Some(reads) = &self.reads {
+            reads.wrote(keys);
+        }
+    }
+
+    fn conn(&self, link: Link) -> Conn {
+        Conn {
+            link,
+            trace: self.trace.clone(),
+            counts: self.counts.clone(),
+            retry: self.retry.clone(),
+        }
+    }
+}
+
+// How Redis outages are ridden out: how often and how patiently to retry, and
+// a breaker that fails operations straight away for a while once connecting
+// failed despite retries, rather than having each wait to connect.
+struct Retry {
+    attempts: u32,
+    backoff: Duration,
+    cooldown: Duration,
+    // Until when operations fail without trying to connect.
+    open_until: Mutex<Option<Instant>>,
+}
+
+impl Retry {
+    fn new(config: &Config) -> Arc<Retry> {
+        Arc::new(Retry {
+            attempts: config.retry_attempts,
+            backoff: Duration::from_millis(config.retry_backoff),
+            cooldown: Duration::from_secs(config.breaker_cooldown),
+            open_until: Mutex::new(None),
+        })
+    }
+
+    // Run f until it succeeds, fails with an error retryable doesn't accept,
+    // or retries are exhausted, backing off exponentially between attempts.
+    fn run<T, E, F, R>(&self, mut f: F, retryable: R) -> Result<T, E>
+    where
+        F: FnMut() -> Result<T, E>,
+        R: Fn(&E) -> bool,
+    {
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(e) if attempt < self.attempts && retryable(&e) => {
+                    thread::sleep(self.backoff * 2u32.pow(attempt.min(MAX_BACKOFF_DOUBLINGS)));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.open_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    fn open(&self) {
+        if self.cooldown.as_secs() == 0 {
+            return;
+        }
+        log::error!(
+            "Couldn't connect to Redis after {} retries, failing operations for {}s.",
+            self.attempts,
+            self.cooldown.as_secs()
+        );
+        *self.open_until.lock().unwrap() = Some(Instant::now() + self.cooldown);
+    }
+
+    fn close(&self) {
+        let mut open_until = self.open_until.lock().unwrap();
+        if open_until.take().is_some() {
+            log::info!("Reconnected to Redis.");
+        }
+    }
+}
+
+// Whether the server refused to run a command for now, without running it,
+// so it's safe to retry.
+fn refused(e: &redis::RedisError) -> bool {
+    matches!(
+        e.code(),
+        Some("LOADING")
+            | Some("TRYAGAIN")
+            | Some("CLUSTERDOWN")
+            | Some("MASTERDOWN")
+            | Some("BUSY")
+    )
+}
+
+enum Link {
+    Single(r2d2::PooledConnection<Manager<redis::Connection>>),
+    // Boxed, as cluster connections are much bigger than the others.
+    #[cfg(feature = "cluster")]
+    Cluster(Box<r2d2::PooledConnection<Manager<redis::cluster::ClusterConnection>>>),
+    // A direct connection to one cluster master.
+    #[cfg(feature = "cluster")]
+    Node(redis::Connection),
+}
+
+struct Conn {
+    link: Link,
+    trace: Option<Arc<Mutex<Trace>>>,
+    counts: Option<Arc<Mutex<Counts>>>,
+    retry: Arc<Retry>,
+}
+
+impl Conn {
+    fn inner(&mut self) -> &mut dyn redis::ConnectionLike {
+        match &mut self.link {
+            Link::Single(c) => &mut **c,
+            #[cfg(feature = "cluster")]
+            Link::Cluster(c) => &mut ***c,
+            #[cfg(feature = "cluster")]
+            Link::Node(c) => c,
+        }
+    }
+
+    fn inner_ref(&self) -> &dyn redis::ConnectionLike {
+        match &self.link {
+            Link::Single(c) => &**c,
+            #[cfg(feature = "cluster")]
+            Link::Cluster(c) => &***c,
+            #[cfg(feature = "cluster")]
+            Link::Node(c) => c,
+        }
+    }
+
+    // Record the packed commands sent at start, and how they went.
+    fn record<T>(&self, packed: &[u8], start: Instant, result: &redis::RedisResult<T>) {
+        if let Some(counts) = &self.counts {
+            count_packed(&mut counts.lock().unwrap(), packed, result.is_err());
+        }
+        if let Some(trace) = &self.trace {
+            let outcome = match result {
+                Ok(_) => "ok".to_string(),
+                Err(e) => e.to_string(),
+            };
+            trace
+                .lock()
+                .unwrap()
+                .record(&describe_packed(packed), start.elapsed(), &outcome);
+        }
+    }
+}
+
+impl redis::ConnectionLike for Conn {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> redis::RedisResult<redis::Value> {
+        let start = Instant::now();
+        let retry = self.retry.clone();
+        let result = retry.run(|| self.inner().req_packed_command(cmd), refused);
+        self.record(cmd, start, &result);
+        result
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> redis::RedisResult<Vec<redis::Value>> {
+        let start = Instant::now();
+        // Pipelines aren't retried, since commands before the refused one may
+        // have run.
+        let result = self.inner().req_packed_commands(cmd, offset, count);
+        self.record(cmd, start, &result);
+        result
+    }
+
+    // Clusters route by the parsed command rather than its packed bytes.
+    fn req_command(&mut self, cmd: &redis::Cmd) -> redis::RedisResult<redis::Value> {
+        let start = Instant::now();
+        let retry = self.retry.clone();
+        let result = retry.run(|| self.inner().req_command(cmd), refused);
+        if self.trace.is_some() || self.counts.is_some() {
+            self.record(&cmd.get_packed_command(), start, &result);
+        }
+        result
+    }
+
+    fn get_db(&self) -> i64 {
+        self.inner_ref().get_db()
+    }
+
+    fn check_connection(&mut self) -> bool {
+        self.inner().check_connection()
+    }
+
+    fn is_open(&self) -> bool {
+        self.inner_ref().is_open()
+    }
+}
+
+// Commands sent to Redis by name, and how many of them failed.
+type Counts = BTreeMap<String, (u64, u64)>;
+
+// The last commands sent to Redis, for /.fusekv/trace.
+struct Trace {
+    size: usize,
+    lines: VecDeque<String>,
+}
+
+impl Trace {
+    fn record(&mut self, command: &str, took: Duration, outcome: &str) {
+        if self.lines.len() >= self.size {
+            self.lines.pop_front();
+        }
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        self.lines.push_back(format!(
+            "{} {}us {} => {}\n",
+            at,
+            took.as_micros(),
+            command,
+            outcome
+        ));
+    }
+}
+
+// A subscription to one channel over a connection of its own, which the server
+// unsubscribes once it's dropped and closed.
+struct Subscription {
+    conn: redis::Connection,
+    channel: String,
 }
 
-#[derive(Debug, Clone)]
+impl fuse::Subscription for Subscription {
+    fn next_message(&mut self, timeout: Option<Duration>) -> fuse::DriverResult<Option<Vec<u8>>> {
+        // Zero read timeouts are refused.
+        let timeout = timeout.map(|t| t.max(Duration::from_millis(1)));
+        self.conn
+            .set_read_timeout(timeout)
+            .context("SUBSCRIBE", &self.channel)?;
+        loop {
+            let reply = match self.conn.recv_response() {
+                Ok(v) => v,
+                Err(e) if e.is_timeout() => return Ok(None),
+                Err(e) => return Err(driver_error(e, "SUBSCRIBE", &self.channel)),
+            };
+            // Anything but messages, eg. confirmations, is skipped.
+            if let Some(msg) = redis::Msg::from_value(&reply) {
+                return Ok(Some(msg.get_payload_bytes().to_vec()));
+            }
+            if let Some(payload) = shard_message(&reply) {
+                return Ok(Some(payload));
+            }
+        }
+    }
+}
+
+// A queue popped from over a connection of its own, so BRPOP waiting as long
+// as reads do doesn't hold up a pooled one.
+struct Consumer {
+    conn: redis::Connection,
+    queue: String,
+}
+
+impl fuse::Subscription for Consumer {
+    fn next_message(&mut self, timeout: Option<Duration>) -> fuse::DriverResult<Option<Vec<u8>>> {
+        // 0 waits forever, and Redis before 6.0 only takes whole seconds.
+        let secs = timeout.map_or(0, |t| (t.as_secs() + (t.subsec_nanos() > 0) as u64).max(1));
+        let popped: Option<(String, Vec<u8>)> = redis::cmd("BRPOP")
+            .arg(&self.queue)
+            .arg(secs)
+            .query(&mut self.conn)
+            .context("BRPOP", &self.queue)?;
+        Ok(popped.map(|(_, item)| item))
+    }
+}
+
+// Inodes recently handed out, so most lookups either way don't need Redis.
+struct Inos {
+    by_key: LruCache<String, u64>,
+    by_ino: LruCache<u64, String>,
+}
+
+impl Inos {
+    fn insert(&mut self, key: &str, ino: u64) {
+        self.by_key.put(key.to_string(), ino);
+        self.by_ino.put(ino, key.to_string());
+    }
+
+    fn forget(&mut self, key: &str) {
+        if let Some(ino) = self.by_key.pop(&key.to_string()) {
+            self.by_ino.pop(&ino);
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct RedisDriver {
-    // TODO add a box for the connection
-    // TODO keep track of ino mappings locally to avoid Redis lookup?
-    client: redis::Client,
+    pool: Pool,
+    inos: Arc<Mutex<Inos>>,
+    // The server's proto-max-bulk-len, once asked for.
+    max_bulk_len: Arc<Mutex<Option<u64>>>,
+    // Whether SCAN_TYPED_SCRIPT could be loaded, once it's been tried.
+    scripting: Arc<Mutex<Option<bool>>>,
+    track_mtime: bool,
+    // The database keys are in, for subscribing to its notifications.
+    database: i64,
 }
 
 impl fuse::KVReader for RedisDriver {
-    fn get_by_name(&self, name: String, ino: u64) -> Result<Option<fuse::KVEntry>, Box<dyn Error>> {
+    // Commands newer than the server are reported as degraded in
+    // /.fusekv/capabilities instead. In a cluster, a key and its tags are in
+    // different slots, so no one script can move them together.
+    fn capabilities(&self) -> fuse::DriverCapabilities {
+        fuse::DriverCapabilities {
+            copy: !self.pool.clustered(),
+            ..fuse::DriverCapabilities::all()
+        }
+    }
+
+    fn get_by_name(&self, name: String, _ino: u64) -> fuse::DriverResult<Option<fuse::KVEntry>> {
         // We have a name, so we can just look directly into redis
-        let mut conn = get_conn!(self.client);
+        let mut conn = read_conn!(self.pool, &[&name]);
         // TODO not sure if this is the best idea, it reads the whole value into
         // memory which might cause problems with large values.
-        let value: String = match redis_cmd!(conn, "GET", &name) {
+        let (value, kind) = match redis::cmd("GET")
+            .arg(&name)
+            .query::<Option<Vec<u8>>>(&mut conn)
+        {
+            Ok(Some(v)) => (v, fuse::ValueKind::String),
+            Ok(None) => return Ok(None),
+            Err(e) if e.code() == Some("WRONGTYPE") => match read_items(&mut conn, &name) {
+                Ok((items, kind)) => (items.into_bytes(), kind),
+                // Deleted since the GET.
+                Err(fuse::DriverError::NotFound(..)) => return Ok(None),
+                Err(e) => return Err(e),
+            },
+            Err(e) => return Err(driver_error(e, "GET", &name)),
+        };
+        let mut entry = fuse::KVEntry::new(name, value);
+        entry.kind = kind;
+        Ok(Some(entry))
+    }
+
+    fn get_by_ino(&self, ino: u64) -> fuse::DriverResult<Option<fuse::KVEntry>> {
+        let cached = self.inos.lock().unwrap().by_ino.get(&ino).cloned();
+        let name = match cached {
+            Some(v) => v,
+            None => {
+                let mut conn = get_conn!(self.pool);
+                let name: Option<String> = conn
+                    .hget(KEYS_BY_INO_KEY, ino)
+                    .context("HGET", KEYS_BY_INO_KEY)?;
+                match name {
+                    Some(v) => {
+                        self.inos.lock().unwrap().insert(&v, ino);
+                        v
+                    }
+                    None => return Ok(None),
+                }
+            }
+        };
+        self.get_by_name(name, ino)
+    }
+
+    fn inos_of(&self, keys: &[String]) -> fuse::DriverResult<Vec<u64>> {
+        let mut conn = get_conn!(self.pool);
+        assign_inos(&mut conn, &self.inos, keys)
+    }
+
+    fn known_inos(&self, keys: &[String]) -> Vec<Option<u64>> {
+        let mut inos = self.inos.lock().unwrap();
+        keys.iter().map(|k| inos.by_key.get(k).copied()).collect()
+    }
+
+    fn list_keys(&self, offset: i64, limit: i64) -> fuse::DriverResult<Vec<fuse::KVRef>> {
+        Ok(self.list_keys_until(offset, limit, None)?.0)
+    }
+
+    fn list_keys_until(
+        &self,
+        offset: i64,
+        limit: i64,
+        deadline: Option<Instant>,
+    ) -> fuse::DriverResult<(Vec<fuse::KVRef>, bool)> {
+        self.match_keys_until("*", offset, limit, deadline)
+    }
+
+    fn match_keys_until(
+        &self,
+        pattern: &str,
+        offset: i64,
+        limit: i64,
+        deadline: Option<Instant>,
+    ) -> fuse::DriverResult<(Vec<fuse::KVRef>, bool)> {
+        let limit = if limit == -1 {
+            usize::MAX
+        } else {
+            limit as usize
+        };
+        let (keys, complete) =
+            self.scan(pattern, (offset as usize).saturating_add(limit), deadline)?;
+        let keys: Vec<String> = keys.into_iter().skip(offset as usize).collect();
+        Ok((self.refs(keys)?, complete))
+    }
+
+    // Cursors are the index of the node being scanned, which is always 0
+    // outside a cluster, and SCAN's cursor on it.
+    fn scan_keys(
+        &self,
+        pattern: &str,
+        cursor: &str,
+        count: usize,
+    ) -> fuse::DriverResult<(Vec<fuse::KVRef>, Option<String>)> {
+        let (node, at) = split_cursor(cursor);
+        let mut conns = self.read_node_conns()?;
+        if node >= conns.len() {
+            return Ok((vec![], None));
+        }
+        let nodes = conns.len();
+        let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(at)
+            .arg("MATCH")
+            .arg(pattern)
+            .arg("COUNT")
+            .arg(count)
+            .query(&mut conns[node])
+            .context("SCAN", pattern)?;
+        let keys = keys.into_iter().filter(|k| !is_internal(k)).collect();
+        Ok((self.refs(keys)?, next_cursor(node, nodes, next)))
+    }
+
+    // Keys are typed and sized by SCAN_TYPED_SCRIPT in the same round trip as
+    // they're scanned, or with pipelines where scripting is disabled.
+    fn scan_typed_keys(
+        &self,
+        pattern: &str,
+        cursor: &str,
+        count: usize,
+    ) -> fuse::DriverResult<(Vec<fuse::TypedKVRef>, Option<String>)> {
+        let (node, at) = split_cursor(cursor);
+        let mut conns = self.read_node_conns()?;
+        if node >= conns.len() {
+            return Ok((vec![], None));
+        }
+        let nodes = conns.len();
+        let conn = &mut conns[node];
+        let (next, keys, kinds, sizes) = match self.scan_typed_script(conn, at, pattern, count)? {
             Some(v) => v,
+            None => scan_typed_pipelined(conn, at, pattern, count)?,
+        };
+        let (keys, (kinds, sizes)): (Vec<String>, (Vec<String>, Vec<u64>)) = keys
+            .into_iter()
+            .zip(kinds.into_iter().zip(sizes))
+            .filter(|(k, _)| !is_internal(k))
+            .unzip();
+        let mtimes: Vec<Option<u64>> = match self.track_mtime && !keys.is_empty() {
+            true => {
+                let mut conn = read_conn!(self.pool, &keys);
+                redis::cmd("HMGET")
+                    .arg(MTIMES_KEY)
+                    .arg(&keys)
+                    .query(&mut conn)
+                    .context("HMGET", MTIMES_KEY)?
+            }
+            false => vec![None; keys.len()],
+        };
+        let refs = self
+            .refs(keys)?
+            .into_iter()
+            .zip(kinds.into_iter().zip(sizes))
+            .zip(mtimes)
+            .map(|((r, (kind, size)), millis)| fuse::TypedKVRef {
+                ino: r.ino,
+                key: r.key,
+                kind,
+                size,
+                modified: millis.map(|m| UNIX_EPOCH + Duration::from_millis(m)),
+            })
+            .collect();
+        Ok((refs, next_cursor(node, nodes, next)))
+    }
+
+    fn read_range(
+        &self,
+        key: &str,
+        offset: u64,
+        size: u64,
+    ) -> fuse::DriverResult<Option<(Vec<u8>, u64)>> {
+        let mut conn = read_conn!(self.pool, &[key]);
+        let chunk = self.max_bulk_len(&mut conn);
+        let result: fuse::DriverResult<(bool, u64, Vec<u8>)> = redis::pipe()
+            .exists(key)
+            .strlen(key)
+            .getrange(
+                key,
+                getrange_start(offset),
+                getrange_end(offset, size.min(chunk)),
+            )
+            .query(&mut conn)
+            .context("GETRANGE", key);
+        match result {
+            Ok((false, _, _)) => Ok(None),
+            Ok((true, len, mut data)) => {
+                // Reads bigger than a bulk string are fetched a chunk at a time.
+                let want = size.min(len.saturating_sub(offset));
+                while (data.len() as u64) < want {
+                    let start = offset + data.len() as u64;
+                    let part: Vec<u8> = redis_cmd!(
+                        conn,
+                        "GETRANGE",
+                        key,
+                        getrange_start(start),
+                        getrange_end(start, (want - data.len() as u64).min(chunk))
+                    );
+                    if part.is_empty() {
+                        break;
+                    }
+                    data.extend(part);
+                }
+                data.truncate(size as usize);
+                Ok(Some((data, len)))
+            }
+            // Lists and sets are only ever read whole.
+            Err(fuse::DriverError::WrongType(..)) => Ok(fuse::value_range(
+                self.get_by_name(key.to_string(), fuse::kv_ino(key))?,
+                offset,
+                size,
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn ping(&self) -> fuse::DriverResult<()> {
+        let mut conn = get_conn!(self.pool);
+        let _: String = redis_cmd!(conn, "PING");
+        Ok(())
+    }
+
+    fn server_info(&self) -> fuse::DriverResult<fuse::ServerInfo> {
+        let mut conn = get_conn!(self.pool);
+        let info: redis::InfoDict = redis_cmd!(conn, "INFO", "server");
+        // MODULE LIST doesn't exist before Redis 4.
+        let modules: Vec<HashMap<String, redis::Value>> = redis::cmd("MODULE")
+            .arg("LIST")
+            .query(&mut conn)
+            .unwrap_or_default();
+        Ok(fuse::ServerInfo {
+            driver: "redis".to_string(),
+            version: info.get("redis_version"),
+            mode: info.get("redis_mode"),
+            modules: modules
+                .iter()
+                .filter_map(|m| m.get("name"))
+                .filter_map(|v| redis::from_redis_value(v).ok())
+                .collect(),
+        })
+    }
+
+    fn is_replica(&self) -> fuse::DriverResult<bool> {
+        let mut conn = get_conn!(self.pool);
+        is_replica(&mut conn)
+    }
+
+    fn modules(&self) -> fuse::DriverResult<Vec<String>> {
+        let mut conn = get_conn!(self.pool);
+        // Each module is listed as its attributes and their values in turn.
+        let modules: Vec<Vec<redis::Value>> = redis_cmd!(conn, "MODULE", "LIST");
+        Ok(modules
+            .into_iter()
+            .filter_map(|attrs| {
+                let i = attrs
+                    .iter()
+                    .position(|a| *a == redis::Value::BulkString(b"name".to_vec()))?;
+                redis::from_redis_value(attrs.get(i + 1)?).ok()
+            })
+            .collect())
+    }
+
+    fn json_get(&self, key: &str, path: &str) -> fuse::DriverResult<Option<String>> {
+        let mut conn = read_conn!(self.pool, &[key]);
+        // JSONPaths reply with an array of every value they matched.
+        let json: Option<String> = redis_cmd!(conn, "JSON.GET", key, path);
+        let matched: Vec<serde_json::Value> = match json {
+            Some(v) => serde_json::from_str(&v).map_err(|e| {
+                fuse::DriverError::Corrupt("JSON.GET".to_string(), key.to_string(), e.to_string())
+            })?,
             None => return Ok(None),
         };
-        // Insert ino into redis cache so we can lookup the name of the key later
-        // in get_by_ino.
-        // TODO make the key configurable?
-        match conn.hset::<&str, &str, u64, u64>(INO_CACHE_KEY, &name, ino) {
-            Err(e) => log::error!("Error updating ino cache {}.", e),
-            _ => {}
+        Ok(matched.first().map(|v| v.to_string()))
+    }
+
+    fn dump(&self, key: &str) -> fuse::DriverResult<Option<(Vec<u8>, u64)>> {
+        let mut conn = read_conn!(self.pool, &[key]);
+        let (payload, pttl): (Option<Vec<u8>>, i64) = redis::pipe()
+            .cmd("DUMP")
+            .arg(key)
+            .cmd("PTTL")
+            .arg(key)
+            .query(&mut conn)
+            .context("DUMP", key)?;
+        // The key may have expired between the two.
+        match payload {
+            Some(v) if pttl != -2 => Ok(Some((v, pttl.max(0) as u64))),
+            _ => Ok(None),
+        }
+    }
+
+    fn server_time(&self) -> fuse::DriverResult<Duration> {
+        let mut conn = get_conn!(self.pool);
+        let (secs, micros): (u64, u64) = redis_cmd!(conn, "TIME");
+        Ok(Duration::from_secs(secs) + Duration::from_micros(micros))
+    }
+
+    fn get_as_of(&self, key: &str, as_of: u64) -> fuse::DriverResult<Option<Vec<u8>>> {
+        let mut conn = read_conn!(self.pool, &[key]);
+        let set = format!("{}{}", VERSIONS_PREFIX, key);
+        let versions: Vec<Vec<u8>> = redis::cmd("ZREVRANGEBYSCORE")
+            .arg(&set)
+            .arg(as_of)
+            .arg("-inf")
+            .arg("LIMIT")
+            .arg(0)
+            .arg(1)
+            .query(&mut conn)
+            .context("ZREVRANGEBYSCORE", &set)?;
+        Ok(versions
+            .into_iter()
+            .next()
+            .map(|v| match v.iter().position(|&b| b == b':') {
+                Some(i) => v[i + 1..].to_vec(),
+                None => v,
+            }))
+    }
+
+    fn list_versioned_keys(&self, as_of: u64, limit: i64) -> fuse::DriverResult<Vec<String>> {
+        let mut conn = read_conn!(self.pool);
+        let sets = self.scan_internal(&format!("{}*", VERSIONS_PREFIX))?;
+        let mut keys = vec![];
+        for set in sets {
+            if limit != -1 && keys.len() as i64 >= limit {
+                break;
+            }
+            let count: u64 = conn.zcount(&set, "-inf", as_of).context("ZCOUNT", &set)?;
+            if count > 0 {
+                keys.push(set[VERSIONS_PREFIX.len()..].to_string());
+            }
+        }
+        Ok(keys)
+    }
+
+    fn match_keys(&self, pattern: &str, limit: i64) -> fuse::DriverResult<Vec<fuse::KVRef>> {
+        let limit = if limit == -1 {
+            usize::MAX
+        } else {
+            limit as usize
         };
-        Ok(Some(fuse::KVEntry::new(ino, name, value)))
+        let (keys, _) = self.scan(pattern, limit, None)?;
+        self.refs(keys)
     }
 
-    fn get_by_ino(&self, ino: u64) -> Result<Option<fuse::KVEntry>, Box<dyn Error>> {
-        // TODO impl
+    fn random_key(&self) -> fuse::DriverResult<Option<String>> {
+        let mut conn = read_conn!(self.pool);
+        for _ in 0..RANDOM_KEY_ATTEMPTS {
+            let key: Option<String> = redis_cmd!(conn, "RANDOMKEY");
+            match key {
+                Some(k) if is_internal(&k) => continue,
+                other => return Ok(other),
+            }
+        }
         Ok(None)
     }
 
-    fn list_keys(&self, offset: i64) -> Result<Vec<fuse::KVRef>, Box<dyn Error>> {
-        // TODO impl
-        Ok(vec![])
+    fn count_keys(&self, sample: u64) -> fuse::DriverResult<u64> {
+        let mut total: u64 = 0;
+        for mut node in self.node_conns()? {
+            let keys: u64 = redis_cmd!(node, "DBSIZE");
+            total += keys;
+        }
+        let mut conn = get_conn!(self.pool);
+        if sample == 0 || total == 0 {
+            return Ok(total);
+        }
+        // Scale DBSIZE by the share of sampled keys that aren't ours.
+        let mut pipe = redis::pipe();
+        for _ in 0..sample {
+            pipe.cmd("RANDOMKEY");
+        }
+        let keys: Vec<Option<String>> = pipe.query(&mut conn).context("RANDOMKEY", "")?;
+        let internal = keys.iter().flatten().filter(|k| is_internal(k)).count() as u64;
+        Ok(total - total * internal / sample)
     }
 
-    fn read(&self, ino: u64, fh: u64, offset: i64) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
-        match self.get_by_ino(ino) {
-            Ok(maybe) => match maybe {
-                Some(v) => Ok(Some(v.val.as_bytes()[offset as usize..].to_vec())),
-                None => Ok(None),
-            },
-            Err(e) => return Err(e),
+    fn usage(&self) -> fuse::DriverResult<fuse::Usage> {
+        let mut usage = fuse::Usage {
+            keys: 0,
+            memory: Some(0),
+            memory_limit: Some(0),
+        };
+        for mut node in self.node_conns()? {
+            let keys: u64 = redis_cmd!(node, "DBSIZE");
+            let info: redis::InfoDict = redis_cmd!(node, "INFO", "memory");
+            usage.keys += keys;
+            usage.memory = match (usage.memory, info.get::<u64>("used_memory")) {
+                (Some(total), Some(used)) => Some(total + used),
+                _ => None,
+            };
+            // A maxmemory of 0 is unlimited, leaving the host's memory.
+            let limit = match info.get::<u64>("maxmemory") {
+                Some(0) | None => info.get::<u64>("total_system_memory"),
+                max => max,
+            };
+            usage.memory_limit = match (usage.memory_limit, limit) {
+                (Some(total), Some(limit)) => Some(total + limit),
+                _ => None,
+            };
         }
+        Ok(usage)
     }
-}
 
-impl RedisDriver {
-    pub fn new(client: redis::Client) -> RedisDriver {
-        RedisDriver { client: client }
+    fn key_info(&self, key: &str) -> fuse::DriverResult<Option<fuse::KeyInfo>> {
+        Ok(self.key_infos(&[key.to_string()])?.pop().flatten())
+    }
+
+    fn key_infos(&self, keys: &[String]) -> fuse::DriverResult<Vec<Option<fuse::KeyInfo>>> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut conn = read_conn!(self.pool, keys);
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.cmd("TTL")
+                .arg(key)
+                .cmd("TYPE")
+                .arg(key)
+                .cmd("OBJECT")
+                .arg("ENCODING")
+                .arg(key)
+                .cmd("MEMORY")
+                .arg("USAGE")
+                .arg(key);
+        }
+        let replies: Vec<(i64, String, Option<String>, Option<u64>)> = pipe
+            .query::<Vec<redis::Value>>(&mut conn)
+            .and_then(|v| {
+                v.chunks(4)
+                    .map(|c| redis::from_redis_value(&redis::Value::Array(c.to_vec())))
+                    .collect()
+            })
+            .context("MEMORY", &keys[0])?;
+        Ok(replies
+            .into_iter()
+            .map(|(ttl, kind, encoding, memory)| {
+                // TTL is -2 for missing keys and -1 for keys without an expiry.
+                if ttl == -2 || kind == "none" {
+                    return None;
+                }
+                Some(fuse::KeyInfo {
+                    ttl: if ttl < 0 { None } else { Some(ttl as u64) },
+                    kind,
+                    encoding: encoding.unwrap_or_default(),
+                    memory,
+                })
+            })
+            .collect())
+    }
+
+    fn redirect_of(&self, key: &str) -> fuse::DriverResult<Option<String>> {
+        let mut conn = get_conn!(self.pool);
+        Ok(redis_cmd!(
+            conn,
+            "GET",
+            format!("{}{}", REDIRECT_PREFIX, key)
+        ))
+    }
+
+    // In a cluster, channels are sharded like keys, so messages only go to
+    // the node owning the channel's slot rather than to every node.
+    fn subscribe(&self, channel: &str) -> fuse::DriverResult<Box<dyn fuse::Subscription>> {
+        let mut conn = self.subscriber_conn(Some(channel))?;
+        let () = match self.pool.clustered() {
+            true => redis_cmd!(conn, "SSUBSCRIBE", channel),
+            false => redis_cmd!(conn, "SUBSCRIBE", channel),
+        };
+        Ok(Box::new(Subscription {
+            conn,
+            channel: channel.to_string(),
+        }))
+    }
+
+    // In a cluster, the sharded channels of every node.
+    fn channels(&self) -> fuse::DriverResult<Vec<String>> {
+        let mut channels: Vec<String> = match self.pool.clustered() {
+            true => {
+                let mut channels = vec![];
+                for mut conn in self.node_conns()? {
+                    let mut shard: Vec<String> = redis_cmd!(conn, "PUBSUB", "SHARDCHANNELS");
+                    channels.append(&mut shard);
+                }
+                channels
+            }
+            false => {
+                let mut conn = get_conn!(self.pool);
+                redis_cmd!(conn, "PUBSUB", "CHANNELS")
+            }
+        };
+        channels.sort();
+        channels.dedup();
+        Ok(channels)
+    }
+
+    // From the keyevent notifications of the server subscriptions are made on,
+    // so in a cluster only changes to the keys it holds are seen. Without
+    // notify-keyspace-events set to send them for every type of key, changes
+    // would be missed, so they're treated as unsupported.
+    fn changes(&self) -> fuse::DriverResult<Box<dyn fuse::Subscription>> {
+        let mut conn = self.subscriber_conn(None)?;
+        let reply: Vec<String> = redis::cmd("CONFIG")
+            .arg("GET")
+            .arg("notify-keyspace-events")
+            .query(&mut conn)
+            .unwrap_or_default();
+        let flags = reply.get(1).cloned().unwrap_or_default();
+        if !notifies_every_change(&flags) {
+            log::warn!(
+                "notify-keyspace-events is {:?}, which needs to include E and A to watch changes",
+                flags
+            );
+            return Err(fuse::DriverError::Unsupported("change notifications"));
+        }
+        let pattern = format!("__keyevent@{}__:*", self.database);
+        let () = redis_cmd!(conn, "PSUBSCRIBE", &pattern);
+        Ok(Box::new(Subscription {
+            conn,
+            channel: pattern,
+        }))
+    }
+
+    // In a cluster, BRPOP is sent to the node owning the queue's slot, as any
+    // other would answer with MOVED.
+    fn consume(&self, queue: &str) -> fuse::DriverResult<Box<dyn fuse::Subscription>> {
+        Ok(Box::new(Consumer {
+            conn: self.subscriber_conn(Some(queue))?,
+            queue: queue.to_string(),
+        }))
+    }
+
+    fn stream_after(
+        &self,
+        key: &str,
+        after: &str,
+        block: Option<Duration>,
+    ) -> fuse::DriverResult<Vec<fuse::StreamEntry>> {
+        let mut conn = read_conn!(self.pool, &[key]);
+        // BLOCK 0 waits forever.
+        let block = block.map_or(0, |b| b.as_millis().max(1) as u64);
+        let reply: Option<Vec<(String, Vec<StreamReply>)>> = redis::cmd("XREAD")
+            .arg("BLOCK")
+            .arg(block)
+            .arg("STREAMS")
+            .arg(key)
+            .arg(after)
+            .query(&mut conn)
+            .context("XREAD", key)?;
+        Ok(reply
+            .into_iter()
+            .flatten()
+            .flat_map(|(_, entries)| entries)
+            .map(stream_entry)
+            .collect())
+    }
+
+    fn sizes(&self, keys: &[String]) -> fuse::DriverResult<Vec<u64>> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut conn = read_conn!(self.pool, keys);
+        let script = redis::Script::new(SIZES_SCRIPT);
+        // In a cluster the keys are spread across slots, so each is sized on
+        // its own.
+        let batches: Vec<&[String]> = match self.pool.clustered() {
+            true => keys.chunks(1).collect(),
+            false => vec![keys],
+        };
+        let mut sizes = Vec::with_capacity(keys.len());
+        for batch in batches {
+            let mut invocation = script.prepare_invoke();
+            for key in batch {
+                invocation.key(key);
+            }
+            let batch: Vec<u64> = invocation
+                .invoke(&mut conn)
+                .context("EVALSHA", &batch.join(" "))?;
+            sizes.extend(batch);
+        }
+        Ok(sizes)
+    }
+
+    // Fails under an LFU maxmemory-policy, which doesn't track idle times.
+    fn idle_times(&self, keys: &[String]) -> fuse::DriverResult<Vec<u64>> {
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut conn = get_conn!(self.pool);
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.cmd("OBJECT").arg("IDLETIME").arg(key);
+        }
+        let idle: Vec<Option<u64>> = pipe.query(&mut conn).context("OBJECT", &keys.join(" "))?;
+        Ok(idle.into_iter().map(|i| i.unwrap_or(0)).collect())
+    }
+
+    fn modified(&self, key: &str) -> fuse::DriverResult<Option<SystemTime>> {
+        if !self.track_mtime {
+            return Ok(None);
+        }
+        let mut conn = read_conn!(self.pool, &[key]);
+        let millis: Option<u64> = conn.hget(MTIMES_KEY, key).context("HGET", MTIMES_KEY)?;
+        Ok(millis.map(|m| UNIX_EPOCH + Duration::from_millis(m)))
+    }
+
+    fn trace(&self) -> fuse::DriverResult<Vec<String>> {
+        Ok(match &self.pool.trace {
+            Some(trace) => trace.lock().unwrap().lines.iter().cloned().collect(),
+            None => vec![],
+        })
+    }
+
+    fn command_counts(&self) -> fuse::DriverResult<Vec<fuse::CommandCount>> {
+        let counts = match &self.pool.counts {
+            Some(v) => v,
+            None => return Err(fuse::DriverError::Unsupported("command counts")),
+        };
+        Ok(counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(command, (sent, failed))| fuse::CommandCount {
+                command: command.clone(),
+                sent: *sent,
+                failed: *failed,
+            })
+            .collect())
+    }
+
+    // Direct connections, eg. for subscriptions or to cluster masters, aren't
+    // counted.
+    fn pool_state(&self) -> fuse::DriverResult<fuse::PoolState> {
+        let (state, max) = match &self.pool.servers {
+            Servers::Single(p, _) => (p.state(), p.max_size()),
+            #[cfg(feature = "cluster")]
+            Servers::Cluster(p, _) => (p.state(), p.max_size()),
+        };
+        Ok(fuse::PoolState {
+            connections: state.connections,
+            idle: state.idle_connections,
+            max,
+        })
+    }
+
+    fn forget_cached(&self) {
+        let mut inos = self.inos.lock().unwrap();
+        inos.by_key.clear();
+        inos.by_ino.clear();
+    }
+}
+
+impl fuse::KVLocker for RedisDriver {
+    fn acquire_lock(
+        &self,
+        name: &str,
+        owner: &str,
+        mode: LockMode,
+        ttl: Option<Duration>,
+    ) -> fuse::DriverResult<fuse::LockOutcome> {
+        let mut conn = get_conn!(self.pool);
+        let (locks, children) = self.lock_prefixes();
+        let script = redis::Script::new(ACQUIRE_LOCK_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        invocation
+            .key(format!("{}{}", locks, name))
+            .key(format!("{}{}", children, name));
+        for ancestor in lock_ancestors(name) {
+            invocation
+                .key(format!("{}{}", locks, ancestor))
+                .key(format!("{}{}", children, ancestor));
+        }
+        invocation
+            .arg(owner)
+            .arg(if mode == LockMode::ParentBlocking {
+                1
+            } else {
+                0
+            })
+            .arg(ttl.map_or(0, |t| t.as_millis() as u64));
+        Ok(
+            match invocation
+                .invoke::<i64>(&mut conn)
+                .context("EVALSHA", name)?
+            {
+                1 => fuse::LockOutcome::Acquired,
+                0 => fuse::LockOutcome::Held,
+                _ => fuse::LockOutcome::Blocked,
+            },
+        )
+    }
+
+    fn release_lock(&self, name: &str, owner: &str) -> fuse::DriverResult<bool> {
+        let mut conn = get_conn!(self.pool);
+        let (locks, children) = self.lock_prefixes();
+        let script = redis::Script::new(RELEASE_LOCK_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        invocation.key(format!("{}{}", locks, name));
+        for ancestor in lock_ancestors(name) {
+            invocation.key(format!("{}{}", children, ancestor));
+        }
+        invocation.arg(owner);
+        Ok(invocation
+            .invoke::<i64>(&mut conn)
+            .context("EVALSHA", name)?
+            == 1)
+    }
+
+    fn lock_owner(&self, name: &str) -> fuse::DriverResult<Option<String>> {
+        let mut conn = get_conn!(self.pool);
+        let (locks, _) = self.lock_prefixes();
+        Ok(redis_cmd!(conn, "GET", format!("{}{}", locks, name)))
+    }
+
+    fn list_locks(&self, prefix: &str) -> fuse::DriverResult<Vec<String>> {
+        let (locks, _) = self.lock_prefixes();
+        let pattern = format!("{}{}*", locks, escape_glob(prefix));
+        let keys = self.scan_internal(&pattern)?;
+        Ok(keys.iter().map(|k| k[locks.len()..].to_string()).collect())
+    }
+
+    fn hold_shared(&self, key: &str, owner: &str, ttl: Duration) -> fuse::DriverResult<()> {
+        let mut conn = get_conn!(self.pool);
+        let locks = format!("{}{}", SHARED_LOCK_PREFIX, key);
+        let expires = SystemTime::now() + ttl;
+        let expires = expires
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        // EXPIRE only takes whole seconds.
+        let secs = (ttl.as_millis() as u64).div_ceil(1000);
+        redis::pipe()
+            .hset(&locks, owner, expires)
+            .ignore()
+            .expire(&locks, secs as i64)
+            .ignore()
+            .query::<()>(&mut conn)
+            .context("HSET", &locks)
+    }
+
+    fn release_shared(&self, key: &str, owner: &str) -> fuse::DriverResult<()> {
+        let mut conn = get_conn!(self.pool);
+        let () = redis_cmd!(
+            conn,
+            "HDEL",
+            format!("{}{}", SHARED_LOCK_PREFIX, key),
+            owner
+        );
+        Ok(())
+    }
+}
+
+impl fuse::KVWriter for RedisDriver {
+    fn preallocate(&self, key: &str, len: u64) -> fuse::DriverResult<()> {
+        let mut conn = get_conn!(self.pool);
+        redis::Script::new(PREALLOCATE_SCRIPT)
+            .key(key)
+            .arg(len)
+            .invoke::<u64>(&mut conn)
+            .context("EVALSHA", key)?;
+        self.pool.wrote(&[key]);
+        Ok(())
+    }
+
+    // Values bigger than the server takes in one bulk string are written a
+    // chunk at a time, so can be read partly written until the last lands.
+    fn set(&self, key: &str, value: &[u8]) -> fuse::DriverResult<()> {
+        let mut conn = get_conn!(self.pool);
+        let chunk = self.max_bulk_len(&mut conn) as usize;
+        let first = &value[..value.len().min(chunk)];
+        let () = redis_cmd!(conn, "SET", key, first);
+        let rest = &value[first.len()..];
+        set_range(&mut conn, key, first.len() as u64, rest, chunk)?;
+        self.touch(&mut conn, &[key]);
+        Ok(())
+    }
+
+    // Values too big for one bulk string are set on their own, as set does.
+    fn set_many(&self, values: &[(String, Vec<u8>)]) -> fuse::DriverResult<()> {
+        if values.is_empty() {
+            return Ok(());
+        }
+        let mut conn = get_conn!(self.pool);
+        let chunk = self.max_bulk_len(&mut conn) as usize;
+        let (small, big): (Vec<_>, Vec<_>) = values.iter().partition(|(_, v)| v.len() <= chunk);
+        let keys: Vec<&str> = small.iter().map(|(k, _)| k.as_str()).collect();
+        if !small.is_empty() {
+            let mut pipe = redis::pipe();
+            for (key, value) in &small {
+                pipe.cmd("SET").arg(key).arg(value).ignore();
+            }
+            let () = pipe.query(&mut conn).context("SET", &keys.join(" "))?;
+            self.touch(&mut conn, &keys);
+        }
+        for (key, value) in big {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    fn transact(&self, writes: &[(String, Option<Vec<u8>>)]) -> fuse::DriverResult<()> {
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (key, value) in writes {
+            match value {
+                Some(v) => pipe.cmd("SET").arg(key).arg(v).ignore(),
+                None => pipe.cmd("DEL").arg(key).ignore(),
+            };
+        }
+        let keys: Vec<&str> = writes.iter().map(|(k, _)| k.as_str()).collect();
+        {
+            let mut conn = self.transaction_conn(&keys)?;
+            let () = pipe.query(&mut conn).context("EXEC", &keys.join(" "))?;
+        }
+        let mut conn = get_conn!(self.pool);
+        let (set, deleted): (Vec<_>, Vec<_>) = writes.iter().partition(|(_, v)| v.is_some());
+        let set: Vec<&str> = set.iter().map(|(k, _)| k.as_str()).collect();
+        let deleted: Vec<String> = deleted.into_iter().map(|(k, _)| k.clone()).collect();
+        self.touch(&mut conn, &set);
+        forget_inos(&mut conn, &self.inos, &deleted);
+        self.forget_mtimes(&mut conn, &deleted);
+        Ok(())
+    }
+
+    fn json_set(&self, key: &str, path: &str, json: &str) -> fuse::DriverResult<bool> {
+        let mut conn = get_conn!(self.pool);
+        let set: Option<String> = redis_cmd!(conn, "JSON.SET", key, path, json);
+        if set.is_some() {
+            self.touch(&mut conn, &[key]);
+        }
+        Ok(set.is_some())
+    }
+
+    fn json_del(&self, key: &str, path: &str) -> fuse::DriverResult<u64> {
+        let mut conn = get_conn!(self.pool);
+        let deleted: u64 = redis_cmd!(conn, "JSON.DEL", key, path);
+        if deleted > 0 {
+            self.touch(&mut conn, &[key]);
+        }
+        Ok(deleted)
+    }
+
+    fn restore(&self, key: &str, ttl: u64, payload: &[u8]) -> fuse::DriverResult<()> {
+        let mut conn = get_conn!(self.pool);
+        let _: () = redis_cmd!(conn, "RESTORE", key, ttl, payload, "REPLACE");
+        self.touch(&mut conn, &[key]);
+        Ok(())
+    }
+
+    fn write_range(&self, key: &str, offset: u64, data: &[u8]) -> fuse::DriverResult<()> {
+        let mut conn = get_conn!(self.pool);
+        let chunk = self.max_bulk_len(&mut conn) as usize;
+        set_range(&mut conn, key, offset, data, chunk)?;
+        self.touch(&mut conn, &[key]);
+        Ok(())
+    }
+
+    fn append(&self, key: &str, separator: &[u8], data: &[u8]) -> fuse::DriverResult<()> {
+        let mut conn = get_conn!(self.pool);
+        match separator.is_empty() {
+            true => {
+                let _: u64 = redis_cmd!(conn, "APPEND", key, data);
+            }
+            false => {
+                redis::Script::new(APPEND_SCRIPT)
+                    .key(key)
+                    .arg(separator)
+                    .arg(data)
+                    .invoke::<u64>(&mut conn)
+                    .context("EVALSHA", key)?;
+            }
+        }
+        self.touch(&mut conn, &[key]);
+        Ok(())
+    }
+
+    fn trim_newline(&self, key: &str, len: u64) -> fuse::DriverResult<()> {
+        let mut conn = get_conn!(self.pool);
+        redis::Script::new(TRIM_NEWLINE_SCRIPT)
+            .key(key)
+            .arg(len)
+            .invoke::<i64>(&mut conn)
+            .context("EVALSHA", key)?;
+        self.pool.wrote(&[key]);
+        Ok(())
+    }
+
+    fn rename(
+        &self,
+        from: &str,
+        to: &str,
+        kind: Option<fuse::ValueKind>,
+        replace: bool,
+    ) -> fuse::DriverResult<fuse::RenameOutcome> {
+        let mut conn = get_conn!(self.pool);
+        let kind = match kind {
+            Some(fuse::ValueKind::String) => "string",
+            Some(fuse::ValueKind::List) => "list",
+            Some(fuse::ValueKind::Set) => "set",
+            Some(fuse::ValueKind::Stream) => "stream",
+            None => "",
+        };
+        let outcome = redis::Script::new(RENAME_SCRIPT)
+            .key(from)
+            .key(to)
+            .key(format!("{}{}", KEY_TAGS_PREFIX, from))
+            .key(format!("{}{}", KEY_TAGS_PREFIX, to))
+            .arg(kind)
+            .arg(if replace { 1 } else { 0 })
+            .arg(TAG_PREFIX)
+            .arg(from)
+            .arg(to)
+            .invoke::<i64>(&mut conn)
+            .context("EVALSHA", from)?;
+        if outcome == 1 {
+            move_ino(&mut conn, &self.inos, from, to);
+            self.forget_mtimes(&mut conn, &[from.to_string()]);
+            self.touch(&mut conn, &[to]);
+        }
+        Ok(match outcome {
+            1 => fuse::RenameOutcome::Renamed,
+            0 => fuse::RenameOutcome::Missing,
+            -1 => fuse::RenameOutcome::Exists,
+            _ => fuse::RenameOutcome::Unconvertible,
+        })
+    }
+
+    fn expire(&self, key: &str, ttl: Option<Duration>) -> fuse::DriverResult<bool> {
+        let mut conn = get_conn!(self.pool);
+        let changed: i64 = match ttl {
+            Some(t) => redis_cmd!(conn, "EXPIRE", key, t.as_secs()),
+            None => redis_cmd!(conn, "PERSIST", key),
+        };
+        self.pool.wrote(&[key]);
+        Ok(changed == 1)
+    }
+
+    fn leave_redirect(&self, from: &str, to: &str, ttl: Duration) -> fuse::DriverResult<()> {
+        let mut conn = get_conn!(self.pool);
+        let redirect = format!("{}{}", REDIRECT_PREFIX, from);
+        let () = redis_cmd!(conn, "SET", redirect, to, "PX", ttl.as_millis() as u64);
+        Ok(())
+    }
+
+    fn incr_by(&self, key: &str, delta: i64) -> fuse::DriverResult<i64> {
+        let mut conn = get_conn!(self.pool);
+        let result: redis::RedisResult<i64> =
+            redis::cmd("INCRBY").arg(key).arg(delta).query(&mut conn);
+        match result {
+            Ok(v) => Ok(v),
+            // Neither holding something other than an integer nor overflowing
+            // will go away if retried.
+            Err(e) if e.code() == Some("ERR") => {
+                log::debug!("Error running INCRBY {}: {}", key, e);
+                Err(fuse::DriverError::WrongType(
+                    "INCRBY".to_string(),
+                    key.to_string(),
+                ))
+            }
+            Err(e) => Err(driver_error(e, "INCRBY", key)),
+        }
+    }
+
+    // SPUBLISH is routed to the node owning the channel's slot, as keys are.
+    fn publish(&self, channel: &str, message: &[u8]) -> fuse::DriverResult<u64> {
+        let mut conn = get_conn!(self.pool);
+        Ok(match self.pool.clustered() {
+            true => redis_cmd!(conn, "SPUBLISH", channel, message),
+            false => redis_cmd!(conn, "PUBLISH", channel, message),
+        })
+    }
+
+    fn push(&self, queue: &str, items: &[Vec<u8>]) -> fuse::DriverResult<()> {
+        if items.is_empty() {
+            return Ok(());
+        }
+        let mut conn = get_conn!(self.pool);
+        let _: u64 = redis_cmd!(conn, "LPUSH", queue, items);
+        self.touch(&mut conn, &[queue]);
+        Ok(())
+    }
+
+    // Tags are read first, so every key DELETE_SCRIPT touches is declared. In
+    // a cluster they're spread across slots, so no one script can touch them
+    // all, and each is deleted or untagged on its own instead.
+    fn delete(&self, keys: &[String]) -> fuse::DriverResult<u64> {
+        let mut conn = get_conn!(self.pool);
+        let tag_sets: Vec<String> = keys
+            .iter()
+            .map(|k| format!("{}{}", KEY_TAGS_PREFIX, k))
+            .collect();
+        let tags: Vec<Vec<String>> = if self.pool.clustered() {
+            let mut tags = vec![];
+            for set in &tag_sets {
+                tags.push(redis_cmd!(conn, "SMEMBERS", set));
+            }
+            tags
+        } else {
+            let mut pipe = redis::pipe();
+            for set in &tag_sets {
+                pipe.smembers(set);
+            }
+            pipe.query(&mut conn)
+                .context("SMEMBERS", &tag_sets.join(" "))?
+        };
+        let untag: Vec<(usize, String)> = tags
+            .iter()
+            .enumerate()
+            .flat_map(|(i, tags)| {
+                tags.iter()
+                    .map(move |t| (i, format!("{}{}", TAG_PREFIX, t)))
+            })
+            .collect();
+        let deleted = if self.pool.clustered() {
+            let mut deleted = 0;
+            for (key, set) in keys.iter().zip(&tag_sets) {
+                let _: u64 = redis_cmd!(conn, "DEL", set);
+                let n: u64 = redis_cmd!(conn, "UNLINK", key);
+                deleted += n;
+            }
+            for (i, set) in &untag {
+                let _: u64 = redis_cmd!(conn, "SREM", set, &keys[*i]);
+            }
+            deleted
+        } else {
+            let script = redis::Script::new(DELETE_SCRIPT);
+            let mut invocation = script.prepare_invoke();
+            invocation.arg(keys.len());
+            for key in keys.iter().chain(&tag_sets) {
+                invocation.key(key);
+            }
+            for (i, set) in &untag {
+                invocation.key(set).arg(i + 1);
+            }
+            invocation
+                .invoke::<u64>(&mut conn)
+                .context("EVALSHA", &keys.join(" "))?
+        };
+        forget_inos(&mut conn, &self.inos, keys);
+        self.forget_mtimes(&mut conn, keys);
+        Ok(deleted)
+    }
+
+    fn write_items(
+        &self,
+        key: &str,
+        kind: fuse::ValueKind,
+        items: &[String],
+        append: bool,
+    ) -> fuse::DriverResult<()> {
+        let mut conn = get_conn!(self.pool);
+        let mut pipe = redis::pipe();
+        // Entries added to a stream don't need to land together.
+        if !append || kind != fuse::ValueKind::Stream {
+            pipe.atomic();
+        }
+        if !append {
+            pipe.del(key).ignore();
+        }
+        // Neither RPUSH nor SADD take zero elements.
+        if !items.is_empty() {
+            match kind {
+                fuse::ValueKind::String => pipe.set(key, items.join("\n")).ignore(),
+                fuse::ValueKind::List => pipe.rpush(key, items).ignore(),
+                fuse::ValueKind::Set => pipe.sadd(key, items).ignore(),
+                fuse::ValueKind::Stream => {
+                    for item in items {
+                        let mut cmd = redis::cmd("XADD");
+                        cmd.arg(key).arg("*");
+                        for (field, value) in fuse::stream_fields(item) {
+                            cmd.arg(field).arg(value);
+                        }
+                        pipe.add_command(cmd).ignore();
+                    }
+                    &mut pipe
+                }
+            };
+        }
+        pipe.query::<()>(&mut conn).context("MULTI", key)?;
+        self.touch(&mut conn, &[key]);
+        Ok(())
+    }
+
+    fn raw(&self, args: &[String]) -> fuse::DriverResult<String> {
+        let mut conn = get_conn!(self.pool);
+        let mut cmd = redis::cmd(&args[0]);
+        cmd.arg(&args[1..]);
+        match cmd.query::<redis::Value>(&mut conn) {
+            Ok(v) => Ok(format_value(&v)),
+            // Only errors sent back by the server have a code.
+            Err(e) => match e.code() {
+                Some(code) => Ok(format!(
+                    "(error) {} {}\n",
+                    code,
+                    e.detail().unwrap_or_default()
+                )),
+                None => Err(driver_error(e, &args[0], args.get(1).map_or("", |a| a))),
+            },
+        }
+    }
+
+    fn eval(&self, script: &str, keys: &[String], args: &[String]) -> fuse::DriverResult<()> {
+        let mut conn = get_conn!(self.pool);
+        redis::Script::new(script)
+            .key(keys)
+            .arg(args)
+            .invoke::<redis::Value>(&mut conn)
+            .context("EVALSHA", &keys.join(" "))?;
+        self.pool.wrote(keys);
+        Ok(())
+    }
+
+    fn append_to_stream(
+        &self,
+        key: &str,
+        max_len: usize,
+        fields: &[(&str, String)],
+    ) -> fuse::DriverResult<()> {
+        let mut conn = get_conn!(self.pool);
+        let mut cmd = redis::cmd("XADD");
+        cmd.arg(key);
+        if max_len > 0 {
+            cmd.arg("MAXLEN").arg("~").arg(max_len);
+        }
+        cmd.arg("*");
+        for (field, value) in fields {
+            cmd.arg(*field).arg(value);
+        }
+        let _: String = cmd.query(&mut conn).context("XADD", key)?;
+        Ok(())
+    }
+
+    fn acl_allows(&self, user: &str, args: &[String]) -> fuse::DriverResult<bool> {
+        let mut conn = get_conn!(self.pool);
+        // Allowed commands get OK, denied ones the reason they would fail.
+        let reply: redis::Value = redis::cmd("ACL")
+            .arg("DRYRUN")
+            .arg(user)
+            .arg(args)
+            .query(&mut conn)
+            .context("ACL", user)?;
+        match reply {
+            redis::Value::Okay => Ok(true),
+            reason => {
+                log::debug!("ACL DRYRUN for {}: {:?}", user, reason);
+                Ok(false)
+            }
+        }
+    }
+}
+
+impl fuse::KVTagger for RedisDriver {
+    fn tag(&self, key: &str, tag: &str) -> fuse::DriverResult<()> {
+        let mut conn = get_conn!(self.pool);
+        let (keys, tags) = (
+            format!("{}{}", TAG_PREFIX, tag),
+            format!("{}{}", KEY_TAGS_PREFIX, key),
+        );
+        if self.pool.clustered() {
+            let _: u64 = redis_cmd!(conn, "SADD", &keys, key);
+            let _: u64 = redis_cmd!(conn, "SADD", &tags, tag);
+            return Ok(());
+        }
+        redis::pipe()
+            .atomic()
+            .sadd(&keys, key)
+            .sadd(&tags, tag)
+            .query::<()>(&mut conn)
+            .context("MULTI", key)?;
+        Ok(())
+    }
+
+    fn untag(&self, key: &str, tag: &str) -> fuse::DriverResult<()> {
+        let mut conn = get_conn!(self.pool);
+        let (keys, tags) = (
+            format!("{}{}", TAG_PREFIX, tag),
+            format!("{}{}", KEY_TAGS_PREFIX, key),
+        );
+        if self.pool.clustered() {
+            let _: u64 = redis_cmd!(conn, "SREM", &keys, key);
+            let _: u64 = redis_cmd!(conn, "SREM", &tags, tag);
+            return Ok(());
+        }
+        redis::pipe()
+            .atomic()
+            .srem(&keys, key)
+            .srem(&tags, tag)
+            .query::<()>(&mut conn)
+            .context("MULTI", key)?;
+        Ok(())
+    }
+
+    fn tags_of(&self, key: &str) -> fuse::DriverResult<Vec<String>> {
+        let mut conn = get_conn!(self.pool);
+        let set = format!("{}{}", KEY_TAGS_PREFIX, key);
+        conn.smembers(&set).context("SMEMBERS", &set)
+    }
+
+    fn tagged(&self, tag: &str) -> fuse::DriverResult<Vec<String>> {
+        let mut conn = get_conn!(self.pool);
+        let set = format!("{}{}", TAG_PREFIX, tag);
+        conn.smembers(&set).context("SMEMBERS", &set)
+    }
+
+    fn list_tags(&self) -> fuse::DriverResult<Vec<String>> {
+        let keys = self.scan_internal(&format!("{}*", TAG_PREFIX))?;
+        Ok(keys
+            .iter()
+            .map(|k| k[TAG_PREFIX.len()..].to_string())
+            .collect())
+    }
+}
+
+impl RedisDriver {
+    fn new(
+        pool: r2d2::Pool<Manager<redis::Connection>>,
+        url: url::Url,
+        reads: Option<ReadServers>,
+        credentials: Arc<Credentials>,
+        config: &Config,
+    ) -> RedisDriver {
+        let database = url_database(&url);
+        RedisDriver {
+            pool: Pool {
+                servers: Servers::Single(pool, url),
+                reads: new_reads(reads, config),
+                credentials,
+                trace: new_trace(config.trace),
+                counts: new_counts(config),
+                retry: Retry::new(config),
+            },
+            inos: Arc::new(Mutex::new(Inos {
+                by_key: LruCache::new(INO_CACHE_SIZE),
+                by_ino: LruCache::new(INO_CACHE_SIZE),
+            })),
+            max_bulk_len: Arc::new(Mutex::new(None)),
+            scripting: Arc::new(Mutex::new(None)),
+            track_mtime: config.track_mtime,
+            database,
+        }
+    }
+
+    // A driver for the cluster pool connects to, reaching its masters directly
+    // with the address of each swapped into seed.
+    #[cfg(feature = "cluster")]
+    fn cluster(
+        pool: r2d2::Pool<Manager<redis::cluster::ClusterConnection>>,
+        seed: url::Url,
+        reads: Option<ReadServers>,
+        credentials: Arc<Credentials>,
+        config: &Config,
+    ) -> RedisDriver {
+        RedisDriver {
+            pool: Pool {
+                servers: Servers::Cluster(pool, seed),
+                reads: new_reads(reads, config),
+                credentials,
+                trace: new_trace(config.trace),
+                counts: new_counts(config),
+                retry: Retry::new(config),
+            },
+            inos: Arc::new(Mutex::new(Inos {
+                by_key: LruCache::new(INO_CACHE_SIZE),
+                by_ino: LruCache::new(INO_CACHE_SIZE),
+            })),
+            max_bulk_len: Arc::new(Mutex::new(None)),
+            scripting: Arc::new(Mutex::new(None)),
+            track_mtime: config.track_mtime,
+            // Clusters only have database 0.
+            database: 0,
+        }
+    }
+
+    // The longest bulk string the server accepts, asked for once. Servers that
+    // don't allow CONFIG are assumed to use the default.
+    fn max_bulk_len(&self, conn: &mut Conn) -> u64 {
+        let mut max_bulk_len = self.max_bulk_len.lock().unwrap();
+        if let Some(v) = *max_bulk_len {
+            return v;
+        }
+        let reply: Vec<String> = redis::cmd("CONFIG")
+            .arg("GET")
+            .arg("proto-max-bulk-len")
+            .query(conn)
+            .unwrap_or_default();
+        let len = match reply.get(1).and_then(|v| v.parse::<u64>().ok()) {
+            Some(v) if v > 0 => v,
+            _ => DEFAULT_MAX_BULK_LEN,
+        };
+        log::debug!("Writing values in chunks of up to {} bytes", len);
+        *max_bulk_len = Some(len);
+        len
+    }
+
+    // Run SCAN_TYPED_SCRIPT on conn, loading it with SCRIPT LOAD the first
+    // time, and again on nodes that don't have it. None if scripting is
+    // disabled, which is only tried once.
+    fn scan_typed_script(
+        &self,
+        conn: &mut Conn,
+        at: u64,
+        pattern: &str,
+        count: usize,
+    ) -> fuse::DriverResult<Option<TypedScan>> {
+        let scripting = *self.scripting.lock().unwrap();
+        if scripting == Some(false) {
+            return Ok(None);
+        }
+        let script = redis::Script::new(SCAN_TYPED_SCRIPT);
+        let load = |conn: &mut Conn| {
+            redis::cmd("SCRIPT")
+                .arg("LOAD")
+                .arg(SCAN_TYPED_SCRIPT)
+                .query::<String>(conn)
+        };
+        if scripting.is_none() {
+            let loaded = load(conn);
+            *self.scripting.lock().unwrap() = Some(loaded.is_ok());
+            if let Err(e) = loaded {
+                log::info!("Listing without scripts, as they can't be loaded: {}", e);
+                return Ok(None);
+            }
+        }
+        let eval = |conn: &mut Conn| {
+            redis::cmd("EVALSHA")
+                .arg(script.get_hash())
+                .arg(0)
+                .arg(at)
+                .arg(pattern)
+                .arg(count)
+                .query::<TypedScan>(conn)
+        };
+        match eval(conn) {
+            Err(e) if e.kind() == redis::ErrorKind::NoScriptError => {
+                load(conn).context("SCRIPT", "LOAD")?;
+                eval(conn).context("EVALSHA", pattern).map(Some)
+            }
+            other => other.context("EVALSHA", pattern).map(Some),
+        }
+    }
+
+    // The prefixes of lock keys and of the sets of locks taken beneath them.
+    fn lock_prefixes(&self) -> (&'static str, &'static str) {
+        match self.pool.clustered() {
+            true => (CLUSTER_LOCK_PREFIX, CLUSTER_LOCK_CHILDREN_PREFIX),
+            false => (LOCK_PREFIX, LOCK_CHILDREN_PREFIX),
+        }
+    }
+
+    // A connection to run a transaction over keys on. A cluster would send
+    // MULTI to any node, so it goes to the one owning the keys' slot instead,
+    // which they must all share.
+    #[cfg_attr(not(feature = "cluster"), allow(unused_variables))]
+    fn transaction_conn(&self, keys: &[&str]) -> fuse::DriverResult<Conn> {
+        match &self.pool.servers {
+            Servers::Single(..) => self.pool.get(),
+            #[cfg(feature = "cluster")]
+            Servers::Cluster(..) => {
+                let mut conn = get_conn!(self.pool);
+                let mut slots = vec![];
+                for key in keys {
+                    let slot: u16 = redis::cmd("CLUSTER")
+                        .arg("KEYSLOT")
+                        .arg(*key)
+                        .query(&mut conn)
+                        .context("CLUSTER", key)?;
+                    slots.push(slot);
+                }
+                slots.sort_unstable();
+                slots.dedup();
+                if slots.len() > 1 {
+                    return Err(fuse::DriverError::Unsupported(
+                        "transactions across cluster slots",
+                    ));
+                }
+                let node = self.subscriber_conn(keys.first().copied())?;
+                Ok(self.pool.conn(Link::Node(node)))
+            }
+        }
+    }
+
+    // Record that keys were just written, for their mtimes and so they're read
+    // from the primary for now. Failing to only leaves their mtimes stale, so
+    // errors are just logged.
+    fn touch(&self, conn: &mut Conn, keys: &[&str]) {
+        self.pool.wrote(keys);
+        if !self.track_mtime {
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        for key in keys {
+            if let Err(e) = conn.hset::<_, _, _, ()>(MTIMES_KEY, *key, now) {
+                log::error!("Error recording mtime of {}: {}", key, e);
+            }
+        }
+    }
+
+    // Forget the mtimes of keys, now that they're gone, reading them from the
+    // primary for now so they don't reappear from replicas.
+    fn forget_mtimes(&self, conn: &mut Conn, keys: &[String]) {
+        self.pool.wrote(keys);
+        if !self.track_mtime {
+            return;
+        }
+        for batch in keys.chunks(INO_BATCH) {
+            if let Err(e) = conn.hdel::<_, _, ()>(MTIMES_KEY, batch) {
+                log::error!("Error forgetting mtimes of deleted keys: {}", e);
+            }
+        }
+    }
+
+    // A connection of its own to the server for subscribing, waiting on a
+    // queue or running a transaction: in a cluster, to the node owning the
+    // slot of key, the channel, queue or one of the keys, or to the seed
+    // without one. Subscribed connections can't run anything else, and
+    // blocked ones would hold up whatever next took them from the pool, so
+    // neither goes back to it.
+    #[cfg_attr(not(feature = "cluster"), allow(unused_variables))]
+    fn subscriber_conn(&self, key: Option<&str>) -> fuse::DriverResult<redis::Connection> {
+        let url = match &self.pool.servers {
+            Servers::Single(_, url) => url.clone(),
+            #[cfg(feature = "cluster")]
+            Servers::Cluster(_, seed) => match key {
+                Some(key) => self.slot_owner(seed, key)?,
+                None => seed.clone(),
+            },
+        };
+        let addr = url.host_str().unwrap_or_default().to_string();
+        self.pool
+            .credentials
+            .connect(|password, tls| open_server(&[with_password(&url, password).to_string()], tls))
+            .context("CONNECT", &addr)
+    }
+
+    // The address of the master owning the slot of key, swapped into seed, or
+    // seed itself if no master claims the slot.
+    #[cfg(feature = "cluster")]
+    fn slot_owner(&self, seed: &url::Url, key: &str) -> fuse::DriverResult<url::Url> {
+        let mut conn = get_conn!(self.pool);
+        let slot: u16 = redis::cmd("CLUSTER")
+            .arg("KEYSLOT")
+            .arg(key)
+            .query(&mut conn)
+            .context("CLUSTER", key)?;
+        let slots: redis::Value = redis::cmd("CLUSTER")
+            .arg("SLOTS")
+            .query(&mut conn)
+            .context("CLUSTER", "")?;
+        let mut url = seed.clone();
+        if let Some((host, port)) = slot_master(&slots, slot) {
+            // Only fails for URLs without a host, which seeds can't be.
+            let _ = url.set_host(Some(&host));
+            let _ = url.set_port(Some(port));
+        }
+        Ok(url)
+    }
+
+    // A connection to every node holding keys: the server itself, or each
+    // master in a cluster.
+    fn node_conns(&self) -> fuse::DriverResult<Vec<Conn>> {
+        match &self.pool.servers {
+            Servers::Single(..) => Ok(vec![get_conn!(self.pool)]),
+            #[cfg(feature = "cluster")]
+            Servers::Cluster(_, seed) => self.cluster_conns(seed, false),
+        }
+    }
+
+    // Like node_conns, but to replicas where read_preference allows: one
+    // keeping up with the server, or a replica of each master in a cluster.
+    // SCAN's cursors carry on across servers holding the same keys, as they
+    // do across a table being resized, so pages may come from different ones.
+    fn read_node_conns(&self) -> fuse::DriverResult<Vec<Conn>> {
+        match &self.pool.servers {
+            Servers::Single(..) => Ok(vec![read_conn!(self.pool)]),
+            #[cfg(feature = "cluster")]
+            Servers::Cluster(_, seed) => self.cluster_conns(seed, self.pool.reads.is_some()),
+        }
+    }
+
+    // A connection to each master of the cluster, or to a replica of each if
+    // replicas is set, with the address of each swapped into seed. Masters
+    // without replicas are connected to themselves.
+    #[cfg(feature = "cluster")]
+    fn cluster_conns(&self, seed: &url::Url, replicas: bool) -> fuse::DriverResult<Vec<Conn>> {
+        let mut conn = get_conn!(self.pool);
+        let slots: redis::Value = redis::cmd("CLUSTER")
+            .arg("SLOTS")
+            .query(&mut conn)
+            .context("CLUSTER", "")?;
+        let mut conns = vec![];
+        for (host, port) in cluster_nodes(&slots, replicas) {
+            let addr = format!("{}:{}", host, port);
+            let mut url = seed.clone();
+            // Only fails for URLs without a host, which seeds can't be.
+            let _ = url.set_host(Some(&host));
+            let _ = url.set_port(Some(port));
+            let node = self
+                .pool
+                .credentials
+                .connect(|password, tls| {
+                    open_server(&[with_password(&url, password).to_string()], tls)
+                })
+                .context("CONNECT", &addr)?;
+            conns.push(self.pool.conn(Link::Node(node)));
+        }
+        Ok(conns)
+    }
+
+    // Up to limit keys matching the glob pattern across every node, giving up
+    // once deadline has passed. SCAN is driven by hand so the deadline is only
+    // checked between batches, keeping every key already fetched. Returns
+    // whether the scan finished. Our own bookkeeping is left out, so nothing
+    // scanned for can delete or list it.
+    fn scan(
+        &self,
+        pattern: &str,
+        limit: usize,
+        deadline: Option<Instant>,
+    ) -> fuse::DriverResult<(Vec<String>, bool)> {
+        self.scan_where(pattern, limit, deadline, |k| !is_internal(k))
+    }
+
+    // Every one of our own keys matching the glob pattern, eg. tag sets.
+    fn scan_internal(&self, pattern: &str) -> fuse::DriverResult<Vec<String>> {
+        Ok(self.scan_where(pattern, usize::MAX, None, |_| true)?.0)
+    }
+
+    fn scan_where(
+        &self,
+        pattern: &str,
+        limit: usize,
+        deadline: Option<Instant>,
+        keep: fn(&str) -> bool,
+    ) -> fuse::DriverResult<(Vec<String>, bool)> {
+        let mut keys = vec![];
+        let mut first = true;
+        for mut conn in self.read_node_conns()? {
+            let mut cursor: u64 = 0;
+            loop {
+                if !first && deadline.is_some_and(|d| Instant::now() >= d) {
+                    return Ok((keys, false));
+                }
+                first = false;
+                let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(pattern)
+                    .query(&mut conn)
+                    .context("SCAN", pattern)?;
+                keys.extend(batch.into_iter().filter(|k| keep(k)));
+                if keys.len() >= limit {
+                    keys.truncate(limit);
+                    return Ok((keys, true));
+                }
+                if next == 0 {
+                    break;
+                }
+                cursor = next;
+            }
+        }
+        Ok((keys, true))
+    }
+
+    // Refs for keys, with the inode each has been handed out.
+    fn refs(&self, keys: Vec<String>) -> fuse::DriverResult<Vec<fuse::KVRef>> {
+        let mut conn = get_conn!(self.pool);
+        let inos = assign_inos(&mut conn, &self.inos, &keys)?;
+        Ok(keys
+            .into_iter()
+            .zip(inos)
+            .map(|(key, ino)| fuse::KVRef { ino, key })
+            .collect())
+    }
+}
+
+// A page of keys scanned from a node: the cursor to carry on from, the keys,
+// and the type and size of each.
+type TypedScan = (u64, Vec<String>, Vec<String>, Vec<u64>);
+
+// Whether key is one of our own, eg. the inode map or a tag set.
+fn is_internal(key: &str) -> bool {
+    key.starts_with(INTERNAL_PREFIX)
+}
+
+// The node and SCAN cursor a scan_keys cursor carries on from.
+fn split_cursor(cursor: &str) -> (usize, u64) {
+    match cursor.split_once(':') {
+        Some((node, at)) => (node.parse().unwrap_or(0), at.parse().unwrap_or(0)),
+        None => (0, 0),
+    }
+}
+
+// The cursor scan_keys carries on from after SCAN on node returned next, which
+// moves on to the next node once one's done.
+fn next_cursor(node: usize, nodes: usize, next: u64) -> Option<String> {
+    match next {
+        0 if node + 1 < nodes => Some(format!("{}:0", node + 1)),
+        0 => None,
+        _ => Some(format!("{}:{}", node, next)),
+    }
+}
+
+// What SCAN_TYPED_SCRIPT returns, fetched with SCAN and then pipelines of TYPE
+// and of whatever sizes each type, for servers that don't allow scripting.
+fn scan_typed_pipelined(
+    conn: &mut Conn,
+    at: u64,
+    pattern: &str,
+    count: usize,
+) -> fuse::DriverResult<TypedScan> {
+    let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+        .arg(at)
+        .arg("MATCH")
+        .arg(pattern)
+        .arg("COUNT")
+        .arg(count)
+        .query(conn)
+        .context("SCAN", pattern)?;
+    if keys.is_empty() {
+        return Ok((next, keys, vec![], vec![]));
+    }
+    let mut pipe = redis::pipe();
+    for key in &keys {
+        pipe.cmd("TYPE").arg(key);
+    }
+    let kinds: Vec<String> = pipe.query(conn).context("TYPE", &keys.join(" "))?;
+    let mut pipe = redis::pipe();
+    let mut sized = vec![];
+    for (i, (key, kind)) in keys.iter().zip(&kinds).enumerate() {
+        match kind.as_str() {
+            "string" => pipe.cmd("STRLEN").arg(key),
+            "hash" => pipe.cmd("HLEN").arg(key),
+            "list" => pipe.cmd("LRANGE").arg(key).arg(0).arg(-1),
+            "set" => pipe.cmd("SMEMBERS").arg(key),
+            _ => continue,
+        };
+        sized.push(i);
+    }
+    let mut sizes = vec![0; keys.len()];
+    if !sized.is_empty() {
+        let replies: Vec<redis::Value> = pipe.query(conn).context("STRLEN", &keys.join(" "))?;
+        for (i, reply) in sized.into_iter().zip(replies) {
+            sizes[i] = match reply {
+                redis::Value::Int(n) => n as u64,
+                // Items are read joined by newlines.
+                redis::Value::Array(items) => items
+                    .iter()
+                    .map(|item| match item {
+                        redis::Value::BulkString(d) => d.len() as u64 + 1,
+                        _ => 1,
+                    })
+                    .sum::<u64>()
+                    .saturating_sub(1),
+                _ => 0,
+            };
+        }
+    }
+    Ok((next, keys, kinds, sizes))
+}
+
+// Write data to key from offset, in SETRANGEs of at most chunk bytes.
+fn set_range(
+    conn: &mut Conn,
+    key: &str,
+    offset: u64,
+    data: &[u8],
+    chunk: usize,
+) -> fuse::DriverResult<()> {
+    let mut offset = offset;
+    for part in data.chunks(chunk.max(1)) {
+        let _: u64 = redis_cmd!(*conn, "SETRANGE", key, offset, part);
+        offset += part.len() as u64;
+    }
+    Ok(())
+}
+
+// GETRANGE's start and inclusive end for size bytes from offset.
+fn getrange_start(offset: u64) -> isize {
+    offset.min(isize::MAX as u64) as isize
+}
+
+fn getrange_end(offset: u64, size: u64) -> isize {
+    offset
+        .saturating_add(size)
+        .saturating_sub(1)
+        .min(isize::MAX as u64) as isize
+}
+
+// The inode of each of keys, handing out one to those that don't have one yet.
+// A key starts at the hash of its name and takes the next free inode if that's
+// held by another key.
+fn assign_inos(
+    conn: &mut Conn,
+    cache: &Mutex<Inos>,
+    keys: &[String],
+) -> fuse::DriverResult<Vec<u64>> {
+    let mut inos: Vec<Option<u64>> = {
+        let mut cache = cache.lock().unwrap();
+        keys.iter().map(|k| cache.by_key.get(k).copied()).collect()
+    };
+    let unknown: Vec<usize> = (0..keys.len()).filter(|&i| inos[i].is_none()).collect();
+    for batch in unknown.chunks(INO_BATCH) {
+        let names: Vec<&str> = batch.iter().map(|&i| keys[i].as_str()).collect();
+        let stored: Vec<Option<u64>> = redis::cmd("HMGET")
+            .arg(INOS_KEY)
+            .arg(&names)
+            .query(conn)
+            .context("HMGET", INOS_KEY)?;
+        let missing: Vec<usize> = batch
+            .iter()
+            .zip(stored)
+            .filter_map(|(&i, ino)| match ino {
+                Some(v) => {
+                    inos[i] = Some(v);
+                    None
+                }
+                None => Some(i),
+            })
+            .collect();
+        if missing.is_empty() {
+            continue;
+        }
+        // Most keys get the inode of their hash on the first try.
+        let mut pipe = redis::pipe();
+        for &i in &missing {
+            pipe.hset_nx(KEYS_BY_INO_KEY, fuse::kv_ino(&keys[i]), &keys[i]);
+        }
+        let claimed: Vec<bool> = pipe.query(conn).context("HSETNX", KEYS_BY_INO_KEY)?;
+        let mut assigned = vec![];
+        for (&i, claimed) in missing.iter().zip(claimed) {
+            let ino = if claimed {
+                fuse::kv_ino(&keys[i])
+            } else {
+                claim_ino(conn, &keys[i])?
+            };
+            inos[i] = Some(ino);
+            assigned.push((keys[i].as_str(), ino));
+        }
+        let _: () = conn
+            .hset_multiple(INOS_KEY, &assigned)
+            .context("HSET", INOS_KEY)?;
+    }
+    let mut cache = cache.lock().unwrap();
+    Ok(keys
+        .iter()
+        .zip(inos)
+        .map(|(key, ino)| {
+            // Every key has been given an inode by now.
+            let ino = ino.unwrap_or_else(|| fuse::kv_ino(key));
+            cache.insert(key, ino);
+            ino
+        })
+        .collect())
+}
+
+// Claim the first free inode after the hash of key, or the one already claimed
+// for it by an earlier attempt that didn't get to record it.
+fn claim_ino(conn: &mut Conn, key: &str) -> fuse::DriverResult<u64> {
+    let mut ino = fuse::kv_ino(key);
+    for _ in 0..INO_PROBES {
+        let holder: Option<String> = conn
+            .hget(KEYS_BY_INO_KEY, ino)
+            .context("HGET", KEYS_BY_INO_KEY)?;
+        match holder {
+            Some(v) if v == key => return Ok(ino),
+            Some(_) => {}
+            None => {
+                let claimed: bool = conn
+                    .hset_nx(KEYS_BY_INO_KEY, ino, key)
+                    .context("HSETNX", KEYS_BY_INO_KEY)?;
+                if claimed {
+                    return Ok(ino);
+                }
+                // Someone else got it first, see who.
+                continue;
+            }
+        }
+        ino = if ino + 1 >= fuse::KV_END {
+            fuse::KV_START
+        } else {
+            ino + 1
+        };
+    }
+    log::warn!(
+        "No free inode near the hash of {}, sharing it with another key.",
+        key
+    );
+    Ok(fuse::kv_ino(key))
+}
+
+// Whether the server behind conn is a replica, which rejects writes.
+fn is_replica<C: redis::ConnectionLike>(conn: &mut C) -> fuse::DriverResult<bool> {
+    let info: redis::InfoDict = redis::cmd("INFO")
+        .arg("replication")
+        .query(conn)
+        .context("INFO", "replication")?;
+    Ok(info.get::<String>("role").as_deref() == Some("slave"))
+}
+
+// Migrate the inode mappings as migrate_inos does, unless the mount mustn't
+// write to the server: it's read-only, or a mirror, or the server is a replica
+// the mount wasn't told to write to anyway. Those leave the mappings as they
+// are, for a writable mount to migrate.
+fn prepare_inos<C: redis::ConnectionLike>(conn: &mut C, config: &Config) -> fuse::DriverResult<()> {
+    // Servers that won't say are taken to be primaries.
+    let replica = !config.replica_writes && is_replica(conn).unwrap_or(false);
+    let read_only = config.read_only || replica;
+    if !read_only {
+        return migrate_inos(conn, config.reset_ino_cache);
+    }
+    let version: Option<u64> = conn.get(INO_VERSION_KEY).context("GET", INO_VERSION_KEY)?;
+    if config.reset_ino_cache || version != Some(INO_VERSION) {
+        log::warn!(
+            "Not migrating or resetting inode mappings on a read-only mount, keys may be given inodes other mounts don't agree with."
+        );
+    }
+    Ok(())
+}
+
+// Bring the inode mappings up to INO_VERSION, keeping the inodes keys were
+// given by older versions, or drop them all if reset.
+fn migrate_inos<C: redis::ConnectionLike>(conn: &mut C, reset: bool) -> fuse::DriverResult<()> {
+    if reset {
+        log::warn!("Resetting inode mappings, keys may be given new inodes.");
+        let _: () = conn
+            .del(&[INOS_KEY, KEYS_BY_INO_KEY, LEGACY_INO_CACHE_KEY][..])
+            .context("DEL", INOS_KEY)?;
+        let () = conn
+            .set(INO_VERSION_KEY, INO_VERSION)
+            .context("SET", INO_VERSION_KEY)?;
+        return Ok(());
+    }
+    let version: Option<u64> = conn.get(INO_VERSION_KEY).context("GET", INO_VERSION_KEY)?;
+    match version {
+        Some(INO_VERSION) => return Ok(()),
+        Some(v) if v > INO_VERSION => {
+            return Err(fuse::DriverError::Corrupt(
+                "GET".to_string(),
+                INO_VERSION_KEY.to_string(),
+                format!(
+                    "inode mappings are in format {}, newer than this fusekv's {}; upgrade it or pass --reset-ino-cache",
+                    v, INO_VERSION
+                ),
+            ))
+        }
+        _ => {}
+    }
+    let mut cursor = 0;
+    let mut migrated = 0;
+    loop {
+        let (next, entries): (u64, Vec<(String, u64)>) = redis::cmd("HSCAN")
+            .arg(LEGACY_INO_CACHE_KEY)
+            .arg(cursor)
+            .arg("COUNT")
+            .arg(INO_BATCH)
+            .query(conn)
+            .context("HSCAN", LEGACY_INO_CACHE_KEY)?;
+        let entries: Vec<(String, u64)> = entries
+            .into_iter()
+            .filter(|(_, ino)| (fuse::KV_START..fuse::KV_END).contains(ino))
+            .collect();
+        if !entries.is_empty() {
+            // Inodes already handed out to other keys by newer mounts win, and
+            // those keys get new ones as they're next seen.
+            let mut pipe = redis::pipe();
+            for (key, ino) in &entries {
+                pipe.hset_nx(KEYS_BY_INO_KEY, *ino, key);
+            }
+            let claimed: Vec<bool> = pipe.query(conn).context("HSETNX", KEYS_BY_INO_KEY)?;
+            let mut pipe = redis::pipe();
+            for ((key, ino), claimed) in entries.iter().zip(claimed) {
+                if claimed {
+                    pipe.hset_nx(INOS_KEY, key, *ino).ignore();
+                    migrated += 1;
+                }
+            }
+            let () = pipe.query(conn).context("HSETNX", INOS_KEY)?;
+        }
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+    if migrated > 0 {
+        log::info!(
+            "Migrated the inodes of {} keys from {}.",
+            migrated,
+            LEGACY_INO_CACHE_KEY
+        );
+    }
+    let _: () = conn
+        .del(LEGACY_INO_CACHE_KEY)
+        .context("DEL", LEGACY_INO_CACHE_KEY)?;
+    let () = conn
+        .set(INO_VERSION_KEY, INO_VERSION)
+        .context("SET", INO_VERSION_KEY)?;
+    Ok(())
+}
+
+// Forget the inodes of keys, now that they're gone. Failing to only leaves
+// stale mappings behind, so errors are just logged.
+fn forget_inos(conn: &mut Conn, cache: &Mutex<Inos>, keys: &[String]) {
+    {
+        let mut cache = cache.lock().unwrap();
+        for key in keys {
+            cache.forget(key);
+        }
+    }
+    for batch in keys.chunks(INO_BATCH) {
+        if let Err(e) = unmap_inos(conn, batch) {
+            log::error!("Error forgetting inodes of deleted keys: {}", e);
+        }
+    }
+}
+
+// Hand from's inode to to, now that it's been renamed, so the file keeps its
+// inode as it would on a local filesystem. Failing to only means to gets a new
+// one, so errors are just logged.
+fn move_ino(conn: &mut Conn, cache: &Mutex<Inos>, from: &str, to: &str) {
+    {
+        let mut cache = cache.lock().unwrap();
+        cache.forget(from);
+        cache.forget(to);
+    }
+    if let Err(e) = remap_ino(conn, from, to) {
+        log::error!("Error moving the inode of {} to {}: {}", from, to, e);
+    }
+}
+
+fn remap_ino(conn: &mut Conn, from: &str, to: &str) -> redis::RedisResult<()> {
+    let ino: Option<u64> = conn.hget(INOS_KEY, from)?;
+    // Whatever to replaced takes its inode with it.
+    unmap_inos(conn, &[from.to_string(), to.to_string()])?;
+    if let Some(ino) = ino {
+        let () = redis::pipe()
+            .hset(INOS_KEY, to, ino)
+            .ignore()
+            .hset(KEYS_BY_INO_KEY, ino, to)
+            .ignore()
+            .query(conn)?;
+    }
+    Ok(())
+}
+
+// Drop keys from both inode hashes.
+fn unmap_inos(conn: &mut Conn, keys: &[String]) -> redis::RedisResult<()> {
+    let inos: Vec<Option<u64>> = redis::cmd("HMGET").arg(INOS_KEY).arg(keys).query(conn)?;
+    let inos: Vec<u64> = inos.into_iter().flatten().collect();
+    if !inos.is_empty() {
+        conn.hdel::<_, _, ()>(KEYS_BY_INO_KEY, inos)?;
+    }
+    conn.hdel(INOS_KEY, keys)
+}
+
+// Where reads go besides the primary, tracking the keys written lately
+// alongside, or None if they only go to the primary.
+fn new_reads(servers: Option<ReadServers>, config: &Config) -> Option<Arc<Reads>> {
+    servers.map(|servers| {
+        Arc::new(Reads {
+            servers,
+            preference: config.read_preference,
+            written: Mutex::new(LruCache::new(WRITTEN_CACHE_SIZE)),
+            hold: Duration::from_secs(config.read_your_writes).max(MIN_READ_YOUR_WRITES),
+        })
+    })
+}
+
+// Command counts, only kept when metrics are served.
+fn new_counts(config: &Config) -> Option<Arc<Mutex<Counts>>> {
+    config
+        .metrics_listen
+        .map(|_| Arc::new(Mutex::new(Counts::new())))
+}
+
+// A trace keeping the last size commands, or None if size is 0.
+fn new_trace(size: usize) -> Option<Arc<Mutex<Trace>>> {
+    match size {
+        0 => None,
+        _ => Some(Arc::new(Mutex::new(Trace {
+            size,
+            lines: VecDeque::new(),
+        }))),
+    }
+}
+
+// Packed commands as a line of text, each argument quoted and cut short past
+// TRACE_ARG_LEN bytes, with pipelined commands separated by semicolons.
+fn describe_packed(packed: &[u8]) -> String {
+    let mut commands = vec![];
+    let mut rest = packed;
+    while let Some((args, next)) = unpack_command(rest) {
+        rest = next;
+        // Passwords are left out, as they are of the audit log. Those are
+        // replaced whole, so there's no need to look past what would be shown.
+        let text: Vec<String> = args
+            .iter()
+            .map(|a| String::from_utf8_lossy(&a[..a.len().min(TRACE_ARG_LEN)]).to_string())
+            .collect();
+        if let Some(redacted) = audit::redact(&text) {
+            commands.push(redacted);
+            continue;
+        }
+        let mut words = vec![];
+        for (i, arg) in args.iter().enumerate() {
+            let text = String::from_utf8_lossy(&arg[..arg.len().min(TRACE_ARG_LEN)]);
+            words.push(match (i, arg.len() > TRACE_ARG_LEN) {
+                (0, _) => text.to_string(),
+                (_, false) => format!("{:?}", text),
+                (_, true) => format!("{:?}...({} bytes)", text, arg.len()),
+            });
+        }
+        commands.push(words.join(" "));
+    }
+    commands.join("; ")
+}
+
+// Count the commands packed in RESP towards counts. Pipelines fail or succeed
+// as a whole.
+fn count_packed(counts: &mut Counts, packed: &[u8], failed: bool) {
+    let mut rest = packed;
+    while let Some((args, next)) = unpack_command(rest) {
+        if let Some(name) = args.first() {
+            let count = counts
+                .entry(String::from_utf8_lossy(name).to_uppercase())
+                .or_default();
+            count.0 += 1;
+            if failed {
+                count.1 += 1;
+            }
+        }
+        rest = next;
+    }
+}
+
+// The arguments of the first command packed in RESP, and what follows it.
+fn unpack_command(packed: &[u8]) -> Option<(Vec<&[u8]>, &[u8])> {
+    let (n, mut rest) = unpack_len(packed, b'*')?;
+    let mut args = Vec::with_capacity(n);
+    for _ in 0..n {
+        let (len, after) = unpack_len(rest, b'$')?;
+        args.push(after.get(..len)?);
+        rest = after.get(len + 2..)?;
+    }
+    Some((args, rest))
+}
+
+// Whether notify-keyspace-events flags has the server send keyevent
+// notifications for every change to the keys fusekv shows, expiries and
+// evictions included.
+fn notifies_every_change(flags: &str) -> bool {
+    flags.contains('E') && (flags.contains('A') || "g$lshxe".chars().all(|c| flags.contains(c)))
+}
+
+// Whether a replica's INFO replication shows it linked to its primary and,
+// unless max_lag is 0, having heard from it in the last max_lag seconds.
+// Primaries ping idle links every repl-ping-replica-period, 10 seconds by
+// default, so a quiet link looks as lagged as that.
+fn keeping_up(info: &redis::InfoDict, max_lag: u64) -> bool {
+    if info.get::<String>("role").as_deref() != Some("slave")
+        || info.get::<String>("master_link_status").as_deref() != Some("up")
+    {
+        return false;
+    }
+    max_lag == 0
+        || matches!(
+            info.get::<i64>("master_last_io_seconds_ago"),
+            Some(secs) if secs >= 0 && secs as u64 <= max_lag
+        )
+}
+
+// The host and port of each online replica a primary's INFO replication
+// lists, as slave<n> fields of the form ip=10.0.0.2,port=6379,state=online.
+fn replica_addrs(info: &redis::InfoDict) -> Vec<(String, u16)> {
+    let replicas = info.get::<u64>("connected_slaves").unwrap_or(0);
+    (0..replicas)
+        .filter_map(|i| info.get::<String>(&format!("slave{}", i)))
+        .filter_map(|fields| {
+            let field = |name: &str| {
+                fields
+                    .split(',')
+                    .filter_map(|f| f.split_once('='))
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| v.to_string())
+            };
+            if field("state").as_deref() != Some("online") {
+                return None;
+            }
+            Some((field("ip")?, field("port")?.parse().ok()?))
+        })
+        .collect()
+}
+
+// The length after prefix at the start of packed, up to its \r\n, and what
+// follows.
+fn unpack_len(packed: &[u8], prefix: u8) -> Option<(usize, &[u8])> {
+    if packed.first() != Some(&prefix) {
+        return None;
+    }
+    let end = packed.iter().position(|&b| b == b'\r')?;
+    let len = std::str::from_utf8(&packed[1..end]).ok()?.parse().ok()?;
+    Some((len, packed.get(end + 2..)?))
+}
+
+// A single connection to the first server in config, with its database,
+// credentials and TLS settings, for commands working on the server's keys
+// directly rather than through a driver. Clusters would need one to each
+// master, so aren't supported.
+pub fn connect(
+    config: &Config,
+    command: &'static str,
+) -> Result<redis::Connection, Box<dyn Error>> {
+    if config.cluster_mode {
+        return Err(Box::new(ConfigError::ClusterUnsupported(command)));
+    }
+    let url = connect_url(config, &config.servers[0].url);
+    let manager = Manager {
+        urls: vec![url],
+        credentials: Credentials::new(config)?,
+        open: open_server,
+    };
+    Ok(manager.connect()?)
+}
+
+// Connect to the servers in config, as a cluster in cluster mode.
+pub fn open(config: &Config) -> Result<Arc<dyn Driver>, Box<dyn Error>> {
+    let credentials = Credentials::new(config)?;
+    if config.cluster_mode {
+        return open_cluster_driver(config, credentials);
+    }
+    let url = &config.servers[0];
+    log::debug!("Attempting to connect to redis URL {}.", url);
+    let url = connect_url(config, &url.url);
+    let manager = Manager {
+        urls: vec![url.clone()],
+        credentials: credentials.clone(),
+        open: open_server,
+    };
+    // Connections are otherwise only made on first use, which is too late to
+    // fail startup.
+    let mut conn = manager.connect()?;
+    prepare_inos(&mut conn, config)?;
+    let reads = open_replicas(config, &url, &mut conn, &credentials)?;
+    Ok(Arc::new(RedisDriver::new(
+        pool_builder(config).build_unchecked(manager),
+        url,
+        reads,
+        credentials,
+        config,
+    )))
+}
+
+// Pools for the replicas in config, along with those the server at url lists
+// over conn if discover_replicas is set. None if reads only go to the primary.
+// Replicas are only connected to once read from.
+fn open_replicas(
+    config: &Config,
+    url: &url::Url,
+    conn: &mut redis::Connection,
+    credentials: &Arc<Credentials>,
+) -> Result<Option<ReadServers>, Box<dyn Error>> {
+    if config.read_preference == ReadPreference::Primary {
+        if !config.replicas.is_empty() || config.discover_replicas {
+            log::warn!("Not reading from replicas, as read_preference is primary.");
+        }
+        return Ok(None);
+    }
+    let mut urls: Vec<url::Url> = config
+        .replicas
+        .iter()
+        .map(|r| connect_url(config, &r.url))
+        .collect();
+    match (config.discover_replicas, url.host_str()) {
+        (false, _) => {}
+        (true, None) => log::warn!(
+            "Not discovering replicas of {}, which has no host for them to share.",
+            url
+        ),
+        (true, Some(_)) => {
+            let info: redis::InfoDict = redis::cmd("INFO")
+                .arg("replication")
+                .query(conn)
+                .context("INFO", "replication")?;
+            for (host, port) in replica_addrs(&info) {
+                let mut replica = url.clone();
+                let _ = replica.set_host(Some(&host));
+                let _ = replica.set_port(Some(port));
+                if !urls.contains(&replica) {
+                    urls.push(replica);
+                }
+            }
+        }
+    }
+    match urls.is_empty() {
+        true => log::warn!("No replicas to read from, so reads go to the primary."),
+        false => log::info!(
+            "Reading from replicas {}.",
+            urls.iter()
+                .map(|u| u.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        ),
+    }
+    let replicas = urls
+        .into_iter()
+        .map(|url| Replica {
+            pool: pool_builder(config).build_unchecked(Manager {
+                urls: vec![url.clone()],
+                credentials: credentials.clone(),
+                open: open_server,
+            }),
+            url,
+            checked: Mutex::new(None),
+        })
+        .collect();
+    Ok(Some(ReadServers::Replicas(Replicas {
+        replicas,
+        max_lag: config.max_replica_lag,
+        next: AtomicUsize::new(0),
+    })))
+}
+
+// Connect to the servers in config as seeds of a cluster.
+#[cfg(feature = "cluster")]
+fn open_cluster_driver(
+    config: &Config,
+    credentials: Arc<Credentials>,
+) -> Result<Arc<dyn Driver>, Box<dyn Error>> {
+    let seeds: Vec<String> = config.servers.iter().map(|s| s.to_string()).collect();
+    log::debug!("Attempting to connect to redis cluster via {:?}.", seeds);
+    let urls: Vec<url::Url> = config
+        .servers
+        .iter()
+        .map(|s| connect_url(config, &s.url))
+        .collect();
+    let manager = Manager {
+        urls: urls.clone(),
+        credentials: credentials.clone(),
+        open: open_cluster,
+    };
+    // Also discovers the cluster's slots, so a bad seed fails startup.
+    prepare_inos(&mut manager.connect()?, config)?;
+    // Replicas aren't checked for lag, which the cluster client doesn't
+    // expose.
+    let reads = match config.read_preference {
+        ReadPreference::Primary => None,
+        _ => Some(ReadServers::Cluster(pool_builder(config).build_unchecked(
+            Manager {
+                urls,
+                credentials: credentials.clone(),
+                open: open_cluster_replicas,
+            },
+        ))),
+    };
+    Ok(Arc::new(RedisDriver::cluster(
+        pool_builder(config).build_unchecked(manager),
+        connect_url(config, &config.servers[0].url),
+        reads,
+        credentials,
+        config,
+    )))
+}
+
+#[cfg(not(feature = "cluster"))]
+fn open_cluster_driver(
+    _config: &Config,
+    _credentials: Arc<Credentials>,
+) -> Result<Arc<dyn Driver>, Box<dyn Error>> {
+    Err(Box::new(ConfigError::NotBuiltIn("cluster")))
+}
+
+// The password connections authenticate with, and the TLS certificates they
+// use. The password is fetched afresh from its file or command whenever the
+// server rejects it, so rotated credentials, eg. short-lived IAM tokens, are
+// picked up without remounting.
+struct Credentials {
+    password: Mutex<Option<Secret>>,
+    file: Option<PathBuf>,
+    command: Option<String>,
+    tls: Tls,
+}
+
+impl Credentials {
+    fn new(config: &Config) -> Result<Arc<Credentials>, ConfigError> {
+        Ok(Arc::new(Credentials {
+            password: Mutex::new(config.redis_password.clone()),
+            file: config.redis_password_file.clone(),
+            command: config.redis_password_command.clone(),
+            tls: Tls::load(config)?,
+        }))
+    }
+
+    // Connect with the current password, and once more with a fresh one if
+    // it's rejected.
+    fn connect<T, F>(&self, connect: F) -> redis::RedisResult<T>
+    where
+        F: Fn(Option<&Secret>, &Tls) -> redis::RedisResult<T>,
+    {
+        let password = self.password.lock().unwrap().clone();
+        match connect(password.as_ref(), &self.tls) {
+            Err(e) if e.kind() == redis::ErrorKind::AuthenticationFailed => match self.refresh() {
+                Some(fresh) => connect(Some(&fresh), &self.tls),
+                None => Err(e),
+            },
+            result => result,
+        }
+    }
+
+    // Fetch the password again, or None if it can't have changed or couldn't
+    // be fetched.
+    fn refresh(&self) -> Option<Secret> {
+        if self.file.is_none() && self.command.is_none() {
+            return None;
+        }
+        match resolve_password(None, self.file.clone(), None, self.command.clone()) {
+            Ok(Some(fresh)) => {
+                log::info!("Password rejected, connecting with a fresh one.");
+                *self.password.lock().unwrap() = Some(fresh.clone());
+                Some(fresh)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                log::error!("Error refreshing the password: {}", e);
+                None
+            }
+        }
+    }
+}
+
+// Makes the pool's connections to urls with open, authenticating with the
+// current credentials.
+struct Manager<C> {
+    urls: Vec<url::Url>,
+    credentials: Arc<Credentials>,
+    open: fn(&[String], &Tls) -> redis::RedisResult<C>,
+}
+
+impl<C: redis::ConnectionLike + Send + 'static> ManageConnection for Manager<C> {
+    type Connection = C;
+    type Error = redis::RedisError;
+
+    fn connect(&self) -> redis::RedisResult<C> {
+        self.credentials.connect(|password, tls| {
+            let urls: Vec<String> = self
+                .urls
+                .iter()
+                .map(|u| with_password(u, password).to_string())
+                .collect();
+            (self.open)(&urls, tls)
+        })
+    }
+
+    // Connections whose credentials have since been revoked fail this too,
+    // so they're replaced by ones made with fresh credentials.
+    fn is_valid(&self, conn: &mut C) -> redis::RedisResult<()> {
+        redis::cmd("PING").query(conn)
+    }
+
+    fn has_broken(&self, conn: &mut C) -> bool {
+        !conn.is_open()
+    }
+}
+
+// The CA certificate connections to rediss:// servers trust, and the client
+// certificate and key they present, read from the files config names. They're
+// given to each connection rather than set for the whole process, so mirrors
+// and other servers don't pick them up.
+#[derive(Default)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+struct Tls {
+    ca_cert: Option<Vec<u8>>,
+    client: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Tls {
+    fn load(config: &Config) -> Result<Tls, ConfigError> {
+        let read = |path: &PathBuf| {
+            std::fs::read(path).map_err(|e| {
+                ConfigError::BadCredentials(format!("reading {}: {}", path.display(), e))
+            })
+        };
+        Ok(Tls {
+            ca_cert: config.tls_ca_cert.as_ref().map(read).transpose()?,
+            client: match (&config.tls_client_cert, &config.tls_client_key) {
+                (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+                _ => None,
+            },
+        })
+    }
+
+    // None if the system's CAs are trusted and no client certificate is
+    // presented, as without any TLS settings.
+    #[cfg(feature = "tls")]
+    fn certificates(&self) -> Option<redis::TlsCertificates> {
+        if self.ca_cert.is_none() && self.client.is_none() {
+            return None;
+        }
+        Some(redis::TlsCertificates {
+            client_tls: self
+                .client
+                .as_ref()
+                .map(|(cert, key)| redis::ClientTlsConfig {
+                    client_cert: cert.clone(),
+                    client_key: key.clone(),
+                }),
+            root_cert: self.ca_cert.clone(),
+        })
+    }
+}
+
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+fn open_server(urls: &[String], tls: &Tls) -> redis::RedisResult<redis::Connection> {
+    #[cfg(feature = "tls")]
+    if let (true, Some(certificates)) = (urls[0].starts_with("rediss:"), tls.certificates()) {
+        return redis::Client::build_with_tls(urls[0].as_str(), certificates)?.get_connection();
+    }
+    redis::Client::open(urls[0].as_str())?.get_connection()
+}
+
+#[cfg(feature = "cluster")]
+fn open_cluster(
+    urls: &[String],
+    tls: &Tls,
+) -> redis::RedisResult<redis::cluster::ClusterConnection> {
+    cluster_client(urls, tls).build()?.get_connection()
+}
+
+// A cluster connection sending commands to a replica of each key's slot, or
+// its master if it has none.
+#[cfg(feature = "cluster")]
+fn open_cluster_replicas(
+    urls: &[String],
+    tls: &Tls,
+) -> redis::RedisResult<redis::cluster::ClusterConnection> {
+    cluster_client(urls, tls)
+        .read_from_replicas()
+        .build()?
+        .get_connection()
+}
+
+#[cfg(feature = "cluster")]
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+fn cluster_client(urls: &[String], tls: &Tls) -> redis::cluster::ClusterClientBuilder {
+    let builder = redis::cluster::ClusterClientBuilder::new(urls.to_vec());
+    #[cfg(feature = "tls")]
+    if let Some(certificates) = tls.certificates() {
+        return builder.certs(certificates);
+    }
+    builder
+}
+
+// url with the database, user and TLS settings from config added. Credentials
+// are kept out of server URLs so they don't show up in ps or logs.
+fn connect_url(config: &Config, url: &url::Url) -> url::Url {
+    let mut url = url.clone();
+    let unix = matches!(url.scheme(), "unix" | "redis+unix");
+    if let Some(db) = config.database {
+        match unix {
+            true => {
+                let pairs: Vec<(String, String)> = url
+                    .query_pairs()
+                    .filter(|(k, _)| k != "db")
+                    .map(|(k, v)| (k.into_owned(), v.into_owned()))
+                    .collect();
+                url.query_pairs_mut()
+                    .clear()
+                    .extend_pairs(pairs)
+                    .append_pair("db", &db.to_string());
+            }
+            false => url.set_path(&format!("/{}", db)),
+        }
+    }
+    if let Some(user) = &config.redis_user {
+        match unix {
+            true => {
+                url.query_pairs_mut().append_pair("user", user);
+            }
+            false => {
+                let _ = url.set_username(user);
+            }
+        }
+    }
+    if config.tls_insecure && url.scheme() == "rediss" {
+        url.set_fragment(Some("insecure"));
+    }
+    url
+}
+
+// The database url connects to, given by its path or, for unix sockets, its
+// db parameter, as redis-rs reads them.
+fn url_database(url: &url::Url) -> i64 {
+    let db = match url.scheme() {
+        "unix" | "redis+unix" => url
+            .query_pairs()
+            .find(|(k, _)| k == "db")
+            .map(|(_, v)| v.into_owned()),
+        _ => Some(url.path().trim_matches('/').to_string()),
+    };
+    db.and_then(|v| v.parse().ok()).unwrap_or(0)
+}
+
+// url with password added, if there is one.
+fn with_password(url: &url::Url, password: Option<&Secret>) -> url::Url {
+    let mut url = url.clone();
+    match (password, url.scheme()) {
+        (None, _) => {}
+        (Some(password), "unix") | (Some(password), "redis+unix") => {
+            url.query_pairs_mut().append_pair("pass", &password.0);
+        }
+        (Some(password), _) => {
+            let _ = url.set_password(Some(&password.0));
+        }
+    }
+    url
+}
+
+// Pool settings from config. Connectivity is checked before the pool is
+// built, so it doesn't wait to fill up before mounting.
+pub fn pool_builder<M: r2d2::ManageConnection>(config: &Config) -> r2d2::Builder<M> {
+    r2d2::Pool::builder()
+        .max_size(config.pool_size)
+        .min_idle(Some(0))
+        .connection_timeout(Duration::from_secs(config.pool_connect_timeout))
+        .idle_timeout(match config.pool_idle_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        })
+}
+
+// Every proper prefix of a /-separated lock name, eg. app and app/db for
+// app/db/migrate.
+fn lock_ancestors(name: &str) -> Vec<&str> {
+    name.match_indices('/').map(|(i, _)| &name[..i]).collect()
+}
+
+// The elements of the list or set at key one per line, along with which it is.
+// Set members are sorted so reads are stable.
+fn read_items<C: redis::ConnectionLike>(
+    conn: &mut C,
+    key: &str,
+) -> fuse::DriverResult<(String, fuse::ValueKind)> {
+    let kind: String = redis::cmd("TYPE")
+        .arg(key)
+        .query(conn)
+        .context("TYPE", key)?;
+    match kind.as_str() {
+        "list" => {
+            let items: Vec<String> = conn.lrange(key, 0, -1).context("LRANGE", key)?;
+            Ok((items.join("\n"), fuse::ValueKind::List))
+        }
+        "set" => {
+            let mut items: Vec<String> = conn.smembers(key).context("SMEMBERS", key)?;
+            items.sort();
+            Ok((items.join("\n"), fuse::ValueKind::Set))
+        }
+        "stream" => {
+            let entries: Vec<(String, Vec<String>)> = redis::cmd("XRANGE")
+                .arg(key)
+                .arg("-")
+                .arg("+")
+                .query(conn)
+                .context("XRANGE", key)?;
+            let lines: Vec<String> = entries
+                .into_iter()
+                .map(|e| stream_entry(e).line())
+                .collect();
+            Ok((lines.join("\n"), fuse::ValueKind::Stream))
+        }
+        "none" => Err(fuse::DriverError::NotFound(
+            "TYPE".to_string(),
+            key.to_string(),
+        )),
+        _ => Err(fuse::DriverError::WrongType(
+            "GET".to_string(),
+            key.to_string(),
+        )),
+    }
+}
+
+// An entry of a stream as XRANGE and XREAD reply with it, its ID and then its
+// fields and values in turn.
+fn stream_entry((id, fields): (String, Vec<String>)) -> fuse::StreamEntry {
+    fuse::StreamEntry {
+        id,
+        fields: fields
+            .chunks(2)
+            .map(|p| (p[0].clone(), p.get(1).cloned().unwrap_or_default()))
+            .collect(),
+    }
+}
+
+// Classify a Redis error by how the filesystem should treat it. Errors the
+// server replies with have a code, everything else is the client's.
+fn driver_error(e: redis::RedisError, command: &str, key: &str) -> fuse::DriverError {
+    log::debug!("Error running {} {}: {}", command, key, e);
+    let (command, key) = (command.to_string(), key.to_string());
+    if e.is_timeout() {
+        return fuse::DriverError::Timeout(command, key);
+    }
+    match (e.kind(), e.code()) {
+        (_, Some("WRONGTYPE")) => fuse::DriverError::WrongType(command, key),
+        (redis::ErrorKind::AuthenticationFailed, _)
+        | (_, Some("NOAUTH"))
+        | (_, Some("NOPERM"))
+        | (_, Some("READONLY")) => fuse::DriverError::PermissionDenied(command, key, e.to_string()),
+        (redis::ErrorKind::TypeError, _) => fuse::DriverError::Corrupt(command, key, e.to_string()),
+        (_, Some("OOM")) => fuse::DriverError::OutOfMemory(command, key, e.to_string()),
+        // Connection failures, and LOADING, TRYAGAIN, CLUSTERDOWN and the
+        // like, which may well succeed if retried.
+        _ => fuse::DriverError::Unavailable(command, key, e.to_string()),
+    }
+}
+
+// Format a reply the way redis-cli does, with one line per array element
+// numbered and indented by nesting.
+fn format_value(value: &redis::Value) -> String {
+    match value {
+        redis::Value::Nil => "(nil)\n".to_string(),
+        redis::Value::Int(i) => format!("(integer) {}\n", i),
+        redis::Value::BulkString(d) => format!("{:?}\n", String::from_utf8_lossy(d)),
+        redis::Value::SimpleString(s) => format!("{}\n", s),
+        redis::Value::Okay => "OK\n".to_string(),
+        redis::Value::Array(items) if items.is_empty() => "(empty array)\n".to_string(),
+        redis::Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .flat_map(|(i, item)| {
+                let number = format!("{}) ", i + 1);
+                let indent = " ".repeat(number.len());
+                format_value(item)
+                    .lines()
+                    .enumerate()
+                    .map(|(j, line)| {
+                        format!("{}{}\n", if j == 0 { &number } else { &indent }, line)
+                    })
+                    .collect::<Vec<String>>()
+            })
+            .collect(),
+        // Only sent over RESP3, which connections don't ask for.
+        other => format!("{:?}\n", other),
+    }
+}
+
+// The host and port of each master in a CLUSTER SLOTS reply, whose entries
+// are [start slot, end slot, [host, port, id], replicas...], or of its first
+// replica instead if replicas is set and it has one.
+#[cfg(feature = "cluster")]
+fn cluster_nodes(slots: &redis::Value, replicas: bool) -> Vec<(String, u16)> {
+    let addr = |node: &redis::Value| match node {
+        redis::Value::Array(node) => match (node.first(), node.get(1)) {
+            (Some(redis::Value::BulkString(host)), Some(redis::Value::Int(port))) => {
+                Some((String::from_utf8_lossy(host).to_string(), *port as u16))
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+    let mut masters: Vec<(String, u16)> = vec![];
+    let mut nodes = vec![];
+    if let redis::Value::Array(ranges) = slots {
+        for range in ranges {
+            let items = match range {
+                redis::Value::Array(items) if items.len() > 2 => items,
+                _ => continue,
+            };
+            let master = match addr(&items[2]) {
+                Some(v) => v,
+                None => continue,
+            };
+            if masters.contains(&master) {
+                continue;
+            }
+            let replica = match replicas {
+                true => items.get(3).and_then(addr),
+                false => None,
+            };
+            nodes.push(replica.unwrap_or_else(|| master.clone()));
+            masters.push(master);
+        }
+    }
+    nodes
+}
+
+// The host and port of the master of the range holding slot in a CLUSTER
+// SLOTS reply, laid out as cluster_nodes expects.
+#[cfg(feature = "cluster")]
+fn slot_master(slots: &redis::Value, slot: u16) -> Option<(String, u16)> {
+    let ranges = match slots {
+        redis::Value::Array(ranges) => ranges,
+        _ => return None,
+    };
+    ranges.iter().find_map(|range| match range {
+        redis::Value::Array(items) => match (items.first(), items.get(1), items.get(2)) {
+            (
+                Some(redis::Value::Int(start)),
+                Some(redis::Value::Int(end)),
+                Some(redis::Value::Array(node)),
+            ) if (*start..=*end).contains(&(slot as i64)) => match (node.first(), node.get(1)) {
+                (Some(redis::Value::BulkString(host)), Some(redis::Value::Int(port))) => {
+                    Some((String::from_utf8_lossy(host).to_string(), *port as u16))
+                }
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+// The payload of a message on a sharded channel, which are sent as
+// [smessage, channel, payload] and not understood by redis::Msg.
+fn shard_message(reply: &redis::Value) -> Option<Vec<u8>> {
+    match reply {
+        redis::Value::Array(items) => match (items.first(), items.get(2)) {
+            (Some(redis::Value::BulkString(kind)), Some(redis::Value::BulkString(payload)))
+                if kind == b"smessage" =>
+            {
+                Some(payload.clone())
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Escape glob metacharacters so s only matches itself in SCAN MATCH.
+fn escape_glob(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if let '*' | '?' | '[' | ']' | '\\' = c {
+            out.push('\\');
+        }
+        out.push(c);
     }
+    out
 }
@@ -0,0 +1,254 @@
+// Export and import of the whole keyspace, or the keys under the configured
+// prefix.
+//
+// Exports are written as RESP-encoded `RESTORE key ttl payload REPLACE`
+// commands, so they can also be replayed with `redis-cli --pipe`. Imports
+// execute any RESP-encoded commands from a file.
+//
+// Both can checkpoint their progress into a state file after every batch so
+// that an interrupted run picks up where it left off instead of restarting.
+use crate::fuse::escape_glob;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Number of keys requested per SCAN, and commands executed between import
+// checkpoints.
+const BATCH: u64 = 1000;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum TransferError {
+        Malformed(offset: u64) {
+            display("Malformed RESP command at byte {} of import file.", offset)
+        }
+        NotRestore(offset: u64) {
+            display("Command at byte {} of import file isn't a RESTORE, the only command imports under a prefix run.", offset)
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    // SCAN cursor to continue exporting from. Unused by imports.
+    cursor: u64,
+    // Number of keys (or commands, for imports) processed so far.
+    keys: u64,
+    // Bytes of the export/import file that have been fully processed.
+    offset: u64,
+    done: bool,
+}
+
+// Export the keys under prefix over conn to file, named without it so they
+// can be imported under another.
+pub fn export(
+    conn: &mut redis::Connection,
+    prefix: &str,
+    file: PathBuf,
+    state: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let mut checkpoint = load_checkpoint(&state)?;
+    if checkpoint.done {
+        log::info!("Export to {} already complete.", file.display());
+        return Ok(());
+    }
+    if checkpoint.keys > 0 {
+        log::info!(
+            "Resuming export to {} after {} keys.",
+            file.display(),
+            checkpoint.keys
+        );
+    }
+
+    // Anything past the last checkpoint belongs to a batch that was never
+    // recorded as finished, so drop it and redo that batch.
+    let mut f = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&file)?;
+    f.set_len(checkpoint.offset)?;
+    f.seek(SeekFrom::End(0))?;
+    let mut out = BufWriter::new(f);
+
+    loop {
+        let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(checkpoint.cursor)
+            .arg("MATCH")
+            .arg(format!("{}*", escape_glob(prefix)))
+            .arg("COUNT")
+            .arg(BATCH)
+            .query(conn)?;
+        for key in keys {
+            let (payload, pttl): (Option<Vec<u8>>, i64) = redis::pipe()
+                .cmd("DUMP")
+                .arg(&key)
+                .cmd("PTTL")
+                .arg(&key)
+                .query(conn)?;
+            // The key expired or was deleted since SCAN returned it.
+            let payload = match payload {
+                Some(v) if pttl != -2 => v,
+                _ => continue,
+            };
+            let ttl = if pttl < 0 { 0 } else { pttl };
+            write_command(
+                &mut out,
+                &[
+                    b"RESTORE",
+                    &key.as_bytes()[prefix.len()..],
+                    ttl.to_string().as_bytes(),
+                    &payload,
+                    b"REPLACE",
+                ],
+            )?;
+            checkpoint.keys += 1;
+        }
+        out.flush()?;
+        checkpoint.offset = out.get_mut().stream_position()?;
+        checkpoint.cursor = next;
+        checkpoint.done = next == 0;
+        save_checkpoint(&state, &checkpoint)?;
+        log::info!("Exported {} keys.", checkpoint.keys);
+        if checkpoint.done {
+            return Ok(());
+        }
+    }
+}
+
+// Run the commands in file over conn. Under a prefix they can only be the
+// RESTOREs exports write, whose keys it's added to.
+pub fn import(
+    conn: &mut redis::Connection,
+    prefix: &str,
+    file: PathBuf,
+    state: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let mut checkpoint = load_checkpoint(&state)?;
+    if checkpoint.done {
+        log::info!("Import from {} already complete.", file.display());
+        return Ok(());
+    }
+    if checkpoint.keys > 0 {
+        log::info!(
+            "Resuming import from {} after {} commands.",
+            file.display(),
+            checkpoint.keys
+        );
+    }
+
+    let mut f = fs::File::open(&file)?;
+    f.seek(SeekFrom::Start(checkpoint.offset))?;
+    let mut reader = BufReader::new(f);
+    let mut offset = checkpoint.offset;
+    loop {
+        let (mut args, len) = match read_command(&mut reader) {
+            Ok(Some(v)) => v,
+            Ok(None) => break,
+            Err(_) => return Err(Box::new(TransferError::Malformed(offset))),
+        };
+        if !prefix.is_empty() {
+            if args.len() < 2 || !args[0].eq_ignore_ascii_case(b"RESTORE") {
+                return Err(Box::new(TransferError::NotRestore(offset)));
+            }
+            args[1].splice(0..0, prefix.bytes());
+        }
+        let mut cmd = redis::cmd(&String::from_utf8_lossy(&args[0]));
+        for arg in &args[1..] {
+            cmd.arg(&arg[..]);
+        }
+        cmd.query::<redis::Value>(conn)?;
+        offset += len;
+        checkpoint.keys += 1;
+        if checkpoint.keys % BATCH == 0 {
+            checkpoint.offset = offset;
+            save_checkpoint(&state, &checkpoint)?;
+            log::info!("Imported {} commands.", checkpoint.keys);
+        }
+    }
+    checkpoint.offset = offset;
+    checkpoint.done = true;
+    save_checkpoint(&state, &checkpoint)?;
+    log::info!("Imported {} commands.", checkpoint.keys);
+    Ok(())
+}
+
+fn load_checkpoint(state: &Option<PathBuf>) -> Result<Checkpoint, Box<dyn Error>> {
+    match state {
+        Some(path) if path.exists() => Ok(toml::from_str(&fs::read_to_string(path)?)?),
+        _ => Ok(Checkpoint::default()),
+    }
+}
+
+fn save_checkpoint(state: &Option<PathBuf>, checkpoint: &Checkpoint) -> Result<(), Box<dyn Error>> {
+    let path = match state {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    // Write then rename so a crash never leaves a half-written state file.
+    let tmp = tmp_path(path);
+    fs::write(&tmp, toml::to_string(checkpoint)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+pub fn write_command(out: &mut impl Write, args: &[&[u8]]) -> std::io::Result<()> {
+    write!(out, "*{}\r\n", args.len())?;
+    for arg in args {
+        write!(out, "${}\r\n", arg.len())?;
+        out.write_all(arg)?;
+        out.write_all(b"\r\n")?;
+    }
+    Ok(())
+}
+
+// Read one RESP array of bulk strings, returning its args and encoded length.
+// Nothing is allocated on the word of the headers, which may be corrupt, so a
+// file claiming more or longer args than it holds fails once it runs out.
+pub fn read_command(reader: &mut impl BufRead) -> std::io::Result<Option<(Vec<Vec<u8>>, u64)>> {
+    let mut line = String::new();
+    let mut len = reader.read_line(&mut line)? as u64;
+    if len == 0 {
+        return Ok(None);
+    }
+    let n: usize = parse_header(&line, '*')?;
+    let mut args = vec![];
+    for _ in 0..n {
+        line.clear();
+        len += reader.read_line(&mut line)? as u64;
+        let size: u64 = parse_header(&line, '$')?;
+        let want = size.checked_add(2).ok_or_else(invalid)?;
+        let mut buf = vec![];
+        reader.by_ref().take(want).read_to_end(&mut buf)?;
+        if buf.len() as u64 != want || !buf.ends_with(b"\r\n") {
+            return Err(invalid());
+        }
+        len += want;
+        buf.truncate(size as usize);
+        args.push(buf);
+    }
+    if args.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Some((args, len)))
+}
+
+fn parse_header<T: std::str::FromStr>(line: &str, prefix: char) -> std::io::Result<T> {
+    match line.trim_end().strip_prefix(prefix).map(|v| v.parse()) {
+        Some(Ok(v)) => Ok(v),
+        _ => Err(invalid()),
+    }
+}
+
+fn invalid() -> std::io::Error {
+    std::io::Error::from(std::io::ErrorKind::InvalidData)
+}
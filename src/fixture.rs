@@ -0,0 +1,111 @@
+// Fixtures: a declared set of keys loaded into the backend, so demos,
+// tutorials, and tests all start from the same keyspace.
+//
+// A fixture file is TOML with one [[key]] stanza per key:
+//
+//   [[key]]
+//   name = "greeting"
+//   value = "hello"
+//   ttl = 60
+//
+//   [[key]]
+//   name = "jobs"
+//   type = "list"
+//   items = ["job1", "job2"]
+//
+// Strings take a value, and lists and sets items. Keys are replaced wholesale,
+// tags included, while keys the fixture doesn't mention are left alone.
+use crate::drivers::Driver;
+use crate::fuse::{DriverResult, ValueKind};
+
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum FixtureError {
+        Io(err: std::io::Error) {
+            source(err)
+            display("Error reading fixture: {}", err)
+        }
+        Parse(err: toml::de::Error) {
+            source(err)
+            display("Error parsing fixture: {}", err)
+        }
+        BadKey(name: String, reason: &'static str) {
+            display("Fixture key {} {}.", name, reason)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+#[derive(Default)]
+enum KeyType {
+    #[default]
+    String,
+    List,
+    Set,
+}
+
+#[derive(Debug, Deserialize)]
+struct FixtureKey {
+    name: String,
+    #[serde(rename = "type", default)]
+    kind: KeyType,
+    value: Option<String>,
+    items: Option<Vec<String>>,
+    // Seconds until the key expires.
+    ttl: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct Fixture {
+    #[serde(default)]
+    key: Vec<FixtureKey>,
+}
+
+impl Fixture {
+    // Read and check the fixture at path.
+    pub fn read(path: &Path) -> Result<Fixture, FixtureError> {
+        let content = fs::read_to_string(path).map_err(FixtureError::Io)?;
+        let fixture: Fixture = toml::from_str(&content).map_err(FixtureError::Parse)?;
+        for key in &fixture.key {
+            let reason = match (key.kind, &key.value, &key.items) {
+                _ if key.ttl == Some(0) => "must have a ttl above 0",
+                (KeyType::String, Some(_), None)
+                | (KeyType::List, None, Some(_))
+                | (KeyType::Set, None, Some(_)) => continue,
+                (KeyType::String, _, _) => "must set value and not items",
+                _ => "must set items and not value",
+            };
+            return Err(FixtureError::BadKey(key.name.clone(), reason));
+        }
+        Ok(fixture)
+    }
+
+    // Write every key in the fixture through driver, returning how many there
+    // were.
+    pub fn load(&self, driver: &dyn Driver) -> DriverResult<usize> {
+        for key in &self.key {
+            log::debug!("Loading fixture key {}", key.name);
+            // Also clears any tags and TTL, and lets the type change.
+            driver.delete(std::slice::from_ref(&key.name))?;
+            let items = key.items.as_deref().unwrap_or_default();
+            match key.kind {
+                KeyType::String => driver.set(
+                    &key.name,
+                    key.value.as_deref().unwrap_or_default().as_bytes(),
+                )?,
+                KeyType::List => driver.write_items(&key.name, ValueKind::List, items, false)?,
+                KeyType::Set => driver.write_items(&key.name, ValueKind::Set, items, false)?,
+            }
+            if let Some(ttl) = key.ttl {
+                driver.expire(&key.name, Some(Duration::from_secs(ttl)))?;
+            }
+        }
+        Ok(self.key.len())
+    }
+}
@@ -1,42 +1,482 @@
-use crate::config::Config;
+use crate::audit::{self, Audit, Audited};
+use crate::cache::Cache;
+use crate::churn::Churn;
+use crate::coalesce::{WriteBehind, WriteCoalescer};
+use crate::codec::{self, Codec, CodecError};
+use crate::config::{
+    Config, EmptyFile, HookOp, ListingOrder, LockMode, LockPrivacy, PathPolicy, Reloader,
+    TtlDisplay,
+};
+use crate::drivers::Driver;
+use crate::export::{read_command, write_command};
+use crate::fixture::Fixture;
+use crate::hooks::Hooks;
+use crate::invalidation::{Changed, Invalidations};
+use crate::metrics::{Metrics, OpTimer};
+use crate::readers::{Job, Readers};
+use crate::readlocks::ReadLocks;
+use crate::tasks::Tasks;
+use crate::validate;
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request,
+    BsdFileFlags, Errno, FileAttr, FileType, Filesystem, FopenFlags, Generation, INodeNo,
+    LockOwner, OpenFlags, RenameFlags, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow,
+    WriteFlags,
+};
+use libc::{
+    FALLOC_FL_KEEP_SIZE, O_ACCMODE, O_APPEND, O_DIRECT, O_NONBLOCK, O_RDONLY, O_TRUNC, O_WRONLY,
+    RENAME_EXCHANGE, RENAME_NOREPLACE,
 };
-use libc::{EAGAIN, ENOENT};
-use seahash;
-use std::collections::HashMap;
-use std::error;
-use std::error::Error;
+use lru::LruCache;
+use openssl::base64;
+use openssl::sha::sha256;
+use regex::Regex;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::ffi::OsStr;
-use std::time::{Duration, SystemTime};
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+// Seconds blocking reads of a file may wait, overriding any configured timeout.
+const BLOCKING_TIMEOUT_XATTR: &str = "user.fusekv.blocking_timeout";
+
+// Set to 1 on a /kv file before opening it to have the first read of each
+// handle fetch the whole value, and every later read see those same bytes
+// however big the value is or whatever happens to the key meanwhile.
+const SNAPSHOT_XATTR: &str = "user.fusekv.snapshot";
+
+// Set to 1 on a /kv file before opening it to have each handle opened only for
+// reading hold a shared lock on the key until released, for other clients
+// writing the key to wait for.
+const READ_LOCK_XATTR: &str = "user.fusekv.readlock";
+
+// Hex SHA-256 of a key's value, also readable from /kv/<key>SHA256_SUFFIX.
+const SHA256_XATTR: &str = "user.fusekv.sha256";
+const SHA256_SUFFIX: &str = ":sha256";
+
+// The codec a key's value is compressed with, if any, read-only. Compressed
+// values are also readable decompressed from /kv/<key>DECOMPRESSED_SUFFIX.
+const CODEC_XATTR: &str = "user.fusekv.codec";
+const DECOMPRESSED_SUFFIX: &str = ":decompressed";
+
+// Number of value checksums remembered, so unchanged values aren't rehashed.
+const CHECKSUM_CACHE_SIZE: usize = 1024;
+
+// Comma-separated operations an inode supports, eg. for writing SELinux or
+// AppArmor policy around the mount.
+const OPERATIONS_XATTR: &str = "user.fusekv.operations";
+
+// Seconds until a key expires. Setting it expires the key, removing it makes
+// the key persist.
+const TTL_XATTR: &str = "user.ttl";
+
+// The backend's type and internal encoding of a key's value, read-only.
+const TYPE_XATTR: &str = "user.type";
+const ENCODING_XATTR: &str = "user.encoding";
+
+// Bytes the backend uses to store a key, read-only.
+const MEMORY_XATTR: &str = "user.memory";
+
+// Keys whose metadata is fetched at once when an xattr of one of them is read
+// after listing its directory, so tools reading xattrs of each file listed
+// don't make a round trip per file.
+const KEY_INFO_BATCH: usize = 1000;
+
+// Keys scanned at a time while listing /kv and its namespaces.
+const READDIR_PAGE: usize = 1000;
+
+// Usage of each configured quota, one per line as <name> <used> <limit>
+// <state>, on the mount root only.
+const QUOTA_XATTR: &str = "user.fusekv.quota";
+
+// Why the last write to a key was rejected by validation, until one isn't.
+const LAST_REJECT_XATTR: &str = "user.fusekv.last_reject";
 
-const TTL: Duration = Duration::from_secs(1); // 1 second
+// Block size reported by statfs, which memory quotas are counted in.
+const STATFS_BLOCK_SIZE: u64 = 4096;
+
+// Keys are tagged with <tag> by setting the xattr TAG_XATTR_PREFIX + <tag>.
+const TAG_XATTR_PREFIX: &str = "user.fusekv.tag.";
 
 // /raw
 const RAW_START: u64 = 2;
 const RAW_END: u64 = 8191;
 
+// /.fusekv control files, which produce their content when read.
+const CONTROL_DIR: u64 = 6144;
+const CONTROL_FREEZE: u64 = 6145;
+const CONTROL_CONFIRM: u64 = 6146;
+const CONTROL_CAPABILITIES: u64 = 6147;
+const CONTROL_HOTKEYS: u64 = 6148;
+const CONTROL_STATS: u64 = 6149;
+const CONTROL_RAW_HISTORY: u64 = 6150;
+const CONTROL_TRACE: u64 = 6151;
+const CONTROL_INVALIDATE: u64 = 6152;
+const CONTROL_RELOAD: u64 = 6153;
+const CONTROL_SYNC: u64 = 6154;
+
+// Static files and directories from config.
+const STATIC_START: u64 = 7168;
+const STATIC_END: u64 = 8191;
+
+// How often the backend's clock is compared with ours.
+const CLOCK_SKEW_INTERVAL: Duration = Duration::from_secs(60);
+
+// How often the backend's key count is checked for signs of it being flushed
+// elsewhere. Keyspace notifications aren't sent for FLUSHDB or FLUSHALL, so
+// losing more than half its keys at once is taken as one.
+const FLUSH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Number of keys access counts are kept for, least recently accessed first out.
+const HOT_KEYS_TRACKED: usize = 10_000;
+
+// Features that need a minimum Redis version, reported as degraded in
+// /.fusekv/capabilities when the server is older.
+const VERSIONED_FEATURES: &[(&str, (u64, u64))] = &[
+    ("unlink", (4, 0)),
+    ("streams", (5, 0)),
+    ("copy", (6, 2)),
+    ("getdel", (6, 2)),
+];
+
 // /lock/<name>
 const LOCK_START: u64 = 8192;
 const LOCK_END: u64 = 100_000_000_000_000;
 
+// /tags/<tag>
+const TAGS_START: u64 = 200_000_000_000_000;
+const TAGS_END: u64 = 300_000_000_000_000;
+
+// /kv/.match/<pattern>
+const KV_MATCH: u64 = 4098;
+
+// /kv:random, /kv:random:value, and /kv:count, whose content is generated
+// afresh for every open.
+const KV_RANDOM: u64 = 4099;
+const KV_RANDOM_VALUE: u64 = 4100;
+const KV_COUNT: u64 = 4101;
+
+// Listed last in /kv when listing_timeout cut the listing short.
+const KV_TRUNCATED: u64 = 4102;
+const TRUNCATED_NAME: &str = "\u{2026}truncated";
+const TRUNCATED_HELP: &str = "Listing this directory took longer than listing_timeout,
+so only the keys found by then are shown. Keys not listed can still be opened
+by name. See /.fusekv/stats for how often this happens.
+";
+const MATCH_START: u64 = 300_000_000_000_001;
+const MATCH_END: u64 = 399_999_999_999_999;
+
+// Keys deleted per driver call by bulk deletes.
+const BULK_DELETE_BATCH: usize = 1000;
+
 // /kv/<name>
-const KV_START: u64 = 400_000_000_000_000;
-const KV_END: u64 = 500_000_000_000_000;
+pub const KV_START: u64 = 400_000_000_000_000;
+pub const KV_END: u64 = 500_000_000_000_000;
+
+// /kv/<namespace>, directories of the keys sharing a prefix up to separator.
+const NAMESPACE_START: u64 = 500_000_000_000_001;
+const NAMESPACE_END: u64 = 599_999_999_999_999;
+
+// /history/<timestamp>
+const HISTORY_START: u64 = 600_000_000_000_000;
+const HISTORY_END: u64 = 700_000_000_000_000;
+
+// /history/<timestamp>/<name>
+const HISTORY_KEY_START: u64 = 700_000_000_000_001;
+const HISTORY_KEY_END: u64 = 800_000_000_000_000;
+
+// /pubsub/<channel>
+const PUBSUB_START: u64 = 800_000_000_000_001;
+const PUBSUB_END: u64 = 899_999_999_999_999;
+
+// /counter/<name>
+const COUNTER_START: u64 = 900_000_000_000_001;
+const COUNTER_END: u64 = 999_999_999_999_999;
+
+// /mirror, and /mirror/<name> for each configured mirror in order.
+const MIRROR_DIR: u64 = 6400;
+const MIRROR_START: u64 = 6402;
+const MIRROR_END: u64 = 6655;
+
+// /mirror/<name>/<key>
+const MIRROR_KEY_START: u64 = 1_000_000_000_000_001;
+const MIRROR_KEY_END: u64 = 1_099_999_999_999_999;
+
+// /txn and /publish, and /txn/<session> and /publish/<batch>
+const TXN_DIR: u64 = 6656;
+const PUBLISH_DIR: u64 = 6658;
+const TXN_START: u64 = 1_100_000_000_000_001;
+const TXN_END: u64 = 1_199_999_999_999_999;
+
+// /txn/<session>/<key> and /publish/<batch>/<key>, and the files controlling
+// each
+const TXN_KEY_START: u64 = 1_200_000_000_000_001;
+const TXN_KEY_END: u64 = 1_299_999_999_999_999;
+const TXN_CONTROL: &str = ".control";
+const PUBLISH_CONTROL: &str = ".commit";
+const PUBLISH_MANIFEST: &str = ".manifest";
+
+// /kv/<key>/..., the objects, arrays, and values of keys holding JSON
+// documents, including the directory of each document itself.
+const JSON_START: u64 = 1_300_000_000_000_001;
+const JSON_END: u64 = 1_399_999_999_999_999;
+
+// /find, and /find/<glob>
+const FIND_DIR: u64 = 6660;
+const FIND_START: u64 = 1_400_000_000_000_001;
+const FIND_END: u64 = 1_499_999_999_999_999;
+
+// /find/<glob>/<key>, links to each key matching glob
+const FIND_LINK_START: u64 = 1_500_000_000_000_001;
+const FIND_LINK_END: u64 = 1_599_999_999_999_999;
+
+// /dump, and /dump/<prefix>
+const DUMP_DIR: u64 = 6662;
+const DUMP_START: u64 = 1_600_000_000_000_001;
+const DUMP_END: u64 = 1_699_999_999_999_999;
+
+// /queue, and /queue/<name>
+const QUEUE_DIR: u64 = 6664;
+const QUEUE_START: u64 = 1_700_000_000_000_001;
+const QUEUE_END: u64 = 1_799_999_999_999_999;
+
+// Keys most open at once listed in /.fusekv/stats.
+const OPEN_KEYS_REPORTED: usize = 10;
+
+// Keys dumped per scan of a /dump file.
+const DUMP_BATCH: usize = 1000;
 
 const RAW_HELP: &str = "Send raw commands to Redis.
 
-TODO fill this in with how to use /raw
+Write a command to /raw, one per line, and read the reply back through the
+same file handle, formatted like redis-cli does:
+  $ exec 3<>/raw
+  $ echo 'SET greeting \"hello world\"' >&3
+  $ echo 'GET greeting' >&3
+  $ cat <&3
+  OK
+  \"hello world\"
+
+Arguments containing whitespace can be quoted with ' or \". Errors returned by
+Redis are part of the reply, prefixed with (error).
 ";
 
 const LOCK_HELP: &str = "Atomic locks via files.
 
-TODO fill this in with how to use /lock
+Create a file under /lock to take a lock, and remove it to release it:
+  $ touch /lock/deploy      # fails with EEXIST if someone else holds it
+  $ rm /lock/deploy
+
+Lock names can be nested into namespaces by creating directories first:
+  $ mkdir -p /lock/app/db
+  $ touch /lock/app/db/migrate
+
+Locks expire after lock_ttl seconds if set, in case their holder dies.
+
+With lock_mode = \"parent-blocking\", holding app/db blocks taking
+app/db/migrate and vice versa (EBUSY). Reading a lock returns its owner.
+
+With lock_privacy = \"hide\", only your own locks are listed or found, and with
+lock_privacy = \"stub\", reading someone else's lock fails with EACCES.
+";
+
+const TAGS_HELP: &str = "Browse keys by tag.
+
+Tag keys under /kv by setting an xattr named user.fusekv.tag.<tag>, after which
+they appear under /tags/<tag>:
+  $ setfattr -n user.fusekv.tag.prod /kv/mykey
+  $ ls /tags/prod
+  mykey
+  $ setfattr -x user.fusekv.tag.prod /kv/mykey
+
+Removing a file from /tags/<tag> untags the key without deleting it.
+";
+
+const HISTORY_HELP: &str = "Keys as they were in the past.
+
+/history/<timestamp> holds every key as it was at that unix timestamp, in
+seconds, as far as recorded versions go. It is always read-only:
+  $ cat /history/1625097600/mykey
+  $ diff /history/1625097600/mykey /kv/mykey
+";
+
+const PUBSUB_HELP: &str = "Publish and subscribe via files.
+
+Every channel is a file under /pubsub. Writing to one publishes each line
+written as a message, and reading from one subscribes, reading one message
+per line as they're published:
+  $ cat /pubsub/news &
+  $ echo hello > /pubsub/news
+  hello
+
+Reads wait up to blocking_timeout seconds for a message before failing with
+ETIMEDOUT. Only what's published after opening is read, and closing the file
+unsubscribes. Listing /pubsub shows the channels with subscribers.
+";
+
+const COUNTER_HELP: &str = "Atomic counters via files.
+
+/counter/<name> reads as the integer held by the key name, or 0 if there's no
+such key. Writing a number sets it, and appending a signed number adds it to
+the counter atomically, so concurrent scripts never lose each other's counts:
+  $ echo 10 > /counter/hits
+  $ echo +1 >> /counter/hits
+  $ echo -3 >> /counter/hits
+  $ cat /counter/hits
+  8
+
+Counters are ordinary keys, also found under /kv. Appending to one that holds
+something other than an integer fails with EINVAL. /counter lists nothing,
+counters are looked up by name.
+";
+
+const MIRROR_HELP: &str = "Other servers' keys, read-only.
+
+/mirror/<name> holds the keys of each mirror configured, eg. another
+environment's Redis, so it can be compared with this one:
+  $ diff -r /kv /mirror/staging
+  $ cat /mirror/staging/mykey
+
+Mirrors can't be written to, and have none of the extras of /kv, eg. namespaces
+or xattrs.
+";
+
+const TXN_HELP: &str = "Atomic multi-key updates via files.
+
+mkdir /txn/<session> opens a transaction. Writing, creating, or removing files
+in it queues setting or deleting the keys of the same name, and nothing
+reaches Redis until the session is committed, when every queued write lands at
+once with MULTI/EXEC:
+  $ mkdir /txn/move
+  $ echo 90 > /txn/move/alice
+  $ echo 110 > /txn/move/bob
+  $ echo commit > /txn/move/.control
+  $ rmdir /txn/move
+
+Files in a session read as their queued value, or the key's current one if
+nothing is queued for it, but only keys with writes queued are listed. .control
+reads as the queued writes, one per line. Writing discard to it drops them, as
+does removing the session. Committing leaves the session open and empty.
+Sessions only exist in this mount, and are lost when it's unmounted.
+";
+
+const PUBLISH_HELP: &str = "All-or-nothing publishes of checksummed files.
+
+mkdir /publish/<batch> opens a batch. Files copied into it are held back along
+with .manifest, which lists the SHA-256 of each as sha256sum prints them.
+Writing anything to .commit checks every file against the manifest, and only
+if they all match sets their keys at once with MULTI/EXEC:
+  $ mkdir /publish/v42
+  $ cp config/* /publish/v42/
+  $ (cd config && sha256sum *) > /publish/v42/.manifest
+  $ echo > /publish/v42/.commit
+
+A batch disappears once published. Files missing from the manifest, listed but
+missing, or not matching their checksum fail the commit with EBADMSG, leaving
+the batch to be fixed or removed with rm -r.
+";
+
+const FIND_HELP: &str = "Keys matching glob patterns.
+
+/find/<glob> lists every key matching glob, as Redis' SCAN MATCH does, each a
+link to the key's file under /kv:
+  $ ls /find/'sess:*'
+  $ cat /find/'user:[0-9]*'/user:42
+
+Like /kv, the keys are scanned a page at a time as they're listed, so large
+matches start listing straight away. /find itself lists nothing, patterns are
+looked up by name. Keys with / in their names aren't listed.
+";
+
+const DUMP_HELP: &str = "Backups of keys under a prefix via files.
+
+/dump/<prefix> reads as every key starting with prefix, serialized as
+RESP-encoded `RESTORE key ttl payload REPLACE` commands, the same as fusekv
+export writes. Writing one back restores every key in it, so copying a
+namespace aside and back is:
+  $ cp /dump/users: users.dump
+  $ cp users.dump /dump/users:
+
+Keys keep their types and remaining time to live. Restoring fails with EINVAL,
+without restoring anything, if what's written isn't RESTORE commands for keys
+starting with the file's prefix. /dump itself lists nothing, prefixes are
+looked up by name.
+";
+
+const QUEUE_HELP: &str = "Work queues via files.
+
+/queue/<name> is the list held by the key name. Writing to it pushes each line
+written onto the list with LPUSH, and reading pops one line at a time off its
+other end with BRPOP, so lines are read in the order they were written, each
+by exactly one reader:
+  $ echo job1 >> /queue/jobs
+  $ read job < /queue/jobs
+
+Reads wait up to blocking_timeout seconds for a line before failing with
+EAGAIN, each over a connection of its own while the file is open. A line
+popped but only partly read is lost once the file is closed. /queue lists
+nothing, queues are looked up by name.
 ";
 
 const KV_HELP: &str = "Key/Value store via files.
 
-TODO fill this in with how to use /kv
+Every key is a file under /kv, and writing to one sets the key. A single
+trailing newline is added when reading and dropped when writing, unless
+append_newline is off:
+  $ echo bar > /kv/foo
+  $ cat /kv/foo
+  bar
+
+Lists and sets read as their elements, one per line. Appending lines adds
+elements, while overwriting the file replaces them all:
+  $ echo job3 >> /kv/myqueue
+
+With the RedisJSON module loaded, JSON documents are directories of their
+objects and arrays, with array elements named by index. Strings read without
+their quotes, and writing to a value sets it:
+  $ cat /kv/user:1/address/city
+  $ echo Paris > /kv/user:1/address/city
+
+Suffix the name with #raw, #json, or #b64 to read the value without a
+trailing newline, as a JSON string, or base64 encoded instead:
+  $ cat /kv/mykey#json
+
+Keys expire after the seconds in the user.ttl xattr, and removing it makes
+them persist. user.type and user.encoding say how the value is stored, and
+user.memory how many bytes it takes up:
+  $ setfattr -n user.ttl -v 60 /kv/session
+  $ getfattr -n user.ttl /kv/session
+  $ setfattr -x user.ttl /kv/session
+
+Set user.fusekv.snapshot to 1 before opening a file to have every read of
+each open see the value as it was on the first, even if the key changes:
+  $ setfattr -n user.fusekv.snapshot -v 1 /kv/big
+  $ cp /kv/big /tmp/big
+
+Set user.fusekv.readlock to 1 to have each open for reading hold a shared
+lock on the key until closed, which writers elsewhere can wait for:
+  $ setfattr -n user.fusekv.readlock -v 1 /kv/config
+
+Read /kv:random for the name of a random key, /kv:random:value for the value
+of one, and /kv:count for how many keys there are:
+  $ cat /kv:count
+
+Keys matching a glob pattern can be listed
+and deleted in bulk via /kv/.match/<pattern>:
+  $ ls '/kv/.match/cache:*'
+  $ rm -r '/kv/.match/cache:*'
+
+Deleting a file deletes its key:
+  $ rm /kv/foo
+
+Deleting more keys than bulk_delete_threshold at once fails with EPERM
+until confirmed by writing back the line for its pattern read from
+/.fusekv/confirm, which allows the next bulk delete of that pattern alone:
+  $ cat /.fusekv/confirm
+  5f1c0e4a9b3d2e71 cache:*
+  $ echo '5f1c0e4a9b3d2e71 cache:*' > /.fusekv/confirm
 ";
 
 // ino, type, attr, name, content
@@ -45,31 +485,98 @@ type DirEntry = (u64, FileType, FileAttr, String, Option<String>);
 // ino, type, name
 type ReadDirEntry = (u64, FileType, String);
 
+// Fail a mutating operation with EROFS once the config is reloaded as
+// read-only, or EBUSY while the mount is frozen.
+macro_rules! reject_unless_writable {
+    ($self:expr, $reply:expr) => {
+        if $self.config.read_only {
+            log::debug!("Rejecting mutation while read-only.");
+            $reply.error(Errno::EROFS);
+            return;
+        }
+        if $self.frozen {
+            log::debug!("Rejecting mutation while frozen.");
+            $reply.error(Errno::EBUSY);
+            return;
+        }
+    };
+}
+
+// Fail a mutation of path with EACCES unless the requester is in a group its
+// write_allow rule lets write, or with EROFS or EACCES if a policy makes it
+// read-only or denies it.
+macro_rules! reject_unless_allowed {
+    ($self:expr, $req:expr, $path:expr, $reply:expr) => {
+        let path: Option<String> = $path;
+        if let Some(policy) = path.as_deref().and_then(|p| $self.policy(p)) {
+            if policy.deny || policy.read_only {
+                log::debug!("Rejecting mutation of {:?} by policy.", path);
+                $reply.error(if policy.deny {
+                    Errno::EACCES
+                } else {
+                    Errno::EROFS
+                });
+                return;
+            }
+        }
+        if let Some(path) = path.filter(|p| !$self.may_write($req, p)) {
+            log::debug!("Rejecting mutation of {} by uid {}.", path, $req.uid());
+            $reply.error(Errno::EACCES);
+            return;
+        }
+    };
+}
+
+// Fail with EACCES if a policy denies path.
+macro_rules! reject_if_denied {
+    ($self:expr, $path:expr, $reply:expr) => {
+        let path: Option<String> = $path;
+        if let Some(path) = path.filter(|p| $self.policy(p).map_or(false, |r| r.deny)) {
+            log::debug!("Rejecting access to {} by policy.", path);
+            $reply.error(Errno::EACCES);
+            return;
+        }
+    };
+}
+
+// Fail a mutation with EACCES unless the backend user the requester maps to
+// through acl_user may run args, the command the mutation amounts to.
+macro_rules! reject_unless_acl_allows {
+    ($self:expr, $req:expr, $args:expr, $reply:expr) => {
+        let args: Option<Vec<String>> = $args;
+        if let Some(Err(e)) = args.map(|a| $self.acl_check($req, &a)) {
+            $reply.error(e);
+            return;
+        }
+    };
+}
+
 macro_rules! curdir {
     ($self:expr, $ino:expr) => {
         (
             $ino,
             FileType::Directory,
-            $self.get_attr(".", FileType::Directory, $ino, 0),
+            $self.get_attr(FileType::Directory, $ino, 0),
             ".".to_string(),
             None,
-        );
+        )
     };
 }
 
 #[derive(Debug, Clone)]
 pub struct KVEntry {
-    pub ino: u64,
     pub key: String,
-    pub val: String,
+    // Lists and sets hold their elements one per line.
+    pub val: Vec<u8>,
+    pub kind: ValueKind,
 }
 
 impl KVEntry {
-    pub fn new(ino: u64, key: String, val: String) -> KVEntry {
+    pub fn new(key: String, val: Vec<u8>) -> KVEntry {
         KVEntry {
-            ino: ino,
-            key: key,
-            val: val,
+            key,
+            val,
+            kind: ValueKind::String,
         }
     }
 
@@ -78,348 +585,7550 @@ impl KVEntry {
     }
 }
 
+// What the backend reports about itself. Anything it can't tell us is None.
+#[derive(Debug, Clone, Default)]
+pub struct ServerInfo {
+    pub driver: String,
+    pub version: Option<String>,
+    // eg. standalone, cluster, or sentinel.
+    pub mode: Option<String>,
+    pub modules: Vec<String>,
+}
+
+// What a driver can do, so the mount leaves out what it can't rather than
+// failing whenever it's used.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DriverCapabilities {
+    // Listing keys matching a glob, for /find and /dump.
+    pub scan: bool,
+    // Expiring keys, for the user.ttl xattr.
+    pub ttl: bool,
+    // Locks under /lock.
+    pub locks: bool,
+    // Arbitrary commands, for /raw.
+    pub raw: bool,
+    // Moving values between keys on the backend, for renames.
+    pub copy: bool,
+    // Streams, tailed by non-blocking reads.
+    pub streams: bool,
+    // The names of keys as other clients change them.
+    pub notifications: bool,
+    // Lists pushed onto and popped off as work queues, for /queue.
+    pub queues: bool,
+}
+
+impl DriverCapabilities {
+    // Everything, for drivers that can do it all.
+    pub fn all() -> DriverCapabilities {
+        DriverCapabilities {
+            scan: true,
+            ttl: true,
+            locks: true,
+            raw: true,
+            copy: true,
+            streams: true,
+            notifications: true,
+            queues: true,
+        }
+    }
+
+    // Names of what's supported, eg. for /.fusekv/capabilities.
+    pub fn names(&self) -> Vec<&'static str> {
+        vec![
+            ("scan", self.scan),
+            ("ttl", self.ttl),
+            ("locks", self.locks),
+            ("raw", self.raw),
+            ("copy", self.copy),
+            ("streams", self.streams),
+            ("notifications", self.notifications),
+            ("queues", self.queues),
+        ]
+        .into_iter()
+        .filter(|(_, supported)| *supported)
+        .map(|(name, _)| name)
+        .collect()
+    }
+}
+
+// What the backend reports about a key, beyond its value.
+#[derive(Debug, Clone, Default)]
+pub struct KeyInfo {
+    // Seconds until the key expires, or None if it doesn't.
+    pub ttl: Option<u64>,
+    // eg. string, list, or hash.
+    pub kind: String,
+    // eg. embstr, quicklist, or listpack.
+    pub encoding: String,
+    // Bytes the backend uses to store the key, if it can tell.
+    pub memory: Option<u64>,
+}
+
+// How much of the backend is in use, for quotas.
+#[derive(Debug, Clone, Default)]
+pub struct Usage {
+    pub keys: u64,
+    // Bytes, if the backend can tell.
+    pub memory: Option<u64>,
+    // Bytes the backend can hold, if it can tell.
+    pub memory_limit: Option<u64>,
+}
+
+// Commands of one name sent to the backend since mounting, for metrics.
+#[cfg_attr(not(feature = "metrics-server"), allow(dead_code))]
+#[derive(Debug, Clone)]
+pub struct CommandCount {
+    pub command: String,
+    pub sent: u64,
+    pub failed: u64,
+}
+
+// Connections held by a driver's pool.
+#[cfg_attr(not(feature = "metrics-server"), allow(dead_code))]
+#[derive(Debug, Clone, Default)]
+pub struct PoolState {
+    pub connections: u32,
+    pub idle: u32,
+    pub max: u32,
+}
+
+// Usage against one configured quota.
+struct QuotaUsage {
+    name: &'static str,
+    used: u64,
+    limit: u64,
+}
+
+impl QuotaUsage {
+    fn state(&self, warn_percent: u64) -> &'static str {
+        if self.used >= self.limit {
+            "over"
+        } else if self.used * 100 >= self.limit * warn_percent {
+            "warn"
+        } else {
+            "ok"
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct KVRef {
     pub ino: u64,
     pub key: String,
 }
 
-pub trait KVReader {
-    fn get_by_name(&self, name: String, ino: u64) -> Result<Option<KVEntry>, Box<dyn Error>>;
-    fn get_by_ino(&self, ino: u64) -> Result<Option<KVEntry>, Box<dyn Error>>;
-    fn list_keys(&self, offset: i64) -> Result<Vec<KVRef>, Box<dyn Error>>;
-    fn read(&self, ino: u64, fh: u64, offset: i64) -> Result<Option<Vec<u8>>, Box<dyn Error>>;
+// A key listed along with what a listing needs to show it without looking it
+// up on its own.
+#[derive(Debug, Clone)]
+pub struct TypedKVRef {
+    pub ino: u64,
+    pub key: String,
+    // eg. string, list, or hash.
+    pub kind: String,
+    // The length of the value as read through the mount, or the number of
+    // fields of hashes.
+    pub size: u64,
+    // When the key was last written through fusekv, if that's known.
+    pub modified: Option<SystemTime>,
 }
 
-pub struct KVFS {
-    config: Config,
-    driver: Box<dyn KVReader>,
-    direntries_by_ino: HashMap<u64, DirEntry>,
-    direntries_by_parent_ino: HashMap<u64, HashMap<String, DirEntry>>,
+quick_error! {
+    // Errors from drivers. All but Unsupported carry the command and key that
+    // failed, so they can be logged usefully and mapped to a precise errno.
+    #[derive(Debug)]
+    pub enum DriverError {
+        Unsupported(feature: &'static str) {
+            display("This driver does not support {}.", feature)
+        }
+        NotFound(command: String, key: String) {
+            display("{} {}: no such key", command, key)
+        }
+        WrongType(command: String, key: String) {
+            display("{} {}: value is the wrong type", command, key)
+        }
+        Unavailable(command: String, key: String, reason: String) {
+            display("{} {}: backend unavailable: {}", command, key, reason)
+        }
+        Timeout(command: String, key: String) {
+            display("{} {}: timed out", command, key)
+        }
+        PermissionDenied(command: String, key: String, reason: String) {
+            display("{} {}: permission denied: {}", command, key, reason)
+        }
+        Corrupt(command: String, key: String, reason: String) {
+            display("{} {}: unexpected reply: {}", command, key, reason)
+        }
+        OutOfMemory(command: String, key: String, reason: String) {
+            display("{} {}: backend out of memory: {}", command, key, reason)
+        }
+        Malformed(command: String, key: String, reason: String) {
+            display("{} {}: malformed input: {}", command, key, reason)
+        }
+    }
 }
 
-impl KVFS {
-    pub fn new(config: Config, reader: impl KVReader + 'static) -> KVFS {
-        KVFS {
-            config: config,
-            driver: Box::new(reader),
-            direntries_by_ino: HashMap::new(),
-            direntries_by_parent_ino: HashMap::new(),
+pub type DriverResult<T> = Result<T, DriverError>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockOutcome {
+    Acquired,
+    // The lock itself is held by someone.
+    Held,
+    // A lock above or below it in the hierarchy is held.
+    Blocked,
+}
+
+// How a subtree stores its values, so values moved between subtrees can be
+// converted. Strings become lists of one element, and lists become strings of
+// their elements joined by newlines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueKind {
+    String,
+    List,
+    Set,
+    // Read as one line per entry, and only ever appended to.
+    Stream,
+}
+
+// An entry of a stream, read as its ID followed by its fields as field=value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamEntry {
+    pub id: String,
+    pub fields: Vec<(String, String)>,
+}
+
+impl StreamEntry {
+    pub fn line(&self) -> String {
+        let mut line = self.id.clone();
+        for (field, value) in &self.fields {
+            line.push_str(&format!(" {}={}", field, value));
         }
+        line
     }
 }
 
-impl Filesystem for KVFS {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        log::debug!("lookup {:?} under parent {}", name, parent);
-        let name_str = match name.to_os_string().into_string() {
-            Ok(v) => v,
-            Err(e) => {
-                log::debug!("Error turning {:?} into string: {:?}", name, e);
-                reply.error(ENOENT);
-                return;
-            }
-        };
+// A subscription to a pubsub channel, which unsubscribes once dropped, or a
+// consumer of a queue.
+pub trait Subscription: Send {
+    // The next message published to the channel, or popped off the queue,
+    // waiting up to timeout for one, or forever if timeout is None. None if
+    // none came in time.
+    fn next_message(&mut self, timeout: Option<Duration>) -> DriverResult<Option<Vec<u8>>>;
+}
 
-        // FUSE root
-        if parent == 1 {
-            match self.direntries_by_parent_ino.get(&parent) {
-                Some(entries) => match entries.get(&name_str) {
-                    Some(entry) => reply.entry(&TTL, &entry.2, 0),
-                    None => reply.error(ENOENT),
-                },
-                None => reply.error(ENOENT),
-            };
-        // /kv
-        } else if parent == 4096 {
-            // Fetch from driver
-            let ino = seahash::hash(name_str.as_bytes()) % (KV_END - KV_START) + KV_START;
-            let entry: KVEntry = match self.driver.get_by_name(name_str, ino) {
-                Ok(maybe) => match maybe {
-                    Some(v) => v,
-                    None => {
-                        reply.error(ENOENT);
-                        return;
-                    }
-                },
-                Err(_) => {
-                    reply.error(EAGAIN);
-                    return;
-                }
-            };
-            let attr = self.get_attr(
-                format!("/kv/{}", &name_str).as_str(),
-                FileType::RegularFile,
-                ino,
-                // We add a \n at the end
-                // TODO add a config option for this?
-                (entry.len() + 1) as u64,
-            );
+// The fields of a line written to a stream: its words as field=value pairs,
+// or the whole line as the line field if any word isn't one.
+pub fn stream_fields(line: &str) -> Vec<(String, String)> {
+    let pairs: Option<Vec<(String, String)>> = line
+        .split_whitespace()
+        .map(|w| {
+            let (field, value) = w.split_once('=')?;
+            Some((field.to_string(), value.to_string()))
+        })
+        .collect();
+    match pairs {
+        Some(v) if !v.is_empty() => v,
+        _ => vec![("line".to_string(), line.to_string())],
+    }
+}
 
-            // We add a \n at the end
-            // TODO add a config option for this?
-            reply.entry(&TTL, &attr, (entry.len() + 1) as u64);
-        // TODO add ranges for /lock and /kv here
-        } else {
-            reply.error(ENOENT);
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenameOutcome {
+    Renamed,
+    // The source doesn't exist.
+    Missing,
+    // The destination exists and replacing it wasn't allowed.
+    Exists,
+    // The value can't be converted to the destination's kind.
+    Unconvertible,
+}
+
+// Map a key to its inode in the /kv range.
+pub fn kv_ino(key: &str) -> u64 {
+    seahash::hash(key.as_bytes()) % (KV_END - KV_START) + KV_START
+}
+
+// Map a tag to the inode of its directory in the /tags range.
+fn tag_ino(tag: &str) -> u64 {
+    seahash::hash(tag.as_bytes()) % (TAGS_END - TAGS_START) + TAGS_START
+}
+
+// Map a timestamp to the inode of its /history directory.
+fn history_ino(ts: u64) -> u64 {
+    seahash::hash(&ts.to_le_bytes()) % (HISTORY_END - HISTORY_START) + HISTORY_START
+}
+
+// Map a key at a timestamp to its inode under /history.
+fn history_key_ino(ts: u64, key: &str) -> u64 {
+    seahash::hash(format!("{}/{}", ts, key).as_bytes()) % (HISTORY_KEY_END - HISTORY_KEY_START)
+        + HISTORY_KEY_START
+}
+
+// Map a glob pattern to the inode of its /kv/.match directory.
+fn match_ino(pattern: &str) -> u64 {
+    seahash::hash(pattern.as_bytes()) % (MATCH_END - MATCH_START) + MATCH_START
+}
+
+// Map a glob pattern to the inode of its /find directory.
+fn find_ino(pattern: &str) -> u64 {
+    seahash::hash(pattern.as_bytes()) % (FIND_END - FIND_START) + FIND_START
+}
+
+// Map a key matching a glob pattern to the inode of its /find link.
+fn find_link_ino(pattern: &str, key: &str) -> u64 {
+    seahash::hash(format!("{}/{}", pattern, key).as_bytes()) % (FIND_LINK_END - FIND_LINK_START)
+        + FIND_LINK_START
+}
+
+// Where the /find link to key points, relative to its directory.
+fn find_link_target(key: &str) -> String {
+    format!("../../kv/{}", key)
+}
+
+// Map a key prefix to the inode of its /dump file.
+fn dump_ino(prefix: &str) -> u64 {
+    seahash::hash(prefix.as_bytes()) % (DUMP_END - DUMP_START) + DUMP_START
+}
+
+// Map a namespace, eg. users:123, to the inode of its /kv directory.
+fn namespace_ino(namespace: &str) -> u64 {
+    seahash::hash(namespace.as_bytes()) % (NAMESPACE_END - NAMESPACE_START) + NAMESPACE_START
+}
+
+// Every parent inode and name key can be looked up by: its whole name in /kv,
+// what follows each separator in the namespace before it, and the namespaces
+// along the way.
+pub fn kv_entries(key: &str, separator: Option<&str>) -> Vec<(u64, String)> {
+    let mut starts = vec![0];
+    let mut ends = vec![];
+    if let Some(separator) = separator.filter(|s| !s.is_empty()) {
+        for (i, _) in key.match_indices(separator) {
+            ends.push(i);
+            starts.push(i + separator.len());
         }
     }
-
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        log::debug!("getattr for {}", ino);
-        match ino {
-            0..=RAW_END => match self.direntries_by_ino.get(&ino) {
-                Some(v) => reply.attr(&TTL, &v.2),
-                None => reply.error(ENOENT),
-            },
-            KV_START..=KV_END => {
-                // Fetch attr from redis
-                let entry: KVEntry = match self.driver.get_by_ino(ino) {
-                    Ok(maybe) => match maybe {
-                        Some(v) => v,
-                        None => {
-                            reply.error(ENOENT);
-                            return;
-                        }
-                    },
-                    Err(_) => {
-                        reply.error(EAGAIN);
-                        return;
-                    }
-                };
-                let attr = self.get_attr(
-                    format!("/kv/{}", &entry.key).as_str(),
-                    FileType::RegularFile,
-                    ino,
-                    // We add a \n at the end
-                    // TODO add a config option for this?
-                    (entry.len() + 1) as u64,
-                );
-                reply.attr(&TTL, &attr);
-            }
-            // TODO add ranges for /lock and /kv here
-            _ => reply.error(ENOENT),
+    ends.push(key.len());
+    let mut entries = vec![];
+    for (n, &start) in starts.iter().enumerate() {
+        let parent = match n {
+            0 => 4096,
+            _ => namespace_ino(&key[..ends[n - 1]]),
         };
+        for &end in &ends[n..] {
+            // Names can't hold a slash.
+            if end > start && !key[start..end].contains('/') {
+                entries.push((parent, key[start..end].to_string()));
+            }
+        }
     }
+    entries
+}
 
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        _size: u32,
-        _flags: i32,
-        _lock: Option<u64>,
-        reply: ReplyData,
-    ) {
-        log::debug!(
-            "read inode {} at offset {} via filehandle {}",
-            ino,
+// Map a pubsub channel to the inode of its /pubsub file.
+fn channel_ino(channel: &str) -> u64 {
+    seahash::hash(channel.as_bytes()) % (PUBSUB_END - PUBSUB_START) + PUBSUB_START
+}
+
+// Map a queue's key to the inode of its /queue file.
+fn queue_ino(name: &str) -> u64 {
+    seahash::hash(name.as_bytes()) % (QUEUE_END - QUEUE_START) + QUEUE_START
+}
+
+// Map a counter's key to the inode of its /counter file.
+fn counter_ino(name: &str) -> u64 {
+    seahash::hash(name.as_bytes()) % (COUNTER_END - COUNTER_START) + COUNTER_START
+}
+
+// Map a key of the mirror at index i to the inode of its /mirror file.
+fn mirror_key_ino(i: usize, key: &str) -> u64 {
+    seahash::hash(format!("{}/{}", i, key).as_bytes()) % (MIRROR_KEY_END - MIRROR_KEY_START)
+        + MIRROR_KEY_START
+}
+
+// Where the /txn session or /publish batch name under parent is kept in txns,
+// eg. txn/<name>.
+fn txn_path(parent: u64, name: &str) -> Option<String> {
+    match parent {
+        TXN_DIR => Some(format!("txn/{}", name)),
+        PUBLISH_DIR => Some(format!("publish/{}", name)),
+        _ => None,
+    }
+}
+
+// Hex SHA-256 digest of data.
+fn sha256_hex(data: &[u8]) -> String {
+    sha256(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Map a /txn session or /publish batch to the inode of its directory.
+fn txn_ino(session: &str) -> u64 {
+    seahash::hash(session.as_bytes()) % (TXN_END - TXN_START) + TXN_START
+}
+
+// Map a key in a /txn session or /publish batch to the inode of its file
+// there.
+fn txn_key_ino(session: &str, key: &str) -> u64 {
+    seahash::hash(format!("{}/{}", session, key).as_bytes()) % (TXN_KEY_END - TXN_KEY_START)
+        + TXN_KEY_START
+}
+
+// Map the node at path in the JSON document key to its inode.
+fn json_ino(key: &str, path: &str) -> u64 {
+    seahash::hash(format!("{}\0{}", key, path).as_bytes()) % (JSON_END - JSON_START) + JSON_START
+}
+
+// JSONPath of the child called name of the object or array at path, or None
+// if an array has no such index.
+fn json_child_path(path: &str, array: bool, name: &str) -> Option<String> {
+    match array {
+        true => match name.parse::<usize>() {
+            Ok(i) if i.to_string() == name => Some(format!("{}[{}]", path, i)),
+            _ => None,
+        },
+        false => Some(format!("{}[{}]", path, Value::from(name))),
+    }
+}
+
+// Content of the file of a JSON value that's neither an object nor an array.
+// Strings read without their quotes.
+fn json_content(value: &Value) -> Vec<u8> {
+    match value {
+        Value::String(s) => s.clone().into_bytes(),
+        other => other.to_string().into_bytes(),
+    }
+}
+
+// JSON to set for content written to the file of a JSON value, which was the
+// value before. Strings stay strings, anything else is parsed as JSON, and
+// taken as a string if it isn't.
+fn json_of(content: &[u8], was: Option<&Value>) -> String {
+    let text = String::from_utf8_lossy(content);
+    match (was, serde_json::from_str::<Value>(&text)) {
+        (Some(Value::String(_)), _) | (_, Err(_)) => Value::from(text.as_ref()).to_string(),
+        (_, Ok(v)) => v.to_string(),
+    }
+}
+
+// Map a lock name, eg. app/db/migrate, to its inode in the /lock range.
+fn lock_ino(name: &str) -> u64 {
+    seahash::hash(name.as_bytes()) % (LOCK_END - LOCK_START) + LOCK_START
+}
+
+pub trait KVReader {
+    // What else the driver can do beyond reading and listing keys. The mount
+    // only offers what's here.
+    fn capabilities(&self) -> DriverCapabilities;
+    fn get_by_name(&self, name: String, ino: u64) -> DriverResult<Option<KVEntry>>;
+    fn get_by_ino(&self, ino: u64) -> DriverResult<Option<KVEntry>>;
+    // The inode of each of keys. Drivers that can remember which key an inode
+    // was handed out for should resolve collisions between their hashes.
+    fn inos_of(&self, keys: &[String]) -> DriverResult<Vec<u64>> {
+        Ok(keys.iter().map(|k| kv_ino(k)).collect())
+    }
+    // The inode of each of keys if one was handed out recently, without
+    // handing out any more.
+    fn known_inos(&self, keys: &[String]) -> Vec<Option<u64>> {
+        keys.iter().map(|k| Some(kv_ino(k))).collect()
+    }
+    fn list_keys(&self, offset: i64, limit: i64) -> DriverResult<Vec<KVRef>>;
+    // Like list_keys, but gives up once deadline has passed, returning the keys
+    // found so far and whether the listing is complete.
+    fn list_keys_until(
+        &self,
+        offset: i64,
+        limit: i64,
+        _deadline: Option<Instant>,
+    ) -> DriverResult<(Vec<KVRef>, bool)> {
+        Ok((self.list_keys(offset, limit)?, true))
+    }
+    // About count keys matching pattern, carrying on from cursor, or from the
+    // start if it's empty, along with the cursor to carry on from next, or None
+    // once every key has been listed. Keys may be listed more than once. By
+    // default cursors are offsets into match_keys.
+    fn scan_keys(
+        &self,
+        pattern: &str,
+        cursor: &str,
+        count: usize,
+    ) -> DriverResult<(Vec<KVRef>, Option<String>)> {
+        let offset: i64 = cursor.parse().unwrap_or(0);
+        let (refs, _) = self.match_keys_until(pattern, offset, count as i64, None)?;
+        let next = match refs.len() < count {
+            true => None,
+            false => Some((offset + refs.len() as i64).to_string()),
+        };
+        Ok((refs, next))
+    }
+    // Like scan_keys, along with the type, size, and mtime of each key, in as
+    // few round trips as the backend allows.
+    fn scan_typed_keys(
+        &self,
+        _pattern: &str,
+        _cursor: &str,
+        _count: usize,
+    ) -> DriverResult<(Vec<TypedKVRef>, Option<String>)> {
+        Err(DriverError::Unsupported("typed listing"))
+    }
+    // Up to size bytes of the value of key from offset, along with the length
+    // of the whole value, or None if key doesn't exist.
+    fn read_range(
+        &self,
+        key: &str,
+        offset: u64,
+        size: u64,
+    ) -> DriverResult<Option<(Vec<u8>, u64)>> {
+        Ok(value_range(
+            self.get_by_name(key.to_string(), kv_ino(key))?,
             offset,
-            fh,
+            size,
+        ))
+    }
+    // Check the backend is reachable.
+    fn ping(&self) -> DriverResult<()> {
+        self.server_info().map(|_| ())
+    }
+    fn server_info(&self) -> DriverResult<ServerInfo> {
+        Err(DriverError::Unsupported("server info"))
+    }
+    // Whether the backend replicates another, so rejects writes.
+    fn is_replica(&self) -> DriverResult<bool> {
+        Ok(false)
+    }
+    // Names of the modules loaded into the backend, eg. ReJSON.
+    fn modules(&self) -> DriverResult<Vec<String>> {
+        Ok(vec![])
+    }
+    // The JSON at path in the JSON document key, or None if there's no such
+    // key or nothing at path. Paths are JSONPaths, eg. $["address"]["city"].
+    fn json_get(&self, _key: &str, _path: &str) -> DriverResult<Option<String>> {
+        Err(DriverError::Unsupported("JSON"))
+    }
+    // The backend's clock, as time since the epoch.
+    fn server_time(&self) -> DriverResult<Duration> {
+        Err(DriverError::Unsupported("server time"))
+    }
+    // key serialized as DUMP does, with the milliseconds it has left to live,
+    // or 0 if it doesn't expire, or None if there's no such key.
+    fn dump(&self, _key: &str) -> DriverResult<Option<(Vec<u8>, u64)>> {
+        Err(DriverError::Unsupported("DUMP"))
+    }
+    // The value key had at as_of, in milliseconds since the epoch.
+    fn get_as_of(&self, _key: &str, _as_of: u64) -> DriverResult<Option<Vec<u8>>> {
+        Err(DriverError::Unsupported("versioning"))
+    }
+    // Up to limit keys with a version at or before as_of, or all of them if
+    // limit is -1.
+    fn list_versioned_keys(&self, _as_of: u64, _limit: i64) -> DriverResult<Vec<String>> {
+        Err(DriverError::Unsupported("versioning"))
+    }
+    // Up to limit keys matching the glob pattern, or all of them if limit is -1.
+    fn match_keys(&self, _pattern: &str, _limit: i64) -> DriverResult<Vec<KVRef>> {
+        Err(DriverError::Unsupported("pattern matching"))
+    }
+    // Like match_keys, but skips the first offset matches and gives up once
+    // deadline has passed, returning whether the listing is complete.
+    fn match_keys_until(
+        &self,
+        pattern: &str,
+        offset: i64,
+        limit: i64,
+        _deadline: Option<Instant>,
+    ) -> DriverResult<(Vec<KVRef>, bool)> {
+        let limit = if limit == -1 {
+            -1
+        } else {
+            offset.saturating_add(limit)
+        };
+        let refs = self.match_keys(pattern, limit)?;
+        Ok((refs.into_iter().skip(offset as usize).collect(), true))
+    }
+    // A key picked at random, or None if there are none.
+    fn random_key(&self) -> DriverResult<Option<String>> {
+        Err(DriverError::Unsupported("random keys"))
+    }
+    // Number of keys, or an estimate from sample random keys if sample is
+    // nonzero. Only the estimate leaves out keys the driver keeps for itself.
+    fn count_keys(&self, _sample: u64) -> DriverResult<u64> {
+        Err(DriverError::Unsupported("counting keys"))
+    }
+    // Keys and memory in use, counting keys the driver keeps for itself.
+    fn usage(&self) -> DriverResult<Usage> {
+        Err(DriverError::Unsupported("usage"))
+    }
+    // Expiry, type, and encoding of key, or None if it doesn't exist.
+    fn key_info(&self, _key: &str) -> DriverResult<Option<KeyInfo>> {
+        Err(DriverError::Unsupported("key metadata"))
+    }
+    // What key_info would return for each of keys, in as few round trips as
+    // the backend allows.
+    fn key_infos(&self, keys: &[String]) -> DriverResult<Vec<Option<KeyInfo>>> {
+        keys.iter().map(|k| self.key_info(k)).collect()
+    }
+    // When key was last written through fusekv, or None if that isn't known.
+    fn modified(&self, _key: &str) -> DriverResult<Option<SystemTime>> {
+        Ok(None)
+    }
+    // The key key was renamed to, if a redirect was left for it.
+    fn redirect_of(&self, _key: &str) -> DriverResult<Option<String>> {
+        Ok(None)
+    }
+    // Subscribe to the pubsub channel, receiving only what's published after.
+    fn subscribe(&self, _channel: &str) -> DriverResult<Box<dyn Subscription>> {
+        Err(DriverError::Unsupported("pubsub"))
+    }
+    // Pubsub channels with at least one subscriber.
+    fn channels(&self) -> DriverResult<Vec<String>> {
+        Ok(vec![])
+    }
+    // The names of keys as any client changes them, from now on.
+    fn changes(&self) -> DriverResult<Box<dyn Subscription>> {
+        Err(DriverError::Unsupported("change notifications"))
+    }
+    // Pop elements off the tail of the list at queue as messages, with a
+    // connection of its own to wait on, so waiting holds up nothing else.
+    fn consume(&self, _queue: &str) -> DriverResult<Box<dyn Subscription>> {
+        Err(DriverError::Unsupported("queues"))
+    }
+    // Entries of the stream at key after the one with ID after, waiting up to
+    // block for some if there are none yet, or forever if block is None.
+    fn stream_after(
+        &self,
+        _key: &str,
+        _after: &str,
+        _block: Option<Duration>,
+    ) -> DriverResult<Vec<StreamEntry>> {
+        Err(DriverError::Unsupported("streams"))
+    }
+    // Lengths of the values of keys as read through the mount, without any
+    // trailing newline. 0 for keys that don't exist.
+    fn sizes(&self, keys: &[String]) -> DriverResult<Vec<u64>> {
+        keys.iter()
+            .map(|k| {
+                Ok(self
+                    .get_by_name(k.clone(), kv_ino(k))?
+                    .map_or(0, |e| e.len() as u64))
+            })
+            .collect()
+    }
+    // Seconds since each of keys was last accessed. 0 for keys that don't
+    // exist.
+    fn idle_times(&self, _keys: &[String]) -> DriverResult<Vec<u64>> {
+        Err(DriverError::Unsupported("idle times"))
+    }
+    // Lines describing the commands last sent to the backend, oldest first.
+    fn trace(&self) -> DriverResult<Vec<String>> {
+        Err(DriverError::Unsupported("command tracing"))
+    }
+    // Commands sent to the backend by name, if they're being counted.
+    #[cfg_attr(not(feature = "metrics-server"), allow(dead_code))]
+    fn command_counts(&self) -> DriverResult<Vec<CommandCount>> {
+        Err(DriverError::Unsupported("command counts"))
+    }
+    // Connections open to the backend, in use or idle.
+    #[cfg_attr(not(feature = "metrics-server"), allow(dead_code))]
+    fn pool_state(&self) -> DriverResult<PoolState> {
+        Err(DriverError::Unsupported("connection pools"))
+    }
+    // Drop anything cached about keys, after the backend lost them behind our
+    // back.
+    fn forget_cached(&self) {}
+}
+
+// Lock names are /-separated paths relative to /lock.
+pub trait KVLocker {
+    fn acquire_lock(
+        &self,
+        name: &str,
+        owner: &str,
+        mode: LockMode,
+        // How long until the lock expires, or None to hold it until released.
+        ttl: Option<Duration>,
+    ) -> DriverResult<LockOutcome>;
+    // Returns false if the lock isn't held by owner.
+    fn release_lock(&self, name: &str, owner: &str) -> DriverResult<bool>;
+    fn lock_owner(&self, name: &str) -> DriverResult<Option<String>>;
+    // Names of all held locks starting with prefix.
+    fn list_locks(&self, prefix: &str) -> DriverResult<Vec<String>>;
+    // Take a shared lock on key, which any number of owners can hold at once,
+    // or extend owner's, until ttl has passed. These are only advisory, for
+    // other clients to check before writing.
+    fn hold_shared(&self, _key: &str, _owner: &str, _ttl: Duration) -> DriverResult<()> {
+        Err(DriverError::Unsupported("shared key locks"))
+    }
+    fn release_shared(&self, _key: &str, _owner: &str) -> DriverResult<()> {
+        Err(DriverError::Unsupported("shared key locks"))
+    }
+}
+
+// How a value is presented to readers. Selected per open by suffixing the file
+// name with #<encoding>, eg. /kv/mykey#json.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    // The value followed by a \n, as read from /kv/<key>.
+    Plain,
+    // The value exactly as stored.
+    Raw,
+    // The value as a JSON string.
+    Json,
+    // The value base64-encoded.
+    Base64,
+}
+
+impl Encoding {
+    fn from_suffix(suffix: &str) -> Option<Encoding> {
+        match suffix {
+            "raw" => Some(Encoding::Raw),
+            "json" => Some(Encoding::Json),
+            "b64" => Some(Encoding::Base64),
+            _ => None,
+        }
+    }
+
+    fn suffix(&self) -> &'static str {
+        match self {
+            Encoding::Plain => "",
+            Encoding::Raw => "raw",
+            Encoding::Json => "json",
+            Encoding::Base64 => "b64",
+        }
+    }
+
+    // JSON strings can only hold text, so values that aren't UTF-8 are
+    // encoded lossily.
+    fn encode(&self, value: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::Plain => [value, b"\n"].concat(),
+            Encoding::Raw => value.to_vec(),
+            Encoding::Json => {
+                format!("{}\n", Value::from(String::from_utf8_lossy(value))).into_bytes()
+            }
+            Encoding::Base64 => format!("{}\n", base64::encode_block(value)).into_bytes(),
+        }
+    }
+}
+
+// State tracked for each open file handle.
+#[derive(Debug, Clone)]
+struct FileHandle {
+    ino: u64,
+    // Skip any local caching and always go to the driver for this handle.
+    bypass_cache: bool,
+    // How long blocking reads on this handle wait before failing with
+    // ETIMEDOUT, or None to wait forever.
+    blocking_timeout: Option<Duration>,
+    encoding: Encoding,
+    // What the file holds as written through this handle, if it was opened
+    // for writing. Written to the key on flush or release when dirty.
+    buffer: Option<Vec<u8>>,
+    dirty: bool,
+    // Replies to commands written to /raw through this handle, drained as
+    // they're read.
+    replies: Vec<u8>,
+    // Content generated on open, for files that would otherwise change
+    // between reads of the same handle, or values small enough to fetch whole
+    // on the first read.
+    content: Option<Vec<u8>>,
+    kind: ValueKind,
+    // Writes add elements to a list or set rather than replacing them.
+    append: bool,
+    // Reads are served from a copy of the whole value fetched on the first.
+    snapshot: bool,
+    // Writes go straight to the key at their offset instead of into buffer,
+    // for values too big to hold, and stream_end is the furthest one reached.
+    streaming: bool,
+    stream_end: u64,
+    // Reads fetch a range at a time, as the value is too big to keep, so are
+    // handed off to readers.
+    ranged: bool,
+    // What reads handed off to readers learned for later reads through this
+    // handle, taken on by the next.
+    fetched: Arc<Mutex<Option<Kept>>>,
+    // Entries of the stream read so far, for handles opened with O_NONBLOCK
+    // whose reads past the end wait for more.
+    tail: Option<Arc<Mutex<Tail>>>,
+    // The subscription of handles reading from a /pubsub channel, or popping
+    // off a /queue.
+    subscriber: Option<Arc<Mutex<Subscriber>>>,
+}
+
+// What a read through a handle learned about its value: the whole of it, or
+// that it's too big to keep.
+#[derive(Debug)]
+enum Kept {
+    Content(Vec<u8>),
+    Ranged,
+}
+
+// A value read for a handle: whole, to be kept, or only the range asked for.
+enum Fetched {
+    Whole(Vec<u8>),
+    Range(Vec<u8>),
+}
+
+// What a handle tailing a stream has read of it.
+#[derive(Debug)]
+struct Tail {
+    content: Vec<u8>,
+    // ID of the last entry in content, which new entries come after.
+    last_id: String,
+}
+
+// A handle's subscription to a channel or queue, and what it received but
+// hasn't read.
+struct Subscriber {
+    subscription: Box<dyn Subscription>,
+    pending: Vec<u8>,
+}
+
+// Writes queued in a /txn session or /publish batch, run together when it's
+// committed.
+struct Txn {
+    // Value queued for each key, None to delete it.
+    writes: BTreeMap<String, Option<Vec<u8>>>,
+    // Batches are checked against their manifest, and only set keys.
+    publish: bool,
+}
+
+impl Txn {
+    // Name of the file writes are committed through.
+    fn control(&self) -> &'static str {
+        match self.publish {
+            true => PUBLISH_CONTROL,
+            false => TXN_CONTROL,
+        }
+    }
+
+    // Whether name is a key rather than one of the files controlling it.
+    fn is_key(&self, name: &str) -> bool {
+        name != self.control() && !(self.publish && name == PUBLISH_MANIFEST)
+    }
+}
+
+// A /kv or /find directory being listed a page at a time, for as long as it's
+// open.
+struct DirHandle {
+    // Entries listed so far, which offsets index into.
+    entries: Vec<ReadDirEntry>,
+    names: HashSet<String>,
+    // What keys of the directory start with.
+    prefix: String,
+    // The glob pattern of a /find directory, whose keys are listed as links.
+    find: Option<String>,
+    // Where scanning carries on from, or None once it's done.
+    cursor: Option<String>,
+}
+
+impl fmt::Debug for Subscriber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Subscriber")
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+// Tags are arbitrary labels attached to keys.
+pub trait KVTagger {
+    fn tag(&self, _key: &str, _tag: &str) -> DriverResult<()> {
+        Err(DriverError::Unsupported("tags"))
+    }
+    fn untag(&self, _key: &str, _tag: &str) -> DriverResult<()> {
+        Err(DriverError::Unsupported("tags"))
+    }
+    fn tags_of(&self, _key: &str) -> DriverResult<Vec<String>> {
+        Err(DriverError::Unsupported("tags"))
+    }
+    // Keys tagged with tag.
+    fn tagged(&self, _tag: &str) -> DriverResult<Vec<String>> {
+        Err(DriverError::Unsupported("tags"))
+    }
+    // Every tag with at least one key.
+    fn list_tags(&self) -> DriverResult<Vec<String>> {
+        Err(DriverError::Unsupported("tags"))
+    }
+}
+
+pub trait KVWriter {
+    // Grow the value of key to at least len bytes by zero-filling its end.
+    fn preallocate(&self, _key: &str, _len: u64) -> DriverResult<()> {
+        Err(DriverError::Unsupported("preallocation"))
+    }
+    // Replace the whole value of key.
+    fn set(&self, _key: &str, _value: &[u8]) -> DriverResult<()> {
+        Err(DriverError::Unsupported("writes"))
+    }
+    // Replace the whole values of keys, in as few round trips as the backend
+    // allows.
+    fn set_many(&self, values: &[(String, Vec<u8>)]) -> DriverResult<()> {
+        values.iter().try_for_each(|(k, v)| self.set(k, v))
+    }
+    // Set each key to its value, or delete it if it has none, all at once or
+    // not at all.
+    fn transact(&self, _writes: &[(String, Option<Vec<u8>>)]) -> DriverResult<()> {
+        Err(DriverError::Unsupported("transactions"))
+    }
+    // Set path in the JSON document key to json, returning false if its parent
+    // doesn't exist.
+    fn json_set(&self, _key: &str, _path: &str, _json: &str) -> DriverResult<bool> {
+        Err(DriverError::Unsupported("JSON"))
+    }
+    // Delete path from the JSON document key, returning how many values went.
+    fn json_del(&self, _key: &str, _path: &str) -> DriverResult<u64> {
+        Err(DriverError::Unsupported("JSON"))
+    }
+    // Replace key with what dump serialized, expiring in ttl milliseconds
+    // unless it's 0.
+    fn restore(&self, _key: &str, _ttl: u64, _payload: &[u8]) -> DriverResult<()> {
+        Err(DriverError::Unsupported("RESTORE"))
+    }
+    // Overwrite the value of key from offset with data, zero-filling any gap
+    // past its end.
+    fn write_range(&self, _key: &str, _offset: u64, _data: &[u8]) -> DriverResult<()> {
+        Err(DriverError::Unsupported("ranged writes"))
+    }
+    // Add data to the end of the value of key, after separator if key already
+    // exists, all at once. Missing keys are created.
+    fn append(&self, _key: &str, _separator: &[u8], _data: &[u8]) -> DriverResult<()> {
+        Err(DriverError::Unsupported("appends"))
+    }
+    // Drop the last byte of the value of key if it's a \n that ends exactly len
+    // bytes in, ie. the one reads add, written back by ranged writes.
+    fn trim_newline(&self, _key: &str, _len: u64) -> DriverResult<()> {
+        Err(DriverError::Unsupported("ranged writes"))
+    }
+    // Move from to to, converting the value to kind if given. Tags move with
+    // the key. Unless replace is set, an existing to is left alone.
+    fn rename(
+        &self,
+        _from: &str,
+        _to: &str,
+        _kind: Option<ValueKind>,
+        _replace: bool,
+    ) -> DriverResult<RenameOutcome> {
+        Err(DriverError::Unsupported("rename"))
+    }
+    // Expire key after ttl, or make it persist if ttl is None. Returns false if
+    // there was nothing to change, ie. key doesn't exist, or when making it
+    // persist, it already did.
+    fn expire(&self, _key: &str, _ttl: Option<Duration>) -> DriverResult<bool> {
+        Err(DriverError::Unsupported("expiry"))
+    }
+    // Have lookups of from find to for ttl, after from was renamed to it.
+    fn leave_redirect(&self, _from: &str, _to: &str, _ttl: Duration) -> DriverResult<()> {
+        Err(DriverError::Unsupported("rename redirects"))
+    }
+    // Delete keys along with their tags, returning how many existed.
+    fn delete(&self, _keys: &[String]) -> DriverResult<u64> {
+        Err(DriverError::Unsupported("deletion"))
+    }
+    // Replace the elements of the list or set at key with items, or add items
+    // to them if append is set.
+    fn write_items(
+        &self,
+        _key: &str,
+        _kind: ValueKind,
+        _items: &[String],
+        _append: bool,
+    ) -> DriverResult<()> {
+        Err(DriverError::Unsupported("lists and sets"))
+    }
+    // Run an arbitrary command, returning its reply formatted for humans.
+    // Errors returned by the command itself are part of the reply.
+    fn raw(&self, _args: &[String]) -> DriverResult<String> {
+        Err(DriverError::Unsupported("raw commands"))
+    }
+    // Add delta to the integer held by key atomically, treating a missing key
+    // as 0, and return the result.
+    fn incr_by(&self, _key: &str, _delta: i64) -> DriverResult<i64> {
+        Err(DriverError::Unsupported("counters"))
+    }
+    // Publish message to the pubsub channel, returning how many subscribers
+    // received it.
+    fn publish(&self, _channel: &str, _message: &[u8]) -> DriverResult<u64> {
+        Err(DriverError::Unsupported("pubsub"))
+    }
+    // Push items onto the head of the list at queue, in order, all at once.
+    fn push(&self, _queue: &str, _items: &[Vec<u8>]) -> DriverResult<()> {
+        Err(DriverError::Unsupported("queues"))
+    }
+    // Run a Lua script on the backend, ignoring its result.
+    fn eval(&self, _script: &str, _keys: &[String], _args: &[String]) -> DriverResult<()> {
+        Err(DriverError::Unsupported("Lua scripts"))
+    }
+    // Append an entry of fields to the stream at key, trimming it to about
+    // max_len entries unless max_len is 0.
+    fn append_to_stream(
+        &self,
+        _key: &str,
+        _max_len: usize,
+        _fields: &[(&str, String)],
+    ) -> DriverResult<()> {
+        Err(DriverError::Unsupported("streams"))
+    }
+    // Whether the backend user called user would be allowed to run args,
+    // without running them.
+    fn acl_allows(&self, _user: &str, _args: &[String]) -> DriverResult<bool> {
+        Err(DriverError::Unsupported("ACL checks"))
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+pub struct KVFS {
+    config: Config,
+    driver: Arc<dyn Driver>,
+    // What driver can do, deciding which subtrees and xattrs there are.
+    caps: DriverCapabilities,
+    // All full-value writes go through this.
+    coalescer: Arc<WriteCoalescer>,
+    hooks: Arc<Hooks>,
+    readers: Arc<Readers>,
+    // How recently keys changed in each namespace, for lengthening TTLs.
+    churn: Arc<Churn>,
+    // Keys changed by any client, whose cached attributes and values are
+    // dropped before the next operation, and forgotten by the kernel as they
+    // change.
+    invalidations: Arc<Invalidations>,
+    // Shared locks held on keys by handles reading them.
+    read_locks: Arc<ReadLocks>,
+    // Attributes and values of keys, forgotten as they're changed here.
+    cache: Cache,
+    direntries_by_ino: HashMap<u64, DirEntry>,
+    direntries_by_parent_ino: HashMap<u64, HashMap<String, DirEntry>>,
+    // Names of every lock or lock namespace handed out an inode.
+    lock_names_by_ino: HashMap<u64, String>,
+    // Lock namespaces created with mkdir. These only exist in this mount.
+    lock_dirs: HashSet<String>,
+    // Keys of every /kv entry handed out an inode.
+    kv_keys_by_ino: HashMap<u64, String>,
+    // Names of every /kv namespace directory handed out an inode, without the
+    // trailing separator.
+    namespaces_by_ino: HashMap<u64, String>,
+    // Namespaces created with mkdir. These only exist in this mount until a
+    // key is written beneath them.
+    kv_dirs: HashSet<String>,
+    // Keys in the /kv directory listed last, and where each is among them, so
+    // their metadata can be fetched together.
+    listed_keys: Vec<String>,
+    listed_positions: HashMap<String, usize>,
+    tags_by_ino: HashMap<u64, String>,
+    handles: HashMap<u64, FileHandle>,
+    dir_handles: HashMap<u64, DirHandle>,
+    next_fh: u64,
+    // Blocking timeouts set via xattr, in seconds.
+    blocking_timeouts_by_ino: HashMap<u64, u64>,
+    // /kv files whose handles are opened in snapshot mode, via xattr.
+    snapshot_inos: HashSet<u64>,
+    // /kv files whose handles hold read locks on their keys, via xattr.
+    read_lock_inos: HashSet<u64>,
+    // Set via /.fusekv/freeze to reject all mutations.
+    frozen: bool,
+    // Where the config file is read again from, if it can be.
+    reloader: Option<Arc<Reloader>>,
+    // Where all state is left once the session ends, to mount again with.
+    remains: Arc<Mutex<Option<KVFS>>>,
+    // Whether the kernel was told the mount is read-only, which only
+    // remounting undoes.
+    mounted_read_only: bool,
+    // Glob patterns of every /kv/.match/<pattern> directory handed out an inode.
+    patterns_by_ino: HashMap<u64, String>,
+    // Keys unlinked from /kv/.match/<pattern>, deleted when it is removed.
+    staged_deletes: HashMap<String, HashSet<String>>,
+    // Glob patterns of every /find/<glob> directory handed out an inode, and
+    // the pattern and key of every link listed in one.
+    finds_by_ino: HashMap<u64, String>,
+    find_links_by_ino: HashMap<u64, (String, String)>,
+    // Prefixes of every /dump/<prefix> file handed out an inode.
+    dumps_by_ino: HashMap<u64, String>,
+    // Why the last write to each key was rejected, for LAST_REJECT_XATTR.
+    last_rejects: HashMap<String, String>,
+    // Highest TTL seen on each expiring key, in seconds, that ttl_display =
+    // "size" counts down from.
+    ttl_starts: HashMap<String, u64>,
+    // Read from /.fusekv/confirm, a token for each pattern a bulk delete over
+    // bulk_delete_threshold was refused for. Writing one back with its pattern
+    // allows the next bulk delete of that pattern alone.
+    delete_tokens: BTreeMap<String, String>,
+    confirmed_deletes: HashSet<String>,
+    // Keys of every /kv/<key>:sha256 companion file handed out an inode.
+    checksum_files_by_ino: HashMap<u64, String>,
+    // Keys of every /kv/<key>:decompressed view handed out an inode.
+    decompressed_by_ino: HashMap<u64, String>,
+    // Seahash and SHA-256 of the last value seen for each key. Seahash is
+    // much cheaper, so it tells us whether the value changed.
+    checksums: LruCache<String, (u64, String)>,
+    // Lookups and reads of each /kv key since mounting.
+    key_hits: LruCache<String, u64>,
+    // Lines of /.fusekv/raw_history, oldest first.
+    raw_history: VecDeque<String>,
+    // Timestamps of every /history/<timestamp> directory handed out an inode.
+    history_ts_by_ino: HashMap<u64, u64>,
+    // Timestamp and key of every /history/<timestamp>/<key> handed out an inode.
+    history_keys_by_ino: HashMap<u64, (u64, String)>,
+    // Channel of every /pubsub/<channel> handed out an inode.
+    channels_by_ino: HashMap<u64, String>,
+    // Key of every /counter/<name> handed out an inode.
+    counters_by_ino: HashMap<u64, String>,
+    // Key of every /queue/<name> handed out an inode.
+    queues_by_ino: HashMap<u64, String>,
+    // Name and driver of each mirror, in the order of their inodes.
+    mirrors: Vec<(String, Arc<dyn Driver>)>,
+    // Mirror index and key of every /mirror/<name>/<key> handed out an inode.
+    mirror_keys_by_ino: HashMap<u64, (usize, String)>,
+    // Key and encoding of every /kv/<key>#<encoding> handed out an inode.
+    encoded_by_ino: HashMap<u64, (String, Encoding)>,
+    // Each /txn session and /publish batch by its path, eg. txn/<session>.
+    // These only exist in this mount.
+    txns: BTreeMap<String, Txn>,
+    // Path of every session or batch handed out an inode.
+    txns_by_ino: HashMap<u64, String>,
+    // Session or batch path and key of every file in one handed out an inode,
+    // including control files.
+    txn_keys_by_ino: HashMap<u64, (String, String)>,
+    // Whether the backend has RedisJSON loaded, so keys holding JSON documents
+    // are directories.
+    json: bool,
+    // Key and JSONPath of every node of a JSON document handed out an inode.
+    json_nodes_by_ino: HashMap<u64, (String, String)>,
+    // Quotas currently past their warning threshold, so crossing it is only
+    // logged once.
+    quotas_warned: HashSet<&'static str>,
+    // Listings cut short by their listing timeout since mounting.
+    truncated_listings: u64,
+    // Milliseconds the backend's clock is ahead of ours, and when that was
+    // last measured. None until the backend first reports its time.
+    clock_skew: Option<i64>,
+    clock_checked: Option<Instant>,
+    // Keys the backend held when last checked for having been flushed.
+    backend_keys: Option<u64>,
+    flush_checked: Option<Instant>,
+    flushes_detected: u64,
+    // Checksums served from, or missing from, checksums since mounting.
+    checksum_hits: u64,
+    checksum_misses: u64,
+    metrics: Arc<Metrics>,
+    // Where reads, writes, deletes, and raw commands are logged with who
+    // made them, if anywhere.
+    audit: Arc<Audit>,
+}
+
+impl KVFS {
+    // A KVFS on driver, with its background work started through tasks.
+    pub fn new(
+        config: Config,
+        driver: Arc<dyn Driver>,
+        mirrors: Vec<(String, Arc<dyn Driver>)>,
+        metrics: Arc<Metrics>,
+        audit: Arc<Audit>,
+        tasks: &Tasks,
+    ) -> KVFS {
+        let behind = match config.write_behind {
+            true => Some(WriteBehind {
+                batch: config.write_behind_batch,
+                interval: Duration::from_millis(config.write_behind_interval),
+                depth: config.write_behind_depth,
+            }),
+            false => None,
+        };
+        let coalescer = WriteCoalescer::start(
+            tasks,
+            driver.clone(),
+            Duration::from_millis(config.coalesce_window),
+            behind,
         );
-        let mut ino_cache = get_ino_cache!(
-            reply,
-            "Failed to acquire write lock on inode cache in read for inode {} on filehandle {}",
-            ino,
-            fh,
+        let hooks = Hooks::start(tasks, driver.clone(), config.hook.clone());
+        let readers = Readers::start(tasks, driver.clone(), config.read_threads);
+        let churn = Churn::start(
+            tasks,
+            driver.clone(),
+            Duration::from_millis(config.adaptive_ttl),
+            config.separator.clone(),
         );
-        match ino {
-            // FUSE internal range
-            0..=RAW_END => match self.direntries_by_ino.get(&ino) {
-                Some(v) => match &v.4 {
-                    Some(content) => reply.data(&content.as_bytes()[offset as usize..]),
-                    None => reply.error(ENOENT),
+        let invalidations = Invalidations::start(tasks, driver.clone(), config.separator.clone());
+        let read_locks = ReadLocks::start(
+            tasks,
+            driver.clone(),
+            Duration::from_secs(config.read_lock_ttl),
+        );
+        KVFS::with_coalescer(
+            config,
+            driver,
+            mirrors,
+            coalescer,
+            hooks,
+            readers,
+            churn,
+            invalidations,
+            read_locks,
+            metrics,
+            audit,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn with_coalescer(
+        config: Config,
+        driver: Arc<dyn Driver>,
+        mirrors: Vec<(String, Arc<dyn Driver>)>,
+        coalescer: Arc<WriteCoalescer>,
+        hooks: Arc<Hooks>,
+        readers: Arc<Readers>,
+        churn: Arc<Churn>,
+        invalidations: Arc<Invalidations>,
+        read_locks: Arc<ReadLocks>,
+        metrics: Arc<Metrics>,
+        audit: Arc<Audit>,
+    ) -> KVFS {
+        let cache = Cache::new(
+            Duration::from_millis(config.attr_ttl),
+            Duration::from_millis(config.data_ttl),
+            Duration::from_millis(config.listing_ttl),
+            metrics.clone(),
+        );
+        let mounted_read_only = config.read_only;
+        KVFS {
+            config,
+            caps: driver.capabilities(),
+            driver,
+            coalescer,
+            hooks,
+            readers,
+            churn,
+            invalidations,
+            read_locks,
+            cache,
+            direntries_by_ino: HashMap::new(),
+            direntries_by_parent_ino: HashMap::new(),
+            lock_names_by_ino: HashMap::new(),
+            lock_dirs: HashSet::new(),
+            kv_keys_by_ino: HashMap::new(),
+            namespaces_by_ino: HashMap::new(),
+            kv_dirs: HashSet::new(),
+            listed_keys: vec![],
+            listed_positions: HashMap::new(),
+            tags_by_ino: HashMap::new(),
+            handles: HashMap::new(),
+            // 0 is what we reply with for handles we don't track.
+            dir_handles: HashMap::new(),
+            next_fh: 1,
+            blocking_timeouts_by_ino: HashMap::new(),
+            snapshot_inos: HashSet::new(),
+            read_lock_inos: HashSet::new(),
+            frozen: false,
+            reloader: None,
+            remains: Arc::new(Mutex::new(None)),
+            mounted_read_only,
+            patterns_by_ino: HashMap::new(),
+            staged_deletes: HashMap::new(),
+            finds_by_ino: HashMap::new(),
+            find_links_by_ino: HashMap::new(),
+            dumps_by_ino: HashMap::new(),
+            last_rejects: HashMap::new(),
+            ttl_starts: HashMap::new(),
+            delete_tokens: BTreeMap::new(),
+            confirmed_deletes: HashSet::new(),
+            checksum_files_by_ino: HashMap::new(),
+            decompressed_by_ino: HashMap::new(),
+            checksums: LruCache::new(CHECKSUM_CACHE_SIZE),
+            key_hits: LruCache::new(HOT_KEYS_TRACKED),
+            raw_history: VecDeque::new(),
+            history_ts_by_ino: HashMap::new(),
+            history_keys_by_ino: HashMap::new(),
+            channels_by_ino: HashMap::new(),
+            counters_by_ino: HashMap::new(),
+            queues_by_ino: HashMap::new(),
+            mirrors,
+            mirror_keys_by_ino: HashMap::new(),
+            encoded_by_ino: HashMap::new(),
+            txns: BTreeMap::new(),
+            txns_by_ino: HashMap::new(),
+            txn_keys_by_ino: HashMap::new(),
+            json: false,
+            json_nodes_by_ino: HashMap::new(),
+            quotas_warned: HashSet::new(),
+            truncated_listings: 0,
+            clock_skew: None,
+            clock_checked: None,
+            backend_keys: None,
+            flush_checked: None,
+            flushes_detected: 0,
+            checksum_hits: 0,
+            checksum_misses: 0,
+            metrics,
+            audit,
+        }
+    }
+
+    // Check which modules the backend has loaded, to expose what's stored with
+    // those supported.
+    pub fn probe_modules(&mut self) -> DriverResult<()> {
+        let modules = self.driver.modules()?;
+        self.json = modules.iter().any(|m| m == "ReJSON");
+        if self.json {
+            log::info!("Found RedisJSON, so reading JSON documents as directories.");
+        }
+        Ok(())
+    }
+
+    // Write the keys in fixture to the backend, returning how many there were.
+    pub fn load_fixture(&self, fixture: &Fixture) -> DriverResult<usize> {
+        fixture.load(&*self.driver)
+    }
+
+    // Write out everything still held back once the session ends, as the
+    // kernel doesn't always say it's unmounting, eg. on a signal. Handles left
+    // open are written as if released.
+    fn flush_all(&mut self) {
+        let fhs: Vec<u64> = self.handles.keys().copied().collect();
+        for fh in fhs {
+            if let Err(e) = self.flush_handle(fh) {
+                log::error!("Error flushing handle {} on unmount: {}", fh, e);
+            }
+            self.read_locks.release(fh);
+        }
+        self.handles.clear();
+        if let Err(e) = self.coalescer.flush_all(false) {
+            log::error!("Error flushing coalesced writes on unmount: {}", e);
+        }
+    }
+
+    // Write out what open handles and coalescing hold back, then forget
+    // everything cached, for /.fusekv/sync. Caches are dropped even if some
+    // writes fail, which are retried on the next sync or flush.
+    fn sync(&mut self) -> DriverResult<()> {
+        let fhs: Vec<u64> = self.handles.keys().copied().collect();
+        let mut result = Ok(());
+        for fh in fhs {
+            if let Err(e) = self.flush_handle(fh) {
+                log::error!("Error flushing handle {} on sync: {}", fh, e);
+                result = Err(e);
+            }
+        }
+        let coalesced = self.coalescer.flush_all(false);
+        self.cache.clear();
+        result.and(coalesced)
+    }
+
+    // Move all state into a new KVFS, leaving an empty one on the same driver
+    // behind.
+    fn detach(&mut self) -> KVFS {
+        let empty = KVFS::with_coalescer(
+            self.config.clone(),
+            self.driver.clone(),
+            self.mirrors.clone(),
+            self.coalescer.clone(),
+            self.hooks.clone(),
+            self.readers.clone(),
+            self.churn.clone(),
+            self.invalidations.clone(),
+            self.read_locks.clone(),
+            self.metrics.clone(),
+            self.audit.clone(),
+        );
+        std::mem::replace(self, empty)
+    }
+}
+
+impl KVFS {
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let _timer = self.time("lookup");
+        self.check_reloaded();
+        log::debug!("lookup {:?} under parent {}", name, parent);
+        self.check_flushed();
+        self.check_changed();
+        reject_if_denied!(self, self.child_path(parent, name), reply);
+        let name_str = match name.to_os_string().into_string() {
+            Ok(v) => v,
+            Err(e) => {
+                log::debug!("Error turning {:?} into string: {:?}", name, e);
+                reply.error(Errno::ENOENT);
+                return;
+            }
+        };
+
+        // FUSE root and other static dirs
+        if self.direntries_by_parent_ino.contains_key(&parent) {
+            match self.direntries_by_parent_ino.get(&parent) {
+                Some(entries) => match entries.get(&name_str) {
+                    Some(entry) => {
+                        reply.entry(&self.entry_ttl(entry.2.ino.0), &entry.2, Generation(0))
+                    }
+                    None => reply.error(Errno::ENOENT),
                 },
-                None => reply.error(ENOENT),
-            },
-            KV_START..=KV_END => {
-                let value: Vec<u8> = match self.driver.read(ino, fh, offset) {
-                    Ok(maybe) => match maybe {
-                        Some(v) => v,
-                        None => {
-                            // TODO lookup all keys and find this one by hash?
-                            reply.error(ENOENT);
-                            return;
+                None => reply.error(Errno::ENOENT),
+            };
+        // /pubsub, where every channel exists
+        } else if parent == 5632 {
+            let attr = self.get_channel_attr(&name_str);
+            reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0));
+        // /counter, where every counter exists
+        } else if parent == 5888 {
+            match self.get_counter_attr(&name_str) {
+                Ok(attr) => reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0)),
+                Err(e) => reply.error(errno(&e)),
+            }
+        // /txn and /publish
+        } else if let Some(path) = txn_path(parent, &name_str) {
+            match self.get_txn_attr(&path) {
+                Some(attr) => reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0)),
+                None => reply.error(Errno::ENOENT),
+            }
+        // /txn/<session> and /publish/<batch>
+        } else if let TXN_START..=TXN_END = parent {
+            let session = self.txns_by_ino.get(&parent).cloned().unwrap_or_default();
+            match self.get_txn_key_attr(&session, &name_str) {
+                Ok(Some(attr)) => reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0)),
+                Ok(None) => reply.error(Errno::ENOENT),
+                Err(e) => reply.error(errno(&e)),
+            }
+        // Objects and arrays of JSON documents
+        } else if let JSON_START..=JSON_END = parent {
+            match self.get_json_child_attr(parent, &name_str) {
+                Ok(Some(attr)) => reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0)),
+                Ok(None) => reply.error(Errno::ENOENT),
+                Err(e) => reply.error(errno(&e)),
+            }
+        // /mirror/<name>
+        } else if let MIRROR_START..=MIRROR_END = parent {
+            match self.get_mirror_key_attr((parent - MIRROR_START) as usize, &name_str) {
+                Ok(Some(attr)) => reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0)),
+                Ok(None) => reply.error(Errno::ENOENT),
+                Err(e) => reply.error(errno(&e)),
+            }
+        // /history
+        } else if parent == 5120 {
+            match name_str.parse::<u64>() {
+                Ok(ts) => {
+                    let attr = self.get_history_attr(ts);
+                    reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0));
+                }
+                Err(_) => reply.error(Errno::ENOENT),
+            }
+        // /history/<timestamp>
+        } else if let HISTORY_START..=HISTORY_END = parent {
+            let ts = match self.history_ts_by_ino.get(&parent) {
+                Some(v) => *v,
+                None => {
+                    reply.error(Errno::ENOENT);
+                    return;
+                }
+            };
+            match self.get_history_key_attr(ts, &name_str) {
+                Ok(Some(attr)) => reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0)),
+                Ok(None) => reply.error(Errno::ENOENT),
+                Err(e) => reply.error(errno(&e)),
+            }
+        // /kv/.match and …truncated in /kv or its namespaces
+        } else if (parent == 4096 && name_str == ".match")
+            || (name_str == TRUNCATED_NAME && self.kv_key_under(parent, &name_str).is_some())
+        {
+            let ino = if name_str == ".match" {
+                KV_MATCH
+            } else {
+                KV_TRUNCATED
+            };
+            match self.direntries_by_ino.get(&ino) {
+                Some(entry) => reply.entry(&self.entry_ttl(entry.2.ino.0), &entry.2, Generation(0)),
+                None => reply.error(Errno::ENOENT),
+            }
+        // /kv/.match/<pattern>
+        } else if parent == KV_MATCH {
+            let attr = self.get_match_attr(&name_str);
+            reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0));
+        // /kv/.match/<pattern>/<name>
+        } else if let MATCH_START..=MATCH_END = parent {
+            if self.is_staged(parent, &name_str) {
+                reply.error(Errno::ENOENT);
+                return;
+            }
+            match self.get_kv_attr(&name_str) {
+                Ok(Some(attr)) => reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0)),
+                Ok(None) => reply.error(Errno::ENOENT),
+                Err(e) => reply.error(errno(&e)),
+            }
+        // /dump/<prefix>
+        } else if parent == DUMP_DIR {
+            let attr = self.get_dump_attr(&name_str);
+            reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0));
+        // /queue, where every queue exists
+        } else if parent == QUEUE_DIR {
+            let attr = self.get_queue_attr(&name_str);
+            reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0));
+        // /find/<glob>
+        } else if parent == FIND_DIR {
+            let attr = self.get_find_attr(&name_str);
+            reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0));
+        // /find/<glob>/<key>
+        } else if let FIND_START..=FIND_END = parent {
+            let pattern = match self.finds_by_ino.get(&parent) {
+                Some(v) => v.clone(),
+                None => {
+                    reply.error(Errno::ENOENT);
+                    return;
+                }
+            };
+            match self.get_find_link_attr(&pattern, &name_str) {
+                Ok(Some(attr)) => reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0)),
+                Ok(None) => reply.error(Errno::ENOENT),
+                Err(e) => reply.error(errno(&e)),
+            }
+        // /kv and /kv namespaces
+        } else if let Some(key) = self.kv_key_under(parent, &name_str) {
+            if let Some(attr) = self.cache.attr(&key) {
+                self.kv_keys_by_ino.insert(attr.ino.0, key.clone());
+                self.checksum_files_by_ino.remove(&attr.ino.0);
+                self.decompressed_by_ino.remove(&attr.ino.0);
+                self.encoded_by_ino.remove(&attr.ino.0);
+                self.record_hit(&key);
+                reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(attr.size));
+                return;
+            }
+            // Fetch from driver, unless a write of it is still held back,
+            // which may be all there is of it in write-behind mode.
+            let fetched = match self.coalescer.pending(&key) {
+                Some(value) => Ok(Some(KVEntry::new(key.clone(), value))),
+                None => self.driver.get_by_name(key.clone(), kv_ino(&key)),
+            };
+            let entry: KVEntry = match fetched {
+                Ok(maybe) => match maybe {
+                    Some(v) => v,
+                    // Real keys win over redirects, encodings, checksum
+                    // companions, decompressed views, and namespaces of the
+                    // same name.
+                    None => {
+                        match self.redirect_of(&key) {
+                            Ok(Some(to)) => {
+                                match self.get_kv_attr(&to) {
+                                    Ok(Some(attr)) => reply.entry(
+                                        &self.entry_ttl(attr.ino.0),
+                                        &attr,
+                                        Generation(0),
+                                    ),
+                                    // Gone again since it was renamed.
+                                    Ok(None) => reply.error(Errno::ESTALE),
+                                    Err(e) => reply.error(errno(&e)),
+                                }
+                                return;
+                            }
+                            Ok(None) => {}
+                            Err(e) => {
+                                reply.error(errno(&e));
+                                return;
+                            }
+                        }
+                        let attr = match (
+                            key.rsplit_once('#'),
+                            key.strip_suffix(SHA256_SUFFIX),
+                            key.strip_suffix(DECOMPRESSED_SUFFIX),
+                        ) {
+                            (Some((key, suffix)), _, _) => match Encoding::from_suffix(suffix) {
+                                Some(encoding) => self.get_encoded_attr(key, encoding),
+                                None => Ok(None),
+                            },
+                            (None, Some(key), _) => self.get_checksum_attr(key),
+                            (None, None, Some(key)) => self.get_decompressed_attr(key),
+                            (None, None, None) => Ok(None),
+                        };
+                        let attr = match attr {
+                            Ok(None) => self.get_namespace_attr(&key),
+                            other => other,
+                        };
+                        match attr {
+                            Ok(Some(attr)) => {
+                                reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0))
+                            }
+                            Ok(None) => reply.error(Errno::ENOENT),
+                            Err(e) => reply.error(errno(&e)),
                         }
-                    },
-                    Err(_) => {
-                        reply.error(EAGAIN);
                         return;
                     }
+                },
+                // JSON documents are directories.
+                Err(DriverError::WrongType(..)) if self.json => {
+                    match self.get_json_attr(&key, "$") {
+                        Ok(Some(attr)) => {
+                            reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0))
+                        }
+                        Ok(None) => reply.error(Errno::ENOENT),
+                        Err(e) => reply.error(errno(&e)),
+                    }
+                    return;
+                }
+                Err(e) => {
+                    reply.error(errno(&e));
+                    return;
+                }
+            };
+            // Only keys that exist are given an inode.
+            let ino = self.key_ino(&key);
+            self.kv_keys_by_ino.insert(ino, key.clone());
+            self.checksum_files_by_ino.remove(&ino);
+            self.decompressed_by_ino.remove(&ino);
+            self.encoded_by_ino.remove(&ino);
+            self.record_hit(&key);
+            let size = (entry.len() + self.newline().len()) as u64;
+            let mut attr = self.get_attr(FileType::RegularFile, ino, size);
+            if let Err(e) = self.stamp_mtime(&key, &mut attr) {
+                reply.error(errno(&e));
+                return;
+            }
+            self.cache.put_attr(&key, attr);
+            reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(size));
+        // /tags
+        } else if parent == 3072 {
+            match self.get_tag_attr(&name_str) {
+                Ok(Some(attr)) => reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0)),
+                Ok(None) => reply.error(Errno::ENOENT),
+                Err(e) => reply.error(errno(&e)),
+            }
+        // /tags/<tag>
+        } else if let TAGS_START..=TAGS_END = parent {
+            let tagged = match self.tags_by_ino.get(&parent) {
+                Some(tag) => self.driver.tagged(tag),
+                None => Ok(vec![]),
+            };
+            match tagged {
+                Ok(keys) if keys.contains(&name_str) => match self.get_kv_attr(&name_str) {
+                    Ok(Some(attr)) => {
+                        reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0))
+                    }
+                    Ok(None) => reply.error(Errno::ENOENT),
+                    Err(e) => reply.error(errno(&e)),
+                },
+                Ok(_) => reply.error(Errno::ENOENT),
+                Err(e) => reply.error(errno(&e)),
+            }
+        // /lock and lock namespaces
+        } else if let Some(lock) = self.lock_child_name(parent, &name_str) {
+            let viewer = self.lock_viewer(req);
+            match self.get_lock_attr(&lock, viewer.as_deref()) {
+                Ok(Some(attr)) => reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0)),
+                Ok(None) => reply.error(Errno::ENOENT),
+                Err(e) => {
+                    log::error!("Error looking up lock {}: {}", lock, e);
+                    reply.error(errno(&e));
+                }
+            }
+        } else {
+            reply.error(Errno::ENOENT);
+        }
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        let _timer = self.time("getattr");
+        self.check_reloaded();
+        log::debug!("getattr for {}", ino);
+        self.check_flushed();
+        self.check_changed();
+        if let Some((key, encoding)) = self.encoded_by_ino.get(&ino).cloned() {
+            match self.get_encoded_attr(&key, encoding) {
+                Ok(Some(attr)) => reply.attr(&self.attr_ttl(attr.ino.0), &attr),
+                Ok(None) => reply.error(Errno::ENOENT),
+                Err(e) => reply.error(errno(&e)),
+            }
+            return;
+        }
+        if let Some(key) = self.checksum_files_by_ino.get(&ino).cloned() {
+            match self.get_checksum_attr(&key) {
+                Ok(Some(attr)) => reply.attr(&self.attr_ttl(attr.ino.0), &attr),
+                Ok(None) => reply.error(Errno::ENOENT),
+                Err(e) => reply.error(errno(&e)),
+            }
+            return;
+        }
+        if let Some(key) = self.decompressed_by_ino.get(&ino).cloned() {
+            match self.get_decompressed_attr(&key) {
+                Ok(Some(attr)) => reply.attr(&self.attr_ttl(attr.ino.0), &attr),
+                Ok(None) => reply.error(Errno::ENOENT),
+                Err(e) => reply.error(errno(&e)),
+            }
+            return;
+        }
+        match ino {
+            0..=RAW_END => match self.direntries_by_ino.get(&ino) {
+                Some(v) => reply.attr(&self.attr_ttl(v.2.ino.0), &v.2),
+                None => reply.error(Errno::ENOENT),
+            },
+            KV_START..=KV_END => {
+                let cached = match self.kv_keys_by_ino.get(&ino) {
+                    Some(key) => self.cache.attr(key),
+                    None => None,
+                };
+                if let Some(attr) = cached {
+                    reply.attr(&self.attr_ttl(attr.ino.0), &attr);
+                    return;
+                }
+                // Fetch attr from redis, unless a write is still held back
+                let pending = self.kv_keys_by_ino.get(&ino).and_then(|key| {
+                    let value = self.coalescer.pending(key)?;
+                    Some(KVEntry::new(key.clone(), value))
+                });
+                let fetched = match pending {
+                    Some(entry) => Ok(Some(entry)),
+                    None => self.driver.get_by_ino(ino),
+                };
+                let entry: KVEntry = match fetched {
+                    Ok(maybe) => match maybe {
+                        Some(v) => v,
+                        None => {
+                            reply.error(Errno::ENOENT);
+                            return;
+                        }
+                    },
+                    Err(e) => {
+                        reply.error(errno(&e));
+                        return;
+                    }
+                };
+                self.kv_keys_by_ino.insert(ino, entry.key.clone());
+                let mut attr = self.get_attr(
+                    FileType::RegularFile,
+                    ino,
+                    (entry.len() + self.newline().len()) as u64,
+                );
+                if let Err(e) = self
+                    .stamp_mtime(&entry.key, &mut attr)
+                    .and_then(|_| self.show_ttl(&entry.key, &mut attr))
+                {
+                    reply.error(errno(&e));
+                    return;
+                }
+                self.cache.put_attr(&entry.key, attr);
+                reply.attr(&self.attr_ttl(attr.ino.0), &attr);
+            }
+            LOCK_START..=LOCK_END => {
+                let lock = match self.lock_names_by_ino.get(&ino) {
+                    Some(v) => v.clone(),
+                    None => {
+                        reply.error(Errno::ENOENT);
+                        return;
+                    }
+                };
+                let viewer = self.lock_viewer(req);
+                match self.get_lock_attr(&lock, viewer.as_deref()) {
+                    Ok(Some(attr)) => reply.attr(&self.attr_ttl(attr.ino.0), &attr),
+                    Ok(None) => reply.error(Errno::ENOENT),
+                    Err(e) => {
+                        log::error!("Error getting attrs of lock {}: {}", lock, e);
+                        reply.error(errno(&e));
+                    }
+                }
+            }
+            TAGS_START..=TAGS_END => {
+                let tag = match self.tags_by_ino.get(&ino) {
+                    Some(v) => v.clone(),
+                    None => {
+                        reply.error(Errno::ENOENT);
+                        return;
+                    }
+                };
+                match self.get_tag_attr(&tag) {
+                    Ok(Some(attr)) => reply.attr(&self.attr_ttl(attr.ino.0), &attr),
+                    Ok(None) => reply.error(Errno::ENOENT),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }
+            HISTORY_START..=HISTORY_END => match self.history_ts_by_ino.get(&ino) {
+                Some(ts) => {
+                    let attr = self.get_history_attr(*ts);
+                    reply.attr(&self.attr_ttl(attr.ino.0), &attr);
+                }
+                None => reply.error(Errno::ENOENT),
+            },
+            HISTORY_KEY_START..=HISTORY_KEY_END => {
+                let (ts, key) = match self.history_keys_by_ino.get(&ino) {
+                    Some(v) => v.clone(),
+                    None => {
+                        reply.error(Errno::ENOENT);
+                        return;
+                    }
+                };
+                match self.get_history_key_attr(ts, &key) {
+                    Ok(Some(attr)) => reply.attr(&self.attr_ttl(attr.ino.0), &attr),
+                    Ok(None) => reply.error(Errno::ENOENT),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }
+            MATCH_START..=MATCH_END => match self.patterns_by_ino.get(&ino) {
+                Some(pattern) => {
+                    let attr = self.get_match_attr(&pattern.clone());
+                    reply.attr(&self.attr_ttl(attr.ino.0), &attr);
+                }
+                None => reply.error(Errno::ENOENT),
+            },
+            FIND_START..=FIND_END => match self.finds_by_ino.get(&ino) {
+                Some(pattern) => {
+                    let attr = self.get_find_attr(&pattern.clone());
+                    reply.attr(&self.attr_ttl(attr.ino.0), &attr);
+                }
+                None => reply.error(Errno::ENOENT),
+            },
+            DUMP_START..=DUMP_END => match self.dumps_by_ino.get(&ino) {
+                Some(prefix) => {
+                    let attr = self.get_dump_attr(&prefix.clone());
+                    reply.attr(&self.attr_ttl(attr.ino.0), &attr);
+                }
+                None => reply.error(Errno::ENOENT),
+            },
+            FIND_LINK_START..=FIND_LINK_END => match self.find_links_by_ino.get(&ino).cloned() {
+                Some((pattern, key)) => match self.get_find_link_attr(&pattern, &key) {
+                    Ok(Some(attr)) => reply.attr(&self.attr_ttl(attr.ino.0), &attr),
+                    Ok(None) => reply.error(Errno::ENOENT),
+                    Err(e) => reply.error(errno(&e)),
+                },
+                None => reply.error(Errno::ENOENT),
+            },
+            PUBSUB_START..=PUBSUB_END => match self.channels_by_ino.get(&ino) {
+                Some(channel) => {
+                    let attr = self.get_channel_attr(&channel.clone());
+                    reply.attr(&self.attr_ttl(attr.ino.0), &attr);
+                }
+                None => reply.error(Errno::ENOENT),
+            },
+            QUEUE_START..=QUEUE_END => match self.queues_by_ino.get(&ino) {
+                Some(name) => {
+                    let attr = self.get_queue_attr(&name.clone());
+                    reply.attr(&self.attr_ttl(attr.ino.0), &attr);
+                }
+                None => reply.error(Errno::ENOENT),
+            },
+            COUNTER_START..=COUNTER_END => {
+                let name = match self.counters_by_ino.get(&ino) {
+                    Some(v) => v.clone(),
+                    None => {
+                        reply.error(Errno::ENOENT);
+                        return;
+                    }
+                };
+                match self.get_counter_attr(&name) {
+                    Ok(attr) => reply.attr(&self.attr_ttl(attr.ino.0), &attr),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }
+            MIRROR_KEY_START..=MIRROR_KEY_END => {
+                let (i, key) = match self.mirror_keys_by_ino.get(&ino) {
+                    Some(v) => v.clone(),
+                    None => {
+                        reply.error(Errno::ENOENT);
+                        return;
+                    }
+                };
+                match self.get_mirror_key_attr(i, &key) {
+                    Ok(Some(attr)) => reply.attr(&self.attr_ttl(attr.ino.0), &attr),
+                    Ok(None) => reply.error(Errno::ENOENT),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }
+            TXN_START..=TXN_END => {
+                let session = self.txns_by_ino.get(&ino).cloned().unwrap_or_default();
+                match self.get_txn_attr(&session) {
+                    Some(attr) => reply.attr(&self.attr_ttl(attr.ino.0), &attr),
+                    None => reply.error(Errno::ENOENT),
+                }
+            }
+            TXN_KEY_START..=TXN_KEY_END => {
+                let (session, key) = match self.txn_keys_by_ino.get(&ino) {
+                    Some(v) => v.clone(),
+                    None => {
+                        reply.error(Errno::ENOENT);
+                        return;
+                    }
+                };
+                match self.get_txn_key_attr(&session, &key) {
+                    Ok(Some(attr)) => reply.attr(&self.attr_ttl(attr.ino.0), &attr),
+                    Ok(None) => reply.error(Errno::ENOENT),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }
+            JSON_START..=JSON_END => {
+                let (key, path) = match self.json_nodes_by_ino.get(&ino) {
+                    Some(v) => v.clone(),
+                    None => {
+                        reply.error(Errno::ENOENT);
+                        return;
+                    }
+                };
+                match self.get_json_attr(&key, &path) {
+                    Ok(Some(attr)) => reply.attr(&self.attr_ttl(attr.ino.0), &attr),
+                    Ok(None) => reply.error(Errno::ENOENT),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }
+            NAMESPACE_START..=NAMESPACE_END => {
+                let namespace = match self.namespaces_by_ino.get(&ino) {
+                    Some(v) => v.clone(),
+                    None => {
+                        reply.error(Errno::ENOENT);
+                        return;
+                    }
+                };
+                match self.get_namespace_attr(&namespace) {
+                    Ok(Some(attr)) => reply.attr(&self.attr_ttl(attr.ino.0), &attr),
+                    Ok(None) => reply.error(Errno::ENOENT),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }
+            _ => reply.error(Errno::ENOENT),
+        };
+    }
+
+    // Links in /find directories point at their keys under /kv.
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        let _timer = self.time("readlink");
+        log::debug!("readlink for inode {}", ino);
+        match self.find_links_by_ino.get(&ino) {
+            Some((_, key)) => reply.data(find_link_target(key).as_bytes()),
+            None => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let _timer = self.time("read");
+        self.check_reloaded();
+        self.check_changed();
+        let key = self.kv_keys_by_ino.get(&ino).cloned();
+        let reply = self.audited(req, "read", self.path_of(ino), key, reply);
+        log::debug!(
+            "read inode {} at offset {} via filehandle {}",
+            ino,
+            offset,
+            fh,
+        );
+        if let Some(content) = self.control_content(ino) {
+            reply.data(window(content.as_bytes(), offset, size));
+            return;
+        }
+        self.take_fetched(fh);
+        if let Some(content) = self.handles.get(&fh).and_then(|h| h.content.as_ref()) {
+            reply.data(window(content, offset, size));
+            return;
+        }
+        // Replies are drained as they're read, since the file offset has
+        // already moved past whatever was written.
+        if ino == RAW_START {
+            match self.handles.get_mut(&fh) {
+                Some(handle) => {
+                    let n = handle.replies.len().min(size as usize);
+                    reply.data(&handle.replies.drain(..n).collect::<Vec<u8>>());
+                }
+                None => reply.error(Errno::EBADF),
+            }
+            return;
+        }
+        if let Some(handle) = self
+            .handles
+            .get(&fh)
+            .filter(|h| h.encoding != Encoding::Plain)
+        {
+            let encoding = handle.encoding;
+            let value = match self.encoded_by_ino.get(&ino) {
+                Some((key, _)) => self.driver.get_by_name(key.clone(), self.key_ino(key)),
+                None => Ok(None),
+            };
+            match value {
+                Ok(Some(entry)) => {
+                    self.record_hit(&entry.key);
+                    reply.data(window(&encoding.encode(&entry.val), offset, size));
+                }
+                Ok(None) => reply.error(Errno::ENOENT),
+                Err(e) => reply.error(errno(&e)),
+            }
+            return;
+        }
+        if let Some(key) = self.checksum_files_by_ino.get(&ino).cloned() {
+            match self.sha256_of(&key) {
+                Ok(Some(v)) => reply.data(window(format!("{}\n", v).as_bytes(), offset, size)),
+                Ok(None) => reply.error(Errno::ENOENT),
+                Err(e) => reply.error(errno(&e)),
+            }
+            return;
+        }
+        match ino {
+            // FUSE internal range
+            0..=RAW_END => match self.direntries_by_ino.get(&ino) {
+                Some(v) => match &v.4 {
+                    Some(content) => reply.data(window(content.as_bytes(), offset, size)),
+                    None => reply.error(Errno::ENOENT),
+                },
+                None => reply.error(Errno::ENOENT),
+            },
+            KV_START..=KV_END => {
+                if let Some(key) = self.kv_keys_by_ino.get(&ino).cloned() {
+                    self.record_hit(&key);
+                    // Writes held back by coalescing haven't reached the
+                    // driver yet.
+                    if let Some(value) = self.coalescer.pending(&key) {
+                        let content = self.with_newline(&value);
+                        reply.data(window(&content, offset, size));
+                        return;
+                    }
+                }
+                let key = match self.kv_keys_by_ino.get(&ino) {
+                    Some(v) => v.clone(),
+                    None => {
+                        reply.error(Errno::ENOENT);
+                        return;
+                    }
+                };
+                let handle = self.handles.get(&fh);
+                if let Some(tail) = handle.and_then(|h| h.tail.clone()) {
+                    let timeout = handle.and_then(|h| h.blocking_timeout);
+                    self.hand_off(Box::new(move |driver| {
+                        read_tail(driver, &key, &tail, timeout, offset, size, reply)
+                    }));
+                    return;
+                }
+                if handle.is_some_and(|h| h.ranged) {
+                    let (offset, size) = (offset.max(0) as u64, size as u64);
+                    let newline = self.newline();
+                    self.hand_off(Box::new(move |driver| {
+                        match driver.read_range(&key, offset, size) {
+                            Ok(Some((mut data, len))) => {
+                                add_newline(&mut data, offset, len, size, newline);
+                                reply.data(&data);
+                            }
+                            Ok(None) => reply.error(Errno::ENOENT),
+                            Err(e) => reply.error(errno(&e)),
+                        }
+                    }));
+                    return;
+                }
+                // Values cached here are read in place, as the cache is only
+                // reachable from this thread.
+                let caching = handle.is_some_and(|h| !h.bypass_cache && !h.snapshot)
+                    && self.cache.caches_values();
+                if !caching {
+                    let (offset, size) = (offset.max(0) as u64, size as u64);
+                    let keep_up_to = self.keep_up_to(fh);
+                    let fetched = handle.map(|h| h.fetched.clone());
+                    let newline = self.newline();
+                    self.hand_off(Box::new(move |driver| {
+                        match fetch_value(driver, &key, offset, size, keep_up_to, newline) {
+                            Ok(Some(Fetched::Whole(mut content))) => {
+                                content.extend_from_slice(newline.as_bytes());
+                                let data = window(&content, offset as i64, size as u32).to_vec();
+                                if let Some(fetched) = fetched {
+                                    *fetched.lock().unwrap() = Some(Kept::Content(content));
+                                }
+                                reply.data(&data);
+                            }
+                            Ok(Some(Fetched::Range(data))) => {
+                                if let (Some(fetched), Some(_)) = (fetched, keep_up_to) {
+                                    *fetched.lock().unwrap() = Some(Kept::Ranged);
+                                }
+                                reply.data(&data);
+                            }
+                            Ok(None) => reply.error(Errno::ENOENT),
+                            Err(e) => reply.error(errno(&e)),
+                        }
+                    }));
+                    return;
+                }
+                match self.read_value(&key, fh, offset.max(0) as u64, size as u64) {
+                    Ok(Some(data)) => reply.data(&data),
+                    Ok(None) => reply.error(Errno::ENOENT),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }
+            MIRROR_KEY_START..=MIRROR_KEY_END => {
+                let value = match self.mirror_keys_by_ino.get(&ino) {
+                    Some((i, key)) => self.mirror_value(*i, key),
+                    None => Ok(None),
+                };
+                match value {
+                    Ok(Some(v)) => {
+                        let content = self.with_newline(&v);
+                        reply.data(window(&content, offset, size));
+                    }
+                    Ok(None) => reply.error(Errno::ENOENT),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }
+            HISTORY_KEY_START..=HISTORY_KEY_END => {
+                let value = match self.history_keys_by_ino.get(&ino) {
+                    Some((ts, key)) => self.driver.get_as_of(key, ts.saturating_mul(1000)),
+                    None => Ok(None),
+                };
+                match value {
+                    Ok(Some(v)) => {
+                        let content = self.with_newline(&v);
+                        reply.data(window(&content, offset, size));
+                    }
+                    Ok(None) => reply.error(Errno::ENOENT),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }
+            LOCK_START..=LOCK_END => {
+                let owner = match self.lock_names_by_ino.get(&ino) {
+                    Some(lock) => self.driver.lock_owner(lock),
+                    None => Ok(None),
+                };
+                // The kernel may have cached the lock's entry from a lookup by
+                // its owner, so privacy can't rest on lookups alone.
+                let viewer = self.lock_viewer(req);
+                match owner {
+                    Ok(Some(v)) if viewer.is_some_and(|viewer| viewer != v) => {
+                        match self.config.lock_privacy {
+                            LockPrivacy::Hide => reply.error(Errno::ENOENT),
+                            _ => reply.error(Errno::EACCES),
+                        }
+                    }
+                    Ok(Some(v)) => reply.data(window(format!("{}\n", v).as_bytes(), offset, size)),
+                    Ok(None) => reply.error(Errno::ENOENT),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }
+            // Messages are drained as they're read, like /raw replies.
+            PUBSUB_START..=PUBSUB_END | QUEUE_START..=QUEUE_END => {
+                let handle = self.handles.get(&fh);
+                let subscriber = match handle.and_then(|h| h.subscriber.clone()) {
+                    Some(v) => v,
+                    // Not opened for reading.
+                    None => {
+                        reply.error(Errno::EBADF);
+                        return;
+                    }
+                };
+                let timeout = handle.and_then(|h| h.blocking_timeout);
+                // Queues being empty is expected, and worth trying again.
+                let timed_out = match ino {
+                    QUEUE_START..=QUEUE_END => Errno::EAGAIN,
+                    _ => Errno::ETIMEDOUT,
+                };
+                self.hand_off(Box::new(move |_| {
+                    read_messages(&subscriber, timeout, timed_out, size, reply)
+                }));
+            }
+            _ => reply.error(Errno::ENOENT),
+        };
+    }
+
+    // /kv and its namespaces are scanned a page at a time as they're read,
+    // unless they need sorting, could be cut short by listing_timeout, or are
+    // cached, in which case readdir lists them whole. /find directories always
+    // are.
+    fn opendir(&mut self, _req: &Request, ino: u64, _flags: i32, reply: ReplyOpen) {
+        let _timer = self.time("opendir");
+        self.check_reloaded();
+        log::debug!("opendir for inode {}", ino);
+        if let Some(pattern) = self.finds_by_ino.get(&ino).cloned() {
+            let fh = self.next_fh;
+            self.next_fh += 1;
+            self.dir_handles.insert(
+                fh,
+                DirHandle {
+                    entries: vec![
+                        (FIND_DIR, FileType::Directory, "..".to_string()),
+                        (ino, FileType::Directory, ".".to_string()),
+                    ],
+                    names: HashSet::new(),
+                    prefix: String::new(),
+                    find: Some(pattern),
+                    cursor: Some(String::new()),
+                },
+            );
+            reply.opened(fuser::FileHandle(fh), FopenFlags::empty());
+            return;
+        }
+        if !matches!(ino, 4096 | NAMESPACE_START..=NAMESPACE_END)
+            || self.listing_order(ino) != ListingOrder::Backend
+            || self.listing_timeout(ino).is_some()
+        {
+            reply.opened(fuser::FileHandle(0), FopenFlags::empty());
+            return;
+        }
+        let path = self.path_of(ino).unwrap_or_default();
+        if self.cache.listing(&path).is_some() {
+            reply.opened(fuser::FileHandle(0), FopenFlags::empty());
+            return;
+        }
+        let (prefix, cursor) = match (ino, &self.config.separator) {
+            (4096, _) | (_, None) => (String::new(), Some(String::new())),
+            (_, Some(separator)) => match self.namespaces_by_ino.get(&ino) {
+                Some(v) => (format!("{}{}", v, separator), Some(String::new())),
+                None => (String::new(), None),
+            },
+        };
+        let cur_dir: DirEntry = curdir!(self, ino);
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.dir_handles.insert(
+            fh,
+            DirHandle {
+                entries: vec![
+                    (1, FileType::Directory, "..".to_string()),
+                    (cur_dir.0, cur_dir.1, cur_dir.3),
+                ],
+                names: HashSet::new(),
+                prefix,
+                find: None,
+                cursor,
+            },
+        );
+        self.listed_keys = vec![];
+        self.listed_positions = HashMap::new();
+        reply.opened(fuser::FileHandle(fh), FopenFlags::empty());
+    }
+
+    fn releasedir(&mut self, _req: &Request, ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        let _timer = self.time("releasedir");
+        log::debug!("releasedir inode {} via filehandle {}", ino, fh);
+        self.dir_handles.remove(&fh);
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let _timer = self.time("readdir");
+        self.check_reloaded();
+        log::debug!("readdir for inode {} via filehandle {}", ino, fh);
+        self.check_flushed();
+        self.check_changed();
+        if let Some(mut dir) = self.dir_handles.remove(&fh) {
+            // Scan until there's more than a reply's worth past offset.
+            while dir.cursor.is_some() && dir.entries.len() <= offset as usize + READDIR_PAGE {
+                if let Err(e) = self.read_dir_page(ino, &mut dir) {
+                    log::error!("Error listing {}: {}", ino, e);
+                    self.dir_handles.insert(fh, dir);
+                    reply.error(errno(&e));
+                    return;
+                }
+            }
+            for (i, entry) in dir.entries.iter().enumerate().skip(offset as usize) {
+                if reply.add(INodeNo(entry.0), (i + 1) as u64, entry.1, &entry.2) {
+                    break;
+                }
+            }
+            self.dir_handles.insert(fh, dir);
+            reply.ok();
+            return;
+        }
+        let cur_dir: DirEntry = curdir!(self, ino);
+        let mut entries: Vec<ReadDirEntry> = vec![(1, FileType::Directory, "..".to_string())];
+        // We have to always include the root dir at inode 1, if we push it
+        // unconditionally we end up with duplicated `.` entries.
+        if ino != 1 {
+            entries.push((cur_dir.0, cur_dir.1, cur_dir.3));
+        }
+
+        // Root dir
+        entries.extend(match ino {
+            // Root dir and other static dirs
+            _ if self.direntries_by_parent_ino.contains_key(&ino) => self.direntries_by_parent_ino
+                [&ino]
+                .values()
+                .map(|v| (v.0, v.1, v.3.clone()))
+                .collect::<Vec<ReadDirEntry>>(),
+            // /kv and /kv namespaces
+            4096 | NAMESPACE_START..=NAMESPACE_END => {
+                match self.cached_direntries(ino, |fs| fs.get_kv_direntries(ino)) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::error!("Error listing root directory: {}", e);
+                        reply.error(errno(&e));
+                        return;
+                    }
+                }
+            }
+            // /lock and lock namespaces
+            2048 | LOCK_START..=LOCK_END => match self.get_lock_direntries(ino, req) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::error!("Error listing locks: {}", e);
+                    reply.error(errno(&e));
+                    return;
+                }
+            },
+            // /tags and /tags/<tag>
+            3072 | TAGS_START..=TAGS_END => {
+                match self.cached_direntries(ino, |fs| fs.get_tag_direntries(ino)) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::error!("Error listing tags: {}", e);
+                        reply.error(errno(&e));
+                        return;
+                    }
+                }
+            }
+            // /history and /history/<timestamp>
+            5120 | HISTORY_START..=HISTORY_END => match self.get_history_direntries(ino) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::error!("Error listing history: {}", e);
+                    reply.error(errno(&e));
+                    return;
+                }
+            },
+            // /counter lists nothing, counters are looked up by name.
+            5888 => vec![],
+            // /find lists nothing, patterns are looked up by name, and /find/<glob>
+            // is only listed without a handle if it was forgotten.
+            FIND_DIR | FIND_START..=FIND_END => vec![],
+            // /queue lists nothing, queues are looked up by name.
+            QUEUE_DIR => vec![],
+            // /dump lists nothing, prefixes are looked up by name.
+            DUMP_DIR => vec![],
+            TXN_DIR | PUBLISH_DIR => self.get_txn_direntries(ino),
+            TXN_START..=TXN_END if self.txns_by_ino.contains_key(&ino) => {
+                self.get_txn_direntries(ino)
+            }
+            JSON_START..=JSON_END => match self.get_json_direntries(ino) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::error!("Error listing JSON document: {}", e);
+                    reply.error(errno(&e));
+                    return;
+                }
+            },
+            MIRROR_START..=MIRROR_END => {
+                match self.get_mirror_direntries((ino - MIRROR_START) as usize) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::error!("Error listing mirror: {}", e);
+                        reply.error(errno(&e));
+                        return;
+                    }
+                }
+            }
+            5632 => match self.get_pubsub_direntries() {
+                Ok(v) => v,
+                Err(e) => {
+                    log::error!("Error listing channels: {}", e);
+                    reply.error(errno(&e));
+                    return;
+                }
+            },
+            // /kv/.match and /kv/.match/<pattern>
+            KV_MATCH | MATCH_START..=MATCH_END => match self.get_match_direntries(ino) {
+                Ok(v) => v,
+                Err(e) => {
+                    log::error!("Error listing matching keys: {}", e);
+                    reply.error(errno(&e));
+                    return;
+                }
+            },
+            _ => {
+                reply.error(Errno::ENOENT);
+                return;
+            }
+        });
+        if matches!(ino, 4096 | NAMESPACE_START..=NAMESPACE_END) && offset == 0 {
+            self.listed_keys = entries
+                .iter()
+                .filter_map(|e| self.kv_keys_by_ino.get(&e.0).cloned())
+                .collect();
+            self.listed_positions = self
+                .listed_keys
+                .iter()
+                .enumerate()
+                .map(|(i, k)| (k.clone(), i))
+                .collect();
+        }
+
+        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
+            // i + 1 means the index of the next entry
+            if reply.add(INodeNo(entry.0), (i + 1) as u64, entry.1, entry.2) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        let _timer = self.time("open");
+        self.check_reloaded();
+        log::debug!("open inode {} with flags {:#o}", ino, flags);
+        self.check_flushed();
+        self.check_changed();
+        reject_if_denied!(self, self.path_of(ino), reply);
+        if flags & O_ACCMODE != O_RDONLY {
+            reject_unless_allowed!(self, req, self.path_of(ino), reply);
+            reject_unless_acl_allows!(self, req, acl_command("SET", self.path_of(ino)), reply);
+        }
+        if let Err(e) = self.check_open_limits(self.kv_keys_by_ino.get(&ino).map(String::as_str)) {
+            reply.error(e);
+            return;
+        }
+        let tail = match self.kv_keys_by_ino.get(&ino).cloned() {
+            Some(key)
+                if self.caps.streams
+                    && flags & O_NONBLOCK != 0
+                    && flags & O_ACCMODE == O_RDONLY =>
+            {
+                match self.current_entry(&key) {
+                    Ok(Some((v, ValueKind::Stream))) => Some(new_tail(&v)),
+                    Ok(_) => None,
+                    Err(e) => {
+                        reply.error(errno(&e));
+                        return;
+                    }
+                }
+            }
+            _ => None,
+        };
+        // Readers of a queue each wait on it over a connection of their own.
+        let subscription = match (self.channels_by_ino.get(&ino), self.queues_by_ino.get(&ino)) {
+            _ if flags & O_ACCMODE == O_WRONLY => None,
+            (Some(channel), _) => Some(self.driver.subscribe(channel)),
+            (_, Some(queue)) => Some(self.driver.consume(queue)),
+            _ => None,
+        };
+        let subscriber = match subscription {
+            Some(Ok(v)) => Some(Subscriber {
+                subscription: v,
+                pending: vec![],
+            }),
+            Some(Err(e)) => {
+                reply.error(errno(&e));
+                return;
+            }
+            None => None,
+        };
+        // Control files, /raw, and generated files change underneath the
+        // kernel, so never cache them. Nor can tailed streams, which grow
+        // past the size the kernel was told, or channels or queues, or keys
+        // whose size counts down their TTL.
+        let bypass_cache = flags & O_DIRECT != 0
+            || tail.is_some()
+            || (self.config.ttl_display == TtlDisplay::Size && matches!(ino, KV_START..=KV_END))
+            || matches!(
+                ino,
+                PUBSUB_START..=PUBSUB_END
+                    | QUEUE_START..=QUEUE_END
+                    | COUNTER_START..=COUNTER_END
+                    | TXN_KEY_START..=TXN_KEY_END
+                    | JSON_START..=JSON_END
+                    | DUMP_START..=DUMP_END
+            )
+            || ino == RAW_START
+            || matches!(ino, KV_RANDOM..=KV_COUNT | CONTROL_STATS | CONTROL_TRACE)
+            || self.control_content(ino).is_some()
+            || match self.path_of(ino) {
+                Some(path) => self.config.nocache.iter().any(|p| p.is_match(&path)),
+                None => false,
+            };
+        let mut kind = ValueKind::String;
+        let mut append = false;
+        let mut streaming = false;
+        let buffer = match self.kv_keys_by_ino.get(&ino).cloned() {
+            Some(key) if flags & O_ACCMODE != O_RDONLY => {
+                reject_unless_writable!(self, reply);
+                // Values too big to load are edited in place instead, bar
+                // appends, which never load them.
+                if flags & (O_TRUNC | O_APPEND) == 0 {
+                    match self.streams_writes(&key) {
+                        Ok(v) => streaming = v,
+                        Err(e) => {
+                            reply.error(errno(&e));
+                            return;
+                        }
+                    }
+                }
+                let entry = match streaming {
+                    true => Ok(None),
+                    false => self.current_entry(&key),
+                };
+                let entry = match entry {
+                    Ok(v) => v,
+                    Err(e) => {
+                        reply.error(errno(&e));
+                        return;
+                    }
+                };
+                if let Some((_, k)) = &entry {
+                    kind = *k;
+                }
+                // Entries can't be replaced, only added.
+                // Appending after truncating is only writing.
+                append = kind == ValueKind::Stream
+                    || (flags & O_APPEND != 0
+                        && (kind != ValueKind::String || flags & O_TRUNC == 0));
+                match entry {
+                    Some((v, _)) if flags & O_TRUNC == 0 && !append => Some(self.with_newline(&v)),
+                    _ if streaming => None,
+                    _ => Some(vec![]),
+                }
+            }
+            // Holds the start of a message until the rest of its line is
+            // written.
+            None if (self.channels_by_ino.contains_key(&ino)
+                || self.queues_by_ino.contains_key(&ino))
+                && flags & O_ACCMODE != O_RDONLY =>
+            {
+                reject_unless_writable!(self, reply);
+                Some(vec![])
+            }
+            // Holds what's set at the node's path once the file is flushed.
+            None if flags & O_ACCMODE != O_RDONLY && matches!(ino, JSON_START..=JSON_END) => {
+                reject_unless_writable!(self, reply);
+                match flags & O_TRUNC {
+                    0 => match self.generated_content(ino) {
+                        Ok(v) => Some(v.unwrap_or_default()),
+                        Err(e) => {
+                            reply.error(errno(&e));
+                            return;
+                        }
+                    },
+                    _ => Some(vec![]),
+                }
+            }
+            // Holds the keys to restore once the file is flushed, which replace
+            // rather than add to what's read.
+            None if flags & O_ACCMODE != O_RDONLY && matches!(ino, DUMP_START..=DUMP_END) => {
+                reject_unless_writable!(self, reply);
+                Some(vec![])
+            }
+            // Holds what's queued for the key once the file is flushed.
+            // Control files take commands instead.
+            None if flags & O_ACCMODE != O_RDONLY => match self.txn_keys_by_ino.get(&ino) {
+                Some((session, key)) if !self.is_txn_control(session, key) => {
+                    reject_unless_writable!(self, reply);
+                    match flags & O_TRUNC {
+                        0 => match self.txn_content(session, key) {
+                            Ok(v) => Some(v.unwrap_or_default()),
+                            Err(e) => {
+                                reply.error(errno(&e));
+                                return;
+                            }
+                        },
+                        _ => Some(vec![]),
+                    }
+                }
+                _ => None,
+            },
+            _ => None,
+        };
+        let encoding = match self.encoded_by_ino.get(&ino) {
+            Some((_, encoding)) => *encoding,
+            None => Encoding::Plain,
+        };
+        // Decompressed views are decompressed once per open.
+        let content = match self.decompressed_by_ino.get(&ino).cloned() {
+            Some(key) => self
+                .decompressed_of(&key)
+                .map(|v| Some(v.unwrap_or_default())),
+            None => match self.counters_by_ino.get(&ino).cloned() {
+                Some(name) => self.counter_content(&name).map(Some),
+                // Restoring a dump needn't take one first.
+                None if flags & O_ACCMODE == O_WRONLY && matches!(ino, DUMP_START..=DUMP_END) => {
+                    Ok(None)
+                }
+                None => self.generated_content(ino),
+            },
+        };
+        let content = match content {
+            Ok(v) => v,
+            Err(e) => {
+                reply.error(errno(&e));
+                return;
+            }
+        };
+        let fh = self.new_handle(ino, bypass_cache, encoding, buffer);
+        if let Some(key) = self.kv_keys_by_ino.get(&ino) {
+            if flags & O_ACCMODE == O_RDONLY && self.read_lock_inos.contains(&ino) {
+                let owner = format!("{}:{}", lock_owner(req), fh);
+                if let Err(e) = self.read_locks.hold(fh, key, owner) {
+                    self.handles.remove(&fh);
+                    reply.error(errno(&e));
+                    return;
+                }
+            }
+        }
+        if let Some(handle) = self.handles.get_mut(&fh) {
+            handle.content = content;
+            handle.kind = kind;
+            handle.append = append;
+            handle.streaming = streaming;
+            handle.tail = tail.map(|t| Arc::new(Mutex::new(t)));
+            handle.subscriber = subscriber.map(|s| Arc::new(Mutex::new(s)));
+            if matches!(ino, COUNTER_START..=COUNTER_END) {
+                handle.append = flags & O_APPEND != 0;
+            }
+        }
+        // Direct IO also keeps the kernel from serving reads out of its page
+        // cache for this handle.
+        reply.opened(
+            fuser::FileHandle(fh),
+            if bypass_cache {
+                FopenFlags::FOPEN_DIRECT_IO
+            } else {
+                FopenFlags::empty()
+            },
+        );
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let _timer = self.time("release");
+        self.check_reloaded();
+        log::debug!("release inode {} via filehandle {}", ino, fh);
+        // Flush should have been called already, this is a last resort.
+        if let Err(e) = self.flush_handle(fh) {
+            log::error!("Error writing inode {} on release: {}", ino, e);
+        }
+        self.read_locks.release(fh);
+        // Dropping a subscriber unsubscribes it, once any read waiting on it
+        // is done.
+        if let Some(handle) = self.handles.remove(&fh) {
+            if handle.subscriber.is_some() {
+                log::debug!("Unsubscribing filehandle {}", fh);
+            }
+        }
+        reply.ok();
+    }
+
+    fn flush(&mut self, _req: &Request, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        let _timer = self.time("flush");
+        self.check_reloaded();
+        log::debug!("flush inode {} via filehandle {}", ino, fh);
+        match self.flush_handle(fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let _timer = self.time("fsync");
+        self.check_reloaded();
+        log::debug!("fsync inode {} via filehandle {}", ino, fh);
+        let key = match self.kv_keys_by_ino.get(&ino) {
+            Some(v) => v.clone(),
+            None => {
+                reply.ok();
+                return;
+            }
+        };
+        match self.coalescer.flush(&key) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    // Called once the session ends, whether the kernel said so or the session
+    // was dropped. Open handles die with the session, so dirty ones are
+    // written out before leaving everything else for remounting.
+    fn destroy(&mut self) {
+        log::debug!("destroy");
+        self.flush_all();
+        let remains = self.remains.clone();
+        *remains.lock().unwrap() = Some(self.detach());
+    }
+
+    fn write(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let _timer = self.time("write");
+        self.check_reloaded();
+        let key = self.kv_keys_by_ino.get(&ino).cloned();
+        let reply = self.audited(req, "write", self.path_of(ino), key, reply);
+        log::debug!(
+            "write {} bytes to inode {} at offset {} via filehandle {}",
+            data.len(),
+            ino,
+            offset,
+            fh
+        );
+        let cmd = String::from_utf8_lossy(data).trim().to_string();
+        match ino {
+            KV_START..=KV_END => {
+                reject_unless_writable!(self, reply);
+                match self.write_in_place(ino, fh, offset.max(0) as u64, data) {
+                    Ok(true) => {
+                        reply.written(data.len() as u32);
+                        return;
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        reply.error(errno(&e));
+                        return;
+                    }
+                }
+                match self.write_buffer(fh, offset, data) {
+                    Ok(()) => reply.written(data.len() as u32),
+                    Err(e) => reply.error(e),
+                }
+            }
+            TXN_KEY_START..=TXN_KEY_END => {
+                reject_unless_writable!(self, reply);
+                let (session, key) = match self.txn_keys_by_ino.get(&ino) {
+                    Some(v) => v.clone(),
+                    None => {
+                        reply.error(Errno::ENOENT);
+                        return;
+                    }
+                };
+                if !self.is_txn_control(&session, &key) {
+                    match self.write_buffer(fh, offset, data) {
+                        Ok(()) => reply.written(data.len() as u32),
+                        Err(e) => reply.error(e),
+                    }
+                    return;
+                }
+                // Anything written publishes a batch.
+                if key == PUBLISH_CONTROL {
+                    match self.publish_batch(&session) {
+                        Ok(n) => {
+                            log::info!("Published {} keys from {}.", n, session);
+                            reply.written(data.len() as u32);
+                        }
+                        Err(e) => reply.error(e),
+                    }
+                    return;
+                }
+                match cmd.as_str() {
+                    "commit" => match self.commit_txn(&session) {
+                        Ok(n) => {
+                            log::info!("Committed {} writes in transaction {}.", n, session);
+                            reply.written(data.len() as u32);
+                        }
+                        Err(e) => {
+                            log::error!("Error committing transaction {}: {}", session, e);
+                            reply.error(errno(&e));
+                        }
+                    },
+                    "discard" => {
+                        if let Some(txn) = self.txns.get_mut(&session) {
+                            txn.writes.clear();
+                        }
+                        reply.written(data.len() as u32);
+                    }
+                    _ => reply.error(Errno::EINVAL),
+                }
+            }
+            JSON_START..=JSON_END | DUMP_START..=DUMP_END => {
+                reject_unless_writable!(self, reply);
+                match self.write_buffer(fh, offset, data) {
+                    Ok(()) => reply.written(data.len() as u32),
+                    Err(e) => reply.error(e),
+                }
+            }
+            COUNTER_START..=COUNTER_END => {
+                reject_unless_writable!(self, reply);
+                match self.write_counter(ino, fh, data) {
+                    Ok(()) => reply.written(data.len() as u32),
+                    Err(e) => reply.error(e),
+                }
+            }
+            PUBSUB_START..=PUBSUB_END | QUEUE_START..=QUEUE_END => {
+                reject_unless_writable!(self, reply);
+                match self.send_lines(ino, fh, data) {
+                    Ok(()) => reply.written(data.len() as u32),
+                    Err(e) => reply.error(e),
+                }
+            }
+            RAW_START => {
+                reject_unless_writable!(self, reply);
+                let mut replies = vec![];
+                for line in String::from_utf8_lossy(data).lines() {
+                    let args = match split_command(line) {
+                        Some(v) if v.is_empty() => continue,
+                        Some(v) => v,
+                        None => {
+                            reply.error(Errno::EINVAL);
+                            return;
+                        }
+                    };
+                    // Passwords given to AUTH and the like are never kept.
+                    let redacted = audit::redact(&args);
+                    let line = redacted.as_deref().unwrap_or(line);
+                    self.record_raw(req.uid(), line);
+                    let entry = self.audit.begin(req, "raw", Some("/raw".to_string()), None);
+                    let result = self.driver.raw(&args);
+                    if let Some(entry) = entry {
+                        let outcome = result.as_ref().map(|_| ()).map_err(errno);
+                        entry.with("command", line).finish(outcome);
+                    }
+                    match result {
+                        Ok(v) => replies.extend_from_slice(v.as_bytes()),
+                        Err(e) => {
+                            reply.error(errno(&e));
+                            return;
+                        }
+                    }
+                }
+                // Raw commands can change any key.
+                self.cache.clear();
+                match self.handles.get_mut(&fh) {
+                    Some(handle) => {
+                        handle.replies.extend(replies);
+                        reply.written(data.len() as u32);
+                    }
+                    None => reply.error(Errno::EBADF),
+                }
+            }
+            CONTROL_FREEZE => match cmd.as_str() {
+                "1" | "freeze" => {
+                    log::info!("Freezing mount, mutations will fail with EBUSY.");
+                    self.frozen = true;
+                    reply.written(data.len() as u32);
+                }
+                "0" | "thaw" => {
+                    log::info!("Thawing mount.");
+                    self.frozen = false;
+                    reply.written(data.len() as u32);
+                }
+                _ => reply.error(Errno::EINVAL),
+            },
+            // Each line is a glob of the paths whose cached listings, or keys'
+            // cached attributes and values, are dropped.
+            CONTROL_INVALIDATE => {
+                let mut forgotten = 0;
+                for line in cmd.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                    match glob_regex(line) {
+                        Some(pattern) => forgotten += self.cache.invalidate(&pattern),
+                        None => {
+                            reply.error(Errno::EINVAL);
+                            return;
+                        }
+                    }
+                }
+                log::info!("Invalidated {} cached entries.", forgotten);
+                reply.written(data.len() as u32);
+            }
+            // Anything written reloads the config file.
+            CONTROL_RELOAD => {
+                let reloaded = match &self.reloader {
+                    Some(reloader) => reloader.reload(),
+                    None => {
+                        reply.error(Errno::ENOTSUP);
+                        return;
+                    }
+                };
+                if let Err(e) = reloaded {
+                    log::error!("Error reloading config, keeping the current one: {}", e);
+                    reply.error(Errno::EINVAL);
+                    return;
+                }
+                self.check_reloaded();
+                reply.written(data.len() as u32);
+            }
+            // Anything written is a barrier: it's only replied to once every
+            // write held back has reached the backend and every cache is dropped.
+            CONTROL_SYNC => match self.sync() {
+                Ok(()) => {
+                    log::info!("Synced buffered writes and dropped caches.");
+                    reply.written(data.len() as u32);
+                }
+                Err(e) => {
+                    log::error!("Error syncing buffered writes: {}", e);
+                    reply.error(errno(&e));
+                }
+            },
+            // Each line is a token and the pattern it was handed out for.
+            CONTROL_CONFIRM => {
+                let mut confirmed = vec![];
+                for line in cmd.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                    match line.split_once(' ') {
+                        Some((token, pattern))
+                            if self.delete_tokens.get(pattern).is_some_and(|t| t == token) =>
+                        {
+                            confirmed.push(pattern.to_string())
+                        }
+                        _ => {
+                            reply.error(Errno::EINVAL);
+                            return;
+                        }
+                    }
+                }
+                if confirmed.is_empty() {
+                    reply.error(Errno::EINVAL);
+                    return;
+                }
+                for pattern in confirmed {
+                    log::info!(
+                        "Confirmed the next bulk delete of keys matching {}.",
+                        pattern
+                    );
+                    self.delete_tokens.remove(&pattern);
+                    self.confirmed_deletes.insert(pattern);
+                }
+                reply.written(data.len() as u32);
+            }
+            _ => reply.error(Errno::EPERM),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let _timer = self.time("setattr");
+        self.check_reloaded();
+        log::debug!("setattr for {}", ino);
+        // Only truncating changes anything.
+        let reply = match size {
+            Some(size) => {
+                let key = self.kv_keys_by_ino.get(&ino).cloned();
+                self.audited(req, "truncate", self.path_of(ino), key, reply)
+                    .with("size", size)
+            }
+            None => Audited::new(reply, None),
+        };
+        if size.is_some() {
+            reject_unless_allowed!(self, req, self.path_of(ino), reply);
+            reject_unless_acl_allows!(self, req, acl_command("SET", self.path_of(ino)), reply);
+        }
+        match ino {
+            // Opening control files or /raw for writing truncates them first.
+            _ if ino == RAW_START || self.control_content(ino).is_some() => {
+                match self.direntries_by_ino.get(&ino) {
+                    Some(v) => reply.attr(&self.attr_ttl(v.2.ino.0), &v.2),
+                    None => reply.error(Errno::ENOENT),
+                }
+            }
+            // Setting a counter replaces it whole, so truncating first is a
+            // no-op.
+            COUNTER_START..=COUNTER_END => {
+                let name = match self.counters_by_ino.get(&ino) {
+                    Some(v) => v.clone(),
+                    None => {
+                        reply.error(Errno::ENOENT);
+                        return;
+                    }
+                };
+                match self.get_counter_attr(&name) {
+                    Ok(attr) => reply.attr(&self.attr_ttl(attr.ino.0), &attr),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }
+            // Writing a /dump file restores what's written whole, so
+            // truncating only empties what's buffered.
+            DUMP_START..=DUMP_END => {
+                let prefix = match self.dumps_by_ino.get(&ino) {
+                    Some(v) => v.clone(),
+                    None => {
+                        reply.error(Errno::ENOENT);
+                        return;
+                    }
+                };
+                if let Some(size) = size {
+                    reject_unless_writable!(self, reply);
+                    let handle = fh.and_then(|fh| self.handles.get_mut(&fh));
+                    if let Some(buffer) = handle.and_then(|h| h.buffer.as_mut()) {
+                        buffer.truncate(size as usize);
+                    }
+                }
+                let attr = self.get_dump_attr(&prefix);
+                reply.attr(&self.attr_ttl(attr.ino.0), &attr);
+            }
+            // Truncating a file in a /txn session queues the truncated value.
+            TXN_KEY_START..=TXN_KEY_END => {
+                let (session, key) = match self.txn_keys_by_ino.get(&ino) {
+                    Some(v) => v.clone(),
+                    None => {
+                        reply.error(Errno::ENOENT);
+                        return;
+                    }
+                };
+                let mut buffered = None;
+                if let (Some(size), false) = (size, self.is_txn_control(&session, &key)) {
+                    reject_unless_writable!(self, reply);
+                    let handle = fh.and_then(|fh| self.handles.get_mut(&fh));
+                    if let Some(handle) = handle.filter(|h| h.buffer.is_some()) {
+                        handle
+                            .buffer
+                            .get_or_insert_with(Vec::new)
+                            .resize(size as usize, 0);
+                        handle.dirty = true;
+                        buffered = Some(size);
+                    } else {
+                        let mut value = match self.txn_content(&session, &key) {
+                            Ok(v) => v.unwrap_or_default(),
+                            Err(e) => {
+                                reply.error(errno(&e));
+                                return;
+                            }
+                        };
+                        value.resize(size as usize, 0);
+                        let value = stored_value(&value, self.config.append_newline);
+                        if let Err(e) = self.queue_txn_write(&session, &key, Some(value)) {
+                            reply.error(e);
+                            return;
+                        }
+                    }
+                }
+                match self.get_txn_key_attr(&session, &key) {
+                    Ok(Some(mut attr)) => {
+                        if let Some(len) = buffered {
+                            attr.size = len;
+                        }
+                        reply.attr(&self.attr_ttl(attr.ino.0), &attr);
+                    }
+                    Ok(None) => reply.error(Errno::ENOENT),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }
+            // Truncating a value of a JSON document sets what's left of it.
+            JSON_START..=JSON_END => {
+                let (key, path) = match self.json_nodes_by_ino.get(&ino) {
+                    Some(v) => v.clone(),
+                    None => {
+                        reply.error(Errno::ENOENT);
+                        return;
+                    }
+                };
+                let mut buffered = None;
+                if let Some(size) = size {
+                    reject_unless_writable!(self, reply);
+                    let handle = fh.and_then(|fh| self.handles.get_mut(&fh));
+                    if let Some(handle) = handle.filter(|h| h.buffer.is_some()) {
+                        handle
+                            .buffer
+                            .get_or_insert_with(Vec::new)
+                            .resize(size as usize, 0);
+                        handle.dirty = true;
+                        buffered = Some(size);
+                    } else {
+                        let result = self.generated_content(ino).and_then(|v| {
+                            let mut value = v.unwrap_or_default();
+                            value.resize(size as usize, 0);
+                            let value = stored_value(&value, self.config.append_newline);
+                            self.write_json(&key, &path, &value)
+                        });
+                        if let Err(e) = result {
+                            reply.error(errno(&e));
+                            return;
+                        }
+                    }
+                }
+                match self.get_json_attr(&key, &path) {
+                    Ok(Some(mut attr)) => {
+                        if let Some(len) = buffered {
+                            attr.size = len;
+                        }
+                        reply.attr(&self.attr_ttl(attr.ino.0), &attr);
+                    }
+                    Ok(None) => reply.error(Errno::ENOENT),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }
+            // Channels hold nothing to truncate, but `echo >` truncates first.
+            PUBSUB_START..=PUBSUB_END => match self.channels_by_ino.get(&ino) {
+                Some(channel) => {
+                    let attr = self.get_channel_attr(&channel.clone());
+                    reply.attr(&self.attr_ttl(attr.ino.0), &attr);
+                }
+                None => reply.error(Errno::ENOENT),
+            },
+            // Nor do queues, which are only added to.
+            QUEUE_START..=QUEUE_END => match self.queues_by_ino.get(&ino) {
+                Some(name) => {
+                    let attr = self.get_queue_attr(&name.clone());
+                    reply.attr(&self.attr_ttl(attr.ino.0), &attr);
+                }
+                None => reply.error(Errno::ENOENT),
+            },
+            // Locks have no settable attributes, but touch expects this to succeed.
+            LOCK_START..=LOCK_END => {
+                let lock = match self.lock_names_by_ino.get(&ino) {
+                    Some(v) => v.clone(),
+                    None => {
+                        reply.error(Errno::ENOENT);
+                        return;
+                    }
+                };
+                let viewer = self.lock_viewer(req);
+                match self.get_lock_attr(&lock, viewer.as_deref()) {
+                    Ok(Some(attr)) => reply.attr(&self.attr_ttl(attr.ino.0), &attr),
+                    Ok(None) => reply.error(Errno::ENOENT),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }
+            KV_START..=KV_END => {
+                let key = match self.kv_keys_by_ino.get(&ino) {
+                    Some(v) => v.clone(),
+                    None => {
+                        reply.error(Errno::ENOENT);
+                        return;
+                    }
+                };
+                let reply = match size {
+                    Some(size) => {
+                        reject_unless_writable!(self, reply);
+                        match self.truncate(&key, fh, size) {
+                            Ok(true) => reply.with("deleted", true),
+                            Ok(false) => reply,
+                            Err(e) => {
+                                reply.error(errno(&e));
+                                return;
+                            }
+                        }
+                    }
+                    None => reply,
+                };
+                let buffered = fh
+                    .and_then(|fh| self.handles.get(&fh))
+                    .and_then(|h| h.buffer.as_ref())
+                    .map(|b| b.len() as u64);
+                match self.get_kv_attr(&key) {
+                    Ok(Some(mut attr)) => {
+                        if let Some(len) = buffered {
+                            attr.size = len;
+                        }
+                        reply.attr(&self.attr_ttl(attr.ino.0), &attr);
+                    }
+                    Ok(None) => reply.error(Errno::ENOENT),
+                    Err(e) => reply.error(errno(&e)),
+                }
+            }
+            _ => reply.error(Errno::EPERM),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request, ino: u64, reply: ReplyStatfs) {
+        let _timer = self.time("statfs");
+        self.check_reloaded();
+        log::debug!("statfs on inode {}", ino);
+        let usage = match self.quota_usage() {
+            Ok(v) => v,
+            Err(e) => {
+                reply.error(errno(&e));
+                return;
+            }
+        };
+        let (mut blocks, mut bfree, mut files, mut ffree) = (0, 0, 0, 0);
+        // Without both quotas, report the backend's own capacity. There's no
+        // limit on keys short of memory, so none are free.
+        if usage.len() < 2 {
+            match self.driver.usage() {
+                Ok(u) => {
+                    if let (Some(used), Some(limit)) = (u.memory, u.memory_limit) {
+                        blocks = limit / STATFS_BLOCK_SIZE;
+                        bfree = limit.saturating_sub(used) / STATFS_BLOCK_SIZE;
+                    }
+                    files = u.keys;
+                }
+                Err(e) => log::debug!("Not reporting backend usage: {}", e),
+            }
+        }
+        for u in usage {
+            match u.name {
+                "memory" => {
+                    blocks = u.limit / STATFS_BLOCK_SIZE;
+                    bfree = u.limit.saturating_sub(u.used) / STATFS_BLOCK_SIZE;
+                }
+                _ => {
+                    files = u.limit;
+                    ffree = u.limit.saturating_sub(u.used);
+                }
+            }
+        }
+        reply.statfs(
+            blocks,
+            bfree,
+            bfree,
+            files,
+            ffree,
+            STATFS_BLOCK_SIZE as u32,
+            255,
+            STATFS_BLOCK_SIZE as u32,
+        );
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let _timer = self.time("setxattr");
+        self.check_reloaded();
+        log::debug!("setxattr {:?} on inode {}", name, ino);
+        let key = self.kv_keys_by_ino.get(&ino).cloned();
+        let reply = self
+            .audited(req, "setxattr", self.path_of(ino), key, reply)
+            .with("name", name.to_string_lossy())
+            .with("value", String::from_utf8_lossy(value));
+        reject_unless_writable!(self, reply);
+        reject_unless_allowed!(self, req, self.path_of(ino), reply);
+        let name = name.to_string_lossy();
+        if name == BLOCKING_TIMEOUT_XATTR {
+            match String::from_utf8_lossy(value).trim().parse::<u64>() {
+                Ok(secs) => {
+                    self.blocking_timeouts_by_ino.insert(ino, secs);
+                    reply.ok();
+                }
+                Err(_) => reply.error(Errno::EINVAL),
+            }
+        } else if let (SNAPSHOT_XATTR, Some(_)) = (name.as_ref(), self.kv_keys_by_ino.get(&ino)) {
+            match String::from_utf8_lossy(value).trim() {
+                "1" => {
+                    self.snapshot_inos.insert(ino);
+                    reply.ok();
+                }
+                "0" => {
+                    self.snapshot_inos.remove(&ino);
+                    reply.ok();
+                }
+                _ => reply.error(Errno::EINVAL),
+            }
+        } else if let (READ_LOCK_XATTR, Some(_)) = (name.as_ref(), self.kv_keys_by_ino.get(&ino)) {
+            match String::from_utf8_lossy(value).trim() {
+                "1" => {
+                    self.read_lock_inos.insert(ino);
+                    reply.ok();
+                }
+                "0" => {
+                    self.read_lock_inos.remove(&ino);
+                    reply.ok();
+                }
+                _ => reply.error(Errno::EINVAL),
+            }
+        } else if name == OPERATIONS_XATTR || (name == QUOTA_XATTR && ino == 1) {
+            reply.error(Errno::EPERM);
+        } else if name == TTL_XATTR && !self.caps.ttl && self.kv_keys_by_ino.contains_key(&ino) {
+            reply.error(Errno::ENOTSUP);
+        } else if let (TTL_XATTR, Some(key)) = (name.as_ref(), self.kv_keys_by_ino.get(&ino)) {
+            // EXPIRE with a TTL of 0 deletes the key, which is what rm is for.
+            let secs = match String::from_utf8_lossy(value).trim().parse::<u64>() {
+                Ok(v) if v > 0 => v,
+                _ => {
+                    reply.error(Errno::EINVAL);
+                    return;
+                }
+            };
+            let key = key.clone();
+            // A held write landing after the EXPIRE would clear the TTL again.
+            if let Err(e) = self.coalescer.flush(&key) {
+                reply.error(errno(&e));
+                return;
+            }
+            // A TTL of 0 deletes the key.
+            self.cache.forget(&key);
+            match self.driver.expire(&key, Some(Duration::from_secs(secs))) {
+                Ok(true) => reply.ok(),
+                Ok(false) => reply.error(Errno::ENOENT),
+                Err(e) => reply.error(errno(&e)),
+            }
+        } else if let (TYPE_XATTR, Some(_))
+        | (ENCODING_XATTR, Some(_))
+        | (CODEC_XATTR, Some(_))
+        | (MEMORY_XATTR, Some(_)) = (name.as_ref(), self.kv_keys_by_ino.get(&ino))
+        {
+            reply.error(Errno::EPERM);
+        } else if let (Some(tag), Some(key)) = (
+            name.strip_prefix(TAG_XATTR_PREFIX),
+            self.kv_keys_by_ino.get(&ino).cloned(),
+        ) {
+            match self.driver.tag(&key, tag) {
+                Ok(()) => {
+                    self.cache.forget(&key);
+                    reply.ok();
+                }
+                Err(e) => reply.error(errno(&e)),
+            }
+        } else {
+            reply.error(Errno::ENOTSUP);
+        }
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let _timer = self.time("getxattr");
+        self.check_reloaded();
+        log::debug!("getxattr {:?} on inode {}", name, ino);
+        let name = name.to_string_lossy();
+        if name == BLOCKING_TIMEOUT_XATTR {
+            let secs = match self.blocking_timeout(ino) {
+                Some(v) => v.as_secs(),
+                None => 0,
+            };
+            reply_xattr(reply, size, secs.to_string().as_bytes());
+        } else if let (SNAPSHOT_XATTR, Some(_)) = (name.as_ref(), self.kv_keys_by_ino.get(&ino)) {
+            let snapshot = self.snapshot_inos.contains(&ino) as u8;
+            reply_xattr(reply, size, snapshot.to_string().as_bytes());
+        } else if let (READ_LOCK_XATTR, Some(_)) = (name.as_ref(), self.kv_keys_by_ino.get(&ino)) {
+            let read_lock = self.read_lock_inos.contains(&ino) as u8;
+            reply_xattr(reply, size, read_lock.to_string().as_bytes());
+        } else if name == OPERATIONS_XATTR {
+            reply_xattr(reply, size, self.operations(ino).join(",").as_bytes());
+        } else if name == QUOTA_XATTR && ino == 1 && self.config.quota.is_some() {
+            let warn_percent = self.config.quota.as_ref().unwrap().warn_percent;
+            match self.quota_usage() {
+                Ok(usage) => {
+                    let lines: String = usage
+                        .iter()
+                        .map(|u| {
+                            format!(
+                                "{} {} {} {}\n",
+                                u.name,
+                                u.used,
+                                u.limit,
+                                u.state(warn_percent)
+                            )
+                        })
+                        .collect();
+                    reply_xattr(reply, size, lines.as_bytes());
+                }
+                Err(e) => reply.error(errno(&e)),
+            }
+        } else if let (SHA256_XATTR, Some(key)) = (name.as_ref(), self.kv_keys_by_ino.get(&ino)) {
+            match self.sha256_of(&key.clone()) {
+                Ok(Some(v)) => reply_xattr(reply, size, v.as_bytes()),
+                Ok(None) => reply.error(Errno::ENOENT),
+                Err(e) => reply.error(errno(&e)),
+            }
+        } else if let (LAST_REJECT_XATTR, Some(key)) =
+            (name.as_ref(), self.kv_keys_by_ino.get(&ino))
+        {
+            match self.last_rejects.get(key) {
+                Some(reason) => reply_xattr(reply, size, reason.as_bytes()),
+                None => reply.error(Errno::ENODATA),
+            }
+        } else if let (CODEC_XATTR, Some(key)) = (name.as_ref(), self.kv_keys_by_ino.get(&ino)) {
+            match self.codec_of(key) {
+                Ok(Some(codec)) => reply_xattr(reply, size, codec.name().as_bytes()),
+                Ok(None) => reply.error(Errno::ENODATA),
+                Err(e) => reply.error(errno(&e)),
+            }
+        } else if name == TTL_XATTR && !self.caps.ttl && self.kv_keys_by_ino.contains_key(&ino) {
+            reply.error(Errno::ENOTSUP);
+        } else if let (TTL_XATTR, Some(key))
+        | (TYPE_XATTR, Some(key))
+        | (ENCODING_XATTR, Some(key))
+        | (MEMORY_XATTR, Some(key)) =
+            (name.as_ref(), self.kv_keys_by_ino.get(&ino).cloned())
+        {
+            let info = match self.key_info(&key) {
+                Ok(Some(v)) => v,
+                Ok(None) => {
+                    reply.error(Errno::ENOENT);
+                    return;
+                }
+                Err(e) => {
+                    reply.error(errno(&e));
+                    return;
+                }
+            };
+            let value = match name.as_ref() {
+                TTL_XATTR => match info.ttl {
+                    Some(secs) => secs.to_string(),
+                    None => {
+                        reply.error(Errno::ENODATA);
+                        return;
+                    }
+                },
+                MEMORY_XATTR => match info.memory {
+                    Some(bytes) => bytes.to_string(),
+                    None => {
+                        reply.error(Errno::ENODATA);
+                        return;
+                    }
+                },
+                TYPE_XATTR => info.kind,
+                _ => info.encoding,
+            };
+            reply_xattr(reply, size, value.as_bytes());
+        } else if let (Some(tag), Some(key)) = (
+            name.strip_prefix(TAG_XATTR_PREFIX),
+            self.kv_keys_by_ino.get(&ino),
+        ) {
+            // Tags have no value, they're either there or they aren't.
+            match self.driver.tags_of(key) {
+                Ok(tags) if tags.iter().any(|t| t == tag) => reply_xattr(reply, size, b""),
+                Ok(_) => reply.error(Errno::ENODATA),
+                Err(e) => reply.error(errno(&e)),
+            }
+        } else {
+            reply.error(Errno::ENODATA);
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        let _timer = self.time("listxattr");
+        self.check_reloaded();
+        log::debug!("listxattr on inode {}", ino);
+        let mut names = vec![
+            BLOCKING_TIMEOUT_XATTR.to_string(),
+            OPERATIONS_XATTR.to_string(),
+        ];
+        if ino == 1 && self.config.quota.is_some() {
+            names.push(QUOTA_XATTR.to_string());
+        }
+        if let Some(key) = self.kv_keys_by_ino.get(&ino).cloned() {
+            names.push(SHA256_XATTR.to_string());
+            names.push(SNAPSHOT_XATTR.to_string());
+            names.push(READ_LOCK_XATTR.to_string());
+            if self.last_rejects.contains_key(&key) {
+                names.push(LAST_REJECT_XATTR.to_string());
+            }
+            match self.codec_of(&key) {
+                Ok(Some(_)) => names.push(CODEC_XATTR.to_string()),
+                Ok(None) => {}
+                Err(e) => log::debug!("Not listing codec of {}: {}", key, e),
+            }
+            match self.key_info(&key) {
+                Ok(Some(info)) => {
+                    if info.ttl.is_some() {
+                        names.push(TTL_XATTR.to_string());
+                    }
+                    if info.memory.is_some() {
+                        names.push(MEMORY_XATTR.to_string());
+                    }
+                    names.push(TYPE_XATTR.to_string());
+                    names.push(ENCODING_XATTR.to_string());
+                }
+                Ok(None) => {}
+                Err(e) => log::debug!("Not listing metadata of {}: {}", key, e),
+            }
+            match self.driver.tags_of(&key) {
+                Ok(tags) => names.extend(tags.iter().map(|t| format!("{}{}", TAG_XATTR_PREFIX, t))),
+                Err(e) => log::debug!("Not listing tags of {}: {}", key, e),
+            }
+        }
+        let mut data = vec![];
+        for name in names {
+            data.extend_from_slice(name.as_bytes());
+            data.push(0);
+        }
+        reply_xattr(reply, size, &data);
+    }
+
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _timer = self.time("removexattr");
+        self.check_reloaded();
+        log::debug!("removexattr {:?} on inode {}", name, ino);
+        let key = self.kv_keys_by_ino.get(&ino).cloned();
+        let reply = self
+            .audited(req, "removexattr", self.path_of(ino), key, reply)
+            .with("name", name.to_string_lossy());
+        reject_unless_writable!(self, reply);
+        reject_unless_allowed!(self, req, self.path_of(ino), reply);
+        let name = name.to_string_lossy();
+        if name == BLOCKING_TIMEOUT_XATTR {
+            match self.blocking_timeouts_by_ino.remove(&ino) {
+                Some(_) => reply.ok(),
+                None => reply.error(Errno::ENODATA),
+            }
+        } else if let (SNAPSHOT_XATTR, Some(_)) = (name.as_ref(), self.kv_keys_by_ino.get(&ino)) {
+            match self.snapshot_inos.remove(&ino) {
+                true => reply.ok(),
+                false => reply.error(Errno::ENODATA),
+            }
+        } else if let (READ_LOCK_XATTR, Some(_)) = (name.as_ref(), self.kv_keys_by_ino.get(&ino)) {
+            match self.read_lock_inos.remove(&ino) {
+                true => reply.ok(),
+                false => reply.error(Errno::ENODATA),
+            }
+        } else if name == OPERATIONS_XATTR || (name == QUOTA_XATTR && ino == 1) {
+            reply.error(Errno::EPERM);
+        } else if name == TTL_XATTR && !self.caps.ttl && self.kv_keys_by_ino.contains_key(&ino) {
+            reply.error(Errno::ENOTSUP);
+        } else if let (TTL_XATTR, Some(key)) = (name.as_ref(), self.kv_keys_by_ino.get(&ino)) {
+            self.cache.forget(key);
+            match self.driver.expire(key, None) {
+                Ok(true) => reply.ok(),
+                Ok(false) => reply.error(Errno::ENODATA),
+                Err(e) => reply.error(errno(&e)),
+            }
+        } else if let (TYPE_XATTR, Some(_))
+        | (ENCODING_XATTR, Some(_))
+        | (CODEC_XATTR, Some(_))
+        | (MEMORY_XATTR, Some(_)) = (name.as_ref(), self.kv_keys_by_ino.get(&ino))
+        {
+            reply.error(Errno::EPERM);
+        } else if let (Some(tag), Some(key)) = (
+            name.strip_prefix(TAG_XATTR_PREFIX),
+            self.kv_keys_by_ino.get(&ino).cloned(),
+        ) {
+            match self.driver.untag(&key, tag) {
+                Ok(()) => {
+                    self.cache.forget(&key);
+                    reply.ok();
+                }
+                Err(e) => reply.error(errno(&e)),
+            }
+        } else {
+            reply.error(Errno::ENOTSUP);
+        }
+    }
+
+    fn fallocate(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        let _timer = self.time("fallocate");
+        self.check_reloaded();
+        log::debug!(
+            "fallocate inode {} from {} for {} bytes with mode {} via filehandle {}",
+            ino,
+            offset,
+            length,
+            mode,
+            fh
+        );
+        let key = self.kv_keys_by_ino.get(&ino).cloned();
+        let reply = self
+            .audited(req, "fallocate", self.path_of(ino), key, reply)
+            .with("size", offset + length);
+        reject_unless_writable!(self, reply);
+        reject_unless_allowed!(self, req, self.path_of(ino), reply);
+        reject_unless_acl_allows!(self, req, acl_command("SET", self.path_of(ino)), reply);
+        let key = match self.kv_keys_by_ino.get(&ino) {
+            Some(v) => v.clone(),
+            None => {
+                reply.error(Errno::EOPNOTSUPP);
+                return;
+            }
+        };
+        // Values can't have space reserved without also growing them, so
+        // there's nothing to do if the size has to stay the same.
+        if mode == FALLOC_FL_KEEP_SIZE {
+            reply.ok();
+            return;
+        } else if mode != 0 {
+            reply.error(Errno::EOPNOTSUPP);
+            return;
+        }
+        match self.driver.preallocate(&key, (offset + length) as u64) {
+            Ok(()) => {
+                self.cache.forget(&key);
+                self.hooks.fire(HookOp::Modify, &key);
+                reply.ok()
+            }
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let _timer = self.time("mkdir");
+        self.check_reloaded();
+        log::debug!("mkdir {:?} under parent {}", name, parent);
+        reject_unless_writable!(self, reply);
+        reject_unless_allowed!(self, req, self.child_path(parent, name), reply);
+        // /txn/<session> opens a transaction, and /publish/<batch> a batch.
+        if let Some(session) = txn_path(parent, &name.to_string_lossy()) {
+            if self.txns.contains_key(&session) {
+                reply.error(Errno::EEXIST);
+                return;
+            }
+            let txn = Txn {
+                writes: BTreeMap::new(),
+                publish: parent == PUBLISH_DIR,
+            };
+            self.txns.insert(session.clone(), txn);
+            match self.get_txn_attr(&session) {
+                Some(attr) => reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0)),
+                None => reply.error(Errno::ENOENT),
+            }
+            return;
+        }
+        if let Some(namespace) = self
+            .kv_key_under(parent, &name.to_string_lossy())
+            .filter(|_| self.config.separator.is_some())
+        {
+            self.make_namespace(namespace, reply);
+            return;
+        }
+        let lock = match self.lock_child_name(parent, &name.to_string_lossy()) {
+            Some(v) => v,
+            None => {
+                reply.error(Errno::EPERM);
+                return;
+            }
+        };
+        match self.get_lock_attr(&lock, None) {
+            Ok(Some(_)) => {
+                reply.error(Errno::EEXIST);
+                return;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                reply.error(errno(&e));
+                return;
+            }
+        };
+        self.lock_dirs.insert(lock.clone());
+        match self.get_lock_attr(&lock, None) {
+            Ok(Some(attr)) => reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0)),
+            Ok(None) => reply.error(Errno::ENOENT),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _timer = self.time("rmdir");
+        self.check_reloaded();
+        log::debug!("rmdir {:?} under parent {}", name, parent);
+        let reply = self.audited(req, "rmdir", self.child_path(parent, name), None, reply);
+        reject_unless_writable!(self, reply);
+        reject_unless_allowed!(self, req, self.child_path(parent, name), reply);
+        // Removing /txn/<session> or /publish/<batch> discards whatever it
+        // still has queued.
+        if let Some(session) = txn_path(parent, &name.to_string_lossy()) {
+            match self.txns.remove(&session) {
+                Some(txn) => {
+                    log::debug!("Discarded {} writes in {}", txn.writes.len(), session);
+                    reply.ok();
+                }
+                None => reply.error(Errno::ENOENT),
+            }
+            return;
+        }
+        // Removing /kv/.match/<pattern> deletes the keys unlinked from it, as
+        // rm -r does with those it listed, and none that only match it since.
+        if parent == KV_MATCH {
+            let pattern = name.to_string_lossy().to_string();
+            let mut keys: Vec<String> = self
+                .staged_deletes
+                .get(&pattern)
+                .map(|s| s.iter().cloned().collect())
+                .unwrap_or_default();
+            // Nothing was unlinked, so it's only empty if nothing matches.
+            if keys.is_empty() {
+                match self.driver.match_keys(&pattern, 1) {
+                    Ok(v) if v.is_empty() => reply.ok(),
+                    Ok(_) => reply.error(Errno::ENOTEMPTY),
+                    Err(e) => reply.error(errno(&e)),
+                }
+                return;
+            }
+            if keys.len() as u64 > self.config.bulk_delete_threshold
+                && !self.confirmed_deletes.remove(&pattern)
+            {
+                self.refuse_bulk_delete(&pattern);
+                reply.error(Errno::EPERM);
+                return;
+            }
+            keys.sort();
+            let reply = reply.with("keys", keys.len());
+            let mut deleted = 0;
+            for batch in keys.chunks(BULK_DELETE_BATCH) {
+                match self.driver.delete(batch) {
+                    Ok(n) => {
+                        deleted += n;
+                        for key in batch {
+                            self.cache.forget(key);
+                            self.hooks.fire(HookOp::Delete, key);
+                        }
+                    }
+                    Err(e) => {
+                        reply.error(errno(&e));
+                        return;
+                    }
+                }
+            }
+            log::info!("Deleted {} keys matching {}.", deleted, pattern);
+            self.staged_deletes.remove(&pattern);
+            reply.ok();
+            return;
+        }
+        if let Some(namespace) = self
+            .kv_key_under(parent, &name.to_string_lossy())
+            .filter(|_| self.config.separator.is_some())
+        {
+            self.remove_namespace(&namespace, reply);
+            return;
+        }
+        let lock = match self.lock_child_name(parent, &name.to_string_lossy()) {
+            Some(v) => v,
+            None => {
+                reply.error(Errno::EPERM);
+                return;
+            }
+        };
+        let prefix = format!("{}/", lock);
+        match self.driver.list_locks(&prefix) {
+            Ok(v) if !v.is_empty() => {
+                reply.error(Errno::ENOTEMPTY);
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                reply.error(errno(&e));
+                return;
+            }
+        };
+        if self.lock_dirs.iter().any(|d| d.starts_with(&prefix)) {
+            reply.error(Errno::ENOTEMPTY);
+        } else if self.lock_dirs.remove(&lock) {
+            reply.ok();
+        } else {
+            reply.error(Errno::ENOENT);
+        }
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let _timer = self.time("create");
+        self.check_reloaded();
+        log::debug!("create {:?} under parent {}", name, parent);
+        let key = self.kv_key_under(parent, &name.to_string_lossy());
+        let reply = self.audited(req, "create", self.child_path(parent, name), key, reply);
+        reject_unless_writable!(self, reply);
+        reject_unless_allowed!(self, req, self.child_path(parent, name), reply);
+        reject_unless_acl_allows!(
+            self,
+            req,
+            acl_command("SET", self.child_path(parent, name)),
+            reply
+        );
+        let key = self.kv_key_under(parent, &name.to_string_lossy());
+        if let Err(e) = self.check_open_limits(key.as_deref()) {
+            reply.error(e);
+            return;
+        }
+        // Creating a file in a /txn session or /publish batch queues setting
+        // its key empty.
+        if let TXN_START..=TXN_END = parent {
+            let session = self.txns_by_ino.get(&parent).cloned().unwrap_or_default();
+            let key = name.to_string_lossy().to_string();
+            if self.is_txn_control(&session, &key) {
+                reply.error(Errno::EEXIST);
+                return;
+            }
+            if let Err(e) = self.queue_txn_write(&session, &key, Some(vec![])) {
+                reply.error(e);
+                return;
+            }
+            let ino = txn_key_ino(&session, &key);
+            self.txn_keys_by_ino
+                .insert(ino, (session.clone(), key.clone()));
+            let fh = self.new_handle(ino, true, Encoding::Plain, Some(vec![]));
+            let attr = self.get_attr(FileType::RegularFile, ino, 0);
+            reply.created(
+                &self.entry_ttl(attr.ino.0),
+                &attr,
+                Generation(0),
+                fuser::FileHandle(fh),
+                FopenFlags::FOPEN_DIRECT_IO,
+            );
+            return;
+        }
+        // Creating a file in an object of a JSON document sets its field to an
+        // empty string.
+        if let JSON_START..=JSON_END = parent {
+            let child = self.json_child(parent, &name.to_string_lossy());
+            let (key, path) = match child {
+                Ok(Some(v)) => v,
+                Ok(None) => {
+                    reply.error(Errno::EPERM);
+                    return;
+                }
+                Err(e) => {
+                    reply.error(errno(&e));
+                    return;
+                }
+            };
+            let attr = self
+                .write_json(&key, &path, b"")
+                .and_then(|()| self.get_json_attr(&key, &path));
+            match attr {
+                Ok(Some(attr)) => {
+                    let fh = self.new_handle(attr.ino.0, true, Encoding::Plain, Some(vec![]));
+                    reply.created(
+                        &self.entry_ttl(attr.ino.0),
+                        &attr,
+                        Generation(0),
+                        fuser::FileHandle(fh),
+                        FopenFlags::FOPEN_DIRECT_IO,
+                    );
+                }
+                Ok(None) => reply.error(Errno::ENOENT),
+                Err(e) => reply.error(errno(&e)),
+            }
+            return;
+        }
+        // /kv and /kv namespaces
+        if let Some(key) = self.kv_key_under(parent, &name.to_string_lossy()) {
+            if let Err(e) = self.coalescer.write(&key, vec![]) {
+                reply.error(errno(&e));
+                return;
+            }
+            self.cache.forget(&key);
+            self.hooks.fire(HookOp::Create, &key);
+            let ino = self.key_ino(&key);
+            self.kv_keys_by_ino.insert(ino, key.clone());
+            let fh = self.new_handle(ino, false, Encoding::Plain, Some(vec![]));
+            // The key only exists while the file is open if nothing is written
+            // to it.
+            if self.config.empty_file == EmptyFile::Delete {
+                if let Some(handle) = self.handles.get_mut(&fh) {
+                    handle.dirty = true;
+                }
+            }
+            let attr = self.get_attr(FileType::RegularFile, ino, 0);
+            reply.created(
+                &self.entry_ttl(attr.ino.0),
+                &attr,
+                Generation(0),
+                fuser::FileHandle(fh),
+                FopenFlags::empty(),
+            );
+            return;
+        }
+        let lock = match self.lock_child_name(parent, &name.to_string_lossy()) {
+            Some(v) => v,
+            None => {
+                reply.error(Errno::EPERM);
+                return;
+            }
+        };
+        if self.lock_dirs.contains(&lock) {
+            reply.error(Errno::EISDIR);
+            return;
+        }
+        let owner = lock_owner(req);
+        match self
+            .driver
+            .acquire_lock(&lock, &owner, self.config.lock_mode, self.lock_ttl())
+        {
+            Ok(LockOutcome::Acquired) => {
+                log::debug!("Acquired lock {} for {}", lock, owner);
+            }
+            Ok(LockOutcome::Held) => {
+                reply.error(Errno::EEXIST);
+                return;
+            }
+            Ok(LockOutcome::Blocked) => {
+                reply.error(Errno::EBUSY);
+                return;
+            }
+            Err(e) => {
+                log::error!("Error acquiring lock {}: {}", lock, e);
+                reply.error(errno(&e));
+                return;
+            }
+        };
+        match self.get_lock_attr(&lock, None) {
+            Ok(Some(attr)) => reply.created(
+                &self.entry_ttl(attr.ino.0),
+                &attr,
+                Generation(0),
+                fuser::FileHandle(0),
+                FopenFlags::empty(),
+            ),
+            // Released or expired already.
+            _ => reply.error(Errno::ENOENT),
+        }
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _timer = self.time("unlink");
+        self.check_reloaded();
+        log::debug!("unlink {:?} under parent {}", name, parent);
+        let key = self.kv_key_under(parent, &name.to_string_lossy());
+        let reply = self.audited(req, "unlink", self.child_path(parent, name), key, reply);
+        reject_unless_writable!(self, reply);
+        reject_unless_allowed!(self, req, self.child_path(parent, name), reply);
+        reject_unless_acl_allows!(
+            self,
+            req,
+            acl_command("DEL", self.child_path(parent, name)),
+            reply
+        );
+        // Removing a key from /tags/<tag> only untags it.
+        if let TAGS_START..=TAGS_END = parent {
+            let key = name.to_string_lossy();
+            let result = match self.tags_by_ino.get(&parent) {
+                Some(tag) => self.driver.untag(&key, tag),
+                None => {
+                    reply.error(Errno::ENOENT);
+                    return;
+                }
+            };
+            match result {
+                Ok(()) => {
+                    self.cache.forget(&key);
+                    reply.ok();
+                }
+                Err(e) => reply.error(errno(&e)),
+            }
+            return;
+        }
+        // Removing a file from a JSON document deletes its value.
+        if let JSON_START..=JSON_END = parent {
+            let result = self
+                .json_child(parent, &name.to_string_lossy())
+                .and_then(|child| match child {
+                    Some((key, path)) => {
+                        let deleted = self.driver.json_del(&key, &path)?;
+                        if deleted > 0 {
+                            self.hooks.fire(HookOp::Modify, &key);
+                        }
+                        Ok(deleted)
+                    }
+                    None => Ok(0),
+                });
+            match result {
+                Ok(0) => reply.error(Errno::ENOENT),
+                Ok(_) => reply.ok(),
+                Err(e) => reply.error(errno(&e)),
+            }
+            return;
+        }
+        // Removing a file from a /txn session queues deleting its key, while
+        // removing one from a /publish batch only takes it out. rm -r removes
+        // the control file too, before discarding the session.
+        if let TXN_START..=TXN_END = parent {
+            let session = self.txns_by_ino.get(&parent).cloned().unwrap_or_default();
+            let key = name.to_string_lossy().to_string();
+            let publish = self.txns.get(&session).is_some_and(|txn| txn.publish);
+            let queued = if self.is_txn_control(&session, &key) {
+                Ok(())
+            } else if publish {
+                let txn = self.txns.get_mut(&session);
+                txn.and_then(|txn| txn.writes.remove(&key))
+                    .map(|_| ())
+                    .ok_or(Errno::ENOENT)
+            } else {
+                self.queue_txn_write(&session, &key, None)
+            };
+            match queued {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e),
+            }
+            return;
+        }
+        // Removing a key from /kv deletes it. Writes to it still held back by
+        // coalescing are dropped, so they can't bring it back.
+        if let Some(key) = self.kv_key_under(parent, &name.to_string_lossy()) {
+            let discarded = self.coalescer.discard(&key);
+            match self.driver.delete(std::slice::from_ref(&key)) {
+                Ok(n) if n > 0 || discarded => {
+                    self.kv_keys_by_ino.retain(|_, k| *k != key);
+                    self.checksums.pop(&key);
+                    self.key_hits.pop(&key);
+                    self.cache.forget(&key);
+                    self.hooks.fire(HookOp::Delete, &key);
+                    reply.ok();
+                }
+                Ok(_) => reply.error(Errno::ENOENT),
+                Err(e) => reply.error(errno(&e)),
+            }
+            return;
+        }
+        // Removing a key from /kv/.match/<pattern> only stages it, so that
+        // removing the directory afterwards deletes everything in batches.
+        if let MATCH_START..=MATCH_END = parent {
+            let pattern = match self.patterns_by_ino.get(&parent) {
+                Some(v) => v.clone(),
+                None => {
+                    reply.error(Errno::ENOENT);
+                    return;
+                }
+            };
+            // Fail early so rm -r doesn't get as far as the rmdir.
+            if !self.staged_deletes.contains_key(&pattern)
+                && !self.confirmed_deletes.contains(&pattern)
+            {
+                let limit = self.config.bulk_delete_threshold as i64 + 1;
+                match self.driver.match_keys(&pattern, limit) {
+                    Ok(v) if v.len() as i64 == limit => {
+                        self.refuse_bulk_delete(&pattern);
+                        reply.error(Errno::EPERM);
+                        return;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        reply.error(errno(&e));
+                        return;
+                    }
+                }
+            }
+            self.staged_deletes
+                .entry(pattern)
+                .or_default()
+                .insert(name.to_string_lossy().to_string());
+            reply.ok();
+            return;
+        }
+        let lock = match self.lock_child_name(parent, &name.to_string_lossy()) {
+            Some(v) => v,
+            None => {
+                reply.error(Errno::EPERM);
+                return;
+            }
+        };
+        match self.driver.release_lock(&lock, &lock_owner(req)) {
+            Ok(true) => reply.ok(),
+            // Either nobody holds it, or someone else does.
+            Ok(false) => match self.driver.lock_owner(&lock) {
+                Ok(Some(_)) => reply.error(Errno::EPERM),
+                Ok(None) => reply.error(Errno::ENOENT),
+                Err(e) => reply.error(errno(&e)),
+            },
+            Err(e) => {
+                log::error!("Error releasing lock {}: {}", lock, e);
+                reply.error(errno(&e));
+            }
+        }
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let _timer = self.time("rename");
+        self.check_reloaded();
+        log::debug!(
+            "rename {:?} under parent {} to {:?} under parent {}",
+            name,
+            parent,
+            newname,
+            newparent
+        );
+        let reply = self
+            .audited(req, "rename", self.child_path(parent, name), None, reply)
+            .with("to", self.child_path(newparent, newname));
+        reject_unless_writable!(self, reply);
+        reject_unless_allowed!(self, req, self.child_path(parent, name), reply);
+        reject_unless_allowed!(self, req, self.child_path(newparent, newname), reply);
+        // A rename deletes the old key and creates the new one.
+        reject_unless_acl_allows!(
+            self,
+            req,
+            acl_command("DEL", self.child_path(parent, name)),
+            reply
+        );
+        reject_unless_acl_allows!(
+            self,
+            req,
+            acl_command("SET", self.child_path(newparent, newname)),
+            reply
+        );
+        if flags & RENAME_EXCHANGE != 0 {
+            reply.error(Errno::EINVAL);
+            return;
+        }
+        let kind = match (value_kind(parent), value_kind(newparent)) {
+            (Some(from), Some(to)) if from == to => None,
+            (Some(_), Some(to)) => Some(to),
+            (None, None) if parent == newparent => {
+                reply.error(Errno::EPERM);
+                return;
+            }
+            // Only values can move between subtrees.
+            _ => {
+                log::debug!("Can't rename between parents {} and {}", parent, newparent);
+                reply.error(Errno::EXDEV);
+                return;
+            }
+        };
+        // Queues are named for their keys.
+        let key_under = |parent: u64, name: &OsStr| match parent {
+            QUEUE_DIR => Some(name.to_string_lossy().to_string()),
+            _ => self.kv_key_under(parent, &name.to_string_lossy()),
+        };
+        let (from, to) = match (key_under(parent, name), key_under(newparent, newname)) {
+            (Some(from), Some(to)) => (from, to),
+            _ => {
+                reply.error(Errno::ENOENT);
+                return;
+            }
+        };
+        // Without a way to move values on the backend, mv copies and unlinks
+        // them instead.
+        if !self.caps.copy {
+            reply.error(Errno::EXDEV);
+            return;
+        }
+        let reply = reply.with("key", from.as_str()).with("to_key", to.as_str());
+        match self
+            .driver
+            .rename(&from, &to, kind, flags & RENAME_NOREPLACE == 0)
+        {
+            Ok(RenameOutcome::Renamed) => {
+                self.cache.forget(&from);
+                self.cache.forget(&to);
+                self.hooks.fire(HookOp::Delete, &from);
+                self.hooks.fire(HookOp::Create, &to);
+                if self.config.rename_redirect > 0 {
+                    let ttl = Duration::from_secs(self.config.rename_redirect);
+                    if let Err(e) = self.driver.leave_redirect(&from, &to, ttl) {
+                        log::warn!("Error leaving a redirect from {} to {}: {}", from, to, e);
+                    }
+                }
+                self.kv_keys_by_ino.retain(|_, k| *k != from && *k != to);
+                let ino = self.key_ino(&to);
+                self.kv_keys_by_ino.insert(ino, to);
+                reply.ok();
+            }
+            Ok(RenameOutcome::Missing) => reply.error(Errno::ENOENT),
+            Ok(RenameOutcome::Exists) => reply.error(Errno::EEXIST),
+            Ok(RenameOutcome::Unconvertible) => {
+                log::debug!("Can't convert {} to {:?}", from, kind);
+                reply.error(Errno::EXDEV);
+            }
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
+}
+
+// KVFS shared between the session's threads, each request taking the lock for
+// as long as KVFS takes to answer it. Reads of values are handed off to
+// readers rather than answered under the lock, so parallel readers only
+// contend on the bookkeeping either side of them.
+pub struct SharedKVFS {
+    kvfs: Mutex<KVFS>,
+}
+
+impl SharedKVFS {
+    pub fn new(kvfs: KVFS) -> SharedKVFS {
+        SharedKVFS {
+            kvfs: Mutex::new(kvfs),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, KVFS> {
+        self.kvfs.lock().unwrap()
+    }
+}
+
+impl Filesystem for SharedKVFS {
+    fn destroy(&mut self) {
+        self.kvfs.get_mut().unwrap().destroy();
+    }
+
+    fn lookup(&self, req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+        self.lock().lookup(req, parent.0, name, reply);
+    }
+
+    fn getattr(
+        &self,
+        req: &Request,
+        ino: INodeNo,
+        fh: Option<fuser::FileHandle>,
+        reply: ReplyAttr,
+    ) {
+        self.lock().getattr(req, ino.0, fh.map(|fh| fh.0), reply);
+    }
+
+    fn readlink(&self, req: &Request, ino: INodeNo, reply: ReplyData) {
+        self.lock().readlink(req, ino.0, reply);
+    }
+
+    fn read(
+        &self,
+        req: &Request,
+        ino: INodeNo,
+        fh: fuser::FileHandle,
+        offset: u64,
+        size: u32,
+        flags: OpenFlags,
+        lock_owner: Option<LockOwner>,
+        reply: ReplyData,
+    ) {
+        let lock_owner = lock_owner.map(|o| o.0);
+        self.lock().read(
+            req,
+            ino.0,
+            fh.0,
+            offset as i64,
+            size,
+            flags.0,
+            lock_owner,
+            reply,
+        );
+    }
+
+    fn opendir(&self, req: &Request, ino: INodeNo, flags: OpenFlags, reply: ReplyOpen) {
+        self.lock().opendir(req, ino.0, flags.0, reply);
+    }
+
+    fn releasedir(
+        &self,
+        req: &Request,
+        ino: INodeNo,
+        fh: fuser::FileHandle,
+        flags: OpenFlags,
+        reply: ReplyEmpty,
+    ) {
+        self.lock().releasedir(req, ino.0, fh.0, flags.0, reply);
+    }
+
+    fn readdir(
+        &self,
+        req: &Request,
+        ino: INodeNo,
+        fh: fuser::FileHandle,
+        offset: u64,
+        reply: ReplyDirectory,
+    ) {
+        self.lock().readdir(req, ino.0, fh.0, offset as i64, reply);
+    }
+
+    fn open(&self, req: &Request, ino: INodeNo, flags: OpenFlags, reply: ReplyOpen) {
+        self.lock().open(req, ino.0, flags.0, reply);
+    }
+
+    fn release(
+        &self,
+        req: &Request,
+        ino: INodeNo,
+        fh: fuser::FileHandle,
+        flags: OpenFlags,
+        lock_owner: Option<LockOwner>,
+        flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let lock_owner = lock_owner.map(|o| o.0);
+        self.lock()
+            .release(req, ino.0, fh.0, flags.0, lock_owner, flush, reply);
+    }
+
+    fn flush(
+        &self,
+        req: &Request,
+        ino: INodeNo,
+        fh: fuser::FileHandle,
+        lock_owner: LockOwner,
+        reply: ReplyEmpty,
+    ) {
+        self.lock().flush(req, ino.0, fh.0, lock_owner.0, reply);
+    }
+
+    fn fsync(
+        &self,
+        req: &Request,
+        ino: INodeNo,
+        fh: fuser::FileHandle,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.lock().fsync(req, ino.0, fh.0, datasync, reply);
+    }
+
+    fn write(
+        &self,
+        req: &Request,
+        ino: INodeNo,
+        fh: fuser::FileHandle,
+        offset: u64,
+        data: &[u8],
+        write_flags: WriteFlags,
+        flags: OpenFlags,
+        lock_owner: Option<LockOwner>,
+        reply: ReplyWrite,
+    ) {
+        let lock_owner = lock_owner.map(|o| o.0);
+        self.lock().write(
+            req,
+            ino.0,
+            fh.0,
+            offset as i64,
+            data,
+            write_flags.bits(),
+            flags.0,
+            lock_owner,
+            reply,
+        );
+    }
+
+    fn setattr(
+        &self,
+        req: &Request,
+        ino: INodeNo,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        ctime: Option<SystemTime>,
+        fh: Option<fuser::FileHandle>,
+        crtime: Option<SystemTime>,
+        chgtime: Option<SystemTime>,
+        bkuptime: Option<SystemTime>,
+        flags: Option<BsdFileFlags>,
+        reply: ReplyAttr,
+    ) {
+        self.lock().setattr(
+            req,
+            ino.0,
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+            ctime,
+            fh.map(|fh| fh.0),
+            crtime,
+            chgtime,
+            bkuptime,
+            flags.map(|f| f.bits()),
+            reply,
+        );
+    }
+
+    fn statfs(&self, req: &Request, ino: INodeNo, reply: ReplyStatfs) {
+        self.lock().statfs(req, ino.0, reply);
+    }
+
+    fn setxattr(
+        &self,
+        req: &Request,
+        ino: INodeNo,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        position: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.lock()
+            .setxattr(req, ino.0, name, value, flags, position, reply);
+    }
+
+    fn getxattr(&self, req: &Request, ino: INodeNo, name: &OsStr, size: u32, reply: ReplyXattr) {
+        self.lock().getxattr(req, ino.0, name, size, reply);
+    }
+
+    fn listxattr(&self, req: &Request, ino: INodeNo, size: u32, reply: ReplyXattr) {
+        self.lock().listxattr(req, ino.0, size, reply);
+    }
+
+    fn removexattr(&self, req: &Request, ino: INodeNo, name: &OsStr, reply: ReplyEmpty) {
+        self.lock().removexattr(req, ino.0, name, reply);
+    }
+
+    fn fallocate(
+        &self,
+        req: &Request,
+        ino: INodeNo,
+        fh: fuser::FileHandle,
+        offset: u64,
+        length: u64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.lock()
+            .fallocate(req, ino.0, fh.0, offset as i64, length as i64, mode, reply);
+    }
+
+    fn mkdir(
+        &self,
+        req: &Request,
+        parent: INodeNo,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        self.lock().mkdir(req, parent.0, name, mode, umask, reply);
+    }
+
+    fn rmdir(&self, req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEmpty) {
+        self.lock().rmdir(req, parent.0, name, reply);
+    }
+
+    fn create(
+        &self,
+        req: &Request,
+        parent: INodeNo,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        self.lock()
+            .create(req, parent.0, name, mode, umask, flags, reply);
+    }
+
+    fn unlink(&self, req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEmpty) {
+        self.lock().unlink(req, parent.0, name, reply);
+    }
+
+    fn rename(
+        &self,
+        req: &Request,
+        parent: INodeNo,
+        name: &OsStr,
+        newparent: INodeNo,
+        newname: &OsStr,
+        flags: RenameFlags,
+        reply: ReplyEmpty,
+    ) {
+        self.lock().rename(
+            req,
+            parent.0,
+            name,
+            newparent.0,
+            newname,
+            flags.bits(),
+            reply,
+        );
+    }
+}
+
+impl KVFS {
+    // Initialize all the static dirs based on the KVFS config.
+    // The root dir reserves the first 8192 inodes (13 bits), leaving 51 bits for
+    // remaining keys (~2 quadrillion values).
+    pub fn init_static_dirs(&mut self) {
+        log::debug!("Building static directory list.");
+        let mut root_entries: Vec<DirEntry> = vec![];
+        if !self.config.disable_raw {
+            log::debug!("Setting up /raw, to disable set disable_raw=true.");
+            root_entries.push(curdir!(self, 1));
+            if self.caps.raw {
+                root_entries.push((
+                    2,
+                    FileType::RegularFile,
+                    self.get_attr(FileType::RegularFile, 2, 0),
+                    "raw".to_string(),
+                    None,
+                ));
+            }
+            let help = self.subtree_help("raw", self.caps.raw, "raw commands", RAW_HELP);
+            root_entries.push((
+                3,
+                FileType::RegularFile,
+                self.get_attr(FileType::RegularFile, 3, help.len() as u64),
+                "raw:help".to_string(),
+                Some(help),
+            ));
+        }
+
+        log::debug!("Setting up /lock.");
+        if self.caps.locks {
+            root_entries.push((
+                2048,
+                FileType::Directory,
+                self.get_attr(FileType::Directory, 2048, 0),
+                "lock".to_string(),
+                None,
+            ));
+        }
+        let help = self.subtree_help("lock", self.caps.locks, "locks", LOCK_HELP);
+        root_entries.push((
+            2049,
+            FileType::RegularFile,
+            self.get_attr(FileType::RegularFile, 2049, help.len() as u64),
+            "lock:help".to_string(),
+            Some(help),
+        ));
+
+        log::debug!("Setting up /tags.");
+        root_entries.push((
+            3072,
+            FileType::Directory,
+            self.get_attr(FileType::Directory, 3072, 0),
+            "tags".to_string(),
+            None,
+        ));
+        root_entries.push((
+            3073,
+            FileType::RegularFile,
+            self.get_attr(FileType::RegularFile, 3073, TAGS_HELP.len() as u64),
+            "tags:help".to_string(),
+            Some(TAGS_HELP.to_string()),
+        ));
+
+        log::debug!("Setting up /kv.");
+        root_entries.push((
+            4096,
+            FileType::Directory,
+            self.get_attr(FileType::Directory, 4096, 0),
+            "kv".to_string(),
+            None,
+        ));
+        root_entries.push((
+            4097,
+            FileType::RegularFile,
+            self.get_attr(FileType::RegularFile, 4097, KV_HELP.len() as u64),
+            "kv:help".to_string(),
+            Some(KV_HELP.to_string()),
+        ));
+
+        for (ino, name) in [
+            (KV_RANDOM, "kv:random"),
+            (KV_RANDOM_VALUE, "kv:random:value"),
+            (KV_COUNT, "kv:count"),
+        ] {
+            root_entries.push((
+                ino,
+                FileType::RegularFile,
+                self.get_attr(FileType::RegularFile, ino, 0),
+                name.to_string(),
+                None,
+            ));
+        }
+
+        if self.config.versioning {
+            log::debug!("Setting up /history.");
+            root_entries.push((
+                5120,
+                FileType::Directory,
+                self.get_attr(FileType::Directory, 5120, 0),
+                "history".to_string(),
+                None,
+            ));
+            root_entries.push((
+                5121,
+                FileType::RegularFile,
+                self.get_attr(FileType::RegularFile, 5121, HISTORY_HELP.len() as u64),
+                "history:help".to_string(),
+                Some(HISTORY_HELP.to_string()),
+            ));
+        }
+
+        log::debug!("Setting up /pubsub.");
+        root_entries.push((
+            5632,
+            FileType::Directory,
+            self.get_attr(FileType::Directory, 5632, 0),
+            "pubsub".to_string(),
+            None,
+        ));
+        root_entries.push((
+            5633,
+            FileType::RegularFile,
+            self.get_attr(FileType::RegularFile, 5633, PUBSUB_HELP.len() as u64),
+            "pubsub:help".to_string(),
+            Some(PUBSUB_HELP.to_string()),
+        ));
+
+        log::debug!("Setting up /counter.");
+        root_entries.push((
+            5888,
+            FileType::Directory,
+            self.get_attr(FileType::Directory, 5888, 0),
+            "counter".to_string(),
+            None,
+        ));
+        root_entries.push((
+            5889,
+            FileType::RegularFile,
+            self.get_attr(FileType::RegularFile, 5889, COUNTER_HELP.len() as u64),
+            "counter:help".to_string(),
+            Some(COUNTER_HELP.to_string()),
+        ));
+
+        log::debug!("Setting up /txn.");
+        root_entries.push((
+            TXN_DIR,
+            FileType::Directory,
+            self.get_attr(FileType::Directory, TXN_DIR, 0),
+            "txn".to_string(),
+            None,
+        ));
+        root_entries.push((
+            TXN_DIR + 1,
+            FileType::RegularFile,
+            self.get_attr(FileType::RegularFile, TXN_DIR + 1, TXN_HELP.len() as u64),
+            "txn:help".to_string(),
+            Some(TXN_HELP.to_string()),
+        ));
+
+        log::debug!("Setting up /publish.");
+        root_entries.push((
+            PUBLISH_DIR,
+            FileType::Directory,
+            self.get_attr(FileType::Directory, PUBLISH_DIR, 0),
+            "publish".to_string(),
+            None,
+        ));
+        root_entries.push((
+            PUBLISH_DIR + 1,
+            FileType::RegularFile,
+            self.get_attr(
+                FileType::RegularFile,
+                PUBLISH_DIR + 1,
+                PUBLISH_HELP.len() as u64,
+            ),
+            "publish:help".to_string(),
+            Some(PUBLISH_HELP.to_string()),
+        ));
+
+        log::debug!("Setting up /find.");
+        if self.caps.scan {
+            root_entries.push((
+                FIND_DIR,
+                FileType::Directory,
+                self.get_attr(FileType::Directory, FIND_DIR, 0),
+                "find".to_string(),
+                None,
+            ));
+        }
+        let help = self.subtree_help("find", self.caps.scan, "listing keys by glob", FIND_HELP);
+        root_entries.push((
+            FIND_DIR + 1,
+            FileType::RegularFile,
+            self.get_attr(FileType::RegularFile, FIND_DIR + 1, help.len() as u64),
+            "find:help".to_string(),
+            Some(help),
+        ));
+
+        log::debug!("Setting up /dump.");
+        if self.caps.scan {
+            root_entries.push((
+                DUMP_DIR,
+                FileType::Directory,
+                self.get_attr(FileType::Directory, DUMP_DIR, 0),
+                "dump".to_string(),
+                None,
+            ));
+        }
+        let help = self.subtree_help("dump", self.caps.scan, "listing keys by glob", DUMP_HELP);
+        root_entries.push((
+            DUMP_DIR + 1,
+            FileType::RegularFile,
+            self.get_attr(FileType::RegularFile, DUMP_DIR + 1, help.len() as u64),
+            "dump:help".to_string(),
+            Some(help),
+        ));
+
+        log::debug!("Setting up /queue.");
+        if self.caps.queues {
+            root_entries.push((
+                QUEUE_DIR,
+                FileType::Directory,
+                self.get_attr(FileType::Directory, QUEUE_DIR, 0),
+                "queue".to_string(),
+                None,
+            ));
+        }
+        let help = self.subtree_help("queue", self.caps.queues, "queues", QUEUE_HELP);
+        root_entries.push((
+            QUEUE_DIR + 1,
+            FileType::RegularFile,
+            self.get_attr(FileType::RegularFile, QUEUE_DIR + 1, help.len() as u64),
+            "queue:help".to_string(),
+            Some(help),
+        ));
+
+        let mut mirror_entries: Vec<DirEntry> = vec![];
+        if !self.mirrors.is_empty() {
+            log::debug!("Setting up /mirror.");
+            root_entries.push((
+                MIRROR_DIR,
+                FileType::Directory,
+                self.get_attr(FileType::Directory, MIRROR_DIR, 0),
+                "mirror".to_string(),
+                None,
+            ));
+            root_entries.push((
+                MIRROR_DIR + 1,
+                FileType::RegularFile,
+                self.get_attr(
+                    FileType::RegularFile,
+                    MIRROR_DIR + 1,
+                    MIRROR_HELP.len() as u64,
+                ),
+                "mirror:help".to_string(),
+                Some(MIRROR_HELP.to_string()),
+            ));
+            let names: Vec<String> = self.mirrors.iter().map(|m| m.0.clone()).collect();
+            for (ino, name) in (MIRROR_START..=MIRROR_END).zip(names) {
+                let mut attr = self.get_attr(FileType::Directory, ino, 0);
+                // Mirrors are never writable.
+                attr.perm &= 0o555;
+                mirror_entries.push((ino, FileType::Directory, attr, name, None));
+            }
+        }
+
+        log::debug!("Setting up /.fusekv.");
+        root_entries.push((
+            CONTROL_DIR,
+            FileType::Directory,
+            self.get_attr(FileType::Directory, CONTROL_DIR, 0),
+            ".fusekv".to_string(),
+            None,
+        ));
+        let capabilities = self.capabilities(
+            root_entries
+                .iter()
+                .map(|e| e.3.clone())
+                .filter(|n| n != "." && !n.contains(':'))
+                .collect(),
+        );
+        let control_entries: Vec<DirEntry> = vec![
+            (
+                CONTROL_FREEZE,
+                FileType::RegularFile,
+                self.get_attr(FileType::RegularFile, CONTROL_FREEZE, 0),
+                "freeze".to_string(),
+                None,
+            ),
+            (
+                CONTROL_CONFIRM,
+                FileType::RegularFile,
+                self.get_attr(FileType::RegularFile, CONTROL_CONFIRM, 0),
+                "confirm".to_string(),
+                None,
+            ),
+            (
+                CONTROL_HOTKEYS,
+                FileType::RegularFile,
+                self.get_attr(FileType::RegularFile, CONTROL_HOTKEYS, 0),
+                "hotkeys".to_string(),
+                None,
+            ),
+            (
+                CONTROL_STATS,
+                FileType::RegularFile,
+                self.get_attr(FileType::RegularFile, CONTROL_STATS, 0),
+                "stats".to_string(),
+                None,
+            ),
+            (
+                CONTROL_TRACE,
+                FileType::RegularFile,
+                self.get_attr(FileType::RegularFile, CONTROL_TRACE, 0),
+                "trace".to_string(),
+                None,
+            ),
+            (
+                CONTROL_INVALIDATE,
+                FileType::RegularFile,
+                self.get_attr(FileType::RegularFile, CONTROL_INVALIDATE, 0),
+                "invalidate".to_string(),
+                None,
+            ),
+            (
+                CONTROL_RELOAD,
+                FileType::RegularFile,
+                self.get_attr(FileType::RegularFile, CONTROL_RELOAD, 0),
+                "reload".to_string(),
+                None,
+            ),
+            (
+                CONTROL_SYNC,
+                FileType::RegularFile,
+                self.get_attr(FileType::RegularFile, CONTROL_SYNC, 0),
+                "sync".to_string(),
+                None,
+            ),
+            (
+                CONTROL_RAW_HISTORY,
+                FileType::RegularFile,
+                self.get_attr(FileType::RegularFile, CONTROL_RAW_HISTORY, 0),
+                "raw_history".to_string(),
+                None,
+            ),
+            (
+                CONTROL_CAPABILITIES,
+                FileType::RegularFile,
+                self.get_attr(
+                    FileType::RegularFile,
+                    CONTROL_CAPABILITIES,
+                    capabilities.len() as u64,
+                ),
+                "capabilities".to_string(),
+                Some(capabilities),
+            ),
+        ];
+
+        let mut tree: BTreeMap<u64, Vec<DirEntry>> = BTreeMap::new();
+        tree.insert(1, root_entries);
+        tree.insert(CONTROL_DIR, control_entries);
+        if !mirror_entries.is_empty() {
+            tree.insert(MIRROR_DIR, mirror_entries);
+        }
+        self.add_static_entries(&mut tree);
+
+        for (parent, entries) in tree {
+            self.direntries_by_parent_ino.insert(
+                parent,
+                entries.iter().map(|e| (e.3.clone(), e.clone())).collect(),
+            );
+            for e in entries {
+                self.direntries_by_ino.insert(e.0, e.clone());
+            }
+        }
+
+        // /kv/.match is looked up explicitly, so it's left out of /kv listings.
+        let kv_match = (
+            KV_MATCH,
+            FileType::Directory,
+            self.get_attr(FileType::Directory, KV_MATCH, 0),
+            ".match".to_string(),
+            None,
+        );
+        self.direntries_by_ino.insert(KV_MATCH, kv_match);
+        let truncated = (
+            KV_TRUNCATED,
+            FileType::RegularFile,
+            self.get_attr(
+                FileType::RegularFile,
+                KV_TRUNCATED,
+                TRUNCATED_HELP.len() as u64,
+            ),
+            TRUNCATED_NAME.to_string(),
+            Some(TRUNCATED_HELP.to_string()),
+        );
+        self.direntries_by_ino.insert(KV_TRUNCATED, truncated);
+    }
+
+    // Add static_dir and static_file entries from config to tree, keyed by
+    // parent inode. Entries whose parent isn't the root or a static_dir, or
+    // whose name is already taken, are skipped.
+    fn add_static_entries(&mut self, tree: &mut BTreeMap<u64, Vec<DirEntry>>) {
+        let mut dirs: Vec<String> = self
+            .config
+            .static_dir
+            .iter()
+            .map(|d| d.path.trim_matches('/').to_string())
+            .collect();
+        // Parents before their children.
+        dirs.sort_by_key(|p| p.matches('/').count());
+        let files: Vec<(String, Option<String>)> = self
+            .config
+            .static_file
+            .iter()
+            .map(|f| (f.path.trim_matches('/').to_string(), f.content.clone()))
+            .collect();
+
+        let mut inos_by_path: HashMap<String, u64> = HashMap::new();
+        inos_by_path.insert(String::new(), 1);
+        let mut ino = STATIC_START;
+        for (path, content) in dirs.into_iter().map(|d| (d, None)).chain(files) {
+            let (parent, name) = match path.rsplit_once('/') {
+                Some((parent, name)) => (parent.to_string(), name.to_string()),
+                None => (String::new(), path.clone()),
+            };
+            let parent_ino = match inos_by_path.get(&parent) {
+                Some(v) => *v,
+                None => {
+                    log::warn!("Skipping /{}, /{} isn't a static_dir.", path, parent);
+                    continue;
+                }
+            };
+            let siblings = tree.entry(parent_ino).or_default();
+            if name.is_empty() || siblings.iter().any(|e| e.3 == name) {
+                log::warn!("Skipping /{}, the name is already taken.", path);
+                continue;
+            }
+            if ino > STATIC_END {
+                log::warn!("Skipping /{} and beyond, too many static entries.", path);
+                break;
+            }
+            let (kind, size) = match &content {
+                Some(c) => (FileType::RegularFile, c.len() as u64),
+                None => (FileType::Directory, 0),
+            };
+            let attr = self.get_attr(kind, ino, size);
+            siblings.push((ino, kind, attr, name, content));
+            if kind == FileType::Directory {
+                // So that empty directories can still be listed.
+                tree.insert(ino, vec![]);
+                inos_by_path.insert(path, ino);
+            }
+            ino += 1;
+        }
+    }
+
+    // help for the subtree /name, or why it's left out if the driver can't do
+    // feature.
+    fn subtree_help(&self, name: &str, supported: bool, feature: &str, help: &str) -> String {
+        match supported {
+            true => help.to_string(),
+            false => format!(
+                "/{} isn't available, as this driver doesn't support {}.\n",
+                name, feature
+            ),
+        }
+    }
+
+    // Content of /.fusekv/capabilities, as key=value lines with lists
+    // comma-separated. Only reflects the backend at mount time.
+    fn capabilities(&self, subtrees: Vec<String>) -> String {
+        let info = match self.driver.server_info() {
+            Ok(v) => v,
+            Err(e) => {
+                log::warn!("Couldn't detect backend capabilities: {}", e);
+                ServerInfo::default()
+            }
+        };
+        let degraded: Vec<&str> = match &info.version {
+            Some(version) => VERSIONED_FEATURES
+                .iter()
+                .filter(|(_, min)| !version_at_least(version, *min))
+                .map(|(feature, _)| *feature)
+                .collect(),
+            None => vec![],
+        };
+        let unknown = || "unknown".to_string();
+        let lines = vec![
+            ("driver", Some(info.driver).filter(|d| !d.is_empty())),
+            ("server_version", info.version),
+            ("server_mode", info.mode),
+            ("modules", Some(info.modules.join(","))),
+            ("cluster_mode", Some(self.config.cluster_mode.to_string())),
+            ("subtrees", Some(subtrees.join(","))),
+            ("supports", Some(self.caps.names().join(","))),
+            ("degraded", Some(degraded.join(","))),
+            ("read_only", Some(self.config.read_only.to_string())),
+            ("allow_other", Some(self.config.allow_other.to_string())),
+            ("harden", Some(self.config.harden.to_string())),
+        ];
+        lines
+            .into_iter()
+            .map(|(k, v)| format!("{}={}\n", k, v.unwrap_or_else(unknown)))
+            .collect()
+    }
+
+    fn get_kv_direntries(&mut self, ino: u64) -> DriverResult<Vec<ReadDirEntry>> {
+        // TODO support hsets by setting them to Directory
+        let deadline = self.listing_timeout(ino).map(|d| Instant::now() + d);
+        let (mut entries, complete) = match self.config.separator.clone() {
+            Some(separator) => self.get_namespace_direntries(ino, &separator, deadline)?,
+            None => {
+                let (refs, complete) =
+                    self.driver
+                        .list_keys_until(0, self.max_results(), deadline)?;
+                let entries: Vec<ReadDirEntry> = refs
+                    .into_iter()
+                    .map(|r| {
+                        self.kv_keys_by_ino.insert(r.ino, r.key.clone());
+                        (r.ino, FileType::RegularFile, r.key)
+                    })
+                    .collect();
+                (entries, complete)
+            }
+        };
+        self.sort_kv_direntries(ino, &mut entries);
+        if !complete {
+            log::warn!(
+                "Listing {} timed out, returning the first {} entries.",
+                self.path_of(ino).unwrap_or_default(),
+                entries.len()
+            );
+            self.truncated_listings += 1;
+            entries.push((
+                KV_TRUNCATED,
+                FileType::RegularFile,
+                TRUNCATED_NAME.to_string(),
+            ));
+        }
+        Ok(entries)
+    }
+
+    // What the backend reports about key, from the cache if it can be. A key
+    // in the directory listed last is fetched along with up to KEY_INFO_BATCH
+    // keys after it not cached yet, in one round trip.
+    fn key_info(&mut self, key: &str) -> DriverResult<Option<KeyInfo>> {
+        if let Some(info) = self.cache.key_info(key) {
+            return Ok(Some(info));
+        }
+        let batch: Vec<String> = match self.listed_positions.get(key) {
+            Some(&i) if self.cache.caches_attrs() => self.listed_keys[i..]
+                .iter()
+                .filter(|k| !self.cache.has_key_info(k))
+                .take(KEY_INFO_BATCH)
+                .cloned()
+                .collect(),
+            _ => vec![key.to_string()],
+        };
+        let infos = self.driver.key_infos(&batch)?;
+        let mut found = None;
+        for (k, info) in batch.iter().zip(infos) {
+            if let Some(info) = info {
+                if k == key {
+                    found = Some(info.clone());
+                }
+                self.cache.put_key_info(k, info);
+            }
+        }
+        Ok(found)
+    }
+
+    // Entries of the directory at ino, as listed by list, or as listed within
+    // listing_ttl.
+    fn cached_direntries<F>(&mut self, ino: u64, list: F) -> DriverResult<Vec<ReadDirEntry>>
+    where
+        F: FnOnce(&mut KVFS) -> DriverResult<Vec<ReadDirEntry>>,
+    {
+        let path = match self.path_of(ino) {
+            Some(v) => v,
+            None => return list(self),
+        };
+        if let Some(entries) = self.cache.listing(&path) {
+            return Ok(entries);
+        }
+        let entries = list(self)?;
+        // Listings cut short by listing_timeout are worth trying again.
+        if !entries.iter().any(|e| e.0 == KV_TRUNCATED) {
+            self.cache.put_listing(&path, &entries);
+        }
+        Ok(entries)
+    }
+
+    // Sort entries of /kv or the namespace at ino by its listing order, with
+    // namespaces first by name. Orders the backend can't tell leave entries as
+    // they are.
+    fn sort_kv_direntries(&self, ino: u64, entries: &mut [ReadDirEntry]) {
+        let order = self.listing_order(ino);
+        if order == ListingOrder::Backend {
+            return;
+        }
+        let files: Vec<(u64, String)> = entries
+            .iter()
+            .filter(|e| e.1 == FileType::RegularFile)
+            .filter_map(|e| Some((e.0, self.kv_keys_by_ino.get(&e.0)?.clone())))
+            .collect();
+        let keys: Vec<String> = files.iter().map(|f| f.1.clone()).collect();
+        let ranks = match order {
+            ListingOrder::Idle => self.driver.idle_times(&keys),
+            ListingOrder::Size => self.driver.sizes(&keys),
+            _ => Ok(vec![]),
+        };
+        let ranks: HashMap<u64, u64> = match ranks {
+            Ok(v) => files.iter().map(|f| f.0).zip(v).collect(),
+            Err(e) => {
+                log::warn!("Error getting {:?} listing order: {}", order, e);
+                return;
+            }
+        };
+        entries.sort_by(|a, b| {
+            let (ra, rb) = (ranks.get(&a.0), ranks.get(&b.0));
+            (a.1 == FileType::RegularFile)
+                .cmp(&(b.1 == FileType::RegularFile))
+                .then_with(|| match order {
+                    ListingOrder::Size => rb.cmp(&ra),
+                    _ => ra.cmp(&rb),
+                })
+                .then_with(|| a.2.cmp(&b.2))
+        });
+    }
+
+    // Scan the next page of keys of the directory at ino into dir. Only the
+    // first component of each key past its prefix is listed, as in
+    // get_namespace_direntries, but a key and a namespace of the same name
+    // are listed once, as whichever is scanned first. /find directories list
+    // every key matching their pattern, as links.
+    fn read_dir_page(&mut self, ino: u64, dir: &mut DirHandle) -> DriverResult<()> {
+        let cursor = match dir.cursor.take() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let pattern = match &dir.find {
+            Some(v) => v.clone(),
+            None => format!("{}*", escape_glob(&dir.prefix)),
+        };
+        // Typing keys as they're scanned saves looking up each listed file's
+        // attributes on its own, but they're only kept if attrs are cached.
+        let typed = match self.cache.caches_attrs() {
+            true => self.driver.scan_typed_keys(&pattern, &cursor, READDIR_PAGE),
+            false => Err(DriverError::Unsupported("typed listing")),
+        };
+        let mut json_keys = HashSet::new();
+        let (refs, next) = match typed {
+            Ok((refs, next)) => {
+                self.cache_typed_attrs(&refs);
+                if self.json {
+                    json_keys.extend(
+                        refs.iter()
+                            .filter(|r| r.kind == "ReJSON-RL")
+                            .map(|r| r.key.clone()),
+                    );
+                }
+                let refs = refs
+                    .into_iter()
+                    .map(|r| KVRef {
+                        ino: r.ino,
+                        key: r.key,
+                    })
+                    .collect();
+                (refs, next)
+            }
+            Err(DriverError::Unsupported(_)) => {
+                self.driver.scan_keys(&pattern, &cursor, READDIR_PAGE)?
+            }
+            Err(e) => return Err(e),
+        };
+        dir.cursor = next;
+        let separator = self.config.separator.clone();
+        let limit = self.config.max_results.filter(|m| *m >= 0);
+        for r in refs {
+            if limit.is_some_and(|m| dir.names.len() as i64 >= m) {
+                dir.cursor = None;
+                break;
+            }
+            if let Some(pattern) = &dir.find {
+                if !r.key.contains('/') && dir.names.insert(r.key.clone()) {
+                    let ino = find_link_ino(pattern, &r.key);
+                    self.find_links_by_ino
+                        .insert(ino, (pattern.clone(), r.key.clone()));
+                    dir.entries.push((ino, FileType::Symlink, r.key));
+                }
+                continue;
+            }
+            let rest = match r.key.strip_prefix(&dir.prefix) {
+                Some(v) if !v.is_empty() => v.to_string(),
+                _ => continue,
+            };
+            match separator.as_ref().and_then(|s| rest.find(s.as_str())) {
+                Some(i) => {
+                    let name = rest[..i].to_string();
+                    if dir.names.insert(name.clone()) {
+                        let namespace = format!("{}{}", dir.prefix, name);
+                        let ino = namespace_ino(&namespace);
+                        self.namespaces_by_ino.insert(ino, namespace);
+                        dir.entries.push((ino, FileType::Directory, name));
+                    }
+                }
+                None if json_keys.contains(&r.key) && dir.names.insert(rest.clone()) => {
+                    let ino = json_ino(&r.key, "$");
+                    self.json_nodes_by_ino
+                        .insert(ino, (r.key.clone(), "$".to_string()));
+                    dir.entries.push((ino, FileType::Directory, rest));
+                }
+                None if dir.names.insert(rest.clone()) => {
+                    self.kv_keys_by_ino.insert(r.ino, r.key.clone());
+                    self.listed_positions
+                        .insert(r.key.clone(), self.listed_keys.len());
+                    self.listed_keys.push(r.key);
+                    dir.entries.push((r.ino, FileType::RegularFile, rest));
+                }
+                None => {}
+            }
+        }
+        if dir.cursor.is_some() || dir.find.is_some() {
+            return Ok(());
+        }
+        if let Some(separator) = separator {
+            let dirs: Vec<String> = self
+                .kv_dirs
+                .iter()
+                .filter_map(|d| {
+                    let rest = d.strip_prefix(&dir.prefix)?;
+                    rest.split(separator.as_str()).next().map(String::from)
+                })
+                .collect();
+            for name in dirs {
+                if !name.is_empty() && dir.names.insert(name.clone()) {
+                    let namespace = format!("{}{}", dir.prefix, name);
+                    let ino = namespace_ino(&namespace);
+                    self.namespaces_by_ino.insert(ino, namespace);
+                    dir.entries.push((ino, FileType::Directory, name));
+                }
+            }
+        }
+        if let Some(path) = self.path_of(ino) {
+            self.cache.put_listing(&path, &dir.entries[2..]);
+        }
+        Ok(())
+    }
+
+    // Cache the attributes of the files for refs, as lookups would. Only
+    // strings, lists, and sets are read as files, and keys with writes held
+    // back are left to be looked up.
+    fn cache_typed_attrs(&mut self, refs: &[TypedKVRef]) {
+        for r in refs {
+            if !matches!(r.kind.as_str(), "string" | "list" | "set")
+                || self.coalescer.pending(&r.key).is_some()
+            {
+                continue;
+            }
+            let size = r.size + self.newline().len() as u64;
+            let mut attr = self.get_attr(FileType::RegularFile, r.ino, size);
+            if let (true, Some(mtime)) = (self.config.track_mtime, r.modified) {
+                attr.mtime = mtime;
+                attr.ctime = mtime;
+            }
+            self.cache.put_attr(&r.key, attr);
+        }
+    }
+
+    // Entries of /kv or the namespace at ino, and whether listing them finished
+    // before deadline. Only the first component of each key past the namespace
+    // is listed, anything nested further implies a namespace directory. Keys
+    // win over namespaces of the same name, as they do in lookups.
+    fn get_namespace_direntries(
+        &mut self,
+        ino: u64,
+        separator: &str,
+        deadline: Option<Instant>,
+    ) -> DriverResult<(Vec<ReadDirEntry>, bool)> {
+        let prefix = match ino {
+            // /kv
+            4096 => String::new(),
+            _ => match self.namespaces_by_ino.get(&ino) {
+                Some(v) => format!("{}{}", v, separator),
+                None => return Ok((vec![], true)),
+            },
+        };
+        let (refs, complete) = self.driver.match_keys_until(
+            &format!("{}*", escape_glob(&prefix)),
+            0,
+            self.max_results(),
+            deadline,
+        )?;
+        let mut children: BTreeMap<String, (u64, FileType)> = BTreeMap::new();
+        let mut dirs: Vec<String> = vec![];
+        for r in refs {
+            let rest = match r.key.strip_prefix(&prefix) {
+                Some(v) if !v.is_empty() => v.to_string(),
+                _ => continue,
+            };
+            match rest.find(separator) {
+                Some(i) => dirs.push(rest[..i].to_string()),
+                None => {
+                    self.kv_keys_by_ino.insert(r.ino, r.key);
+                    children.insert(rest, (r.ino, FileType::RegularFile));
+                }
+            }
+        }
+        dirs.extend(self.kv_dirs.iter().filter_map(|d| {
+            let rest = d.strip_prefix(&prefix)?;
+            rest.split(separator).next().map(String::from)
+        }));
+        for dir in dirs {
+            if dir.is_empty() || children.contains_key(&dir) {
+                continue;
+            }
+            let namespace = format!("{}{}", prefix, dir);
+            let ino = namespace_ino(&namespace);
+            self.namespaces_by_ino.insert(ino, namespace);
+            children.insert(dir, (ino, FileType::Directory));
+        }
+        Ok((
+            children
+                .into_iter()
+                .map(|(name, (ino, kind))| (ino, kind, name))
+                .collect(),
+            complete,
+        ))
+    }
+
+    // Current content of the /.fusekv control file at ino, or None if ino isn't
+    // one.
+    fn control_content(&self, ino: u64) -> Option<String> {
+        match ino {
+            CONTROL_FREEZE => Some(format!("{}\n", if self.frozen { 1 } else { 0 })),
+            CONTROL_CONFIRM => Some(
+                self.delete_tokens
+                    .iter()
+                    .map(|(pattern, token)| format!("{} {}\n", token, pattern))
+                    .collect(),
+            ),
+            CONTROL_HOTKEYS => {
+                let mut hits: Vec<(&String, &u64)> = self.key_hits.iter().collect();
+                hits.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+                Some(
+                    hits.into_iter()
+                        .take(self.config.hot_keys)
+                        .map(|(key, n)| format!("{} {}\n", n, key))
+                        .collect(),
+                )
+            }
+            CONTROL_RAW_HISTORY => Some(self.raw_history.iter().cloned().collect()),
+            CONTROL_INVALIDATE | CONTROL_RELOAD | CONTROL_SYNC => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    // Content of /kv:random, /kv:random:value, /kv:count, /.fusekv/stats,
+    // /.fusekv/trace, or a file in a /txn session or /publish batch, or None if
+    // ino isn't one of them.
+    fn generated_content(&mut self, ino: u64) -> DriverResult<Option<Vec<u8>>> {
+        let content = match ino {
+            KV_RANDOM => match self.driver.random_key()? {
+                Some(key) => format!("{}\n", key).into_bytes(),
+                None => vec![],
+            },
+            KV_RANDOM_VALUE => match self.driver.random_key()? {
+                Some(key) => match self.current_value(&key)? {
+                    Some(value) => [&value[..], b"\n"].concat(),
+                    None => vec![],
+                },
+                None => vec![],
+            },
+            KV_COUNT => {
+                format!("{}\n", self.driver.count_keys(self.config.count_sample)?).into_bytes()
+            }
+            CONTROL_STATS => self.stats().into_bytes(),
+            CONTROL_TRACE => self.driver.trace()?.concat().into_bytes(),
+            TXN_KEY_START..=TXN_KEY_END => match self.txn_keys_by_ino.get(&ino) {
+                Some((session, key)) => self.txn_content(session, key)?.unwrap_or_default(),
+                None => return Ok(None),
+            },
+            DUMP_START..=DUMP_END => match self.dumps_by_ino.get(&ino).cloned() {
+                Some(prefix) => self.dump_content(&prefix)?,
+                None => return Ok(None),
+            },
+            JSON_START..=JSON_END => match self.json_nodes_by_ino.get(&ino) {
+                Some((key, path)) => match self.json_value(key, path)? {
+                    Some(v) => self.with_newline(&json_content(&v)),
+                    None => vec![],
+                },
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(content))
+    }
+
+    // Time an operation towards /.fusekv/stats and metrics until the returned
+    // timer drops.
+    fn time(&self, op: &'static str) -> OpTimer {
+        OpTimer::start(self.metrics.clone(), op)
+    }
+
+    // Content of /.fusekv/stats, one "<name> <value>" per line. Pinging the
+    // backend doubles as a health check.
+    fn stats(&mut self) -> String {
+        let started = Instant::now();
+        let backend_up = self.driver.ping().is_ok();
+        let mut lines = vec![
+            format!("truncated_listings {}", self.truncated_listings),
+            format!("flushes_detected {}", self.flushes_detected),
+            format!("checksum_cache_hits {}", self.checksum_hits),
+            format!("checksum_cache_misses {}", self.checksum_misses),
+            format!("backend_up {}", backend_up as u8),
+            format!("backend_latency_us {}", started.elapsed().as_micros()),
+        ];
+        if let Some(skew) = self.clock_skew() {
+            lines.push(format!("clock_skew_ms {}", skew));
+        }
+        if self.config.adaptive_ttl > 0 {
+            let (watching, namespaces) = self.churn.stats();
+            lines.push(format!("adaptive_ttl_watching {}", watching as u8));
+            lines.push(format!("adaptive_ttl_namespaces {}", namespaces));
+        }
+        lines.push(format!("read_locks_held {}", self.read_locks.held()));
+        lines.push(format!("open_handles {}", self.handles.len()));
+        let mut open: Vec<(&str, usize)> = self.open_counts().into_iter().collect();
+        open.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        for (key, n) in open.into_iter().take(OPEN_KEYS_REPORTED) {
+            lines.push(format!("open_handles.{} {}", key, n));
+        }
+        if self.config.write_behind {
+            lines.push(format!("write_behind_queued {}", self.coalescer.queued()));
+        }
+        for (op, stats) in self.metrics.ops() {
+            lines.push(format!("ops.{}.count {}", op, stats.count));
+            lines.push(format!("ops.{}.total_us {}", op, stats.total.as_micros()));
+            lines.push(format!("ops.{}.max_us {}", op, stats.max.as_micros()));
+        }
+        lines.iter().map(|l| format!("{}\n", l)).collect()
+    }
+
+    // The inode of the /kv entry for key, falling back to its hash if the driver
+    // can't say.
+    fn key_ino(&self, key: &str) -> u64 {
+        match self.driver.inos_of(&[key.to_string()]) {
+            Ok(inos) if !inos.is_empty() => inos[0],
+            Ok(_) => kv_ino(key),
+            Err(e) => {
+                log::error!("Error finding inode of {}: {}", key, e);
+                kv_ino(key)
+            }
+        }
+    }
+
+    // Milliseconds the backend's clock is ahead of ours, measured again once
+    // CLOCK_SKEW_INTERVAL has passed, or None if the backend can't say. Half
+    // the round trip is allowed for the reply.
+    fn clock_skew(&mut self) -> Option<i64> {
+        if self
+            .clock_checked
+            .is_some_and(|c| c.elapsed() < CLOCK_SKEW_INTERVAL)
+        {
+            return self.clock_skew;
+        }
+        self.clock_checked = Some(Instant::now());
+        let before = epoch_millis(SystemTime::now());
+        match self.driver.server_time() {
+            Ok(server) => {
+                let after = epoch_millis(SystemTime::now());
+                let skew = server.as_millis() as i64 - (before + after) / 2;
+                if self.clock_skew != Some(skew) {
+                    log::debug!("Backend clock is {}ms ahead of ours.", skew);
+                }
+                self.clock_skew = Some(skew);
+            }
+            Err(e) => log::debug!("Error measuring backend clock skew: {}", e),
+        }
+        self.clock_skew
+    }
+
+    // Where all state is left once the session ends, to mount again with.
+    pub fn remains(&self) -> Arc<Mutex<Option<KVFS>>> {
+        self.remains.clone()
+    }
+
+    // Changes to keys, to tell the kernel of the session about.
+    pub fn invalidations(&self) -> Arc<Invalidations> {
+        self.invalidations.clone()
+    }
+
+    // Have the config file reloaded through reloader when asked to.
+    pub fn reload_with(&mut self, reloader: Arc<Reloader>) {
+        self.reloader = Some(reloader);
+    }
+
+    // Take up a reloaded config, if there is one. Only permissions,
+    // max_results, TTLs, and read_only change without remounting, and
+    // read_only can't be turned off if the mount started read-only.
+    fn check_reloaded(&mut self) {
+        let config = match self.reloader.as_ref().and_then(|r| r.take()) {
+            Some(v) => v,
+            None => return,
+        };
+        let mut reloaded = self.config.clone();
+        reloaded.permission = config.permission.clone();
+        reloaded.write_allow = config.write_allow.clone();
+        reloaded.policy = config.policy.clone();
+        reloaded.max_results = config.max_results;
+        reloaded.entry_ttl = config.entry_ttl;
+        reloaded.attr_ttl = config.attr_ttl;
+        reloaded.data_ttl = config.data_ttl;
+        reloaded.listing_ttl = config.listing_ttl;
+        reloaded.read_only = config.read_only || self.mounted_read_only;
+        if config.read_only != reloaded.read_only {
+            log::warn!("Staying read-only, as the mount can only be made writable by remounting.");
+        }
+        // Configs can't be compared directly, as they hold regexes.
+        let mut ignored = config;
+        ignored.read_only = reloaded.read_only;
+        ignored.disable_raw |= self.mounted_read_only;
+        if format!("{:?}", ignored) != format!("{:?}", reloaded) {
+            log::warn!("Some changed settings only take effect on remounting.");
+        }
+        self.cache = Cache::new(
+            Duration::from_millis(reloaded.attr_ttl),
+            Duration::from_millis(reloaded.data_ttl),
+            Duration::from_millis(reloaded.listing_ttl),
+            self.metrics.clone(),
+        );
+        self.config = reloaded;
+        log::info!("Reloaded config.");
+    }
+
+    // Forget every key cached here and by the driver if the backend has lost
+    // most of its keys since the last check, once FLUSH_CHECK_INTERVAL has
+    // passed. The kernel still has entries cached for up to entry_ttl.
+    fn check_flushed(&mut self) {
+        if self
+            .flush_checked
+            .is_some_and(|c| c.elapsed() < FLUSH_CHECK_INTERVAL)
+        {
+            return;
+        }
+        self.flush_checked = Some(Instant::now());
+        let keys = match self.driver.usage() {
+            Ok(usage) => usage.keys,
+            Err(e) => {
+                log::debug!("Error checking whether the backend was flushed: {}", e);
+                return;
+            }
+        };
+        if let Some(before) = self.backend_keys {
+            if keys < before / 2 {
+                log::warn!(
+                    "Backend went from {} keys to {}, assuming it was flushed and forgetting cached keys.",
+                    before,
+                    keys
+                );
+                self.forget_keys();
+                self.flushes_detected += 1;
+            }
+        }
+        self.backend_keys = Some(keys);
+    }
+
+    // Forget what's cached of keys other clients changed since the last check.
+    fn check_changed(&mut self) {
+        match self.invalidations.take() {
+            Some(Changed::Keys(keys)) => {
+                log::debug!("Forgetting {} keys changed elsewhere", keys.len());
+                for key in keys {
+                    self.cache.forget(&key);
+                    self.ttl_starts.remove(&key);
+                }
+            }
+            Some(Changed::Everything) => self.cache.clear(),
+            None => {}
+        }
+    }
+
+    // Forget everything cached about keys, except the keys of open handles so
+    // their writes still land.
+    fn forget_keys(&mut self) {
+        let open: HashSet<u64> = self.handles.values().map(|h| h.ino).collect();
+        self.kv_keys_by_ino.retain(|ino, _| open.contains(ino));
+        self.cache.clear();
+        self.namespaces_by_ino.clear();
+        self.checksum_files_by_ino.clear();
+        self.decompressed_by_ino.clear();
+        self.encoded_by_ino.clear();
+        self.staged_deletes.clear();
+        self.checksums.clear();
+        self.key_hits.clear();
+        self.driver.forget_cached();
+    }
+
+    // The time now by the backend's clock, so that times we hand out agree
+    // with those it and other mounts use. TTLs are always sent as durations,
+    // which skew doesn't affect.
+    fn server_now(&mut self) -> SystemTime {
+        let now = SystemTime::now();
+        match self.clock_skew() {
+            Some(skew) if skew >= 0 => now + Duration::from_millis(skew as u64),
+            Some(skew) => now - Duration::from_millis(skew.unsigned_abs()),
+            None => now,
+        }
+    }
+
+    // The reply to op by whoever sent req on path, and key if it's on one,
+    // logging its outcome to the audit log.
+    fn audited<R>(
+        &self,
+        req: &Request,
+        op: &'static str,
+        path: Option<String>,
+        key: Option<String>,
+        reply: R,
+    ) -> Audited<R> {
+        Audited::new(reply, self.audit.begin(req, op, path, key))
+    }
+
+    // Remember a command run through /raw by uid for /.fusekv/raw_history,
+    // forgetting the oldest past raw_history, and append it to
+    // raw_history_stream if there is one.
+    fn record_raw(&mut self, uid: u32, line: &str) {
+        let at = self
+            .server_now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        if let Some(stream) = self
+            .config
+            .raw_history_stream
+            .as_ref()
+            .filter(|_| self.caps.streams)
+        {
+            let fields = [
+                ("at", at.to_string()),
+                ("uid", uid.to_string()),
+                ("command", line.to_string()),
+            ];
+            if let Err(e) = self
+                .driver
+                .append_to_stream(stream, self.config.raw_history, &fields)
+            {
+                log::error!("Error appending to {}: {}", stream, e);
+            }
+        }
+        if self.config.raw_history == 0 {
+            return;
+        }
+        if self.raw_history.len() >= self.config.raw_history {
+            self.raw_history.pop_front();
+        }
+        self.raw_history
+            .push_back(format!("{} {} {}\n", at, uid, line));
+    }
+
+    // Count an access of key towards /.fusekv/hotkeys.
+    fn record_hit(&mut self, key: &str) {
+        if self.config.hot_keys == 0 {
+            return;
+        }
+        match self.key_hits.get_mut(&key.to_string()) {
+            Some(n) => *n += 1,
+            None => {
+                self.key_hits.put(key.to_string(), 1);
+            }
+        }
+    }
+
+    // How long blocking reads of ino may wait, or None to wait forever. An xattr
+    // on the inode beats a matching [[timeout]], which beats blocking_timeout.
+    fn blocking_timeout(&self, ino: u64) -> Option<Duration> {
+        let path = self.path_of(ino).unwrap_or_default();
+        let secs = match self.blocking_timeouts_by_ino.get(&ino) {
+            Some(v) => *v,
+            None => match self
+                .config
+                .timeout
+                .iter()
+                .find(|t| t.blocking.is_some() && t.pattern.is_match(&path))
+            {
+                Some(t) => t.blocking.unwrap(),
+                None => self.config.blocking_timeout,
+            },
+        };
+        match secs {
+            0 => None,
+            _ => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    // The key key was renamed to through a mount, if that was recently enough
+    // that its redirect is still there.
+    fn redirect_of(&self, key: &str) -> DriverResult<Option<String>> {
+        match self.config.rename_redirect {
+            0 => Ok(None),
+            _ => self.driver.redirect_of(key),
+        }
+    }
+
+    // The order of the directory at ino, from the first matching [[listing]],
+    // or listing_order.
+    fn listing_order(&self, ino: u64) -> ListingOrder {
+        let path = self.path_of(ino).unwrap_or_default();
+        match self
+            .config
+            .listing
+            .iter()
+            .find(|l| l.pattern.is_match(&path))
+        {
+            Some(l) => l.order,
+            None => self.config.listing_order,
+        }
+    }
+
+    // Entries listed per directory at most, or -1 for all of them.
+    fn max_results(&self) -> i64 {
+        self.config.max_results.unwrap_or(-1)
+    }
+
+    // How long listing the directory at ino may take, if there's a limit.
+    fn listing_timeout(&self, ino: u64) -> Option<Duration> {
+        let path = self.path_of(ino).unwrap_or_default();
+        let ms = match self
+            .config
+            .timeout
+            .iter()
+            .find(|t| t.listing.is_some() && t.pattern.is_match(&path))
+        {
+            Some(t) => t.listing.unwrap(),
+            None => self.config.listing_timeout,
+        };
+        match ms {
+            0 => None,
+            _ => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    // Operations supported on ino, reported via OPERATIONS_XATTR. Mutations
+    // are left out entirely on read-only mounts.
+    fn operations(&self, ino: u64) -> Vec<&'static str> {
+        let ops: &[&'static str] = match ino {
+            CONTROL_DIR => &["read"],
+            CONTROL_FREEZE | CONTROL_CONFIRM | CONTROL_INVALIDATE | CONTROL_RELOAD
+            | CONTROL_SYNC => &["read", "write"],
+            CONTROL_CAPABILITIES | CONTROL_HOTKEYS | CONTROL_STATS | CONTROL_RAW_HISTORY
+            | CONTROL_TRACE => &["read"],
+            KV_MATCH | MATCH_START..=MATCH_END => &["read", "delete"],
+            FIND_START..=FIND_END | FIND_LINK_START..=FIND_LINK_END => &["read"],
+            DUMP_START..=DUMP_END => &["read", "write"],
+            LOCK_START..=LOCK_END => &["read", "create", "delete"],
+            NAMESPACE_START..=NAMESPACE_END => &["read", "create", "delete"],
+            TAGS_START..=TAGS_END => &["read", "untag"],
+            KV_START..=KV_END => &[
+                "read", "write", "create", "delete", "rename", "tag", "expire",
+            ],
+            HISTORY_START..=HISTORY_KEY_END | MIRROR_KEY_START..=MIRROR_KEY_END => &["read"],
+            PUBSUB_START..=PUBSUB_END | QUEUE_START..=QUEUE_END => &["read", "write"],
+            COUNTER_START..=COUNTER_END => &["read", "write", "increment"],
+            TXN_START..=TXN_END => &["read", "create", "delete"],
+            TXN_KEY_START..=TXN_KEY_END if self.is_txn_control_ino(ino) => &["read", "write"],
+            TXN_KEY_START..=TXN_KEY_END => &["read", "write", "create", "delete"],
+            JSON_START..=JSON_END => &["read", "write", "create", "delete"],
+            _ => match self.path_of(ino).as_deref() {
+                Some("/raw") => &["read", "write", "exec"],
+                Some("/kv") | Some("/lock") | Some("/txn") | Some("/publish") => {
+                    &["read", "create"]
+                }
+                _ => &["read"],
+            },
+        };
+        ops.iter()
+            .copied()
+            .filter(|op| !self.config.read_only || *op == "read")
+            .collect()
+    }
+
+    // Usage against each configured quota, logging any that have newly crossed
+    // their warning threshold.
+    fn quota_usage(&mut self) -> DriverResult<Vec<QuotaUsage>> {
+        let quota = match &self.config.quota {
+            Some(q) => q.clone(),
+            None => return Ok(vec![]),
+        };
+        let usage = self.driver.usage()?;
+        let mut quotas = vec![];
+        if let Some(limit) = quota.keys {
+            quotas.push(QuotaUsage {
+                name: "keys",
+                used: usage.keys,
+                limit,
+            });
+        }
+        if let (Some(limit), Some(used)) = (quota.memory, usage.memory) {
+            quotas.push(QuotaUsage {
+                name: "memory",
+                used,
+                limit,
+            });
+        }
+        for q in &quotas {
+            if q.state(quota.warn_percent) == "ok" {
+                self.quotas_warned.remove(q.name);
+            } else if self.quotas_warned.insert(q.name) {
+                log::warn!(
+                    "Backend is past {}% of its {} quota, using {} of {}.",
+                    quota.warn_percent,
+                    q.name,
+                    q.used,
+                    q.limit
+                );
+            }
+        }
+        Ok(quotas)
+    }
+
+    // How long new locks are held before expiring, if at all.
+    fn lock_ttl(&self) -> Option<Duration> {
+        match self.config.lock_ttl {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    // The first policy matching path, if any.
+    fn policy(&self, path: &str) -> Option<&PathPolicy> {
+        self.config.policy.iter().find(|p| p.pattern.is_match(path))
+    }
+
+    // Whether req may mutate path. Paths matching a write_allow rule can only
+    // be mutated by members of its groups, the first matching rule winning.
+    fn may_write(&self, req: &Request, path: &str) -> bool {
+        match self
+            .config
+            .write_allow
+            .iter()
+            .find(|r| r.pattern.is_match(path))
+        {
+            Some(rule) => {
+                let groups = request_groups(req);
+                rule.gids.iter().any(|g| groups.contains(g))
+            }
+            None => true,
+        }
+    }
+
+    // Check the backend user req's uid maps to through acl_user may run args,
+    // failing with the errno to reply with if not. Unmapped uids aren't checked.
+    fn acl_check(&self, req: &Request, args: &[String]) -> Result<(), Errno> {
+        let rule = match self.config.acl_user.iter().find(|u| u.uid == req.uid()) {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        match self.driver.acl_allows(&rule.redis_user, args) {
+            Ok(true) => Ok(()),
+            Ok(false) => {
+                log::debug!(
+                    "Backend user {} may not run {} for uid {}.",
+                    rule.redis_user,
+                    args[0],
+                    req.uid()
+                );
+                Err(Errno::EACCES)
+            }
+            Err(e) => Err(errno(&e)),
+        }
+    }
+
+    // Full path of name under parent within the mount, if we know parent's.
+    // Keys in namespaces are named in full, as they would be without one.
+    fn child_path(&self, parent: u64, name: &OsStr) -> Option<String> {
+        if let Some(key) = self.kv_key_under(parent, &name.to_string_lossy()) {
+            return Some(format!("/kv/{}", key));
+        }
+        // Files in /txn sessions and /publish batches are checked as the /kv
+        // files they write.
+        let name_str = name.to_string_lossy();
+        if let Some(txn) = self.txns_by_ino.get(&parent).and_then(|s| self.txns.get(s)) {
+            if txn.is_key(&name_str) {
+                return Some(format!("/kv/{}", name_str));
+            }
+        }
+        if let JSON_START..=JSON_END = parent {
+            return self.path_of(parent);
+        }
+        let parent = match parent {
+            1 => String::new(),
+            _ => self.path_of(parent)?,
+        };
+        Some(format!("{}/{}", parent, name.to_string_lossy()))
+    }
+
+    // Full path of ino within the mount, if we know it.
+    fn path_of(&self, ino: u64) -> Option<String> {
+        match ino {
+            0..=RAW_END => self
+                .direntries_by_ino
+                .get(&ino)
+                .map(|e| format!("/{}", e.3)),
+            LOCK_START..=LOCK_END => self
+                .lock_names_by_ino
+                .get(&ino)
+                .map(|n| format!("/lock/{}", n)),
+            TAGS_START..=TAGS_END => self.tags_by_ino.get(&ino).map(|t| format!("/tags/{}", t)),
+            KV_START..=KV_END => self.kv_keys_by_ino.get(&ino).map(|k| format!("/kv/{}", k)),
+            NAMESPACE_START..=NAMESPACE_END => self
+                .namespaces_by_ino
+                .get(&ino)
+                .map(|n| format!("/kv/{}", n)),
+            HISTORY_START..=HISTORY_END => self
+                .history_ts_by_ino
+                .get(&ino)
+                .map(|ts| format!("/history/{}", ts)),
+            HISTORY_KEY_START..=HISTORY_KEY_END => self
+                .history_keys_by_ino
+                .get(&ino)
+                .map(|(ts, k)| format!("/history/{}/{}", ts, k)),
+            MATCH_START..=MATCH_END => self
+                .patterns_by_ino
+                .get(&ino)
+                .map(|p| format!("/kv/.match/{}", p)),
+            FIND_START..=FIND_END => self.finds_by_ino.get(&ino).map(|p| format!("/find/{}", p)),
+            DUMP_START..=DUMP_END => self.dumps_by_ino.get(&ino).map(|p| format!("/dump/{}", p)),
+            FIND_LINK_START..=FIND_LINK_END => self
+                .find_links_by_ino
+                .get(&ino)
+                .map(|(p, k)| format!("/find/{}/{}", p, k)),
+            PUBSUB_START..=PUBSUB_END => self
+                .channels_by_ino
+                .get(&ino)
+                .map(|c| format!("/pubsub/{}", c)),
+            COUNTER_START..=COUNTER_END => self
+                .counters_by_ino
+                .get(&ino)
+                .map(|n| format!("/counter/{}", n)),
+            QUEUE_START..=QUEUE_END => self
+                .queues_by_ino
+                .get(&ino)
+                .map(|n| format!("/queue/{}", n)),
+            MIRROR_KEY_START..=MIRROR_KEY_END => self
+                .mirror_keys_by_ino
+                .get(&ino)
+                .map(|(i, k)| format!("/mirror/{}/{}", self.mirrors[*i].0, k)),
+            TXN_START..=TXN_END => self.txns_by_ino.get(&ino).map(|s| format!("/{}", s)),
+            // Files in /txn sessions and /publish batches are checked as the
+            // /kv files they write.
+            TXN_KEY_START..=TXN_KEY_END => {
+                self.txn_keys_by_ino.get(&ino).map(|(s, k)| {
+                    match self.txns.get(s).is_some_and(|txn| txn.is_key(k)) {
+                        true => format!("/kv/{}", k),
+                        false => format!("/{}/{}", s, k),
+                    }
+                })
+            }
+            // Nodes of JSON documents are checked as the documents' keys.
+            JSON_START..=JSON_END => self
+                .json_nodes_by_ino
+                .get(&ino)
+                .map(|(k, _)| format!("/kv/{}", k)),
+            _ => None,
+        }
+    }
+
+    // Attributes of the /kv entry for key, or None if it doesn't exist.
+    fn get_kv_attr(&mut self, key: &str) -> DriverResult<Option<FileAttr>> {
+        if let Some(attr) = self.cache.attr(key) {
+            self.kv_keys_by_ino.insert(attr.ino.0, key.to_string());
+            return Ok(Some(attr));
+        }
+        let value = match self.current_value(key)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let ino = self.key_ino(key);
+        self.kv_keys_by_ino.insert(ino, key.to_string());
+        let mut attr = self.get_attr(
+            FileType::RegularFile,
+            ino,
+            (value.len() + self.newline().len()) as u64,
+        );
+        self.stamp_mtime(key, &mut attr)?;
+        self.show_ttl(key, &mut attr)?;
+        self.cache.put_attr(key, attr);
+        Ok(Some(attr))
+    }
+
+    // Show how long key has left in attr, as ttl_display says to.
+    fn show_ttl(&mut self, key: &str, attr: &mut FileAttr) -> DriverResult<()> {
+        if self.config.ttl_display == TtlDisplay::None || !self.caps.ttl {
+            return Ok(());
+        }
+        let ttl = match self.key_info(key)?.and_then(|i| i.ttl) {
+            Some(v) => v,
+            None => {
+                self.ttl_starts.remove(key);
+                return Ok(());
+            }
+        };
+        match self.config.ttl_display {
+            TtlDisplay::Size => {
+                let start = self.ttl_starts.entry(key.to_string()).or_insert(0);
+                *start = (*start).max(ttl).max(1);
+                attr.size = (attr.size * ttl).div_ceil(*start);
+            }
+            TtlDisplay::Blocks => attr.blocks = ttl,
+            TtlDisplay::None => {}
+        }
+        Ok(())
+    }
+
+    // Stamp attr with when key was last written, if that's tracked. Writes
+    // still held back by coalescing keep the current time.
+    fn stamp_mtime(&self, key: &str, attr: &mut FileAttr) -> DriverResult<()> {
+        if !self.config.track_mtime || self.coalescer.pending(key).is_some() {
+            return Ok(());
+        }
+        if let Some(mtime) = self.driver.modified(key)? {
+            attr.mtime = mtime;
+            attr.ctime = mtime;
+        }
+        Ok(())
+    }
+
+    fn entry_ttl(&self, ino: u64) -> Duration {
+        self.adapted_ttl(ino, self.config.entry_ttl)
+    }
+
+    fn attr_ttl(&self, ino: u64) -> Duration {
+        self.adapted_ttl(ino, self.config.attr_ttl)
+    }
+
+    fn adapted_ttl(&self, ino: u64, ttl: u64) -> Duration {
+        let ttl = Duration::from_millis(ttl);
+        match self.kv_keys_by_ino.get(&ino) {
+            Some(key) => self.churn.ttl(key, ttl),
+            None => ttl,
+        }
+    }
+
+    // Run job on a reader thread, or in place if there are none.
+    fn hand_off(&self, job: Job) {
+        if let Err(job) = self.readers.run(job) {
+            job(&*self.driver);
+        }
+    }
+
+    // What reads of values add after them, and writes drop from their end.
+    fn newline(&self) -> &'static str {
+        match self.config.append_newline {
+            true => "\n",
+            false => "",
+        }
+    }
+
+    // value as read through the mount.
+    fn with_newline(&self, value: &[u8]) -> Vec<u8> {
+        [value, self.newline().as_bytes()].concat()
+    }
+
+    // The value of key, including any write still held back by coalescing.
+    fn current_value(&self, key: &str) -> DriverResult<Option<Vec<u8>>> {
+        Ok(self.current_entry(key)?.map(|(v, _)| v))
+    }
+
+    // Like current_value, along with the kind of value key holds.
+    fn current_entry(&self, key: &str) -> DriverResult<Option<(Vec<u8>, ValueKind)>> {
+        if let Some(v) = self.coalescer.pending(key) {
+            return Ok(Some((v, ValueKind::String)));
+        }
+        Ok(self
+            .driver
+            .get_by_name(key.to_string(), kv_ino(key))?
+            .map(|e| (e.val, e.kind)))
+    }
+
+    // EMFILE if opening another file, of key if it's one under /kv, would go
+    // past max_open_handles or max_open_per_key.
+    fn check_open_limits(&self, key: Option<&str>) -> Result<(), Errno> {
+        let max = self.config.max_open_handles;
+        if max > 0 && self.handles.len() >= max {
+            log::warn!("Refusing to open more than {} files at once.", max);
+            return Err(Errno::EMFILE);
+        }
+        let max = self.config.max_open_per_key;
+        if let Some(key) = key.filter(|_| max > 0) {
+            if self.open_counts().get(key).is_some_and(|n| *n >= max) {
+                log::warn!("Refusing to open {} more than {} times at once.", key, max);
+                return Err(Errno::EMFILE);
+            }
+        }
+        Ok(())
+    }
+
+    // How many handles are open on each key under /kv with any open.
+    fn open_counts(&self) -> HashMap<&str, usize> {
+        let mut counts = HashMap::new();
+        for handle in self.handles.values() {
+            if let Some(key) = self.kv_keys_by_ino.get(&handle.ino) {
+                *counts.entry(key.as_str()).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    fn new_handle(
+        &mut self,
+        ino: u64,
+        bypass_cache: bool,
+        encoding: Encoding,
+        buffer: Option<Vec<u8>>,
+    ) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        let blocking_timeout = self.blocking_timeout(ino);
+        self.handles.insert(
+            fh,
+            FileHandle {
+                ino,
+                bypass_cache,
+                blocking_timeout,
+                encoding,
+                buffer,
+                dirty: false,
+                replies: vec![],
+                content: None,
+                kind: ValueKind::String,
+                append: false,
+                snapshot: self.snapshot_inos.contains(&ino),
+                streaming: false,
+                stream_end: 0,
+                ranged: false,
+                fetched: Arc::default(),
+                tail: None,
+                subscriber: None,
+            },
+        );
+        fh
+    }
+
+    // Whether writes to key should go straight to it rather than editing a
+    // copy, because it's too big to load.
+    fn streams_writes(&mut self, key: &str) -> DriverResult<bool> {
+        // Values have to be whole to be validated.
+        if self.validates(key) {
+            return Ok(false);
+        }
+        // A held write landing later would undo anything written in place.
+        self.coalescer.flush(key)?;
+        match self.driver.read_range(key, 0, 0)? {
+            Some((_, len)) => Ok(len > self.config.stream_threshold),
+            None => Ok(false),
+        }
+    }
+
+    // Add data at offset to the buffer of fh, to be written out on flush.
+    // Only /kv handles move on to streaming once their buffer outgrows
+    // stream_threshold, so others fail with EFBIG rather than growing it past
+    // that.
+    fn write_buffer(&mut self, fh: u64, offset: i64, data: &[u8]) -> Result<(), Errno> {
+        let limit = self.config.stream_threshold;
+        let handle = self.handles.get_mut(&fh).ok_or(Errno::EBADF)?;
+        let capped = !matches!(handle.ino, KV_START..=KV_END);
+        // Not opened for writing.
+        let buffer = handle.buffer.as_mut().ok_or(Errno::EBADF)?;
+        let start = match handle.append {
+            // Appends land at the end of the file as the kernel sees it, but
+            // the buffer only holds what's being added.
+            true => buffer.len(),
+            false if offset < 0 => return Err(Errno::EINVAL),
+            false => offset as usize,
+        };
+        let end = start.checked_add(data.len()).ok_or(Errno::EFBIG)?;
+        if capped && end as u64 > limit {
+            return Err(Errno::EFBIG);
+        }
+        if buffer.len() < end {
+            buffer.resize(end, 0);
+        }
+        buffer[start..end].copy_from_slice(data);
+        handle.dirty = true;
+        Ok(())
+    }
+
+    // Write data at offset of the key behind fh in place if fh streams writes,
+    // or once its buffer would outgrow stream_threshold, in which case the
+    // buffer is written out first and fh streams from then on. Returns whether
+    // data was written, or still needs adding to the buffer.
+    fn write_in_place(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> DriverResult<bool> {
+        let key = match self.kv_keys_by_ino.get(&ino) {
+            Some(v) => v.clone(),
+            None => return Ok(false),
+        };
+        let handle = match self.handles.get_mut(&fh) {
+            Some(v) if v.kind == ValueKind::String && !v.append => v,
+            _ => return Ok(false),
+        };
+        let end = offset + data.len() as u64;
+        if !handle.streaming {
+            match &handle.buffer {
+                Some(buffer) if (buffer.len() as u64).max(end) > self.config.stream_threshold => {
+                    log::debug!("Streaming writes to {} past {} bytes", key, buffer.len());
+                    self.coalescer.flush(&key)?;
+                    self.driver.set(&key, b"")?;
+                    if !buffer.is_empty() {
+                        self.driver.write_range(&key, 0, buffer)?;
+                    }
+                    handle.stream_end = buffer.len() as u64;
+                    handle.buffer = None;
+                    handle.streaming = true;
+                }
+                _ => return Ok(false),
+            }
+        }
+        self.driver.write_range(&key, offset, data)?;
+        self.cache.forget(&key);
+        handle.stream_end = handle.stream_end.max(end);
+        handle.dirty = true;
+        Ok(true)
+    }
+
+    // Write anything written through fh since it was last flushed to its key.
+    fn flush_handle(&mut self, fh: u64) -> DriverResult<()> {
+        let handle = match self.handles.get_mut(&fh) {
+            Some(v) if v.dirty => v,
+            _ => return Ok(()),
+        };
+        // A last line written without a newline is still a message.
+        if self.channels_by_ino.contains_key(&handle.ino)
+            || self.queues_by_ino.contains_key(&handle.ino)
+        {
+            handle.dirty = false;
+            let ino = handle.ino;
+            let message = handle.buffer.as_mut().map(std::mem::take);
+            return self.send_messages(ino, vec![message.unwrap_or_default()]);
+        }
+        // Files in /txn sessions and /publish batches only queue their
+        // content, which is dropped if the session was discarded meanwhile.
+        if let Some((session, key)) = self.txn_keys_by_ino.get(&handle.ino) {
+            handle.dirty = false;
+            let content = handle.buffer.as_deref().unwrap_or_default();
+            let value = stored_value(content, self.config.append_newline);
+            if self.txns.get(session).is_some_and(|txn| txn.is_key(key)) {
+                validate_write(&self.config, &mut self.last_rejects, key, &value)?;
+            }
+            if let Some(txn) = self.txns.get_mut(session) {
+                txn.writes.insert(key.clone(), Some(value));
+            }
+            return Ok(());
+        }
+        // /dump files restore the keys written to them.
+        if let Some(prefix) = self.dumps_by_ino.get(&handle.ino).cloned() {
+            handle.dirty = false;
+            let content = handle.buffer.take().unwrap_or_default();
+            handle.buffer = Some(vec![]);
+            let n = self.restore_dump(&prefix, &content)?;
+            log::info!("Restored {} keys under {:?}.", n, prefix);
+            return Ok(());
+        }
+        // Files of JSON documents set the value at their path.
+        if let Some((key, path)) = self.json_nodes_by_ino.get(&handle.ino).cloned() {
+            handle.dirty = false;
+            let content = handle.buffer.as_deref().unwrap_or_default();
+            let value = stored_value(content, self.config.append_newline);
+            return self.write_json(&key, &path, &value);
+        }
+        let key = match self.kv_keys_by_ino.get(&handle.ino) {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        // Everything's already written, bar dropping any \n reads add.
+        if handle.streaming {
+            if self.config.append_newline {
+                self.driver.trim_newline(key, handle.stream_end)?;
+            }
+            handle.dirty = false;
+            self.cache.forget(key);
+            self.hooks.fire(HookOp::Modify, key);
+            return Ok(());
+        }
+        // Only what was added is held, and mustn't be added again by the next
+        // flush.
+        if handle.kind == ValueKind::String && handle.append {
+            let content = handle.buffer.replace(vec![]).unwrap_or_default();
+            handle.dirty = false;
+            let key = key.clone();
+            self.append_value(&key, &content)?;
+            self.cache.forget(&key);
+            self.hooks.fire(HookOp::Modify, &key);
+            return Ok(());
+        }
+        let content = handle.buffer.as_deref().unwrap_or_default();
+        match handle.kind {
+            ValueKind::String => {
+                let value = stored_value(content, self.config.append_newline);
+                if value.is_empty() && self.config.empty_file == EmptyFile::Delete {
+                    self.coalescer.discard(key);
+                    self.driver.delete(std::slice::from_ref(key))?;
+                    handle.dirty = false;
+                    self.cache.forget(key);
+                    self.hooks.fire(HookOp::Delete, key);
+                    return Ok(());
+                }
+                // Rejected writes aren't tried again on release.
+                if let Err(e) = validate_write(&self.config, &mut self.last_rejects, key, &value) {
+                    handle.dirty = false;
+                    return Err(e);
+                }
+                self.coalescer.write(key, value)?
+            }
+            kind => {
+                if let Err(e) = validate_write(&self.config, &mut self.last_rejects, key, content) {
+                    handle.dirty = false;
+                    return Err(e);
+                }
+                let items: Vec<String> = String::from_utf8_lossy(content)
+                    .lines()
+                    .map(String::from)
+                    .collect();
+                self.driver.write_items(key, kind, &items, handle.append)?;
+                // Appended items mustn't be added again by the next flush.
+                if handle.append {
+                    handle.buffer = Some(vec![]);
+                }
+            }
+        }
+        handle.dirty = false;
+        self.cache.forget(key);
+        self.hooks.fire(HookOp::Modify, key);
+        Ok(())
+    }
+
+    // Add content, as written to the end of key through the mount, to its
+    // value: with APPEND, or by rewriting it when it has to be whole to be
+    // validated or the driver can't append.
+    fn append_value(&mut self, key: &str, content: &[u8]) -> DriverResult<()> {
+        if !self.validates(key) {
+            // A held write landing later would undo the append.
+            self.coalescer.flush(key)?;
+            let data = stored_value(content, self.config.append_newline);
+            match self.driver.append(key, self.newline().as_bytes(), &data) {
+                Err(DriverError::Unsupported(_)) => {}
+                result => return result,
+            }
+        }
+        let mut whole = match self.current_value(key)? {
+            Some(v) => self.with_newline(&v),
+            None => vec![],
+        };
+        whole.extend_from_slice(content);
+        let value = stored_value(&whole, self.config.append_newline);
+        validate_write(&self.config, &mut self.last_rejects, key, &value)?;
+        self.coalescer.write(key, value)
+    }
+
+    // Whether any [[validation]] stanza checks values written to key.
+    fn validates(&self, key: &str) -> bool {
+        let path = format!("/kv/{}", key);
+        self.config
+            .validation
+            .iter()
+            .any(|r| r.pattern.is_match(&path))
+    }
+
+    // Resize the value of key to size bytes as seen through the mount, through
+    // fh's buffer if it has one, zero-filling any growth. True if that
+    // deleted key instead, as emptying it does when empty_file is delete.
+    fn truncate(&mut self, key: &str, fh: Option<u64>, size: u64) -> DriverResult<bool> {
+        if let Some(handle) = fh.and_then(|fh| self.handles.get_mut(&fh)) {
+            if let Some(buffer) = handle.buffer.as_mut() {
+                buffer.resize(size as usize, 0);
+                handle.dirty = true;
+                return Ok(false);
+            }
+        }
+        // A held write landing later would undo the resize.
+        self.coalescer.flush(key)?;
+        let len = self.driver.read_range(key, 0, 0)?.map(|(_, len)| len);
+        let newline = self.newline();
+        let content_len = len.map_or(0, |l| l + newline.len() as u64);
+        let validated = self.validates(key);
+        // Shrinking only reads what's kept, and growing zero-fills the end with
+        // SETRANGE, unless the value has to be whole to be validated.
+        let mut content = match len {
+            Some(_) if size == content_len && size > 0 => return Ok(false),
+            Some(_) if size < content_len && !validated => {
+                match self.driver.read_range(key, 0, size)? {
+                    Some((v, _)) => v,
+                    None => vec![],
+                }
+            }
+            _ if size > content_len && !validated => match self.grow_in_place(key, len, size) {
+                Err(DriverError::Unsupported(_)) => self.whole_value(key)?,
+                result => return result.map(|()| false),
+            },
+            _ => self.whole_value(key)?,
+        };
+        content.resize(size as usize, 0);
+        let value = stored_value(&content, self.config.append_newline);
+        if value.is_empty() && self.config.empty_file == EmptyFile::Delete {
+            self.coalescer.discard(key);
+            self.driver.delete(&[key.to_string()])?;
+            self.cache.forget(key);
+            self.hooks.fire(HookOp::Delete, key);
+            return Ok(true);
+        }
+        // Every rewrite truncates first, so only what's written after is
+        // validated.
+        if size > 0 {
+            validate_write(&self.config, &mut self.last_rejects, key, &value)?;
+        }
+        self.coalescer.write(key, value)?;
+        self.cache.forget(key);
+        self.hooks.fire(HookOp::Modify, key);
+        Ok(false)
+    }
+
+    // Zero-fill the value of key, len bytes long if it exists, to size bytes as
+    // seen through the mount.
+    fn grow_in_place(&mut self, key: &str, len: Option<u64>, size: u64) -> DriverResult<()> {
+        // The \n reads add becomes part of the value.
+        let newline = self.newline();
+        if let (Some(len), false) = (len, newline.is_empty()) {
+            self.driver.write_range(key, len, newline.as_bytes())?;
+        }
+        // Writing the last byte zero-fills everything before it.
+        self.driver.write_range(key, size - 1, &[0])?;
+        self.cache.forget(key);
+        self.hooks.fire(HookOp::Modify, key);
+        Ok(())
+    }
+
+    // The value of key as read through the mount, or nothing if it doesn't
+    // exist.
+    fn whole_value(&self, key: &str) -> DriverResult<Vec<u8>> {
+        Ok(match self.current_value(key)? {
+            Some(v) => self.with_newline(&v),
+            None => vec![],
+        })
+    }
+
+    // Up to size bytes of /kv/<key> from offset as read through fh, or None if
+    // key doesn't exist. Values up to stream_threshold, or any value for
+    // snapshot handles, are fetched whole and kept for later reads through the
+    // same handle, unless it's open for writing, while bigger ones are only
+    // ever fetched a range at a time. Whole values are also kept for data_ttl,
+    // for handles that don't bypass the cache.
+    fn read_value(
+        &mut self,
+        key: &str,
+        fh: u64,
+        offset: u64,
+        size: u64,
+    ) -> DriverResult<Option<Vec<u8>>> {
+        let cacheable = match self.handles.get(&fh) {
+            Some(h) if h.buffer.is_none() && !h.streaming => !h.bypass_cache && !h.snapshot,
+            _ => false,
+        };
+        if let Some(value) = self.cache.value(key).filter(|_| cacheable) {
+            let content = self.with_newline(&value);
+            return Ok(Some(self.keep_content(fh, offset, size, content)));
+        }
+        let keep_up_to = self.keep_up_to(fh);
+        let newline = self.newline();
+        match fetch_value(&*self.driver, key, offset, size, keep_up_to, newline)? {
+            Some(Fetched::Whole(mut content)) => {
+                if cacheable {
+                    self.cache.put_value(key, &content);
+                }
+                content.extend_from_slice(newline.as_bytes());
+                Ok(Some(self.keep_content(fh, offset, size, content)))
+            }
+            Some(Fetched::Range(data)) => {
+                if let (Some(handle), Some(_)) = (self.handles.get_mut(&fh), keep_up_to) {
+                    handle.ranged = true;
+                }
+                Ok(Some(data))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // How long a value read through fh may be to be fetched whole and kept
+    // for later reads through it, or None if it's never kept. Snapshots are
+    // always kept whole.
+    fn keep_up_to(&self, fh: u64) -> Option<u64> {
+        match self.handles.get(&fh) {
+            Some(h) if h.buffer.is_none() && !h.streaming => Some(match h.snapshot {
+                true => u64::MAX,
+                false => self.config.stream_threshold,
+            }),
+            _ => None,
+        }
+    }
+
+    // Take on what reads of fh handed off to readers learned, unless it's
+    // been written through since.
+    fn take_fetched(&mut self, fh: u64) {
+        let handle = match self.handles.get_mut(&fh) {
+            Some(v) => v,
+            None => return,
+        };
+        let kept = handle.fetched.lock().unwrap().take();
+        match kept {
+            _ if handle.buffer.is_some() || handle.streaming => {}
+            Some(Kept::Content(content)) => handle.content = Some(content),
+            Some(Kept::Ranged) => handle.ranged = true,
+            None => {}
+        }
+    }
+
+    // Up to size bytes of content from offset, keeping content for later reads
+    // through fh.
+    fn keep_content(&mut self, fh: u64, offset: u64, size: u64, content: Vec<u8>) -> Vec<u8> {
+        let end = (offset.saturating_add(size) as usize).min(content.len());
+        let data = content[(offset as usize).min(end)..end].to_vec();
+        if let Some(handle) = self.handles.get_mut(&fh) {
+            handle.content = Some(content);
+        }
+        data
+    }
+
+    // Hex SHA-256 of the value of key, or None if it doesn't exist.
+    fn sha256_of(&mut self, key: &str) -> DriverResult<Option<String>> {
+        let entry = match self.driver.get_by_name(key.to_string(), kv_ino(key))? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let hash = seahash::hash(&entry.val);
+        if let Some((cached_hash, digest)) = self.checksums.get(&entry.key) {
+            if *cached_hash == hash {
+                self.checksum_hits += 1;
+                return Ok(Some(digest.clone()));
+            }
+        }
+        self.checksum_misses += 1;
+        let digest = sha256_hex(&entry.val);
+        self.checksums.put(entry.key, (hash, digest.clone()));
+        Ok(Some(digest))
+    }
+
+    // Attributes of /kv/<key>#<encoding>, or None if key doesn't exist.
+    fn get_encoded_attr(
+        &mut self,
+        key: &str,
+        encoding: Encoding,
+    ) -> DriverResult<Option<FileAttr>> {
+        let entry = match self.driver.get_by_name(key.to_string(), kv_ino(key))? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let name = format!("{}#{}", key, encoding.suffix());
+        let ino = kv_ino(&name);
+        self.encoded_by_ino.insert(ino, (key.to_string(), encoding));
+        Ok(Some(self.get_attr(
+            FileType::RegularFile,
+            ino,
+            encoding.encode(&entry.val).len() as u64,
+        )))
+    }
+
+    // Attributes of /kv/<key>:sha256, or None if key doesn't exist.
+    fn get_checksum_attr(&mut self, key: &str) -> DriverResult<Option<FileAttr>> {
+        if self
+            .driver
+            .get_by_name(key.to_string(), kv_ino(key))?
+            .is_none()
+        {
+            return Ok(None);
+        }
+        let name = format!("{}{}", key, SHA256_SUFFIX);
+        let ino = kv_ino(&name);
+        self.checksum_files_by_ino.insert(ino, key.to_string());
+        // 64 hex digits and a \n
+        Ok(Some(self.get_attr(FileType::RegularFile, ino, 65)))
+    }
+
+    // The codec the value of key is compressed with, or None if it isn't or
+    // doesn't exist. Only the first few bytes are read.
+    fn codec_of(&self, key: &str) -> DriverResult<Option<Codec>> {
+        Ok(self
+            .driver
+            .read_range(key, 0, codec::MAGIC_LEN)?
+            .and_then(|(head, _)| Codec::detect(&head)))
+    }
+
+    // The decompressed value of key, or None if it isn't compressed or doesn't
+    // exist.
+    fn decompressed_of(&self, key: &str) -> DriverResult<Option<Vec<u8>>> {
+        let value = match self.driver.read_range(key, 0, u64::MAX)? {
+            Some((v, _)) => v,
+            None => return Ok(None),
+        };
+        let codec = match Codec::detect(&value) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        match codec.decompress(&value) {
+            Ok(v) => Ok(Some(v)),
+            Err(e @ CodecError::Unsupported(..)) => {
+                log::debug!("Can't decompress {}: {}", key, e);
+                Err(DriverError::Unsupported("decompressing this codec"))
+            }
+            Err(e) => Err(DriverError::Corrupt(
+                "DECOMPRESS".to_string(),
+                key.to_string(),
+                e.to_string(),
+            )),
+        }
+    }
+
+    // Attributes of /kv/<key>:decompressed, or None if key doesn't exist or
+    // isn't compressed.
+    fn get_decompressed_attr(&mut self, key: &str) -> DriverResult<Option<FileAttr>> {
+        let size = match self.decompressed_of(key) {
+            Ok(Some(v)) => v.len(),
+            Ok(None) => return Ok(None),
+            // Values that can't be decompressed still have a view, which fails
+            // to open with the reason.
+            Err(DriverError::Unsupported(_)) | Err(DriverError::Corrupt(..)) => 0,
+            Err(e) => return Err(e),
+        };
+        let name = format!("{}{}", key, DECOMPRESSED_SUFFIX);
+        let ino = kv_ino(&name);
+        self.decompressed_by_ino.insert(ino, key.to_string());
+        let mut attr = self.get_attr(FileType::RegularFile, ino, size as u64);
+        // Views are never writable.
+        attr.perm &= 0o555;
+        Ok(Some(attr))
+    }
+
+    // Attributes of the /history/<ts> directory, which always exists.
+    fn get_history_attr(&mut self, ts: u64) -> FileAttr {
+        let ino = history_ino(ts);
+        self.history_ts_by_ino.insert(ino, ts);
+        let mut attr = self.get_attr(FileType::Directory, ino, 0);
+        // History is never writable.
+        attr.perm &= 0o555;
+        attr
+    }
+
+    // Attributes of /history/<ts>/<key>, or None if key had no value at ts.
+    fn get_history_key_attr(&mut self, ts: u64, key: &str) -> DriverResult<Option<FileAttr>> {
+        let value = match self.driver.get_as_of(key, ts.saturating_mul(1000))? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let ino = history_key_ino(ts, key);
+        self.history_keys_by_ino.insert(ino, (ts, key.to_string()));
+        let mut attr = self.get_attr(
+            FileType::RegularFile,
+            ino,
+            (value.len() + self.newline().len()) as u64,
+        );
+        attr.perm &= 0o555;
+        Ok(Some(attr))
+    }
+
+    fn get_history_direntries(&mut self, ino: u64) -> DriverResult<Vec<ReadDirEntry>> {
+        let ts = match self.history_ts_by_ino.get(&ino) {
+            Some(v) => *v,
+            // /history itself lists nothing, timestamps are looked up by name.
+            None => return Ok(vec![]),
+        };
+        let keys = self
+            .driver
+            .list_versioned_keys(ts.saturating_mul(1000), self.max_results())?;
+        Ok(keys
+            .into_iter()
+            .map(|key| {
+                let ino = history_key_ino(ts, &key);
+                self.history_keys_by_ino.insert(ino, (ts, key.clone()));
+                (ino, FileType::RegularFile, key)
+            })
+            .collect())
+    }
+
+    // The value of key on the mirror at index i, as of now.
+    fn mirror_value(&self, i: usize, key: &str) -> DriverResult<Option<Vec<u8>>> {
+        let driver = match self.mirrors.get(i) {
+            Some((_, driver)) => driver,
+            None => return Ok(None),
+        };
+        Ok(driver
+            .get_by_name(key.to_string(), mirror_key_ino(i, key))?
+            .map(|e| e.val))
+    }
+
+    // Attributes of /mirror/<name>/<key>, or None if the mirror has no key.
+    fn get_mirror_key_attr(&mut self, i: usize, key: &str) -> DriverResult<Option<FileAttr>> {
+        let value = match self.mirror_value(i, key)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let ino = mirror_key_ino(i, key);
+        self.mirror_keys_by_ino.insert(ino, (i, key.to_string()));
+        let mut attr = self.get_attr(
+            FileType::RegularFile,
+            ino,
+            (value.len() + self.newline().len()) as u64,
+        );
+        attr.perm &= 0o555;
+        Ok(Some(attr))
+    }
+
+    fn get_mirror_direntries(&mut self, i: usize) -> DriverResult<Vec<ReadDirEntry>> {
+        let driver = match self.mirrors.get(i) {
+            Some((_, driver)) => driver.clone(),
+            None => return Ok(vec![]),
+        };
+        let refs = driver.list_keys(0, self.max_results())?;
+        Ok(refs
+            .into_iter()
+            .map(|r| {
+                let ino = mirror_key_ino(i, &r.key);
+                self.mirror_keys_by_ino.insert(ino, (i, r.key.clone()));
+                (ino, FileType::RegularFile, r.key)
+            })
+            .collect())
+    }
+
+    // Attributes of the /pubsub/<channel> file, which always exists.
+    fn get_channel_attr(&mut self, channel: &str) -> FileAttr {
+        let ino = channel_ino(channel);
+        self.channels_by_ino.insert(ino, channel.to_string());
+        self.get_attr(FileType::RegularFile, ino, 0)
+    }
+
+    fn get_pubsub_direntries(&mut self) -> DriverResult<Vec<ReadDirEntry>> {
+        Ok(self
+            .driver
+            .channels()?
+            .into_iter()
+            .map(|channel| {
+                let ino = channel_ino(&channel);
+                self.channels_by_ino.insert(ino, channel.clone());
+                (ino, FileType::RegularFile, channel)
+            })
+            .collect())
+    }
+
+    // Send every whole line written to the channel or queue at ino through fh
+    // as a message, keeping any partial last line for the next write.
+    fn send_lines(&mut self, ino: u64, fh: u64, data: &[u8]) -> Result<(), Errno> {
+        let handle = match self.handles.get_mut(&fh) {
+            Some(v) => v,
+            None => return Err(Errno::EBADF),
+        };
+        let buffer = match handle.buffer.as_mut() {
+            Some(v) => v,
+            // Not opened for writing.
+            None => return Err(Errno::EBADF),
+        };
+        buffer.extend_from_slice(data);
+        let lines: Vec<u8> = match buffer.iter().rposition(|&b| b == b'\n') {
+            Some(end) => buffer.drain(..=end).collect(),
+            None => vec![],
+        };
+        handle.dirty = !buffer.is_empty();
+        match lines.split_last() {
+            Some((_, lines)) => self
+                .send_messages(
+                    ino,
+                    lines.split(|&b| b == b'\n').map(|m| m.to_vec()).collect(),
+                )
+                .map_err(|e| errno(&e)),
+            None => Ok(()),
+        }
+    }
+
+    // Publish messages to the channel at ino one by one, or push them onto the
+    // queue at ino together.
+    fn send_messages(&self, ino: u64, messages: Vec<Vec<u8>>) -> DriverResult<()> {
+        if let Some(channel) = self.channels_by_ino.get(&ino) {
+            for message in &messages {
+                self.driver.publish(channel, message)?;
+            }
+        } else if let Some(queue) = self.queues_by_ino.get(&ino) {
+            self.driver.push(queue, &messages)?;
+        }
+        Ok(())
+    }
+
+    // Attributes of the /queue/<name> file, which always exists.
+    fn get_queue_attr(&mut self, name: &str) -> FileAttr {
+        let ino = queue_ino(name);
+        self.queues_by_ino.insert(ino, name.to_string());
+        self.get_attr(FileType::RegularFile, ino, 0)
+    }
+
+    // What /counter/<name> reads as: the value of the key, or 0 if it has none.
+    fn counter_content(&self, name: &str) -> DriverResult<Vec<u8>> {
+        Ok(match self.current_value(name)? {
+            Some(v) => self.with_newline(&v),
+            None => self.with_newline(b"0"),
+        })
+    }
+
+    // Attributes of the /counter/<name> file, which always exists.
+    fn get_counter_attr(&mut self, name: &str) -> DriverResult<FileAttr> {
+        let size = self.counter_content(name)?.len() as u64;
+        let ino = counter_ino(name);
+        self.counters_by_ino.insert(ino, name.to_string());
+        Ok(self.get_attr(FileType::RegularFile, ino, size))
+    }
+
+    // Set the counter at ino to each number written through fh, or add each to
+    // it if fh was opened to append.
+    fn write_counter(&mut self, ino: u64, fh: u64, data: &[u8]) -> Result<(), Errno> {
+        let name = match self.counters_by_ino.get(&ino) {
+            Some(v) => v.clone(),
+            None => return Err(Errno::ENOENT),
+        };
+        let append = match self.handles.get(&fh) {
+            Some(v) => v.append,
+            None => return Err(Errno::EBADF),
+        };
+        // A coalesced write landing later would undo the change.
+        self.coalescer.flush(&name).map_err(|e| errno(&e))?;
+        let text = String::from_utf8_lossy(data);
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            // Deltas may be written +1, as well as 1.
+            let n = match line.strip_prefix('+').unwrap_or(line).parse::<i64>() {
+                Ok(v) => v,
+                Err(_) => return Err(Errno::EINVAL),
+            };
+            let result = match append {
+                true => self.driver.incr_by(&name, n).map(|_| ()),
+                false => self.driver.set(&name, n.to_string().as_bytes()),
+            };
+            result.map_err(|e| errno(&e))?;
+        }
+        self.cache.forget(&name);
+        self.hooks.fire(HookOp::Modify, &name);
+        Ok(())
+    }
+
+    // The JSON at path in the document key, or None if there's nothing there.
+    fn json_value(&self, key: &str, path: &str) -> DriverResult<Option<Value>> {
+        match self.driver.json_get(key, path)? {
+            Some(json) => serde_json::from_str(&json).map(Some).map_err(|e| {
+                DriverError::Corrupt("JSON.GET".to_string(), key.to_string(), e.to_string())
+            }),
+            None => Ok(None),
+        }
+    }
+
+    // Attributes of the node at path in the JSON document key, or None if
+    // there's nothing there. Objects and arrays are directories.
+    fn get_json_attr(&mut self, key: &str, path: &str) -> DriverResult<Option<FileAttr>> {
+        let value = match self.json_value(key, path)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let ino = json_ino(key, path);
+        self.json_nodes_by_ino
+            .insert(ino, (key.to_string(), path.to_string()));
+        let attr = match value {
+            Value::Object(_) | Value::Array(_) => self.get_attr(FileType::Directory, ino, 0),
+            v => {
+                let size = self.with_newline(&json_content(&v)).len() as u64;
+                self.get_attr(FileType::RegularFile, ino, size)
+            }
+        };
+        Ok(Some(attr))
+    }
+
+    // Key and JSONPath of the child called name of the JSON object or array at
+    // parent, or None if it can't have one.
+    fn json_child(&self, parent: u64, name: &str) -> DriverResult<Option<(String, String)>> {
+        let (key, path) = match self.json_nodes_by_ino.get(&parent) {
+            Some(v) => v.clone(),
+            None => return Ok(None),
+        };
+        let array = match self.json_value(&key, &path)? {
+            Some(Value::Object(_)) => false,
+            Some(Value::Array(_)) => true,
+            _ => return Ok(None),
+        };
+        Ok(json_child_path(&path, array, name).map(|p| (key, p)))
+    }
+
+    // Attributes of the child called name of the JSON object or array at
+    // parent, or None if it has no such child.
+    fn get_json_child_attr(&mut self, parent: u64, name: &str) -> DriverResult<Option<FileAttr>> {
+        match self.json_child(parent, name)? {
+            Some((key, path)) => self.get_json_attr(&key, &path),
+            None => Ok(None),
+        }
+    }
+
+    // Fields of the JSON object at ino, or elements of the array there. Fields
+    // whose names can't be file names are left out.
+    fn get_json_direntries(&mut self, ino: u64) -> DriverResult<Vec<ReadDirEntry>> {
+        let (key, path) = match self.json_nodes_by_ino.get(&ino) {
+            Some(v) => v.clone(),
+            None => return Ok(vec![]),
+        };
+        let (children, array): (Vec<(String, Value)>, bool) = match self.json_value(&key, &path)? {
+            Some(Value::Object(fields)) => (fields.into_iter().collect(), false),
+            Some(Value::Array(items)) => (
+                items
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, v)| (i.to_string(), v))
+                    .collect(),
+                true,
+            ),
+            _ => return Ok(vec![]),
+        };
+        Ok(children
+            .into_iter()
+            .filter(|(name, _)| !name.is_empty() && !name.contains('/'))
+            .filter_map(|(name, value)| {
+                let child = json_child_path(&path, array, &name)?;
+                let ino = json_ino(&key, &child);
+                self.json_nodes_by_ino.insert(ino, (key.clone(), child));
+                let kind = match value {
+                    Value::Object(_) | Value::Array(_) => FileType::Directory,
+                    _ => FileType::RegularFile,
+                };
+                Some((ino, kind, name))
+            })
+            .collect())
+    }
+
+    // Set the value at path in the JSON document key from content written to
+    // its file.
+    fn write_json(&mut self, key: &str, path: &str, content: &[u8]) -> DriverResult<()> {
+        let was = self.json_value(key, path)?;
+        let json = json_of(content, was.as_ref());
+        if !self.driver.json_set(key, path, &json)? {
+            return Err(DriverError::NotFound(
+                "JSON.SET".to_string(),
+                format!("{} {}", key, path),
+            ));
+        }
+        self.hooks.fire(HookOp::Modify, key);
+        Ok(())
+    }
+
+    // Attributes of the directory of the /txn session or /publish batch at
+    // path, or None if it isn't open.
+    fn get_txn_attr(&mut self, path: &str) -> Option<FileAttr> {
+        if !self.txns.contains_key(path) {
+            return None;
+        }
+        let ino = txn_ino(path);
+        self.txns_by_ino.insert(ino, path.to_string());
+        Some(self.get_attr(FileType::Directory, ino, 0))
+    }
+
+    // Whether the file at ino is one writes are committed through.
+    fn is_txn_control_ino(&self, ino: u64) -> bool {
+        match self.txn_keys_by_ino.get(&ino) {
+            Some((session, key)) => self.is_txn_control(session, key),
+            None => false,
+        }
+    }
+
+    // Whether key is the file the writes queued in session are committed
+    // through.
+    fn is_txn_control(&self, session: &str, key: &str) -> bool {
+        self.txns
+            .get(session)
+            .is_some_and(|txn| key == txn.control())
+    }
+
+    // What a file in a /txn session or /publish batch reads as: the value
+    // queued for key, or its current value if nothing is queued. None if it's
+    // to be deleted or doesn't exist.
+    fn txn_content(&self, session: &str, key: &str) -> DriverResult<Option<Vec<u8>>> {
+        let txn = match self.txns.get(session) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        // The control file lists the queued writes.
+        if key == txn.control() {
+            let lines: String = txn
+                .writes
+                .iter()
+                .filter(|(key, _)| txn.is_key(key))
+                .map(|(key, value)| match value {
+                    Some(_) => format!("SET {}\n", key),
+                    None => format!("DEL {}\n", key),
+                })
+                .collect();
+            return Ok(Some(lines.into_bytes()));
+        }
+        let value = match txn.writes.get(key) {
+            Some(queued) => queued.clone(),
+            // Batches only hold what's copied into them.
+            None if txn.publish => None,
+            None => self.current_value(key)?,
+        };
+        Ok(value.map(|v| self.with_newline(&v)))
+    }
+
+    // Attributes of key's file in a /txn session or /publish batch, or None if
+    // there's no such file.
+    fn get_txn_key_attr(&mut self, session: &str, key: &str) -> DriverResult<Option<FileAttr>> {
+        let size = match self.txn_content(session, key)? {
+            Some(v) => v.len() as u64,
+            None => return Ok(None),
+        };
+        let ino = txn_key_ino(session, key);
+        self.txn_keys_by_ino
+            .insert(ino, (session.to_string(), key.to_string()));
+        Ok(Some(self.get_attr(FileType::RegularFile, ino, size)))
+    }
+
+    // Open sessions in /txn or batches in /publish, or the control file and
+    // keys with a value queued in the one at ino.
+    fn get_txn_direntries(&mut self, ino: u64) -> Vec<ReadDirEntry> {
+        if let Some(root) = txn_path(ino, "") {
+            let paths: Vec<String> = self
+                .txns
+                .keys()
+                .filter(|p| p.starts_with(&root))
+                .cloned()
+                .collect();
+            return paths
+                .into_iter()
+                .map(|path| {
+                    let ino = txn_ino(&path);
+                    let name = path[root.len()..].to_string();
+                    self.txns_by_ino.insert(ino, path);
+                    (ino, FileType::Directory, name)
+                })
+                .collect();
+        }
+        let session = match self.txns_by_ino.get(&ino) {
+            Some(v) => v.clone(),
+            None => return vec![],
+        };
+        let mut names = vec![];
+        if let Some(txn) = self.txns.get(&session) {
+            names.push(txn.control().to_string());
+            names.extend(
+                txn.writes
+                    .iter()
+                    .filter(|(_, v)| v.is_some())
+                    .map(|(k, _)| k.clone()),
+            );
+        }
+        names
+            .into_iter()
+            .map(|key| {
+                let ino = txn_key_ino(&session, &key);
+                self.txn_keys_by_ino
+                    .insert(ino, (session.clone(), key.clone()));
+                (ino, FileType::RegularFile, key)
+            })
+            .collect()
+    }
+
+    // Queue setting key to value in session, or deleting it if there's none.
+    fn queue_txn_write(
+        &mut self,
+        session: &str,
+        key: &str,
+        value: Option<Vec<u8>>,
+    ) -> Result<(), Errno> {
+        match self.txns.get_mut(session) {
+            Some(txn) => {
+                txn.writes.insert(key.to_string(), value);
+                Ok(())
+            }
+            None => Err(Errno::ENOENT),
+        }
+    }
+
+    // Run every write queued in session as one transaction, leaving it empty,
+    // or still queued if the transaction fails.
+    fn commit_txn(&mut self, session: &str) -> DriverResult<usize> {
+        let writes: Vec<(String, Option<Vec<u8>>)> = match self.txns.get(session) {
+            Some(txn) => txn.writes.clone().into_iter().collect(),
+            None => return Ok(0),
+        };
+        self.apply_txn(&writes)?;
+        if let Some(txn) = self.txns.get_mut(session) {
+            txn.writes.clear();
+        }
+        Ok(writes.len())
+    }
+
+    // Check the files in the /publish batch against its manifest, and set
+    // their keys in one transaction if every one matches, closing the batch.
+    fn publish_batch(&mut self, batch: &str) -> Result<usize, Errno> {
+        let mut writes = match self.txns.get(batch) {
+            Some(txn) => txn.writes.clone(),
+            None => return Err(Errno::ENOENT),
+        };
+        let manifest = match writes.remove(PUBLISH_MANIFEST) {
+            Some(Some(v)) => v,
+            _ => {
+                log::error!("Refusing to publish {} without a manifest.", batch);
+                return Err(Errno::EBADMSG);
+            }
+        };
+        // Lines as sha256sum prints them, with * before binary files.
+        let mut expected = HashMap::new();
+        for line in String::from_utf8_lossy(&manifest).lines() {
+            let (digest, name) = match line.trim_end().split_once(' ') {
+                Some(v) => v,
+                None if line.trim().is_empty() => continue,
+                None => return Err(Errno::EBADMSG),
+            };
+            let name = name.strip_prefix(|c| c == ' ' || c == '*').unwrap_or(name);
+            let name = name.strip_prefix("./").unwrap_or(name);
+            expected.insert(name.to_string(), digest.to_lowercase());
+        }
+        for (key, value) in &writes {
+            let value = value.as_deref().unwrap_or_default();
+            // Checksums are of the file as copied, which may have had the
+            // newline reads add.
+            let matches = match expected.remove(key) {
+                Some(digest) => {
+                    digest == sha256_hex(value) || digest == sha256_hex(&self.with_newline(value))
+                }
+                None => false,
+            };
+            if !matches {
+                log::error!(
+                    "Refusing to publish {}: {} doesn't match its manifest.",
+                    batch,
+                    key
+                );
+                return Err(Errno::EBADMSG);
+            }
+        }
+        if let Some(key) = expected.keys().next() {
+            log::error!("Refusing to publish {}: {} is missing.", batch, key);
+            return Err(Errno::EBADMSG);
+        }
+        let writes: Vec<(String, Option<Vec<u8>>)> = writes.into_iter().collect();
+        if let Err(e) = self.apply_txn(&writes) {
+            log::error!("Error publishing {}: {}", batch, e);
+            return Err(errno(&e));
+        }
+        self.txns.remove(batch);
+        Ok(writes.len())
+    }
+
+    // Run writes as one transaction, and forget what was cached of their keys.
+    fn apply_txn(&mut self, writes: &[(String, Option<Vec<u8>>)]) -> DriverResult<()> {
+        // Writes held back by coalescing are older, so mustn't land after.
+        for (key, _) in writes {
+            self.coalescer.flush(key)?;
+        }
+        self.driver.transact(writes)?;
+        for (key, value) in writes {
+            self.cache.forget(key);
+            match value {
+                Some(_) => self.hooks.fire(HookOp::Modify, key),
+                None => {
+                    self.kv_keys_by_ino.retain(|_, k| k != key);
+                    self.hooks.fire(HookOp::Delete, key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Attributes of the /dump/<prefix> file, which always exists. Its size is
+    // only known once read, so it's left 0 and read with direct IO.
+    fn get_dump_attr(&mut self, prefix: &str) -> FileAttr {
+        let ino = dump_ino(prefix);
+        self.dumps_by_ino.insert(ino, prefix.to_string());
+        self.get_attr(FileType::RegularFile, ino, 0)
+    }
+
+    // Every key starting with prefix, as RESTORE commands recreating them.
+    fn dump_content(&mut self, prefix: &str) -> DriverResult<Vec<u8>> {
+        let pattern = format!("{}*", escape_glob(prefix));
+        let mut out = vec![];
+        let mut cursor = String::new();
+        loop {
+            let (refs, next) = self.driver.scan_keys(&pattern, &cursor, DUMP_BATCH)?;
+            for r in refs {
+                // Writes held back would otherwise be missing.
+                self.coalescer.flush(&r.key)?;
+                // The key expired or was deleted since it was scanned.
+                let (payload, ttl) = match self.driver.dump(&r.key)? {
+                    Some(v) => v,
+                    None => continue,
                 };
-                reply.data(&format!("{}\n", String::from_utf8_lossy(&value)).as_bytes());
+                let ttl = ttl.to_string();
+                let args: [&[u8]; 5] = [
+                    b"RESTORE",
+                    r.key.as_bytes(),
+                    ttl.as_bytes(),
+                    &payload,
+                    b"REPLACE",
+                ];
+                // Writing to memory can't fail.
+                write_command(&mut out, &args).ok();
             }
-            // TODO add ranges for /lock
-            _ => reply.error(ENOENT),
+            cursor = match next {
+                Some(v) => v,
+                None => return Ok(out),
+            };
+        }
+    }
+
+    // Restore every key content, as read from /dump/<prefix>, holds,
+    // returning how many. Nothing is restored unless it's all RESTORE
+    // commands for keys starting with prefix.
+    fn restore_dump(&mut self, prefix: &str, content: &[u8]) -> DriverResult<usize> {
+        let malformed = |reason: &str| {
+            DriverError::Malformed(
+                "RESTORE".to_string(),
+                prefix.to_string(),
+                reason.to_string(),
+            )
         };
+        let mut reader = content;
+        let mut restores = vec![];
+        loop {
+            let args = match read_command(&mut reader) {
+                Ok(Some((args, _))) => args,
+                Ok(None) => break,
+                Err(_) => return Err(malformed("not RESP-encoded commands")),
+            };
+            if args.len() < 4 || !args[0].eq_ignore_ascii_case(b"RESTORE") {
+                return Err(malformed("not a RESTORE command"));
+            }
+            let key = String::from_utf8_lossy(&args[1]).to_string();
+            if !key.starts_with(prefix) {
+                return Err(malformed(&format!("{} doesn't start with the prefix", key)));
+            }
+            let ttl = match String::from_utf8_lossy(&args[2]).parse::<u64>() {
+                Ok(v) => v,
+                Err(_) => return Err(malformed(&format!("bad time to live for {}", key))),
+            };
+            restores.push((key, ttl, args[3].clone()));
+        }
+        for (key, ttl, payload) in &restores {
+            // Writes held back by coalescing are older, so mustn't land after.
+            self.coalescer.discard(key);
+            self.driver.restore(key, *ttl, payload)?;
+            self.cache.forget(key);
+            self.hooks.fire(HookOp::Modify, key);
+        }
+        Ok(restores.len())
     }
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        log::debug!("readdir for inode {} via filehandle {}", ino, fh);
-        let cur_dir: DirEntry = curdir!(self, ino);
-        let mut entries: Vec<ReadDirEntry> = vec![(1, FileType::Directory, "..".to_string())];
-        // We have to always include the root dir at inode 1, if we push it
-        // unconditionally we end up with duplicated `.` entries.
-        if ino != 1 {
-            entries.push((cur_dir.0, cur_dir.1, cur_dir.3));
+    // Attributes of the /find/<glob> directory, which always exists.
+    fn get_find_attr(&mut self, pattern: &str) -> FileAttr {
+        let ino = find_ino(pattern);
+        self.finds_by_ino.insert(ino, pattern.to_string());
+        self.get_attr(FileType::Directory, ino, 0)
+    }
+
+    // Attributes of the /find/<glob>/<key> link, or None if key doesn't match
+    // pattern or doesn't exist.
+    fn get_find_link_attr(&mut self, pattern: &str, key: &str) -> DriverResult<Option<FileAttr>> {
+        if key.contains('/') || !glob_regex(pattern).is_some_and(|r| r.is_match(key)) {
+            return Ok(None);
         }
+        // Keys that aren't files under /kv, eg. JSON documents, are linked
+        // to all the same.
+        match self.get_kv_attr(key) {
+            Ok(Some(_)) | Err(DriverError::WrongType(..)) => {}
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let ino = find_link_ino(pattern, key);
+        self.find_links_by_ino
+            .insert(ino, (pattern.to_string(), key.to_string()));
+        let target = find_link_target(key);
+        Ok(Some(self.get_attr(
+            FileType::Symlink,
+            ino,
+            target.len() as u64,
+        )))
+    }
 
-        // Root dir
-        entries.extend(match ino {
-            // Root dir
-            1 => self.direntries_by_parent_ino[&ino]
-                .iter()
-                .map(|(_, v)| (v.0, v.1, v.3.clone()))
-                .collect::<Vec<ReadDirEntry>>(),
+    // Attributes of the /kv/.match/<pattern> directory, which always exists.
+    fn get_match_attr(&mut self, pattern: &str) -> FileAttr {
+        let ino = match_ino(pattern);
+        self.patterns_by_ino.insert(ino, pattern.to_string());
+        self.get_attr(FileType::Directory, ino, 0)
+    }
+
+    fn get_match_direntries(&mut self, ino: u64) -> DriverResult<Vec<ReadDirEntry>> {
+        let pattern = match self.patterns_by_ino.get(&ino) {
+            Some(v) => v.clone(),
+            // /kv/.match itself lists nothing, patterns are looked up by name.
+            None => return Ok(vec![]),
+        };
+        let refs = self.driver.match_keys(&pattern, self.max_results())?;
+        let staged = self
+            .staged_deletes
+            .get(&pattern)
+            .cloned()
+            .unwrap_or_default();
+        Ok(refs
+            .into_iter()
+            .filter(|r| !staged.contains(&r.key))
+            .map(|r| {
+                self.kv_keys_by_ino.insert(r.ino, r.key.clone());
+                (r.ino, FileType::RegularFile, r.key)
+            })
+            .collect())
+    }
+
+    // Refuse to delete keys matching pattern in bulk until confirmed, handing
+    // out a token to confirm it with through /.fusekv/confirm.
+    fn refuse_bulk_delete(&mut self, pattern: &str) {
+        log::info!(
+            "Refusing to delete keys matching {} in bulk without confirmation.",
+            pattern
+        );
+        self.delete_tokens
+            .entry(pattern.to_string())
+            .or_insert_with(|| new_confirm_token(pattern));
+    }
+
+    // Whether key was unlinked from the /kv/.match/<pattern> directory at ino.
+    fn is_staged(&self, ino: u64, key: &str) -> bool {
+        match self.patterns_by_ino.get(&ino) {
+            Some(pattern) => self
+                .staged_deletes
+                .get(pattern)
+                .is_some_and(|s| s.contains(key)),
+            None => false,
+        }
+    }
+
+    // Attributes of the /tags/<tag> directory, or None if nothing is tagged tag.
+    fn get_tag_attr(&mut self, tag: &str) -> DriverResult<Option<FileAttr>> {
+        if self.driver.tagged(tag)?.is_empty() {
+            return Ok(None);
+        }
+        let ino = tag_ino(tag);
+        self.tags_by_ino.insert(ino, tag.to_string());
+        Ok(Some(self.get_attr(FileType::Directory, ino, 0)))
+    }
+
+    fn get_tag_direntries(&mut self, ino: u64) -> DriverResult<Vec<ReadDirEntry>> {
+        // /tags
+        if ino == 3072 {
+            let tags = self.driver.list_tags()?;
+            return Ok(tags
+                .into_iter()
+                .map(|tag| {
+                    let ino = tag_ino(&tag);
+                    self.tags_by_ino.insert(ino, tag.clone());
+                    (ino, FileType::Directory, tag)
+                })
+                .collect());
+        }
+        let keys = match self.tags_by_ino.get(&ino) {
+            Some(tag) => self.driver.tagged(tag)?,
+            None => return Ok(vec![]),
+        };
+        // Tagged keys share their inode with /kv/<key>.
+        let inos = self.driver.inos_of(&keys)?;
+        Ok(keys
+            .into_iter()
+            .zip(inos)
+            .map(|(key, ino)| {
+                self.kv_keys_by_ino.insert(ino, key.clone());
+                (ino, FileType::RegularFile, key)
+            })
+            .collect())
+    }
+
+    // Key of the /kv entry called name under parent, if parent is /kv or a /kv
+    // namespace.
+    fn kv_key_under(&self, parent: u64, name: &str) -> Option<String> {
+        match parent {
             // /kv
-            4096 => match self.get_kv_direntries() {
-                Ok(v) => v,
-                Err(e) => {
-                    log::error!("Error listing root directory: {}", e);
-                    reply.error(EAGAIN);
-                    return;
-                }
-            },
-            _ => {
-                reply.error(ENOENT);
+            4096 => Some(name.to_string()),
+            NAMESPACE_START..=NAMESPACE_END => {
+                let separator = self.config.separator.as_deref()?;
+                self.namespaces_by_ino
+                    .get(&parent)
+                    .map(|n| format!("{}{}{}", n, separator, name))
+            }
+            _ => None,
+        }
+    }
+
+    // Create the /kv directory of namespace, which only exists in this mount
+    // until a key is written beneath it.
+    fn make_namespace(&mut self, namespace: String, reply: ReplyEntry) {
+        let exists = match self.current_value(&namespace) {
+            Ok(Some(_)) => Ok(true),
+            Ok(None) => self.get_namespace_attr(&namespace).map(|a| a.is_some()),
+            Err(e) => Err(e),
+        };
+        match exists {
+            Ok(true) => {
+                reply.error(Errno::EEXIST);
                 return;
             }
-        });
+            Ok(false) => {}
+            Err(e) => {
+                reply.error(errno(&e));
+                return;
+            }
+        };
+        self.kv_dirs.insert(namespace.clone());
+        match self.get_namespace_attr(&namespace) {
+            Ok(Some(attr)) => reply.entry(&self.entry_ttl(attr.ino.0), &attr, Generation(0)),
+            Ok(None) => reply.error(Errno::ENOENT),
+            Err(e) => reply.error(errno(&e)),
+        }
+    }
 
-        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
-            // i + 1 means the index of the next entry
-            if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
-                break;
+    // Remove the /kv directory of namespace, if only mkdir created it and
+    // nothing is beneath it.
+    fn remove_namespace(&mut self, namespace: &str, reply: Audited<ReplyEmpty>) {
+        let separator = self.config.separator.clone().unwrap_or_default();
+        let prefix = format!("{}{}", namespace, separator);
+        let keys = match self
+            .driver
+            .match_keys(&format!("{}*", escape_glob(&prefix)), 1)
+        {
+            Ok(v) => v,
+            Err(e) => {
+                reply.error(errno(&e));
+                return;
             }
+        };
+        if keys.iter().any(|r| r.key.starts_with(&prefix))
+            || self.kv_dirs.iter().any(|d| d.starts_with(&prefix))
+        {
+            reply.error(Errno::ENOTEMPTY);
+        } else if self.kv_dirs.remove(namespace) {
+            self.namespaces_by_ino.remove(&namespace_ino(namespace));
+            reply.ok();
+        } else {
+            reply.error(Errno::ENOENT);
         }
-        reply.ok();
     }
-}
 
-impl KVFS {
-    // Initialize all the static dirs based on the KVFS config.
-    // The root dir reserves the first 8192 inodes (13 bits), leaving 51 bits for
-    // remaining keys (~2 quadrillion values).
-    pub fn init_static_dirs(&mut self) {
-        log::debug!("Building static directory list.");
-        let mut root_entries: Vec<DirEntry> = vec![];
-        if !self.config.disable_raw {
-            log::debug!("Setting up /raw, to disable set disable_raw=true.");
-            root_entries.push(curdir!(self, 1));
-            root_entries.push((
-                2,
-                FileType::RegularFile,
-                self.get_attr("/raw", FileType::RegularFile, 2, 0),
-                "raw".to_string(),
-                None,
-            ));
-            root_entries.push((
-                3,
-                FileType::RegularFile,
-                self.get_attr("/raw:help", FileType::RegularFile, 3, RAW_HELP.len() as u64),
-                "raw:help".to_string(),
-                Some(RAW_HELP.to_string()),
-            ));
+    // Attributes of the /kv directory of namespace, or None if it doesn't exist.
+    // Namespaces exist if they were created in this mount or have a key anywhere
+    // beneath them.
+    fn get_namespace_attr(&mut self, namespace: &str) -> DriverResult<Option<FileAttr>> {
+        let prefix = match &self.config.separator {
+            Some(separator) => format!("{}{}", namespace, separator),
+            None => return Ok(None),
+        };
+        if !self.kv_dirs.contains(namespace)
+            && !self.kv_dirs.iter().any(|d| d.starts_with(&prefix))
+            && !self
+                .driver
+                .match_keys(&format!("{}*", escape_glob(&prefix)), 1)?
+                .iter()
+                .any(|r| r.key.starts_with(&prefix))
+        {
+            return Ok(None);
         }
+        let ino = namespace_ino(namespace);
+        self.namespaces_by_ino.insert(ino, namespace.to_string());
+        Ok(Some(self.get_attr(FileType::Directory, ino, 0)))
+    }
 
-        log::debug!("Setting up /lock.");
-        root_entries.push((
-            2048,
-            FileType::Directory,
-            self.get_attr("/lock", FileType::Directory, 2048, 0),
-            "lock".to_string(),
-            None,
-        ));
-        root_entries.push((
-            2049,
-            FileType::RegularFile,
-            self.get_attr(
-                "/lock:help",
-                FileType::RegularFile,
-                2049,
-                LOCK_HELP.len() as u64,
-            ),
-            "lock:help".to_string(),
-            Some(LOCK_HELP.to_string()),
-        ));
+    // Name of the lock called name under parent, if parent is /lock or a lock
+    // namespace.
+    fn lock_child_name(&self, parent: u64, name: &str) -> Option<String> {
+        match parent {
+            // /lock
+            2048 => Some(name.to_string()),
+            LOCK_START..=LOCK_END => self
+                .lock_names_by_ino
+                .get(&parent)
+                .map(|p| format!("{}/{}", p, name)),
+            _ => None,
+        }
+    }
 
-        log::debug!("Setting up /kv.");
-        root_entries.push((
-            4096,
-            FileType::Directory,
-            self.get_attr("/kv", FileType::Directory, 4096, 0),
-            "kv".to_string(),
-            None,
-        ));
-        root_entries.push((
-            4097,
-            FileType::RegularFile,
-            self.get_attr(
-                "/kv:help",
-                FileType::RegularFile,
-                4097,
-                KV_HELP.len() as u64,
-            ),
-            "kv:help".to_string(),
-            Some(KV_HELP.to_string()),
-        ));
+    // Attributes of a lock or lock namespace as seen by viewer, or None if it
+    // doesn't exist. Namespaces exist if they were created in this mount or
+    // have a held lock anywhere beneath them.
+    fn get_lock_attr(
+        &mut self,
+        lock: &str,
+        viewer: Option<&str>,
+    ) -> DriverResult<Option<FileAttr>> {
+        let ino = lock_ino(lock);
+        let attr = if self.lock_dirs.contains(lock)
+            || !self
+                .visible_locks(&format!("{}/", lock), viewer)?
+                .is_empty()
+        {
+            self.get_attr(FileType::Directory, ino, 0)
+        } else {
+            match self.driver.lock_owner(lock)? {
+                Some(owner) if viewer.is_none_or(|v| v == owner) => {
+                    self.get_attr(FileType::RegularFile, ino, (owner.len() + 1) as u64)
+                }
+                Some(_) if self.config.lock_privacy == LockPrivacy::Stub => {
+                    let mut attr = self.get_attr(FileType::RegularFile, ino, 0);
+                    attr.perm = 0;
+                    attr
+                }
+                _ => return Ok(None),
+            }
+        };
+        self.lock_names_by_ino.insert(ino, lock.to_string());
+        Ok(Some(attr))
+    }
 
-        self.direntries_by_parent_ino.insert(
-            1,
-            root_entries
-                .iter()
-                .map(|e| (e.3.clone(), e.clone()))
-                .collect(),
-        );
-        for e in root_entries {
-            self.direntries_by_ino.insert(e.0, e.clone());
-        }
-    }
-
-    fn get_kv_direntries(&mut self) -> Result<Vec<ReadDirEntry>, Box<dyn error::Error>> {
-        // TODO figure out how to work with cluster mode
-        let mut conn = self.pool.clone().unwrap().get()?;
-        let iter: redis::Iter<String> =
-            redis::cmd("SCAN").cursor_arg(0).clone().iter(&mut *conn)?;
-        let mut entries: Vec<ReadDirEntry> = vec![];
-        let mut ino_cache = INO_CACHE.write()?;
-        for (i, key) in iter.enumerate() {
-            if self.config.max_results == -1 || self.config.max_results > i as i64 {
-                let key_str = key.to_string();
-                let ino = seahash::hash(key_str.as_bytes()) % (KV_END - KV_START) + KV_START;
-                // TODO support hsets by setting them to Directory
-                // TODO define a lua function that does the scan and returns the
-                // key type and size along with it.
-                entries.push((ino, FileType::RegularFile, key_str.clone()));
-                ino_cache.put(ino, key_str.clone());
-            } else {
-                break;
+    // Who req is as far as lock_privacy is concerned, or None if they may see
+    // every lock.
+    fn lock_viewer(&self, req: &Request) -> Option<String> {
+        match self.config.lock_privacy {
+            LockPrivacy::Off => None,
+            _ if req.uid() == 0 => None,
+            _ => Some(lock_owner(req)),
+        }
+    }
+
+    // Names of the held locks starting with prefix that viewer may see listed.
+    fn visible_locks(&self, prefix: &str, viewer: Option<&str>) -> DriverResult<Vec<String>> {
+        let locks = self.driver.list_locks(prefix)?;
+        let viewer = match viewer {
+            Some(v) if self.config.lock_privacy == LockPrivacy::Hide => v,
+            _ => return Ok(locks),
+        };
+        let mut visible = vec![];
+        for lock in locks {
+            if self.driver.lock_owner(&lock)?.as_deref() == Some(viewer) {
+                visible.push(lock);
             }
         }
-        Ok(entries)
+        Ok(visible)
+    }
+
+    fn get_lock_direntries(&mut self, ino: u64, req: &Request) -> DriverResult<Vec<ReadDirEntry>> {
+        let prefix = match ino {
+            // /lock
+            2048 => "".to_string(),
+            _ => match self.lock_names_by_ino.get(&ino) {
+                Some(v) => format!("{}/", v),
+                None => return Ok(vec![]),
+            },
+        };
+        // Only the immediate children of this namespace are listed, anything
+        // nested further implies a namespace directory.
+        let mut children: BTreeMap<String, FileType> = BTreeMap::new();
+        let viewer = self.lock_viewer(req);
+        let names = self
+            .visible_locks(&prefix, viewer.as_deref())?
+            .into_iter()
+            .map(|n| (n, FileType::RegularFile))
+            .chain(
+                self.lock_dirs
+                    .iter()
+                    .filter(|d| d.starts_with(&prefix))
+                    .map(|d| (d.clone(), FileType::Directory)),
+            );
+        for (name, kind) in names {
+            let rest = &name[prefix.len()..];
+            match rest.find('/') {
+                Some(i) => {
+                    children.insert(rest[..i].to_string(), FileType::Directory);
+                }
+                None => {
+                    children.entry(rest.to_string()).or_insert(kind);
+                }
+            };
+        }
+        Ok(children
+            .into_iter()
+            .map(|(name, kind)| {
+                let lock = format!("{}{}", prefix, name);
+                let ino = lock_ino(&lock);
+                self.lock_names_by_ino.insert(ino, lock);
+                (ino, kind, name)
+            })
+            .collect())
     }
 
-    fn get_attr(&mut self, path: &str, kind: FileType, ino: u64, size: u64) -> FileAttr {
+    fn get_attr(&mut self, kind: FileType, ino: u64, size: u64) -> FileAttr {
         // TODO implement permissions adjustments from config.
         let now = SystemTime::now();
         FileAttr {
-            ino: ino,
-            size: size,
+            ino: INodeNo(ino),
+            size,
             blocks: 0,
             atime: now,
             mtime: now,
             ctime: now,
             crtime: now,
-            kind: kind,
+            kind,
             perm: self.config.chmod,
             nlink: 1,
             uid: self.config.uid,
@@ -430,3 +8139,386 @@ impl KVFS {
         }
     }
 }
+
+// The command, in the form ACL DRYRUN takes, that mutating the /kv entry at
+// path with cmd amounts to, or None if path isn't a /kv entry, counter, queue,
+// or channel.
+fn acl_command(cmd: &str, path: Option<String>) -> Option<Vec<String>> {
+    let path = path?;
+    // Writing to a channel publishes to it rather than setting anything.
+    if let Some(channel) = path.strip_prefix("/pubsub/") {
+        return Some(vec![
+            "PUBLISH".to_string(),
+            channel.to_string(),
+            String::new(),
+        ]);
+    }
+    // As does writing to a queue push onto it.
+    if let Some(queue) = path.strip_prefix("/queue/") {
+        return Some(vec!["LPUSH".to_string(), queue.to_string(), String::new()]);
+    }
+    // Counters are keys too.
+    let key = path
+        .strip_prefix("/kv/")
+        .or_else(|| path.strip_prefix("/counter/"))?
+        .to_string();
+    if key.contains('/') {
+        return None;
+    }
+    Some(match cmd {
+        // SET needs a value, which doesn't change whether it's allowed.
+        "SET" => vec![cmd.to_string(), key, String::new()],
+        _ => vec![cmd.to_string(), key],
+    })
+}
+
+// A regex matching what the Redis glob pattern does: * and ? match any run of
+// characters or any one, [...] any in the class, and \ escapes the next.
+pub fn glob_regex(pattern: &str) -> Option<Regex> {
+    // Keys may hold newlines, which . should match too.
+    let mut re = String::from("(?s)^");
+    let mut chars = pattern.chars();
+    let mut in_class = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next) => re.push_str(&regex::escape(&next.to_string())),
+                None => re.push_str(r"\\"),
+            },
+            ']' if in_class => {
+                in_class = false;
+                re.push(']');
+            }
+            '^' if in_class && re.ends_with('[') => re.push('^'),
+            '-' if in_class => re.push('-'),
+            _ if in_class => re.push_str(&regex::escape(&c.to_string())),
+            '[' => {
+                in_class = true;
+                re.push('[');
+            }
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            _ => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    Regex::new(&re).ok()
+}
+
+// pattern with every character Redis globs treat specially escaped.
+pub fn escape_glob(pattern: &str) -> String {
+    let mut escaped = String::new();
+    for c in pattern.chars() {
+        if let '*' | '?' | '[' | ']' | '\\' = c {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// The primary and supplementary groups of the process behind req. FUSE only
+// tells us the former, so the rest come from /proc.
+fn request_groups(req: &Request) -> Vec<u32> {
+    let mut groups = vec![req.gid()];
+    let status = fs::read_to_string(format!("/proc/{}/status", req.pid())).unwrap_or_default();
+    if let Some(line) = status.lines().find(|l| l.starts_with("Groups:")) {
+        groups.extend(
+            line["Groups:".len()..]
+                .split_whitespace()
+                .filter_map(|g| g.parse::<u32>().ok()),
+        );
+    }
+    groups
+}
+
+// Locks are owned per user per host, so any process run by the user that took
+// a lock can release it.
+fn lock_owner(req: &Request) -> String {
+    format!(
+        "{}:{}",
+        whoami::fallible::hostname().unwrap_or_default(),
+        req.uid()
+    )
+}
+
+// The kind of values stored directly under parent, or None if it doesn't hold
+// values.
+fn value_kind(parent: u64) -> Option<ValueKind> {
+    match parent {
+        // /kv and /kv namespaces
+        4096 | NAMESPACE_START..=NAMESPACE_END => Some(ValueKind::String),
+        // /queue, whose queues are lists
+        QUEUE_DIR | QUEUE_START..=QUEUE_END => Some(ValueKind::List),
+        _ => None,
+    }
+}
+
+// Map a driver error to the errno to reply with.
+pub fn errno(e: &DriverError) -> Errno {
+    match e {
+        DriverError::Unsupported(_) => Errno::ENOTSUP,
+        DriverError::NotFound(..) => Errno::ENOENT,
+        DriverError::WrongType(..) => Errno::EINVAL,
+        DriverError::Timeout(..) => {
+            log::warn!("Driver error: {}", e);
+            Errno::ETIMEDOUT
+        }
+        DriverError::PermissionDenied(..) => {
+            log::warn!("Driver error: {}", e);
+            Errno::EACCES
+        }
+        // Only returned once retries are exhausted, so callers shouldn't
+        // just try again.
+        DriverError::Unavailable(..) => {
+            log::error!("Driver error: {}", e);
+            Errno::EIO
+        }
+        DriverError::Corrupt(..) => {
+            log::error!("Driver error: {}", e);
+            Errno::EIO
+        }
+        DriverError::OutOfMemory(..) => {
+            log::warn!("Driver error: {}", e);
+            Errno::ENOSPC
+        }
+        DriverError::Malformed(..) => {
+            log::warn!("Driver error: {}", e);
+            Errno::EINVAL
+        }
+    }
+}
+
+// Whether a dotted version string, eg. 6.2.5, is at least major.minor.
+fn version_at_least(version: &str, min: (u64, u64)) -> bool {
+    let mut parts = version.split('.').map(|p| p.parse::<u64>().unwrap_or(0));
+    let major = parts.next().unwrap_or(0);
+    let minor = parts.next().unwrap_or(0);
+    (major, minor) >= min
+}
+
+// Check value may be written to key, per the validation rules matching its
+// path, keeping why not in last_rejects for LAST_REJECT_XATTR.
+fn validate_write(
+    config: &Config,
+    last_rejects: &mut HashMap<String, String>,
+    key: &str,
+    value: &[u8],
+) -> DriverResult<()> {
+    if config.validation.is_empty() {
+        return Ok(());
+    }
+    match validate::check(&config.validation, &format!("/kv/{}", key), key, value) {
+        Ok(()) => {
+            last_rejects.remove(key);
+            Ok(())
+        }
+        Err(reason) => {
+            last_rejects.insert(key.to_string(), reason.clone());
+            Err(DriverError::Malformed(
+                "SET".to_string(),
+                key.to_string(),
+                reason,
+            ))
+        }
+    }
+}
+
+// The value to store for file content written through the mount. Reads add a
+// trailing \n if append_newline is set, so one is dropped here to round-trip.
+fn stored_value(content: &[u8], append_newline: bool) -> Vec<u8> {
+    match append_newline {
+        true => content.strip_suffix(b"\n").unwrap_or(content).to_vec(),
+        false => content.to_vec(),
+    }
+}
+
+// Split a line written to /raw into arguments on whitespace. Arguments can be
+// quoted with ' or " to keep whitespace, and \ escapes the next character
+// inside double quotes. None if a quote is left open.
+fn split_command(line: &str) -> Option<Vec<String>> {
+    let mut args = vec![];
+    let mut arg: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        q if q == c => break,
+                        '\\' if c == '"' => arg.push(chars.next()?),
+                        other => arg.push(other),
+                    }
+                }
+            }
+            _ if c.is_whitespace() => args.extend(arg.take()),
+            _ => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+    Some(args)
+}
+
+// Milliseconds since the epoch at t.
+fn epoch_millis(t: SystemTime) -> i64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+// A fresh token for /.fusekv/confirm to confirm deleting keys matching
+// pattern with.
+fn new_confirm_token(pattern: &str) -> String {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut seed = now.as_nanos().to_le_bytes().to_vec();
+    seed.extend_from_slice(pattern.as_bytes());
+    format!("{:016x}", seahash::hash(&seed))
+}
+
+// Up to size bytes of entry's value from offset, and its whole length, for
+// drivers that can only fetch whole values.
+pub fn value_range(entry: Option<KVEntry>, offset: u64, size: u64) -> Option<(Vec<u8>, u64)> {
+    let value = entry?.val;
+    let start = (offset as usize).min(value.len());
+    let end = (offset.saturating_add(size) as usize).min(value.len());
+    Some((value[start..end].to_vec(), value.len() as u64))
+}
+
+// A tail of the stream whose entries read as value.
+fn new_tail(value: &[u8]) -> Tail {
+    let mut content = value.to_vec();
+    if !content.is_empty() {
+        content.push(b'\n');
+    }
+    let last_id = String::from_utf8_lossy(value)
+        .lines()
+        .last()
+        .and_then(|l| l.split(' ').next())
+        .unwrap_or("0-0")
+        .to_string();
+    Tail { content, last_id }
+}
+
+// Reply to a read of size bytes from offset through a handle tailing the
+// stream at key, waiting up to timeout for new entries if it's past the end of
+// those read so far.
+fn read_tail(
+    driver: &dyn Driver,
+    key: &str,
+    tail: &Mutex<Tail>,
+    timeout: Option<Duration>,
+    offset: i64,
+    size: u32,
+    reply: Audited<ReplyData>,
+) {
+    let mut tail = tail.lock().unwrap();
+    if offset.max(0) as usize >= tail.content.len() {
+        match driver.stream_after(key, &tail.last_id, timeout) {
+            Ok(entries) if entries.is_empty() => {
+                reply.error(Errno::ETIMEDOUT);
+                return;
+            }
+            Ok(entries) => {
+                for entry in entries {
+                    tail.content.extend_from_slice(entry.line().as_bytes());
+                    tail.content.push(b'\n');
+                    tail.last_id = entry.id;
+                }
+            }
+            Err(e) => {
+                reply.error(errno(&e));
+                return;
+            }
+        }
+    }
+    reply.data(window(&tail.content, offset, size));
+}
+
+// Reply to a read of up to size bytes through a handle subscribed to a
+// channel or queue, waiting up to timeout for a message if it has none pending
+// before failing with timed_out.
+fn read_messages(
+    subscriber: &Mutex<Subscriber>,
+    timeout: Option<Duration>,
+    timed_out: Errno,
+    size: u32,
+    reply: Audited<ReplyData>,
+) {
+    let mut subscriber = subscriber.lock().unwrap();
+    if subscriber.pending.is_empty() {
+        match subscriber.subscription.next_message(timeout) {
+            Ok(Some(message)) => {
+                subscriber.pending.extend_from_slice(&message);
+                subscriber.pending.push(b'\n');
+            }
+            Ok(None) => {
+                reply.error(timed_out);
+                return;
+            }
+            Err(e) => {
+                reply.error(errno(&e));
+                return;
+            }
+        }
+    }
+    let n = subscriber.pending.len().min(size as usize);
+    reply.data(&subscriber.pending.drain(..n).collect::<Vec<u8>>());
+}
+
+// A read of size bytes from offset of key: the whole value, without newline,
+// if it's no longer than keep_up_to, or otherwise only the range read, with
+// newline added if it reaches the end.
+fn fetch_value(
+    driver: &dyn Driver,
+    key: &str,
+    offset: u64,
+    size: u64,
+    keep_up_to: Option<u64>,
+    newline: &str,
+) -> DriverResult<Option<Fetched>> {
+    let (mut data, len) = match driver.read_range(key, offset, size)? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    if keep_up_to.is_some_and(|max| len <= max) {
+        if offset == 0 && data.len() as u64 == len {
+            return Ok(Some(Fetched::Whole(data)));
+        }
+        // In one go, so it's consistent even if key changed since.
+        return Ok(driver
+            .read_range(key, 0, u64::MAX)?
+            .map(|(v, _)| Fetched::Whole(v)));
+    }
+    add_newline(&mut data, offset, len, size, newline);
+    Ok(Some(Fetched::Range(data)))
+}
+
+// Add newline to data read from offset of a value len bytes long, if the read
+// of up to size bytes reaches its end.
+pub fn add_newline(data: &mut Vec<u8>, offset: u64, len: u64, size: u64, newline: &str) {
+    if offset + (data.len() as u64) == len && (data.len() as u64) < size {
+        data.extend_from_slice(newline.as_bytes());
+    }
+}
+
+// Up to size bytes of data from offset, or nothing if offset is at or past the
+// end.
+fn window(data: &[u8], offset: i64, size: u32) -> &[u8] {
+    let start = (offset.max(0) as usize).min(data.len());
+    let end = start.saturating_add(size as usize).min(data.len());
+    &data[start..end]
+}
+
+// Reply to a getxattr or listxattr with data, or just its size if that's all
+// the caller asked for.
+fn reply_xattr(reply: ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(Errno::ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
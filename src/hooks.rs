@@ -0,0 +1,99 @@
+// Hooks run when keys are created, modified, or deleted through the mount, eg.
+// to bust a cache or ping a webhook without a separate watcher process.
+//
+// Each [[hook]] stanza matches paths within the mount against its pattern and
+// runs either a shell command, with the operation and path in its environment,
+// or a Lua script on the backend, with the key as KEYS[1] and the operation and
+// path as ARGV. Hooks run in order on a background task, so a slow one never
+// holds up the filesystem, and failures are only logged.
+use crate::config::{Hook, HookOp};
+use crate::drivers::Driver;
+use crate::tasks::Tasks;
+
+use std::process::Command;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+
+struct Event {
+    op: HookOp,
+    key: String,
+    path: String,
+}
+
+pub struct Hooks {
+    hooks: Vec<Hook>,
+    // None when there are no hooks, so nothing is ever queued.
+    events: Option<Mutex<Sender<Event>>>,
+}
+
+impl Hooks {
+    // Hooks with a background task running them against driver, which exits
+    // once the hooks are dropped.
+    pub fn start(tasks: &Tasks, driver: Arc<dyn Driver>, hooks: Vec<Hook>) -> Arc<Hooks> {
+        if hooks.is_empty() {
+            return Arc::new(Hooks {
+                hooks,
+                events: None,
+            });
+        }
+        let (sender, receiver) = channel::<Event>();
+        let runner = hooks.clone();
+        let receiver = Arc::new(Mutex::new(receiver));
+        tasks.serve("hooks", receiver, move |event: Event| {
+            for hook in runner.iter().filter(|h| matches(h, &event)) {
+                run(&driver, hook, &event);
+            }
+        });
+        Arc::new(Hooks {
+            hooks,
+            events: Some(Mutex::new(sender)),
+        })
+    }
+
+    // Queue the hooks matching op on key, which lives at /kv/<key>.
+    pub fn fire(&self, op: HookOp, key: &str) {
+        let events = match &self.events {
+            Some(v) => v,
+            None => return,
+        };
+        let event = Event {
+            op,
+            key: key.to_string(),
+            path: format!("/kv/{}", key),
+        };
+        if !self.hooks.iter().any(|h| matches(h, &event)) {
+            return;
+        }
+        if events.lock().unwrap().send(event).is_err() {
+            log::error!("Hook runner has exited, not running hooks for {}", key);
+        }
+    }
+}
+
+fn matches(hook: &Hook, event: &Event) -> bool {
+    hook.ops.contains(&event.op) && hook.pattern.is_match(&event.path)
+}
+
+fn run(driver: &Arc<dyn Driver>, hook: &Hook, event: &Event) {
+    log::debug!("Running {} hook for {}", event.op, event.path);
+    if let Some(command) = &hook.command {
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("FUSEKV_OP", event.op.to_string())
+            .env("FUSEKV_PATH", &event.path)
+            .env("FUSEKV_KEY", &event.key)
+            .status();
+        match status {
+            Ok(s) if s.success() => {}
+            Ok(s) => log::warn!("Hook {:?} for {} exited with {}", command, event.path, s),
+            Err(e) => log::error!("Error running hook {:?} for {}: {}", command, event.path, e),
+        }
+    }
+    if let Some(script) = &hook.lua {
+        let args = [event.op.to_string(), event.path.clone()];
+        if let Err(e) = driver.eval(script, std::slice::from_ref(&event.key), &args) {
+            log::error!("Error running Lua hook for {}: {}", event.path, e);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+// Keys changed by any client, learned from the backend's notifications of
+// changes, so what's cached of them here and in the kernel is dropped as soon
+// as they change rather than once it expires.
+//
+// The kernel is told to forget the entries each key can be looked up by, and
+// the attributes of its inode if one was handed out, from the background task
+// rather than while answering a request, which the kernel may be waiting on.
+// Until notifications arrive, or while they can't be received, or when too
+// many keys change at once, caches are only bounded by their TTLs.
+use crate::drivers::Driver;
+use crate::fuse::{kv_entries, DriverError, Subscription};
+use crate::tasks::Tasks;
+
+use fuser::{INodeNo, Notifier};
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// How often notifications are read.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// How long to wait before subscribing again after failing to.
+const RESUBSCRIBE_INTERVAL: Duration = Duration::from_secs(5);
+
+// Changed keys held until taken. Past this, everything is dropped instead.
+const MAX_CHANGED: usize = 10_000;
+
+pub enum Changed {
+    Keys(Vec<String>),
+    // Too many keys changed to track, or notifications were lost, so any of
+    // them may have.
+    Everything,
+}
+
+#[derive(Default)]
+pub struct Invalidations {
+    state: Mutex<State>,
+    // The session of the mount, once there is one.
+    kernel: Mutex<Option<Notifier>>,
+}
+
+#[derive(Default)]
+struct State {
+    changed: HashSet<String>,
+    everything: bool,
+}
+
+impl Invalidations {
+    // Changes learned by a background task through driver, or none at all if
+    // it can't send notifications of them. separator splits keys into the
+    // namespaces they're found in.
+    pub fn start(
+        tasks: &Tasks,
+        driver: Arc<dyn Driver>,
+        separator: Option<String>,
+    ) -> Arc<Invalidations> {
+        let invalidations = Arc::new(Invalidations::default());
+        if !driver.capabilities().notifications {
+            return invalidations;
+        }
+        let watcher = invalidations.clone();
+        let mut changes: Option<Box<dyn Subscription>> = None;
+        let mut retry_at = Instant::now();
+        tasks.every("invalidation", POLL_INTERVAL, move || {
+            if changes.is_none() {
+                if Instant::now() < retry_at {
+                    return true;
+                }
+                match driver.changes() {
+                    Ok(v) => {
+                        log::info!("Dropping cached keys as they change");
+                        changes = Some(v);
+                        // Anything could have changed while not subscribed.
+                        watcher.state.lock().unwrap().everything = true;
+                    }
+                    Err(DriverError::Unsupported(feature)) => {
+                        log::info!(
+                            "Caching keys until they expire, as the driver lacks {}",
+                            feature
+                        );
+                        return false;
+                    }
+                    Err(e) => {
+                        log::warn!(
+                            "Caching keys until they expire, until changes can be watched: {}",
+                            e
+                        );
+                        retry_at = Instant::now() + RESUBSCRIBE_INTERVAL;
+                        return true;
+                    }
+                }
+            }
+            let subscription = changes.as_mut().unwrap();
+            let mut changed = vec![];
+            let lost = loop {
+                match subscription.next_message(Some(Duration::from_millis(0))) {
+                    Ok(Some(key)) => {
+                        let key = String::from_utf8_lossy(&key).to_string();
+                        watcher.record(key.clone());
+                        changed.push(key);
+                    }
+                    Ok(None) => break None,
+                    Err(e) => break Some(e),
+                }
+            };
+            if changed.len() <= MAX_CHANGED {
+                watcher.forget_in_kernel(&*driver, separator.as_deref(), &changed);
+            }
+            if let Some(e) = lost {
+                log::warn!(
+                    "Lost track of changes, caching keys until they expire: {}",
+                    e
+                );
+                changes = None;
+                retry_at = Instant::now() + RESUBSCRIBE_INTERVAL;
+            }
+            true
+        });
+        invalidations
+    }
+
+    // Tell the kernel of session about changes from now on, in place of any
+    // session before it.
+    pub fn notify(&self, session: Notifier) {
+        *self.kernel.lock().unwrap() = Some(session);
+    }
+
+    // Have the kernel forget the entries and attributes it holds of keys.
+    fn forget_in_kernel(&self, driver: &dyn Driver, separator: Option<&str>, keys: &[String]) {
+        let kernel = match self.kernel.lock().unwrap().clone() {
+            Some(v) if !keys.is_empty() => v,
+            _ => return,
+        };
+        for (key, ino) in keys.iter().zip(driver.known_inos(keys)) {
+            for (parent, name) in kv_entries(key, separator) {
+                if let Err(e) = kernel.inval_entry(INodeNo(parent), OsStr::new(&name)) {
+                    log::debug!("Error invalidating {} in the kernel: {}", key, e);
+                    return;
+                }
+            }
+            if let Some(ino) = ino {
+                if let Err(e) = kernel.inval_inode(INodeNo(ino), 0, 0) {
+                    log::debug!("Error invalidating {} in the kernel: {}", key, e);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Note that key just changed.
+    fn record(&self, key: String) {
+        let mut state = self.state.lock().unwrap();
+        if state.everything {
+            return;
+        }
+        state.changed.insert(key);
+        if state.changed.len() > MAX_CHANGED {
+            state.changed.clear();
+            state.everything = true;
+        }
+    }
+
+    // What changed since last taken, or None if nothing did.
+    pub fn take(&self) -> Option<Changed> {
+        let mut state = self.state.lock().unwrap();
+        if mem::take(&mut state.everything) {
+            state.changed.clear();
+            return Some(Changed::Everything);
+        }
+        if state.changed.is_empty() {
+            return None;
+        }
+        Some(Changed::Keys(state.changed.drain().collect()))
+    }
+}
@@ -1,6 +1,24 @@
+mod audit;
+mod cache;
+mod churn;
+mod coalesce;
+mod codec;
 mod config;
 mod drivers;
+mod export;
+mod fixture;
 mod fuse;
+mod hooks;
+mod invalidation;
+mod metrics;
+mod readers;
+mod readlocks;
+mod schema;
+mod sync;
+mod tasks;
+mod top;
+mod validate;
+mod verify;
 
 #[macro_use]
 extern crate quick_error;
@@ -9,19 +27,51 @@ extern crate toml;
 extern crate url;
 
 use env_logger::Env;
-use fuser::MountOption;
+use fuser::{MountOption, SessionACL};
 use human_panic::setup_panic;
-use redis;
 use std::error;
-use std::path::PathBuf;
+use std::ffi::CString;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use structopt::clap::arg_enum;
 use structopt::StructOpt;
-use users;
-use whoami;
+use validator::Validate;
 
 type CLIResult<T> = std::result::Result<T, Box<dyn error::Error>>;
 
+// Wait between remounts after the FUSE session dies, multiplied by the attempt.
+const REMOUNT_BACKOFF: Duration = Duration::from_secs(1);
+
+// How often to check whether SIGUSR1 asked for the command trace.
+const TRACE_SIGNAL_POLL: Duration = Duration::from_millis(200);
+
+// Set by the SIGUSR1 handler, which can't safely log the trace itself.
+static TRACE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// How often to check whether SIGHUP asked for the config to be reloaded.
+const RELOAD_SIGNAL_POLL: Duration = Duration::from_millis(200);
+
+// Set by the SIGHUP handler.
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// How often to check whether SIGTERM or SIGINT asked for the mount to go.
+const UNMOUNT_SIGNAL_POLL: Duration = Duration::from_millis(200);
+
+// Set by the SIGTERM and SIGINT handlers.
+static UNMOUNT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Written by a daemonized fusekv to its parent once mounted. The parent gives
+// up waiting for it once the child exits instead.
+const DAEMON_READY: u8 = b'1';
+
 arg_enum! {
     #[derive(Debug, Clone)]
     enum LogLevel {
@@ -31,6 +81,125 @@ arg_enum! {
     }
 }
 
+arg_enum! {
+    #[derive(Debug, Clone, PartialEq)]
+    enum ErrorFormat {
+        Text,
+        Json,
+    }
+}
+
+// Categories of failure, each exiting with its own code so tooling can react
+// to them differently.
+#[derive(Debug, Clone, Copy)]
+enum Failure {
+    Other,
+    Config,
+    Connection,
+    Mount,
+    Permission,
+    SessionLost,
+}
+
+#[cfg(feature = "external")]
+fn is_external_error(err: &(dyn error::Error + 'static)) -> bool {
+    err.is::<drivers::external::ExternalError>()
+}
+
+#[cfg(not(feature = "external"))]
+fn is_external_error(_err: &(dyn error::Error + 'static)) -> bool {
+    false
+}
+
+impl Failure {
+    fn classify(err: &(dyn error::Error + 'static)) -> Failure {
+        if err.is::<config::ConfigError>()
+            || err.is::<config::PermissionParsingError>()
+            || err.is::<fixture::FixtureError>()
+        {
+            Failure::Config
+        } else if let Some(e) = err.downcast_ref::<redis::RedisError>() {
+            match e.kind() {
+                redis::ErrorKind::AuthenticationFailed => Failure::Permission,
+                _ => Failure::Connection,
+            }
+        } else if is_external_error(err) {
+            Failure::Connection
+        } else if let Some(e) = err.downcast_ref::<MountError>() {
+            match e {
+                MountError::DropPrivileges(_) => Failure::Permission,
+                MountError::SessionLost(_) => Failure::SessionLost,
+                MountError::Failed(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                    Failure::Permission
+                }
+                MountError::Failed(_)
+                | MountError::Daemonize(_)
+                | MountError::PidFile(..)
+                | MountError::AuditLog(..) => Failure::Mount,
+                MountError::Unhealthy(_) => Failure::Connection,
+            }
+        } else {
+            Failure::Other
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Other => 1,
+            Failure::Config => 2,
+            Failure::Connection => 3,
+            Failure::Mount => 4,
+            Failure::Permission => 5,
+            Failure::SessionLost => 6,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Failure::Other => "other",
+            Failure::Config => "config",
+            Failure::Connection => "connection",
+            Failure::Mount => "mount",
+            Failure::Permission => "permission",
+            Failure::SessionLost => "session_lost",
+        }
+    }
+}
+
+quick_error! {
+    #[derive(Debug)]
+    enum MountError {
+        Failed(err: std::io::Error) {
+            source(err)
+            display("Error mounting: {}", err)
+        }
+        SessionLost(err: std::io::Error) {
+            source(err)
+            display("FUSE session lost: {}", err)
+        }
+        DropPrivileges(err: std::io::Error) {
+            source(err)
+            display("Error dropping privileges: {}", err)
+        }
+        Daemonize(err: std::io::Error) {
+            source(err)
+            display("Error forking into the background: {}", err)
+        }
+        PidFile(path: PathBuf, err: std::io::Error) {
+            source(err)
+            display("Error writing PID file {}: {}", path.display(), err)
+        }
+        AuditLog(path: PathBuf, err: std::io::Error) {
+            source(err)
+            display("Error opening audit log {}: {}", path.display(), err)
+        }
+        Unhealthy(err: fuse::DriverError) {
+            source(err)
+            display("Backend failed its health check: {}", err)
+        }
+    }
+}
+
 #[derive(Debug, StructOpt, Clone)]
 #[structopt(
     name = "fusekv",
@@ -41,15 +210,27 @@ arg_enum! {
 struct Opt {
     /// Path to mount fusekv
     #[structopt(parse(from_os_str))]
-    mount: PathBuf,
+    mount: Option<PathBuf>,
 
     /// Path to config file
     #[structopt(parse(from_os_str), short, long)]
     config: Option<PathBuf>,
 
-    /// Redis server(s) to connect to [default: redis://127.0.0.1:6379]
-    #[structopt(short, long)]
-    server: Option<url::Url>,
+    /// Redis server(s) to connect to, or mem:// to keep keys in memory. Repeat to give several cluster seeds [default: redis://127.0.0.1:6379]
+    #[structopt(short, long, number_of_values = 1)]
+    server: Vec<url::Url>,
+
+    /// Shell command that starts an external driver process to use instead of Redis
+    #[structopt(long)]
+    external_driver: Option<String>,
+
+    /// Only expose keys starting with this, which is left out of their names under the mount
+    #[structopt(long)]
+    prefix: Option<String>,
+
+    /// Show keys as nested directories under /kv, split on this, eg. users:123:name as users/123/name
+    #[structopt(long)]
+    separator: Option<String>,
 
     /// Enable Redis cluster mode
     #[structopt(long)]
@@ -59,10 +240,70 @@ struct Opt {
     #[structopt(long)]
     read_only: bool,
 
+    /// Mount read-write even when the backend is a replica, which is mounted read-only otherwise
+    #[structopt(long)]
+    replica_writes: bool,
+
+    /// Replica of the server to send reads to, as read_preference allows. Repeat to give several
+    #[structopt(long, number_of_values = 1)]
+    replica: Vec<url::Url>,
+
+    /// Find replicas to read from by asking the server for its INFO replication
+    #[structopt(long)]
+    discover_replicas: bool,
+
+    /// Where reads go: primary, prefer-replica (falling back to the primary), or replica [default: primary]
+    #[structopt(long)]
+    read_preference: Option<config::ReadPreference>,
+
+    /// Seconds a replica may go without hearing from its primary before reads stop going to it. 0 doesn't check [default: 30]
+    #[structopt(long)]
+    max_replica_lag: Option<u64>,
+
+    /// Seconds keys the mount wrote are read from the primary afterwards, rather than replicas, at least 1 [default: 30]
+    #[structopt(long)]
+    read_your_writes: Option<u64>,
+
+    /// Drop privileges to --user and --group once mounted, and require --confirm-allow-other for --allow-other
+    #[structopt(long)]
+    harden: bool,
+
+    /// Confirm that --allow-other should expose the mount to every user on the host
+    #[structopt(long)]
+    confirm_allow_other: bool,
+
+    /// Fork into the background once mounted
+    #[structopt(long)]
+    daemon: bool,
+
+    /// Check the config, the backend, and the users and groups it names, then exit without mounting
+    #[structopt(long, alias = "dry-run")]
+    check: bool,
+
+    /// File to write fusekv's PID to once mounted
+    #[structopt(long, parse(from_os_str))]
+    pid_file: Option<PathBuf>,
+
+    /// File to append a JSON line to for every read, change, and raw command, saying who made it, or "syslog"
+    #[structopt(long, parse(from_os_str))]
+    audit_log: Option<PathBuf>,
+
+    /// Expose past versions of keys under /history/<unix timestamp>
+    #[structopt(long)]
+    versioning: bool,
+
     /// Don't mount /raw path that accepts raw Redis commands
     #[structopt(long)]
     disable_raw: bool,
 
+    /// Read and write values under /kv exactly, without appending a trailing newline
+    #[structopt(long)]
+    no_append_newline: bool,
+
+    /// Don't keep track of when keys were last written, stamping files with the current time instead
+    #[structopt(long)]
+    no_track_mtime: bool,
+
     /// Set the allow_other mount option. Requires root or user_allow_other set in /etc/fuse.conf
     #[structopt(long)]
     allow_other: bool,
@@ -79,43 +320,372 @@ struct Opt {
     #[structopt(long, parse(try_from_str = config::parse_octal))]
     chmod: Option<u16>,
 
-    /// Maximum number of keys to return to readdir. Set to -1 to disable [default: 1000]
+    /// Maximum number of entries to list per directory. Unlimited by default
     #[structopt(short, long)]
     max_results: Option<i64>,
+
+    /// Regex of paths whose reads always bypass caching. Repeatable
+    #[structopt(long)]
+    nocache: Vec<String>,
+
+    /// Seconds blocking reads (queues, pubsub) wait before failing with ETIMEDOUT. 0 waits forever [default: 30]
+    #[structopt(long)]
+    blocking_timeout: Option<u64>,
+
+    /// Milliseconds listing /kv may take before returning the keys found so far. 0 waits for all of them [default: 0]
+    #[structopt(long)]
+    listing_timeout: Option<u64>,
+
+    /// Order keys are listed in under /kv: backend, lexicographic, idle, or size [default: backend]
+    #[structopt(long)]
+    listing_order: Option<config::ListingOrder>,
+
+    /// How expiring keys show the time they have left: none, size (shrinking toward zero), or blocks (seconds left) [default: none]
+    #[structopt(long)]
+    ttl_display: Option<config::TtlDisplay>,
+
+    /// Seconds opening a key renamed through the mount by its old name opens the new one. 0 disables redirects [default: 0]
+    #[structopt(long)]
+    rename_redirect: Option<u64>,
+
+    /// How nested locks interact: independent or parent-blocking [default: independent]
+    #[structopt(long)]
+    lock_mode: Option<config::LockMode>,
+
+    /// Seconds until locks under /lock expire. 0 holds them until released [default: 0]
+    #[structopt(long)]
+    lock_ttl: Option<u64>,
+
+    /// What users see of others' locks under /lock: off, hide, or stub [default: off]
+    #[structopt(long)]
+    lock_privacy: Option<config::LockPrivacy>,
+
+    /// What writing an empty file under /kv does: store an empty value, or delete the key [default: store]
+    #[structopt(long)]
+    empty_file: Option<config::EmptyFile>,
+
+    /// Bulk deletes of more keys than this need confirming via /.fusekv/confirm [default: 100]
+    #[structopt(long)]
+    bulk_delete_threshold: Option<u64>,
+
+    /// Number of most-accessed keys listed in /.fusekv/hotkeys. 0 disables tracking [default: 20]
+    #[structopt(long)]
+    hot_keys: Option<usize>,
+
+    /// Number of files that may be open through the mount at once, past which opening fails with EMFILE. 0 allows any number [default: 0]
+    #[structopt(long)]
+    max_open_handles: Option<usize>,
+
+    /// Number of times any one key may be open at once, past which opening it fails with EMFILE. 0 allows any number [default: 0]
+    #[structopt(long)]
+    max_open_per_key: Option<usize>,
+
+    /// Number of commands run through /raw listed in /.fusekv/raw_history. 0 disables it [default: 100]
+    #[structopt(long)]
+    raw_history: Option<usize>,
+
+    /// Number of commands sent to Redis listed in /.fusekv/trace. 0 disables tracing [default: 1000]
+    #[structopt(long)]
+    trace: Option<usize>,
+
+    /// Serve Prometheus metrics over HTTP on this address, eg. 127.0.0.1:9876
+    #[structopt(long)]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Estimate /kv:count from this many random keys, leaving out fusekv's own. 0 counts exactly [default: 0]
+    #[structopt(long)]
+    count_sample: Option<u64>,
+
+    /// Milliseconds within which repeated writes to a key are coalesced into one SET. 0 disables coalescing [default: 0]
+    #[structopt(long)]
+    coalesce_window: Option<u64>,
+
+    /// Acknowledge writes once queued, and write them out to the backend in pipelined batches in the background
+    #[structopt(long)]
+    write_behind: bool,
+
+    /// Queued writes sent to the backend in one pipeline with --write-behind [default: 100]
+    #[structopt(long)]
+    write_behind_batch: Option<usize>,
+
+    /// Milliseconds between writing out queued writes with --write-behind [default: 100]
+    #[structopt(long)]
+    write_behind_interval: Option<u64>,
+
+    /// Writes queued at most with --write-behind, past which writes wait for the queue to be written out [default: 10000]
+    #[structopt(long)]
+    write_behind_depth: Option<usize>,
+
+    /// Don't check which modules the backend has loaded, for servers where MODULE LIST is denied
+    #[structopt(long)]
+    no_probe_modules: bool,
+
+    /// Milliseconds the kernel may cache names of entries for [default: 1000]
+    #[structopt(long)]
+    entry_ttl: Option<u64>,
+
+    /// Milliseconds attributes of entries are cached for [default: 1000]
+    #[structopt(long)]
+    attr_ttl: Option<u64>,
+
+    /// Milliseconds values of keys are cached for. 0 disables caching them [default: 0]
+    #[structopt(long)]
+    data_ttl: Option<u64>,
+
+    /// Milliseconds listings of /kv, its namespaces, and /tags are cached for. 0 disables caching them [default: 0]
+    #[structopt(long)]
+    listing_ttl: Option<u64>,
+
+    /// Milliseconds the kernel may cache entries and attributes of keys for in namespaces that rarely change. 0 disables adapting TTLs [default: 0]
+    #[structopt(long)]
+    adaptive_ttl: Option<u64>,
+
+    /// Seconds read locks on keys opened with user.fusekv.readlock outlive the mount if it dies [default: 10]
+    #[structopt(long)]
+    read_lock_ttl: Option<u64>,
+
+    /// Fixture file of keys to load into the backend before mounting
+    #[structopt(long, parse(from_os_str))]
+    fixture: Option<PathBuf>,
+
+    /// Values larger than this many bytes are read in ranges rather than fetched whole on open [default: 1048576]
+    #[structopt(long)]
+    stream_threshold: Option<u64>,
+
+    /// Threads reading values, so parallel readers don't wait on each other. 0 reads them one at a time [default: 4]
+    #[structopt(long)]
+    read_threads: Option<usize>,
+
+    /// Threads taking requests from the kernel, so requests can be read while others are being answered [default: 4]
+    #[structopt(long)]
+    fuse_threads: Option<usize>,
+
+    /// Directory to keep values larger than offload_threshold in, leaving only a pointer to them in Redis
+    #[structopt(long, parse(from_os_str))]
+    offload_dir: Option<PathBuf>,
+
+    /// Values larger than this many bytes are kept in offload_dir, if set [default: 1048576]
+    #[structopt(long)]
+    offload_threshold: Option<u64>,
+
+    /// Times to remount after the FUSE session dies before exiting. 0 exits immediately [default: 3]
+    #[structopt(long)]
+    remount_attempts: Option<u32>,
+
+    /// Most Redis connections kept open at once [default: 8]
+    #[structopt(long)]
+    pool_size: Option<u32>,
+
+    /// Seconds to wait for a Redis connection before failing with EIO [default: 5]
+    #[structopt(long)]
+    pool_connect_timeout: Option<u64>,
+
+    /// Seconds an unused Redis connection stays open. 0 keeps them open forever [default: 300]
+    #[structopt(long)]
+    pool_idle_timeout: Option<u64>,
+
+    /// Times to retry connecting to Redis, or commands it refuses to run for now, eg. while loading [default: 3]
+    #[structopt(long)]
+    retry_attempts: Option<u32>,
+
+    /// Milliseconds before the first retry, doubling for each after [default: 50]
+    #[structopt(long)]
+    retry_backoff: Option<u64>,
+
+    /// Seconds operations fail straight away with EIO after retries connecting are exhausted. 0 never does [default: 5]
+    #[structopt(long)]
+    breaker_cooldown: Option<u64>,
+
+    /// Redis logical database to mount, overriding any /N path in the server URL [default: 0]
+    #[structopt(long)]
+    database: Option<i64>,
+
+    /// Redis ACL user to authenticate as
+    #[structopt(long)]
+    redis_user: Option<String>,
+
+    /// File holding the Redis password, which can't be given on the command line where ps would show it
+    #[structopt(long, parse(from_os_str))]
+    redis_password_file: Option<PathBuf>,
+
+    /// Environment variable holding the Redis password
+    #[structopt(long)]
+    redis_password_env: Option<String>,
+
+    /// Shell command printing the Redis password, run again whenever the server rejects it
+    #[structopt(long)]
+    redis_password_command: Option<String>,
+
+    /// CA certificate to trust, instead of the system's, for rediss:// servers
+    #[structopt(long, parse(from_os_str))]
+    tls_ca_cert: Option<PathBuf>,
+
+    /// Client certificate to present to rediss:// servers, with --tls-client-key
+    #[structopt(long, parse(from_os_str))]
+    tls_client_cert: Option<PathBuf>,
+
+    /// Private key of --tls-client-cert
+    #[structopt(long, parse(from_os_str))]
+    tls_client_key: Option<PathBuf>,
+
+    /// Don't verify the certificates of rediss:// servers
+    #[structopt(long)]
+    tls_insecure: bool,
+
+    /// Drop the inodes handed out to keys at mount rather than migrating them, eg. if they're in a format this version can't read
+    #[structopt(long)]
+    reset_ino_cache: bool,
+
+    /// How to print errors that stop fusekv: text or json
+    #[structopt(long, default_value = "text")]
+    error_format: ErrorFormat,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, StructOpt, Clone)]
+#[structopt(rename_all = "kebab-case")]
+enum Command {
+    /// Export all keys to a file of RESP-encoded RESTORE commands instead of mounting
+    Export {
+        /// File to write the export to
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        /// State file to checkpoint progress into, so interrupted exports resume
+        #[structopt(long, parse(from_os_str))]
+        state: Option<PathBuf>,
+    },
+    /// Execute the RESP-encoded commands in a file, such as an export, instead of mounting
+    Import {
+        /// File to read commands from
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        /// State file to checkpoint progress into, so interrupted imports resume
+        #[structopt(long, parse(from_os_str))]
+        state: Option<PathBuf>,
+    },
+    /// Load the keys declared in a fixture file into the backend instead of mounting
+    LoadFixture {
+        /// Fixture file to load
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Print a JSON Schema for the config file, or check a config file against it
+    Config(ConfigCommand),
+    /// Show a live view of a running mount's operation rates, latencies, hot keys, and backend health
+    Top {
+        /// Path fusekv is mounted on
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+
+        /// Seconds between refreshes
+        #[structopt(long, default_value = "1")]
+        interval: u64,
+    },
+    /// Check a running mount against its backend, reporting keys whose listing, size, or type disagree
+    Verify {
+        /// Path fusekv is mounted on, with the same backend as given here
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Keep a local directory a copy of the keys under a path of /kv, following changes as they're made, instead of mounting
+    Sync {
+        /// Path of the keys to copy, as under /kv, eg. app/config
+        path: String,
+
+        /// Directory to copy them into, which is made to match them exactly
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+
+        /// Copy everything once and exit, rather than following changes
+        #[structopt(long)]
+        once: bool,
+
+        /// Seconds between copying everything again while changes can't be followed
+        #[structopt(long, default_value = "60")]
+        resync_interval: u64,
+    },
 }
 
+#[derive(Debug, StructOpt, Clone)]
+#[structopt(rename_all = "kebab-case")]
+enum ConfigCommand {
+    /// Print a JSON Schema describing the config file
+    Schema,
+    /// Check a config file against the schema, reporting every problem with its line
+    Validate {
+        /// Config file to check
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+}
+
+// human_panic still names the panic hook's info PanicInfo.
+#[allow(deprecated)]
 fn main() {
     setup_panic!();
-    process::exit(match run_app() {
+    let env = Env::default().filter_or("FUSEKV_LOG_LEVEL", "info");
+    env_logger::init_from_env(env);
+    log::debug!("Parsing CLI args.");
+    let opt = Opt::from_args();
+    log::debug!("Parsed {:?} from CLI.", opt);
+    let error_format = opt.error_format.clone();
+    process::exit(match run_app(opt) {
         Ok(_) => 0,
         Err(err) => {
-            eprintln!("error: {:?}", err);
-            1
+            let failure = Failure::classify(err.as_ref());
+            match error_format {
+                ErrorFormat::Text => eprintln!("error: {}", err),
+                ErrorFormat::Json => eprintln!(
+                    "{}",
+                    serde_json::json!({
+                        "error": failure.name(),
+                        "code": failure.exit_code(),
+                        "message": err.to_string(),
+                    })
+                ),
+            }
+            failure.exit_code()
         }
     });
 }
 
-fn run_app() -> CLIResult<()> {
-    let env = Env::default().filter_or("FUSEKV_LOG_LEVEL", "info");
-    env_logger::init_from_env(env);
-    log::debug!("Parsing CLI args.");
-    let opt = Opt::from_args();
-    log::debug!("Parsed {:?} from CLI.", opt);
+fn run_app(opt: Opt) -> CLIResult<()> {
     let mountpoint = opt.mount.clone();
+    let cmd = opt.cmd.clone();
+    let reload_opt = opt.clone();
+    let check = opt.check;
+    // Config commands work on config files, so mustn't need a valid one.
+    if let Some(Command::Config(cmd)) = cmd {
+        return run_config_command(cmd);
+    }
     let mut config = match merge_config(opt) {
         Ok(config) => config,
         Err(e) => return Err(Box::new(e)),
     };
     log::debug!("Final loaded config: {:?}.", config);
 
+    if let Some(cmd) = cmd {
+        return run_command(cmd, &config);
+    }
+    if check {
+        return run_check(&config);
+    }
+    let mountpoint = match mountpoint {
+        Some(v) => v,
+        None => return Err(Box::new(config::ConfigError::NoMountpoint)),
+    };
+
     // Setup fuse options
-    let mut fuse_options = vec![
+    let mut fuse_config = fuser::Config::default();
+    fuse_config.mount_options = vec![
         MountOption::FSName("fusekv".to_string()),
         MountOption::AutoUnmount,
         MountOption::NoExec,
         MountOption::DefaultPermissions,
-        // TODO would async be faster? is that compatible with what we are doing with
-        // redis kv?
         MountOption::DirSync,
         MountOption::Sync,
         // TODO do we need block/special devices?
@@ -125,43 +695,489 @@ fn run_app() -> CLIResult<()> {
         // Tracking atime would have perf issues, so don't bother
         MountOption::NoAtime,
     ];
+    fuse_config.n_threads = Some(config.fuse_threads);
     if config.allow_other {
         log::info!("Setting allow_other mount option.");
-        fuse_options.push(MountOption::AllowOther);
+        fuse_config.acl = SessionACL::All;
+    } else {
+        // fusermount needs allow_other to unmount automatically, so fuser
+        // turns away everyone but root and whoever mounted it itself.
+        fuse_config.acl = SessionACL::RootAndOwner;
+    }
+
+    // Only the forking thread carries on in the child, so this has to happen
+    // before the driver or any task starts threads of its own.
+    let ready = match config.daemon {
+        true => Some(daemonize()?),
+        false => None,
+    };
+
+    let driver = drivers::open(&config)?;
+    check_backend(driver.as_ref())?;
+    // Replicas reject every write with READONLY, so say so up front rather
+    // than have writes fail one at a time.
+    if !config.read_only && !config.replica_writes {
+        match driver.is_replica() {
+            Ok(true) => {
+                log::warn!(
+                    "The backend is a replica, so mounting read-only. Set replica_writes to mount it read-write anyway."
+                );
+                config.read_only = true;
+            }
+            Ok(false) => {}
+            Err(e) => log::warn!("Error checking whether the backend is a replica: {}", e),
+        }
     }
     if config.read_only {
         log::info!("Mounting in read-only mode.");
-        fuse_options.push(MountOption::RO);
+        fuse_config.mount_options.push(MountOption::RO);
         log::info!("Disabling raw command support due to read-only mode.");
         config.disable_raw = true;
     } else {
         log::info!("Mounting in read-write mode.");
-        fuse_options.push(MountOption::RW);
+        fuse_config.mount_options.push(MountOption::RW);
     }
-
-    // TODO how to support multiple drivers here? Do we need a function that returns
-    // an Option and then we can match->err on that?
-    let mut driver = drivers::redis::RedisDriver::new(match config.redis {
-        Some(url) => {
-            log::debug!("Attempting to connect to redis URL {}.", url);
-            match redis::Client::open(url.to_string()) {
-                Ok(v) => v,
-                Err(e) => return Err(Box::new(e)),
-            }
+    let tasks = tasks::Tasks::new();
+    log_trace_on_sigusr1(&tasks, driver.clone());
+    unmount_on_signals(&tasks, mountpoint.clone());
+    let mut mirrors = vec![];
+    for mirror in &config.mirror {
+        let driver = drivers::open_mirror(&config, mirror)?;
+        check_backend(driver.as_ref())?;
+        mirrors.push((mirror.name.clone(), driver));
+    }
+    let metrics = metrics::Metrics::new();
+    if let Some(addr) = config.metrics_listen {
+        metrics::serve(&tasks, addr, metrics.clone(), driver.clone())?;
+        log::info!("Serving metrics on http://{}/metrics.", addr);
+    }
+    // Opened before dropping privileges, like the PID file.
+    let audit = match audit::Audit::open(config.audit_log.as_deref()) {
+        Ok(v) => v,
+        Err(e) => {
+            let path = config.audit_log.clone().unwrap_or_default();
+            return Err(Box::new(MountError::AuditLog(path, e)));
         }
-        None => return Err(Box::new(config::ConfigError::NoDriver)),
-    });
+    };
+    if let Some(path) = &config.audit_log {
+        log::info!("Auditing filesystem operations to {}.", path.display());
+    }
+    let mut kvfs = fuse::KVFS::new(config.clone(), driver, mirrors, metrics, audit, &tasks);
+    if config.probe_modules {
+        if let Err(e) = kvfs.probe_modules() {
+            log::warn!("Error checking which modules the backend has loaded: {}", e);
+        }
+    }
 
-    let mut kvfs = fuse::KVFS::new(config.clone(), driver);
+    if let Some(path) = &config.fixture {
+        let fixture = fixture::Fixture::read(path)?;
+        let n = kvfs.load_fixture(&fixture)?;
+        log::info!("Loaded {} keys from fixture {}.", n, path.display());
+    }
 
     log::debug!("Building directory structure.");
     kvfs.init_static_dirs();
+    let reloader = config::Reloader::new(move || merge_config(reload_opt.clone()));
+    reload_on_sighup(&tasks, reloader.clone());
+    kvfs.reload_with(reloader);
 
     // Mount the filestystem
     log::info!("Mounting fusekv at {}.", mountpoint.display());
-    match fuser::mount2(kvfs, mountpoint, &fuse_options) {
-        Ok(v) => Ok(v),
-        Err(e) => Err(Box::new(e)),
+    let invalidations = kvfs.invalidations();
+    let remains = kvfs.remains();
+    let mut session = match mount(kvfs, &mountpoint, &fuse_config) {
+        Ok(v) => v,
+        Err(e) => return Err(Box::new(MountError::Failed(e))),
+    };
+    invalidations.notify(session.notifier());
+    // Written before dropping privileges, as PID files usually live where only
+    // root can write.
+    if let Some(path) = &config.pid_file {
+        if let Err(e) = std::fs::write(path, format!("{}\n", process::id())) {
+            return Err(Box::new(MountError::PidFile(path.clone(), e)));
+        }
+    }
+    if config.harden {
+        drop_privileges(config.uid, config.gid)?;
+    }
+    if let Some(ready) = ready {
+        detach_from_parent(ready)?;
+    }
+    let mut remounts = 0;
+    let result = loop {
+        // Unmounting ends the session cleanly, so any error means the kernel
+        // side went away underneath us. Either way the session writes out
+        // anything still held as it ends, and is unmounted once joined.
+        let err = match session.spawn().and_then(|s| s.join()) {
+            Ok(v) => break Ok(v),
+            Err(e) => e,
+        };
+        if remounts >= config.remount_attempts {
+            break Err(MountError::SessionLost(err));
+        }
+        remounts += 1;
+        log::error!(
+            "FUSE session died ({}), remounting (attempt {} of {}).",
+            err,
+            remounts,
+            config.remount_attempts
+        );
+        // What was mounted is left in remains as the session ends.
+        let kvfs = remains.lock().unwrap().take().unwrap();
+        std::thread::sleep(REMOUNT_BACKOFF * remounts);
+        session = match mount(kvfs, &mountpoint, &fuse_config) {
+            Ok(v) => v,
+            Err(e) => break Err(MountError::SessionLost(e)),
+        };
+        invalidations.notify(session.notifier());
+    };
+    // Any writes held back were flushed on unmount, so background work can
+    // wind down.
+    tasks.stop();
+    if let Some(path) = &config.pid_file {
+        if let Err(e) = std::fs::remove_file(path) {
+            log::warn!("Error removing PID file {}: {}", path.display(), e);
+        }
+    }
+    result.map_err(|e| Box::new(e) as Box<dyn error::Error>)
+}
+
+// A session serving kvfs at mountpoint, its requests shared between the
+// threads fuse_config asks for.
+fn mount(
+    kvfs: fuse::KVFS,
+    mountpoint: &Path,
+    fuse_config: &fuser::Config,
+) -> std::io::Result<fuser::Session<fuse::SharedKVFS>> {
+    fuser::Session::new(fuse::SharedKVFS::new(kvfs), mountpoint, fuse_config)
+}
+
+extern "C" fn request_trace(_: libc::c_int) {
+    TRACE_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// Log the commands driver last sent whenever fusekv gets SIGUSR1.
+fn log_trace_on_sigusr1(tasks: &tasks::Tasks, driver: Arc<dyn drivers::Driver>) {
+    unsafe {
+        let handler: extern "C" fn(libc::c_int) = request_trace;
+        libc::signal(libc::SIGUSR1, handler as libc::sighandler_t);
+    }
+    tasks.every("trace", TRACE_SIGNAL_POLL, move || {
+        if !TRACE_REQUESTED.swap(false, Ordering::SeqCst) {
+            return true;
+        }
+        match driver.trace() {
+            Ok(lines) => {
+                log::info!("Last {} commands sent to the backend:", lines.len());
+                for line in lines {
+                    log::info!("{}", line.trim_end());
+                }
+            }
+            Err(e) => log::error!("Error getting the command trace: {}", e),
+        }
+        true
+    });
+}
+
+extern "C" fn request_reload(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// Reload the config file whenever fusekv gets SIGHUP, for the mount to take up
+// before its next operation.
+fn reload_on_sighup(tasks: &tasks::Tasks, reloader: Arc<config::Reloader>) {
+    unsafe {
+        let handler: extern "C" fn(libc::c_int) = request_reload;
+        libc::signal(libc::SIGHUP, handler as libc::sighandler_t);
+    }
+    tasks.every("reload", RELOAD_SIGNAL_POLL, move || {
+        if !RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+            return true;
+        }
+        match reloader.reload() {
+            Ok(()) => log::info!("Read config again, taking it up before the next operation."),
+            Err(e) => log::error!("Error reloading config, keeping the current one: {}", e),
+        }
+        true
+    });
+}
+
+extern "C" fn request_unmount(_: libc::c_int) {
+    UNMOUNT_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// Unmount mountpoint whenever fusekv gets SIGTERM or SIGINT, ending the
+// session as if unmounted by hand, rather than dying and leaving a stale mount
+// behind.
+fn unmount_on_signals(tasks: &tasks::Tasks, mountpoint: PathBuf) {
+    unsafe {
+        let handler: extern "C" fn(libc::c_int) = request_unmount;
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+    tasks.every("unmount", UNMOUNT_SIGNAL_POLL, move || {
+        if !UNMOUNT_REQUESTED.swap(false, Ordering::SeqCst) {
+            return true;
+        }
+        log::info!("Unmounting {} on signal.", mountpoint.display());
+        match unmount(&mountpoint) {
+            Ok(()) => false,
+            Err(e) => {
+                log::error!("Error unmounting {}: {}", mountpoint.display(), e);
+                true
+            }
+        }
+    });
+}
+
+// Unmount mountpoint, lazily if it's busy, so it goes away once the last file
+// open in it is closed. Only root can unmount directly, others go through
+// fusermount.
+fn unmount(mountpoint: &Path) -> std::io::Result<()> {
+    let path = CString::new(mountpoint.as_os_str().as_bytes())?;
+    if unsafe { libc::umount2(path.as_ptr(), 0) } == 0 {
+        return Ok(());
+    }
+    for args in &[&["-u", "-q"][..], &["-u", "-q", "-z"][..]] {
+        let status = process::Command::new("fusermount")
+            .args(*args)
+            .arg("--")
+            .arg(mountpoint)
+            .status()?;
+        if status.success() {
+            return Ok(());
+        }
+    }
+    Err(std::io::Error::other("fusermount failed"))
+}
+
+// Fork into the background, returning in the child with the pipe to tell the
+// parent it's mounted through. The parent never returns: it exits 0 once told,
+// or with the child's exit code if the child exits first.
+fn daemonize() -> CLIResult<fs::File> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(Box::new(MountError::Daemonize(
+            std::io::Error::last_os_error(),
+        )));
+    }
+    let (mut read_end, write_end) =
+        unsafe { (fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1])) };
+    match unsafe { libc::fork() } {
+        -1 => Err(Box::new(MountError::Daemonize(
+            std::io::Error::last_os_error(),
+        ))),
+        0 => {
+            drop(read_end);
+            // Leave the terminal's session, so hanging it up doesn't reach
+            // the mount.
+            unsafe { libc::setsid() };
+            Ok(write_end)
+        }
+        child => {
+            drop(write_end);
+            let mut buf = [0u8; 1];
+            if let Ok(1) = read_end.read(&mut buf) {
+                if buf[0] == DAEMON_READY {
+                    process::exit(0);
+                }
+            }
+            // The child failed, and already said why on stderr.
+            let mut status = 0;
+            unsafe { libc::waitpid(child, &mut status, 0) };
+            process::exit(match libc::WIFEXITED(status) {
+                true => libc::WEXITSTATUS(status),
+                false => Failure::Other.exit_code(),
+            });
+        }
+    }
+}
+
+// Tell the parent waiting in daemonize that the mount is up, and stop using
+// the terminal it was started from. Logs still go to stderr unless it's a
+// terminal, eg. so they reach the journal under systemd. The working directory
+// is kept, as relative paths in the config are resolved against it on reload.
+fn detach_from_parent(mut ready: fs::File) -> CLIResult<()> {
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    unsafe {
+        libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO);
+        libc::dup2(null.as_raw_fd(), libc::STDOUT_FILENO);
+        if libc::isatty(libc::STDERR_FILENO) == 1 {
+            libc::dup2(null.as_raw_fd(), libc::STDERR_FILENO);
+        }
+    }
+    ready.write_all(&[DAEMON_READY])?;
+    log::info!("Running in the background as PID {}.", process::id());
+    Ok(())
+}
+
+// Switch to uid and gid for good, dropping any supplementary groups. Only
+// possible when running as root, otherwise there's nothing to drop.
+fn drop_privileges(uid: u32, gid: u32) -> CLIResult<()> {
+    if unsafe { libc::geteuid() } != 0 {
+        log::info!("Not running as root, no privileges to drop.");
+        return Ok(());
+    }
+    log::info!("Dropping privileges to uid {} and gid {}.", uid, gid);
+    // Group first, we can't change it once we're no longer root.
+    let failed = unsafe {
+        libc::setgroups(0, std::ptr::null()) != 0
+            || libc::setgid(gid) != 0
+            || libc::setuid(uid) != 0
+    };
+    if failed {
+        return Err(Box::new(MountError::DropPrivileges(
+            std::io::Error::last_os_error(),
+        )));
+    }
+    Ok(())
+}
+
+// PING the backend, so one that can't be reached or won't answer fails
+// startup saying why, rather than every FUSE op after with EAGAIN, and INFO it
+// to say what it is.
+fn check_backend(driver: &dyn drivers::Driver) -> Result<fuse::ServerInfo, MountError> {
+    match driver.ping() {
+        Ok(()) | Err(fuse::DriverError::Unsupported(_)) => {}
+        Err(e) => return Err(MountError::Unhealthy(e)),
+    }
+    // INFO isn't needed to serve files, so servers that disable it, or ACLs
+    // that deny it, still mount.
+    match driver.server_info() {
+        Ok(info) => Ok(info),
+        Err(e) => {
+            log::warn!("Backend is up, but couldn't describe itself: {}", e);
+            Ok(fuse::ServerInfo::default())
+        }
+    }
+}
+
+// Everything mounting would check before touching the mountpoint, printing
+// what was found, for --check.
+fn run_check(config: &config::Config) -> CLIResult<()> {
+    println!("Config is valid.");
+    let driver = drivers::open(config)?;
+    let info = check_backend(driver.as_ref())?;
+    println!(
+        "Backend is up: {} {}, {}.",
+        Some(info.driver)
+            .filter(|d| !d.is_empty())
+            .unwrap_or_else(|| "unknown driver".to_string()),
+        info.version
+            .unwrap_or_else(|| "unknown version".to_string()),
+        info.mode.unwrap_or_else(|| "unknown mode".to_string())
+    );
+    if let Ok(true) = driver.is_replica() {
+        println!("Backend is a replica, so would be mounted read-only.");
+    }
+    for mirror in &config.mirror {
+        check_backend(drivers::open_mirror(config, mirror)?.as_ref())?;
+        println!("Mirror {} is up.", mirror.name);
+    }
+    println!(
+        "Files would belong to uid {} and gid {}, with mode {:o}.",
+        config.uid, config.gid, config.chmod
+    );
+    for permission in &config.permission {
+        if let Some(user) = &permission.user {
+            if users::get_user_by_name(user).is_none() {
+                println!("No user {} for permission on {}.", user, permission.pattern);
+                return Err(Box::new(config::ConfigError::UserNotFound));
+            }
+        }
+        if let Some(group) = &permission.group {
+            if users::get_group_by_name(group).is_none() {
+                println!(
+                    "No group {} for permission on {}.",
+                    group, permission.pattern
+                );
+                return Err(Box::new(config::ConfigError::GroupNotFound));
+            }
+        }
+    }
+    println!("Ready to mount.");
+    Ok(())
+}
+
+fn run_command(cmd: Command, config: &config::Config) -> CLIResult<()> {
+    // top only talks to the mount, not the backend.
+    let connect = |command| -> CLIResult<redis::Connection> {
+        match config.servers.first() {
+            Some(_) => drivers::redis::connect(config, command),
+            None => Err(Box::new(config::ConfigError::NoDriver)),
+        }
+    };
+    match cmd {
+        Command::Export { file, state } => {
+            export::export(&mut connect("export")?, &config.prefix, file, state)
+        }
+        Command::Import { file, state } => {
+            export::import(&mut connect("import")?, &config.prefix, file, state)
+        }
+        Command::LoadFixture { file } => {
+            let fixture = fixture::Fixture::read(&file)?;
+            let n = fixture.load(drivers::open(config)?.as_ref())?;
+            log::info!("Loaded {} keys from {}.", n, file.display());
+            Ok(())
+        }
+        Command::Config(cmd) => run_config_command(cmd),
+        Command::Top { path, interval } => top::top(&path, Duration::from_secs(interval.max(1))),
+        Command::Verify { path } => verify::verify(
+            &path,
+            drivers::open(config)?.as_ref(),
+            config.separator.as_deref(),
+            config.max_results.unwrap_or(-1),
+        ),
+        Command::Sync {
+            path,
+            dir,
+            once,
+            resync_interval,
+        } => {
+            let driver = drivers::open(config)?;
+            let sync = sync::DirSync::new(
+                driver.as_ref(),
+                &path,
+                &dir,
+                config.separator.as_deref(),
+                config.append_newline,
+            );
+            match once {
+                true => {
+                    let n = sync.resync()?;
+                    log::info!("Copied {} keys into {}.", n, dir.display());
+                    Ok(())
+                }
+                false => sync.run(Duration::from_secs(resync_interval.max(1))),
+            }
+        }
+    }
+}
+
+fn run_config_command(cmd: ConfigCommand) -> CLIResult<()> {
+    match cmd {
+        ConfigCommand::Schema => {
+            println!("{:#}", schema::config_schema());
+            Ok(())
+        }
+        ConfigCommand::Validate { file } => {
+            let content = match std::fs::read_to_string(&file) {
+                Ok(v) => v,
+                Err(e) => return Err(Box::new(config::ConfigError::Io(e))),
+            };
+            let problems = schema::validate(&content);
+            for problem in &problems {
+                println!("{}: {}", file.display(), problem);
+            }
+            if !problems.is_empty() {
+                return Err(Box::new(config::ConfigError::Invalid(problems.len())));
+            }
+            println!("{} is valid.", file.display());
+            Ok(())
+        }
     }
 }
 
@@ -171,47 +1187,93 @@ fn merge_config(opt: Opt) -> Result<config::Config, config::ConfigError> {
     let cfgfile = match opt.config {
         Some(config_file) => {
             log::debug!("Reading config from {}.", config_file.display());
-            match config::load_file(config_file) {
-                Ok(cfg) => cfg,
-                Err(e) => return Err(e),
-            }
+            config::load_file(config_file)?
         }
         None => config::ConfigFile::default(),
     };
+    // Sources on the command line replace any in the config file.
+    let (password, password_file, password_env, password_command) = match (
+        opt.redis_password_file,
+        opt.redis_password_env,
+        opt.redis_password_command,
+    ) {
+        (None, None, None) => (
+            cfgfile.redis_password,
+            cfgfile.redis_password_file,
+            cfgfile.redis_password_env,
+            cfgfile.redis_password_command,
+        ),
+        (file, env, command) => (None, file, env, command),
+    };
     let cfg = config::Config {
-        cluster_mode: opt.cluster_mode
-            || match cfgfile.cluster_mode {
-                Some(cfgval) => cfgval,
-                None => false,
-            },
-        redis: match opt.server {
-            Some(optval) => Some(config::RedisServer { url: optval }),
-            None => match cfgfile.redis {
-                Some(cfgval) => Some(cfgval),
-                None => Some(config::RedisServer {
+        cluster_mode: opt.cluster_mode || cfgfile.cluster_mode.unwrap_or(false),
+        external: match opt.external_driver {
+            Some(command) => Some(config::ExternalDriver { command }),
+            None => cfgfile.external,
+        },
+        servers: match opt.server {
+            ref optval if !optval.is_empty() => optval
+                .iter()
+                .map(|url| config::RedisServer { url: url.clone() })
+                .collect(),
+            _ => match (cfgfile.server, cfgfile.redis) {
+                (Some(cfgval), _) if !cfgval.is_empty() => cfgval,
+                (_, Some(cfgval)) => vec![cfgval],
+                _ => vec![config::RedisServer {
                     url: url::Url::parse("redis://127.0.0.1:6379").unwrap(),
-                }),
+                }],
             },
         },
-        permission: match cfgfile.permission {
-            Some(permission) => permission,
-            None => vec![],
+        prefix: match opt.prefix {
+            Some(optval) => optval,
+            None => cfgfile.prefix.unwrap_or_default(),
+        },
+        // An empty separator would split keys on every character.
+        separator: match opt.separator {
+            Some(optval) => Some(optval),
+            None => cfgfile.separator,
+        }
+        .filter(|s| !s.is_empty()),
+        permission: cfgfile.permission.unwrap_or_default(),
+        disable_raw: opt.disable_raw || cfgfile.disable_raw.unwrap_or(false),
+        append_newline: !opt.no_append_newline && cfgfile.append_newline.unwrap_or(true),
+        track_mtime: !opt.no_track_mtime && cfgfile.track_mtime.unwrap_or(true),
+        read_only: opt.read_only || cfgfile.read_only.unwrap_or(false),
+        replica_writes: opt.replica_writes || cfgfile.replica_writes.unwrap_or(false),
+        replicas: match opt.replica {
+            ref optval if !optval.is_empty() => optval
+                .iter()
+                .map(|url| config::RedisServer { url: url.clone() })
+                .collect(),
+            _ => cfgfile.replica.unwrap_or_default(),
+        },
+        discover_replicas: opt.discover_replicas || cfgfile.discover_replicas.unwrap_or(false),
+        read_preference: match opt.read_preference {
+            Some(optval) => optval,
+            None => cfgfile.read_preference.unwrap_or_default(),
+        },
+        max_replica_lag: match opt.max_replica_lag {
+            Some(optval) => optval,
+            None => cfgfile.max_replica_lag.unwrap_or(30),
+        },
+        read_your_writes: match opt.read_your_writes {
+            Some(optval) => optval,
+            None => cfgfile.read_your_writes.unwrap_or(30),
+        },
+        allow_other: opt.allow_other || cfgfile.allow_other.unwrap_or(false),
+        versioning: opt.versioning || cfgfile.versioning.unwrap_or(false),
+        harden: opt.harden || cfgfile.harden.unwrap_or(false),
+        confirm_allow_other: opt.confirm_allow_other
+            || cfgfile.confirm_allow_other.unwrap_or(false),
+        daemon: opt.daemon || cfgfile.daemon.unwrap_or(false),
+        pid_file: match opt.pid_file {
+            Some(optval) => Some(optval),
+            None => cfgfile.pid_file,
+        },
+        audit_log: match opt.audit_log {
+            Some(optval) => Some(optval),
+            None => cfgfile.audit_log,
         },
-        disable_raw: opt.disable_raw
-            || match cfgfile.disable_raw {
-                Some(cfgval) => cfgval,
-                None => false,
-            },
-        read_only: opt.read_only
-            || match cfgfile.read_only {
-                Some(cfgval) => cfgval,
-                None => false,
-            },
-        allow_other: opt.allow_other
-            || match cfgfile.allow_other {
-                Some(cfgval) => cfgval,
-                None => false,
-            },
         // Defaults to the current user
         uid: match users::get_user_by_name(&match opt.user {
             Some(optval) => optval,
@@ -240,18 +1302,386 @@ fn merge_config(opt: Opt) -> Result<config::Config, config::ConfigError> {
         // Defaults to read/write by current user.
         chmod: match opt.chmod {
             Some(optval) => optval,
-            None => match cfgfile.chmod {
-                Some(cfgval) => cfgval,
-                None => 0o755,
-            },
+            None => cfgfile.chmod.unwrap_or(0o755),
         },
         max_results: match opt.max_results {
+            Some(optval) => Some(optval),
+            None => cfgfile.max_results,
+        },
+        lock_mode: match opt.lock_mode {
             Some(optval) => optval,
-            None => match cfgfile.max_results {
-                Some(cfgval) => cfgval,
-                None => 1000,
-            },
+            None => cfgfile.lock_mode.unwrap_or_default(),
+        },
+        lock_ttl: match opt.lock_ttl {
+            Some(optval) => optval,
+            None => cfgfile.lock_ttl.unwrap_or(0),
         },
+        lock_privacy: match opt.lock_privacy {
+            Some(optval) => optval,
+            None => cfgfile.lock_privacy.unwrap_or_default(),
+        },
+        empty_file: match opt.empty_file {
+            Some(optval) => optval,
+            None => cfgfile.empty_file.unwrap_or_default(),
+        },
+        nocache: match opt
+            .nocache
+            .iter()
+            .chain(cfgfile.nocache.iter().flatten())
+            .map(|p| regex::Regex::new(p))
+            .collect()
+        {
+            Ok(v) => v,
+            Err(e) => return Err(config::ConfigError::BadPattern(e)),
+        },
+        listing_timeout: match opt.listing_timeout {
+            Some(optval) => optval,
+            None => cfgfile.listing_timeout.unwrap_or(0),
+        },
+        blocking_timeout: match opt.blocking_timeout {
+            Some(optval) => optval,
+            None => cfgfile.blocking_timeout.unwrap_or(30),
+        },
+        timeout: cfgfile.timeout.unwrap_or_default(),
+        listing_order: match opt.listing_order {
+            Some(optval) => optval,
+            None => cfgfile.listing_order.unwrap_or_default(),
+        },
+        ttl_display: match opt.ttl_display {
+            Some(optval) => optval,
+            None => cfgfile.ttl_display.unwrap_or_default(),
+        },
+        rename_redirect: match opt.rename_redirect {
+            Some(optval) => optval,
+            None => cfgfile.rename_redirect.unwrap_or(0),
+        },
+        listing: cfgfile.listing.unwrap_or_default(),
+        policy: cfgfile.policy.unwrap_or_default(),
+        validation: cfgfile
+            .validation
+            .into_iter()
+            .flatten()
+            .map(load_validation)
+            .collect::<Result<_, _>>()?,
+        write_allow: cfgfile
+            .write_allow
+            .into_iter()
+            .flatten()
+            .map(resolve_write_allow)
+            .collect::<Result<_, _>>()?,
+        acl_user: cfgfile
+            .acl_user
+            .into_iter()
+            .flatten()
+            .map(resolve_acl_user)
+            .collect::<Result<_, _>>()?,
+        bulk_delete_threshold: match opt.bulk_delete_threshold {
+            Some(optval) => optval,
+            None => cfgfile.bulk_delete_threshold.unwrap_or(100),
+        },
+        hot_keys: match opt.hot_keys {
+            Some(optval) => optval,
+            None => cfgfile.hot_keys.unwrap_or(20),
+        },
+        max_open_handles: match opt.max_open_handles {
+            Some(optval) => optval,
+            None => cfgfile.max_open_handles.unwrap_or(0),
+        },
+        max_open_per_key: match opt.max_open_per_key {
+            Some(optval) => optval,
+            None => cfgfile.max_open_per_key.unwrap_or(0),
+        },
+        raw_history: match opt.raw_history {
+            Some(optval) => optval,
+            None => cfgfile.raw_history.unwrap_or(100),
+        },
+        raw_history_stream: cfgfile.raw_history_stream,
+        trace: match opt.trace {
+            Some(optval) => optval,
+            None => cfgfile.trace.unwrap_or(1000),
+        },
+        metrics_listen: match opt.metrics_listen {
+            Some(optval) => Some(optval),
+            None => cfgfile.metrics_listen,
+        },
+        count_sample: match opt.count_sample {
+            Some(optval) => optval,
+            None => cfgfile.count_sample.unwrap_or(0),
+        },
+        coalesce_window: match opt.coalesce_window {
+            Some(optval) => optval,
+            None => cfgfile.coalesce_window.unwrap_or(0),
+        },
+        write_behind: opt.write_behind || cfgfile.write_behind.unwrap_or(false),
+        write_behind_batch: match opt.write_behind_batch {
+            Some(optval) => optval,
+            None => cfgfile.write_behind_batch.unwrap_or(100),
+        },
+        write_behind_interval: match opt.write_behind_interval {
+            Some(optval) => optval,
+            None => cfgfile.write_behind_interval.unwrap_or(100),
+        },
+        write_behind_depth: match opt.write_behind_depth {
+            Some(optval) => optval,
+            None => cfgfile.write_behind_depth.unwrap_or(10000),
+        },
+        probe_modules: !opt.no_probe_modules && cfgfile.probe_modules.unwrap_or(true),
+        entry_ttl: match opt.entry_ttl {
+            Some(optval) => optval,
+            None => cfgfile.entry_ttl.unwrap_or(1000),
+        },
+        attr_ttl: match opt.attr_ttl {
+            Some(optval) => optval,
+            None => cfgfile.attr_ttl.unwrap_or(1000),
+        },
+        data_ttl: match opt.data_ttl {
+            Some(optval) => optval,
+            None => cfgfile.data_ttl.unwrap_or(0),
+        },
+        listing_ttl: match opt.listing_ttl {
+            Some(optval) => optval,
+            None => cfgfile.listing_ttl.unwrap_or(0),
+        },
+        adaptive_ttl: match opt.adaptive_ttl {
+            Some(optval) => optval,
+            None => cfgfile.adaptive_ttl.unwrap_or(0),
+        },
+        read_lock_ttl: match opt.read_lock_ttl {
+            Some(optval) => optval,
+            None => cfgfile.read_lock_ttl.unwrap_or(10),
+        },
+        stream_threshold: match opt.stream_threshold {
+            Some(optval) => optval,
+            None => cfgfile.stream_threshold.unwrap_or(1048576),
+        },
+        read_threads: match opt.read_threads {
+            Some(optval) => optval,
+            None => cfgfile.read_threads.unwrap_or(4),
+        },
+        fuse_threads: match opt.fuse_threads {
+            Some(optval) => optval,
+            None => cfgfile.fuse_threads.unwrap_or(4),
+        },
+        offload_dir: match opt.offload_dir {
+            Some(optval) => Some(optval),
+            None => cfgfile.offload_dir,
+        },
+        offload_threshold: match opt.offload_threshold {
+            Some(optval) => optval,
+            None => cfgfile.offload_threshold.unwrap_or(1048576),
+        },
+        remount_attempts: match opt.remount_attempts {
+            Some(optval) => optval,
+            None => cfgfile.remount_attempts.unwrap_or(3),
+        },
+        static_file: cfgfile
+            .static_file
+            .into_iter()
+            .flatten()
+            .map(load_static_file)
+            .collect::<Result<_, _>>()?,
+        static_dir: cfgfile.static_dir.unwrap_or_default(),
+        mirror: cfgfile.mirror.unwrap_or_default(),
+        quota: cfgfile.quota,
+        pool_size: match opt.pool_size {
+            Some(optval) => optval,
+            None => cfgfile.pool_size.unwrap_or(8),
+        },
+        pool_connect_timeout: match opt.pool_connect_timeout {
+            Some(optval) => optval,
+            None => cfgfile.pool_connect_timeout.unwrap_or(5),
+        },
+        pool_idle_timeout: match opt.pool_idle_timeout {
+            Some(optval) => optval,
+            None => cfgfile.pool_idle_timeout.unwrap_or(300),
+        },
+        retry_attempts: match opt.retry_attempts {
+            Some(optval) => optval,
+            None => cfgfile.retry_attempts.unwrap_or(3),
+        },
+        retry_backoff: match opt.retry_backoff {
+            Some(optval) => optval,
+            None => cfgfile.retry_backoff.unwrap_or(50),
+        },
+        breaker_cooldown: match opt.breaker_cooldown {
+            Some(optval) => optval,
+            None => cfgfile.breaker_cooldown.unwrap_or(5),
+        },
+        fixture: match opt.fixture {
+            Some(optval) => Some(optval),
+            None => cfgfile.fixture,
+        },
+        database: match opt.database {
+            Some(optval) => Some(optval),
+            None => cfgfile.database,
+        },
+        redis_user: match opt.redis_user {
+            Some(optval) => Some(optval),
+            None => cfgfile.redis_user,
+        },
+        redis_password: config::resolve_password(
+            password,
+            password_file.clone(),
+            password_env,
+            password_command.clone(),
+        )?,
+        redis_password_file: password_file,
+        redis_password_command: password_command,
+        tls_ca_cert: match opt.tls_ca_cert {
+            Some(optval) => Some(optval),
+            None => cfgfile.tls_ca_cert,
+        },
+        tls_client_cert: match opt.tls_client_cert {
+            Some(optval) => Some(optval),
+            None => cfgfile.tls_client_cert,
+        },
+        tls_client_key: match opt.tls_client_key {
+            Some(optval) => Some(optval),
+            None => cfgfile.tls_client_key,
+        },
+        tls_insecure: opt.tls_insecure || cfgfile.tls_insecure.unwrap_or(false),
+        reset_ino_cache: opt.reset_ino_cache,
+        hook: cfgfile.hook.unwrap_or_default(),
     };
+    if cfg.harden && cfg.allow_other && !cfg.confirm_allow_other {
+        return Err(config::ConfigError::AllowOtherUnconfirmed);
+    }
+    if cfg.harden && (cfg.uid == 0 || cfg.gid == 0) {
+        return Err(config::ConfigError::HardenAsRoot);
+    }
+    if cfg.pool_size == 0 {
+        return Err(config::ConfigError::BadPool("pool_size must be at least 1"));
+    }
+    if cfg.fuse_threads == 0 {
+        return Err(config::ConfigError::NoFuseThreads);
+    }
+    if cfg.database.is_some_and(|db| db < 0) {
+        return Err(config::ConfigError::BadDatabase(
+            "database can't be negative",
+        ));
+    }
+    // Redis Cluster only has database 0.
+    if cfg.cluster_mode && cfg.database.is_some_and(|db| db != 0) {
+        return Err(config::ConfigError::BadDatabase(
+            "only database 0 exists in cluster mode",
+        ));
+    }
+    // Nodes discovered through the seeds are only given the password.
+    if cfg.cluster_mode && cfg.redis_user.is_some() {
+        return Err(config::ConfigError::BadCredentials(
+            "redis_user isn't supported in cluster mode".to_string(),
+        ));
+    }
+    if cfg.tls_client_cert.is_some() != cfg.tls_client_key.is_some() {
+        return Err(config::ConfigError::BadCredentials(
+            "set both or neither of tls_client_cert and tls_client_key".to_string(),
+        ));
+    }
+    if cfg.pool_connect_timeout == 0 {
+        return Err(config::ConfigError::BadPool(
+            "pool_connect_timeout must be at least 1",
+        ));
+    }
+    // A cluster's replicas are found through its slots.
+    if cfg.cluster_mode && (!cfg.replicas.is_empty() || cfg.discover_replicas) {
+        return Err(config::ConfigError::BadReplicas(
+            "replicas are found through the cluster in cluster mode",
+        ));
+    }
+    if cfg.read_preference == config::ReadPreference::Replica
+        && !cfg.cluster_mode
+        && cfg.replicas.is_empty()
+        && !cfg.discover_replicas
+    {
+        return Err(config::ConfigError::BadReplicas(
+            "read_preference replica needs a [[replica]] or discover_replicas",
+        ));
+    }
+    for (i, mirror) in cfg.mirror.iter().enumerate() {
+        if mirror.name.is_empty() || mirror.name.contains('/') {
+            return Err(config::ConfigError::BadMirror(
+                mirror.name.clone(),
+                "must be a non-empty file name",
+            ));
+        }
+        if cfg.mirror[..i].iter().any(|m| m.name == mirror.name) {
+            return Err(config::ConfigError::BadMirror(
+                mirror.name.clone(),
+                "is named twice",
+            ));
+        }
+    }
+    if let Some(hook) = cfg
+        .hook
+        .iter()
+        .find(|h| h.command.is_some() == h.lua.is_some())
+    {
+        return Err(config::ConfigError::BadHook(hook.pattern.to_string()));
+    }
+    if let Some(quota) = &cfg.quota {
+        if quota.warn_percent == 0 || quota.warn_percent > 100 {
+            return Err(config::ConfigError::BadWarnPercent(quota.warn_percent));
+        }
+    }
+    // Settings from the command line haven't been checked yet.
+    cfg.validate().map_err(config::ConfigError::InvalidConfig)?;
     Ok(cfg)
 }
+
+// Read the content of file from its source, if it has one, so the mount never
+// touches the local filesystem.
+fn load_static_file(file: config::StaticFile) -> Result<config::StaticFile, config::ConfigError> {
+    match (&file.content, &file.source) {
+        (Some(_), None) => Ok(file),
+        (None, Some(source)) => match std::fs::read_to_string(source) {
+            Ok(content) => Ok(config::StaticFile {
+                content: Some(content),
+                ..file
+            }),
+            Err(e) => Err(config::ConfigError::Io(e)),
+        },
+        _ => Err(config::ConfigError::BadStaticFile(file.path)),
+    }
+}
+
+// Read the JSON Schema rule checks values against, if it has one, so the
+// mount never touches the local filesystem for it.
+fn load_validation(rule: config::Validation) -> Result<config::Validation, config::ConfigError> {
+    let path = match &rule.json_schema {
+        Some(v) => v.clone(),
+        None => return Ok(rule),
+    };
+    let content = std::fs::read(&path).map_err(config::ConfigError::Io)?;
+    match serde_json::from_slice(&content) {
+        Ok(schema) => Ok(config::Validation {
+            schema: Some(schema),
+            ..rule
+        }),
+        Err(e) => Err(config::ConfigError::BadSchema(path, e)),
+    }
+}
+
+// Look up the gids of the groups rule names.
+fn resolve_write_allow(
+    rule: config::WriteAllow,
+) -> Result<config::WriteAllow, config::ConfigError> {
+    let mut gids = vec![];
+    for group in &rule.groups {
+        match users::get_group_by_name(group) {
+            Some(v) => gids.push(v.gid()),
+            None => return Err(config::ConfigError::GroupNotFound),
+        }
+    }
+    Ok(config::WriteAllow { gids, ..rule })
+}
+
+// Look up the uid of the user rule maps.
+fn resolve_acl_user(rule: config::AclUser) -> Result<config::AclUser, config::ConfigError> {
+    match users::get_user_by_name(&rule.user) {
+        Some(v) => Ok(config::AclUser {
+            uid: v.uid(),
+            ..rule
+        }),
+        None => Err(config::ConfigError::UserNotFound),
+    }
+}
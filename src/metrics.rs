@@ -0,0 +1,374 @@
+// Counts and timings of what the mount does, shown in /.fusekv/stats and, with
+// metrics_listen set, served to Prometheus over HTTP at /metrics, so mounts
+// across a fleet can be watched without strace.
+//
+// The HTTP server is only as much of one as scrapers need: one request per
+// connection, answered in turn on a background task.
+use crate::drivers::Driver;
+use crate::tasks::Tasks;
+
+use std::collections::BTreeMap;
+use std::error::Error;
+#[cfg(feature = "metrics-server")]
+use std::fmt::Write as _;
+#[cfg(feature = "metrics-server")]
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+#[cfg(feature = "metrics-server")]
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Upper bounds of the latency histogram buckets, in seconds.
+const BUCKETS: &[f64] = &[
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+    5.0, 10.0,
+];
+
+// How often connections are accepted.
+#[cfg(feature = "metrics-server")]
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+
+// How long a scraper gets to send its request.
+#[cfg(feature = "metrics-server")]
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Bytes of request read at most, which is plenty for a GET.
+#[cfg(feature = "metrics-server")]
+const MAX_REQUEST: usize = 8192;
+
+// Calls and time spent in one kind of filesystem operation since mounting.
+#[derive(Debug, Clone)]
+pub struct OpStats {
+    pub count: u64,
+    pub total: Duration,
+    pub max: Duration,
+    // Calls taking up to each of BUCKETS, not counting those in earlier ones.
+    buckets: Vec<u64>,
+}
+
+impl Default for OpStats {
+    fn default() -> OpStats {
+        OpStats {
+            count: 0,
+            total: Duration::default(),
+            max: Duration::default(),
+            buckets: vec![0; BUCKETS.len()],
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    ops: Mutex<BTreeMap<&'static str, OpStats>>,
+    // Hits and misses of each of the caches.
+    caches: Mutex<BTreeMap<&'static str, (u64, u64)>>,
+}
+
+// Counts an operation once dropped, so it's timed however the operation
+// returns.
+pub struct OpTimer {
+    op: &'static str,
+    started: Instant,
+    metrics: Arc<Metrics>,
+}
+
+impl OpTimer {
+    pub fn start(metrics: Arc<Metrics>, op: &'static str) -> OpTimer {
+        OpTimer {
+            op,
+            started: Instant::now(),
+            metrics,
+        }
+    }
+}
+
+impl Drop for OpTimer {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        let mut ops = self.metrics.ops.lock().unwrap();
+        let op = ops.entry(self.op).or_default();
+        op.count += 1;
+        op.total += elapsed;
+        op.max = op.max.max(elapsed);
+        let seconds = elapsed.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|b| seconds <= *b) {
+            op.buckets[i] += 1;
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Arc<Metrics> {
+        Arc::new(Metrics::default())
+    }
+
+    // Every kind of operation handled so far, by name.
+    pub fn ops(&self) -> Vec<(&'static str, OpStats)> {
+        let ops = self.ops.lock().unwrap();
+        ops.iter().map(|(op, stats)| (*op, stats.clone())).collect()
+    }
+
+    // Count a lookup in the named cache.
+    pub fn cache_lookup(&self, cache: &'static str, hit: bool) {
+        let mut caches = self.caches.lock().unwrap();
+        let counts = caches.entry(cache).or_default();
+        match hit {
+            true => counts.0 += 1,
+            false => counts.1 += 1,
+        }
+    }
+
+    // Everything in Prometheus's text format, asking driver how the backend is
+    // doing.
+    #[cfg(feature = "metrics-server")]
+    fn render(&self, driver: &dyn Driver) -> String {
+        let mut out = String::new();
+        family(
+            &mut out,
+            "fusekv_operation_duration_seconds",
+            "histogram",
+            "Time spent handling filesystem operations.",
+        );
+        for (op, stats) in self.ops() {
+            let labels = format!("op=\"{}\"", escape(op));
+            let mut cumulative = 0;
+            for (bound, n) in BUCKETS.iter().zip(&stats.buckets) {
+                cumulative += n;
+                sample(
+                    &mut out,
+                    "fusekv_operation_duration_seconds_bucket",
+                    &format!("{},le=\"{}\"", labels, bound),
+                    cumulative,
+                );
+            }
+            sample(
+                &mut out,
+                "fusekv_operation_duration_seconds_bucket",
+                &format!("{},le=\"+Inf\"", labels),
+                stats.count,
+            );
+            sample(
+                &mut out,
+                "fusekv_operation_duration_seconds_sum",
+                &labels,
+                stats.total.as_secs_f64(),
+            );
+            sample(
+                &mut out,
+                "fusekv_operation_duration_seconds_count",
+                &labels,
+                stats.count,
+            );
+        }
+
+        let started = Instant::now();
+        let up = driver.ping().is_ok();
+        family(
+            &mut out,
+            "fusekv_backend_up",
+            "gauge",
+            "Whether the backend answered a ping.",
+        );
+        sample(&mut out, "fusekv_backend_up", "", up as u8);
+        family(
+            &mut out,
+            "fusekv_backend_ping_seconds",
+            "gauge",
+            "How long the backend took to answer a ping.",
+        );
+        sample(
+            &mut out,
+            "fusekv_backend_ping_seconds",
+            "",
+            started.elapsed().as_secs_f64(),
+        );
+
+        if let Ok(counts) = driver.command_counts() {
+            family(
+                &mut out,
+                "fusekv_backend_commands_total",
+                "counter",
+                "Commands sent to the backend.",
+            );
+            for count in &counts {
+                let labels = format!("command=\"{}\"", escape(&count.command));
+                sample(
+                    &mut out,
+                    "fusekv_backend_commands_total",
+                    &labels,
+                    count.sent,
+                );
+            }
+            family(
+                &mut out,
+                "fusekv_backend_command_errors_total",
+                "counter",
+                "Commands sent to the backend that failed.",
+            );
+            for count in &counts {
+                let labels = format!("command=\"{}\"", escape(&count.command));
+                sample(
+                    &mut out,
+                    "fusekv_backend_command_errors_total",
+                    &labels,
+                    count.failed,
+                );
+            }
+        }
+
+        if let Ok(pool) = driver.pool_state() {
+            for (name, help, value) in &[
+                (
+                    "fusekv_pool_connections",
+                    "Connections open to the backend.",
+                    pool.connections,
+                ),
+                (
+                    "fusekv_pool_idle_connections",
+                    "Connections open to the backend and not in use.",
+                    pool.idle,
+                ),
+                (
+                    "fusekv_pool_max_connections",
+                    "Connections the pool opens at most.",
+                    pool.max,
+                ),
+            ] {
+                family(&mut out, name, "gauge", help);
+                sample(&mut out, name, "", value);
+            }
+        }
+
+        let caches = self.caches.lock().unwrap().clone();
+        family(
+            &mut out,
+            "fusekv_cache_hits_total",
+            "counter",
+            "Lookups answered from a cache.",
+        );
+        for (cache, (hits, _)) in &caches {
+            sample(
+                &mut out,
+                "fusekv_cache_hits_total",
+                &format!("cache=\"{}\"", cache),
+                hits,
+            );
+        }
+        family(
+            &mut out,
+            "fusekv_cache_misses_total",
+            "counter",
+            "Lookups a cache couldn't answer.",
+        );
+        for (cache, (_, misses)) in &caches {
+            sample(
+                &mut out,
+                "fusekv_cache_misses_total",
+                &format!("cache=\"{}\"", cache),
+                misses,
+            );
+        }
+        out
+    }
+}
+
+// Serve metrics over HTTP on addr from a background task, until the tasks are
+// stopped. Fails if addr can't be listened on.
+#[cfg(feature = "metrics-server")]
+pub fn serve(
+    tasks: &Tasks,
+    addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    driver: Arc<dyn Driver>,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(addr)?;
+    // Accepting without blocking lets the task notice it's being stopped.
+    listener.set_nonblocking(true)?;
+    tasks.every("metrics", ACCEPT_POLL, move || loop {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                if let Err(e) = respond(stream, &metrics, &*driver) {
+                    log::debug!("Error serving metrics to {}: {}", peer, e);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+            Err(e) => {
+                log::error!("Error accepting metrics connection: {}", e);
+                return true;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(feature = "metrics-server"))]
+pub fn serve(
+    _tasks: &Tasks,
+    _addr: SocketAddr,
+    _metrics: Arc<Metrics>,
+    _driver: Arc<dyn Driver>,
+) -> Result<(), Box<dyn Error>> {
+    Err(Box::new(crate::config::ConfigError::NotBuiltIn(
+        "metrics-server",
+    )))
+}
+
+// Answer the request on stream: metrics for GET /metrics, and not found for
+// anything else.
+#[cfg(feature = "metrics-server")]
+fn respond(mut stream: TcpStream, metrics: &Metrics, driver: &dyn Driver) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut words = request.split_whitespace();
+    let (status, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some(path)) if path.split('?').next() == Some("/metrics") => {
+            ("200 OK", metrics.render(driver))
+        }
+        _ => (
+            "404 Not Found",
+            "Metrics are served at /metrics.\n".to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+// Add the HELP and TYPE lines of a metric family to out.
+#[cfg(feature = "metrics-server")]
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// Add one sample of a metric to out, labelled if labels isn't empty.
+#[cfg(feature = "metrics-server")]
+fn sample<T: std::fmt::Display>(out: &mut String, name: &str, labels: &str, value: T) {
+    let _ = match labels {
+        "" => writeln!(out, "{} {}", name, value),
+        _ => writeln!(out, "{}{{{}}} {}", name, labels, value),
+    };
+}
+
+// s escaped for a label value.
+#[cfg(feature = "metrics-server")]
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
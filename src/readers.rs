@@ -0,0 +1,56 @@
+// Reads that only need the driver, served on a pool of background threads so
+// parallel readers of values, or readers blocked waiting for new stream
+// entries, don't hold up everything else on the one thread FUSE requests are
+// handled on.
+//
+// Serving every request concurrently would need a fuser newer than 0.8, whose
+// Filesystem methods all take &mut self, and async Redis connections would need
+// tokio 1. Reads hand their reply to the job, which answers it once done.
+use crate::drivers::Driver;
+use crate::tasks::Tasks;
+
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+
+// A read to run through the driver, replying to the request itself.
+pub type Job = Box<dyn FnOnce(&dyn Driver) + Send>;
+
+pub struct Readers {
+    // None without any threads, so jobs are run by the caller.
+    jobs: Option<Mutex<Sender<Job>>>,
+}
+
+impl Readers {
+    // Readers with threads background tasks running jobs through driver,
+    // which exit once the readers are dropped.
+    pub fn start(tasks: &Tasks, driver: Arc<dyn Driver>, threads: usize) -> Arc<Readers> {
+        if threads == 0 {
+            return Arc::new(Readers { jobs: None });
+        }
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let driver = driver.clone();
+            tasks.serve("reader", receiver.clone(), move |job: Job| job(&*driver));
+        }
+        Arc::new(Readers {
+            jobs: Some(Mutex::new(sender)),
+        })
+    }
+
+    // Queue job for a background thread, or hand it back if there's none to
+    // run it.
+    pub fn run(&self, job: Job) -> Result<(), Job> {
+        let jobs = match &self.jobs {
+            Some(v) => v,
+            None => return Err(job),
+        };
+        match jobs.lock().unwrap().send(job) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                log::error!("Readers have exited, reading in place");
+                Err(e.0)
+            }
+        }
+    }
+}
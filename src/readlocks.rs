@@ -0,0 +1,96 @@
+// Shared locks on the keys of handles opened with user.fusekv.readlock set,
+// held from open until release so other clients can tell a long read of a
+// value is under way and hold off writing it. The locks are advisory, and
+// expire after read_lock_ttl unless renewed, which a background task does
+// while handles stay open, so a mount that dies doesn't leave them behind.
+use crate::drivers::Driver;
+use crate::fuse::DriverResult;
+use crate::tasks::Tasks;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Locks are renewed this many times per TTL, so one failed renewal doesn't
+// lose them.
+const RENEWALS_PER_TTL: u32 = 3;
+
+// Shortest TTL locks are taken with, as the backend may only expire them in
+// whole seconds.
+const MIN_TTL: Duration = Duration::from_secs(1);
+
+pub struct ReadLocks {
+    driver: Arc<dyn Driver>,
+    ttl: Duration,
+    // The key and owner of the lock held for each handle.
+    held: Mutex<HashMap<u64, (String, String)>>,
+}
+
+impl ReadLocks {
+    // Read locks taken through driver and renewed by a background task.
+    pub fn start(tasks: &Tasks, driver: Arc<dyn Driver>, ttl: Duration) -> Arc<ReadLocks> {
+        let locks = Arc::new(ReadLocks {
+            driver,
+            ttl: ttl.max(MIN_TTL),
+            held: Mutex::new(HashMap::new()),
+        });
+        let renewer = locks.clone();
+        tasks.every("readlocks", locks.ttl / RENEWALS_PER_TTL, move || {
+            renewer.renew();
+            true
+        });
+        locks
+    }
+
+    // Take a lock on key for handle fh, owned by owner.
+    pub fn hold(&self, fh: u64, key: &str, owner: String) -> DriverResult<()> {
+        self.driver.hold_shared(key, &owner, self.ttl)?;
+        self.held
+            .lock()
+            .unwrap()
+            .insert(fh, (key.to_string(), owner));
+        Ok(())
+    }
+
+    // Give up the lock held for handle fh, if there is one.
+    pub fn release(&self, fh: u64) {
+        let held = self.held.lock().unwrap().remove(&fh);
+        if let Some((key, owner)) = held {
+            if let Err(e) = self.driver.release_shared(&key, &owner) {
+                log::warn!("Error releasing read lock on {}: {}", key, e);
+            }
+        }
+    }
+
+    // How many handles hold locks.
+    pub fn held(&self) -> usize {
+        self.held.lock().unwrap().len()
+    }
+
+    // Renew every lock without holding up opens and releases meanwhile. A
+    // handle released while its lock was being renewed may have had it taken
+    // again, so that's given up once more.
+    fn renew(&self) {
+        let held: Vec<(u64, (String, String))> = self
+            .held
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(fh, v)| (*fh, v.clone()))
+            .collect();
+        for (fh, (key, owner)) in held {
+            if let Err(e) = self.driver.hold_shared(&key, &owner, self.ttl) {
+                log::warn!("Error renewing read lock on {}: {}", key, e);
+                continue;
+            }
+            let lock = (key, owner);
+            if self.held.lock().unwrap().get(&fh) == Some(&lock) {
+                continue;
+            }
+            let (key, owner) = lock;
+            if let Err(e) = self.driver.release_shared(&key, &owner) {
+                log::warn!("Error releasing read lock on {}: {}", key, e);
+            }
+        }
+    }
+}
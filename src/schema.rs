@@ -0,0 +1,578 @@
+// A JSON Schema for the config file, and checking config files against it.
+//
+// The schema is worked out from ConfigFile's own Deserialize impl rather than
+// written by hand, so it can't drift from what fusekv actually reads: Tracer
+// is a Deserializer that answers every request with a plausible value while
+// noting what was asked for. Which fields are required is found by tracing
+// again with each field left out in turn and seeing whether that fails.
+use crate::config::ConfigFile;
+
+use serde::de::{self, DeserializeSeed, Deserializer, IntoDeserializer, Visitor};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::fmt;
+use validator::Validate;
+
+#[derive(Debug)]
+struct TraceError(String);
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl de::Error for TraceError {
+    fn custom<T: fmt::Display>(msg: T) -> TraceError {
+        TraceError(msg.to_string())
+    }
+}
+
+// What a visitor says it expects, eg. "a string representing an URL".
+struct Expecting<'a, V>(&'a V);
+
+impl<'de, 'a, V: Visitor<'de>> fmt::Display for Expecting<'a, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.expecting(f)
+    }
+}
+
+#[derive(Default)]
+struct Trace {
+    // Path of the struct field to leave out, if any.
+    omit: Option<Vec<&'static str>>,
+    // Path of every struct field seen.
+    fields: Vec<Vec<&'static str>>,
+}
+
+struct Tracer<'a> {
+    trace: &'a mut Trace,
+    // Where the schema of whatever is deserialized goes.
+    schema: &'a mut Value,
+    path: Vec<&'static str>,
+}
+
+impl<'a> Tracer<'a> {
+    fn unsigned<'de, V: Visitor<'de>>(self, max: u64, visitor: V) -> Result<V::Value, TraceError> {
+        *self.schema = json!({"type": "integer", "minimum": 0, "maximum": max});
+        visitor.visit_u64(0)
+    }
+
+    fn signed<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.schema = json!({"type": "integer"});
+        visitor.visit_i64(0)
+    }
+}
+
+impl<'de, 'a> Deserializer<'de> for Tracer<'a> {
+    type Error = TraceError;
+
+    // Anything not handled below isn't used by the config file, and has no
+    // obvious schema to give it.
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        Err(TraceError(format!(
+            "can't describe {} at {}",
+            Expecting(&visitor),
+            self.path.join(".")
+        )))
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.schema = json!({"type": "boolean"});
+        visitor.visit_bool(false)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.unsigned(u8::MAX as u64, visitor)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.unsigned(u16::MAX as u64, visitor)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.unsigned(u32::MAX as u64, visitor)
+    }
+
+    // TOML integers are signed 64 bit, so that's as high as they go.
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.unsigned(i64::MAX as u64, visitor)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.signed(visitor)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.signed(visitor)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.signed(visitor)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.signed(visitor)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        *self.schema = json!({"type": "number"});
+        visitor.visit_f64(0.0)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        // URLs are the only strings whose format Deserialize checks.
+        if Expecting(&visitor).to_string().contains("URL") {
+            *self.schema = json!({"type": "string", "format": "uri"});
+            return visitor.visit_str("mem://");
+        }
+        *self.schema = json!({"type": "string"});
+        visitor.visit_str("")
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        let mut items = Value::Null;
+        let value = visitor.visit_seq(Element {
+            trace: &mut *self.trace,
+            schema: Some(&mut items),
+            path: self.path,
+        })?;
+        *self.schema = json!({"type": "array", "items": items});
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        let mut properties = Map::new();
+        let omit = self.trace.omit.clone();
+        let fields: Vec<&'static str> = fields
+            .iter()
+            .copied()
+            .filter(|f| {
+                let mut path = self.path.clone();
+                path.push(f);
+                omit.as_ref() != Some(&path)
+            })
+            .collect();
+        let value = visitor.visit_map(Fields {
+            trace: &mut *self.trace,
+            properties: &mut properties,
+            path: self.path,
+            fields,
+            next: 0,
+        })?;
+        *self.schema = json!({
+            "type": "object",
+            "properties": properties,
+            "additionalProperties": false,
+        });
+        Ok(value)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        *self.schema = json!({"type": "string", "enum": variants});
+        visitor.visit_enum(UnitVariant(variants[0]))
+    }
+
+    serde::forward_to_deserialize_any! {
+        char bytes byte_buf unit unit_struct tuple tuple_struct map identifier
+        ignored_any
+    }
+}
+
+// The one element traced for every sequence.
+struct Element<'a> {
+    trace: &'a mut Trace,
+    schema: Option<&'a mut Value>,
+    path: Vec<&'static str>,
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for Element<'a> {
+    type Error = TraceError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, TraceError> {
+        let schema = match self.schema.take() {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        seed.deserialize(Tracer {
+            trace: &mut *self.trace,
+            schema,
+            path: self.path.clone(),
+        })
+        .map(Some)
+    }
+}
+
+struct Fields<'a> {
+    trace: &'a mut Trace,
+    properties: &'a mut Map<String, Value>,
+    path: Vec<&'static str>,
+    fields: Vec<&'static str>,
+    next: usize,
+}
+
+impl<'de, 'a> de::MapAccess<'de> for Fields<'a> {
+    type Error = TraceError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, TraceError> {
+        match self.fields.get(self.next) {
+            Some(field) => seed.deserialize(field.into_deserializer()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<T::Value, TraceError> {
+        let field = self.fields[self.next];
+        self.next += 1;
+        let mut path = self.path.clone();
+        path.push(field);
+        self.trace.fields.push(path.clone());
+        let schema = self
+            .properties
+            .entry(field.to_string())
+            .or_insert(Value::Null);
+        seed.deserialize(Tracer {
+            trace: &mut *self.trace,
+            schema,
+            path,
+        })
+    }
+}
+
+struct UnitVariant(&'static str);
+
+impl<'de> de::EnumAccess<'de> for UnitVariant {
+    type Error = TraceError;
+    type Variant = UnitVariant;
+
+    fn variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<(T::Value, UnitVariant), TraceError> {
+        Ok((seed.deserialize(self.0.into_deserializer())?, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for UnitVariant {
+    type Error = TraceError;
+
+    fn unit_variant(self) -> Result<(), TraceError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        _seed: T,
+    ) -> Result<T::Value, TraceError> {
+        Err(TraceError(format!("can't describe variant {}", self.0)))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        _visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        Err(TraceError(format!("can't describe variant {}", self.0)))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        Err(TraceError(format!("can't describe variant {}", self.0)))
+    }
+}
+
+// The object schema for the struct at path, which may be inside arrays.
+fn object_at<'a>(mut schema: &'a mut Value, path: &[&str]) -> &'a mut Value {
+    for field in path {
+        schema = &mut schema["properties"][*field];
+        while schema.get("items").is_some() {
+            schema = &mut schema["items"];
+        }
+    }
+    schema
+}
+
+pub fn config_schema() -> Value {
+    let mut schema = Value::Null;
+    let mut trace = Trace::default();
+    ConfigFile::deserialize(Tracer {
+        trace: &mut trace,
+        schema: &mut schema,
+        path: vec![],
+    })
+    .expect("ConfigFile can't be described by a schema");
+    for field in trace.fields {
+        let mut omitted = Trace {
+            omit: Some(field.clone()),
+            fields: vec![],
+        };
+        let required = ConfigFile::deserialize(Tracer {
+            trace: &mut omitted,
+            schema: &mut Value::Null,
+            path: vec![],
+        })
+        .is_err();
+        if required {
+            let (name, parent) = field.split_last().unwrap();
+            let object = object_at(&mut schema, parent);
+            match object.get_mut("required") {
+                Some(Value::Array(names)) => names.push(json!(name)),
+                _ => object["required"] = json!([name]),
+            }
+        }
+    }
+    if let Value::Object(ref mut top) = schema {
+        top.insert(
+            "$schema".to_string(),
+            json!("http://json-schema.org/draft-07/schema#"),
+        );
+        top.insert("title".to_string(), json!("fusekv config file"));
+    }
+    schema
+}
+
+// Something wrong with a config file, at a path like hook[1].pattern.
+#[derive(Debug)]
+pub struct Problem {
+    pub path: String,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.line, self.path.as_str()) {
+            (_, "") => f.write_str(&self.message),
+            (Some(line), path) => write!(f, "line {}: {}: {}", line, path, self.message),
+            (None, path) => write!(f, "{}: {}", path, self.message),
+        }
+    }
+}
+
+// Every problem with the config file content, checked against the schema and
+// then against anything Deserialize and Validate catch that the schema can't
+// express, such as bad regexes and out of range permissions.
+pub fn validate(content: &str) -> Vec<Problem> {
+    let value: toml::Value = match toml::from_str(content) {
+        Ok(v) => v,
+        // toml's errors already say where they are.
+        Err(e) => {
+            return vec![Problem {
+                path: String::new(),
+                line: None,
+                message: e.to_string(),
+            }]
+        }
+    };
+    let mut problems = vec![];
+    check(&value, &config_schema(), "", content, &mut problems);
+    problems.sort_by_key(|p| p.line.unwrap_or(usize::MAX));
+    if !problems.is_empty() {
+        return problems;
+    }
+    let config: ConfigFile = match toml::from_str(content) {
+        Ok(v) => v,
+        Err(e) => {
+            return vec![Problem {
+                path: String::new(),
+                line: None,
+                message: e.to_string(),
+            }]
+        }
+    };
+    if let Err(errors) = config.validate() {
+        for (field, errors) in errors.field_errors() {
+            for error in errors {
+                problems.push(Problem {
+                    path: field.to_string(),
+                    line: locate(content, field),
+                    message: match &error.message {
+                        Some(m) => m.to_string(),
+                        None => error.code.to_string(),
+                    },
+                });
+            }
+        }
+    }
+    problems
+}
+
+fn check(
+    value: &toml::Value,
+    schema: &Value,
+    path: &str,
+    content: &str,
+    problems: &mut Vec<Problem>,
+) {
+    let expected = schema["type"].as_str().unwrap_or_default();
+    match (expected, value) {
+        ("boolean", toml::Value::Boolean(_)) => {}
+        ("integer", toml::Value::Integer(i)) => {
+            let (min, max) = (schema["minimum"].as_i64(), schema["maximum"].as_u64());
+            if min.is_some_and(|m| *i < m) || max.is_some_and(|m| *i as u64 > m) {
+                let message = format!(
+                    "{} is out of range {}..={}",
+                    i,
+                    min.unwrap_or(i64::MIN),
+                    max.unwrap_or(i64::MAX as u64)
+                );
+                problems.push(problem(content, path, message));
+            }
+        }
+        ("number", toml::Value::Integer(_)) | ("number", toml::Value::Float(_)) => {}
+        ("string", toml::Value::String(s)) => {
+            if let Some(variants) = schema["enum"].as_array() {
+                if !variants.iter().any(|v| v.as_str() == Some(s.as_str())) {
+                    let names: Vec<&str> = variants.iter().filter_map(|v| v.as_str()).collect();
+                    let message = format!("{:?} is not one of {}", s, names.join(", "));
+                    problems.push(problem(content, path, message));
+                }
+            }
+            if schema["format"] == "uri" {
+                if let Err(e) = url::Url::parse(s) {
+                    let message = format!("{:?} is not a URL: {}", s, e);
+                    problems.push(problem(content, path, message));
+                }
+            }
+        }
+        ("array", toml::Value::Array(items)) => {
+            for (i, item) in items.iter().enumerate() {
+                let item_path = format!("{}[{}]", path, i);
+                check(item, &schema["items"], &item_path, content, problems);
+            }
+        }
+        ("object", toml::Value::Table(table)) => {
+            for (key, item) in table {
+                let item_path = match path {
+                    "" => key.clone(),
+                    _ => format!("{}.{}", path, key),
+                };
+                match schema["properties"].get(key) {
+                    Some(s) => check(item, s, &item_path, content, problems),
+                    // fusekv itself would silently ignore these, which is
+                    // never what was meant.
+                    None => problems.push(problem(content, &item_path, "unknown key".to_string())),
+                }
+            }
+            for name in schema["required"].as_array().into_iter().flatten() {
+                let name = name.as_str().unwrap_or_default();
+                if !table.contains_key(name) {
+                    let message = format!("missing required key {}", name);
+                    problems.push(problem(content, path, message));
+                }
+            }
+        }
+        _ => {
+            let message = format!("expected {}, found {}", expected, value.type_str());
+            problems.push(problem(content, path, message));
+        }
+    }
+}
+
+fn problem(content: &str, path: &str, message: String) -> Problem {
+    Problem {
+        path: path.to_string(),
+        line: locate(content, path),
+        message,
+    }
+}
+
+// Split a path segment like hook[1] into its name and index.
+fn split_index(segment: &str) -> (&str, Option<usize>) {
+    let mut parts = segment.splitn(2, '[');
+    let name = parts.next().unwrap_or_default();
+    let index = parts
+        .next()
+        .and_then(|i| i.trim_end_matches(']').parse().ok());
+    (name, index)
+}
+
+// The line number of the value at path within content, eg. the second
+// [[hook]]'s pattern for hook[1].pattern, as best it can be found by looking
+// at table headers and keys. Values in inline tables aren't found.
+fn locate(content: &str, path: &str) -> Option<usize> {
+    let mut segments: Vec<&str> = path.split('.').collect();
+    let (key, key_index) = split_index(segments.pop()?);
+    // The table the key is in, and which of an array of them.
+    let (table, index) = match segments.pop() {
+        Some(segment) => {
+            let (name, index) = split_index(segment);
+            segments.push(name);
+            (segments.join("."), index.unwrap_or(0))
+        }
+        None => (String::new(), 0),
+    };
+    let whole = match table.as_str() {
+        "" => key.to_string(),
+        _ => format!("{}.{}", table, key),
+    };
+    let mut in_table = table.is_empty();
+    let (mut tables_seen, mut wholes_seen) = (0, 0);
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            let header = line.trim_matches(|c| c == '[' || c == ']').trim();
+            // The value is a table itself, or one of an array of them.
+            if header == whole {
+                if wholes_seen == key_index.unwrap_or(0) {
+                    return Some(n + 1);
+                }
+                wholes_seen += 1;
+            }
+            in_table = header == table && tables_seen == index;
+            if header == table {
+                tables_seen += 1;
+            }
+            continue;
+        }
+        let name = line.split('=').next().unwrap_or_default().trim();
+        if in_table && line.contains('=') && name.trim_matches('"') == key {
+            return Some(n + 1);
+        }
+    }
+    None
+}
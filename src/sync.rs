@@ -0,0 +1,255 @@
+// `fusekv sync`, which keeps a local directory a copy of the keys under a path
+// of /kv, for consumers that can't read through FUSE.
+//
+// Every key is copied once, then the backend's notifications of changes say
+// which to copy again or remove, so the directory follows the keyspace in near
+// real time. Files are written under a temporary name and renamed into place,
+// so readers never see one half written. Keys nested by separator become
+// subdirectories, as they are under /kv. Whenever notifications can't be
+// received, or were lost, everything is copied again, every resync interval
+// until they're back. The directory is made to match the keys exactly, so
+// anything else in it is removed.
+use crate::drivers::Driver;
+use crate::fuse::{escape_glob, DriverError, Subscription};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Keys listed per scan.
+const BATCH: usize = 1000;
+
+// How long to wait for a notification before checking whether to resync.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Suffix of files being written, before they're renamed into place.
+const TEMP_SUFFIX: &str = ".fusekv-tmp";
+
+pub struct DirSync<'a> {
+    driver: &'a dyn Driver,
+    // Keys are synced from under this, eg. app: for /kv/app with : separating.
+    prefix: String,
+    dir: PathBuf,
+    separator: Option<String>,
+    append_newline: bool,
+}
+
+impl<'a> DirSync<'a> {
+    // Sync the keys under path, relative to /kv, into dir.
+    pub fn new(
+        driver: &'a dyn Driver,
+        path: &str,
+        dir: &Path,
+        separator: Option<&str>,
+        append_newline: bool,
+    ) -> DirSync<'a> {
+        let path = path.trim_start_matches('/');
+        let path = match path.strip_prefix("kv") {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => rest,
+            _ => path,
+        };
+        let path = path.trim_matches('/');
+        let prefix = match (separator, path.is_empty()) {
+            (_, true) => String::new(),
+            (Some(s), false) => format!("{}{}", path.replace('/', s), s),
+            (None, false) => path.to_string(),
+        };
+        DirSync {
+            driver,
+            prefix,
+            dir: dir.to_path_buf(),
+            separator: separator.map(String::from),
+            append_newline,
+        }
+    }
+
+    // Copy every key once, then keep copying those that change, until an
+    // error the backend can't recover from.
+    pub fn run(&self, resync_interval: Duration) -> Result<(), Box<dyn Error>> {
+        let mut changes: Option<Box<dyn Subscription>> = None;
+        let mut watch = self.driver.capabilities().notifications;
+        if !watch {
+            log::warn!(
+                "The driver lacks change notifications, so copying everything every {:?}.",
+                resync_interval
+            );
+        }
+        let mut subscribe_at = Instant::now();
+        let mut resync_at = Some(Instant::now());
+        loop {
+            // Subscribed before copying, so nothing changed meanwhile is missed.
+            if watch && changes.is_none() && Instant::now() >= subscribe_at {
+                match self.driver.changes() {
+                    Ok(v) => {
+                        log::info!("Watching for changes under {:?}.", self.prefix);
+                        changes = Some(v);
+                    }
+                    Err(DriverError::Unsupported(feature)) => {
+                        log::warn!(
+                            "The driver lacks {}, so copying everything every {:?}.",
+                            feature,
+                            resync_interval
+                        );
+                        watch = false;
+                    }
+                    Err(e) => {
+                        log::warn!("Not watching for changes yet: {}", e);
+                        subscribe_at = Instant::now() + resync_interval;
+                    }
+                }
+            }
+            if resync_at.is_some_and(|at| Instant::now() >= at) {
+                let n = self.resync()?;
+                log::info!("Copied {} keys into {}.", n, self.dir.display());
+                resync_at = match changes {
+                    Some(_) => None,
+                    None => Some(Instant::now() + resync_interval),
+                };
+            }
+            let subscription = match changes.as_mut() {
+                Some(v) => v,
+                None => {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+            };
+            // Keys changing repeatedly are only copied once per batch.
+            let mut changed = HashSet::new();
+            let mut timeout = POLL_INTERVAL;
+            loop {
+                match subscription.next_message(Some(timeout)) {
+                    Ok(Some(key)) => {
+                        changed.insert(String::from_utf8_lossy(&key).to_string());
+                        timeout = Duration::from_millis(0);
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        log::warn!("Lost track of changes, copying everything again: {}", e);
+                        changes = None;
+                        resync_at = Some(Instant::now());
+                        break;
+                    }
+                }
+            }
+            for key in changed {
+                self.copy(&key)?;
+            }
+        }
+    }
+
+    // Copy every key, and remove anything in the directory that isn't one,
+    // returning how many keys there were.
+    pub fn resync(&self) -> Result<usize, Box<dyn Error>> {
+        fs::create_dir_all(&self.dir)?;
+        let pattern = format!("{}*", escape_glob(&self.prefix));
+        let mut synced = HashSet::new();
+        let mut cursor = String::new();
+        loop {
+            let (refs, next) = self.driver.scan_keys(&pattern, &cursor, BATCH)?;
+            for r in refs {
+                if let Some(path) = self.copy(&r.key)? {
+                    synced.insert(path);
+                }
+            }
+            cursor = match next {
+                Some(v) => v,
+                None => break,
+            };
+        }
+        self.prune(&self.dir.clone(), &synced)?;
+        Ok(synced.len())
+    }
+
+    // Copy key into the directory, or remove its file if it no longer exists,
+    // returning where it was copied to. Keys outside the prefix, or whose
+    // names can't be made into paths, are skipped.
+    fn copy(&self, key: &str) -> Result<Option<PathBuf>, Box<dyn Error>> {
+        let path = match self.path_of(key) {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        let value = match self.driver.get_by_name(key.to_string(), 0) {
+            Ok(Some(entry)) => entry.val,
+            Ok(None) => {
+                if fs::remove_file(&path).is_ok() {
+                    log::debug!("Removed {}.", path.display());
+                    self.remove_empty_dirs(&path);
+                }
+                return Ok(None);
+            }
+            // Types that can't be read as files, eg. hashes.
+            Err(DriverError::WrongType(..)) => return Ok(None),
+            Err(e) => return Err(Box::new(e)),
+        };
+        let content = match self.append_newline {
+            true => [&value[..], b"\n"].concat(),
+            false => value,
+        };
+        // Keys whose files would clash with directories of others, or the
+        // other way round, are left out rather than stopping the sync.
+        if let Err(e) = write_file(&path, &content) {
+            log::warn!("Error copying {} to {}: {}", key, path.display(), e);
+            return Ok(None);
+        }
+        log::debug!("Copied {} to {}.", key, path.display());
+        Ok(Some(path))
+    }
+
+    // Where key is copied to, or None if it's not under the prefix or can't
+    // be made into a path.
+    fn path_of(&self, key: &str) -> Option<PathBuf> {
+        let rest = key.strip_prefix(&self.prefix)?;
+        let parts: Vec<&str> = match &self.separator {
+            Some(s) => rest.split(s.as_str()).collect(),
+            None => vec![rest],
+        };
+        let unsafe_part = |p: &&str| p.is_empty() || *p == "." || *p == ".." || p.contains('/');
+        if parts.iter().any(unsafe_part) || rest.ends_with(TEMP_SUFFIX) {
+            return None;
+        }
+        Some(parts.iter().fold(self.dir.clone(), |path, p| path.join(p)))
+    }
+
+    // Remove every file under dir that isn't in synced, and every directory
+    // left empty.
+    fn prune(&self, dir: &Path, synced: &HashSet<PathBuf>) -> Result<(), Box<dyn Error>> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                self.prune(&path, synced)?;
+                if fs::remove_dir(&path).is_ok() {
+                    log::debug!("Removed {}.", path.display());
+                }
+            } else if !synced.contains(&path) {
+                fs::remove_file(&path)?;
+                log::debug!("Removed {}.", path.display());
+            }
+        }
+        Ok(())
+    }
+
+    // Remove the directories above path left empty, up to the synced one.
+    fn remove_empty_dirs(&self, path: &Path) {
+        let mut dir = path.parent();
+        while let Some(d) = dir.filter(|d| *d != self.dir) {
+            if fs::remove_dir(d).is_err() {
+                return;
+            }
+            dir = d.parent();
+        }
+    }
+}
+
+// Write content to path, by way of a temporary file renamed into place.
+fn write_file(path: &Path, content: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut temp = path.as_os_str().to_os_string();
+    temp.push(TEMP_SUFFIX);
+    fs::write(&temp, content)?;
+    fs::rename(&temp, path)
+}
@@ -0,0 +1,156 @@
+// Background work, eg. flushing coalesced writes, running hooks, serving reads
+// on reader threads, and logging the command trace on SIGUSR1, all started
+// through one Tasks rather than each spawning its own threads. Every thread is
+// named after its task, and stopping the Tasks once unmounted winds them all
+// down, waiting a while for any still busy.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often idle workers check whether they should stop.
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+// How long stopping waits for busy tasks before giving up on them.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Running {
+    // Names of the threads still running, by an id of their own.
+    names: Mutex<HashMap<u64, String>>,
+    next_id: Mutex<u64>,
+    done: Condvar,
+}
+
+pub struct Tasks {
+    stopping: Arc<AtomicBool>,
+    running: Arc<Running>,
+}
+
+// Marks its thread as finished when dropped, even if the task panicked.
+struct Finished {
+    running: Arc<Running>,
+    id: u64,
+}
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        self.running.names.lock().unwrap().remove(&self.id);
+        self.running.done.notify_all();
+    }
+}
+
+impl Tasks {
+    pub fn new() -> Arc<Tasks> {
+        Arc::new(Tasks {
+            stopping: Arc::new(AtomicBool::new(false)),
+            running: Arc::new(Running::default()),
+        })
+    }
+
+    // Run f on a thread of its own until it returns.
+    pub fn spawn<F>(&self, name: &str, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let id = {
+            let mut next_id = self.running.next_id.lock().unwrap();
+            *next_id += 1;
+            *next_id
+        };
+        self.running
+            .names
+            .lock()
+            .unwrap()
+            .insert(id, name.to_string());
+        let finished = Finished {
+            running: self.running.clone(),
+            id,
+        };
+        let spawned = thread::Builder::new()
+            .name(format!("fusekv-{}", name))
+            .spawn(move || {
+                let _finished = finished;
+                f()
+            });
+        if let Err(e) = spawned {
+            log::error!("Error starting {} thread: {}", name, e);
+        }
+    }
+
+    // Run f every interval until it returns false or the tasks are stopped.
+    pub fn every<F>(&self, name: &str, interval: Duration, mut f: F)
+    where
+        F: FnMut() -> bool + Send + 'static,
+    {
+        let stopping = self.stopping.clone();
+        self.spawn(name, move || {
+            while !sleep_unless_stopped(&stopping, interval) {
+                if !f() {
+                    return;
+                }
+            }
+        });
+    }
+
+    // Pass everything sent to receiver to f until every sender is dropped or
+    // the tasks are stopped, at which point whatever was already queued is
+    // still handled. The receiver may be shared between several workers.
+    pub fn serve<T, F>(&self, name: &str, receiver: Arc<Mutex<Receiver<T>>>, mut f: F)
+    where
+        T: Send + 'static,
+        F: FnMut(T) + Send + 'static,
+    {
+        let stopping = self.stopping.clone();
+        self.spawn(name, move || loop {
+            // The lock is only held while waiting, so the others can work
+            // meanwhile.
+            let received = receiver.lock().unwrap().recv_timeout(STOP_POLL);
+            match received {
+                Ok(v) => f(v),
+                Err(RecvTimeoutError::Timeout) if !stopping.load(Ordering::SeqCst) => {}
+                Err(_) => return,
+            }
+        });
+    }
+
+    // Stop every task, waiting for those busy to finish.
+    pub fn stop(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        let mut names = self.running.names.lock().unwrap();
+        while !names.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                let mut left: Vec<&String> = names.values().collect();
+                left.sort();
+                log::warn!("Gave up waiting for background tasks: {:?}", left);
+                return;
+            }
+            names = self
+                .running
+                .done
+                .wait_timeout(names, deadline - now)
+                .unwrap()
+                .0;
+        }
+        log::debug!("Background tasks stopped.");
+    }
+}
+
+// Sleep for duration, waking early and returning true if the tasks are stopped.
+fn sleep_unless_stopped(stopping: &AtomicBool, duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    loop {
+        if stopping.load(Ordering::SeqCst) {
+            return true;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+        thread::sleep(STOP_POLL.min(deadline - now));
+    }
+}
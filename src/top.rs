@@ -0,0 +1,125 @@
+// `fusekv top`, a live view of a running mount for operators.
+//
+// Everything shown comes from the mount's own /.fusekv/stats and
+// /.fusekv/hotkeys, read every interval. Operation rates and average latencies
+// are worked out from how much the counters in stats moved since the last
+// read, so the first screen only has totals.
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Hot keys shown, at most.
+const HOT_KEYS_SHOWN: usize = 10;
+
+type Stats = BTreeMap<String, u64>;
+
+pub fn top(mount: &Path, interval: Duration) -> Result<(), Box<dyn Error>> {
+    let stats_path = mount.join(".fusekv/stats");
+    let hotkeys_path = mount.join(".fusekv/hotkeys");
+    let mut previous: Option<(Instant, Stats)> = None;
+    loop {
+        let now = Instant::now();
+        let stats = parse_stats(&fs::read_to_string(&stats_path)?);
+        // Hot key tracking may be disabled, which is no reason to stop.
+        let hotkeys = fs::read_to_string(&hotkeys_path).unwrap_or_default();
+        let screen = render(mount, interval, &stats, previous.as_ref(), now, &hotkeys);
+        // Clear the terminal and draw from the top left.
+        print!("\x1b[2J\x1b[H{}", screen);
+        std::io::stdout().flush()?;
+        previous = Some((now, stats));
+        thread::sleep(interval);
+    }
+}
+
+fn parse_stats(content: &str) -> Stats {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ' ');
+            let name = parts.next()?;
+            let value = parts.next()?.trim().parse().ok()?;
+            Some((name.to_string(), value))
+        })
+        .collect()
+}
+
+fn render(
+    mount: &Path,
+    interval: Duration,
+    stats: &Stats,
+    previous: Option<&(Instant, Stats)>,
+    now: Instant,
+    hotkeys: &str,
+) -> String {
+    let stat = |name: &str| stats.get(name).copied().unwrap_or(0);
+    let mut out = format!(
+        "fusekv top - {} (every {}s, ^C to quit)\n\n",
+        mount.display(),
+        interval.as_secs_f64()
+    );
+    out += &match stat("backend_up") {
+        0 => "backend         down\n".to_string(),
+        _ => format!(
+            "backend         up, {:.2}ms ping\n",
+            stat("backend_latency_us") as f64 / 1000.0
+        ),
+    };
+    let (hits, misses) = (stat("checksum_cache_hits"), stat("checksum_cache_misses"));
+    out += &match hits + misses {
+        0 => "checksum cache  unused\n".to_string(),
+        total => format!(
+            "checksum cache  {:.1}% hits ({}/{})\n",
+            hits as f64 * 100.0 / total as f64,
+            hits,
+            total
+        ),
+    };
+    out += &format!("truncated lists {}\n\n", stat("truncated_listings"));
+
+    out += &format!(
+        "{:<12} {:>10} {:>10} {:>10} {:>10}\n",
+        "op", "total", "rate/s", "avg ms", "max ms"
+    );
+    let ops: Vec<&str> = stats
+        .keys()
+        .filter_map(|k| k.strip_prefix("ops.")?.strip_suffix(".count"))
+        .collect();
+    for op in ops {
+        let count = stat(&format!("ops.{}.count", op));
+        let total_us = stat(&format!("ops.{}.total_us", op));
+        let max_us = stat(&format!("ops.{}.max_us", op));
+        // Rates and averages over the last interval, or since mounting for the
+        // first screen.
+        let (rate, avg_us) = match previous {
+            Some((then, before)) => {
+                let before = |name: String| before.get(&name).copied().unwrap_or(0);
+                let calls = count - before(format!("ops.{}.count", op)).min(count);
+                let spent = total_us - before(format!("ops.{}.total_us", op)).min(total_us);
+                let secs = now.duration_since(*then).as_secs_f64().max(0.001);
+                (
+                    format!("{:.1}", calls as f64 / secs),
+                    spent.checked_div(calls).unwrap_or(0),
+                )
+            }
+            None => ("-".to_string(), total_us.checked_div(count).unwrap_or(0)),
+        };
+        out += &format!(
+            "{:<12} {:>10} {:>10} {:>10.2} {:>10.2}\n",
+            op,
+            count,
+            rate,
+            avg_us as f64 / 1000.0,
+            max_us as f64 / 1000.0
+        );
+    }
+
+    out += "\nhot keys\n";
+    for line in hotkeys.lines().take(HOT_KEYS_SHOWN) {
+        out += &format!("  {}\n", line);
+    }
+    out
+}
@@ -0,0 +1,219 @@
+// Validation of values written through the mount, before they reach the
+// backend.
+//
+// Each [[validation]] stanza matches paths within the mount against its
+// pattern and checks values written to them: against a regex, size bounds, a
+// JSON Schema, or an external command reading the value on stdin. The first
+// check failing rejects the write, with a reason saying why. JSON Schemas are
+// checked for the keywords most schemas use: type, enum, const, properties,
+// required, additionalProperties, items, minItems, maxItems, minLength,
+// maxLength, pattern, minimum, maximum, exclusiveMinimum, exclusiveMaximum,
+// allOf, anyOf, and not. Anything else in a schema is ignored.
+use crate::config::Validation;
+
+use regex::Regex;
+use serde_json::Value;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// Why value can't be written to key at path, if any of rules matching path
+// reject it.
+pub fn check(rules: &[Validation], path: &str, key: &str, value: &[u8]) -> Result<(), String> {
+    for rule in rules.iter().filter(|r| r.pattern.is_match(path)) {
+        check_rule(rule, path, key, value)?;
+    }
+    Ok(())
+}
+
+fn check_rule(rule: &Validation, path: &str, key: &str, value: &[u8]) -> Result<(), String> {
+    let len = value.len() as u64;
+    if let Some(min) = rule.min_size.filter(|m| len < *m) {
+        return Err(format!("{} bytes is under min_size {}", len, min));
+    }
+    if let Some(max) = rule.max_size.filter(|m| len > *m) {
+        return Err(format!("{} bytes is over max_size {}", len, max));
+    }
+    if let Some(regex) = &rule.regex {
+        if !regex.is_match(&String::from_utf8_lossy(value)) {
+            return Err(format!("doesn't match {}", regex));
+        }
+    }
+    if let Some(schema) = &rule.schema {
+        let json: Value = serde_json::from_slice(value).map_err(|e| format!("not JSON: {}", e))?;
+        schema_error(schema, &json, "$")
+            .map_or(Ok(()), |e| Err(format!("doesn't match schema: {}", e)))?;
+    }
+    if let Some(command) = &rule.command {
+        run(command, path, key, value)?;
+    }
+    Ok(())
+}
+
+// Run command with value on stdin, failing with what it printed to stderr, or
+// its exit status if nothing, unless it exits 0.
+fn run(command: &str, path: &str, key: &str, value: &[u8]) -> Result<(), String> {
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("FUSEKV_PATH", path)
+        .env("FUSEKV_KEY", key)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(v) => v,
+        Err(e) => return Err(format!("error running {:?}: {}", command, e)),
+    };
+    // Commands may exit without reading everything.
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(value).ok();
+    }
+    let out = match child.wait_with_output() {
+        Ok(v) => v,
+        Err(e) => return Err(format!("error running {:?}: {}", command, e)),
+    };
+    if out.status.success() {
+        return Ok(());
+    }
+    match String::from_utf8_lossy(&out.stderr).trim() {
+        "" => Err(format!("{:?} exited with {}", command, out.status)),
+        reason => Err(reason.to_string()),
+    }
+}
+
+// The first way value at path doesn't match schema, if any.
+fn schema_error(schema: &Value, value: &Value, path: &str) -> Option<String> {
+    let schema = match schema {
+        Value::Bool(true) => return None,
+        Value::Bool(false) => return Some(format!("{} isn't allowed", path)),
+        Value::Object(v) => v,
+        _ => return None,
+    };
+    if let Some(types) = schema.get("type") {
+        let types: Vec<&str> = match types {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+            _ => vec![],
+        };
+        if !types.is_empty() && !types.iter().any(|t| is_type(value, t)) {
+            return Some(format!("{} isn't {}", path, types.join(" or ")));
+        }
+    }
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            return Some(format!(
+                "{} isn't one of {}",
+                path,
+                Value::from(allowed.clone())
+            ));
+        }
+    }
+    if let Some(expected) = schema.get("const").filter(|c| *c != value) {
+        return Some(format!("{} isn't {}", path, expected));
+    }
+    match value {
+        Value::Object(fields) => {
+            if let Some(Value::Array(required)) = schema.get("required") {
+                for name in required.iter().filter_map(Value::as_str) {
+                    if !fields.contains_key(name) {
+                        return Some(format!("{} is missing {}", path, name));
+                    }
+                }
+            }
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for (name, field) in fields {
+                let field_path = format!("{}.{}", path, name);
+                let error = match properties.and_then(|p| p.get(name)) {
+                    Some(s) => schema_error(s, field, &field_path),
+                    None => schema
+                        .get("additionalProperties")
+                        .and_then(|s| schema_error(s, field, &field_path)),
+                };
+                if error.is_some() {
+                    return error;
+                }
+            }
+        }
+        Value::Array(items) => {
+            let len = items.len() as f64;
+            if let Some(min) = number(schema, "minItems").filter(|m| len < *m) {
+                return Some(format!("{} has fewer than {} items", path, min));
+            }
+            if let Some(max) = number(schema, "maxItems").filter(|m| len > *m) {
+                return Some(format!("{} has more than {} items", path, max));
+            }
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    let error = schema_error(item_schema, item, &format!("{}[{}]", path, i));
+                    if error.is_some() {
+                        return error;
+                    }
+                }
+            }
+        }
+        Value::String(s) => {
+            let len = s.chars().count() as f64;
+            if let Some(min) = number(schema, "minLength").filter(|m| len < *m) {
+                return Some(format!("{} is shorter than {}", path, min));
+            }
+            if let Some(max) = number(schema, "maxLength").filter(|m| len > *m) {
+                return Some(format!("{} is longer than {}", path, max));
+            }
+            if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+                if !Regex::new(pattern).map_or(true, |r| r.is_match(s)) {
+                    return Some(format!("{} doesn't match {}", path, pattern));
+                }
+            }
+        }
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or_default();
+            if let Some(min) = number(schema, "minimum").filter(|m| n < *m) {
+                return Some(format!("{} is less than {}", path, min));
+            }
+            if let Some(max) = number(schema, "maximum").filter(|m| n > *m) {
+                return Some(format!("{} is more than {}", path, max));
+            }
+            if let Some(min) = number(schema, "exclusiveMinimum").filter(|m| n <= *m) {
+                return Some(format!("{} isn't more than {}", path, min));
+            }
+            if let Some(max) = number(schema, "exclusiveMaximum").filter(|m| n >= *m) {
+                return Some(format!("{} isn't less than {}", path, max));
+            }
+        }
+        _ => {}
+    }
+    if let Some(Value::Array(all)) = schema.get("allOf") {
+        if let Some(error) = all.iter().find_map(|s| schema_error(s, value, path)) {
+            return Some(error);
+        }
+    }
+    if let Some(Value::Array(any)) = schema.get("anyOf") {
+        if any.iter().all(|s| schema_error(s, value, path).is_some()) {
+            return Some(format!("{} matches none of anyOf", path));
+        }
+    }
+    if let Some(not) = schema.get("not") {
+        if schema_error(not, value, path).is_none() {
+            return Some(format!("{} matches not", path));
+        }
+    }
+    None
+}
+
+fn is_type(value: &Value, t: &str) -> bool {
+    match (t, value) {
+        ("object", Value::Object(_)) => true,
+        ("array", Value::Array(_)) => true,
+        ("string", Value::String(_)) => true,
+        ("number", Value::Number(_)) => true,
+        ("integer", Value::Number(n)) => n.is_i64() || n.is_u64(),
+        ("boolean", Value::Bool(_)) => true,
+        ("null", Value::Null) => true,
+        _ => false,
+    }
+}
+
+fn number(schema: &serde_json::Map<String, Value>, keyword: &str) -> Option<f64> {
+    schema.get(keyword).and_then(Value::as_f64)
+}
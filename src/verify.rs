@@ -0,0 +1,177 @@
+// `fusekv verify`, a check that a running mount agrees with its backend.
+//
+// Walks /kv on the mount and compares every file with its key, queried from
+// the backend directly: that the key exists, and has the size and type the
+// mount reports for it. Keys the mount doesn't list are reported too, unless
+// its listings were cut short. Discrepancies are down to stale caches or bugs
+// mapping keys to files, though keys written while verifying can cause some
+// that go away when run again.
+use crate::drivers::Driver;
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::ffi::CString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Keys whose sizes are asked for at once.
+const BATCH: usize = 1000;
+
+// Name of the entry /kv listings end with when cut short.
+const TRUNCATED_NAME: &str = "\u{2026}truncated";
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum VerifyError {
+        Discrepancies(n: usize) {
+            display("Found {} discrepancies between the mount and the backend.", n)
+        }
+    }
+}
+
+// What the mount lists for a key.
+struct Listed {
+    path: PathBuf,
+    size: u64,
+}
+
+pub fn verify(
+    mount: &Path,
+    driver: &dyn Driver,
+    separator: Option<&str>,
+    max_results: i64,
+) -> Result<(), Box<dyn Error>> {
+    let mut listed = BTreeMap::new();
+    let complete = walk(&mount.join("kv"), "", separator, max_results, &mut listed)?;
+    let keys: Vec<String> = driver
+        .list_keys(0, -1)?
+        .into_iter()
+        .map(|r| r.key)
+        .collect();
+    let mut problems = vec![];
+    if complete {
+        for key in &keys {
+            if !listed.contains_key(key) {
+                problems.push(format!("{}: in the backend but not listed", key));
+            }
+        }
+    } else {
+        log::warn!("The mount's listings are cut short, so keys it doesn't list aren't reported.");
+    }
+    let keys: HashSet<&String> = keys.iter().collect();
+    // Types are only checked if the backend can tell them.
+    let mut check_types = true;
+    let names: Vec<&String> = listed.keys().collect();
+    for batch in names.chunks(BATCH) {
+        let batch: Vec<String> = batch.iter().map(|k| k.to_string()).collect();
+        let sizes = driver.sizes(&batch)?;
+        for (key, size) in batch.iter().zip(sizes) {
+            let file = &listed[key];
+            if !keys.contains(key) {
+                problems.push(format!(
+                    "{}: listed but not in the backend",
+                    file.path.display()
+                ));
+                continue;
+            }
+            if file.size != size {
+                problems.push(format!(
+                    "{}: size {} but the backend's value is {} bytes",
+                    file.path.display(),
+                    file.size,
+                    size
+                ));
+            }
+            if !check_types {
+                continue;
+            }
+            let kind = match driver.key_info(key) {
+                Ok(Some(info)) => info.kind,
+                // Deleted since it was listed.
+                Ok(None) => continue,
+                Err(e) => {
+                    log::info!("Not checking types: {}", e);
+                    check_types = false;
+                    continue;
+                }
+            };
+            match type_xattr(&file.path) {
+                Some(t) if t != kind => problems.push(format!(
+                    "{}: type {} but the backend's is {}",
+                    file.path.display(),
+                    t,
+                    kind
+                )),
+                _ => {}
+            }
+        }
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    println!(
+        "Checked {} files against {} keys.",
+        listed.len(),
+        keys.len()
+    );
+    if !problems.is_empty() {
+        return Err(Box::new(VerifyError::Discrepancies(problems.len())));
+    }
+    Ok(())
+}
+
+// Add the files under dir to listed by key, where namespace is the key prefix
+// dir stands for. Returns whether every listing was complete.
+fn walk(
+    dir: &Path,
+    namespace: &str,
+    separator: Option<&str>,
+    max_results: i64,
+    listed: &mut BTreeMap<String, Listed>,
+) -> Result<bool, Box<dyn Error>> {
+    let mut complete = true;
+    let mut n: i64 = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name == TRUNCATED_NAME {
+            complete = false;
+            continue;
+        }
+        n += 1;
+        let meta = entry.metadata()?;
+        if meta.is_dir() {
+            let prefix = format!("{}{}{}", namespace, name, separator.unwrap_or_default());
+            complete &= walk(&entry.path(), &prefix, separator, max_results, listed)?;
+            continue;
+        }
+        listed.insert(
+            format!("{}{}", namespace, name),
+            Listed {
+                path: entry.path(),
+                size: meta.len(),
+            },
+        );
+    }
+    // Listings stop at max_results entries without saying so.
+    Ok(complete && (max_results < 0 || n < max_results))
+}
+
+// The type the mount reports for the file at path, if it reports one.
+fn type_xattr(path: &Path) -> Option<String> {
+    let c_path = CString::new(path.to_str()?).ok()?;
+    let c_name = CString::new("user.type").ok()?;
+    let mut buf = vec![0u8; 64];
+    let n = unsafe {
+        libc::getxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+        )
+    };
+    if n < 0 {
+        return None;
+    }
+    buf.truncate(n as usize);
+    String::from_utf8(buf).ok()
+}
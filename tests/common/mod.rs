@@ -0,0 +1,1452 @@
+// Shared harness for the integration tests.
+//
+// FakeRedis is a minimal in-process RESP server backed by a scriptable
+// keyspace, and Mount runs the real fusekv binary against it so tests can
+// drive the filesystem through plain libc calls.
+#![allow(dead_code)]
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone)]
+pub enum Reply {
+    Nil,
+    Status(String),
+    Int(i64),
+    Bulk(Vec<u8>),
+    Error(String),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Nil => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Status(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            Reply::Int(i) => out.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+            Reply::Bulk(b) => {
+                out.extend_from_slice(format!("${}\r\n", b.len()).as_bytes());
+                out.extend_from_slice(b);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Error(e) => out.extend_from_slice(format!("-{}\r\n", e).as_bytes()),
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+}
+
+// An entry of a stream, as its ID and its fields and values in turn.
+type StreamEntry = (String, Vec<Vec<u8>>);
+
+#[derive(Default)]
+struct Script {
+    keys: BTreeMap<String, Vec<u8>>,
+    hashes: BTreeMap<String, BTreeMap<String, Vec<u8>>>,
+    // Entries of each stream, as ID and fields and values in turn. IDs are
+    // 0-<n> for the nth entry.
+    streams: BTreeMap<String, Vec<StreamEntry>>,
+    // JSON documents, as the RedisJSON module holds them.
+    json: BTreeMap<String, serde_json::Value>,
+    // Items of each list, head first. Lists are never scanned, and BRPOP
+    // never blocks.
+    lists: BTreeMap<String, VecDeque<Vec<u8>>>,
+    // Members of each set, eg. fusekv's tag sets, scanned along with the
+    // strings in keys.
+    sets: BTreeMap<String, BTreeSet<String>>,
+    // Members of each sorted set and their scores, lowest first, eg. the
+    // versions of keys. Scanned along with strings too.
+    zsets: BTreeMap<String, Vec<(i64, Vec<u8>)>>,
+    // Scripts loaded by their SHA1, which can only be those run_script knows.
+    scripts: BTreeMap<String, String>,
+    // Names of the modules MODULE LIST reports as loaded.
+    modules: Vec<String>,
+    // Seconds until each key with an expiry expires. Keys never actually do.
+    ttls: BTreeMap<String, i64>,
+    // Canned replies for a command name, taking precedence over the keyspace.
+    overrides: BTreeMap<String, Reply>,
+    // The only password AUTH accepts, if any.
+    password: Option<String>,
+    latency: Duration,
+    // How long each command name is held before being run, as if still on
+    // its way to the server.
+    stalls: BTreeMap<String, Duration>,
+    // Seconds TIME is ahead of the local clock.
+    clock_ahead: i64,
+    log: Vec<Vec<String>>,
+    // Connections subscribed to channels, by SUBSCRIBE, SSUBSCRIBE or
+    // PSUBSCRIBE, and the channel or pattern each is subscribed to.
+    subscribers: Vec<(String, String, TcpStream)>,
+    // Where the server listens, which it claims to be in cluster mode.
+    port: u16,
+    // Whether scripts, transactions and commands whose keys hash to different
+    // slots are refused, as a real cluster does.
+    check_slots: bool,
+    // Connections accepted so far.
+    connections: usize,
+}
+
+#[derive(Clone)]
+pub struct FakeRedis {
+    port: u16,
+    script: Arc<Mutex<Script>>,
+}
+
+impl FakeRedis {
+    pub fn start() -> FakeRedis {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let script = Arc::new(Mutex::new(Script {
+            port,
+            ..Script::default()
+        }));
+        let accept_script = script.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let script = accept_script.clone();
+                match stream {
+                    Ok(s) => {
+                        script.lock().unwrap().connections += 1;
+                        thread::spawn(move || serve(s, script));
+                    }
+                    Err(_) => return,
+                }
+            }
+        });
+        FakeRedis { port, script }
+    }
+
+    pub fn url(&self) -> String {
+        format!("redis://127.0.0.1:{}", self.port)
+    }
+
+    pub fn set(&self, key: &str, val: &[u8]) -> &FakeRedis {
+        self.script
+            .lock()
+            .unwrap()
+            .keys
+            .insert(key.to_string(), val.to_vec());
+        self
+    }
+
+    // Drop every key, as FLUSHDB would.
+    pub fn flush(&self) -> &FakeRedis {
+        let mut script = self.script.lock().unwrap();
+        script.keys.clear();
+        script.hashes.clear();
+        script.streams.clear();
+        script.ttls.clear();
+        drop(script);
+        self
+    }
+
+    // Add an entry of fields to the stream at key, as XADD would.
+    pub fn xadd(&self, key: &str, fields: &[(&str, &str)]) -> &FakeRedis {
+        let args: Vec<Vec<u8>> = fields
+            .iter()
+            .flat_map(|(f, v)| vec![f.as_bytes().to_vec(), v.as_bytes().to_vec()])
+            .collect();
+        xadd(&mut self.script.lock().unwrap(), key, args);
+        self
+    }
+
+    // Have key expire in secs seconds, as EXPIRE would.
+    pub fn expire(&self, key: &str, secs: i64) -> &FakeRedis {
+        self.script
+            .lock()
+            .unwrap()
+            .ttls
+            .insert(key.to_string(), secs);
+        self
+    }
+
+    // Seconds until key expires, or None if it doesn't.
+    pub fn ttl(&self, key: &str) -> Option<i64> {
+        self.script.lock().unwrap().ttls.get(key).cloned()
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.script.lock().unwrap().keys.get(key).cloned()
+    }
+
+    // Report name as a loaded module. Loading ReJSON doesn't change which
+    // commands are understood.
+    pub fn module(&self, name: &str) -> &FakeRedis {
+        self.script.lock().unwrap().modules.push(name.to_string());
+        self
+    }
+
+    // Set key to the JSON document json, as JSON.SET key $ would.
+    pub fn json_set(&self, key: &str, json: &str) -> &FakeRedis {
+        self.script
+            .lock()
+            .unwrap()
+            .json
+            .insert(key.to_string(), serde_json::from_str(json).unwrap());
+        self
+    }
+
+    pub fn json_get(&self, key: &str) -> Option<serde_json::Value> {
+        self.script.lock().unwrap().json.get(key).cloned()
+    }
+
+    pub fn hset(&self, hash: &str, field: &str, val: &[u8]) -> &FakeRedis {
+        self.script
+            .lock()
+            .unwrap()
+            .hashes
+            .entry(hash.to_string())
+            .or_default()
+            .insert(field.to_string(), val.to_vec());
+        self
+    }
+
+    pub fn hget(&self, hash: &str, field: &str) -> Option<Vec<u8>> {
+        let script = self.script.lock().unwrap();
+        script.hashes.get(hash).and_then(|h| h.get(field)).cloned()
+    }
+
+    // Add items to the end of the list at key, as RPUSH would.
+    pub fn rpush(&self, key: &str, items: &[&str]) -> &FakeRedis {
+        self.script
+            .lock()
+            .unwrap()
+            .lists
+            .entry(key.to_string())
+            .or_default()
+            .extend(items.iter().map(|i| i.as_bytes().to_vec()));
+        self
+    }
+
+    // Items of the list at key, head first.
+    pub fn lrange(&self, key: &str) -> Vec<String> {
+        let script = self.script.lock().unwrap();
+        script
+            .lists
+            .get(key)
+            .into_iter()
+            .flatten()
+            .map(|i| String::from_utf8_lossy(i).into_owned())
+            .collect()
+    }
+
+    pub fn sadd(&self, set: &str, members: &[&str]) -> &FakeRedis {
+        self.script
+            .lock()
+            .unwrap()
+            .sets
+            .entry(set.to_string())
+            .or_default()
+            .extend(members.iter().map(|m| m.to_string()));
+        self
+    }
+
+    pub fn smembers(&self, set: &str) -> Vec<String> {
+        let script = self.script.lock().unwrap();
+        script
+            .sets
+            .get(set)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    // Add member to the sorted set at key with score, as ZADD would.
+    pub fn zadd(&self, key: &str, score: i64, member: &[u8]) -> &FakeRedis {
+        let mut script = self.script.lock().unwrap();
+        let zset = script.zsets.entry(key.to_string()).or_default();
+        zset.retain(|(_, m)| m != member);
+        zset.push((score, member.to_vec()));
+        zset.sort();
+        drop(script);
+        self
+    }
+
+    pub fn hdel(&self, hash: &str, field: &str) -> &FakeRedis {
+        if let Some(h) = self.script.lock().unwrap().hashes.get_mut(hash) {
+            h.remove(field);
+        }
+        self
+    }
+
+    // Send the keyevent notification of event on key, as a server with
+    // notify-keyspace-events set would for database 0.
+    pub fn notify(&self, event: &str, key: &str) -> &FakeRedis {
+        let channel = format!("__keyevent@0__:{}", event);
+        publish(
+            &mut self.script.lock().unwrap(),
+            "PUBLISH",
+            &channel,
+            key.as_bytes(),
+        );
+        self
+    }
+
+    // Answer every invocation of cmd with reply instead of consulting the keyspace.
+    pub fn reply(&self, cmd: &str, reply: Reply) -> &FakeRedis {
+        self.script
+            .lock()
+            .unwrap()
+            .overrides
+            .insert(cmd.to_uppercase(), reply);
+        self
+    }
+
+    pub fn clear_reply(&self, cmd: &str) -> &FakeRedis {
+        self.script
+            .lock()
+            .unwrap()
+            .overrides
+            .remove(&cmd.to_uppercase());
+        self
+    }
+
+    // Have AUTH accept only password.
+    pub fn require_password(&self, password: &str) -> &FakeRedis {
+        self.script.lock().unwrap().password = Some(password.to_string());
+        self
+    }
+
+    // Delay every reply by d.
+    pub fn latency(&self, d: Duration) -> &FakeRedis {
+        self.script.lock().unwrap().latency = d;
+        self
+    }
+
+    // Hold off running every cmd for d after it arrives.
+    pub fn stall(&self, cmd: &str, d: Duration) -> &FakeRedis {
+        self.script
+            .lock()
+            .unwrap()
+            .stalls
+            .insert(cmd.to_string(), d);
+        self
+    }
+
+    // Refuse commands touching keys in more than one slot with CROSSSLOT.
+    pub fn check_slots(&self) -> &FakeRedis {
+        self.script.lock().unwrap().check_slots = true;
+        self
+    }
+
+    // Have TIME report a clock secs ahead of the local one.
+    pub fn clock_ahead(&self, secs: i64) -> &FakeRedis {
+        self.script.lock().unwrap().clock_ahead = secs;
+        self
+    }
+
+    // Every command received so far, as upper-cased name followed by its args.
+    // How many connections have been made to the server.
+    pub fn connections(&self) -> usize {
+        self.script.lock().unwrap().connections
+    }
+
+    pub fn commands(&self) -> Vec<Vec<String>> {
+        self.script.lock().unwrap().log.clone()
+    }
+
+    pub fn count(&self, cmd: &str) -> usize {
+        let cmd = cmd.to_uppercase();
+        self.commands().iter().filter(|c| c[0] == cmd).count()
+    }
+}
+
+fn serve(stream: TcpStream, script: Arc<Mutex<Script>>) {
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    // Commands queued since MULTI, run together on EXEC.
+    let mut queued = None;
+    loop {
+        let args = match read_command(&mut reader) {
+            Some(v) => v,
+            None => return,
+        };
+        let cmd = String::from_utf8_lossy(&args[0]).to_uppercase();
+        let stall = script.lock().unwrap().stalls.get(&cmd).copied();
+        if let Some(d) = stall {
+            thread::sleep(d);
+        }
+        let (reply, latency) = {
+            let mut script = script.lock().unwrap();
+            let reply = match cmd.as_str() {
+                "SUBSCRIBE" | "SSUBSCRIBE" | "PSUBSCRIBE" if args.len() == 2 => {
+                    let channel = String::from_utf8_lossy(&args[1]).to_string();
+                    let conn = writer.try_clone().unwrap();
+                    script.subscribers.push((cmd.clone(), channel, conn));
+                    Reply::Array(vec![
+                        Reply::Bulk(cmd.to_lowercase().into_bytes()),
+                        Reply::Bulk(args[1].clone()),
+                        Reply::Int(1),
+                    ])
+                }
+                _ => transact(&mut script, &mut queued, &args),
+            };
+            let mut logged: Vec<String> = args
+                .iter()
+                .map(|a| String::from_utf8_lossy(a).to_string())
+                .collect();
+            logged[0] = logged[0].to_uppercase();
+            script.log.push(logged);
+            (reply, script.latency)
+        };
+        thread::sleep(latency);
+        let mut out = vec![];
+        reply.encode(&mut out);
+        if writer.write_all(&out).is_err() {
+            return;
+        }
+    }
+}
+
+// Send message to the subscribers of channel, returning how many there were.
+// SPUBLISH only reaches SSUBSCRIBE, and PUBLISH the others. Patterns only
+// support a trailing *. Subscribers that have gone are dropped.
+fn publish(script: &mut Script, cmd: &str, channel: &str, message: &[u8]) -> Reply {
+    let mut n = 0;
+    script.subscribers.retain_mut(|(kind, to, conn)| {
+        let push = match (cmd, kind.as_str()) {
+            ("SPUBLISH", "SSUBSCRIBE") if to == channel => vec!["smessage", channel],
+            ("PUBLISH", "SUBSCRIBE") if to == channel => vec!["message", channel],
+            ("PUBLISH", "PSUBSCRIBE")
+                if to
+                    .strip_suffix('*')
+                    .map_or(to == channel, |p| channel.starts_with(p)) =>
+            {
+                vec!["pmessage", to.as_str(), channel]
+            }
+            _ => return true,
+        };
+        let mut items: Vec<Reply> = push
+            .into_iter()
+            .map(|s| Reply::Bulk(s.as_bytes().to_vec()))
+            .collect();
+        items.push(Reply::Bulk(message.to_vec()));
+        let mut out = vec![];
+        Reply::Array(items).encode(&mut out);
+        n += 1;
+        conn.write_all(&out).is_ok()
+    });
+    Reply::Int(n)
+}
+
+// Add an entry of fields to the stream at key, returning its ID.
+fn xadd(script: &mut Script, key: &str, fields: Vec<Vec<u8>>) -> String {
+    let entries = script.streams.entry(key.to_string()).or_default();
+    let id = format!("0-{}", entries.len() + 1);
+    entries.push((id.clone(), fields));
+    id
+}
+
+fn stream_entry((id, fields): &(String, Vec<Vec<u8>>)) -> Reply {
+    Reply::Array(vec![
+        Reply::Bulk(id.clone().into_bytes()),
+        Reply::Array(fields.iter().map(|f| Reply::Bulk(f.clone())).collect()),
+    ])
+}
+
+// The n of an entry ID 0-<n>.
+fn entry_seq(id: &str) -> u64 {
+    id.rsplit('-')
+        .next()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0)
+}
+
+fn read_command(reader: &mut BufReader<TcpStream>) -> Option<Vec<Vec<u8>>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).ok()? == 0 {
+        return None;
+    }
+    let n: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+    let mut args = Vec::with_capacity(n);
+    for _ in 0..n {
+        line.clear();
+        reader.read_line(&mut line).ok()?;
+        let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+        let mut buf = vec![0; len + 2];
+        reader.read_exact(&mut buf).ok()?;
+        buf.truncate(len);
+        args.push(buf);
+    }
+    Some(args)
+}
+
+// Reply to args, queueing them instead while a MULTI is open.
+fn transact(
+    script: &mut Script,
+    queued: &mut Option<Vec<Vec<Vec<u8>>>>,
+    args: &[Vec<u8>],
+) -> Reply {
+    let cmd = String::from_utf8_lossy(&args[0]).to_uppercase();
+    match (cmd.as_str(), queued.take()) {
+        ("MULTI", None) => {
+            *queued = Some(vec![]);
+            Reply::Status("OK".to_string())
+        }
+        ("EXEC", Some(cmds))
+            if script.check_slots && cross_slot(cmds.iter().flat_map(|c| keys_of(c))) =>
+        {
+            cross_slot_error()
+        }
+        ("EXEC", Some(cmds)) => match script.overrides.get("EXEC") {
+            Some(reply) => reply.clone(),
+            None => Reply::Array(cmds.iter().map(|c| dispatch(script, c)).collect()),
+        },
+        ("DISCARD", Some(_)) => Reply::Status("OK".to_string()),
+        (_, Some(mut cmds)) => {
+            cmds.push(args.to_vec());
+            *queued = Some(cmds);
+            Reply::Status("QUEUED".to_string())
+        }
+        (_, None) if script.check_slots && cross_slot(keys_of(args).into_iter()) => {
+            cross_slot_error()
+        }
+        (_, None) => dispatch(script, args),
+    }
+}
+
+// The keys of a command: those declared to a script, every argument of the
+// multi-key commands fusekv sends, and otherwise the first argument.
+fn keys_of(args: &[Vec<u8>]) -> Vec<&[u8]> {
+    let cmd = String::from_utf8_lossy(&args[0]).to_uppercase();
+    match cmd.as_str() {
+        "EVALSHA" | "EVAL" => {
+            let n: usize = String::from_utf8_lossy(&args[2]).parse().unwrap_or(0);
+            args[3..3 + n].iter().map(|k| k.as_slice()).collect()
+        }
+        "DEL" | "UNLINK" | "EXISTS" | "MGET" => args[1..].iter().map(|k| k.as_slice()).collect(),
+        _ => args.get(1).map(|k| k.as_slice()).into_iter().collect(),
+    }
+}
+
+// Whether keys hash to more than one slot.
+fn cross_slot<'a>(keys: impl Iterator<Item = &'a [u8]>) -> bool {
+    let mut slots: Vec<u16> = keys.map(key_slot).collect();
+    slots.sort_unstable();
+    slots.dedup();
+    slots.len() > 1
+}
+
+fn cross_slot_error() -> Reply {
+    Reply::Error("CROSSSLOT Keys in request don't hash to the same slot".to_string())
+}
+
+// The cluster slot of key: the CRC16 of its hash tag, the part between the
+// first { and the next }, or of the whole key without one.
+fn key_slot(key: &[u8]) -> u16 {
+    let tagged = key.iter().position(|&b| b == b'{').and_then(|open| {
+        let rest = &key[open + 1..];
+        rest.iter()
+            .position(|&b| b == b'}')
+            .filter(|&close| close > 0)
+            .map(|close| &rest[..close])
+    });
+    let mut crc: u16 = 0;
+    for &b in tagged.unwrap_or(key) {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x1021,
+            };
+        }
+    }
+    crc % 16384
+}
+
+// Segments of a JSONPath, eg. $["a"][0], as the field names and indexes they
+// are in turn. Only bracketed segments are supported, which is all fusekv sends.
+fn json_path(path: &str) -> Vec<serde_json::Value> {
+    let mut rest = path.strip_prefix('$').unwrap_or(path);
+    let mut segments = vec![];
+    while let Some(inner) = rest.strip_prefix('[') {
+        let mut values = serde_json::Deserializer::from_str(inner).into_iter();
+        segments.push(values.next().unwrap().unwrap());
+        rest = &inner[values.byte_offset()..];
+        rest = rest.strip_prefix(']').unwrap();
+    }
+    segments
+}
+
+// The value at segments under value, if there is one.
+fn json_at<'a>(
+    value: &'a mut serde_json::Value,
+    segments: &[serde_json::Value],
+) -> Option<&'a mut serde_json::Value> {
+    segments.iter().try_fold(value, |v, s| match (v, s) {
+        (serde_json::Value::Object(m), serde_json::Value::String(k)) => m.get_mut(k),
+        (serde_json::Value::Array(a), serde_json::Value::Number(i)) => {
+            a.get_mut(i.as_u64()? as usize)
+        }
+        _ => None,
+    })
+}
+
+// What DUMP payloads start with.
+const DUMP_MARKER: &[u8] = b"fakedump:";
+
+fn dispatch(script: &mut Script, args: &[Vec<u8>]) -> Reply {
+    let cmd = String::from_utf8_lossy(&args[0]).to_uppercase();
+    let arg = |i: usize| String::from_utf8_lossy(&args[i]).to_string();
+    if let Some(reply) = script.overrides.get(&cmd) {
+        return reply.clone();
+    }
+    let on_stream = args.len() > 1 && script.streams.contains_key(&arg(1));
+    let on_json = args.len() > 1 && script.json.contains_key(&arg(1));
+    let on_items =
+        args.len() > 1 && (script.lists.contains_key(&arg(1)) || script.sets.contains_key(&arg(1)));
+    if (on_stream || on_json || on_items)
+        && matches!(cmd.as_str(), "GET" | "STRLEN" | "GETRANGE" | "SETRANGE")
+    {
+        return Reply::Error(
+            "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+        );
+    }
+    match cmd.as_str() {
+        "PING" => Reply::Status("PONG".to_string()),
+        // Every database shares the one keyspace.
+        "SELECT" => Reply::Status("OK".to_string()),
+        "AUTH" => match &script.password {
+            Some(password) if *password != arg(args.len() - 1) => Reply::Error(
+                "WRONGPASS invalid username-password pair or user is disabled.".to_string(),
+            ),
+            _ => Reply::Status("OK".to_string()),
+        },
+        "GET" => match script.keys.get(&arg(1)) {
+            Some(v) => Reply::Bulk(v.clone()),
+            None => Reply::Nil,
+        },
+        // Only the NX, EX and PX options are understood.
+        "SET" => {
+            let option = |name: &str| {
+                (3..args.len()).find(|&i| args[i].eq_ignore_ascii_case(name.as_bytes()))
+            };
+            if option("NX").is_some() && script.keys.contains_key(&arg(1)) {
+                return Reply::Nil;
+            }
+            script.ttls.remove(&arg(1));
+            script.keys.insert(arg(1), args[2].clone());
+            let secs = match (option("EX"), option("PX")) {
+                (Some(i), _) => arg(i + 1).parse().ok(),
+                (_, Some(i)) => arg(i + 1).parse::<i64>().ok().map(|ms| (ms + 999) / 1000),
+                _ => None,
+            };
+            if let Some(secs) = secs {
+                script.ttls.insert(arg(1), secs);
+            }
+            Reply::Status("OK".to_string())
+        }
+        "DEL" | "UNLINK" => {
+            let mut n = 0;
+            for i in 1..args.len() {
+                script.ttls.remove(&arg(i));
+                if script.keys.remove(&arg(i)).is_some()
+                    || script.sets.remove(&arg(i)).is_some()
+                    || script.lists.remove(&arg(i)).is_some()
+                    || script.streams.remove(&arg(i)).is_some()
+                    || script.hashes.remove(&arg(i)).is_some()
+                    || script.json.remove(&arg(i)).is_some()
+                {
+                    n += 1;
+                }
+            }
+            Reply::Int(n)
+        }
+        "LPUSH" => {
+            let list = script.lists.entry(arg(1)).or_default();
+            for item in &args[2..] {
+                list.push_front(item.clone());
+            }
+            Reply::Int(list.len() as i64)
+        }
+        "RPUSH" => {
+            let list = script.lists.entry(arg(1)).or_default();
+            list.extend(args[2..].iter().cloned());
+            Reply::Int(list.len() as i64)
+        }
+        // Only whole lists are read, as LRANGE key 0 -1.
+        "LRANGE" => Reply::Array(
+            script
+                .lists
+                .get(&arg(1))
+                .into_iter()
+                .flatten()
+                .map(|item| Reply::Bulk(item.clone()))
+                .collect(),
+        ),
+        "BRPOP" => {
+            let queue = arg(1);
+            let list = script.lists.entry(queue.clone()).or_default();
+            let popped = list.pop_back();
+            if list.is_empty() {
+                script.lists.remove(&queue);
+            }
+            match popped {
+                Some(item) => {
+                    Reply::Array(vec![Reply::Bulk(queue.into_bytes()), Reply::Bulk(item)])
+                }
+                None => Reply::Nil,
+            }
+        }
+        "SADD" => {
+            let set = script.sets.entry(arg(1)).or_default();
+            Reply::Int((2..args.len()).filter(|&i| set.insert(arg(i))).count() as i64)
+        }
+        "SREM" => {
+            let set = script.sets.entry(arg(1)).or_default();
+            let n = (2..args.len()).filter(|&i| set.remove(&arg(i))).count();
+            if set.is_empty() {
+                script.sets.remove(&arg(1));
+            }
+            Reply::Int(n as i64)
+        }
+        "SMEMBERS" => Reply::Array(
+            script
+                .sets
+                .get(&arg(1))
+                .into_iter()
+                .flatten()
+                .map(|m| Reply::Bulk(m.as_bytes().to_vec()))
+                .collect(),
+        ),
+        // ZREVRANGEBYSCORE key max min [LIMIT offset count]
+        "ZREVRANGEBYSCORE" => {
+            let (max, min) = (score_bound(&arg(2)), score_bound(&arg(3)));
+            let (offset, count) = match args.len() > 6 && arg(4).eq_ignore_ascii_case("LIMIT") {
+                true => (arg(5).parse().unwrap_or(0), arg(6).parse().unwrap_or(0)),
+                false => (0, usize::MAX),
+            };
+            Reply::Array(
+                script
+                    .zsets
+                    .get(&arg(1))
+                    .into_iter()
+                    .flat_map(|z| z.iter().rev())
+                    .filter(|(score, _)| (min..=max).contains(score))
+                    .skip(offset)
+                    .take(count)
+                    .map(|(_, m)| Reply::Bulk(m.clone()))
+                    .collect(),
+            )
+        }
+        "ZCOUNT" => {
+            let (min, max) = (score_bound(&arg(2)), score_bound(&arg(3)));
+            Reply::Int(script.zsets.get(&arg(1)).map_or(0, |z| {
+                z.iter()
+                    .filter(|(score, _)| (min..=max).contains(score))
+                    .count()
+            }) as i64)
+        }
+        "SCRIPT" if arg(1).eq_ignore_ascii_case("LOAD") && runs_script(&arg(2)) => {
+            let sha: String = openssl::sha::sha1(&args[2])
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            script.scripts.insert(sha.clone(), arg(2));
+            Reply::Bulk(sha.into_bytes())
+        }
+        "EVALSHA" => match script.scripts.get(&arg(1)).cloned() {
+            Some(body) => {
+                let n: usize = arg(2).parse().unwrap_or(0);
+                let (keys, argv) = args[3..].split_at(n);
+                run_script(script, &body, keys, argv)
+            }
+            None => Reply::Error("NOSCRIPT No matching script. Please use EVAL.".to_string()),
+        },
+        "PUBLISH" | "SPUBLISH" => publish(script, &cmd, &arg(1), &args[2]),
+        "PUBSUB" => {
+            let kind = match arg(1).to_uppercase().as_str() {
+                "SHARDCHANNELS" => "SSUBSCRIBE",
+                _ => "SUBSCRIBE",
+            };
+            let channels: BTreeSet<&String> = script
+                .subscribers
+                .iter()
+                .filter(|(k, _, _)| k == kind)
+                .map(|(_, c, _)| c)
+                .collect();
+            Reply::Array(
+                channels
+                    .into_iter()
+                    .map(|c| Reply::Bulk(c.as_bytes().to_vec()))
+                    .collect(),
+            )
+        }
+        "EXISTS" => Reply::Int((script.keys.contains_key(&arg(1)) || on_stream) as i64),
+        "STRLEN" => Reply::Int(script.keys.get(&arg(1)).map_or(0, |v| v.len()) as i64),
+        // Only handles non-negative offsets.
+        "GETRANGE" => {
+            let value = script.keys.get(&arg(1)).cloned().unwrap_or_default();
+            let start: usize = arg(2).parse::<usize>().unwrap_or(0).min(value.len());
+            let end: usize = (arg(3).parse::<usize>().unwrap_or(0) + 1).min(value.len());
+            Reply::Bulk(value[start..end.max(start)].to_vec())
+        }
+        "SETRANGE" => {
+            let value = script.keys.entry(arg(1)).or_default();
+            let offset: usize = arg(2).parse().unwrap_or(0);
+            let end = offset + args[3].len();
+            if value.len() < end {
+                value.resize(end, 0);
+            }
+            value[offset..end].copy_from_slice(&args[3]);
+            Reply::Int(value.len() as i64)
+        }
+        "TYPE" => match script.keys.get(&arg(1)) {
+            Some(_) => Reply::Status("string".to_string()),
+            None if script.sets.contains_key(&arg(1)) => Reply::Status("set".to_string()),
+            None if script.lists.contains_key(&arg(1)) => Reply::Status("list".to_string()),
+            None if script.zsets.contains_key(&arg(1)) => Reply::Status("zset".to_string()),
+            None if on_stream => Reply::Status("stream".to_string()),
+            None if on_json => Reply::Status("ReJSON-RL".to_string()),
+            None => Reply::Status("none".to_string()),
+        },
+        "EXPIRE" => match script.keys.contains_key(&arg(1)) {
+            true => {
+                script.ttls.insert(arg(1), arg(2).parse().unwrap_or(0));
+                Reply::Int(1)
+            }
+            false => Reply::Int(0),
+        },
+        "PERSIST" => Reply::Int(script.ttls.remove(&arg(1)).is_some() as i64),
+        "TTL" => match (
+            script.keys.contains_key(&arg(1)) || on_items,
+            script.ttls.get(&arg(1)),
+        ) {
+            (false, _) => Reply::Int(-2),
+            (true, Some(ttl)) => Reply::Int(*ttl),
+            (true, None) => Reply::Int(-1),
+        },
+        "PTTL" => match (
+            script.keys.contains_key(&arg(1)) || on_items,
+            script.ttls.get(&arg(1)),
+        ) {
+            (false, _) => Reply::Int(-2),
+            (true, Some(ttl)) => Reply::Int(*ttl * 1000),
+            (true, None) => Reply::Int(-1),
+        },
+        // Only strings are dumped, as their value behind a marker RESTORE
+        // checks for.
+        "DUMP" => match script.keys.get(&arg(1)) {
+            Some(v) => Reply::Bulk([DUMP_MARKER, v].concat()),
+            None => Reply::Nil,
+        },
+        "RESTORE" => match args[3].strip_prefix(DUMP_MARKER) {
+            Some(v) => {
+                script.keys.insert(arg(1), v.to_vec());
+                match arg(2).parse::<i64>().unwrap_or(0) {
+                    0 => script.ttls.remove(&arg(1)),
+                    ms => script.ttls.insert(arg(1), ms / 1000),
+                };
+                Reply::Status("OK".to_string())
+            }
+            None => Reply::Error("ERR DUMP payload version or checksum are wrong".to_string()),
+        },
+        "OBJECT" => match script.keys.get(&arg(2)) {
+            Some(_) => Reply::Bulk(b"embstr".to_vec()),
+            None => Reply::Nil,
+        },
+        "MEMORY" => match script.keys.get(&arg(2)) {
+            Some(v) => Reply::Int(v.len() as i64 + 50),
+            None => Reply::Nil,
+        },
+        // A cluster of one master holding every slot.
+        "CLUSTER" if arg(1).to_uppercase() == "KEYSLOT" => Reply::Int(key_slot(&args[2]) as i64),
+        "CLUSTER" if arg(1).to_uppercase() == "SLOTS" => Reply::Array(vec![Reply::Array(vec![
+            Reply::Int(0),
+            Reply::Int(16383),
+            Reply::Array(vec![
+                Reply::Bulk(b"127.0.0.1".to_vec()),
+                Reply::Int(script.port as i64),
+            ]),
+        ])]),
+        "DBSIZE" => Reply::Int(script.keys.len() as i64),
+        "TIME" => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            let secs = now.as_secs() as i64 + script.clock_ahead;
+            Reply::Array(vec![
+                Reply::Bulk(secs.to_string().into_bytes()),
+                Reply::Bulk(now.subsec_micros().to_string().into_bytes()),
+            ])
+        }
+        "HSET" => {
+            let hash = script.hashes.entry(arg(1)).or_default();
+            let mut n = 0;
+            for i in (2..args.len()).step_by(2) {
+                if hash.insert(arg(i), args[i + 1].clone()).is_none() {
+                    n += 1;
+                }
+            }
+            Reply::Int(n)
+        }
+        "HSETNX" => {
+            let hash = script.hashes.entry(arg(1)).or_default();
+            if let std::collections::btree_map::Entry::Vacant(e) = hash.entry(arg(2)) {
+                e.insert(args[3].clone());
+                Reply::Int(1)
+            } else {
+                Reply::Int(0)
+            }
+        }
+        "HGET" => match script.hashes.get(&arg(1)).and_then(|h| h.get(&arg(2))) {
+            Some(v) => Reply::Bulk(v.clone()),
+            None => Reply::Nil,
+        },
+        "HMGET" => Reply::Array(
+            (2..args.len())
+                .map(
+                    |i| match script.hashes.get(&arg(1)).and_then(|h| h.get(&arg(i))) {
+                        Some(v) => Reply::Bulk(v.clone()),
+                        None => Reply::Nil,
+                    },
+                )
+                .collect(),
+        ),
+        "HDEL" => {
+            let hash = script.hashes.entry(arg(1)).or_default();
+            let n = (2..args.len())
+                .filter(|&i| hash.remove(&arg(i)).is_some())
+                .count();
+            Reply::Int(n as i64)
+        }
+        // Any MAXLEN is ignored.
+        "XADD" => {
+            let start = args
+                .iter()
+                .position(|a| a == b"*")
+                .unwrap_or(args.len() - 1)
+                + 1;
+            Reply::Bulk(xadd(script, &arg(1), args[start..].to_vec()).into_bytes())
+        }
+        "XRANGE" => Reply::Array(
+            script
+                .streams
+                .get(&arg(1))
+                .into_iter()
+                .flatten()
+                .map(stream_entry)
+                .collect(),
+        ),
+        // Never actually blocks, replying nil straight away if there's nothing
+        // new. Only reads one stream.
+        "XREAD" => {
+            let streams = args.iter().position(|a| a.eq_ignore_ascii_case(b"STREAMS"));
+            let (key, after) = match streams {
+                Some(i) if i + 2 < args.len() => (arg(i + 1), entry_seq(&arg(i + 2))),
+                _ => return Reply::Error("ERR syntax error".to_string()),
+            };
+            let entries: Vec<Reply> = script
+                .streams
+                .get(&key)
+                .into_iter()
+                .flatten()
+                .filter(|(id, _)| entry_seq(id) > after)
+                .map(stream_entry)
+                .collect();
+            match entries.is_empty() {
+                true => Reply::Nil,
+                false => Reply::Array(vec![Reply::Array(vec![
+                    Reply::Bulk(key.into_bytes()),
+                    Reply::Array(entries),
+                ])]),
+            }
+        }
+        // Pages through the keyspace ten keys at a time, using the index into the
+        // sorted key list as the cursor.
+        "HSCAN" => {
+            let cursor: usize = arg(2).parse().unwrap_or(0);
+            let fields: Vec<(&String, &Vec<u8>)> =
+                script.hashes.get(&arg(1)).into_iter().flatten().collect();
+            let end = (cursor + 10).min(fields.len());
+            let next = if end == fields.len() { 0 } else { end };
+            Reply::Array(vec![
+                Reply::Bulk(next.to_string().into_bytes()),
+                Reply::Array(
+                    fields[cursor.min(end)..end]
+                        .iter()
+                        .flat_map(|(f, v)| {
+                            vec![Reply::Bulk(f.as_bytes().to_vec()), Reply::Bulk(v.to_vec())]
+                        })
+                        .collect(),
+                ),
+            ])
+        }
+        "SCAN" => {
+            let cursor: usize = arg(1).parse().unwrap_or(0);
+            let pattern = if args.len() > 3 && arg(2).eq_ignore_ascii_case("MATCH") {
+                arg(3)
+            } else {
+                "*".to_string()
+            };
+            let keys: Vec<&String> = script
+                .keys
+                .keys()
+                .chain(script.sets.keys())
+                .chain(script.zsets.keys())
+                .collect::<BTreeSet<&String>>()
+                .into_iter()
+                .filter(|k| glob_match(pattern.as_bytes(), k.as_bytes()))
+                .collect();
+            let end = (cursor + 10).min(keys.len());
+            let next = if end == keys.len() { 0 } else { end };
+            Reply::Array(vec![
+                Reply::Bulk(next.to_string().into_bytes()),
+                Reply::Array(
+                    keys[cursor.min(end)..end]
+                        .iter()
+                        .map(|k| Reply::Bulk(k.as_bytes().to_vec()))
+                        .collect(),
+                ),
+            ])
+        }
+        "MODULE" => Reply::Array(
+            script
+                .modules
+                .iter()
+                .map(|m| {
+                    Reply::Array(vec![
+                        Reply::Bulk(b"name".to_vec()),
+                        Reply::Bulk(m.as_bytes().to_vec()),
+                        Reply::Bulk(b"ver".to_vec()),
+                        Reply::Int(20000),
+                    ])
+                })
+                .collect(),
+        ),
+        // JSONPaths reply with every value matched, which is at most one here.
+        "JSON.GET" => match script.json.get_mut(&arg(1)) {
+            Some(doc) => {
+                let matched: Vec<serde_json::Value> = json_at(doc, &json_path(&arg(2)))
+                    .cloned()
+                    .into_iter()
+                    .collect();
+                Reply::Bulk(serde_json::to_vec(&matched).unwrap())
+            }
+            None => Reply::Nil,
+        },
+        "JSON.SET" => {
+            let value: serde_json::Value = serde_json::from_slice(&args[3]).unwrap();
+            let segments = json_path(&arg(2));
+            let (last, parent) = match segments.split_last() {
+                Some(v) => v,
+                None => {
+                    script.json.insert(arg(1), value);
+                    return Reply::Status("OK".to_string());
+                }
+            };
+            let parent = script
+                .json
+                .get_mut(&arg(1))
+                .and_then(|d| json_at(d, parent));
+            match (parent, last) {
+                (Some(serde_json::Value::Object(m)), serde_json::Value::String(k)) => {
+                    m.insert(k.clone(), value);
+                    Reply::Status("OK".to_string())
+                }
+                (Some(serde_json::Value::Array(a)), serde_json::Value::Number(i)) => {
+                    match a.get_mut(i.as_u64().unwrap() as usize) {
+                        Some(v) => {
+                            *v = value;
+                            Reply::Status("OK".to_string())
+                        }
+                        None => Reply::Error("ERR index out of bounds".to_string()),
+                    }
+                }
+                _ => Reply::Nil,
+            }
+        }
+        "JSON.DEL" => {
+            let segments = json_path(&arg(2));
+            let (last, parent) = match segments.split_last() {
+                Some(v) => v,
+                None => return Reply::Int(script.json.remove(&arg(1)).is_some() as i64),
+            };
+            let parent = script
+                .json
+                .get_mut(&arg(1))
+                .and_then(|d| json_at(d, parent));
+            let removed = match (parent, last) {
+                (Some(serde_json::Value::Object(m)), serde_json::Value::String(k)) => {
+                    m.remove(k).is_some()
+                }
+                (Some(serde_json::Value::Array(a)), serde_json::Value::Number(i)) => {
+                    let i = i.as_u64().unwrap() as usize;
+                    i < a.len() && {
+                        a.remove(i);
+                        true
+                    }
+                }
+                _ => false,
+            };
+            Reply::Int(removed as i64)
+        }
+        _ => Reply::Error(format!("ERR unknown command '{}'", cmd)),
+    }
+}
+
+// Whether run_script can run the Lua script body. Others fail to load, as if
+// scripting were disabled.
+fn runs_script(body: &str) -> bool {
+    body.contains("redis.call('UNLINK', KEYS[i])")
+        || body.contains("redis.call('SMEMBERS', KEYS[2])")
+        || body.contains("redis.call('GET', KEYS[1]) ~= ARGV[1]")
+        || body.contains("redis.call('SETRANGE', KEYS[1], len - 1")
+        || body.contains("size = redis.call('STRLEN', key)")
+}
+
+// Run the Lua script body, as fusekv's own scripts are recognised and redone
+// here through dispatch rather than interpreted.
+fn run_script(script: &mut Script, body: &str, keys: &[Vec<u8>], argv: &[Vec<u8>]) -> Reply {
+    let cmd = |name: &str, args: &[&Vec<u8>]| {
+        let mut cmd = vec![name.as_bytes().to_vec()];
+        cmd.extend(args.iter().map(|a| a.to_vec()));
+        cmd
+    };
+    let int = |a: &Vec<u8>| -> usize { String::from_utf8_lossy(a).parse().unwrap_or(0) };
+    // DELETE_SCRIPT
+    if body.contains("redis.call('UNLINK', KEYS[i])") {
+        let n = int(&argv[0]);
+        let mut deleted = 0;
+        for i in 0..n {
+            dispatch(script, &cmd("DEL", &[&keys[n + i]]));
+            if let Reply::Int(k) = dispatch(script, &cmd("UNLINK", &[&keys[i]])) {
+                deleted += k;
+            }
+        }
+        for (j, i) in argv[1..].iter().enumerate() {
+            dispatch(script, &cmd("SREM", &[&keys[2 * n + j], &keys[int(i) - 1]]));
+        }
+        return Reply::Int(deleted);
+    }
+    let exists = |script: &mut Script, key: &Vec<u8>| {
+        matches!(dispatch(script, &cmd("EXISTS", &[key])), Reply::Int(1))
+    };
+    // ACQUIRE_LOCK_SCRIPT
+    if body.contains("redis.call('SMEMBERS', KEYS[2])") {
+        if exists(script, &keys[0]) {
+            return Reply::Int(0);
+        }
+        if argv[1] == b"1" {
+            if keys[2..].iter().step_by(2).any(|k| exists(script, k)) {
+                return Reply::Int(-1);
+            }
+            let children = String::from_utf8_lossy(&keys[1]).to_string();
+            for child in script.sets.get(&children).cloned().unwrap_or_default() {
+                if script.keys.contains_key(&child) {
+                    return Reply::Int(-1);
+                }
+                dispatch(script, &cmd("SREM", &[&keys[1], &child.into_bytes()]));
+            }
+        }
+        let (nx, px) = (b"NX".to_vec(), b"PX".to_vec());
+        match argv[2].as_slice() {
+            b"0" => dispatch(script, &cmd("SET", &[&keys[0], &argv[0], &nx])),
+            _ => dispatch(
+                script,
+                &cmd("SET", &[&keys[0], &argv[0], &nx, &px, &argv[2]]),
+            ),
+        };
+        for set in keys[3..].iter().step_by(2) {
+            dispatch(script, &cmd("SADD", &[set, &keys[0]]));
+        }
+        return Reply::Int(1);
+    }
+    // RELEASE_LOCK_SCRIPT
+    if body.contains("redis.call('GET', KEYS[1]) ~= ARGV[1]") {
+        let key = String::from_utf8_lossy(&keys[0]).to_string();
+        if script.keys.get(&key) != Some(&argv[0]) {
+            return Reply::Int(0);
+        }
+        dispatch(script, &cmd("DEL", &[&keys[0]]));
+        for set in &keys[1..] {
+            dispatch(script, &cmd("SREM", &[set, &keys[0]]));
+        }
+        return Reply::Int(1);
+    }
+    // PREALLOCATE_SCRIPT
+    if body.contains("redis.call('SETRANGE', KEYS[1], len - 1") {
+        let len = int(&argv[0]);
+        let value = script
+            .keys
+            .entry(String::from_utf8_lossy(&keys[0]).to_string());
+        let value = value.or_default();
+        if value.len() < len {
+            value.resize(len, 0);
+        }
+        return Reply::Int(value.len() as i64);
+    }
+    // SIZES_SCRIPT
+    if body.contains("size = redis.call('STRLEN', key)") {
+        return Reply::Array(
+            keys.iter()
+                .map(|k| {
+                    let k = String::from_utf8_lossy(k).to_string();
+                    Reply::Int(script.keys.get(&k).map_or(0, |v| v.len() as i64))
+                })
+                .collect(),
+        );
+    }
+    Reply::Error("ERR the fake server can't run this script".to_string())
+}
+
+// A sorted set score bound, eg. 1000 or -inf. Exclusive bounds aren't
+// supported, as fusekv never sends them.
+fn score_bound(bound: &str) -> i64 {
+    match bound {
+        "-inf" => i64::MIN,
+        "+inf" | "inf" => i64::MAX,
+        _ => bound.parse().unwrap_or(0),
+    }
+}
+
+// Whether key matches a Redis glob pattern. Only *, ?, and \ escapes are
+// supported, which is all fusekv sends.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((b'*', rest)) => (0..=key.len()).any(|i| glob_match(rest, &key[i..])),
+        Some((b'?', rest)) => !key.is_empty() && glob_match(rest, &key[1..]),
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            key.first() == Some(&rest[0]) && glob_match(&rest[1..], &key[1..])
+        }
+        Some((c, rest)) => key.first() == Some(c) && glob_match(rest, &key[1..]),
+    }
+}
+
+// A real redis-server on a free port, for what FakeRedis only imitates, eg.
+// running fusekv's Lua scripts. Stopped when dropped.
+pub struct RealRedis {
+    port: u16,
+    child: Child,
+}
+
+impl RealRedis {
+    // Returns None when redis-server isn't installed so tests can skip rather
+    // than fail.
+    pub fn start() -> Option<RealRedis> {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let child = match Command::new("redis-server")
+            .args([
+                "--port",
+                &port.to_string(),
+                "--save",
+                "",
+                "--appendonly",
+                "no",
+            ])
+            .stdout(Stdio::null())
+            .spawn()
+        {
+            Ok(c) => c,
+            Err(_) => {
+                eprintln!("redis-server not available, skipping");
+                return None;
+            }
+        };
+        let server = RealRedis { port, child };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            if Instant::now() > deadline {
+                panic!("redis-server did not listen on {} in time", port);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        Some(server)
+    }
+
+    pub fn url(&self) -> String {
+        format!("redis://127.0.0.1:{}", self.port)
+    }
+
+    // Run a command as another client would.
+    pub fn cmd<T: redis::FromRedisValue>(&self, args: &[&str]) -> T {
+        let client = redis::Client::open(self.url()).unwrap();
+        let mut conn = client.get_connection().unwrap();
+        redis::cmd(args[0])
+            .arg(&args[1..])
+            .query(&mut conn)
+            .unwrap()
+    }
+}
+
+impl Drop for RealRedis {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+static MOUNT_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// A running fusekv process mounted on a fresh temporary directory, unmounted
+// when dropped.
+pub struct Mount {
+    pub path: PathBuf,
+    child: Child,
+}
+
+impl Mount {
+    // Mount fusekv against server with extra CLI args. Returns None when FUSE is
+    // unavailable on this host so tests can skip rather than fail.
+    pub fn start(server: &FakeRedis, extra: &[&str]) -> Option<Mount> {
+        Mount::start_url(&server.url(), extra)
+    }
+
+    // Like start, but against any server URL, eg. mem:// for the in-memory
+    // driver.
+    pub fn start_url(url: &str, extra: &[&str]) -> Option<Mount> {
+        if !Path::new("/dev/fuse").exists() {
+            eprintln!("/dev/fuse not available, skipping");
+            return None;
+        }
+        let path = std::env::temp_dir().join(format!(
+            "fusekv-test-{}-{}",
+            std::process::id(),
+            MOUNT_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_fusekv"))
+            .arg(&path)
+            .arg("--server")
+            .arg(url)
+            .args(extra)
+            .env("FUSEKV_LOG_LEVEL", "debug")
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mount = Mount { path, child };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !mount.is_mounted() {
+            if Instant::now() > deadline {
+                panic!("fusekv did not mount {} in time", mount.path.display());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        Some(mount)
+    }
+
+    pub fn join(&self, rel: &str) -> PathBuf {
+        self.path.join(rel)
+    }
+
+    // PID of the fusekv process started, which exits once mounted with
+    // --daemon.
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    // Wait up to timeout for the fusekv process started to exit, returning
+    // whether it exited successfully, or None if it's still running.
+    pub fn wait(&mut self, timeout: Duration) -> Option<bool> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return Some(status.success());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        None
+    }
+
+    pub fn is_mounted(&self) -> bool {
+        let mounts = std::fs::read_to_string("/proc/mounts").unwrap_or_default();
+        let path = self.path.to_string_lossy();
+        mounts
+            .lines()
+            .any(|l| l.split_whitespace().nth(1) == Some(&path))
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        let _ = Command::new("fusermount")
+            .arg("-u")
+            .arg(&self.path)
+            .status();
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir(&self.path);
+    }
+}
+
+// errno from stat(2) on path, or 0 on success.
+pub fn stat_errno(path: &Path) -> i32 {
+    let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::stat(c_path.as_ptr(), &mut st) } == 0 {
+        return 0;
+    }
+    std::io::Error::last_os_error().raw_os_error().unwrap()
+}
+
+// Read path with a single read(2) of up to len bytes at offset.
+pub fn pread(path: &Path, len: usize, offset: i64) -> Result<Vec<u8>, i32> {
+    let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_RDONLY) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().raw_os_error().unwrap());
+    }
+    let mut buf = vec![0u8; len];
+    let n = unsafe { libc::pread(fd, buf.as_mut_ptr() as *mut libc::c_void, len, offset) };
+    let err = std::io::Error::last_os_error().raw_os_error().unwrap();
+    unsafe { libc::close(fd) };
+    if n < 0 {
+        return Err(err);
+    }
+    buf.truncate(n as usize);
+    Ok(buf)
+}
+
+// Value of the xattr name on path, or the errno from getxattr(2).
+pub fn getxattr(path: &Path, name: &str) -> Result<Vec<u8>, i32> {
+    let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+    let c_name = std::ffi::CString::new(name).unwrap();
+    let mut buf = vec![0u8; 4096];
+    let n = unsafe {
+        libc::getxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+        )
+    };
+    if n < 0 {
+        return Err(std::io::Error::last_os_error().raw_os_error().unwrap());
+    }
+    buf.truncate(n as usize);
+    Ok(buf)
+}
+
+// Set the xattr name on path to value, or the errno from setxattr(2).
+pub fn setxattr(path: &Path, name: &str, value: &[u8]) -> Result<(), i32> {
+    let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+    let c_name = std::ffi::CString::new(name).unwrap();
+    let rc = unsafe {
+        libc::setxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if rc < 0 {
+        return Err(std::io::Error::last_os_error().raw_os_error().unwrap());
+    }
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn fusekv(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_fusekv"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn schema_describes_the_config_file() {
+    let out = fusekv(&["config", "schema"]);
+    assert!(out.status.success());
+    let schema: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    let properties = &schema["properties"];
+    assert_eq!(
+        properties["lock_mode"]["enum"],
+        serde_json::json!(["independent", "parent-blocking"])
+    );
+    assert_eq!(
+        properties["hook"]["items"]["required"],
+        serde_json::json!(["pattern"])
+    );
+    // Fields with defaults can be left out.
+    assert!(properties["quota"].get("required").is_none());
+}
+
+#[test]
+fn example_config_is_valid() {
+    let example = Path::new(env!("CARGO_MANIFEST_DIR")).join("fusekv.toml.example");
+    let out = fusekv(&["config", "validate", example.to_str().unwrap()]);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stdout)
+    );
+}
+
+#[test]
+fn validate_reports_every_problem_with_its_line() {
+    let path = std::env::temp_dir().join(format!("fusekv-validate-{}.toml", std::process::id()));
+    fs::write(
+        &path,
+        "lock_ttl = \"soon\"\nlock_tll = 5\n\n[[hook]]\nops = [\"create\", \"explode\"]\n",
+    )
+    .unwrap();
+    let out = fusekv(&["config", "validate", path.to_str().unwrap()]);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(out.status.code(), Some(2));
+    let report = String::from_utf8_lossy(&out.stdout);
+    assert!(report.contains("line 1: lock_ttl: expected integer, found string"));
+    assert!(report.contains("line 2: lock_tll: unknown key"));
+    assert!(report.contains("line 4: hook[0]: missing required key pattern"));
+    assert!(report.contains("line 5: hook[0].ops[1]: \"explode\" is not one of"));
+}
+
+#[test]
+fn mounting_with_a_bad_config_reports_its_problems() {
+    let path = std::env::temp_dir().join(format!("fusekv-bad-{}.toml", std::process::id()));
+    fs::write(&path, "read_only = true\npremission = []\n").unwrap();
+    let mountpoint = std::env::temp_dir().join("fusekv-never-mounted");
+    let out = fusekv(&[
+        mountpoint.to_str().unwrap(),
+        "--server",
+        "mem://",
+        "--config",
+        path.to_str().unwrap(),
+    ]);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(out.status.code(), Some(2));
+    let report = String::from_utf8_lossy(&out.stderr);
+    assert!(
+        report.contains("line 2: premission: unknown key"),
+        "{}",
+        report
+    );
+}
+
+#[test]
+fn features_left_out_of_the_build_are_refused_by_name() {
+    // Whether args pass --check, or fail it as a config error naming feature.
+    let check = |feature: &str, built: bool, args: &[&str]| {
+        let mut args = args.to_vec();
+        args.push("--check");
+        let out = fusekv(&args);
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        let refused = format!("fusekv was built without the {} feature.", feature);
+        assert_eq!(stderr.contains(&refused), !built, "{}", stderr);
+        if !built {
+            assert_eq!(out.status.code(), Some(2));
+        }
+        out.status.success()
+    };
+    assert_eq!(
+        check("mem", cfg!(feature = "mem"), &["--server", "mem://"]),
+        cfg!(feature = "mem")
+    );
+    // Nothing listens on port 1, so built in, these only fail to connect.
+    check(
+        "tls",
+        cfg!(feature = "tls"),
+        &["--server", "rediss://127.0.0.1:1"],
+    );
+    check(
+        "cluster",
+        cfg!(feature = "cluster"),
+        &["--server", "redis://127.0.0.1:1", "--cluster-mode"],
+    );
+    if cfg!(feature = "mem") {
+        let dir = std::env::temp_dir().join(format!("fusekv-offload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let offload = check(
+            "offload",
+            cfg!(feature = "offload"),
+            &["--server", "mem://", "--offload-dir", dir.to_str().unwrap()],
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(offload, cfg!(feature = "offload"));
+    }
+}
+
+#[test]
+fn client_certificates_need_their_key_and_files_that_can_be_read() {
+    let check = |args: &[&str]| {
+        let mut args = args.to_vec();
+        args.extend(["--server", "rediss://127.0.0.1:1", "--check"]);
+        let out = fusekv(&args);
+        assert!(!out.status.success());
+        String::from_utf8_lossy(&out.stderr).into_owned()
+    };
+    let stderr = check(&["--tls-client-cert", "/nonexistent/client.pem"]);
+    assert!(
+        stderr.contains("set both or neither of tls_client_cert and tls_client_key"),
+        "{}",
+        stderr
+    );
+    let stderr = check(&[
+        "--tls-client-cert",
+        "/nonexistent/client.pem",
+        "--tls-client-key",
+        "/nonexistent/client.key",
+    ]);
+    assert!(
+        stderr.contains("reading /nonexistent/client.pem"),
+        "{}",
+        stderr
+    );
+}
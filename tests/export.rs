@@ -0,0 +1,196 @@
+mod common;
+
+use common::FakeRedis;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Run fusekv's export or import of file against server, checkpointing into
+// state, returning whether it succeeded.
+fn transfer(server: &FakeRedis, cmd: &str, file: &Path, state: &Path) -> bool {
+    transfer_with(server, &[], cmd, file, state)
+}
+
+// Like transfer, with extra options before cmd.
+fn transfer_with(server: &FakeRedis, extra: &[&str], cmd: &str, file: &Path, state: &Path) -> bool {
+    Command::new(env!("CARGO_BIN_EXE_fusekv"))
+        .args(["--server", &server.url()])
+        .args(extra)
+        .arg(cmd)
+        .arg(file)
+        .arg("--state")
+        .arg(state)
+        .status()
+        .unwrap()
+        .success()
+}
+
+fn temp(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("fusekv-{}-{}", name, std::process::id()))
+}
+
+#[test]
+fn exports_import_into_another_server_with_their_ttls() {
+    let from = FakeRedis::start();
+    let to = FakeRedis::start();
+    from.set("a", b"1")
+        .set("b", b"2")
+        .set("c", b"3")
+        .expire("c", 60);
+    let (file, state) = (temp("export.resp"), temp("export.toml"));
+    let exported = transfer(&from, "export", &file, &state);
+    let checkpoint = fs::read_to_string(&state).unwrap_or_default();
+    let _ = fs::remove_file(&state);
+    let imported = transfer(&to, "import", &file, &state);
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&state);
+    assert!(exported);
+    assert!(checkpoint.contains("done = true"), "{}", checkpoint);
+    assert!(checkpoint.contains("keys = 3"), "{}", checkpoint);
+    assert!(imported);
+    assert_eq!(to.get("a").unwrap(), b"1");
+    assert_eq!(to.get("b").unwrap(), b"2");
+    assert_eq!(to.get("c").unwrap(), b"3");
+    let restores: Vec<Vec<String>> = to
+        .commands()
+        .into_iter()
+        .filter(|c| c[0] == "RESTORE")
+        .map(|c| c[1..3].to_vec())
+        .collect();
+    assert_eq!(
+        restores,
+        vec![
+            vec!["a".to_string(), "0".to_string()],
+            vec!["b".to_string(), "0".to_string()],
+            vec!["c".to_string(), "60000".to_string()],
+        ]
+    );
+}
+
+#[test]
+fn interrupted_imports_resume_after_their_last_checkpoint() {
+    let from = FakeRedis::start();
+    let to = FakeRedis::start();
+    from.set("a", b"1").set("b", b"2");
+    let (file, state) = (temp("resume.resp"), temp("resume.toml"));
+    assert!(transfer(&from, "export", &file, &state));
+    let _ = fs::remove_file(&state);
+    // As if the import stopped after checkpointing the first RESTORE.
+    let export = fs::read(&file).unwrap();
+    let second = export
+        .windows(b"*5\r\n".len())
+        .skip(1)
+        .position(|w| w == b"*5\r\n")
+        .unwrap()
+        + 1;
+    fs::write(
+        &state,
+        format!("cursor = 0\nkeys = 1\noffset = {}\ndone = false\n", second),
+    )
+    .unwrap();
+    let imported = transfer(&to, "import", &file, &state);
+    let checkpoint = fs::read_to_string(&state).unwrap_or_default();
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&state);
+    assert!(imported);
+    assert_eq!(to.get("a"), None);
+    assert_eq!(to.get("b").unwrap(), b"2");
+    assert!(checkpoint.contains("keys = 2"), "{}", checkpoint);
+    assert!(checkpoint.contains("done = true"), "{}", checkpoint);
+}
+
+#[test]
+fn finished_exports_are_not_redone() {
+    let redis = FakeRedis::start();
+    redis.set("a", b"1");
+    let (file, state) = (temp("done.resp"), temp("done.toml"));
+    fs::write(&file, b"kept").unwrap();
+    fs::write(&state, "cursor = 0\nkeys = 7\noffset = 4\ndone = true\n").unwrap();
+    let exported = transfer(&redis, "export", &file, &state);
+    let kept = fs::read(&file).unwrap();
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&state);
+    assert!(exported);
+    assert_eq!(kept, b"kept");
+    assert_eq!(redis.count("SCAN"), 0);
+}
+
+#[test]
+fn imports_of_files_claiming_huge_commands_fail_as_malformed() {
+    let redis = FakeRedis::start();
+    let (file, state) = (temp("huge.resp"), temp("huge.toml"));
+    let mut failed = vec![];
+    for content in [
+        &b"*18446744073709551615\r\n$1\r\na\r\n"[..],
+        b"*1\r\n$18446744073709551615\r\na\r\n",
+        b"*1\r\n$1000000000000\r\nabc\r\n",
+    ] {
+        fs::write(&file, content).unwrap();
+        let _ = fs::remove_file(&state);
+        failed.push(!transfer(&redis, "import", &file, &state));
+    }
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&state);
+    assert_eq!(failed, vec![true, true, true]);
+    assert_eq!(redis.count("RESTORE"), 0);
+}
+
+#[test]
+fn transfers_use_the_configured_credentials_and_prefix() {
+    let from = FakeRedis::start();
+    let to = FakeRedis::start();
+    from.set("team:a", b"1").set("other:b", b"2");
+    from.require_password("secret");
+    to.require_password("secret");
+    let password = temp("transfer-password");
+    fs::write(&password, "secret\n").unwrap();
+    let (file, state) = (temp("prefix.resp"), temp("prefix.toml"));
+    let exported = transfer_with(
+        &from,
+        &[
+            "--redis-password-file",
+            password.to_str().unwrap(),
+            "--prefix",
+            "team:",
+        ],
+        "export",
+        &file,
+        &state,
+    );
+    let _ = fs::remove_file(&state);
+    let imported = transfer_with(
+        &to,
+        &[
+            "--redis-password-file",
+            password.to_str().unwrap(),
+            "--prefix",
+            "copy:",
+        ],
+        "import",
+        &file,
+        &state,
+    );
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&state);
+    let _ = fs::remove_file(&password);
+    assert!(exported);
+    assert!(imported);
+    for server in [&from, &to] {
+        assert!(server.commands().iter().any(|c| c == &["AUTH", "secret"]));
+    }
+    assert_eq!(to.get("copy:a").unwrap(), b"1");
+    assert_eq!(to.get("copy:b"), None);
+    assert_eq!(to.get("other:b"), None);
+}
+
+#[test]
+fn transfers_refuse_cluster_mode() {
+    let redis = FakeRedis::start();
+    redis.set("a", b"1");
+    let (file, state) = (temp("cluster.resp"), temp("cluster.toml"));
+    let exported = transfer_with(&redis, &["--cluster-mode"], "export", &file, &state);
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&state);
+    assert!(!exported);
+    assert_eq!(redis.count("SCAN"), 0);
+}
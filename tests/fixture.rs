@@ -0,0 +1,28 @@
+mod common;
+
+use common::{getxattr, FakeRedis, Mount};
+use std::fs;
+
+#[test]
+fn fixture_is_loaded_before_mounting() {
+    let redis = FakeRedis::start();
+    redis.set("greeting", b"stale");
+    let fixture = std::env::temp_dir().join(format!("fusekv-fixture-{}.toml", std::process::id()));
+    fs::write(
+        &fixture,
+        "[[key]]\nname = \"greeting\"\nvalue = \"hello\"\nttl = 60\n\n[[key]]\nname = \"other\"\nvalue = \"x\"\n",
+    )
+    .unwrap();
+    let mount = Mount::start(&redis, &["--fixture", fixture.to_str().unwrap()]);
+    fs::remove_file(&fixture).unwrap();
+    let mount = match mount {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(fs::read(mount.join("kv/greeting")).unwrap(), b"hello\n");
+    assert_eq!(fs::read(mount.join("kv/other")).unwrap(), b"x\n");
+    assert_eq!(
+        getxattr(&mount.join("kv/greeting"), "user.ttl").unwrap(),
+        b"60"
+    );
+}
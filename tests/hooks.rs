@@ -0,0 +1,79 @@
+mod common;
+
+use common::{FakeRedis, Mount};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Contents of path once it has at least lines lines, or whatever it has after
+// a few seconds, since hooks run in the background.
+fn wait_for_lines(path: &Path, lines: usize) -> String {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let content = fs::read_to_string(path).unwrap_or_default();
+        if content.lines().count() >= lines || Instant::now() > deadline {
+            return content;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn command_hooks_run_for_matching_keys() {
+    let redis = FakeRedis::start();
+    let id = std::process::id();
+    let log = std::env::temp_dir().join(format!("fusekv-hooks-{}.log", id));
+    let config = std::env::temp_dir().join(format!("fusekv-hooks-{}.toml", id));
+    fs::write(
+        &config,
+        format!(
+            "[[hook]]\npattern = \"^/kv/job:\"\ncommand = 'echo \"$FUSEKV_OP $FUSEKV_PATH\" >> {}'\n",
+            log.display()
+        ),
+    )
+    .unwrap();
+    let mount = Mount::start(&redis, &["--config", config.to_str().unwrap()]);
+    fs::remove_file(&config).unwrap();
+    let mount = match mount {
+        Some(m) => m,
+        None => return,
+    };
+    fs::write(mount.join("kv/other"), b"ignored").unwrap();
+    fs::write(mount.join("kv/job:1"), b"run").unwrap();
+    fs::remove_file(mount.join("kv/job:1")).unwrap();
+    let content = wait_for_lines(&log, 3);
+    let _ = fs::remove_file(&log);
+    assert_eq!(
+        content,
+        "create /kv/job:1\nmodify /kv/job:1\ndelete /kv/job:1\n"
+    );
+}
+
+#[test]
+fn lua_hooks_run_on_the_backend() {
+    let redis = FakeRedis::start();
+    let config = std::env::temp_dir().join(format!("fusekv-lua-hooks-{}.toml", std::process::id()));
+    fs::write(
+        &config,
+        "[[hook]]\npattern = \"^/kv/cache:\"\nops = [\"create\"]\nlua = \"return 1\"\n",
+    )
+    .unwrap();
+    let mount = Mount::start(&redis, &["--config", config.to_str().unwrap()]);
+    fs::remove_file(&config).unwrap();
+    let mount = match mount {
+        Some(m) => m,
+        None => return,
+    };
+    fs::write(mount.join("kv/cache:a"), b"v").unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while redis.count("EVALSHA") == 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+    let eval = redis
+        .commands()
+        .into_iter()
+        .find(|c| c[0] == "EVALSHA")
+        .unwrap();
+    assert_eq!(&eval[2..], &["1", "cache:a", "create", "/kv/cache:a"]);
+}
@@ -0,0 +1,3145 @@
+mod common;
+
+use common::{getxattr, pread, setxattr, stat_errno, FakeRedis, Mount, RealRedis, Reply};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[test]
+fn missing_key_is_enoent() {
+    let redis = FakeRedis::start();
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(stat_errno(&mount.join("kv/nope")), libc::ENOENT);
+}
+
+#[test]
+fn reads_value_of_existing_key() {
+    let redis = FakeRedis::start();
+    redis.set("greeting", b"hello");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(stat_errno(&mount.join("kv/greeting")), 0);
+    assert_eq!(fs::read(mount.join("kv/greeting")).unwrap(), b"hello\n");
+}
+
+#[test]
+fn reads_honor_size_and_offset() {
+    let redis = FakeRedis::start();
+    redis.set("greeting", b"hello");
+    // Direct IO, so the kernel passes our sizes and offsets through as is.
+    let mount = match Mount::start(&redis, &["--nocache", "greeting"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let path = mount.join("kv/greeting");
+    assert_eq!(pread(&path, 2, 1).unwrap(), b"el");
+    assert_eq!(pread(&path, 64, 5).unwrap(), b"\n");
+    assert_eq!(pread(&path, 64, 6).unwrap(), b"");
+    assert_eq!(pread(&path, 64, 100).unwrap(), b"");
+    assert_eq!(pread(&mount.join(".fusekv/freeze"), 1, 0).unwrap(), b"0");
+    assert_eq!(pread(&mount.join(".fusekv/freeze"), 8, 100).unwrap(), b"");
+    assert_eq!(pread(&mount.join("kv:help"), 8, 1_000_000).unwrap(), b"");
+}
+
+#[test]
+fn reads_honour_offset() {
+    let redis = FakeRedis::start();
+    redis.set("greeting", b"hello");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(pread(&mount.join("kv/greeting"), 64, 2).unwrap(), b"llo\n");
+}
+
+#[test]
+fn large_values_are_read_in_ranges() {
+    let redis = FakeRedis::start();
+    let value: Vec<u8> = (0..100u8).map(|i| b'a' + i % 26).collect();
+    redis.set("big", &value);
+    let mount = match Mount::start(&redis, &["--stream-threshold", "10"]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(
+        pread(&mount.join("kv/big"), 10, 50).unwrap(),
+        &value[50..60]
+    );
+    let mut whole = value.clone();
+    whole.push(b'\n');
+    assert_eq!(fs::read(mount.join("kv/big")).unwrap(), whole);
+    assert!(redis.count("GETRANGE") >= 2);
+}
+
+#[test]
+fn fallocate_zero_fills_values_up_to_the_size_reserved() {
+    use std::os::unix::io::AsRawFd;
+    let redis = FakeRedis::start();
+    redis.set("big", b"ab");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .open(mount.join("kv/big"))
+        .unwrap();
+    let fallocate =
+        |mode: i32, len: i64| match unsafe { libc::fallocate(file.as_raw_fd(), mode, 0, len) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error().raw_os_error().unwrap()),
+        };
+    assert_eq!(fallocate(0, 16), Ok(()));
+    assert_eq!(redis.get("big").unwrap(), [&b"ab"[..], &[0; 14]].concat());
+    // Values are never shrunk, nor grown while keeping their size.
+    assert_eq!(fallocate(0, 4), Ok(()));
+    assert_eq!(fallocate(libc::FALLOC_FL_KEEP_SIZE, 64), Ok(()));
+    assert_eq!(redis.get("big").unwrap().len(), 16);
+    assert_eq!(
+        fallocate(libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE, 4),
+        Err(libc::EOPNOTSUPP)
+    );
+}
+
+#[test]
+fn large_writes_stream_with_setrange() {
+    let redis = FakeRedis::start();
+    // The fake server can't run the script trimming the trailing \n.
+    redis.reply("EVALSHA", Reply::Int(0));
+    let mount = match Mount::start(&redis, &["--stream-threshold", "10"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let value: Vec<u8> = (0..100u8).map(|i| b'a' + i % 26).collect();
+    fs::write(mount.join("kv/big"), &value).unwrap();
+    assert_eq!(redis.get("big").unwrap(), value);
+    assert!(redis.count("SETRANGE") >= 1);
+}
+
+#[test]
+fn snapshot_handles_keep_reading_the_first_value() {
+    let redis = FakeRedis::start();
+    let value: Vec<u8> = (0..100u8).map(|i| b'a' + i % 26).collect();
+    redis.set("big", &value);
+    let mount = match Mount::start(&redis, &["--stream-threshold", "10"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let path = mount.join("kv/big");
+    setxattr(&path, "user.fusekv.snapshot", b"1").unwrap();
+    assert_eq!(getxattr(&path, "user.fusekv.snapshot").unwrap(), b"1");
+    let file = fs::File::open(&path).unwrap();
+    let mut first = vec![0u8; 10];
+    file.read_exact_at(&mut first, 0).unwrap();
+    assert_eq!(first, &value[..10]);
+    redis.set("big", &[b'z'; 100]);
+    let mut rest = vec![0u8; 10];
+    file.read_exact_at(&mut rest, 50).unwrap();
+    assert_eq!(rest, &value[50..60]);
+    assert_eq!(
+        setxattr(&path, "user.fusekv.snapshot", b"yes"),
+        Err(libc::EINVAL)
+    );
+}
+
+#[test]
+fn read_locked_handles_hold_shared_locks_while_open() {
+    let redis = FakeRedis::start();
+    redis.set("config", b"critical");
+    let mount = match Mount::start(&redis, &["--read-lock-ttl", "1"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let path = mount.join("kv/config");
+    setxattr(&path, "user.fusekv.readlock", b"1").unwrap();
+    assert_eq!(getxattr(&path, "user.fusekv.readlock").unwrap(), b"1");
+    let mut file = fs::File::open(&path).unwrap();
+    let locks = "__fusekv_shared__:config";
+    let holds = || -> Vec<String> {
+        redis
+            .commands()
+            .iter()
+            .filter(|c| c[0] == "HSET" && c[1] == locks)
+            .map(|c| c[2].clone())
+            .collect()
+    };
+    let owners = holds();
+    assert_eq!(owners.len(), 1);
+    let expires: u64 = String::from_utf8(redis.hget(locks, &owners[0]).unwrap())
+        .unwrap()
+        .parse()
+        .unwrap();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    assert!(expires > now);
+
+    // Locks are renewed for as long as the file is open.
+    std::thread::sleep(Duration::from_millis(1200));
+    let mut value = String::new();
+    file.read_to_string(&mut value).unwrap();
+    assert_eq!(value, "critical");
+    assert!(holds().len() >= 3);
+    drop(file);
+    assert_eq!(redis.count("HDEL"), 1);
+    assert_eq!(redis.hget(locks, &owners[0]), None);
+
+    // Writers don't take them.
+    let before = holds().len();
+    fs::write(&path, "changed").unwrap();
+    assert_eq!(holds().len(), before);
+    assert_eq!(
+        setxattr(&path, "user.fusekv.readlock", b"yes"),
+        Err(libc::EINVAL)
+    );
+}
+
+#[test]
+fn backend_errors_map_to_eio() {
+    let redis = FakeRedis::start();
+    redis.reply("GET", Reply::Error("ERR boom".to_string()));
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(stat_errno(&mount.join("kv/anything")), libc::EIO);
+}
+
+#[test]
+fn refused_commands_are_retried_before_failing() {
+    let redis = FakeRedis::start();
+    let mount = match Mount::start(&redis, &["--retry-attempts", "2", "--retry-backoff", "1"]) {
+        Some(m) => m,
+        None => return,
+    };
+    redis.reply(
+        "GET",
+        Reply::Error("LOADING Redis is loading the dataset in memory".to_string()),
+    );
+    let gets = |key: &str| {
+        redis
+            .commands()
+            .iter()
+            .filter(|c| c[0] == "GET" && c[1] == key)
+            .count()
+    };
+    assert_eq!(stat_errno(&mount.join("kv/anything")), libc::EIO);
+    assert_eq!(gets("anything"), 3);
+    // Errors the server may have run the command for aren't retried.
+    redis.reply("GET", Reply::Error("ERR boom".to_string()));
+    assert_eq!(stat_errno(&mount.join("kv/other")), libc::EIO);
+    assert_eq!(gets("other"), 1);
+}
+
+#[test]
+fn backend_errors_map_by_kind() {
+    let redis = FakeRedis::start();
+    redis.reply(
+        "GET",
+        Reply::Error("WRONGTYPE Operation against a key".to_string()),
+    );
+    redis.reply("TYPE", Reply::Status("hash".to_string()));
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(stat_errno(&mount.join("kv/hash")), libc::EINVAL);
+    redis.reply("GET", Reply::Error("NOPERM no permissions".to_string()));
+    assert_eq!(stat_errno(&mount.join("kv/secret")), libc::EACCES);
+    redis.reply(
+        "GET",
+        Reply::Error("OOM command not allowed when used memory > 'maxmemory'".to_string()),
+    );
+    assert_eq!(stat_errno(&mount.join("kv/big")), libc::ENOSPC);
+}
+
+#[test]
+fn xattrs_of_listed_keys_are_fetched_together() {
+    let redis = FakeRedis::start();
+    for key in &["a", "b", "c", "d", "e"] {
+        redis.set(key, b"value");
+    }
+    let mount = match Mount::start(&redis, &["--attr-ttl", "60000"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let mut paths: Vec<_> = fs::read_dir(mount.join("kv"))
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .collect();
+    paths.sort();
+    assert_eq!(redis.count("MEMORY"), 0);
+    for path in &paths {
+        assert_eq!(getxattr(path, "user.type").unwrap(), b"string");
+        assert_eq!(getxattr(path, "user.memory").unwrap(), b"55");
+    }
+    assert_eq!(redis.count("MEMORY"), 5);
+
+    // Changing a key through the mount fetches it again.
+    setxattr(&paths[0], "user.ttl", b"60").unwrap();
+    assert_eq!(getxattr(&paths[0], "user.ttl").unwrap(), b"60");
+    assert_eq!(redis.count("MEMORY"), 6);
+}
+
+#[test]
+fn ttl_is_read_and_set_through_xattrs() {
+    let redis = FakeRedis::start();
+    redis.set("session", b"abc");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let path = mount.join("kv/session");
+    assert_eq!(getxattr(&path, "user.ttl"), Err(libc::ENODATA));
+    setxattr(&path, "user.ttl", b"60").unwrap();
+    assert_eq!(getxattr(&path, "user.ttl").unwrap(), b"60");
+    assert_eq!(getxattr(&path, "user.type").unwrap(), b"string");
+    assert_eq!(getxattr(&path, "user.encoding").unwrap(), b"embstr");
+    assert_eq!(setxattr(&path, "user.type", b"list"), Err(libc::EPERM));
+    assert_eq!(setxattr(&path, "user.ttl", b"soon"), Err(libc::EINVAL));
+}
+
+#[test]
+fn readdir_pages_through_scan_up_to_max_results() {
+    let redis = FakeRedis::start();
+    for i in 0..25 {
+        redis.set(&format!("key{:02}", i), b"v");
+    }
+    let mount = match Mount::start(&redis, &["--max-results", "15"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let names: Vec<String> = fs::read_dir(mount.join("kv"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(names.len(), 15);
+    assert!(redis.count("SCAN") >= 2);
+}
+
+#[test]
+fn readdir_lists_every_key_a_page_at_a_time() {
+    let redis = FakeRedis::start();
+    for i in 0..2500 {
+        redis.set(&format!("key{:04}", i), b"v");
+    }
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let names: HashSet<String> = fs::read_dir(mount.join("kv"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(names.len(), 2500);
+    assert!(names.contains("key2499"));
+    assert!(redis.count("SCAN") >= 250);
+}
+
+#[test]
+fn listed_files_are_sized_without_reading_each_key() {
+    let redis = FakeRedis::start();
+    for i in 0..30 {
+        redis.set(&format!("key{:02}", i), "v".repeat(i).as_bytes());
+    }
+    let mount = match Mount::start(&redis, &["--attr-ttl", "60000"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let gets = redis.count("GET");
+    for entry in fs::read_dir(mount.join("kv")).unwrap() {
+        let entry = entry.unwrap();
+        let name = entry.file_name().into_string().unwrap();
+        let i: u64 = name[3..].parse().unwrap();
+        assert_eq!(entry.metadata().unwrap().len(), i + 1);
+    }
+    // Scripting isn't supported, so keys are typed and sized with pipelines.
+    assert_eq!(redis.count("GET"), gets);
+    assert_eq!(redis.count("STRLEN"), 30);
+}
+
+#[test]
+fn listed_files_are_typed_and_sized_by_a_script() {
+    let redis = FakeRedis::start();
+    redis.set("a", b"hello");
+    redis.reply("SCRIPT", Reply::Bulk(b"sha".to_vec()));
+    redis.reply(
+        "EVALSHA",
+        Reply::Array(vec![
+            Reply::Bulk(b"0".to_vec()),
+            Reply::Array(vec![Reply::Bulk(b"a".to_vec())]),
+            Reply::Array(vec![Reply::Bulk(b"string".to_vec())]),
+            Reply::Array(vec![Reply::Int(5)]),
+        ]),
+    );
+    let mount = match Mount::start(&redis, &["--attr-ttl", "60000"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let gets = redis.count("GET");
+    let entries: Vec<fs::DirEntry> = fs::read_dir(mount.join("kv"))
+        .unwrap()
+        .map(|e| e.unwrap())
+        .collect();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].metadata().unwrap().len(), 6);
+    assert_eq!(redis.count("TYPE"), 0);
+    assert_eq!(redis.count("GET"), gets);
+}
+
+#[test]
+fn slow_listings_are_cut_short() {
+    let redis = FakeRedis::start();
+    for i in 0..25 {
+        redis.set(&format!("key{:02}", i), b"v");
+    }
+    let mount = match Mount::start(&redis, &["--listing-timeout", "100"]) {
+        Some(m) => m,
+        None => return,
+    };
+    redis.latency(Duration::from_millis(200));
+    let names: Vec<String> = fs::read_dir(mount.join("kv"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    // Only the first SCAN batch made it in before the deadline.
+    assert_eq!(names.len(), 11);
+    assert!(names.contains(&"\u{2026}truncated".to_string()));
+    redis.latency(Duration::from_millis(0));
+    let stats = fs::read_to_string(mount.join(".fusekv/stats")).unwrap();
+    assert!(!stats.contains("truncated_listings 0\n"));
+}
+
+#[test]
+fn cluster_mode_lists_and_reads_keys() {
+    let redis = FakeRedis::start();
+    for i in 0..3 {
+        redis.set(&format!("key{}", i), b"v");
+    }
+    let mount = match Mount::start(&redis, &["--cluster-mode"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let mut names: Vec<String> = fs::read_dir(mount.join("kv"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["key0", "key1", "key2"]);
+    assert_eq!(fs::read(mount.join("kv/key1")).unwrap(), b"v\n");
+    assert_eq!(fs::read(mount.join("kv:count")).unwrap(), b"3\n");
+    assert!(redis.count("CLUSTER") >= 1);
+}
+
+#[test]
+fn cluster_mode_shards_channels_on_the_node_owning_their_slot() {
+    use std::io::{BufRead, BufReader};
+    let redis = FakeRedis::start();
+    let mount = match Mount::start(&redis, &["--cluster-mode", "--blocking-timeout", "1"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let path = mount.join("pubsub/news");
+    let mut reader = BufReader::new(fs::File::open(&path).unwrap());
+    let listed: Vec<String> = fs::read_dir(mount.join("pubsub"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(listed, vec!["news"]);
+    fs::write(&path, b"hello\n").unwrap();
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert_eq!(line, "hello\n");
+    let commands = redis.commands();
+    for sent in [
+        &["CLUSTER", "KEYSLOT", "news"][..],
+        &["SSUBSCRIBE", "news"],
+        &["PUBSUB", "SHARDCHANNELS"],
+    ] {
+        assert!(commands.iter().any(|c| c == sent), "{:?} wasn't sent", sent);
+    }
+    assert!(commands.iter().any(|c| c[..2] == ["SPUBLISH", "news"]));
+    assert_eq!(redis.count("SUBSCRIBE") + redis.count("PUBLISH"), 0);
+}
+
+#[test]
+fn cluster_mode_pops_queues_from_the_node_owning_their_slot() {
+    let redis = FakeRedis::start();
+    let mount = match Mount::start(&redis, &["--cluster-mode", "--blocking-timeout", "1"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let path = mount.join("queue/jobs");
+    fs::write(&path, b"job1\njob2\n").unwrap();
+    let mut reader = fs::File::open(&path).unwrap();
+    let mut buf = [0; 64];
+    let n = reader.read(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"job1\n");
+    let commands = redis.commands();
+    assert!(commands
+        .iter()
+        .any(|c| c == &["CLUSTER", "KEYSLOT", "jobs"]));
+    assert!(commands.iter().any(|c| c[..2] == ["BRPOP", "jobs"]));
+}
+
+#[test]
+fn cluster_mode_keeps_each_script_and_transaction_in_one_slot() {
+    let redis = FakeRedis::start();
+    redis.check_slots().set("a", b"1").set("b", b"22");
+    let mount = match Mount::start(&redis, &["--cluster-mode", "--listing-order", "size"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let names: Vec<String> = fs::read_dir(mount.join("kv"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(names, vec!["b", "a"]);
+    assert!(redis.count("EVALSHA") >= 2);
+    // Nested locks share a hash tag with their ancestors.
+    fs::create_dir_all(mount.join("lock/app/db")).unwrap();
+    fs::File::create(mount.join("lock/app/db/migrate")).unwrap();
+    assert!(redis.get("__fusekv_{lock}__:app/db/migrate").is_some());
+    fs::remove_file(mount.join("lock/app/db/migrate")).unwrap();
+    assert_eq!(redis.get("__fusekv_{lock}__:app/db/migrate"), None);
+
+    setxattr(&mount.join("kv/a"), "user.fusekv.tag.prod", b"").unwrap();
+    assert_eq!(redis.smembers("__fusekv_tag__:prod"), vec!["a"]);
+    let err = fs::rename(mount.join("kv/a"), mount.join("kv/c")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+
+    // Transactions only span keys sharing a slot.
+    fs::create_dir(mount.join("txn/t")).unwrap();
+    fs::write(mount.join("txn/t/{user}:a"), "1\n").unwrap();
+    fs::write(mount.join("txn/t/{user}:b"), "2\n").unwrap();
+    fs::write(mount.join("txn/t/.control"), "commit\n").unwrap();
+    assert_eq!(redis.get("{user}:b"), Some(b"2".to_vec()));
+    fs::write(mount.join("txn/t/a"), "3\n").unwrap();
+    fs::write(mount.join("txn/t/b"), "4\n").unwrap();
+    let err = fs::write(mount.join("txn/t/.control"), "commit\n").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOTSUP));
+    assert_eq!(redis.get("a"), Some(b"1".to_vec()));
+}
+
+#[test]
+fn slow_backend_still_answers() {
+    let redis = FakeRedis::start();
+    redis.set("slow", b"eventually");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    redis.latency(Duration::from_millis(250));
+    assert_eq!(fs::read(mount.join("kv/slow")).unwrap(), b"eventually\n");
+}
+
+#[test]
+fn stats_report_operations_and_backend_health() {
+    let redis = FakeRedis::start();
+    redis.set("greeting", b"hello");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::read(mount.join("kv/greeting")).unwrap();
+    let stats = fs::read_to_string(mount.join(".fusekv/stats")).unwrap();
+    assert!(stats.contains("backend_up 1\n"));
+    assert!(stats.contains("ops.read.count "));
+    assert!(stats.contains("ops.lookup.max_us "));
+    redis.reply("PING", Reply::Error("LOADING".to_string()));
+    let stats = fs::read_to_string(mount.join(".fusekv/stats")).unwrap();
+    assert!(stats.contains("backend_up 0\n"));
+}
+
+#[test]
+fn stats_report_backend_clock_skew() {
+    let redis = FakeRedis::start();
+    redis.clock_ahead(3600);
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let stats = fs::read_to_string(mount.join(".fusekv/stats")).unwrap();
+    let skew: i64 = stats
+        .lines()
+        .find_map(|l| l.strip_prefix("clock_skew_ms "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((skew - 3_600_000).abs() < 2000, "skew was {}", skew);
+}
+
+#[test]
+fn capabilities_report_the_server_and_what_it_lacks() {
+    let redis = FakeRedis::start();
+    redis.module("ReJSON").reply(
+        "INFO",
+        Reply::Bulk(b"# Server\r\nredis_version:6.0.9\r\nredis_mode:standalone\r\n".to_vec()),
+    );
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let capabilities = fs::read_to_string(mount.join(".fusekv/capabilities")).unwrap();
+    let lines: Vec<&str> = capabilities.lines().collect();
+    for line in [
+        "driver=redis",
+        "server_version=6.0.9",
+        "server_mode=standalone",
+        "modules=ReJSON",
+        "cluster_mode=false",
+        "degraded=copy,getdel",
+        "read_only=false",
+    ] {
+        assert!(lines.contains(&line), "no {} in {}", line, capabilities);
+    }
+    let subtrees = lines
+        .iter()
+        .find_map(|l| l.strip_prefix("subtrees="))
+        .unwrap();
+    for subtree in ["kv", "raw", "lock", "tags"] {
+        assert!(subtrees.split(',').any(|s| s == subtree), "{}", subtrees);
+    }
+}
+
+#[test]
+fn trace_lists_commands_sent_to_redis() {
+    let redis = FakeRedis::start();
+    redis.set("greeting", b"hello");
+    let mount = match Mount::start(&redis, &["--trace", "20"]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::read(mount.join("kv/greeting")).unwrap();
+    let trace = fs::read_to_string(mount.join(".fusekv/trace")).unwrap();
+    assert!(trace.lines().count() <= 20);
+    assert!(trace.lines().any(|l| l.ends_with("GET \"greeting\" => ok")));
+}
+
+#[test]
+fn flushes_elsewhere_are_detected() {
+    let redis = FakeRedis::start();
+    redis.reply("INFO", Reply::Bulk(b"used_memory:0\r\n".to_vec()));
+    for i in 0..10 {
+        redis.set(&format!("key{}", i), b"value");
+    }
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert!(mount.join("kv/key0").exists());
+    redis.flush();
+    std::thread::sleep(Duration::from_secs(6));
+    assert!(!mount.join("kv/key0").exists());
+    let stats = fs::read_to_string(mount.join(".fusekv/stats")).unwrap();
+    assert!(stats.lines().any(|l| l == "flushes_detected 1"));
+}
+
+#[test]
+fn count_reports_number_of_keys() {
+    let redis = FakeRedis::start();
+    for i in 0..3 {
+        redis.set(&format!("key{}", i), b"v");
+    }
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(fs::read(mount.join("kv:count")).unwrap(), b"3\n");
+}
+
+#[test]
+fn inodes_persist_and_skip_collisions() {
+    use std::os::unix::fs::MetadataExt;
+    let redis = FakeRedis::start();
+    redis.set("a", b"1").set("c", b"3");
+    let (a, c) = {
+        let mount = match Mount::start(&redis, &[]) {
+            Some(m) => m,
+            None => return,
+        };
+        let a = fs::metadata(mount.join("kv/a")).unwrap().ino();
+        let c = fs::metadata(mount.join("kv/c")).unwrap().ino();
+        (a, c)
+    };
+    assert_eq!(
+        redis.hget("__fusekv_keys_by_ino__", &a.to_string()),
+        Some(b"a".to_vec())
+    );
+    assert_eq!(
+        redis.hget("__fusekv_inos__", "a"),
+        Some(a.to_string().into_bytes())
+    );
+    // Pretend another key took the inode of a before a was ever seen.
+    redis
+        .hdel("__fusekv_inos__", "a")
+        .hset("__fusekv_keys_by_ino__", &a.to_string(), b"b");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(fs::metadata(mount.join("kv/a")).unwrap().ino(), a + 1);
+    assert_eq!(fs::metadata(mount.join("kv/c")).unwrap().ino(), c);
+    assert_eq!(
+        redis.hget("__fusekv_keys_by_ino__", &(a + 1).to_string()),
+        Some(b"a".to_vec())
+    );
+}
+
+#[test]
+fn acl_users_are_checked_before_mutations() {
+    use std::process::Command;
+    let redis = FakeRedis::start();
+    redis.reply(
+        "ACL",
+        Reply::Bulk(b"This user has no permissions to run the 'set' command".to_vec()),
+    );
+    let user = Command::new("id").arg("-un").output().unwrap().stdout;
+    let config = std::env::temp_dir().join(format!("fusekv-acl-{}.toml", std::process::id()));
+    fs::write(
+        &config,
+        format!(
+            "[[acl_user]]\nuser = \"{}\"\nredis_user = \"app\"\n",
+            String::from_utf8_lossy(&user).trim()
+        ),
+    )
+    .unwrap();
+    let mount = Mount::start(&redis, &["--config", config.to_str().unwrap()]);
+    fs::remove_file(&config).unwrap();
+    let mount = match mount {
+        Some(m) => m,
+        None => return,
+    };
+    let err = fs::write(mount.join("kv/denied"), b"v").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+    assert_eq!(redis.get("denied"), None);
+    assert!(redis.commands().contains(
+        &vec!["ACL", "DRYRUN", "app", "SET", "denied", ""]
+            .into_iter()
+            .map(String::from)
+            .collect()
+    ));
+
+    redis.reply("ACL", Reply::Status("OK".to_string()));
+    fs::write(mount.join("kv/allowed"), b"v").unwrap();
+    assert_eq!(redis.get("allowed"), Some(b"v".to_vec()));
+}
+
+#[test]
+fn checksums_of_values_are_read_from_an_xattr_or_a_companion_file() {
+    const HELLO: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    const WORLD: &str = "486ea46224d1bb4fb680f34f7c9ad96a8f24ec88be73ea8e5a6c65260e9cb8a7";
+    use std::os::unix::fs::OpenOptionsExt;
+    let redis = FakeRedis::start();
+    redis.set("greeting", b"hello");
+    let mount = match Mount::start(&redis, &["--attr-ttl", "0"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let xattr = || getxattr(&mount.join("kv/greeting"), "user.fusekv.sha256");
+    // Read past the page cache, as the digest changes but its size doesn't.
+    let companion = || {
+        let mut digest = String::new();
+        fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(mount.join("kv/greeting:sha256"))
+            .unwrap()
+            .read_to_string(&mut digest)
+            .unwrap();
+        digest
+    };
+    assert_eq!(xattr().unwrap(), HELLO.as_bytes());
+    assert_eq!(companion(), format!("{}\n", HELLO));
+    // Companion files aren't listed, only found.
+    let names: Vec<String> = fs::read_dir(mount.join("kv"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(names, vec!["greeting"]);
+    redis.set("greeting", b"world");
+    assert_eq!(xattr().unwrap(), WORLD.as_bytes());
+    assert_eq!(companion(), format!("{}\n", WORLD));
+    assert_eq!(stat_errno(&mount.join("kv/missing:sha256")), libc::ENOENT);
+}
+
+#[test]
+fn name_suffixes_read_values_in_other_encodings() {
+    let redis = FakeRedis::start();
+    redis.set("quote", b"say \"hi\"").set("real#raw", b"mine");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let read = |name: &str| fs::read(mount.join("kv").join(name)).unwrap();
+    assert_eq!(read("quote"), b"say \"hi\"\n");
+    assert_eq!(read("quote#raw"), b"say \"hi\"");
+    assert_eq!(read("quote#json"), b"\"say \\\"hi\\\"\"\n");
+    assert_eq!(read("quote#b64"), b"c2F5ICJoaSI=\n");
+    assert_eq!(stat_errno(&mount.join("kv/quote#yaml")), libc::ENOENT);
+    assert_eq!(stat_errno(&mount.join("kv/missing#raw")), libc::ENOENT);
+    // Real keys win over encodings of others.
+    assert_eq!(read("real#raw"), b"mine\n");
+    let mut names: Vec<String> = fs::read_dir(mount.join("kv"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["quote", "real#raw"]);
+}
+
+#[test]
+fn compressed_values_have_a_decompressed_view() {
+    let redis = FakeRedis::start();
+    // "hello", gzipped.
+    redis.set(
+        "packed",
+        &[
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48, 0xcd, 0xc9,
+            0xc9, 0x07, 0x00, 0x86, 0xa6, 0x10, 0x36, 0x05, 0x00, 0x00, 0x00,
+        ],
+    );
+    redis.set("plain", b"hello");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(
+        fs::read(mount.join("kv/packed:decompressed")).unwrap(),
+        b"hello"
+    );
+    assert_eq!(
+        getxattr(&mount.join("kv/packed"), "user.fusekv.codec").unwrap(),
+        b"gzip"
+    );
+    assert_eq!(
+        stat_errno(&mount.join("kv/plain:decompressed")),
+        libc::ENOENT
+    );
+    assert_eq!(
+        getxattr(&mount.join("kv/plain"), "user.fusekv.codec"),
+        Err(libc::ENODATA)
+    );
+}
+
+#[test]
+fn prefix_limits_the_mount_to_its_keys() {
+    let redis = FakeRedis::start();
+    redis.set("app1:a", b"mine").set("app2:b", b"theirs");
+    let mount = match Mount::start(&redis, &["--prefix", "app1:"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let names: Vec<String> = fs::read_dir(mount.join("kv"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(names, vec!["a"]);
+    assert!(redis
+        .commands()
+        .iter()
+        .any(|c| c[0] == "SCAN" && c[2..] == ["MATCH", "app1:*"]));
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"mine\n");
+    assert_eq!(stat_errno(&mount.join("kv/app2:b")), libc::ENOENT);
+    fs::write(mount.join("kv/new"), b"v").unwrap();
+    assert_eq!(redis.get("app1:new"), Some(b"v".to_vec()));
+}
+
+#[test]
+fn separator_nests_keys_in_directories() {
+    let redis = FakeRedis::start();
+    redis
+        .set("users:1:name", b"ann")
+        .set("users:1:email", b"ann@example.com")
+        .set("users:2:name", b"bob")
+        .set("top", b"level");
+    let mount = match Mount::start(&redis, &["--separator", ":"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let names = |path: &str| -> Vec<String> {
+        fs::read_dir(mount.join(path))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect()
+    };
+    assert_eq!(names("kv"), vec!["top", "users"]);
+    assert_eq!(names("kv/users"), vec!["1", "2"]);
+    assert_eq!(names("kv/users/1"), vec!["email", "name"]);
+    assert!(redis
+        .commands()
+        .iter()
+        .any(|c| c[0] == "SCAN" && c[2..] == ["MATCH", "users:1:*"]));
+    assert!(mount.join("kv/users/2").is_dir());
+    assert_eq!(fs::read(mount.join("kv/users/2/name")).unwrap(), b"bob\n");
+    assert_eq!(stat_errno(&mount.join("kv/users/3")), libc::ENOENT);
+
+    fs::write(mount.join("kv/users/2/email"), b"bob@example.com").unwrap();
+    assert_eq!(
+        redis.get("users:2:email"),
+        Some(b"bob@example.com".to_vec())
+    );
+    fs::remove_file(mount.join("kv/users/1/email")).unwrap();
+    assert_eq!(redis.get("users:1:email"), None);
+    assert_eq!(names("kv/users/1"), vec!["name"]);
+
+    // New namespaces are virtual until a key is written beneath them.
+    fs::create_dir(mount.join("kv/users/3")).unwrap();
+    assert_eq!(names("kv/users"), vec!["1", "2", "3"]);
+    fs::write(mount.join("kv/users/3/name"), b"cy").unwrap();
+    assert_eq!(redis.get("users:3:name"), Some(b"cy".to_vec()));
+    assert_eq!(
+        fs::remove_dir(mount.join("kv/users/3"))
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENOTEMPTY)
+    );
+}
+
+#[test]
+fn empty_values_are_not_missing_keys() {
+    let redis = FakeRedis::start();
+    redis.set("empty", b"");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    // Values read back with a trailing newline, even empty ones.
+    assert_eq!(fs::metadata(mount.join("kv/empty")).unwrap().len(), 1);
+    assert_eq!(fs::read(mount.join("kv/empty")).unwrap(), b"\n");
+    assert_eq!(stat_errno(&mount.join("kv/missing")), libc::ENOENT);
+    assert!(fs::read(mount.join("kv/missing")).is_err());
+
+    fs::write(mount.join("kv/new"), b"").unwrap();
+    assert_eq!(redis.get("new"), Some(vec![]));
+    fs::write(mount.join("kv/empty"), b"").unwrap();
+    assert_eq!(redis.get("empty"), Some(vec![]));
+}
+
+#[test]
+fn empty_files_can_delete_their_keys() {
+    let redis = FakeRedis::start();
+    redis.set("a", b"value");
+    let mount = match Mount::start(&redis, &["--empty-file", "delete"]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::write(mount.join("kv/a"), b"").unwrap();
+    assert_eq!(redis.get("a"), None);
+    assert_eq!(stat_errno(&mount.join("kv/a")), libc::ENOENT);
+    fs::write(mount.join("kv/new"), b"").unwrap();
+    assert_eq!(redis.get("new"), None);
+    fs::write(mount.join("kv/new"), b"v").unwrap();
+    assert_eq!(redis.get("new"), Some(b"v".to_vec()));
+}
+
+#[test]
+fn writes_create_overwrite_and_truncate_keys() {
+    let redis = FakeRedis::start();
+    redis.set("long", b"a long value");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let sets = |key: &str| {
+        redis
+            .commands()
+            .into_iter()
+            .filter(|c| c[0] == "SET" && c[1] == key)
+            .count()
+    };
+    // As echo foo > /kv/mykey would, dropping the newline read back.
+    fs::write(mount.join("kv/mykey"), b"foo\n").unwrap();
+    assert_eq!(redis.get("mykey").unwrap(), b"foo");
+    assert_eq!(sets("mykey"), 1);
+    assert_eq!(fs::read(mount.join("kv/mykey")).unwrap(), b"foo\n");
+    // Shorter values replace longer ones whole.
+    fs::write(mount.join("kv/long"), b"short").unwrap();
+    assert_eq!(redis.get("long").unwrap(), b"short");
+    // Writes through one handle go out together once it's closed.
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .open(mount.join("kv/long"))
+        .unwrap();
+    file.write_all_at(b"SH", 0).unwrap();
+    file.write_all_at(b"T", 4).unwrap();
+    drop(file);
+    assert_eq!(redis.get("long").unwrap(), b"SHorT");
+    fs::OpenOptions::new()
+        .write(true)
+        .open(mount.join("kv/long"))
+        .unwrap()
+        .set_len(2)
+        .unwrap();
+    assert_eq!(redis.get("long").unwrap(), b"SH");
+    // Writes can't escape /kv.
+    assert!(fs::write(mount.join("elsewhere"), b"v").is_err());
+    assert_eq!(redis.get("elsewhere"), None);
+}
+
+#[test]
+fn unlinking_deletes_keys_and_forgets_their_inodes() {
+    let redis = FakeRedis::start();
+    redis.set("a", b"1").set("b", b"2");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let ino = fs::metadata(mount.join("kv/a")).unwrap().ino();
+    assert!(redis.hget("__fusekv_inos__", "a").is_some());
+    fs::remove_file(mount.join("kv/a")).unwrap();
+    assert_eq!(redis.get("a"), None);
+    assert_eq!(redis.get("b").unwrap(), b"2");
+    assert_eq!(redis.hget("__fusekv_inos__", "a"), None);
+    assert_eq!(redis.hget("__fusekv_keys_by_ino__", &ino.to_string()), None);
+    assert_eq!(stat_errno(&mount.join("kv/a")), libc::ENOENT);
+    let err = fs::remove_file(mount.join("kv/a")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    let names: Vec<String> = fs::read_dir(mount.join("kv"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(names, vec!["b"]);
+}
+
+#[test]
+fn values_can_be_read_and_written_exactly() {
+    let redis = FakeRedis::start();
+    redis.set("greeting", b"hello");
+    let mount = match Mount::start(&redis, &["--no-append-newline"]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(fs::metadata(mount.join("kv/greeting")).unwrap().len(), 5);
+    assert_eq!(fs::read(mount.join("kv/greeting")).unwrap(), b"hello");
+    fs::write(mount.join("kv/greeting"), b"line\n").unwrap();
+    assert_eq!(redis.get("greeting"), Some(b"line\n".to_vec()));
+    assert_eq!(fs::read(mount.join("kv/greeting")).unwrap(), b"line\n");
+}
+
+#[test]
+fn values_are_binary_safe() {
+    let redis = FakeRedis::start();
+    redis.set("blob", &[0xff, 0x00, 0xfe]);
+    let mount = match Mount::start(&redis, &["--no-append-newline"]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(fs::read(mount.join("kv/blob")).unwrap(), [0xff, 0x00, 0xfe]);
+    fs::write(mount.join("kv/blob"), [0x80, 0x81, 0x00]).unwrap();
+    assert_eq!(redis.get("blob"), Some(vec![0x80, 0x81, 0x00]));
+    assert_eq!(fs::read(mount.join("kv/blob")).unwrap(), [0x80, 0x81, 0x00]);
+}
+
+#[test]
+fn values_past_the_bulk_limit_are_split() {
+    let redis = FakeRedis::start();
+    redis.reply(
+        "CONFIG",
+        Reply::Array(vec![
+            Reply::Bulk(b"proto-max-bulk-len".to_vec()),
+            Reply::Bulk(b"8".to_vec()),
+        ]),
+    );
+    let big: Vec<u8> = (0..200u8).map(|i| b'a' + i % 26).collect();
+    redis.set("big", &big);
+    let mount = match Mount::start(&redis, &["--stream-threshold", "100"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let value: Vec<u8> = (0..30u8).map(|i| b'a' + i % 26).collect();
+    fs::write(mount.join("kv/small"), &value).unwrap();
+    assert_eq!(redis.get("small").unwrap(), value);
+    for cmd in redis.commands() {
+        match cmd[0].as_str() {
+            "SET" => assert!(cmd[2].len() <= 8),
+            "SETRANGE" => assert!(cmd[3].len() <= 8),
+            _ => {}
+        }
+    }
+    assert!(redis.count("SETRANGE") >= 3);
+    assert_eq!(pread(&mount.join("kv/big"), 50, 10).unwrap(), &big[10..60]);
+    assert!(redis.count("GETRANGE") >= 7);
+}
+
+#[test]
+fn mtimes_are_when_keys_were_last_written() {
+    use std::os::unix::fs::MetadataExt;
+    let redis = FakeRedis::start();
+    redis.set("old", b"value");
+    redis.hset("__fusekv_mtimes__", "old", b"1000000000000");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(
+        fs::metadata(mount.join("kv/old")).unwrap().mtime(),
+        1_000_000_000
+    );
+    fs::write(mount.join("kv/new"), b"value\n").unwrap();
+    assert!(redis.hget("__fusekv_mtimes__", "new").is_some());
+    fs::remove_file(mount.join("kv/new")).unwrap();
+    assert_eq!(redis.hget("__fusekv_mtimes__", "new"), None);
+    drop(mount);
+
+    let mount = match Mount::start(&redis, &["--no-track-mtime"]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert!(fs::metadata(mount.join("kv/old")).unwrap().mtime() > 1_000_000_000);
+}
+
+#[test]
+fn attrs_and_values_are_cached_until_changed_here() {
+    let redis = FakeRedis::start();
+    redis.set("a", b"one");
+    let mount = match Mount::start(&redis, &["--attr-ttl", "60000", "--data-ttl", "60000"]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"one\n");
+    // Changed elsewhere, so only seen once the TTLs pass.
+    redis.set("a", b"three");
+    assert_eq!(fs::metadata(mount.join("kv/a")).unwrap().len(), 4);
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"one\n");
+    fs::write(mount.join("kv/a"), b"four\n").unwrap();
+    assert_eq!(fs::metadata(mount.join("kv/a")).unwrap().len(), 5);
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"four\n");
+}
+
+#[test]
+fn direct_opens_and_nocache_paths_read_past_the_caches() {
+    use std::os::unix::fs::OpenOptionsExt;
+    let redis = FakeRedis::start();
+    redis.set("a", b"one").set("fresh:b", b"one");
+    let mount = match Mount::start(
+        &redis,
+        &[
+            "--attr-ttl",
+            "60000",
+            "--data-ttl",
+            "60000",
+            "--nocache",
+            "^/kv/fresh:",
+        ],
+    ) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"one\n");
+    assert_eq!(fs::read(mount.join("kv/fresh:b")).unwrap(), b"one\n");
+    redis.set("a", b"two").set("fresh:b", b"two");
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"one\n");
+    let mut direct = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(mount.join("kv/a"))
+        .unwrap();
+    let mut value = vec![];
+    direct.read_to_end(&mut value).unwrap();
+    assert_eq!(value, b"two\n");
+    assert_eq!(fs::read(mount.join("kv/fresh:b")).unwrap(), b"two\n");
+}
+
+#[test]
+fn freezing_fails_mutations_with_ebusy_until_thawed() {
+    let redis = FakeRedis::start();
+    redis.set("a", b"1");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let freeze = mount.join(".fusekv/freeze");
+    let errno = |r: std::io::Result<()>| r.map_err(|e| e.raw_os_error().unwrap());
+    fs::write(&freeze, "freeze\n").unwrap();
+    assert_eq!(fs::read_to_string(&freeze).unwrap().trim(), "1");
+    // Reads carry on.
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"1\n");
+    assert_eq!(errno(fs::write(mount.join("kv/a"), b"2")), Err(libc::EBUSY));
+    assert_eq!(errno(fs::write(mount.join("kv/b"), b"2")), Err(libc::EBUSY));
+    assert_eq!(errno(fs::remove_file(mount.join("kv/a"))), Err(libc::EBUSY));
+    assert_eq!(
+        errno(fs::write(mount.join("raw"), b"SET a 2\n")),
+        Err(libc::EBUSY)
+    );
+    assert_eq!(redis.get("a").unwrap(), b"1");
+    assert_eq!(redis.get("b"), None);
+    assert_eq!(errno(fs::write(&freeze, "melt\n")), Err(libc::EINVAL));
+
+    fs::write(&freeze, "thaw\n").unwrap();
+    assert_eq!(fs::read_to_string(&freeze).unwrap().trim(), "0");
+    fs::write(mount.join("kv/a"), b"2").unwrap();
+    assert_eq!(redis.get("a").unwrap(), b"2");
+}
+
+#[test]
+fn most_accessed_keys_are_listed_as_hotkeys() {
+    let redis = FakeRedis::start();
+    redis.set("a", b"1").set("b", b"2").set("c", b"3");
+    let mount = match Mount::start(&redis, &["--hot-keys", "2", "--nocache", "^/kv/"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let hotkeys = || -> Vec<(u64, String)> {
+        fs::read_to_string(mount.join(".fusekv/hotkeys"))
+            .unwrap()
+            .lines()
+            .map(|l| {
+                let (n, key) = l.split_once(' ').unwrap();
+                (n.parse().unwrap(), key.to_string())
+            })
+            .collect()
+    };
+    for _ in 0..5 {
+        fs::read(mount.join("kv/a")).unwrap();
+    }
+    for _ in 0..3 {
+        fs::read(mount.join("kv/b")).unwrap();
+    }
+    fs::read(mount.join("kv/c")).unwrap();
+    // Only the hottest are listed, hottest first.
+    let hot = hotkeys();
+    let keys: Vec<&str> = hot.iter().map(|(_, k)| k.as_str()).collect();
+    assert_eq!(keys, vec!["a", "b"]);
+    assert!(hot[0].0 > hot[1].0, "{:?}", hot);
+    // Deleted keys are forgotten.
+    fs::remove_file(mount.join("kv/a")).unwrap();
+    let keys: Vec<String> = hotkeys().into_iter().map(|(_, k)| k).collect();
+    assert_eq!(keys, vec!["b", "c"]);
+}
+
+#[test]
+fn history_shows_keys_as_they_were_at_a_timestamp() {
+    let redis = FakeRedis::start();
+    redis
+        .set("a", b"three")
+        .zadd("__fusekv_versions__:a", 1_000_000, b"1000000:one")
+        .zadd("__fusekv_versions__:a", 2_000_000, b"2000000:two")
+        .zadd("__fusekv_versions__:b", 3_000_000, b"3000000:new");
+    let mount = match Mount::start(&redis, &["--versioning"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let list = |ts: &str| -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(mount.join("history").join(ts))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    };
+    assert_eq!(fs::read(mount.join("history/1500/a")).unwrap(), b"one\n");
+    assert_eq!(fs::read(mount.join("history/2000/a")).unwrap(), b"two\n");
+    assert_eq!(fs::read(mount.join("history/9999/a")).unwrap(), b"two\n");
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"three\n");
+    // Keys with no version by then didn't exist yet.
+    assert_eq!(stat_errno(&mount.join("history/999/a")), libc::ENOENT);
+    assert_eq!(stat_errno(&mount.join("history/1500/b")), libc::ENOENT);
+    assert_eq!(list("1500"), vec!["a"]);
+    assert_eq!(list("3000"), vec!["a", "b"]);
+    assert!(fs::write(mount.join("history/1500/a"), b"changed").is_err());
+    assert_eq!(stat_errno(&mount.join("history/yesterday")), libc::ENOENT);
+    assert_eq!(redis.get("a").unwrap(), b"three");
+}
+
+#[test]
+fn repeated_writes_within_the_window_are_coalesced_into_one_set() {
+    let redis = FakeRedis::start();
+    redis.set("status", b"s0");
+    let mount = match Mount::start(&redis, &["--coalesce-window", "60000"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let sets = || {
+        redis
+            .commands()
+            .into_iter()
+            .filter(|c| c[0] == "SET" && c[1] == "status")
+            .count()
+    };
+    // Values are the same length, so rewrites needn't truncate, which writes
+    // straight through.
+    let write = |value: &[u8]| {
+        fs::OpenOptions::new()
+            .write(true)
+            .open(mount.join("kv/status"))
+            .unwrap()
+            .write_all(value)
+            .unwrap()
+    };
+    for i in 1..=5 {
+        write(format!("s{}", i).as_bytes());
+    }
+    // Only the first write went out, the rest are held, each replacing the
+    // last, and read back meanwhile.
+    assert_eq!(sets(), 1);
+    assert_eq!(redis.get("status").unwrap(), b"s1");
+    assert_eq!(fs::read(mount.join("kv/status")).unwrap(), b"s5\n");
+    // fsync writes the latest out early.
+    fs::OpenOptions::new()
+        .write(true)
+        .open(mount.join("kv/status"))
+        .unwrap()
+        .sync_all()
+        .unwrap();
+    assert_eq!(sets(), 2);
+    assert_eq!(redis.get("status").unwrap(), b"s5");
+    // Held writes to a deleted key are dropped rather than bringing it back.
+    write(b"s6");
+    fs::remove_file(mount.join("kv/status")).unwrap();
+    drop(mount);
+    assert_eq!(redis.get("status"), None);
+    assert_eq!(sets(), 2);
+}
+
+#[test]
+fn write_behind_acknowledges_writes_before_writing_them_out() {
+    let redis = FakeRedis::start();
+    let mount = match Mount::start(
+        &redis,
+        &[
+            "--write-behind",
+            "--write-behind-interval",
+            "60000",
+            "--write-behind-batch",
+            "2",
+        ],
+    ) {
+        Some(m) => m,
+        None => return,
+    };
+    for i in 0..5 {
+        fs::write(mount.join(&format!("kv/k{}", i)), b"v").unwrap();
+    }
+    assert_eq!(redis.get("k0"), None);
+    // Queued writes are read back from the queue meanwhile.
+    assert_eq!(fs::read(mount.join("kv/k0")).unwrap(), b"v\n");
+    let stats = fs::read_to_string(mount.join(".fusekv/stats")).unwrap();
+    assert!(stats.contains("write_behind_queued 5\n"));
+    fs::OpenOptions::new()
+        .write(true)
+        .open(mount.join("kv/k0"))
+        .unwrap()
+        .sync_all()
+        .unwrap();
+    assert_eq!(redis.get("k0").unwrap(), b"v");
+    assert_eq!(redis.get("k1"), None);
+    fs::write(mount.join(".fusekv/sync"), b"1").unwrap();
+    for i in 1..5 {
+        assert_eq!(redis.get(&format!("k{}", i)).unwrap(), b"v");
+    }
+    drop(mount);
+
+    let mount = match Mount::start(
+        &redis,
+        &[
+            "--write-behind",
+            "--write-behind-interval",
+            "60000",
+            "--write-behind-depth",
+            "3",
+        ],
+    ) {
+        Some(m) => m,
+        None => return,
+    };
+    for i in 0..3 {
+        fs::write(mount.join(&format!("kv/d{}", i)), b"v").unwrap();
+    }
+    // The third write filled the queue, so waited for it to be written out.
+    assert_eq!(redis.get("d0").unwrap(), b"v");
+}
+
+#[test]
+fn held_writes_under_way_are_still_seen_and_waited_for() {
+    let redis = FakeRedis::start();
+    let mount = match Mount::start(&redis, &["--write-behind", "--write-behind-interval", "50"]) {
+        Some(m) => m,
+        None => return,
+    };
+    redis.stall("SET", Duration::from_millis(1000));
+    fs::write(mount.join("kv/a"), b"1").unwrap();
+    // Long enough for the write to be taken off the queue and sent.
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"1\n");
+    // Removing the key waits for the write to land, so it can't bring the
+    // key back afterwards.
+    fs::remove_file(mount.join("kv/a")).unwrap();
+    std::thread::sleep(Duration::from_millis(1000));
+    assert_eq!(redis.get("a"), None);
+
+    fs::write(mount.join("kv/b"), b"2").unwrap();
+    std::thread::sleep(Duration::from_millis(300));
+    fs::OpenOptions::new()
+        .write(true)
+        .open(mount.join("kv/b"))
+        .unwrap()
+        .sync_all()
+        .unwrap();
+    assert_eq!(redis.get("b").unwrap(), b"2");
+}
+
+#[test]
+fn background_tasks_run_on_named_threads_and_stop_on_unmount() {
+    let redis = FakeRedis::start();
+    let mut mount = match Mount::start(
+        &redis,
+        &[
+            "--write-behind",
+            "--write-behind-interval",
+            "60000",
+            "--read-threads",
+            "2",
+        ],
+    ) {
+        Some(m) => m,
+        None => return,
+    };
+    // Thread names are cut short at 15 bytes.
+    let mut threads: Vec<String> = fs::read_dir(format!("/proc/{}/task", mount.pid()))
+        .unwrap()
+        .filter_map(|t| fs::read_to_string(t.unwrap().path().join("comm")).ok())
+        .map(|comm| comm.trim().to_string())
+        .filter(|comm| comm.starts_with("fusekv-"))
+        .collect();
+    threads.sort();
+    let readers = threads.iter().filter(|t| *t == "fusekv-reader").count();
+    assert_eq!(readers, 2, "{:?}", threads);
+    assert!(
+        threads.contains(&"fusekv-coalesce".to_string()),
+        "{:?}",
+        threads
+    );
+    assert!(
+        threads.contains(&"fusekv-unmount".to_string()),
+        "{:?}",
+        threads
+    );
+    fs::write(mount.join("kv/a"), b"v").unwrap();
+    assert_eq!(redis.get("a"), None);
+    // Stopping winds every task down, once what they hold is written out.
+    unsafe { libc::kill(mount.pid() as i32, libc::SIGTERM) };
+    assert_eq!(mount.wait(Duration::from_secs(10)), Some(true));
+    assert_eq!(redis.get("a").unwrap(), b"v");
+}
+
+#[test]
+fn syncing_writes_out_held_back_writes_and_drops_caches() {
+    let redis = FakeRedis::start();
+    redis.set("b", b"one");
+    let mount = match Mount::start(
+        &redis,
+        &[
+            "--coalesce-window",
+            "60000",
+            "--attr-ttl",
+            "60000",
+            "--data-ttl",
+            "60000",
+        ],
+    ) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::write(mount.join("kv/a"), b"1").unwrap();
+    fs::write(mount.join("kv/a"), b"2").unwrap();
+    assert_ne!(redis.get("a"), Some(b"2".to_vec()));
+    assert_eq!(fs::read(mount.join("kv/b")).unwrap(), b"one\n");
+    redis.set("b", b"two");
+    fs::write(mount.join(".fusekv/sync"), b"1").unwrap();
+    assert_eq!(redis.get("a").unwrap(), b"2");
+    assert_eq!(fs::read(mount.join("kv/b")).unwrap(), b"two\n");
+}
+
+#[test]
+fn listings_follow_the_listing_order() {
+    let mount = match Mount::start_url("mem://", &["--listing-order", "size"]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::write(mount.join("kv/b"), b"xx").unwrap();
+    fs::write(mount.join("kv/a"), b"x").unwrap();
+    fs::write(mount.join("kv/c"), b"xxx").unwrap();
+    let names: Vec<String> = fs::read_dir(mount.join("kv"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(names, ["c", "b", "a"]);
+    drop(mount);
+
+    let mount = match Mount::start_url("mem://", &["--listing-order", "lexicographic"]) {
+        Some(m) => m,
+        None => return,
+    };
+    for key in &["b", "c", "a"] {
+        fs::write(mount.join("kv").join(key), b"x").unwrap();
+    }
+    let names: Vec<String> = fs::read_dir(mount.join("kv"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(names, ["a", "b", "c"]);
+}
+
+#[test]
+fn parallel_reads_of_values_dont_queue_behind_each_other() {
+    let redis = FakeRedis::start();
+    for i in 0..4 {
+        redis.set(&format!("k{}", i), b"v");
+    }
+    let mount = match Mount::start(&redis, &["--read-threads", "4"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let files: Vec<fs::File> = (0..4)
+        .map(|i| fs::File::open(mount.join(&format!("kv/k{}", i))).unwrap())
+        .collect();
+    redis.latency(Duration::from_millis(200));
+    let started = Instant::now();
+    let readers: Vec<_> = files
+        .into_iter()
+        .map(|mut f| {
+            std::thread::spawn(move || {
+                let mut read = vec![];
+                f.read_to_end(&mut read).unwrap();
+                read
+            })
+        })
+        .collect();
+    for reader in readers {
+        assert_eq!(reader.join().unwrap(), b"v\n");
+    }
+    // Each read sends at least three commands, so one at a time the four
+    // would take at least 2.4s.
+    assert!(started.elapsed() < Duration::from_millis(2000));
+}
+
+#[test]
+fn big_values_are_read_on_reader_threads() {
+    let redis = FakeRedis::start();
+    let value: Vec<u8> = (0..512 * 1024).map(|i| (i % 251) as u8).collect();
+    redis.set("big", &value);
+    let mount = match Mount::start(
+        &redis,
+        &["--stream-threshold", "1024", "--read-threads", "2"],
+    ) {
+        Some(m) => m,
+        None => return,
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let path = mount.join("kv/big");
+            std::thread::spawn(move || fs::read(path).unwrap())
+        })
+        .collect();
+    for reader in readers {
+        let read = reader.join().unwrap();
+        assert_eq!(&read[..value.len()], &value[..]);
+        assert_eq!(&read[value.len()..], b"\n");
+    }
+}
+
+#[test]
+fn renamed_keys_redirect_from_their_old_names() {
+    let mount = match Mount::start_url(
+        "mem://",
+        &[
+            "--rename-redirect",
+            "60",
+            "--entry-ttl",
+            "0",
+            "--attr-ttl",
+            "0",
+        ],
+    ) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::write(mount.join("kv/old"), b"value\n").unwrap();
+    fs::rename(mount.join("kv/old"), mount.join("kv/new")).unwrap();
+    assert_eq!(fs::read(mount.join("kv/old")).unwrap(), b"value\n");
+    // Lookups aren't cached, so this finds the redirect again.
+    fs::remove_file(mount.join("kv/new")).unwrap();
+    assert_eq!(stat_errno(&mount.join("kv/old")), libc::ESTALE);
+}
+
+#[test]
+fn lists_and_sets_read_as_lines_and_take_appended_ones() {
+    let redis = FakeRedis::start();
+    redis
+        .rpush("jobs", &["a", "b"])
+        .sadd("colours", &["red", "blue"]);
+    let mount = match Mount::start(&redis, &["--attr-ttl", "0", "--nocache", "^/kv/"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let append = |name: &str, lines: &[u8]| {
+        fs::OpenOptions::new()
+            .append(true)
+            .open(mount.join("kv").join(name))
+            .unwrap()
+            .write_all(lines)
+            .unwrap()
+    };
+    assert_eq!(
+        getxattr(&mount.join("kv/jobs"), "user.type").unwrap(),
+        b"list"
+    );
+    assert_eq!(
+        getxattr(&mount.join("kv/colours"), "user.type").unwrap(),
+        b"set"
+    );
+    assert_eq!(fs::read(mount.join("kv/jobs")).unwrap(), b"a\nb\n");
+    // Members of sets are sorted, so reads are stable.
+    assert_eq!(fs::read(mount.join("kv/colours")).unwrap(), b"blue\nred\n");
+    append("jobs", b"c\nd\n");
+    assert_eq!(redis.lrange("jobs"), vec!["a", "b", "c", "d"]);
+    append("colours", b"green\nred\n");
+    assert_eq!(redis.smembers("colours"), vec!["blue", "green", "red"]);
+    assert_eq!(fs::read(mount.join("kv/jobs")).unwrap(), b"a\nb\nc\nd\n");
+    // Rewriting replaces every item, keeping the type.
+    fs::write(mount.join("kv/jobs"), b"x\ny\n").unwrap();
+    assert_eq!(redis.lrange("jobs"), vec!["x", "y"]);
+    assert_eq!(redis.get("jobs"), None);
+}
+
+#[test]
+fn streams_read_as_entries_and_can_be_tailed() {
+    use std::io::{Read, Write};
+    use std::os::unix::fs::OpenOptionsExt;
+    let redis = FakeRedis::start();
+    redis.xadd("log", &[("level", "info"), ("msg", "started")]);
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let path = mount.join("kv/log");
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "0-1 level=info msg=started\n"
+    );
+    // Lines are appended as entries, whether or not O_APPEND is given.
+    let mut f = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    f.write_all(b"level=warn msg=slow\nplain words\n").unwrap();
+    drop(f);
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "0-1 level=info msg=started\n0-2 level=warn msg=slow\n0-3 line=plain words\n"
+    );
+
+    let mut tail = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(&path)
+        .unwrap();
+    let mut buf = vec![0; 4096];
+    let n = tail.read(&mut buf).unwrap();
+    assert!(buf[..n].ends_with(b"0-3 line=plain words\n"));
+    redis.xadd("log", &[("msg", "done")]);
+    let n = tail.read(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"0-4 msg=done\n");
+    // Nothing new arrives before the blocking timeout.
+    let err = tail.read(&mut buf).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ETIMEDOUT));
+}
+
+#[test]
+fn channels_publish_written_lines_and_stream_them_to_readers() {
+    use std::io::{BufRead, BufReader, Write};
+    let mount = match Mount::start_url("mem://", &["--blocking-timeout", "1"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let path = mount.join("pubsub/news");
+    let mut reader = BufReader::new(fs::File::open(&path).unwrap());
+    let listed: Vec<String> = fs::read_dir(mount.join("pubsub"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(listed, vec!["news"]);
+
+    let mut writer = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    writer.write_all(b"hello\nwor").unwrap();
+    // A partial line waits for the rest, or for the file to be closed.
+    writer.write_all(b"ld\nbye").unwrap();
+    drop(writer);
+    let mut lines = vec![];
+    for _ in 0..3 {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        lines.push(line);
+    }
+    assert_eq!(lines, vec!["hello\n", "world\n", "bye\n"]);
+    // Nothing more is published before the blocking timeout.
+    let err = reader.read_line(&mut String::new()).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ETIMEDOUT));
+}
+
+#[test]
+fn blocking_timeouts_are_overridden_per_path_and_per_file() {
+    use std::io::{BufRead, BufReader};
+    let config = std::env::temp_dir().join(format!("fusekv-timeouts-{}.toml", std::process::id()));
+    fs::write(
+        &config,
+        "[[timeout]]\npattern = \"^/pubsub/slow$\"\nblocking = 1\n",
+    )
+    .unwrap();
+    // Left to blocking_timeout, reads would wait forever.
+    let mount = Mount::start_url(
+        "mem://",
+        &[
+            "--blocking-timeout",
+            "0",
+            "--config",
+            config.to_str().unwrap(),
+        ],
+    );
+    fs::remove_file(&config).unwrap();
+    let mount = match mount {
+        Some(m) => m,
+        None => return,
+    };
+    setxattr(
+        &mount.join("pubsub/quiet"),
+        "user.fusekv.blocking_timeout",
+        b"1",
+    )
+    .unwrap();
+    assert_eq!(
+        getxattr(&mount.join("pubsub/quiet"), "user.fusekv.blocking_timeout").unwrap(),
+        b"1"
+    );
+    for channel in ["pubsub/slow", "pubsub/quiet"] {
+        let mut reader = BufReader::new(fs::File::open(mount.join(channel)).unwrap());
+        let start = Instant::now();
+        let err = reader.read_line(&mut String::new()).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ETIMEDOUT), "{}", channel);
+        assert!(start.elapsed() < Duration::from_secs(5), "{}", channel);
+    }
+}
+
+#[test]
+fn queues_pop_written_lines_in_order_to_one_reader_each() {
+    let mount = match Mount::start_url("mem://", &["--blocking-timeout", "1"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let path = mount.join("queue/jobs");
+    let mut writer = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    writer.write_all(b"job1\njob2\njo").unwrap();
+    writer.write_all(b"b3\n").unwrap();
+    drop(writer);
+    // Queues are lists, pushed onto the head.
+    assert_eq!(
+        fs::read_to_string(mount.join("kv/jobs")).unwrap(),
+        "job3\njob2\njob1\n"
+    );
+
+    let mut first = fs::File::open(&path).unwrap();
+    let mut second = fs::File::open(&path).unwrap();
+    let mut buf = [0; 64];
+    let n = first.read(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"job1\n");
+    let n = second.read(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"job2\n");
+    let n = first.read(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"job3\n");
+    // Reading an empty queue fails once the blocking timeout passes.
+    let err = second.read(&mut buf).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EAGAIN));
+
+    // Readers waiting on an empty queue get what's pushed next.
+    let waiting = std::thread::spawn(move || {
+        let n = first.read(&mut buf).unwrap();
+        buf[..n].to_vec()
+    });
+    std::thread::sleep(Duration::from_millis(200));
+    fs::write(&path, b"late\n").unwrap();
+    assert_eq!(waiting.join().unwrap(), b"late\n");
+}
+
+#[test]
+fn big_values_are_offloaded_and_read_back_transparently() {
+    let dir = std::env::temp_dir().join(format!("fusekv-offload-{}", std::process::id()));
+    let mount = match Mount::start_url(
+        "mem://",
+        &[
+            "--offload-dir",
+            dir.to_str().unwrap(),
+            "--offload-threshold",
+            "16",
+        ],
+    ) {
+        Some(m) => m,
+        None => return,
+    };
+    let blobs = || fs::read_dir(&dir).unwrap().count();
+    let big = "x".repeat(100);
+    fs::write(mount.join("kv/big"), format!("{}\n", big)).unwrap();
+    assert_eq!(blobs(), 1);
+    assert_eq!(
+        fs::read_to_string(mount.join("kv/big")).unwrap(),
+        format!("{}\n", big)
+    );
+    assert_eq!(fs::metadata(mount.join("kv/big")).unwrap().len(), 101);
+
+    // Small values stay in the backend, and the old file goes.
+    fs::write(mount.join("kv/big"), b"small\n").unwrap();
+    assert_eq!(blobs(), 0);
+    fs::write(mount.join("kv/big"), format!("{}\n", big)).unwrap();
+    fs::remove_file(mount.join("kv/big")).unwrap();
+    assert_eq!(blobs(), 0);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn counters_set_on_write_and_add_appended_deltas() {
+    use std::io::Write;
+    let mount = match Mount::start_url("mem://", &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let path = mount.join("counter/hits");
+    // Counters with no key yet read as 0.
+    assert_eq!(fs::read_to_string(&path).unwrap(), "0\n");
+
+    fs::write(&path, "10\n").unwrap();
+    for delta in &["+1\n", "-3\n", "5\n"] {
+        let mut f = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(delta.as_bytes()).unwrap();
+    }
+    assert_eq!(fs::read_to_string(&path).unwrap(), "13\n");
+    assert_eq!(fs::read_to_string(mount.join("kv/hits")).unwrap(), "13\n");
+
+    let mut f = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    let err = f.write_all(b"lots\n").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    fs::write(mount.join("kv/name"), "fusekv\n").unwrap();
+    let mut f = fs::OpenOptions::new()
+        .append(true)
+        .open(mount.join("counter/name"))
+        .unwrap();
+    let err = f.write_all(b"1\n").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+}
+
+#[test]
+fn lock_privacy_hides_locks_held_by_other_users() {
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+    // Listing as another user takes root.
+    if unsafe { libc::getuid() } != 0 {
+        return;
+    }
+    let host = fs::read_to_string("/proc/sys/kernel/hostname").unwrap();
+    let redis = FakeRedis::start();
+    redis
+        .set("__fusekv_lock__:theirs", b"elsewhere:1")
+        .set("__fusekv_lock__:app/theirs", b"elsewhere:1")
+        .set(
+            "__fusekv_lock__:mine",
+            format!("{}:65534", host.trim()).as_bytes(),
+        );
+    let mount = match Mount::start(&redis, &["--allow-other", "--lock-privacy", "hide"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let listed = Command::new("ls")
+        .arg(mount.join("lock"))
+        .uid(65534)
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8_lossy(&listed.stdout), "mine\n");
+    let read = Command::new("cat")
+        .arg(mount.join("lock/theirs"))
+        .uid(65534)
+        .status()
+        .unwrap();
+    assert!(!read.success());
+    // Root sees every lock.
+    let mut names: Vec<String> = fs::read_dir(mount.join("lock"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["app", "mine", "theirs"]);
+}
+
+#[test]
+fn locks_are_taken_once_and_released_only_by_their_owner() {
+    let redis = FakeRedis::start();
+    redis.set("__fusekv_lock__:theirs", b"elsewhere:1");
+    let mount = match Mount::start(&redis, &["--lock-ttl", "30"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let take = |name: &str| {
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(mount.join("lock").join(name))
+            .map(|_| ())
+            .map_err(|e| e.raw_os_error().unwrap())
+    };
+    let release = |name: &str| {
+        fs::remove_file(mount.join("lock").join(name)).map_err(|e| e.raw_os_error().unwrap())
+    };
+    assert_eq!(take("deploy"), Ok(()));
+    assert!(redis.get("__fusekv_lock__:deploy").is_some());
+    assert_eq!(redis.ttl("__fusekv_lock__:deploy"), Some(30));
+    assert_eq!(take("deploy"), Err(libc::EEXIST));
+    assert_eq!(take("theirs"), Err(libc::EEXIST));
+    // Locks held by someone else are left alone.
+    assert_eq!(release("theirs"), Err(libc::EPERM));
+    assert_eq!(redis.get("__fusekv_lock__:theirs").unwrap(), b"elsewhere:1");
+    assert_eq!(release("deploy"), Ok(()));
+    assert_eq!(redis.get("__fusekv_lock__:deploy"), None);
+    assert_eq!(release("deploy"), Err(libc::ENOENT));
+    drop(mount);
+
+    // Without a TTL, locks are held until released.
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::File::create(mount.join("lock/forever")).unwrap();
+    assert!(redis.get("__fusekv_lock__:forever").is_some());
+    assert_eq!(redis.ttl("__fusekv_lock__:forever"), None);
+}
+
+#[test]
+fn nested_locks_are_taken_and_released_under_their_namespace() {
+    let redis = FakeRedis::start();
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::create_dir_all(mount.join("lock/app/db")).unwrap();
+    fs::File::create(mount.join("lock/app/db/migrate")).unwrap();
+    let owner = String::from_utf8(redis.get("__fusekv_lock__:app/db/migrate").unwrap()).unwrap();
+    assert!(owner.ends_with(&format!(":{}", unsafe { libc::getuid() })));
+    assert_eq!(
+        fs::read_to_string(mount.join("lock/app/db/migrate")).unwrap(),
+        format!("{}\n", owner)
+    );
+    // Each ancestor remembers the locks taken beneath it.
+    for ancestor in ["app", "app/db"] {
+        assert_eq!(
+            redis.smembers(&format!("__fusekv_lock_children__:{}", ancestor)),
+            vec!["__fusekv_lock__:app/db/migrate"]
+        );
+    }
+    fs::remove_file(mount.join("lock/app/db/migrate")).unwrap();
+    assert_eq!(redis.get("__fusekv_lock__:app/db/migrate"), None);
+    assert!(redis.smembers("__fusekv_lock_children__:app/db").is_empty());
+    fs::remove_dir(mount.join("lock/app/db")).unwrap();
+    fs::remove_dir(mount.join("lock/app")).unwrap();
+}
+
+#[test]
+fn held_parent_locks_block_nested_ones_only_when_parent_blocking() {
+    // Take team/deploy once another client holds team, returning the errno
+    // it fails with.
+    let take = |mode: &str| {
+        let redis = FakeRedis::start();
+        let mount = Mount::start(&redis, &["--lock-mode", mode])?;
+        fs::create_dir(mount.join("lock/team")).unwrap();
+        redis.set("__fusekv_lock__:team", b"elsewhere:1");
+        let taken = fs::File::create(mount.join("lock/team/deploy"));
+        Some(taken.map(|_| ()).map_err(|e| e.raw_os_error().unwrap()))
+    };
+    if let Some(taken) = take("parent-blocking") {
+        assert_eq!(taken, Err(libc::EBUSY));
+    }
+    if let Some(taken) = take("independent") {
+        assert_eq!(taken, Ok(()));
+    }
+}
+
+#[test]
+fn lock_scripts_nest_and_block_on_a_real_server() {
+    let redis = match RealRedis::start() {
+        Some(r) => r,
+        None => return,
+    };
+    let mount = match Mount::start_url(&redis.url(), &["--lock-mode", "parent-blocking"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let take = |name: &str| {
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(mount.join("lock").join(name))
+            .map(|_| ())
+            .map_err(|e| e.raw_os_error().unwrap())
+    };
+    let children = |name: &str| -> Vec<String> {
+        redis.cmd(&["SMEMBERS", &format!("__fusekv_lock_children__:{}", name)])
+    };
+    fs::create_dir_all(mount.join("lock/app/db")).unwrap();
+    assert_eq!(take("app/db/migrate"), Ok(()));
+    let owner: Option<String> = redis.cmd(&["GET", "__fusekv_lock__:app/db/migrate"]);
+    assert!(owner.is_some());
+    for ancestor in ["app", "app/db"] {
+        assert_eq!(children(ancestor), vec!["__fusekv_lock__:app/db/migrate"]);
+    }
+    assert_eq!(take("app/db/migrate"), Err(libc::EEXIST));
+
+    // A held ancestor blocks locks beneath it.
+    let () = redis.cmd(&["SET", "__fusekv_lock__:app", "elsewhere:1"]);
+    assert_eq!(take("app/db/seed"), Err(libc::EBUSY));
+    let () = redis.cmd(&["DEL", "__fusekv_lock__:app"]);
+    assert_eq!(take("app/db/seed"), Ok(()));
+
+    // As does a held descendant its ancestors, while released ones are
+    // forgotten.
+    let () = redis.cmd(&["SET", "__fusekv_lock__:jobs/nightly", "elsewhere:1"]);
+    let () = redis.cmd(&[
+        "SADD",
+        "__fusekv_lock_children__:jobs",
+        "__fusekv_lock__:jobs/nightly",
+    ]);
+    assert_eq!(take("jobs"), Err(libc::EBUSY));
+    let () = redis.cmd(&["DEL", "__fusekv_lock__:jobs/nightly"]);
+    assert_eq!(take("jobs"), Ok(()));
+    assert!(children("jobs").is_empty());
+
+    fs::remove_file(mount.join("lock/app/db/migrate")).unwrap();
+    let owner: Option<String> = redis.cmd(&["GET", "__fusekv_lock__:app/db/migrate"]);
+    assert_eq!(owner, None);
+    assert_eq!(children("app/db"), vec!["__fusekv_lock__:app/db/seed"]);
+    drop(mount);
+
+    // Independent locks ignore those above and below them.
+    let mount = match Mount::start_url(&redis.url(), &["--lock-mode", "independent"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let () = redis.cmd(&["SET", "__fusekv_lock__:team", "elsewhere:1"]);
+    fs::create_dir(mount.join("lock/team")).unwrap();
+    fs::File::create(mount.join("lock/team/deploy")).unwrap();
+    let owner: Option<String> = redis.cmd(&["GET", "__fusekv_lock__:team/deploy"]);
+    assert!(owner.is_some());
+}
+
+#[test]
+fn tagged_keys_are_listed_under_their_tags() {
+    let redis = FakeRedis::start();
+    redis.set("a", b"1").set("b", b"2").set("c", b"3");
+    let mount = match Mount::start(&redis, &["--entry-ttl", "0", "--attr-ttl", "0"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let names = |dir: &str| {
+        let mut names: Vec<String> = fs::read_dir(mount.join(dir))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    };
+    setxattr(&mount.join("kv/a"), "user.fusekv.tag.prod", b"").unwrap();
+    setxattr(&mount.join("kv/b"), "user.fusekv.tag.prod", b"").unwrap();
+    setxattr(&mount.join("kv/a"), "user.fusekv.tag.eu", b"").unwrap();
+    assert_eq!(redis.smembers("__fusekv_tag__:prod"), vec!["a", "b"]);
+    assert_eq!(redis.smembers("__fusekv_tags__:a"), vec!["eu", "prod"]);
+    assert_eq!(names("tags"), vec!["eu", "prod"]);
+    assert_eq!(names("tags/prod"), vec!["a", "b"]);
+    assert_eq!(fs::read(mount.join("tags/prod/a")).unwrap(), b"1\n");
+    assert_eq!(
+        getxattr(&mount.join("kv/a"), "user.fusekv.tag.eu"),
+        Ok(vec![])
+    );
+    assert_eq!(
+        getxattr(&mount.join("kv/c"), "user.fusekv.tag.eu"),
+        Err(libc::ENODATA)
+    );
+    // Both untag the key, leaving it be.
+    fs::remove_file(mount.join("tags/prod/b")).unwrap();
+    let path = std::ffi::CString::new(mount.join("kv/a").to_str().unwrap()).unwrap();
+    let name = std::ffi::CString::new("user.fusekv.tag.prod").unwrap();
+    assert_eq!(
+        unsafe { libc::removexattr(path.as_ptr(), name.as_ptr()) },
+        0
+    );
+    assert_eq!(redis.get("b").unwrap(), b"2");
+    assert!(redis.smembers("__fusekv_tag__:prod").is_empty());
+    assert_eq!(redis.smembers("__fusekv_tags__:a"), vec!["eu"]);
+    assert_eq!(stat_errno(&mount.join("tags/prod")), libc::ENOENT);
+    assert_eq!(names("tags"), vec!["eu"]);
+}
+
+#[test]
+fn listings_are_cached_until_invalidated() {
+    let redis = FakeRedis::start();
+    redis.set("a", b"1");
+    let mount = match Mount::start(&redis, &["--listing-ttl", "60000"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let list = || {
+        let mut names: Vec<String> = fs::read_dir(mount.join("kv"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    };
+    assert_eq!(list(), vec!["a"]);
+    // Keys set by other clients are missed until the listing expires.
+    redis.set("b", b"2");
+    assert_eq!(list(), vec!["a"]);
+    fs::write(mount.join(".fusekv/invalidate"), "/kv/nope*\n").unwrap();
+    assert_eq!(list(), vec!["a"]);
+    fs::write(mount.join(".fusekv/invalidate"), "/kv\n").unwrap();
+    assert_eq!(list(), vec!["a", "b"]);
+    // Changes made through the mount are listed straight away.
+    fs::write(mount.join("kv/c"), "3").unwrap();
+    assert_eq!(list(), vec!["a", "b", "c"]);
+}
+
+#[test]
+fn authenticates_with_credentials_kept_out_of_the_url() {
+    let redis = FakeRedis::start();
+    redis.reply("AUTH", Reply::Status("OK".to_string()));
+    let file = std::env::temp_dir().join(format!("fusekv-password-{}", std::process::id()));
+    fs::write(&file, "hunter2\n").unwrap();
+    let mount = match Mount::start(
+        &redis,
+        &[
+            "--redis-user",
+            "fusekv",
+            "--redis-password-file",
+            file.to_str().unwrap(),
+        ],
+    ) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::write(mount.join("kv/a"), "1").unwrap();
+    let auths: Vec<Vec<String>> = redis
+        .commands()
+        .into_iter()
+        .filter(|c| c[0] == "AUTH")
+        .collect();
+    assert!(!auths.is_empty());
+    // The trailing newline in the file isn't part of the password.
+    assert!(auths.iter().all(|c| c[1..] == ["fusekv", "hunter2"]));
+    fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn rejected_passwords_are_fetched_again_from_the_password_command() {
+    let redis = FakeRedis::start();
+    // The command prints a new token every time it runs, and the first one
+    // has expired by the time it's used.
+    redis.require_password("token1");
+    let counter = std::env::temp_dir().join(format!("fusekv-token-{}", std::process::id()));
+    fs::write(&counter, "0").unwrap();
+    let command = format!(
+        "n=$(cat {0}); echo $((n + 1)) > {0}; echo token$n",
+        counter.display()
+    );
+    let mount = match Mount::start(&redis, &["--redis-password-command", &command]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::write(mount.join("kv/a"), "1").unwrap();
+    assert_eq!(fs::read_to_string(mount.join("kv/a")).unwrap(), "1");
+    let auths: Vec<String> = redis
+        .commands()
+        .into_iter()
+        .filter(|c| c[0] == "AUTH")
+        .map(|c| c[1].clone())
+        .collect();
+    assert_eq!(auths[0], "token0");
+    assert!(auths[1..].iter().all(|p| p == "token1"));
+    fs::remove_file(&counter).unwrap();
+}
+
+#[test]
+fn database_setting_selects_the_logical_database() {
+    let redis = FakeRedis::start();
+    redis.reply("SELECT", Reply::Status("OK".to_string()));
+    let mount = match Mount::start_url(&format!("{}/1", redis.url()), &["--database", "3"]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::write(mount.join("kv/a"), "1").unwrap();
+    let selects: Vec<Vec<String>> = redis
+        .commands()
+        .into_iter()
+        .filter(|c| c[0] == "SELECT")
+        .collect();
+    assert!(!selects.is_empty());
+    assert!(selects.iter().all(|c| c[1..] == ["3"]));
+}
+
+#[test]
+fn legacy_inode_mappings_are_migrated_at_mount() {
+    use std::os::unix::fs::MetadataExt;
+    let redis = FakeRedis::start();
+    let ino = 400_000_000_012_345u64;
+    redis
+        .set("a", b"1")
+        .hset("__fusekv_ino_cache__", "a", ino.to_string().as_bytes());
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(fs::metadata(mount.join("kv/a")).unwrap().ino(), ino);
+    assert_eq!(redis.hget("__fusekv_ino_cache__", "a"), None);
+    assert_eq!(redis.get("__fusekv_ino_version__"), Some(b"2".to_vec()));
+}
+
+#[test]
+fn inode_mappings_are_left_alone_by_mounts_that_cant_write() {
+    let redis = FakeRedis::start();
+    let ino = 400_000_000_012_345u64;
+    redis
+        .set("a", b"1")
+        .hset("__fusekv_ino_cache__", "a", ino.to_string().as_bytes());
+    let mount = match Mount::start(&redis, &["--read-only"]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"1\n");
+    drop(mount);
+    // Replicas reject the writes, so would fail the mount.
+    redis.reply(
+        "INFO",
+        Reply::Bulk(b"# Replication\r\nrole:slave\r\n".to_vec()),
+    );
+    let mount = Mount::start(&redis, &["--reset-ino-cache"]).unwrap();
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"1\n");
+    drop(mount);
+    assert!(redis.hget("__fusekv_ino_cache__", "a").is_some());
+    assert_eq!(redis.get("__fusekv_ino_version__"), None);
+    assert_eq!(redis.count("DEL"), 0);
+}
+
+#[test]
+fn verify_reports_files_that_disagree_with_the_backend() {
+    let redis = FakeRedis::start();
+    redis.set("a", b"1");
+    let mount = match Mount::start(&redis, &["--attr-ttl", "60", "--entry-ttl", "60"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let verify = || {
+        std::process::Command::new(env!("CARGO_BIN_EXE_fusekv"))
+            .arg("--server")
+            .arg(redis.url())
+            .arg("verify")
+            .arg(&mount.path)
+            .output()
+            .unwrap()
+    };
+    assert_eq!(fs::metadata(mount.join("kv/a")).unwrap().len(), 1);
+    let out = verify();
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&out.stdout),
+        "Checked 1 files against 1 keys.\n"
+    );
+    // Changed behind the mount's back, so it still has the old size cached.
+    redis.set("a", b"longer");
+    let out = verify();
+    assert!(!out.status.success());
+    assert!(
+        String::from_utf8_lossy(&out.stdout).contains("size 1 but the backend's value is 6 bytes")
+    );
+}
+
+#[test]
+fn mirrors_show_another_servers_keys_read_only() {
+    let redis = FakeRedis::start();
+    redis.set("a", b"1");
+    let staging = FakeRedis::start();
+    staging.set("app:a", b"2").set("other", b"3");
+    let config = std::env::temp_dir().join(format!("fusekv-mirror-{}.toml", std::process::id()));
+    fs::write(
+        &config,
+        format!(
+            "[[mirror]]\nname = \"staging\"\nurl = \"{}\"\nprefix = \"app:\"\n",
+            staging.url()
+        ),
+    )
+    .unwrap();
+    let mount = Mount::start(&redis, &["--config", config.to_str().unwrap()]);
+    fs::remove_file(&config).unwrap();
+    let mount = match mount {
+        Some(m) => m,
+        None => return,
+    };
+    let names: Vec<String> = fs::read_dir(mount.join("mirror/staging"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(names, vec!["a"]);
+    assert_eq!(fs::read(mount.join("mirror/staging/a")).unwrap(), b"2\n");
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"1\n");
+    assert!(fs::write(mount.join("mirror/staging/a"), b"4").is_err());
+    assert_eq!(staging.get("app:a").unwrap(), b"2");
+    // Nor is anything written to it when mounting.
+    assert_eq!(staging.get("__fusekv_ino_version__"), None);
+    assert_eq!(staging.count("SET") + staging.count("DEL"), 0);
+}
+
+#[test]
+fn mirrors_are_read_from_their_own_server_not_the_mounts_replicas() {
+    let redis = FakeRedis::start();
+    let replica = FakeRedis::start();
+    let staging = FakeRedis::start();
+    redis.set("a", b"primary");
+    replica.set("a", b"replica");
+    staging.set("a", b"staging");
+    replica.reply(
+        "INFO",
+        Reply::Bulk(b"# Replication\r\nrole:slave\r\nmaster_link_status:up\r\n".to_vec()),
+    );
+    let config = std::env::temp_dir().join(format!(
+        "fusekv-mirror-replicas-{}.toml",
+        std::process::id()
+    ));
+    fs::write(
+        &config,
+        format!(
+            "[[mirror]]\nname = \"staging\"\nurl = \"{}\"\n",
+            staging.url()
+        ),
+    )
+    .unwrap();
+    let mount = Mount::start(
+        &redis,
+        &[
+            "--config",
+            config.to_str().unwrap(),
+            "--replica",
+            &replica.url(),
+            "--read-preference",
+            "prefer-replica",
+        ],
+    );
+    fs::remove_file(&config).unwrap();
+    let mount = match mount {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"replica\n");
+    assert_eq!(
+        fs::read(mount.join("mirror/staging/a")).unwrap(),
+        b"staging\n"
+    );
+}
+
+#[test]
+fn adaptive_ttls_need_keyspace_notifications() {
+    // The fake server refuses CONFIG, so notify-keyspace-events can't be
+    // known to be set.
+    let redis = FakeRedis::start();
+    let mount = match Mount::start(&redis, &["--adaptive-ttl", "60000"]) {
+        Some(m) => m,
+        None => return,
+    };
+    std::thread::sleep(Duration::from_millis(500));
+    let stats = fs::read_to_string(mount.join(".fusekv/stats")).unwrap();
+    assert!(stats.contains("adaptive_ttl_watching 0\n"));
+    assert_eq!(redis.count("PSUBSCRIBE"), 0);
+}
+
+#[test]
+fn keys_changed_elsewhere_are_dropped_from_the_cache() {
+    let redis = FakeRedis::start();
+    redis.reply(
+        "CONFIG",
+        Reply::Array(vec![
+            Reply::Bulk(b"notify-keyspace-events".to_vec()),
+            Reply::Bulk(b"KEA".to_vec()),
+        ]),
+    );
+    redis.set("a", b"1");
+    let mount = match Mount::start(&redis, &["--data-ttl", "60000", "--attr-ttl", "60000"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while redis.count("PSUBSCRIBE") == 0 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert!(redis
+        .commands()
+        .iter()
+        .any(|c| c == &["PSUBSCRIBE", "__keyevent@0__:*"]));
+    std::thread::sleep(Duration::from_millis(300));
+
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"1\n");
+    redis.set("a", b"2");
+    // Cached until the change is heard of.
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"1\n");
+    redis.notify("set", "a");
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"2\n");
+}
+
+#[test]
+fn keys_changed_elsewhere_are_dropped_from_the_kernel() {
+    let redis = FakeRedis::start();
+    redis.reply(
+        "CONFIG",
+        Reply::Array(vec![
+            Reply::Bulk(b"notify-keyspace-events".to_vec()),
+            Reply::Bulk(b"KEA".to_vec()),
+        ]),
+    );
+    redis.set("app:a", b"1");
+    let args = [
+        "--entry-ttl",
+        "60000",
+        "--attr-ttl",
+        "60000",
+        "--data-ttl",
+        "60000",
+        "--separator",
+        ":",
+    ];
+    let mount = match Mount::start(&redis, &args) {
+        Some(m) => m,
+        None => return,
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while redis.count("PSUBSCRIBE") == 0 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    std::thread::sleep(Duration::from_millis(300));
+
+    assert_eq!(fs::metadata(mount.join("kv/app:a")).unwrap().len(), 2);
+    assert_eq!(fs::metadata(mount.join("kv/app/a")).unwrap().len(), 2);
+    redis.set("app:a", b"100");
+    redis.notify("set", "app:a");
+    std::thread::sleep(Duration::from_millis(300));
+    // Both of the entries it's found by, not just the one last looked up.
+    assert_eq!(fs::metadata(mount.join("kv/app:a")).unwrap().len(), 4);
+    assert_eq!(fs::metadata(mount.join("kv/app/a")).unwrap().len(), 4);
+}
+
+#[test]
+fn notifications_are_heard_for_the_database_in_the_url() {
+    let redis = FakeRedis::start();
+    redis.reply(
+        "CONFIG",
+        Reply::Array(vec![
+            Reply::Bulk(b"notify-keyspace-events".to_vec()),
+            Reply::Bulk(b"KEA".to_vec()),
+        ]),
+    );
+    let mount = match Mount::start_url(&format!("{}/3", redis.url()), &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while redis.count("PSUBSCRIBE") == 0 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert!(redis
+        .commands()
+        .iter()
+        .any(|c| c == &["PSUBSCRIBE", "__keyevent@3__:*"]));
+    drop(mount);
+}
+
+#[test]
+fn metrics_are_served_for_prometheus() {
+    let redis = FakeRedis::start();
+    redis.set("greeting", b"hello");
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let mount = match Mount::start(&redis, &["--metrics-listen", &addr]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::read(mount.join("kv/greeting")).unwrap();
+    let scrape = |path: &str| {
+        let mut stream = TcpStream::connect(&addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: fusekv\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let metrics = scrape("/metrics");
+    assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(metrics.contains("fusekv_operation_duration_seconds_count{op=\"read\"} "));
+    assert!(
+        metrics.contains("fusekv_operation_duration_seconds_bucket{op=\"lookup\",le=\"+Inf\"} ")
+    );
+    assert!(metrics.contains("\nfusekv_backend_up 1\n"));
+    assert!(metrics.contains("\nfusekv_backend_commands_total{command=\"GET\"} "));
+    assert!(metrics.contains("\nfusekv_pool_max_connections "));
+    assert!(scrape("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+#[test]
+fn replicas_are_mounted_read_only_unless_writes_are_allowed() {
+    let redis = FakeRedis::start();
+    redis.set("a", b"1");
+    redis.reply(
+        "INFO",
+        Reply::Bulk(b"# Replication\r\nrole:slave\r\n".to_vec()),
+    );
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"1\n");
+    let err = fs::write(mount.join("kv/b"), b"2").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+    drop(mount);
+    let mount = Mount::start(&redis, &["--replica-writes"]).unwrap();
+    fs::write(mount.join("kv/b"), b"2").unwrap();
+    assert_eq!(redis.get("b").unwrap(), b"2");
+}
+
+#[test]
+fn transactions_queue_writes_until_committed() {
+    let redis = FakeRedis::start();
+    redis.set("a", b"1").set("gone", b"x");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::create_dir(mount.join("txn/t")).unwrap();
+    fs::write(mount.join("txn/t/a"), "2\n").unwrap();
+    fs::write(mount.join("txn/t/b"), "3\n").unwrap();
+    fs::remove_file(mount.join("txn/t/gone")).unwrap();
+    // Nothing reaches Redis until the session is committed.
+    assert_eq!(redis.get("a"), Some(b"1".to_vec()));
+    assert_eq!(redis.get("b"), None);
+    assert_eq!(fs::read_to_string(mount.join("txn/t/a")).unwrap(), "2\n");
+    assert_eq!(
+        fs::read_to_string(mount.join("txn/t/.control")).unwrap(),
+        "SET a\nSET b\nDEL gone\n"
+    );
+    fs::write(mount.join("txn/t/.control"), "commit\n").unwrap();
+    assert_eq!(redis.count("MULTI"), 1);
+    assert_eq!(redis.count("EXEC"), 1);
+    assert_eq!(redis.get("a"), Some(b"2".to_vec()));
+    assert_eq!(redis.get("b"), Some(b"3".to_vec()));
+    assert_eq!(redis.get("gone"), None);
+    assert_eq!(fs::read_to_string(mount.join("kv/b")).unwrap(), "3\n");
+
+    // Removing a session discards whatever it still has queued.
+    fs::write(mount.join("txn/t/a"), "4\n").unwrap();
+    fs::remove_dir(mount.join("txn/t")).unwrap();
+    assert!(!mount.join("txn/t").exists());
+    assert_eq!(redis.get("a"), Some(b"2".to_vec()));
+    assert_eq!(redis.count("EXEC"), 1);
+}
+
+#[test]
+fn transaction_writes_stop_at_stream_threshold() {
+    let redis = FakeRedis::start();
+    let mount = match Mount::start(&redis, &["--stream-threshold", "10"]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::create_dir(mount.join("txn/t")).unwrap();
+    fs::write(mount.join("txn/t/a"), "0123456789").unwrap();
+    let err = fs::write(mount.join("txn/t/b"), "0123456789a").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EFBIG));
+    // Nor can a write far past the end grow the buffer to reach it.
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .open(mount.join("txn/t/a"))
+        .unwrap();
+    let err = file.write_at(b"x", 1 << 40).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EFBIG));
+}
+
+#[test]
+fn publishes_apply_only_when_every_checksum_matches() {
+    let redis = FakeRedis::start();
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let one = "2c8b08da5ce60398e1f19af0e5dccc744df274b826abe585eaba68c525434806";
+    let two = "27dd8ed44a83ff94d557f9fd0412ed5a8cbca69ea04922d88c01184a07300a5a";
+    fs::create_dir(mount.join("publish/v1")).unwrap();
+    fs::write(mount.join("publish/v1/one"), "one\n").unwrap();
+    fs::write(mount.join("publish/v1/two"), "tw0\n").unwrap();
+    let manifest = format!("{}  one\n{} *./two\n", one, two);
+    fs::write(mount.join("publish/v1/.manifest"), manifest).unwrap();
+    // A single mismatch publishes nothing.
+    let err = fs::write(mount.join("publish/v1/.commit"), "\n").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EBADMSG));
+    assert_eq!(redis.get("one"), None);
+    assert_eq!(redis.count("EXEC"), 0);
+
+    fs::write(mount.join("publish/v1/two"), "two\n").unwrap();
+    fs::write(mount.join("publish/v1/.commit"), "\n").unwrap();
+    assert_eq!(redis.count("MULTI"), 1);
+    assert_eq!(redis.count("EXEC"), 1);
+    assert_eq!(redis.get("one"), Some(b"one".to_vec()));
+    assert_eq!(redis.get("two"), Some(b"two".to_vec()));
+    assert!(!mount.join("publish/v1").exists());
+}
+
+#[test]
+fn json_documents_are_directories_with_redisjson() {
+    let redis = FakeRedis::start();
+    redis.module("ReJSON").json_set(
+        "user:1",
+        r#"{"name":"Ada","address":{"city":"London","zip":12345},"tags":["a","b"]}"#,
+    );
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert!(mount.join("kv/user:1/address").is_dir());
+    let mut names: Vec<String> = fs::read_dir(mount.join("kv/user:1"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["address", "name", "tags"]);
+    let read = |rel: &str| fs::read_to_string(mount.join(rel)).unwrap();
+    assert_eq!(read("kv/user:1/address/city"), "London\n");
+    assert_eq!(read("kv/user:1/address/zip"), "12345\n");
+    assert_eq!(read("kv/user:1/tags/1"), "b\n");
+
+    // Strings stay strings, and anything else written is parsed as JSON.
+    fs::write(mount.join("kv/user:1/address/city"), "Paris\n").unwrap();
+    fs::write(mount.join("kv/user:1/address/zip"), "75001\n").unwrap();
+    fs::write(mount.join("kv/user:1/address/country"), "FR\n").unwrap();
+    fs::remove_file(mount.join("kv/user:1/name")).unwrap();
+    let doc = redis.json_get("user:1").unwrap();
+    assert_eq!(doc["address"]["city"], "Paris");
+    assert_eq!(doc["address"]["zip"], 75001);
+    assert_eq!(doc["address"]["country"], "FR");
+    assert!(doc.get("name").is_none());
+}
+
+#[test]
+fn json_documents_are_left_alone_without_probing_modules() {
+    let redis = FakeRedis::start();
+    redis
+        .module("ReJSON")
+        .json_set("user:1", r#"{"name":"Ada"}"#);
+    let mount = match Mount::start(&redis, &["--no-probe-modules"]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_ne!(stat_errno(&mount.join("kv/user:1")), 0);
+    assert_eq!(redis.count("MODULE"), 0);
+    assert_eq!(redis.count("JSON.GET"), 0);
+}
+
+#[test]
+fn find_lists_keys_matching_a_glob_as_links_into_kv() {
+    let redis = FakeRedis::start();
+    for i in 0..25 {
+        redis.set(&format!("sess:{}", i), format!("s{}", i).as_bytes());
+    }
+    redis.set("user:1", b"ada");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let mut names: Vec<String> = fs::read_dir(mount.join("find/sess:*"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    let mut expected: Vec<String> = (0..25).map(|i| format!("sess:{}", i)).collect();
+    expected.sort();
+    assert_eq!(names, expected);
+    // Scanned a page at a time, with the glob as the pattern.
+    assert!(redis
+        .commands()
+        .iter()
+        .any(|c| c[0] == "SCAN" && c.get(3).map(String::as_str) == Some("sess:*")));
+
+    let link = mount.join("find/sess:*/sess:3");
+    assert_eq!(
+        fs::read_link(&link).unwrap(),
+        std::path::PathBuf::from("../../kv/sess:3")
+    );
+    assert_eq!(fs::read_to_string(&link).unwrap(), "s3\n");
+    assert!(fs::symlink_metadata(mount.join("find/sess:*/user:1")).is_err());
+}
+
+#[test]
+fn dumps_of_a_prefix_restore_its_keys_when_written_back() {
+    let redis = FakeRedis::start();
+    redis
+        .set("users:1", b"ada")
+        .set("users:2", b"bob")
+        .set("other", b"x");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let dump = fs::read(mount.join("dump/users:")).unwrap();
+    assert_eq!(redis.count("DUMP"), 2);
+    assert!(!String::from_utf8_lossy(&dump).contains("other"));
+
+    fs::write(mount.join("kv/users:1"), "changed\n").unwrap();
+    fs::remove_file(mount.join("kv/users:2")).unwrap();
+    fs::write(mount.join("dump/users:"), &dump).unwrap();
+    assert_eq!(redis.count("RESTORE"), 2);
+    assert_eq!(redis.get("users:1"), Some(b"ada".to_vec()));
+    assert_eq!(redis.get("users:2"), Some(b"bob".to_vec()));
+
+    // Keys outside the file's prefix restore nothing.
+    fs::write(mount.join("dump/other"), &dump).ok();
+    assert_eq!(redis.count("RESTORE"), 2);
+}
+
+#[test]
+fn opens_past_the_handle_limits_fail_with_emfile() {
+    let redis = FakeRedis::start();
+    redis.set("a", b"1").set("b", b"2").set("c", b"3");
+    let mount = match Mount::start(
+        &redis,
+        &["--max-open-per-key", "2", "--max-open-handles", "3"],
+    ) {
+        Some(m) => m,
+        None => return,
+    };
+    let first = fs::File::open(mount.join("kv/a")).unwrap();
+    let _second = fs::File::open(mount.join("kv/a")).unwrap();
+    let err = fs::File::open(mount.join("kv/a")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EMFILE));
+    let stats = fs::read_to_string(mount.join(".fusekv/stats")).unwrap();
+    assert!(stats.contains("open_handles 2\n"));
+    assert!(stats.contains("open_handles.a 2\n"));
+
+    let _b = fs::File::open(mount.join("kv/b")).unwrap();
+    let err = fs::File::open(mount.join("kv/c")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EMFILE));
+    // Releases reach the mount after close returns.
+    drop(first);
+    let deadline = SystemTime::now() + Duration::from_secs(2);
+    while fs::File::open(mount.join("kv/c")).is_err() {
+        assert!(SystemTime::now() < deadline);
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn policies_make_paths_read_only_or_deny_them() {
+    let redis = FakeRedis::start();
+    redis.set("config:a", b"1").set("secret:a", b"2");
+    let config = std::env::temp_dir().join(format!("fusekv-policy-{}.toml", std::process::id()));
+    fs::write(
+        &config,
+        "[[policy]]\npattern = \"/kv/config:*\"\nread_only = true\n\n\
+         [[policy]]\npattern = \"/kv/secret:*\"\ndeny = true\n",
+    )
+    .unwrap();
+    let mount = Mount::start(&redis, &["--config", config.to_str().unwrap()]);
+    fs::remove_file(&config).unwrap();
+    let mount = match mount {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(
+        fs::read_to_string(mount.join("kv/config:a")).unwrap(),
+        "1\n"
+    );
+    let err = fs::write(mount.join("kv/config:a"), "changed\n").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+    let err = fs::remove_file(mount.join("kv/config:a")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+    assert_eq!(redis.get("config:a"), Some(b"1".to_vec()));
+
+    assert_eq!(stat_errno(&mount.join("kv/secret:a")), libc::EACCES);
+    assert_eq!(stat_errno(&mount.join("kv/secret:new")), libc::EACCES);
+
+    fs::write(mount.join("kv/scratch:a"), "v\n").unwrap();
+    assert_eq!(redis.get("scratch:a"), Some(b"v".to_vec()));
+}
+
+#[test]
+fn write_allow_lets_only_members_of_its_groups_mutate_paths() {
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+    // Writing as another group takes root.
+    if unsafe { libc::getuid() } != 0 {
+        return;
+    }
+    let group = match users::get_group_by_gid(65534) {
+        Some(g) => g.name().to_string_lossy().into_owned(),
+        None => return,
+    };
+    let redis = FakeRedis::start();
+    redis.set("ops:a", b"1");
+    let config = std::env::temp_dir().join(format!("fusekv-allow-{}.toml", std::process::id()));
+    fs::write(
+        &config,
+        format!(
+            "[[write_allow]]\npattern = \"^/kv/ops:.*\"\ngroups = [\"{}\"]\n",
+            group
+        ),
+    )
+    .unwrap();
+    let mount = Mount::start(
+        &redis,
+        &["--allow-other", "--config", config.to_str().unwrap()],
+    );
+    fs::remove_file(&config).unwrap();
+    let mount = match mount {
+        Some(m) => m,
+        None => return,
+    };
+    // Everyone else can still read them, and write elsewhere.
+    assert_eq!(fs::read(mount.join("kv/ops:a")).unwrap(), b"1\n");
+    let err = fs::write(mount.join("kv/ops:a"), b"2").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+    let err = fs::write(mount.join("kv/ops:b"), b"2").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+    let err = fs::remove_file(mount.join("kv/ops:a")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+    fs::write(mount.join("kv/scratch"), b"v").unwrap();
+    assert_eq!(redis.get("ops:a").unwrap(), b"1");
+    assert_eq!(redis.get("ops:b"), None);
+    assert_eq!(redis.get("scratch").unwrap(), b"v");
+
+    let written = Command::new("sh")
+        .args(["-c", "echo 3 > \"$0\""])
+        .arg(mount.join("kv/ops:a"))
+        .gid(65534)
+        .status()
+        .unwrap();
+    assert!(written.success());
+    assert_eq!(redis.get("ops:a").unwrap(), b"3");
+}
+
+#[test]
+fn writes_failing_validation_are_rejected_with_a_reason() {
+    let redis = FakeRedis::start();
+    redis.set("port:a", b"80");
+    let config =
+        std::env::temp_dir().join(format!("fusekv-validation-{}.toml", std::process::id()));
+    fs::write(
+        &config,
+        "[[validation]]\npattern = \"^/kv/port:\"\nregex = \"^[0-9]+\\n?$\"\nmax_size = 6\n\n\
+         [[validation]]\npattern = \"^/kv/name:\"\ncommand = \"grep -q bob || (echo not bob >&2; exit 1)\"\n",
+    )
+    .unwrap();
+    let mount = Mount::start(&redis, &["--config", config.to_str().unwrap()]);
+    fs::remove_file(&config).unwrap();
+    let mount = match mount {
+        Some(m) => m,
+        None => return,
+    };
+    let path = mount.join("kv/port:a");
+    // Rejections surface when the file is closed, which fs::write ignores.
+    fs::write(&path, "http\n").ok();
+    assert_eq!(redis.get("port:a"), Some(b"80".to_vec()));
+    let reason = String::from_utf8(getxattr(&path, "user.fusekv.last_reject").unwrap()).unwrap();
+    assert!(reason.contains("doesn't match"), "{}", reason);
+    fs::write(&path, "12345678\n").ok();
+    assert_eq!(redis.get("port:a"), Some(b"80".to_vec()));
+    let reason = String::from_utf8(getxattr(&path, "user.fusekv.last_reject").unwrap()).unwrap();
+    assert!(reason.contains("max_size"), "{}", reason);
+
+    fs::write(&path, "8080\n").unwrap();
+    assert_eq!(redis.get("port:a"), Some(b"8080".to_vec()));
+    assert_eq!(
+        getxattr(&path, "user.fusekv.last_reject").unwrap_err(),
+        libc::ENODATA
+    );
+
+    let path = mount.join("kv/name:a");
+    fs::write(&path, "alice\n").ok();
+    assert_ne!(redis.get("name:a"), Some(b"alice".to_vec()));
+    assert_eq!(
+        getxattr(&path, "user.fusekv.last_reject").unwrap(),
+        b"not bob".to_vec()
+    );
+    fs::write(&path, "bob\n").unwrap();
+    assert_eq!(redis.get("name:a"), Some(b"bob".to_vec()));
+}
+
+#[test]
+fn expiring_keys_count_down_their_ttl_in_size_or_blocks() {
+    let redis = FakeRedis::start();
+    redis.set("session", b"abcdefghi");
+    let mount = match Mount::start(&redis, &["--ttl-display", "size", "--attr-ttl", "0"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let path = mount.join("kv/session");
+    assert_eq!(fs::metadata(&path).unwrap().len(), 10);
+    setxattr(&path, "user.ttl", b"100").unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 10);
+    setxattr(&path, "user.ttl", b"50").unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 5);
+    // Reads still return the whole value.
+    assert_eq!(fs::read_to_string(&path).unwrap(), "abcdefghi\n");
+    drop(mount);
+
+    let mount = match Mount::start(&redis, &["--ttl-display", "blocks", "--attr-ttl", "0"]) {
+        Some(m) => m,
+        None => return,
+    };
+    let path = mount.join("kv/session");
+    let meta = fs::metadata(&path).unwrap();
+    assert_eq!(meta.len(), 10);
+    assert!((49..=50).contains(&meta.blocks()), "{}", meta.blocks());
+}
+
+#[test]
+fn check_connects_to_the_backend_without_mounting() {
+    let redis = FakeRedis::start();
+    let check = || {
+        std::process::Command::new(env!("CARGO_BIN_EXE_fusekv"))
+            .arg("--server")
+            .arg(redis.url())
+            .arg("--check")
+            .output()
+            .unwrap()
+    };
+    let out = check();
+    let report = String::from_utf8_lossy(&out.stdout);
+    assert!(out.status.success(), "{}", report);
+    assert!(report.contains("Backend is up"), "{}", report);
+    assert!(report.ends_with("Ready to mount.\n"), "{}", report);
+
+    redis.reply("PING", Reply::Error("LOADING still loading".to_string()));
+    let out = check();
+    assert_eq!(out.status.code(), Some(3));
+}
+
+#[test]
+fn commands_reuse_pooled_connections() {
+    let redis = FakeRedis::start();
+    redis.set("a", b"1");
+    let mount = match Mount::start(&redis, &["--pool-size", "2", "--nocache", "^/kv/"]) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"1\n");
+    let connected = redis.connections();
+    for _ in 0..30 {
+        assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"1\n");
+        fs::write(mount.join("kv/b"), b"2").unwrap();
+    }
+    // At most one more is opened, up to the pool's size, rather than one per
+    // operation.
+    assert!(
+        redis.connections() <= connected + 1,
+        "{}",
+        redis.connections()
+    );
+    drop(mount);
+
+    // Never mounted, as the pool can't be built.
+    let mountpoint = std::env::temp_dir().join(format!("fusekv-no-pool-{}", std::process::id()));
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_fusekv"))
+        .arg(&mountpoint)
+        .args(["--server", &redis.url(), "--pool-size", "0"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(2));
+}
+
+#[test]
+fn startup_failures_exit_with_their_category() {
+    let redis = FakeRedis::start();
+    redis.require_password("secret");
+    // Nothing listens here once the listener is dropped.
+    let closed = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    // Never mounted, as the backend is checked first.
+    let mountpoint = std::env::temp_dir().join(format!("fusekv-failing-{}", std::process::id()));
+    let start = |url: &str| {
+        let out = std::process::Command::new(env!("CARGO_BIN_EXE_fusekv"))
+            .arg(&mountpoint)
+            .args(["--server", url, "--error-format", "json"])
+            .args(["--redis-password-env", "FUSEKV_TEST_PASSWORD"])
+            .env("FUSEKV_TEST_PASSWORD", "wrong")
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr).into_owned();
+        let error: serde_json::Value = serde_json::from_str(stderr.lines().last().unwrap())
+            .unwrap_or_else(|e| panic!("{}: {}", e, stderr));
+        (out.status.code(), error)
+    };
+    let (code, error) = start(&format!("redis://{}", closed));
+    assert_eq!(code, Some(3));
+    assert_eq!(error["error"], "connection");
+    assert_eq!(error["code"], 3);
+    let (code, error) = start(&redis.url());
+    assert_eq!(code, Some(5));
+    assert_eq!(error["error"], "permission");
+    assert!(error["message"].is_string());
+    assert!(!mountpoint.exists());
+}
+
+#[test]
+fn reads_go_to_replicas_keeping_up_with_the_primary() {
+    let redis = FakeRedis::start();
+    let replica = FakeRedis::start();
+    redis.set("a", b"primary").set("b", b"primary");
+    replica.set("a", b"replica").set("b", b"replica");
+    let info = |last_io: u64| {
+        Reply::Bulk(
+            format!(
+                "# Replication\r\nrole:slave\r\nmaster_link_status:up\r\nmaster_last_io_seconds_ago:{}\r\n",
+                last_io
+            )
+            .into_bytes(),
+        )
+    };
+    replica.reply("INFO", info(1));
+    let mount = match Mount::start(
+        &redis,
+        &[
+            "--replica",
+            &replica.url(),
+            "--read-preference",
+            "prefer-replica",
+            "--max-replica-lag",
+            "5",
+            "--attr-ttl",
+            "0",
+        ],
+    ) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"replica\n");
+    // Keys just written are read back from the primary.
+    fs::write(mount.join("kv/a"), b"new").unwrap();
+    assert_eq!(redis.get("a").unwrap(), b"new");
+    assert_eq!(replica.get("a").unwrap(), b"replica");
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"new\n");
+    // Replicas falling behind are passed over once checked again.
+    replica.reply("INFO", info(60));
+    std::thread::sleep(Duration::from_millis(1100));
+    assert_eq!(fs::read(mount.join("kv/b")).unwrap(), b"primary\n");
+}
+
+#[test]
+fn keys_written_are_read_from_the_primary_even_without_a_lag_check() {
+    let redis = FakeRedis::start();
+    let replica = FakeRedis::start();
+    redis.set("a", b"primary");
+    replica.set("a", b"replica").set("b", b"replica");
+    replica.reply(
+        "INFO",
+        Reply::Bulk(b"# Replication\r\nrole:slave\r\nmaster_link_status:up\r\n".to_vec()),
+    );
+    let mount = match Mount::start(
+        &redis,
+        &[
+            "--replica",
+            &replica.url(),
+            "--read-preference",
+            "prefer-replica",
+            "--max-replica-lag",
+            "0",
+            "--read-your-writes",
+            "0",
+            "--attr-ttl",
+            "0",
+        ],
+    ) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(fs::read(mount.join("kv/b")).unwrap(), b"replica\n");
+    fs::write(mount.join("kv/a"), b"new").unwrap();
+    assert_eq!(replica.get("a").unwrap(), b"replica");
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"new\n");
+}
+
+#[test]
+fn bulk_deletes_remove_only_the_unlinked_keys_once_their_pattern_is_confirmed() {
+    let redis = FakeRedis::start();
+    redis
+        .set("cache:1", b"a")
+        .set("cache:2", b"b")
+        .set("cache:3", b"c")
+        .set("x:1", b"a")
+        .set("x:2", b"b")
+        .set("x:3", b"c");
+    let mount = match Mount::start(&redis, &["--bulk-delete-threshold", "2"]) {
+        Some(m) => m,
+        None => return,
+    };
+    setxattr(&mount.join("kv/cache:1"), "user.fusekv.tag.t", b"").unwrap();
+    assert_eq!(redis.smembers("__fusekv_tag__:t"), vec!["cache:1"]);
+    let dir = mount.join("kv/.match/cache:*");
+    let unlink = |key: &str| fs::remove_file(dir.join(key)).map_err(|e| e.raw_os_error());
+    assert_eq!(unlink("cache:1"), Err(Some(libc::EPERM)));
+    let confirm = fs::read_to_string(mount.join(".fusekv/confirm")).unwrap();
+    let (token, pattern) = confirm.trim().split_once(' ').unwrap();
+    assert_eq!(pattern, "cache:*");
+    let wrong = format!("{} x:*\n", token);
+    assert!(fs::write(mount.join(".fusekv/confirm"), wrong).is_err());
+    fs::write(mount.join(".fusekv/confirm"), &confirm).unwrap();
+    assert_eq!(
+        fs::read_to_string(mount.join(".fusekv/confirm")).unwrap(),
+        ""
+    );
+
+    // The confirmation is only for cache:*.
+    assert_eq!(
+        fs::remove_file(mount.join("kv/.match/x:*/x:1")).map_err(|e| e.raw_os_error()),
+        Err(Some(libc::EPERM))
+    );
+
+    for key in ["cache:1", "cache:2", "cache:3"] {
+        unlink(key).unwrap();
+    }
+    // Keys coming to match after they were listed aren't deleted.
+    redis.set("cache:4", b"d");
+    fs::remove_dir(&dir).unwrap();
+    for key in ["cache:1", "cache:2", "cache:3"] {
+        assert_eq!(redis.get(key), None);
+    }
+    assert_eq!(redis.get("cache:4"), Some(b"d".to_vec()));
+    assert!(redis.smembers("__fusekv_tag__:t").is_empty());
+    assert!(redis.smembers("__fusekv_tags__:cache:1").is_empty());
+
+    // Nor is fusekv's own bookkeeping, and the confirmation is used up.
+    redis.set("cache:5", b"e").set("cache:6", b"f");
+    assert!(redis.get("__fusekv_ino_version__").is_some());
+    assert_eq!(unlink("cache:4"), Err(Some(libc::EPERM)));
+}
+
+#[test]
+fn bulk_deletes_never_match_internal_keys() {
+    let redis = FakeRedis::start();
+    redis.set("a", b"1");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let listed: Vec<String> = fs::read_dir(mount.join("kv/.match/*"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    assert_eq!(listed, vec!["a"]);
+    fs::remove_dir_all(mount.join("kv/.match/*")).unwrap();
+    assert_eq!(redis.get("a"), None);
+    assert!(redis.get("__fusekv_ino_version__").is_some());
+}
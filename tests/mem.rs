@@ -0,0 +1,348 @@
+mod common;
+
+use common::{getxattr, stat_errno, Mount};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn mem_driver_keeps_keys_without_redis() {
+    let mount = match Mount::start_url("mem://", &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::write(mount.join("kv/greeting"), b"hello").unwrap();
+    fs::write(mount.join("kv/other"), b"world").unwrap();
+    assert_eq!(fs::read(mount.join("kv/greeting")).unwrap(), b"hello\n");
+    let mut names: Vec<String> = fs::read_dir(mount.join("kv"))
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["greeting", "other"]);
+
+    fs::rename(mount.join("kv/other"), mount.join("kv/moved")).unwrap();
+    assert_eq!(fs::read(mount.join("kv/moved")).unwrap(), b"world\n");
+    fs::remove_file(mount.join("kv/greeting")).unwrap();
+    assert_eq!(stat_errno(&mount.join("kv/greeting")), libc::ENOENT);
+    assert_eq!(stat_errno(&mount.join("kv/other")), libc::ENOENT);
+}
+
+#[test]
+fn unknown_scheme_is_a_config_error() {
+    let path = std::env::temp_dir().join(format!("fusekv-scheme-{}", std::process::id()));
+    fs::create_dir_all(&path).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_fusekv"))
+        .arg(&path)
+        .arg("--server")
+        .arg("nosuch://somewhere")
+        .status()
+        .unwrap();
+    let _ = fs::remove_dir(&path);
+    assert_eq!(status.code(), Some(2));
+}
+
+// renameat2(2) from to to with flags, returning the errno it failed with or 0.
+fn rename_errno(from: &Path, to: &Path, flags: libc::c_uint) -> i32 {
+    let from = std::ffi::CString::new(from.to_str().unwrap()).unwrap();
+    let to = std::ffi::CString::new(to.to_str().unwrap()).unwrap();
+    if unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            from.as_ptr(),
+            libc::AT_FDCWD,
+            to.as_ptr(),
+            flags,
+        )
+    } == 0
+    {
+        return 0;
+    }
+    std::io::Error::last_os_error().raw_os_error().unwrap()
+}
+
+#[test]
+fn renames_refuse_to_replace_keys_or_leave_the_kv_tree() {
+    let mount = match Mount::start_url("mem://", &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::write(mount.join("kv/a"), b"a").unwrap();
+    fs::write(mount.join("kv/b"), b"b").unwrap();
+    assert_eq!(
+        rename_errno(
+            &mount.join("kv/a"),
+            &mount.join("kv/b"),
+            libc::RENAME_NOREPLACE
+        ),
+        libc::EEXIST
+    );
+    assert_eq!(fs::read(mount.join("kv/b")).unwrap(), b"b");
+    assert_eq!(
+        rename_errno(&mount.join("kv/a"), &mount.join("lock/a"), 0),
+        libc::EXDEV
+    );
+    // Without the flag, the key is replaced.
+    assert_eq!(rename_errno(&mount.join("kv/a"), &mount.join("kv/b"), 0), 0);
+    assert_eq!(fs::read(mount.join("kv/b")).unwrap(), b"a");
+    assert_eq!(stat_errno(&mount.join("kv/a")), libc::ENOENT);
+}
+
+#[test]
+fn strings_become_one_item_lists_when_moved_onto_a_queue() {
+    use std::io::Read;
+    let mount = match Mount::start_url("mem://", &["--blocking-timeout", "1"]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::write(mount.join("kv/foo"), b"hello").unwrap();
+    fs::rename(mount.join("kv/foo"), mount.join("queue/bar")).unwrap();
+    assert_eq!(stat_errno(&mount.join("kv/foo")), libc::ENOENT);
+    assert_eq!(
+        getxattr(&mount.join("kv/bar"), "user.type").unwrap(),
+        b"list"
+    );
+    let mut queue = fs::File::open(mount.join("queue/bar")).unwrap();
+    let mut buf = [0; 64];
+    let n = queue.read(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"hello\n");
+}
+
+#[test]
+fn lists_become_newline_joined_strings_when_moved_off_a_queue() {
+    let mount = match Mount::start_url("mem://", &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::write(mount.join("queue/jobs"), b"a\nb\n").unwrap();
+    fs::rename(mount.join("queue/jobs"), mount.join("kv/joined")).unwrap();
+    assert_eq!(stat_errno(&mount.join("kv/jobs")), libc::ENOENT);
+    assert_eq!(
+        getxattr(&mount.join("kv/joined"), "user.type").unwrap(),
+        b"string"
+    );
+    // Queues are pushed onto the head, so the last line written is first.
+    assert_eq!(fs::read(mount.join("kv/joined")).unwrap(), b"b\na\n");
+}
+
+#[test]
+fn queues_move_between_queues_as_they_are() {
+    let mount = match Mount::start_url("mem://", &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::write(mount.join("queue/a"), b"1\n2\n").unwrap();
+    fs::rename(mount.join("queue/a"), mount.join("queue/b")).unwrap();
+    assert_eq!(stat_errno(&mount.join("kv/a")), libc::ENOENT);
+    assert_eq!(getxattr(&mount.join("kv/b"), "user.type").unwrap(), b"list");
+    assert_eq!(fs::read(mount.join("kv/b")).unwrap(), b"2\n1\n");
+}
+
+#[test]
+fn values_that_cant_be_converted_stay_put() {
+    let fixture = std::env::temp_dir().join(format!("fusekv-convert-{}.toml", std::process::id()));
+    fs::write(
+        &fixture,
+        "[[key]]\nname = \"members\"\ntype = \"set\"\nitems = [\"x\"]\n",
+    )
+    .unwrap();
+    let mount = Mount::start_url("mem://", &["--fixture", fixture.to_str().unwrap()]);
+    fs::remove_file(&fixture).unwrap();
+    let mount = match mount {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(
+        rename_errno(&mount.join("kv/members"), &mount.join("queue/members"), 0),
+        libc::EXDEV
+    );
+    assert_eq!(
+        getxattr(&mount.join("kv/members"), "user.type").unwrap(),
+        b"set"
+    );
+}
+
+#[test]
+fn adaptive_ttls_watch_changes_to_keys() {
+    let mount = match Mount::start_url("mem://", &["--adaptive-ttl", "60000"]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::write(mount.join("kv/a"), b"1").unwrap();
+    fs::write(mount.join("kv/b"), b"2").unwrap();
+    // Changes are picked up in the background.
+    thread::sleep(Duration::from_millis(500));
+    let stats = fs::read_to_string(mount.join(".fusekv/stats")).unwrap();
+    assert!(stats.contains("adaptive_ttl_watching 1\n"));
+    assert!(stats.contains("adaptive_ttl_namespaces 1\n"));
+}
+
+#[test]
+fn reloading_the_config_can_make_the_mount_read_only() {
+    let config = std::env::temp_dir().join(format!("fusekv-reload-{}.toml", std::process::id()));
+    fs::write(&config, "read_only = false\n").unwrap();
+    let mount = match Mount::start_url("mem://", &["--config", config.to_str().unwrap()]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::write(mount.join("kv/a"), b"1").unwrap();
+    fs::write(&config, "read_only = true\n").unwrap();
+    fs::write(mount.join(".fusekv/reload"), b"1").unwrap();
+    let err = fs::write(mount.join("kv/b"), b"2").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"1");
+    // A broken file leaves the config as it was.
+    fs::write(&config, "read_only = \"no\"\n").unwrap();
+    let err = fs::write(mount.join(".fusekv/reload"), b"1").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    fs::write(&config, "read_only = false\n").unwrap();
+    fs::write(mount.join(".fusekv/reload"), b"1").unwrap();
+    fs::remove_file(&config).unwrap();
+    fs::write(mount.join("kv/b"), b"2").unwrap();
+}
+
+#[test]
+fn sigterm_unmounts_and_removes_the_pid_file() {
+    let pid_file = std::env::temp_dir().join(format!("fusekv-term-{}.pid", std::process::id()));
+    let mut mount = match Mount::start_url("mem://", &["--pid-file", pid_file.to_str().unwrap()]) {
+        Some(m) => m,
+        None => return,
+    };
+    let pid = fs::read_to_string(&pid_file).unwrap();
+    assert_eq!(pid.trim(), mount.pid().to_string());
+    fs::write(mount.join("kv/a"), b"1").unwrap();
+    unsafe { libc::kill(mount.pid() as i32, libc::SIGTERM) };
+    assert_eq!(mount.wait(Duration::from_secs(10)), Some(true));
+    assert!(!mount.is_mounted());
+    assert!(!pid_file.exists());
+}
+
+#[test]
+fn daemon_mode_returns_once_mounted() {
+    let pid_file = std::env::temp_dir().join(format!("fusekv-daemon-{}.pid", std::process::id()));
+    let mut mount = match Mount::start_url(
+        "mem://",
+        &["--daemon", "--pid-file", pid_file.to_str().unwrap()],
+    ) {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(mount.wait(Duration::from_secs(10)), Some(true));
+    assert!(mount.is_mounted());
+    fs::write(mount.join("kv/a"), b"1").unwrap();
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"1");
+    let pid: i32 = fs::read_to_string(&pid_file)
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap();
+    assert_ne!(pid as u32, mount.pid());
+    unsafe { libc::kill(pid, libc::SIGTERM) };
+    for _ in 0..100 {
+        if !mount.is_mounted() && !pid_file.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(!mount.is_mounted());
+    assert!(!pid_file.exists());
+}
+
+#[test]
+fn daemon_mode_exits_with_the_mount_error() {
+    if !Path::new("/dev/fuse").exists() {
+        return;
+    }
+    let mountpoint = std::env::temp_dir().join(format!("fusekv-missing-{}", std::process::id()));
+    let status = Command::new(env!("CARGO_BIN_EXE_fusekv"))
+        .arg(&mountpoint)
+        .args(["--server", "mem://", "--daemon"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(4));
+}
+
+#[test]
+fn mem_driver_simulates_a_degraded_backend() {
+    let mount = match Mount::start_url("mem://?latency=200", &["--attr-ttl", "0"]) {
+        Some(m) => m,
+        None => return,
+    };
+    fs::write(mount.join("kv/a"), b"1").unwrap();
+    let started = Instant::now();
+    assert_eq!(fs::read(mount.join("kv/a")).unwrap(), b"1");
+    assert!(started.elapsed() >= Duration::from_millis(200));
+
+    let mount = match Mount::start_url("mem://?error_rate=1", &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let err = fs::write(mount.join("kv/a"), b"1").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+}
+
+#[test]
+fn mem_driver_rejects_unknown_chaos_settings() {
+    // Never mounted, as the setting is refused first.
+    let mountpoint = std::env::temp_dir().join(format!("fusekv-chaos-{}", std::process::id()));
+    let status = Command::new(env!("CARGO_BIN_EXE_fusekv"))
+        .arg(&mountpoint)
+        .args(["--server", "mem://?error_rate=2"])
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(2));
+}
+
+#[test]
+fn subtrees_the_driver_cannot_back_are_left_out() {
+    let mount = match Mount::start_url("mem://", &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    // The mem driver can't run raw commands.
+    assert_eq!(stat_errno(&mount.join("raw")), libc::ENOENT);
+    let help = fs::read_to_string(mount.join("raw:help")).unwrap();
+    assert!(help.contains("doesn't support raw commands"), "{}", help);
+    assert!(mount.join("lock").is_dir());
+    assert!(mount.join("find").is_dir());
+    let capabilities = fs::read_to_string(mount.join(".fusekv/capabilities")).unwrap();
+    assert!(
+        capabilities.contains("supports=scan,ttl,locks,copy,notifications,queues\n"),
+        "{}",
+        capabilities
+    );
+}
+
+#[test]
+fn appends_and_resizes_leave_the_rest_of_string_keys_alone() {
+    use std::io::Write;
+    let mount = match Mount::start_url("mem://", &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let path = mount.join("kv/log");
+    fs::write(&path, "a\n").unwrap();
+    for line in &["b\n", "c\n"] {
+        let mut f = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(line.as_bytes()).unwrap();
+    }
+    assert_eq!(fs::read(&path).unwrap(), b"a\nb\nc\n");
+    // Appending to a missing key creates it.
+    let mut f = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(mount.join("kv/fresh"))
+        .unwrap();
+    f.write_all(b"first\n").unwrap();
+    drop(f);
+    assert_eq!(fs::read(mount.join("kv/fresh")).unwrap(), b"first\n");
+
+    // Truncating by path has no handle's buffer to resize.
+    let c_path = std::ffi::CString::new(path.to_str().unwrap()).unwrap();
+    assert_eq!(unsafe { libc::truncate(c_path.as_ptr(), 3) }, 0);
+    assert_eq!(fs::read(&path).unwrap(), b"a\nb\n");
+    assert_eq!(unsafe { libc::truncate(c_path.as_ptr(), 6) }, 0);
+    assert_eq!(fs::read(&path).unwrap(), b"a\nb\n\0\0\n");
+}
@@ -0,0 +1,67 @@
+mod common;
+
+use common::{getxattr, FakeRedis, Mount, Reply};
+use std::fs;
+
+#[test]
+fn usage_is_reported_through_statfs_and_xattr() {
+    let redis = FakeRedis::start();
+    for i in 0..9 {
+        redis.set(&format!("key{}", i), b"v");
+    }
+    redis.reply(
+        "INFO",
+        Reply::Bulk(b"# Memory\r\nused_memory:1048576\r\n".to_vec()),
+    );
+    let config = std::env::temp_dir().join(format!("fusekv-quota-{}.toml", std::process::id()));
+    fs::write(&config, "[quota]\nkeys = 10\nmemory = 4194304\n").unwrap();
+    let mount = Mount::start(&redis, &["--config", config.to_str().unwrap()]);
+    fs::remove_file(&config).unwrap();
+    let mount = match mount {
+        Some(m) => m,
+        None => return,
+    };
+    assert_eq!(
+        getxattr(&mount.path, "user.fusekv.quota").unwrap(),
+        b"keys 9 10 warn\nmemory 1048576 4194304 ok\n"
+    );
+    let c_path = std::ffi::CString::new(mount.path.to_str().unwrap()).unwrap();
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    assert_eq!(unsafe { libc::statvfs(c_path.as_ptr(), &mut st) }, 0);
+    assert_eq!((st.f_files, st.f_ffree), (10, 1));
+    assert_eq!((st.f_blocks, st.f_bfree), (1024, 768));
+}
+
+#[test]
+fn statfs_reports_backend_memory_without_quotas() {
+    let redis = FakeRedis::start();
+    for i in 0..3 {
+        redis.set(&format!("key{}", i), b"v");
+    }
+    redis.reply(
+        "INFO",
+        Reply::Bulk(
+            b"# Memory\r\nused_memory:1048576\r\nmaxmemory:4194304\r\ntotal_system_memory:8388608\r\n"
+                .to_vec(),
+        ),
+    );
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let c_path = std::ffi::CString::new(mount.path.to_str().unwrap()).unwrap();
+    let mut st: libc::statvfs = unsafe { std::mem::zeroed() };
+    assert_eq!(unsafe { libc::statvfs(c_path.as_ptr(), &mut st) }, 0);
+    assert_eq!((st.f_files, st.f_ffree), (3, 0));
+    assert_eq!((st.f_blocks, st.f_bfree), (1024, 768));
+    // Without maxmemory, the host's memory is the limit.
+    redis.reply(
+        "INFO",
+        Reply::Bulk(
+            b"# Memory\r\nused_memory:1048576\r\nmaxmemory:0\r\ntotal_system_memory:8388608\r\n"
+                .to_vec(),
+        ),
+    );
+    assert_eq!(unsafe { libc::statvfs(c_path.as_ptr(), &mut st) }, 0);
+    assert_eq!((st.f_blocks, st.f_bfree), (2048, 1792));
+}
@@ -0,0 +1,237 @@
+mod common;
+
+use common::{setxattr, FakeRedis, Mount};
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+
+#[test]
+fn replies_are_read_back_through_the_same_handle() {
+    let redis = FakeRedis::start();
+    redis.set("greeting", b"hello");
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let mut raw = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(mount.join("raw"))
+        .unwrap();
+    raw.write_all(b"SET other \"two words\"\nGET greeting\n")
+        .unwrap();
+    let mut replies = String::new();
+    raw.read_to_string(&mut replies).unwrap();
+    assert_eq!(replies, "OK\n\"hello\"\n");
+    assert_eq!(redis.get("other").unwrap(), b"two words");
+}
+
+#[test]
+fn command_errors_are_part_of_the_reply() {
+    let redis = FakeRedis::start();
+    let mount = match Mount::start(&redis, &[]) {
+        Some(m) => m,
+        None => return,
+    };
+    let mut raw = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(mount.join("raw"))
+        .unwrap();
+    raw.write_all(b"BOGUS\n").unwrap();
+    let mut replies = String::new();
+    raw.read_to_string(&mut replies).unwrap();
+    assert!(replies.starts_with("(error) ERR unknown command"));
+}
+
+#[test]
+fn commands_are_kept_in_raw_history() {
+    let redis = FakeRedis::start();
+    let config = std::env::temp_dir().join(format!("fusekv-raw-{}.toml", std::process::id()));
+    std::fs::write(&config, "raw_history = 2\nraw_history_stream = \"audit\"\n").unwrap();
+    let mount = Mount::start(&redis, &["--config", config.to_str().unwrap()]);
+    std::fs::remove_file(&config).unwrap();
+    let mount = match mount {
+        Some(m) => m,
+        None => return,
+    };
+    let mut raw = OpenOptions::new()
+        .write(true)
+        .open(mount.join("raw"))
+        .unwrap();
+    raw.write_all(b"SET a 1\nSET b 2\nGET a\n").unwrap();
+    let history = std::fs::read_to_string(mount.join(".fusekv/raw_history")).unwrap();
+    let uid = unsafe { libc::getuid() };
+    let commands: Vec<String> = history
+        .lines()
+        .map(|l| {
+            let mut parts = l.splitn(3, ' ');
+            parts.next().unwrap().parse::<u64>().unwrap();
+            assert_eq!(parts.next().unwrap(), uid.to_string());
+            parts.next().unwrap().to_string()
+        })
+        .collect();
+    assert_eq!(commands, vec!["SET b 2", "GET a"]);
+    let streamed: Vec<Vec<String>> = redis
+        .commands()
+        .into_iter()
+        .filter(|c| c[0] == "XADD")
+        .collect();
+    assert_eq!(streamed.len(), 3);
+    assert_eq!(&streamed[0][1..5], &["audit", "MAXLEN", "~", "2"]);
+    assert_eq!(streamed[0].last().unwrap(), "SET a 1");
+}
+
+#[test]
+fn reads_writes_unlinks_and_commands_are_audited() {
+    let redis = FakeRedis::start();
+    let log = std::env::temp_dir().join(format!("fusekv-audit-{}.log", std::process::id()));
+    let mount = match Mount::start(&redis, &["--audit-log", log.to_str().unwrap()]) {
+        Some(m) => m,
+        None => return,
+    };
+    std::fs::write(mount.join("kv/a"), "1").unwrap();
+    assert_eq!(std::fs::read_to_string(mount.join("kv/a")).unwrap(), "1\n");
+    std::fs::remove_file(mount.join("kv/a")).unwrap();
+    let mut raw = OpenOptions::new()
+        .write(true)
+        .open(mount.join("raw"))
+        .unwrap();
+    raw.write_all(b"SET b 2\n").unwrap();
+    drop(raw);
+    let entries: Vec<serde_json::Value> = std::fs::read_to_string(&log)
+        .unwrap()
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    std::fs::remove_file(&log).unwrap();
+    let uid = unsafe { libc::getuid() };
+    assert!(entries.iter().all(|e| e["uid"] == uid && e["pid"].is_u64()));
+    let find = |op: &str, key: Option<&str>| {
+        entries
+            .iter()
+            .find(|e| e["op"] == op && e["key"].as_str() == key)
+            .unwrap_or_else(|| panic!("no {} of {:?} in {:?}", op, key, entries))
+    };
+    assert_eq!(find("write", Some("a"))["outcome"], "ok");
+    assert_eq!(find("read", Some("a"))["bytes"], 2);
+    assert_eq!(find("unlink", Some("a"))["path"], "/kv/a");
+    let command = find("raw", None);
+    assert_eq!(command["command"], "SET b 2");
+    assert_eq!(command["outcome"], "ok");
+}
+
+#[test]
+fn every_change_is_audited() {
+    let log = std::env::temp_dir().join(format!("fusekv-audit-changes-{}.log", std::process::id()));
+    let args = [
+        "--audit-log",
+        log.to_str().unwrap(),
+        "--empty-file",
+        "delete",
+    ];
+    let mount = match Mount::start_url("mem://", &args) {
+        Some(m) => m,
+        None => return,
+    };
+    let truncate = |name: &str, size: i64| {
+        let path = std::ffi::CString::new(mount.join(name).to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::truncate(path.as_ptr(), size) }, 0);
+    };
+    std::fs::write(mount.join("kv/a"), "12345").unwrap();
+    truncate("kv/a", 2);
+    let file = OpenOptions::new()
+        .write(true)
+        .open(mount.join("kv/a"))
+        .unwrap();
+    assert_eq!(unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, 8) }, 0);
+    drop(file);
+    setxattr(&mount.join("kv/a"), "user.ttl", b"60").unwrap();
+    setxattr(&mount.join("kv/a"), "user.fusekv.tag.blue", b"").unwrap();
+    std::fs::rename(mount.join("kv/a"), mount.join("kv/b")).unwrap();
+    std::fs::write(mount.join("kv/c"), "1").unwrap();
+    truncate("kv/c", 0);
+    std::fs::remove_file(mount.join("kv/.match/b*/b")).unwrap();
+    std::fs::remove_dir(mount.join("kv/.match/b*")).unwrap();
+    let entries: Vec<serde_json::Value> = std::fs::read_to_string(&log)
+        .unwrap()
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    std::fs::remove_file(&log).unwrap();
+    let find = |op: &str, key: Option<&str>| {
+        entries
+            .iter()
+            .find(|e| e["op"] == op && e["key"].as_str() == key && e["outcome"] == "ok")
+            .unwrap_or_else(|| panic!("no {} of {:?} in {:?}", op, key, entries))
+    };
+    assert_eq!(find("create", Some("a"))["path"], "/kv/a");
+    assert_eq!(find("truncate", Some("a"))["size"], 2);
+    assert_eq!(find("fallocate", Some("a"))["size"], 8);
+    let ttl = find("setxattr", Some("a"));
+    assert_eq!(ttl["name"], "user.ttl");
+    assert_eq!(ttl["value"], "60");
+    assert!(entries
+        .iter()
+        .any(|e| e["op"] == "setxattr" && e["name"] == "user.fusekv.tag.blue"));
+    let rename = find("rename", Some("a"));
+    assert_eq!(rename["to"], "/kv/b");
+    assert_eq!(rename["to_key"], "b");
+    assert_eq!(find("truncate", Some("c"))["deleted"], true);
+    let rmdir = find("rmdir", None);
+    assert_eq!(rmdir["path"], "/kv/.match/b*");
+    assert_eq!(rmdir["keys"], 1);
+}
+
+#[test]
+fn passwords_in_commands_are_never_logged() {
+    let redis = FakeRedis::start();
+    let log = std::env::temp_dir().join(format!("fusekv-audit-auth-{}.log", std::process::id()));
+    let args = ["--audit-log", log.to_str().unwrap(), "--trace", "20"];
+    let mount = match Mount::start(&redis, &args) {
+        Some(m) => m,
+        None => return,
+    };
+    for command in [
+        "AUTH hunter2",
+        "AUTH default hunter2",
+        "CONFIG SET requirepass hunter2",
+        "HELLO 3 AUTH default hunter2",
+        "ACL SETUSER alice on >hunter2",
+    ] {
+        let mut raw = OpenOptions::new()
+            .write(true)
+            .open(mount.join("raw"))
+            .unwrap();
+        // Whether the fake server accepts them doesn't matter.
+        let _ = raw.write_all(format!("{}\n", command).as_bytes());
+    }
+    let history = std::fs::read_to_string(mount.join(".fusekv/raw_history")).unwrap();
+    let trace = std::fs::read_to_string(mount.join(".fusekv/trace")).unwrap();
+    let logged = std::fs::read_to_string(&log).unwrap();
+    std::fs::remove_file(&log).unwrap();
+    assert!(!history.contains("hunter2"), "{}", history);
+    assert!(!trace.contains("hunter2"), "{}", trace);
+    assert!(
+        trace.contains("CONFIG SET requirepass <redacted>"),
+        "{}",
+        trace
+    );
+    assert!(!logged.contains("hunter2"), "{}", logged);
+    let commands: Vec<String> = logged
+        .lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .filter(|e| e["op"] == "raw")
+        .map(|e| e["command"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(
+        commands,
+        vec![
+            "AUTH <redacted>",
+            "AUTH <redacted> <redacted>",
+            "CONFIG SET requirepass <redacted>",
+            "HELLO 3 AUTH <redacted> <redacted>",
+            "ACL SETUSER alice on <redacted>",
+        ]
+    );
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use common::FakeRedis;
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn sync_once_copies_keys_under_a_path_into_a_directory() {
+    let redis = FakeRedis::start();
+    redis
+        .set("app:a", b"1")
+        .set("app:sub:b", b"2")
+        .set("other", b"x");
+    let dir = std::env::temp_dir().join(format!("fusekv-sync-{}", std::process::id()));
+    fs::create_dir_all(dir.join("gone")).unwrap();
+    fs::write(dir.join("gone/stale"), "old\n").unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_fusekv"))
+        .args(["--server", &redis.url(), "--separator", ":"])
+        .arg("sync")
+        .arg("/kv/app")
+        .arg(&dir)
+        .arg("--once")
+        .status()
+        .unwrap();
+    let read = |rel: &str| fs::read_to_string(dir.join(rel)).ok();
+    let (a, b, other, gone) = (
+        read("a"),
+        read("sub/b"),
+        read("other"),
+        dir.join("gone").exists(),
+    );
+    let _ = fs::remove_dir_all(&dir);
+    assert!(status.success());
+    assert_eq!(a.as_deref(), Some("1\n"));
+    assert_eq!(b.as_deref(), Some("2\n"));
+    assert_eq!(other, None);
+    assert!(!gone);
+}